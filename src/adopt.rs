@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config::{self, PackageDeclaration, ScrubFilter};
+use filesystem;
+use git;
+use local_config;
+use new_package;
+
+/// Moves an existing file or directory into a package of the repository, adds it to the
+/// package's files in global.toml, and replaces it with symlinks to the moved files.
+/// A file that the `scrub` filters of global.toml find secrets in is moved with variables in
+/// their place instead, and deployed as a template whose variables are set in local.toml.
+pub fn adopt(opt: &Options, file: &Path, package: Option<&str>) -> Result<()> {
+    if fs::symlink_metadata(file).is_err() {
+        bail!("{:?} doesn't exist", file);
+    }
+    // Only the parent is resolved, since the file itself may be a symlink
+    let parent = match file.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    let original = filesystem::real_path(parent)
+        .context("get real path of parent directory")?
+        .join(file.file_name().context("get file name of adopted file")?);
+    if fs::symlink_metadata(&original)?.file_type().is_symlink() {
+        bail!(
+            "{:?} is a symlink already. Adopt the file it points at instead.",
+            original
+        );
+    }
+
+    let declarations =
+        config::load_package_declarations(&opt.global_config).context("load packages")?;
+    let package = match package {
+        Some(package) if declarations.iter().any(|d| d.name == package) => package.to_string(),
+        Some(package) => bail!(
+            "package {:?} doesn't exist in {:?}",
+            package,
+            opt.global_config
+        ),
+        None => guess_package(&original, &declarations)?,
+    };
+
+    let name = original
+        .file_name()
+        .context("get file name of adopted file")?
+        .to_string_lossy()
+        .trim_start_matches('.')
+        .to_string();
+    let source = PathBuf::from(&package).join(name);
+    if source.exists() {
+        bail!("{:?} already exists in the repository", source);
+    }
+    let target = new_package::collapse_home(&original);
+
+    let filters = config::load_scrub_filters(&opt.global_config).context("load scrub filters")?;
+    let filters = filters
+        .iter()
+        .map(|filter| {
+            Regex::new(&filter.pattern)
+                .map(|pattern| (pattern, filter))
+                .with_context(|| format!("compile scrub pattern {:?}", filter.pattern))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let scrubbed = scrub_adopted(opt, &original, &filters)?;
+
+    info!("Moving {:?} into {:?}", original, source);
+    if opt.act {
+        fs::create_dir_all(&package).context("create package directory")?;
+        match &scrubbed {
+            Some(scrubbed) => fs::write(&source, &scrubbed.contents)
+                .with_context(|| format!("write scrubbed {:?} into the repository", original))?,
+            None => filesystem::copy_recursively(&original, &source)
+                .with_context(|| format!("copy {:?} into the repository", original))?,
+        }
+    }
+
+    let entry = match scrubbed {
+        Some(_) => {
+            let mut entry = toml::value::Table::new();
+            entry.insert("target".into(), toml::Value::String(target.clone()));
+            entry.insert("type".into(), toml::Value::String("template".into()));
+            toml::Value::Table(entry)
+        }
+        None => toml::Value::String(target.clone()),
+    };
+    info!(
+        "Adding {:?} = {} to package {:?} in {:?}",
+        source,
+        config::toml_inline(&entry),
+        package,
+        opt.global_config
+    );
+    if opt.act {
+        config::add_package_file(
+            &opt.global_config,
+            &package,
+            &source.to_string_lossy().replace('\\', "/"),
+            &entry,
+        )
+        .context("add file to package")?;
+    }
+
+    if let Some(scrubbed) = scrubbed {
+        info!(
+            "Saving the values of {} in {:?}",
+            scrubbed
+                .values
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+            opt.local_config
+        );
+        local_config::set_variables(opt, &scrubbed.values).context("save scrubbed secrets")?;
+
+        // The original is what the template renders to, so it stays where it is as the
+        // deployed target, and is cached as the template's render
+        let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+        let mut cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+        let render = cache.render_path(&source, &opt.cache_directory);
+        if opt.act {
+            fs::create_dir_all(render.parent().context("get parent of render")?)
+                .context("create parent of render")?;
+            fs::copy(&original, &render).context("cache render of adopted file")?;
+        }
+        cache.templates.insert(source.clone(), original);
+        if opt.act {
+            config::save_cache(&opt.cache_file, cache)?;
+        }
+
+        git::commit(
+            opt,
+            &[&opt.global_config, &source],
+            &format!("Adopt {} into package {}", target, package),
+        )
+        .context("commit adopted file")?;
+        return Ok(());
+    }
+
+    // Deploy replaces every file of a directory by its own symlink, so adopting does the same
+    let links = if original.is_dir() {
+        files_under(&original)?
+            .into_iter()
+            .map(|relative| (original.join(&relative), source.join(&relative)))
+            .collect()
+    } else {
+        vec![(original.clone(), source.clone())]
+    };
+
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let mut cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    info!("Replacing {:?} with symlinks into the repository", original);
+    if opt.act {
+        if original.is_dir() {
+            fs::remove_dir_all(&original).context("remove original directory")?;
+        } else {
+            fs::remove_file(&original).context("remove original file")?;
+        }
+    }
+    for (link, link_source) in links {
+        debug!("Linking {:?} -> {:?}", link, link_source);
+        if opt.act {
+            fs::create_dir_all(link.parent().context("get parent of link")?)
+                .context("create parent of link")?;
+            filesystem::make_symlink(&link, &link_source)
+                .with_context(|| format!("link {:?} to {:?}", link, link_source))?;
+        }
+        cache.symlinks.insert(link_source, link);
+    }
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+    }
+
+    git::commit(
+        opt,
+        &[&opt.global_config, &source],
+        &format!("Adopt {} into package {}", target, package),
+    )
+    .context("commit adopted file")?;
+
+    Ok(())
+}
+
+struct Scrubbed {
+    contents: String,
+    /// Secrets that were replaced, by variable
+    values: BTreeMap<String, String>,
+}
+
+/// The adopted file with its secrets replaced, or `None` if no filter matches it
+fn scrub_adopted(
+    opt: &Options,
+    original: &Path,
+    filters: &[(Regex, &ScrubFilter)],
+) -> Result<Option<Scrubbed>> {
+    if filters.is_empty() {
+        return Ok(None);
+    }
+    if original.is_dir() {
+        for relative in files_under(original)? {
+            let file = original.join(&relative);
+            let contents = match fs::read_to_string(&file) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            if filters
+                .iter()
+                .any(|(pattern, _)| pattern.is_match(&contents))
+            {
+                bail!(
+                    "{:?} contains secrets that the scrub filters match, which are only scrubbed from single files. Adopt them one by one.",
+                    file
+                );
+            }
+        }
+        return Ok(None);
+    }
+
+    let contents = match fs::read_to_string(original) {
+        Ok(contents) => contents,
+        Err(e) => {
+            debug!("Not scrubbing {:?}, which isn't text: {}", original, e);
+            return Ok(None);
+        }
+    };
+    let existing = local_config::string_variables(opt).context("load variables of local config")?;
+    let scrubbed = scrub(&contents, filters, &existing);
+    if scrubbed.is_some() && contents.contains("{{") {
+        bail!(
+            "{:?} contains secrets that the scrub filters match, but it can't be turned into a template since it contains `{{{{` already",
+            original
+        );
+    }
+    Ok(scrubbed)
+}
+
+/// Replaces the first capture group of every match of the filters, or the whole match if the
+/// pattern has no groups, with a reference to the filter's variable. A variable that is taken
+/// by a different value, in `existing` or by an earlier match, gets a numbered name like
+/// `token_2` instead.
+fn scrub(
+    contents: &str,
+    filters: &[(Regex, &ScrubFilter)],
+    existing: &BTreeMap<String, String>,
+) -> Option<Scrubbed> {
+    let mut values = BTreeMap::<String, String>::new();
+    let mut contents = contents.to_string();
+    for (pattern, filter) in filters {
+        contents = pattern
+            .replace_all(&contents, |captures: &regex::Captures| {
+                let whole = captures.get(0).expect("match has a group 0");
+                let secret = captures.get(1).unwrap_or(whole);
+                if secret.as_str().is_empty() {
+                    return whole.as_str().to_string();
+                }
+                let name = (1..)
+                    .map(|n| match n {
+                        1 => filter.variable.clone(),
+                        n => format!("{}_{}", filter.variable, n),
+                    })
+                    .find(|name| {
+                        [values.get(name), existing.get(name)]
+                            .iter()
+                            .flatten()
+                            .all(|value| *value == secret.as_str())
+                    })
+                    .expect("some name is free");
+                values.insert(name.clone(), secret.as_str().to_string());
+                format!(
+                    "{}{{{{{}}}}}{}",
+                    &contents[whole.start()..secret.start()],
+                    name,
+                    &contents[secret.end()..whole.end()]
+                )
+            })
+            .into_owned();
+    }
+    if values.is_empty() {
+        None
+    } else {
+        Some(Scrubbed { contents, values })
+    }
+}
+
+/// A package named after the application that the file belongs to, like `nvim` for
+/// `~/.config/nvim/init.vim` or `zshrc` for `~/.zshrc`, or the only package there is
+fn guess_package(original: &Path, declarations: &[PackageDeclaration]) -> Result<String> {
+    let mut candidates = Vec::new();
+    if let Ok(rest) = original.strip_prefix(filesystem::xdg_config_home()) {
+        if let Some(application) = rest.iter().next() {
+            candidates.push(application.to_string_lossy().into_owned());
+        }
+    }
+    if let Some(name) = original.file_name() {
+        candidates.push(name.to_string_lossy().trim_start_matches('.').to_string());
+    }
+
+    if let Some(package) = candidates
+        .iter()
+        .find(|c| declarations.iter().any(|d| &d.name == *c))
+    {
+        debug!(
+            "Adopting into package {:?}, which is named after the file",
+            package
+        );
+        return Ok(package.clone());
+    }
+    match declarations {
+        [only] => Ok(only.name.clone()),
+        _ => bail!(
+            "couldn't tell which package {:?} belongs to. Choose one with --package: {}",
+            original,
+            declarations
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Every file inside `directory`, relative to it
+fn files_under(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for child in fs::read_dir(directory).context("read contents of directory")? {
+        let child = child?;
+        let name = PathBuf::from(child.file_name());
+        if child.file_type()?.is_dir() {
+            files.extend(
+                files_under(&child.path())?
+                    .into_iter()
+                    .map(|file| name.join(file)),
+            );
+        } else {
+            files.push(name);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        let token = ScrubFilter {
+            pattern: r#"token = "([^"]*)""#.into(),
+            variable: "token".into(),
+        };
+        let key = ScrubFilter {
+            pattern: r"sk-[a-z0-9]+".into(),
+            variable: "api_key".into(),
+        };
+        let filters = [
+            (Regex::new(&token.pattern).unwrap(), &token),
+            (Regex::new(&key.pattern).unwrap(), &key),
+        ];
+        let mut existing = BTreeMap::new();
+        existing.insert("token".to_string(), "taken".to_string());
+
+        let scrubbed = scrub(
+            "token = \"abc\"\ntoken = \"taken\"\nkey = sk-123\nagain = sk-123\ntoken = \"\"\n",
+            &filters,
+            &existing,
+        )
+        .unwrap();
+        assert_eq!(
+            scrubbed.contents,
+            "token = \"{{token_2}}\"\ntoken = \"{{token}}\"\nkey = {{api_key}}\nagain = {{api_key}}\ntoken = \"\"\n"
+        );
+        assert_eq!(scrubbed.values["token_2"], "abc");
+        assert_eq!(scrubbed.values["token"], "taken");
+        assert_eq!(scrubbed.values["api_key"], "sk-123");
+        assert!(scrub("nothing here", &filters, &existing).is_none());
+    }
+}
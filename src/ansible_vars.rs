@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use config::Variables;
+use facts;
+
+/// Reads the variables that an Ansible inventory directory defines for this machine:
+/// `group_vars/all`, then `host_vars/<hostname>` on top of it.
+/// Each of them can be a YAML file (with a `.yml` or `.yaml` extension) or a directory of
+/// YAML files, like in Ansible. The full hostname is tried before the short one.
+pub fn load_host_vars(inventory: &Path) -> Result<Variables> {
+    let hostname = facts::hostname();
+    let short_hostname = hostname.split('.').next().unwrap_or_default().to_string();
+
+    let mut variables = load_vars(&inventory.join("group_vars"), "all")?;
+    let host_vars = inventory.join("host_vars");
+    let host = load_vars(&host_vars, &hostname)?;
+    let host = if host.is_empty() && short_hostname != hostname {
+        load_vars(&host_vars, &short_hostname)?
+    } else {
+        host
+    };
+    if host.is_empty() {
+        debug!("No Ansible host_vars for {:?} in {:?}", hostname, inventory);
+    }
+    variables.extend(host);
+    Ok(variables)
+}
+
+/// Variables of `name` in a `group_vars` or `host_vars` directory, empty if it has none
+fn load_vars(directory: &Path, name: &str) -> Result<Variables> {
+    // Host names have dots in them, the extension goes after the whole name
+    let mut files = [
+        format!("{}.yml", name),
+        format!("{}.yaml", name),
+        name.into(),
+    ]
+    .iter()
+    .map(|file| directory.join(file))
+    .filter(|path| path.is_file())
+    .collect::<Vec<_>>();
+
+    let subdirectory = directory.join(name);
+    if subdirectory.is_dir() {
+        let mut entries = fs::read_dir(&subdirectory)
+            .with_context(|| format!("read directory {:?}", subdirectory))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_yaml(path))
+            .collect::<Vec<PathBuf>>();
+        // Ansible reads them in lexicographical order, later files override earlier ones
+        entries.sort();
+        files.extend(entries);
+    }
+
+    let mut variables = Variables::new();
+    for file in files {
+        debug!("Loading Ansible variables from {:?}", file);
+        let contents =
+            fs::read_to_string(&file).with_context(|| format!("read file {:?}", file))?;
+        variables.extend(parse_vars(&contents).with_context(|| format!("parse {:?}", file))?);
+    }
+    Ok(variables)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|extension| extension == "yml" || extension == "yaml")
+}
+
+fn parse_vars(contents: &str) -> Result<Variables> {
+    if contents.trim().is_empty() {
+        return Ok(Variables::new());
+    }
+    let value: serde_yaml::Value = serde_yaml::from_str(contents).context("parse YAML")?;
+    match value {
+        serde_yaml::Value::Mapping(_) => match to_toml(value) {
+            Some(toml::Value::Table(table)) => Ok(table),
+            _ => Ok(Variables::new()),
+        },
+        // A document without content, like `---`
+        serde_yaml::Value::Null => Ok(Variables::new()),
+        _ => bail!("expected a mapping of variable names to values"),
+    }
+}
+
+/// TOML has no null, so null values (and the keys that hold them) are left out
+fn to_toml(value: serde_yaml::Value) -> Option<toml::Value> {
+    Some(match value {
+        serde_yaml::Value::Null => return None,
+        serde_yaml::Value::Bool(b) => toml::Value::Boolean(b),
+        serde_yaml::Value::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => toml::Value::Float(n.as_f64()?),
+        },
+        serde_yaml::Value::String(s) => toml::Value::String(s),
+        serde_yaml::Value::Sequence(sequence) => {
+            toml::Value::Array(sequence.into_iter().filter_map(to_toml).collect())
+        }
+        serde_yaml::Value::Mapping(mapping) => toml::Value::Table(
+            mapping
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let key = match key {
+                        serde_yaml::Value::String(s) => s,
+                        other => serde_yaml::to_string(&other)
+                            .ok()?
+                            .trim_start_matches("---")
+                            .trim()
+                            .to_string(),
+                    };
+                    Some((key, to_toml(value)?))
+                })
+                .collect(),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_vars() {
+        let variables = parse_vars(
+            "---\nfont_size: 12\nlaptop: true\nscale: 1.5\nempty:\nmonitors:\n  - DP-1\n  - HDMI-1\ngit:\n  email: me@example.com\n",
+        )
+        .unwrap();
+        let expected: Variables = toml::from_str(
+            "font_size = 12\nlaptop = true\nscale = 1.5\nmonitors = [\"DP-1\", \"HDMI-1\"]\n[git]\nemail = \"me@example.com\"\n",
+        )
+        .unwrap();
+        assert_eq!(variables, expected);
+
+        assert!(parse_vars("").unwrap().is_empty());
+        assert!(parse_vars("- a\n").is_err());
+    }
+
+    #[test]
+    fn test_load_vars_of_dotted_host() {
+        let directory = tempfile::tempdir().unwrap();
+        fs::write(directory.path().join("web1.example.com.yml"), "port: 80\n").unwrap();
+        fs::write(directory.path().join("web1.example.yml"), "port: 8080\n").unwrap();
+        let variables = load_vars(directory.path(), "web1.example.com").unwrap();
+        assert_eq!(variables["port"], toml::Value::Integer(80));
+    }
+}
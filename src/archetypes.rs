@@ -0,0 +1,266 @@
+//! Starter packages for common programs, which `dotter new-package --from-archetype` scaffolds:
+//! the usual layout of their files, the variables their templates use, and how they reload
+
+/// A starter package
+pub struct Archetype {
+    pub name: &'static str,
+    pub files: &'static [ArchetypeFile],
+    /// Variables of the package and their default values
+    pub variables: &'static [(&'static str, &'static str)],
+}
+
+pub struct ArchetypeFile {
+    /// Path in the package's directory
+    pub source: &'static str,
+    pub target: &'static str,
+    /// Rendered as a template instead of symlinked
+    pub template: bool,
+    /// `reload` of the file, see `reload.rs`
+    pub reload: Option<&'static str>,
+    pub contents: &'static str,
+}
+
+impl ArchetypeFile {
+    /// Target of the file in the package's `files` table
+    pub fn target_value(&self) -> toml::Value {
+        if !self.template && self.reload.is_none() {
+            return toml::Value::String(self.target.into());
+        }
+        let mut table = toml::value::Table::new();
+        table.insert("target".into(), toml::Value::String(self.target.into()));
+        if self.template {
+            table.insert("type".into(), toml::Value::String("template".into()));
+        }
+        if let Some(reload) = self.reload {
+            table.insert("reload".into(), toml::Value::String(reload.into()));
+        }
+        toml::Value::Table(table)
+    }
+}
+
+pub const ARCHETYPES: &[Archetype] = &[
+    Archetype {
+        name: "nvim",
+        files: &[
+            ArchetypeFile {
+                source: "init.lua",
+                target: "~/.config/nvim/init.lua",
+                template: false,
+                reload: None,
+                contents: "require(\"options\")\n",
+            },
+            ArchetypeFile {
+                source: "lua/options.lua",
+                target: "~/.config/nvim/lua/options.lua",
+                template: false,
+                reload: None,
+                contents: "vim.opt.number = true\nvim.opt.expandtab = true\nvim.opt.shiftwidth = 4\n",
+            },
+        ],
+        variables: &[],
+    },
+    Archetype {
+        name: "vim",
+        files: &[ArchetypeFile {
+            source: "vimrc",
+            target: "~/.vimrc",
+            template: false,
+            reload: None,
+            contents: "set number\nset expandtab\nset shiftwidth=4\n",
+        }],
+        variables: &[],
+    },
+    Archetype {
+        name: "git",
+        files: &[
+            ArchetypeFile {
+                source: "gitconfig",
+                target: "~/.gitconfig",
+                template: true,
+                reload: None,
+                contents: "[user]\n\tname = {{git_name}}\n\temail = {{git_email}}\n[core]\n\texcludesFile = ~/.config/git/ignore\n",
+            },
+            ArchetypeFile {
+                source: "ignore",
+                target: "~/.config/git/ignore",
+                template: false,
+                reload: None,
+                contents: ".DS_Store\n*.swp\n",
+            },
+        ],
+        variables: &[("git_name", "Your Name"), ("git_email", "you@example.com")],
+    },
+    Archetype {
+        name: "zsh",
+        files: &[
+            ArchetypeFile {
+                source: "zshrc",
+                target: "~/.zshrc",
+                template: true,
+                reload: None,
+                contents: "export EDITOR={{zsh_editor}}\n\nHISTFILE=~/.zsh_history\nHISTSIZE=10000\nSAVEHIST=10000\n",
+            },
+            ArchetypeFile {
+                source: "zprofile",
+                target: "~/.zprofile",
+                template: false,
+                reload: None,
+                contents: "export PATH=\"$HOME/.local/bin:$PATH\"\n",
+            },
+        ],
+        variables: &[("zsh_editor", "vim")],
+    },
+    Archetype {
+        name: "bash",
+        files: &[
+            ArchetypeFile {
+                source: "bashrc",
+                target: "~/.bashrc",
+                template: true,
+                reload: None,
+                contents: "export EDITOR={{bash_editor}}\n\nHISTSIZE=10000\nshopt -s histappend\n",
+            },
+            ArchetypeFile {
+                source: "bash_profile",
+                target: "~/.bash_profile",
+                template: false,
+                reload: None,
+                contents: "[ -f ~/.bashrc ] && . ~/.bashrc\n",
+            },
+        ],
+        variables: &[("bash_editor", "vim")],
+    },
+    Archetype {
+        name: "tmux",
+        files: &[ArchetypeFile {
+            source: "tmux.conf",
+            target: "~/.tmux.conf",
+            template: true,
+            reload: Some("tmux source-file ~/.tmux.conf"),
+            contents: "set -g prefix {{tmux_prefix}}\nset -g mouse on\nset -g base-index 1\n",
+        }],
+        variables: &[("tmux_prefix", "C-a")],
+    },
+    Archetype {
+        name: "kitty",
+        files: &[ArchetypeFile {
+            source: "kitty.conf",
+            target: "~/.config/kitty/kitty.conf",
+            template: true,
+            reload: Some("kitty"),
+            contents: "font_family {{kitty_font_family}}\nfont_size {{kitty_font_size}}\n",
+        }],
+        variables: &[
+            ("kitty_font_family", "monospace"),
+            ("kitty_font_size", "11"),
+        ],
+    },
+    Archetype {
+        name: "alacritty",
+        files: &[ArchetypeFile {
+            source: "alacritty.toml",
+            target: "~/.config/alacritty/alacritty.toml",
+            template: true,
+            reload: Some("alacritty"),
+            contents: "[font]\nsize = {{alacritty_font_size}}\nnormal = { family = \"{{alacritty_font_family}}\" }\n",
+        }],
+        variables: &[
+            ("alacritty_font_family", "monospace"),
+            ("alacritty_font_size", "11"),
+        ],
+    },
+    Archetype {
+        name: "sway",
+        files: &[ArchetypeFile {
+            source: "config",
+            target: "~/.config/sway/config",
+            template: true,
+            reload: Some("sway"),
+            contents: "set $mod {{sway_modifier}}\nset $term {{sway_terminal}}\n\nbindsym $mod+Return exec $term\n",
+        }],
+        variables: &[("sway_modifier", "Mod4"), ("sway_terminal", "foot")],
+    },
+    Archetype {
+        name: "i3",
+        files: &[ArchetypeFile {
+            source: "config",
+            target: "~/.config/i3/config",
+            template: true,
+            reload: Some("i3"),
+            contents: "set $mod {{i3_modifier}}\nset $term {{i3_terminal}}\n\nbindsym $mod+Return exec $term\n",
+        }],
+        variables: &[
+            ("i3_modifier", "Mod4"),
+            ("i3_terminal", "i3-sensible-terminal"),
+        ],
+    },
+    Archetype {
+        name: "starship",
+        files: &[ArchetypeFile {
+            source: "starship.toml",
+            target: "~/.config/starship.toml",
+            template: false,
+            reload: None,
+            contents: "add_newline = false\n",
+        }],
+        variables: &[],
+    },
+];
+
+/// The archetype called `name`
+pub fn find(name: &str) -> Option<&'static Archetype> {
+    ARCHETYPES.iter().find(|a| a.name == name)
+}
+
+/// Names of every archetype, for error messages
+pub fn names() -> String {
+    ARCHETYPES
+        .iter()
+        .map(|a| a.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_archetypes() {
+        for archetype in ARCHETYPES {
+            for file in archetype.files {
+                if file.template {
+                    handlebars::Template::compile(file.contents).unwrap();
+                }
+            }
+            // Every variable is used by one of the templates
+            for (variable, _) in archetype.variables {
+                let placeholder = format!("{{{{{}}}}}", variable);
+                assert!(
+                    archetype
+                        .files
+                        .iter()
+                        .any(|f| f.template && f.contents.contains(&placeholder)),
+                    "{} doesn't use {}",
+                    archetype.name,
+                    variable
+                );
+            }
+        }
+        // Packages are enabled together, and can only both declare a variable with a merge
+        // strategy
+        let mut variables = std::collections::BTreeSet::new();
+        for archetype in ARCHETYPES {
+            for (variable, _) in archetype.variables {
+                assert!(
+                    variables.insert(variable),
+                    "{} declares {} again",
+                    archetype.name,
+                    variable
+                );
+            }
+        }
+        assert_eq!(find("kitty").unwrap().files[0].source, "kitty.conf");
+        assert!(find("emacs").is_none());
+    }
+}
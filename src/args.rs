@@ -1,8 +1,14 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
 use std::path::PathBuf;
 
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+use cancel::CancellationToken;
+use config::DuplicateTargets;
+
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(name = "Dotter")]
 /// A small dotfile manager.
 pub struct Options {
@@ -14,12 +20,13 @@ pub struct Options {
     #[structopt(short, long, default_value = ".dotter/local.toml", global = true)]
     pub local_config: PathBuf,
 
-    /// Location of cache file
-    #[structopt(long, default_value = ".dotter/cache.toml")]
+    /// Location of cache file. The cache remembers its repository, so a cache outside of
+    /// `.dotter` can't be shared by several repositories by accident.
+    #[structopt(long, default_value = ".dotter/cache.toml", env = "DOTTER_CACHE_FILE")]
     pub cache_file: PathBuf,
 
     /// Directory to cache into.
-    #[structopt(long, default_value = ".dotter/cache")]
+    #[structopt(long, default_value = ".dotter/cache", env = "DOTTER_CACHE_DIRECTORY")]
     pub cache_directory: PathBuf,
 
     /// Dry run - don't do anything, only print information.
@@ -36,63 +43,899 @@ pub struct Options {
     #[structopt(short, long, global = true)]
     pub quiet: bool,
 
+    /// `json` writes a line of JSON to standard output for every planned and made change,
+    /// target status and error of deploy, undeploy, status and diff, instead of their usual
+    /// output. The log goes to standard error then. Goes before the subcommand.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    pub output: OutputMode,
+
     /// Force - instead of skipping, overwrite target files if their content is unexpected.
-    /// Overrides --dry-run.
+    /// `--force=<glob-or-package>,...` only overwrites the targets that match one of the globs
+    /// or belong to one of the packages. Overrides --dry-run.
+    #[structopt(
+        long,
+        global = true,
+        min_values = 0,
+        require_equals = true,
+        use_delimiter = true,
+        value_name = "glob-or-package"
+    )]
+    pub force: Option<Vec<String>>,
+
+    /// Render every template again, even the ones whose source, partials and the variables
+    /// they read haven't changed since they were last deployed, like after an upgrade of
+    /// dotter changed the output of a helper they use
+    #[structopt(long, visible_alias = "re-render-all", global = true)]
+    pub refresh: bool,
+
+    /// Reveal every secret with its provider again, instead of using the copies that
+    /// `cache_ttl` of the [secrets] table keeps
     #[structopt(long, global = true)]
-    pub force: bool,
+    pub refresh_secrets: bool,
+
+    /// What to do when the target of a new file exists, but dotter didn't create it: `skip` it
+    /// with an error, `overwrite` it (with a backup), `keep` it without an error, or `adopt` it
+    /// by replacing the source with its contents and deploying that. Templates are never adopted.
+    #[structopt(
+        long,
+        global = true,
+        default_value = "skip",
+        possible_values = &["skip", "overwrite", "keep", "adopt"],
+        value_name = "policy"
+    )]
+    pub on_conflict: ConflictPolicy,
+
+    /// Ask what to do with every target of a new file that dotter didn't create, with the choices
+    /// of --on-conflict and showing the diff. Overrides --on-conflict.
+    #[structopt(long = "interactive", global = true)]
+    pub ask_conflicts: bool,
 
     /// Assume "yes" instead of prompting when removing empty directories
     #[structopt(short = "y", long = "noconfirm", parse(from_flag = std::ops::Not::not), global = true)]
     pub interactive: bool,
 
+    /// Never prompt: implies --noconfirm, conflicts are resolved by --on-conflict, and sudo and
+    /// secret providers fail instead of asking for a password. Failures that would have asked
+    /// carry a `reason` in the errors of `--output json`. Also enabled by
+    /// `DOTTER_NON_INTERACTIVE=1`, which hooks get too.
+    #[structopt(long, global = true, conflicts_with = "ask-conflicts")]
+    pub non_interactive: bool,
+
+    /// Profile of global.toml whose packages and variables are used, instead of the `profile`
+    /// of local.toml. Its variables override the packages' and are overridden by local.toml's.
+    #[structopt(long, global = true, env = "DOTTER_PROFILE")]
+    pub profile: Option<String>,
+
+    /// What happens when packages, local.toml or the patch deploy a source or a target that's
+    /// already deployed: the one of the package that's deployed later, or of local.toml, wins,
+    /// or it's an error. Overrides the `duplicate_targets` of global.toml.
+    #[structopt(long, global = true, possible_values = &["later-wins", "error"], value_name = "resolution")]
+    pub duplicate_targets: Option<DuplicateTargets>,
+
     /// Take standard input as an additional files/variables patch, added after evaluating
     /// `local.toml`. Assumes --noconfirm flag because all of stdin is taken as the patch.
     #[structopt(short, long, global = true)]
     pub patch: bool,
 
+    /// Location of the pre-deploy hook. Hooks receive `$DOTTER_DRY_RUN`, `$DOTTER_VERBOSITY`,
+    /// `$DOTTER_CACHE_DIR` and `$DOTTER_HOOKS_API`, and only run during a dry run if they
+    /// contain a `dotter-hooks-api: 1` comment.
+    #[structopt(long, default_value = ".dotter/pre_deploy.sh")]
+    pub pre_deploy: PathBuf,
+
+    /// Location of the post-deploy hook. The path to a JSON file listing every changed target
+    /// along with its old and new content hashes is passed in `$DOTTER_CHANGES_FILE`.
+    #[structopt(long, default_value = ".dotter/post_deploy.sh")]
+    pub post_deploy: PathBuf,
+
+    /// Only print a summary of the changes grouped by package, instead of every file.
+    /// The summary is always printed at the end of a dry run.
+    #[structopt(long, global = true)]
+    pub summary_only: bool,
+
+    /// Deploy the other files when some sources are missing from the repository, instead of
+    /// aborting. The targets of missing sources are left as they are. A dry run always shows
+    /// the rest of the plan.
+    #[structopt(long, global = true)]
+    pub skip_missing_sources: bool,
+
+    /// Packages that a deploy is limited to, from `deploy --only`. Empty deploys them all.
+    #[structopt(skip)]
+    pub only: Vec<String>,
+
+    /// Sources that a deploy is limited to, from `var set --deploy-affected`. `None` deploys
+    /// them all.
+    #[structopt(skip)]
+    pub only_sources: Option<BTreeSet<PathBuf>>,
+
+    /// Stops a deploy between files once it's cancelled, for programs that use dotter as a
+    /// library
+    #[structopt(skip)]
+    pub cancellation: CancellationToken,
+
+    /// Only create or update files that are marked `volatile = true`, without touching anything
+    /// else. Meant to be run at login to recreate targets on a tmpfs like `$XDG_RUNTIME_DIR`.
+    #[structopt(long, global = true)]
+    pub volatile_only: bool,
+
+    /// Undo the whole deploy if any file fails, instead of keeping the files that were deployed
+    /// before it. Every target is backed up in the cache directory before it's changed, and the
+    /// cache and hooks only see the deploy if it succeeded.
+    #[structopt(long, global = true)]
+    pub transactional: bool,
+
     /// Amount of lines that are printed before and after a diff hunk.
     #[structopt(long, default_value = "3")]
     pub diff_context_lines: usize,
 
+    /// Maximum size in bytes of a template's source and of its rendered output.
+    /// Templates that exceed it fail instead of being deployed.
+    #[structopt(long, default_value = "16777216")]
+    pub max_template_size: u64,
+
+    /// Maximum amount of seconds that rendering a single template may take
+    #[structopt(long, default_value = "30")]
+    pub render_timeout: u64,
+
+    /// How many templates are rendered at the same time, by default one per CPU.
+    /// Files are still written, and hooks still run, one at a time and in order.
+    #[structopt(short, long, global = true)]
+    pub jobs: Option<usize>,
+
+    /// The repository is on a read-only mount (like the Nix store). Fails early if the command
+    /// would write into it, including a cache file or cache directory inside it.
+    #[structopt(long, global = true)]
+    pub read_only_repo: bool,
+
+    /// Fail on `cmd("...")` variables and `variable_providers` instead of running their
+    /// command, for repositories that aren't trusted.
+    #[structopt(long, global = true)]
+    pub no_exec: bool,
+
+    /// Print how long each phase of the deploy took, including rendering and every hook.
+    /// The timings of the last deploy are also shown by `dotter stats`.
+    #[structopt(long, global = true)]
+    pub timings: bool,
+
+    /// Freeze the date and time helpers (`now`, `timestamp`, `date_passed`) at
+    /// `$SOURCE_DATE_EPOCH`, or at the Unix epoch if it isn't set, so that rendering is reproducible
+    #[structopt(long, global = true)]
+    pub deterministic: bool,
+
+    /// Home directory that `~` expands to, instead of the real one. Also used for the XDG
+    /// directories and passed as `$HOME` to hooks and helpers, so a deploy can target a fake
+    /// home in tests and container builds.
+    #[structopt(long, global = true)]
+    pub home: Option<PathBuf>,
+
+    /// Make the changes of targets marked `elevate = true` through a helper that is started
+    /// with sudo once, the first time it's needed, instead of running sudo for every change
+    #[structopt(long, global = true)]
+    pub privileged_helper: bool,
+
     #[structopt(subcommand)]
     pub action: Option<Action>,
 }
 
-#[derive(Debug, Clone, Copy, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 pub enum Action {
     /// Deploy the files to their respective targets. This is the default subcommand.
-    Deploy,
+    Deploy {
+        /// Only deploy the files of these packages. Deployed files of the other packages are
+        /// left as they are, instead of being deleted.
+        #[structopt(long, use_delimiter = true, value_name = "package")]
+        only: Vec<String>,
+
+        /// Deploy to this SSH destination instead of this machine, like `me@server`. Files are
+        /// rendered here with the host's local.toml given by --local-config, and copied to the
+        /// host, which needs neither dotter nor the repository. Only what changed since the
+        /// last deploy to the host is written.
+        #[structopt(long, value_name = "destination")]
+        host: Option<String>,
+
+        /// Run `git pull --rebase` in the repository first, and don't deploy if it stops at
+        /// conflicts
+        #[structopt(long)]
+        sync: bool,
+    },
 
     /// Delete all deployed files from their target locations.
     /// Note that this operates on all files that are currently in cache.
-    Undeploy,
+    Undeploy {
+        /// Only delete the files of this package, even if it's no longer enabled
+        package: Option<String>,
+
+        /// Stop managing the files instead of deleting them: templates and copies are left as
+        /// they are, and symlinks are replaced with copies of what they point at. Hooks and
+        /// reloads don't run, since the files don't change.
+        #[structopt(long)]
+        keep_rendered: bool,
+    },
 
-    /// Initialize global.toml with a single package containing all the files in the current
-    /// directory pointing to a dummy value and a local.toml that selects that package.
+    /// Initialize global.toml and local.toml, asking whether to use a single package or a
+    /// package per application and offering to import common dotfiles from the home directory.
+    /// With --noconfirm, all the files in the current directory go into a single package.
     Init,
 
     /// Run continuously, watching the repository for changes and deploying as soon as they
     /// happen. Can be ran with `--dry-run`
-    Watch,
+    Watch {
+        /// When a deployed template fails to render, write the error as a banner at the top of
+        /// its target, with every line starting with this comment syntax (for example `#`)
+        #[structopt(long)]
+        error_comment: Option<String>,
+
+        /// After every deploy, write the templates that failed to render and why into this
+        /// file. It is empty if every template rendered.
+        #[structopt(long)]
+        status_file: Option<PathBuf>,
+
+        /// Also deploy whenever something connects to the Unix domain socket at this path and
+        /// sends the path of a changed file, either as a line or as the body of an HTTP POST.
+        /// Only the user may connect to it. On Windows, dotter listens on a local port instead
+        /// and writes `<port> <token>` into this file, and each notification starts with a line
+        /// holding the token, or has an `Authorization: Bearer <token>` header. Meant for editors
+        /// and scripts, where watching the filesystem is unreliable.
+        #[structopt(long)]
+        listen: Option<PathBuf>,
+
+        /// Don't watch the filesystem, only deploy when notified through --listen
+        #[structopt(long, requires = "listen")]
+        no_file_watching: bool,
+
+        /// What to do when the watch starts, before anything changed: `deploy`, `dry-run` to
+        /// only print what a deploy would do, or `skip`. Services that start on login may want
+        /// to skip it, since the login deploy already ran.
+        #[structopt(long, default_value = "deploy", possible_values = &["deploy", "dry-run", "skip"])]
+        initial: InitialDeploy,
+
+        /// Milliseconds to wait for more changes after a file changed, so that saving several
+        /// files at once (like a checkout) deploys only once
+        #[structopt(long, default_value = "300")]
+        debounce: u64,
+    },
+
+    /// Forget the cached renders and recorded state of deployed files, so that the next deploy
+    /// treats them as new. Operates on the entire cache unless a package is specified.
+    CleanCache {
+        /// Only clean the files that belong to this package
+        #[structopt(long)]
+        package: Option<String>,
+    },
+
+    /// Find the symlinks in a directory that point into the repository or the cache directory
+    /// but aren't deployed, like leftovers of experiments or of another tool, and offer to
+    /// delete them. The directory has to be inside the `allowed_target_roots`, or the home
+    /// directory if there are none.
+    Sweep {
+        /// Directory to scan, like `~/.config`
+        directory: PathBuf,
+    },
+
+    /// Check that the cache matches the filesystem, or repair it
+    Cache {
+        #[structopt(subcommand)]
+        action: CacheAction,
+    },
+
+    /// Export the cache and the renders of the deployed templates as a single file, or import
+    /// one, to take over the deployed files on a new machine or after moving the cache
+    State {
+        #[structopt(subcommand)]
+        action: StateAction,
+    },
+
+    /// Print the variables that templates are rendered with, after all configuration files
+    /// are merged.
+    Context {
+        /// Print the context of this package's templates
+        #[structopt(long)]
+        package: Option<String>,
+
+        /// Print the context of this template
+        #[structopt(long)]
+        file: Option<PathBuf>,
+
+        /// Output format
+        #[structopt(long, default_value = "json", possible_values = &["json", "toml"])]
+        format: OutputFormat,
+    },
+
+    /// Move a file or directory in the repository, renaming it in the configuration files and
+    /// the cache so that the next deploy doesn't treat it as deleted and created.
+    Mv {
+        /// Current location of the file, as written in the configuration
+        old_source: PathBuf,
+
+        /// New location of the file
+        new_source: PathBuf,
+
+        /// Recreate the symlinks that point at the old location immediately
+        #[structopt(long)]
+        relink: bool,
+    },
+
+    /// Create a directory for a new package and add the package to global.toml
+    NewPackage {
+        /// Name of the package
+        name: String,
+
+        /// Existing file or directory to import into the package. It is copied into the
+        /// package's directory and deployed back to its original location.
+        #[structopt(long)]
+        from: Option<PathBuf>,
+
+        /// Starter package to scaffold the files, variables and reloads of, like `nvim`, `git`,
+        /// `zsh`, `tmux`, `kitty` or `sway`
+        #[structopt(long, conflicts_with = "from")]
+        from_archetype: Option<String>,
+    },
+
+    /// Move an existing file or directory into a package of the repository, add it to the
+    /// package's files in global.toml, and replace it with a symlink into the repository
+    Adopt {
+        /// File or directory to adopt, like `~/.zshrc`
+        file: PathBuf,
+
+        /// Package to add the file to. By default it's the package named after the file's
+        /// application, like `nvim` for `~/.config/nvim`, or the only package there is.
+        #[structopt(long)]
+        package: Option<String>,
+    },
+
+    /// Print a graph of the packages in the global configuration and what enables them.
+    /// Disabled packages are drawn with a dashed border.
+    Graph {
+        /// Output format
+        #[structopt(long, default_value = "dot", possible_values = &["dot", "mermaid"])]
+        format: GraphFormat,
+
+        /// Also draw an edge from every file of an enabled package to its package
+        #[structopt(long)]
+        files: bool,
+    },
+
+    /// Print whether every file is deployed as a template or as a symlink, and why: its explicit
+    /// type, or whether it contains `{{`. Files whose type disagrees with their contents are
+    /// highlighted.
+    Classify,
+
+    /// Check the configuration for likely mistakes. Every finding has a rule ID, and expected
+    /// findings are silenced with `allow = ["<rule>"]` or `allow = ["<rule>:<package or source>"]`
+    /// in the `[lint]` table of global.toml or local.toml.
+    Lint,
+
+    /// Print the files that a deploy would create, as the configuration of another tool:
+    /// `nix` is a home-manager module with `home.file` and `xdg.configFile` entries.
+    Export {
+        #[structopt(possible_values = &["nix"])]
+        format: ExportFormat,
+
+        /// Write to this file instead of standard output
+        #[structopt(long)]
+        output: Option<PathBuf>,
+
+        /// Reference the rendered copies of templates in the cache directory instead of
+        /// inlining their contents. They're left there by the last deploy.
+        #[structopt(long)]
+        reference_rendered: bool,
+    },
+
+    /// Print the variables that would render a template into a file that is deployed already,
+    /// as TOML for the `[variables]` of a package. Speeds up turning a plain file into a
+    /// template: write the template, then extract the values from the original.
+    ExtractVars {
+        /// Template in the repository
+        template: PathBuf,
+
+        /// File that the template should reproduce, like `~/.gitconfig`
+        deployed: PathBuf,
+    },
+
+    /// Print how many files are deployed and how long each phase of the last deploy took
+    Stats,
+
+    /// Archive the current contents of every deployed target, with a manifest.json that maps
+    /// them to their sources. Useful before a risky change to the configuration.
+    Snapshot {
+        /// Path of the tar archive [default: dotter-snapshot-<hostname>-<time>.tar]
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Put back a file that a deploy overwrote or deleted because it was in the way of a
+    /// target. Without a target, print every backup that is kept.
+    Restore {
+        /// Target whose latest backup is restored, like `~/.zshrc`
+        target: Option<PathBuf>,
+    },
+
+    /// Record the deployed state of every target under a name: the contents of templates and
+    /// copies and where symlinks point, along with the cache. Take one before an experiment like
+    /// switching themes, and go back to it with `revert-to`. Without a name, print every
+    /// checkpoint.
+    Checkpoint {
+        /// Name of the checkpoint, like `pre-theme-switch`
+        name: Option<String>,
+    },
+
+    /// Put every target back the way it was when the checkpoint was taken, and delete the
+    /// targets that were deployed since. Files that get replaced are backed up, and can be put
+    /// back with `restore`.
+    RevertTo {
+        /// Name of the checkpoint
+        name: String,
+    },
+
+    /// Print what a deploy would change without changing anything: a diff of every template
+    /// against its target, where symlinks would point, and the files that would be deleted
+    Diff,
+
+    /// Print what a deploy would do, grouped into the files that would be created, updated,
+    /// have only their metadata changed, or deleted, and the hooks that would run. Nothing is
+    /// changed.
+    Plan {
+        /// Exit with 2 if anything would change and 0 if nothing would, like terraform, so
+        /// that CI can fail when the targets drifted. Errors still exit with 1.
+        #[structopt(long)]
+        detailed_exit_code: bool,
+    },
+
+    /// Print whether every target is deployed as configured: OK, missing, modified on target,
+    /// a symlink to the wrong place, or orphaned (deployed, but no longer configured). Nothing
+    /// is changed. Exits with a failure if anything is out of sync, for CI and shell prompts.
+    Status,
+
+    /// Print a summary of this machine: its name, the version of dotter, the enabled packages,
+    /// when it was last deployed and how many targets drifted from the configuration. With
+    /// `--output json` it's a single `report` event, for auditing many machines with tooling
+    /// that collects it. Nothing is sent anywhere.
+    Report {
+        /// Same as the `--output` that goes before the subcommand
+        #[structopt(long, possible_values = &["text", "json"])]
+        output: Option<OutputMode>,
+    },
+
+    /// Print the drift of this machine as a plugin of a menu bar or tray host like xbar,
+    /// SwiftBar or Argos, with items that deploy and show the diff. dotter has no tray icon of
+    /// its own, the host shows it: install a plugin that runs `cd <repository> && dotter tray`.
+    /// Needs dotter to be built with the `tray` feature.
+    Tray,
+
+    /// Deploy the repository to several machines, each with its own local configuration and
+    /// variables, and print whether each one succeeded and how many files changed
+    Fleet {
+        #[structopt(subcommand)]
+        action: FleetAction,
+    },
+
+    /// Enable or disable a package on this machine, by editing the packages in local.toml
+    Package {
+        #[structopt(subcommand)]
+        action: PackageAction,
+    },
+
+    /// Set or remove a variable of this machine in local.toml
+    Var {
+        #[structopt(subcommand)]
+        action: VariableAction,
+    },
+
+    /// Deploy a small repository into a scratch home directory, change it and undeploy it,
+    /// checking the files after every step, to see whether dotter works on this platform and
+    /// filesystem
+    Selftest {
+        /// Directory to create the scratch directory in, like one on the filesystem of the home
+        /// directory, instead of the temporary directory
+        #[structopt(long)]
+        directory: Option<PathBuf>,
+
+        /// Keep the scratch directory afterwards, to look at what went wrong
+        #[structopt(long)]
+        keep: bool,
+    },
+
+    /// Deploy the files of a package with `lazy = true` for real, which its shims do when one of
+    /// them is run for the first time
+    Activate {
+        /// Name of the package
+        package: String,
+    },
+
+    /// Deploy another profile of global.toml and make it the `profile` of local.toml. Only the
+    /// files that the new profile no longer wants are deleted, and the whole switch is rolled
+    /// back if any of it fails.
+    SwitchProfile {
+        /// Name of the profile
+        name: String,
+    },
+
+    /// Rewrite the configuration and the cache from the format of an older dotter, keeping the
+    /// originals next to them with a `.bak` extension. Deploying does it too.
+    Migrate,
+
+    /// Move the targets that the cache recorded under an old home directory to a new one, and
+    /// point the deployed symlinks into the new one, after the home directory moved because of
+    /// a new username or mount point. Run it in the repository at its new location.
+    MigrateHome {
+        /// Home directory that the files were deployed to, like /home/old
+        old: PathBuf,
+
+        /// Home directory that it moved to, like /home/new
+        new: PathBuf,
+    },
+
+    // Hidden subcommands start with a single underscore, since clap's bash completions can't
+    // handle names that contain `__`
+    /// Run the changes that a dotter with --privileged-helper sends, as root
+    #[structopt(name = "_privileged_helper", setting = structopt::clap::AppSettings::Hidden)]
+    PrivilegedHelper,
+
+    /// Print the JSON Schema of global.toml or local.toml, for editors to complete and check
+    /// them with. Taplo reads it with a `#:schema <path>` comment at the top of the file.
+    Schema {
+        #[structopt(default_value = "global", possible_values = &["global", "local"])]
+        file: SchemaFile,
+    },
+
+    /// Print the completion script of a shell, like `dotter gen-completions bash >
+    /// /etc/bash_completion.d/dotter`. Besides the options, the scripts of bash, zsh and fish
+    /// complete the packages, profiles and sources of the repository in the current directory.
+    GenCompletions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+
+    /// Print the values that start with the given word, one per line, for shell completion
+    /// scripts to complete packages and files from the actual configuration
+    #[structopt(name = "_complete", setting = structopt::clap::AppSettings::Hidden)]
+    Complete {
+        /// What to complete
+        #[structopt(possible_values = &["packages", "profiles", "sources", "targets"])]
+        kind: CompletionKind,
+
+        /// The word being completed
+        #[structopt(default_value = "")]
+        current: String,
+    },
 }
 
 impl Default for Action {
-    fn default() -> Self {
-        Action::Deploy
+    fn default() -> Action {
+        Action::Deploy {
+            only: Vec::new(),
+            host: None,
+            sync: false,
+        }
     }
 }
 
+/// Edits of local.toml keep its comments and formatting, so they can be run by
+/// provisioning scripts on a hand-written configuration
+#[derive(Debug, Clone, StructOpt)]
+pub enum PackageAction {
+    /// Add a package from global.toml to the packages of this machine
+    Enable { name: String },
+
+    /// Remove a package from the packages of this machine
+    Disable { name: String },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum CacheAction {
+    /// Print every deployed file whose target is missing or was replaced, every template whose
+    /// cached render is missing, and every leftover entry
+    Verify,
+
+    /// Forget the files whose target is missing or was replaced, render the templates whose
+    /// cached render is missing again, and remove the leftover entries
+    Repair,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum StateAction {
+    /// Print the state: the deployed files, the hashes and renders of the templates, and the
+    /// index of the backups, which stay in the cache directory
+    Export {
+        #[structopt(long, default_value = "json", possible_values = &["json", "toml"])]
+        format: OutputFormat,
+
+        /// Write to this file instead of standard output
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Replace the cache with a state from `dotter state export`, in either format. States of
+    /// older dotters are migrated.
+    Import {
+        state: PathBuf,
+
+        /// Replace a cache that already records deployed files
+        #[structopt(long)]
+        replace: bool,
+    },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum FleetAction {
+    /// Deploy to every host, one after the other. Dry runs report the pending changes instead.
+    Deploy {
+        /// File that declares the hosts as tables, with an `ssh` destination and the `directory`
+        /// of the repository for remote ones, a `local_config` and `variables`
+        #[structopt(long, default_value = ".dotter/hosts.toml")]
+        hosts: PathBuf,
+
+        /// Only deploy to these hosts
+        #[structopt(long)]
+        only: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum VariableAction {
+    /// Set a top-level variable. Values that are valid TOML, like `42`, `true` or `[1, 2]`,
+    /// are stored as such, anything else is stored as a string.
+    Set {
+        name: String,
+        value: String,
+
+        /// Deploy the templates that read the variable right away, and nothing else, so that
+        /// a theme switcher can change a color and see it applied
+        #[structopt(long)]
+        deploy_affected: bool,
+    },
+
+    /// Remove a variable that was set in local.toml
+    Unset { name: String },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SchemaFile {
+    Global,
+    Local,
+}
+
+impl std::str::FromStr for SchemaFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(SchemaFile::Global),
+            "local" => Ok(SchemaFile::Local),
+            other => Err(format!("unknown configuration file {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Json,
+    Toml,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "toml" => Ok(OutputFormat::Toml),
+            other => Err(format!("unknown format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    Keep,
+    Adopt,
+    /// From --interactive
+    Ask,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep" => Ok(ConflictPolicy::Keep),
+            "adopt" => Ok(ConflictPolicy::Adopt),
+            other => Err(format!("unknown policy {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputMode::Text),
+            "json" => Ok(OutputMode::Json),
+            other => Err(format!("unknown output {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            other => Err(format!("unknown format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitialDeploy {
+    Deploy,
+    DryRun,
+    Skip,
+}
+
+impl std::str::FromStr for InitialDeploy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deploy" => Ok(InitialDeploy::Deploy),
+            "dry-run" => Ok(InitialDeploy::DryRun),
+            "skip" => Ok(InitialDeploy::Skip),
+            other => Err(format!("unknown initial action {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Nix,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nix" => Ok(ExportFormat::Nix),
+            other => Err(format!("unknown format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CompletionKind {
+    Packages,
+    Profiles,
+    Sources,
+    Targets,
+}
+
+impl std::str::FromStr for CompletionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "packages" => Ok(CompletionKind::Packages),
+            "profiles" => Ok(CompletionKind::Profiles),
+            "sources" => Ok(CompletionKind::Sources),
+            "targets" => Ok(CompletionKind::Targets),
+            other => Err(format!("unknown completion kind {:?}", other)),
+        }
+    }
+}
+
+impl Default for Options {
+    /// The options of running `dotter` without arguments
+    fn default() -> Options {
+        Options::from_iter(&["dotter"])
+    }
+}
+
+/// Names that the hidden subcommands had before, which completion scripts and sudoers rules may
+/// still use. They can't be aliases, since clap's bash completions can't handle them either.
+const OLD_SUBCOMMAND_NAMES: &[(&str, &str)] = &[
+    ("__complete", "_complete"),
+    ("__privileged_helper", "_privileged_helper"),
+];
+
+/// Renames the subcommand if it has an old name. Only an argument that is where clap expects
+/// the subcommand is renamed, which is the one whose renaming makes the arguments parse, so that
+/// values like the one of `dotter var set x __complete` stay as they are.
+fn rename_old_subcommand(args: Vec<OsString>) -> Vec<OsString> {
+    if Options::from_iter_safe(&args).is_ok() {
+        return args;
+    }
+    for (index, arg) in args.iter().enumerate().skip(1) {
+        if let Some((_, new)) = OLD_SUBCOMMAND_NAMES.iter().find(|(old, _)| arg == *old) {
+            let mut renamed = args.clone();
+            renamed[index] = new.into();
+            if Options::from_iter_safe(&renamed).is_ok() {
+                return renamed;
+            }
+        }
+    }
+    args
+}
+
 pub fn get_options() -> Options {
-    let mut opt = Options::from_args();
-    if opt.force {
+    let mut opt = Options::from_iter(rename_old_subcommand(std::env::args_os().collect()));
+    if opt.force.is_some() {
         opt.act = true;
     }
-    if !opt.act {
+    if !opt.act && !opt.summary_only {
         opt.verbosity = std::cmp::max(opt.verbosity, 1);
     }
     opt.verbosity = std::cmp::min(3, opt.verbosity);
-    if opt.patch {
+    if std::env::var("DOTTER_NON_INTERACTIVE").is_ok_and(|v| v == "1") {
+        opt.non_interactive = true;
+    }
+    if opt.patch || opt.non_interactive {
         opt.interactive = false;
     }
+    if opt.non_interactive {
+        opt.ask_conflicts = false;
+    }
+    // All of stdin is the patch, there's nothing left to answer with
+    if opt.ask_conflicts && !opt.patch {
+        opt.on_conflict = ConflictPolicy::Ask;
+    }
+    if let Some(Action::Report {
+        output: Some(output),
+    }) = opt.action
+    {
+        opt.output = output;
+    }
     opt
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rename_old_subcommand() {
+        let rename = |args: &[&str]| {
+            rename_old_subcommand(args.iter().map(OsString::from).collect())
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rename(&["dotter", "-v", "__complete", "packages"]),
+            ["dotter", "-v", "_complete", "packages"]
+        );
+        assert_eq!(
+            rename(&[
+                "dotter",
+                "--profile",
+                "__complete",
+                "__complete",
+                "packages"
+            ]),
+            ["dotter", "--profile", "__complete", "_complete", "packages"]
+        );
+        assert_eq!(
+            rename(&["dotter", "var", "set", "x", "__complete"]),
+            ["dotter", "var", "set", "x", "__complete"]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use args::Options;
+use config;
+use filesystem;
+
+/// Directory inside the cache where the files that a deploy overwrites are kept, in a
+/// subdirectory per deploy named after its time
+const BACKUP_DIRECTORY: &str = ".dotter_backups";
+
+/// A regular file that was in the way of a target and was overwritten or deleted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub target: PathBuf,
+    /// Copy of the file's contents inside the cache directory
+    pub backup: PathBuf,
+    /// When it was backed up, in RFC 3339
+    pub time: String,
+}
+
+/// The deploy that is running, and the backups it made
+struct Run {
+    directory: PathBuf,
+    time: chrono::DateTime<chrono::Local>,
+    backups: Vec<Backup>,
+}
+
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+/// Starts backing up the files that this deploy overwrites into the cache directory
+pub fn start(cache_directory: &Path) {
+    let time = chrono::Local::now();
+    let directory = cache_directory
+        .join(BACKUP_DIRECTORY)
+        .join(time.format("%Y%m%d-%H%M%S").to_string());
+    *RUN.lock().unwrap_or_else(|e| e.into_inner()) = Some(Run {
+        directory,
+        time,
+        backups: Vec::new(),
+    });
+}
+
+/// Returns the backups that were made since `start`, to be recorded in the cache
+pub fn finish() -> Vec<Backup> {
+    RUN.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .map(|run| run.backups)
+        .unwrap_or_default()
+}
+
+/// Copies `target` into the backups of this deploy if it's a regular file, before it's
+/// overwritten or deleted. Symlinks and missing targets have nothing worth keeping.
+pub fn back_up(target: &Path) -> Result<()> {
+    match fs::symlink_metadata(target) {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("inspect target"),
+    }
+
+    let mut run = RUN.lock().unwrap_or_else(|e| e.into_inner());
+    let run = run.as_mut().expect("backups were started");
+    fs::create_dir_all(&run.directory).context("create backup directory")?;
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // Deploys in the same second share the directory
+    let backup = (run.backups.len()..)
+        .map(|index| run.directory.join(format!("{}-{}", index, name)))
+        .find(|backup| !backup.exists())
+        .expect("unused backup name");
+    fs::copy(target, &backup).with_context(|| format!("back up {:?}", target))?;
+    info!("Backed up {:?} to {:?}", target, backup);
+    run.backups.push(Backup {
+        target: target.into(),
+        backup,
+        time: run.time.to_rfc3339(),
+    });
+    Ok(())
+}
+
+/// Without a target, prints every backup. With one, puts its latest backup back in place of
+/// whatever is there now and forgets the backup.
+pub fn restore(opt: &Options, target: Option<&Path>) -> Result<()> {
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let mut cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+
+    let target = match target {
+        Some(target) => absolute_target(target)?,
+        None => {
+            if cache.backups.is_empty() {
+                println!("There are no backups");
+            }
+            for backup in &cache.backups {
+                println!(
+                    "{}  {:?}  ({:?})",
+                    backup.time, backup.target, backup.backup
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    let index = cache
+        .backups
+        .iter()
+        .rposition(|backup| backup.target == target)
+        .with_context(|| format!("there is no backup of {:?}", target))?;
+    let backup = cache.backups[index].clone();
+    if !opt.act {
+        println!("Would restore {:?} from {}", target, backup.time);
+        return Ok(());
+    }
+
+    match fs::symlink_metadata(&target) {
+        Ok(metadata) if metadata.is_dir() => bail!("{:?} is a directory now", target),
+        Ok(_) => fs::remove_file(&target).context("remove current target")?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).context("create parent of target")?;
+            }
+        }
+        Err(e) => return Err(e).context("inspect current target"),
+    }
+    fs::copy(&backup.backup, &target).context("copy backup to target")?;
+    cache.backups.remove(index);
+    config::save_cache(&opt.cache_file, cache)?;
+    fs::remove_file(&backup.backup).context("remove restored backup")?;
+
+    println!("Restored {:?} from {}", target, backup.time);
+    warn!(
+        "The next deploy treats the restored file as changed, and only replaces it with --force."
+    );
+    Ok(())
+}
+
+/// Targets are recorded with `~` expanded and as absolute paths
+fn absolute_target(target: &Path) -> Result<PathBuf> {
+    let target = PathBuf::from(filesystem::expand_tilde(&target.to_string_lossy()));
+    if target.is_absolute() {
+        return Ok(target);
+    }
+    Ok(std::env::current_dir()
+        .context("get current directory")?
+        .join(target))
+}
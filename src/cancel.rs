@@ -0,0 +1,26 @@
+//! Cancelling a deploy from another thread, for programs that embed dotter: a GUI's cancel
+//! button or a server that shuts down. The deploy stops between files, so no file is left
+//! half-written, and a transactional deploy is rolled back.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag that asks a deploy to stop. Clones share it, so a clone can be handed to the
+/// thread that cancels while the deploy runs with the original.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Asks the deploys that use this token to stop at the next file
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,255 @@
+//! What the filesystems of the targets support, probed once per filesystem by creating a
+//! file next to the first target on it, so that a filesystem that can't hold a symlink or a
+//! hardlink (like FAT or some network shares) fails with an explanation instead of an OS error
+//! code. The results are recorded in the cache, so later deploys don't probe again.
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use filesystem;
+
+/// Whether a filesystem can hold something
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Support {
+    Supported,
+    Unsupported,
+    /// The filesystem might hold it, but the user isn't allowed to create it, like symlinks on
+    /// Windows without Developer Mode. Never recorded, since it depends on the user.
+    NotPermitted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capabilities {
+    pub symlinks: Support,
+    pub hardlinks: Support,
+    /// Extended attributes, only probed on Linux and macOS
+    pub xattrs: Support,
+}
+
+/// Capabilities of the filesystems of the targets, by `filesystem_key`, as they're recorded in
+/// the cache so that they aren't probed again by every deploy
+pub type ProbedCapabilities = BTreeMap<String, Capabilities>;
+
+/// Capabilities of every filesystem that was probed, by `filesystem_key`, and whether they were
+/// probed by this run rather than recorded by an earlier one
+static PROBED: Mutex<BTreeMap<String, (Capabilities, bool)>> = Mutex::new(BTreeMap::new());
+
+/// Uses the capabilities that earlier runs recorded instead of probing those filesystems again
+pub fn remember(recorded: &ProbedCapabilities) {
+    let mut probed = PROBED.lock().unwrap_or_else(|e| e.into_inner());
+    for (key, capabilities) in recorded {
+        probed.entry(key.clone()).or_insert((*capabilities, false));
+    }
+}
+
+/// Capabilities to record in the cache: the remembered ones and the ones that were probed
+pub fn recorded() -> ProbedCapabilities {
+    PROBED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|(_, (capabilities, _))| capabilities.symlinks != Support::NotPermitted)
+        .map(|(key, (capabilities, _))| (key.clone(), *capabilities))
+        .collect()
+}
+
+/// Fails with an explanation if `target` is on a filesystem that can't hold symlinks, or if
+/// the user isn't allowed to create them
+pub fn ensure_symlinks(target: &Path) -> Result<()> {
+    match probe(target, |capabilities| capabilities.symlinks)? {
+        Some(Support::Unsupported) => bail!(
+            "{:?} is on a filesystem that doesn't support symlinks. Deploy it with `type = \"copy\"` instead.",
+            target
+        ),
+        Some(Support::NotPermitted) => bail!(
+            "{:?} can't be a symlink, since this user isn't allowed to create symlinks. Turn on Developer Mode in the Windows settings, or deploy it with `type = \"copy\"` instead.",
+            target
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with an explanation if `target` is on a filesystem that can't hold hardlinks
+pub fn ensure_hardlinks(target: &Path) -> Result<()> {
+    match probe(target, |capabilities| capabilities.hardlinks)? {
+        Some(Support::Unsupported) => bail!(
+            "{:?} is on a filesystem that doesn't support hardlinks. Deploy it with `type = \"copy\"` instead.",
+            target
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the filesystem of `target` supports what `support` picks, or `None` if that can't
+/// be found out, like when its directory isn't writable. A recorded lack of support is probed
+/// again, since the filesystem may have been replaced by another one with the same key.
+fn probe(target: &Path, support: fn(&Capabilities) -> Support) -> Result<Option<Support>> {
+    let directory = match existing_ancestor(target) {
+        Some(directory) => directory,
+        None => return Ok(None),
+    };
+    let key = filesystem_key(&directory)
+        .with_context(|| format!("identify filesystem of {:?}", directory))?;
+    let mut probed = PROBED.lock().unwrap_or_else(|e| e.into_inner());
+    match probed.get(&key) {
+        Some((capabilities, fresh)) if *fresh || support(capabilities) == Support::Supported => {
+            return Ok(Some(support(capabilities)))
+        }
+        _ => {}
+    }
+
+    let capabilities = match probe_directory(&directory) {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            debug!("Cannot probe the filesystem of {:?}: {}", directory, e);
+            return Ok(None);
+        }
+    };
+    debug!("Filesystem of {:?} supports {:?}", directory, capabilities);
+    probed.insert(key, (capabilities, true));
+    Ok(Some(support(&capabilities)))
+}
+
+/// Creates a file in `directory`, and tries to link to it and to give it an extended attribute
+fn probe_directory(directory: &Path) -> io::Result<Capabilities> {
+    let file = directory.join(format!(".dotter_probe_{}", std::process::id()));
+    fs::write(&file, "")?;
+    let link = directory.join(format!(".dotter_probe_{}_link", std::process::id()));
+    let symlinks = match filesystem::make_symlink(&link, &file) {
+        Ok(()) => Support::Supported,
+        Err(e) if is_privilege_not_held(&e) => Support::NotPermitted,
+        Err(_) => Support::Unsupported,
+    };
+    let _ = fs::remove_file(&link);
+    let hardlinks = supported(filesystem::make_hardlink(&link, &file).is_ok());
+    let _ = fs::remove_file(&link);
+    let xattrs = supported(set_xattr(&file).is_ok());
+    let _ = fs::remove_file(&file);
+    Ok(Capabilities {
+        symlinks,
+        hardlinks,
+        xattrs,
+    })
+}
+
+fn supported(supported: bool) -> Support {
+    if supported {
+        Support::Supported
+    } else {
+        Support::Unsupported
+    }
+}
+
+/// Windows only lets users with Developer Mode or administrators create symlinks, and tells
+/// the others with `ERROR_PRIVILEGE_NOT_HELD`
+fn is_privilege_not_held(error: &anyhow::Error) -> bool {
+    const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+    cfg!(windows)
+        && error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD))
+        })
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_xattr(path: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new("user.dotter_probe")?;
+    let value = b"1";
+    #[cfg(target_os = "linux")]
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_xattr(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "extended attributes aren't probed on this platform",
+    ))
+}
+
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Tells filesystems apart by their device
+#[cfg(unix)]
+fn filesystem_key(directory: &Path) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(directory)?.dev().to_string())
+}
+
+/// Tells filesystems apart by their drive, which misses the ones mounted into a directory
+#[cfg(not(unix))]
+fn filesystem_key(directory: &Path) -> Result<String> {
+    let absolute = std::env::current_dir()?.join(directory);
+    Ok(absolute
+        .components()
+        .next()
+        .map(|root| root.as_os_str().to_string_lossy().into_owned())
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let capabilities = probe_directory(dir.path()).unwrap();
+        assert_eq!(capabilities.symlinks, Support::Supported);
+        assert_eq!(capabilities.hardlinks, Support::Supported);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // A recorded lack of support is probed again before it fails a deploy
+        let key = filesystem_key(dir.path()).unwrap();
+        let unsupported = Capabilities {
+            symlinks: Support::Unsupported,
+            hardlinks: Support::Unsupported,
+            xattrs: Support::Unsupported,
+        };
+        remember(&vec![(key.clone(), unsupported)].into_iter().collect());
+        ensure_symlinks(&dir.path().join("target")).unwrap();
+        assert_eq!(recorded()[&key].symlinks, Support::Supported);
+    }
+}
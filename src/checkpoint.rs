@@ -0,0 +1,380 @@
+use anyhow::{Context, Result};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use backup;
+use config::{self, Cache};
+use filesystem;
+use secrets;
+
+/// Directory inside the cache where every checkpoint has a subdirectory with its name
+const CHECKPOINT_DIRECTORY: &str = ".dotter_checkpoints";
+
+/// manifest.json, next to the cache as it was (cache.toml) and the copies of the files
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// When it was taken, in RFC 3339
+    time: String,
+    /// File inside the checkpoint with the cached render of every template, by source
+    renders: BTreeMap<PathBuf, String>,
+    targets: Vec<Target>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Target {
+    target: PathBuf,
+    state: State,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    Missing,
+    /// A regular file, copied into the checkpoint under this name
+    File(String),
+    /// A symlink, with what it pointed at
+    Symlink(PathBuf),
+    /// A deployed template that contains a secret, of which only the hash is kept, like in the
+    /// render cache. It's left alone when it's unchanged, and otherwise needs a deploy.
+    Secret(String),
+}
+
+/// Without a name, prints every checkpoint. With one, records what every deployed target is
+/// now, along with the cache and the cached renders, so that `revert_to` can put them back.
+pub fn checkpoint(opt: &Options, name: Option<&str>) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => return list(opt),
+    };
+    let directory = checkpoint_directory(opt, name)?;
+    if directory.exists() {
+        bail!(
+            "checkpoint {:?} exists already. Remove {:?} to take it again.",
+            name,
+            directory
+        );
+    }
+
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let cache = config::load_cache(&opt.cache_file)?
+        .context("load cache: Cannot take a checkpoint without a cache, nothing is deployed.")?;
+    if !opt.act {
+        println!(
+            "Would take checkpoint {:?} of {} targets",
+            name,
+            deployed_targets(&cache).len()
+        );
+        return Ok(());
+    }
+
+    let taken = take(opt, &directory, cache);
+    if taken.is_err() {
+        // A partial checkpoint would revert to something that never was
+        let _ = fs::remove_dir_all(&directory);
+    }
+    println!("Took checkpoint {:?} of {} targets", name, taken?);
+    Ok(())
+}
+
+/// Returns how many targets were recorded
+fn take(opt: &Options, directory: &Path, cache: Cache) -> Result<usize> {
+    let files = directory.join("files");
+    fs::create_dir_all(&files).context("create checkpoint directory")?;
+    let mut manifest = Manifest {
+        time: chrono::Local::now().to_rfc3339(),
+        renders: BTreeMap::new(),
+        targets: Vec::new(),
+    };
+    // The renders of templates with secrets are hashes already, see `secrets::cached_render`
+    let mut secret_targets = BTreeSet::new();
+    for (index, (source, target)) in cache.templates.iter().enumerate() {
+        let render = cache.render_path(source, &opt.cache_directory);
+        if !render.is_file() {
+            continue;
+        }
+        let file = format!("render-{}", index);
+        fs::copy(&render, files.join(&file))
+            .with_context(|| format!("copy cached render of {:?}", source))?;
+        manifest.renders.insert(source.clone(), file);
+        let contents =
+            fs::read(&render).with_context(|| format!("read cached render of {:?}", source))?;
+        if contents.starts_with(secrets::HASH_PREFIX.as_bytes()) {
+            secret_targets.insert(target.clone());
+        }
+    }
+    for (index, target) in deployed_targets(&cache).into_iter().enumerate() {
+        let state = match fs::symlink_metadata(&target) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::Missing,
+            Err(e) => return Err(e).with_context(|| format!("inspect {:?}", target)),
+            Ok(metadata) if metadata.file_type().is_symlink() => State::Symlink(
+                fs::read_link(&target).with_context(|| format!("read symlink {:?}", target))?,
+            ),
+            Ok(metadata) if metadata.is_dir() => {
+                warn!("Target {:?} is a directory, leaving it out", target);
+                continue;
+            }
+            Ok(_) if secret_targets.contains(&target) => State::Secret(secrets::hash_marker(
+                &fs::read(&target).with_context(|| format!("read {:?}", target))?,
+            )),
+            Ok(_) => {
+                let file = format!("target-{}", index);
+                fs::copy(&target, files.join(&file))
+                    .with_context(|| format!("copy {:?}", target))?;
+                State::File(file)
+            }
+        };
+        manifest.targets.push(Target { target, state });
+    }
+
+    config::save_cache(&directory.join("cache.toml"), cache).context("save cache")?;
+    let serialized = serde_json::to_string_pretty(&manifest).context("serialize manifest")?;
+    fs::write(directory.join("manifest.json"), serialized).context("save manifest")?;
+    Ok(manifest.targets.len())
+}
+
+/// Puts every target of the checkpoint back the way it was, deletes the targets that were
+/// deployed since, and restores the cache so that dotter knows what's deployed. Regular files
+/// that get replaced are backed up like during a deploy. Reverting continues past failures,
+/// and fails at the end if any target couldn't be reverted.
+pub fn revert_to(opt: &Options, name: &str) -> Result<()> {
+    let directory = checkpoint_directory(opt, name)?;
+    let manifest =
+        load_manifest(&directory).with_context(|| format!("load checkpoint {:?}", name))?;
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let mut reverted =
+        config::load_cache(&directory.join("cache.toml"))?.context("load cache of checkpoint")?;
+    let current = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+
+    let mut targets = manifest
+        .targets
+        .iter()
+        .map(|target| (target.target.clone(), target.state.clone()))
+        .collect::<BTreeMap<_, _>>();
+    for target in deployed_targets(&current) {
+        targets.entry(target).or_insert(State::Missing);
+    }
+
+    let files = directory.join("files");
+    backup::start(&opt.cache_directory);
+    let mut changed = 0;
+    let mut failed = Vec::new();
+    for (target, state) in &targets {
+        match is_reverted(target, state, &files) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to inspect {:?}: {:#}", target, e);
+                failed.push(target.clone());
+                continue;
+            }
+        }
+        changed += 1;
+        if !opt.act {
+            println!("Would revert {:?}", target);
+            continue;
+        }
+        info!("Reverting {:?}", target);
+        if let Err(e) = revert_target(target, state, &files) {
+            error!("Failed to revert {:?}: {:#}", target, e);
+            failed.push(target.clone());
+        }
+    }
+
+    if opt.act {
+        for (source, file) in &manifest.renders {
+            let render = reverted.render_path(source, &opt.cache_directory);
+            if let Some(parent) = render.parent() {
+                fs::create_dir_all(parent).context("create parent of cached render")?;
+            }
+            fs::copy(files.join(file), &render)
+                .with_context(|| format!("restore cached render of {:?}", source))?;
+        }
+        // Backups are of files that aren't deployed, they outlive the checkpoint
+        reverted.backups = current.backups;
+        reverted.backups.extend(backup::finish());
+        config::save_cache(&opt.cache_file, reverted)?;
+    } else {
+        backup::finish();
+    }
+
+    if !failed.is_empty() {
+        bail!("couldn't revert {} targets: {:?}", failed.len(), failed);
+    }
+    if opt.act {
+        println!(
+            "Reverted {} targets to checkpoint {:?} from {}",
+            changed, name, manifest.time
+        );
+        warn!("The next deploy deploys the repository as it is now again.");
+    }
+    Ok(())
+}
+
+fn list(opt: &Options) -> Result<()> {
+    let checkpoints = match fs::read_dir(opt.cache_directory.join(CHECKPOINT_DIRECTORY)) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<BTreeSet<_>, _>>()
+            .context("read checkpoint directory")?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+        Err(e) => return Err(e).context("read checkpoint directory"),
+    };
+    if checkpoints.is_empty() {
+        println!("There are no checkpoints");
+    }
+    for directory in checkpoints {
+        let name = directory
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match load_manifest(&directory) {
+            Ok(manifest) => println!(
+                "{}  {}  ({} targets)",
+                manifest.time,
+                name,
+                manifest.targets.len()
+            ),
+            Err(e) => warn!("Checkpoint {:?} is unreadable: {:#}", name, e),
+        }
+    }
+    Ok(())
+}
+
+fn load_manifest(directory: &Path) -> Result<Manifest> {
+    let manifest = fs::read_to_string(directory.join("manifest.json")).context("read manifest")?;
+    serde_json::from_str(&manifest).context("parse manifest")
+}
+
+fn checkpoint_directory(opt: &Options, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        bail!("{:?} isn't a valid checkpoint name", name);
+    }
+    Ok(opt.cache_directory.join(CHECKPOINT_DIRECTORY).join(name))
+}
+
+fn deployed_targets(cache: &Cache) -> BTreeSet<PathBuf> {
+    cache
+        .symlinks
+        .values()
+        .chain(cache.templates.values())
+        .chain(cache.copies.values())
+        .cloned()
+        .collect()
+}
+
+/// Whether `target` is in `state` already, so that reverting leaves it alone
+fn is_reverted(target: &Path, state: &State, files: &Path) -> Result<bool> {
+    let metadata = match fs::symlink_metadata(target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(*state == State::Missing),
+        Err(e) => return Err(e).context("inspect target"),
+    };
+    Ok(match state {
+        State::Missing => false,
+        State::Symlink(pointee) => {
+            metadata.file_type().is_symlink()
+                && &fs::read_link(target).context("read symlink")? == pointee
+        }
+        State::File(file) => {
+            metadata.is_file()
+                && fs::read(target).context("read target")?
+                    == fs::read(files.join(file)).context("read file from checkpoint")?
+        }
+        State::Secret(marker) => {
+            metadata.is_file()
+                && secrets::matches_hash_marker(
+                    marker,
+                    &String::from_utf8_lossy(&fs::read(target).context("read target")?),
+                ) == Some(true)
+        }
+    })
+}
+
+fn revert_target(target: &Path, state: &State, files: &Path) -> Result<()> {
+    if let State::Secret(_) = state {
+        bail!("it contains a secret, of which the checkpoint only kept the hash. Deploy to render it again.");
+    }
+    backup::back_up(target)?;
+    match fs::symlink_metadata(target) {
+        Ok(_) => fs::remove_file(target).context("remove target")?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("inspect target"),
+    }
+    match state {
+        State::Missing => {
+            filesystem::delete_parents(target, false).context("delete empty parents")?
+        }
+        State::File(file) => {
+            create_parent(target)?;
+            fs::copy(files.join(file), target).context("copy file from checkpoint")?;
+        }
+        State::Symlink(pointee) => {
+            create_parent(target)?;
+            filesystem::make_symlink(target, pointee).context("create symlink")?;
+        }
+        State::Secret(_) => unreachable!("secrets aren't kept"),
+    }
+    Ok(())
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    fs::create_dir_all(path.parent().context("get parent of target")?).context("create parent")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_keeps_only_hash_of_secrets() {
+        let home = tempfile::tempdir().unwrap();
+        let cache_directory = tempfile::tempdir().unwrap();
+        let directory = cache_directory.path().join("checkpoint");
+        let secret = home.path().join("netrc");
+        let plain = home.path().join("gitconfig");
+        fs::write(&secret, "password hunter2\n").unwrap();
+        fs::write(&plain, "[user]\n").unwrap();
+
+        let mut cache = Cache::default();
+        cache.templates.insert("netrc".into(), secret.clone());
+        cache.templates.insert("gitconfig".into(), plain.clone());
+        fs::write(
+            cache_directory.path().join("netrc"),
+            secrets::hash_marker(b"password hunter2\n"),
+        )
+        .unwrap();
+        fs::write(cache_directory.path().join("gitconfig"), "[user]\n").unwrap();
+
+        let opt = Options {
+            cache_directory: cache_directory.path().into(),
+            ..Options::default()
+        };
+        assert_eq!(take(&opt, &directory, cache).unwrap(), 2);
+        let manifest = load_manifest(&directory).unwrap();
+        for entry in fs::read_dir(directory.join("files")).unwrap() {
+            let contents = fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!contents.contains("hunter2"));
+        }
+        let states = manifest
+            .targets
+            .iter()
+            .map(|target| (target.target.clone(), target.state.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let files = directory.join("files");
+        assert!(matches!(states[&secret], State::Secret(_)));
+        assert!(matches!(states[&plain], State::File(_)));
+
+        // The secret is left alone while it's unchanged, and isn't replaced by its hash after
+        assert!(is_reverted(&secret, &states[&secret], &files).unwrap());
+        fs::write(&secret, "password hunter3\n").unwrap();
+        assert!(!is_reverted(&secret, &states[&secret], &files).unwrap());
+        assert!(revert_target(&secret, &states[&secret], &files).is_err());
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "password hunter3\n");
+    }
+}
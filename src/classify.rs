@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config::{Configuration, FileTarget, Variables};
+use deploy::{self, Detection};
+use filesystem;
+use warnings;
+
+/// Longest excerpt of the line where `{{` was found that is printed
+const EXCERPT_LENGTH: usize = 60;
+
+/// Prints whether every file is deployed as a template or as a symlink, and why.
+/// Files whose explicit type disagrees with what detection would have chosen are highlighted,
+/// since literal braces in a file that should be a symlink are easy to miss.
+pub fn classify(opt: &Options) -> Result<()> {
+    let configuration = deploy::load_configuration(opt).context("get a configuration")?;
+    let symlinks_enabled = filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
+        .context("check whether symlinks are enabled")?;
+
+    for (source, package) in &configuration.missing_sources {
+        let package = package.as_deref().unwrap_or("(no package)");
+        println!(
+            "{:?}: {} (in package {})",
+            source,
+            "missing source".red(),
+            package
+        );
+    }
+
+    for (source, target) in &configuration.files {
+        let detection = deploy::detect_template(source)
+            .with_context(|| format!("check whether {:?} is a template", source))?;
+        let (kind, reason, surprising) = match target {
+            FileTarget::Automatic(_) if !symlinks_enabled => (
+                "template",
+                "symlinks aren't available, so files are copied".to_string(),
+                false,
+            ),
+            FileTarget::Automatic(_) => match &detection {
+                Detection::Braces { .. } => ("template", describe(&detection), false),
+                _ => ("symlink", describe(&detection), false),
+            },
+            FileTarget::Symbolic(_) if !symlinks_enabled => (
+                "template",
+                "symlinks aren't available, so files are copied".to_string(),
+                false,
+            ),
+            FileTarget::Symbolic(_) => (
+                "symlink",
+                format!("type = \"symbolic\", {}", describe(&detection)),
+                matches!(detection, Detection::Braces { .. }),
+            ),
+            FileTarget::ComplexTemplate(target) if target.write_once => (
+                "write once",
+                "type = \"template\" with write_once = true, unmanaged after the first deploy"
+                    .to_string(),
+                false,
+            ),
+            FileTarget::ComplexTemplate(_) => (
+                "template",
+                format!("type = \"template\", {}", describe(&detection)),
+                !matches!(detection, Detection::Braces { .. }),
+            ),
+            FileTarget::Copy(target) if target.write_once => (
+                "write once",
+                "type = \"copy\" with write_once = true, unmanaged after the first deploy"
+                    .to_string(),
+                false,
+            ),
+            FileTarget::Copy(_) => ("copy", "type = \"copy\"".to_string(), false),
+            FileTarget::Fragment(fragment) if fragment.template => (
+                "template",
+                format!(
+                    "fragment of {:?}, {}",
+                    fragment.target,
+                    describe(&detection)
+                ),
+                false,
+            ),
+            FileTarget::Fragment(fragment) => (
+                "verbatim",
+                format!("fragment of {:?} with template = false", fragment.target),
+                false,
+            ),
+        };
+        print_file(source, target.path(), kind, &reason, surprising);
+    }
+
+    Ok(())
+}
+
+fn print_file(source: &Path, target: &Path, kind: &str, reason: &str, surprising: bool) {
+    let line = format!("{:?} -> {:?}: {} ({})", source, target, kind, reason);
+    if surprising {
+        println!("{}", line.yellow());
+    } else {
+        println!("{}", line);
+    }
+}
+
+fn describe(detection: &Detection) -> String {
+    match detection {
+        Detection::Braces { line, text } => {
+            let mut excerpt = text.chars().take(EXCERPT_LENGTH).collect::<String>();
+            if excerpt.len() < text.len() {
+                excerpt.push_str("...");
+            }
+            format!("contains `{{{{` on line {}: {}", line, excerpt)
+        }
+        Detection::NoBraces => "doesn't contain `{{`".into(),
+        Detection::NotUtf8 => "isn't valid UTF-8".into(),
+    }
+}
+
+/// Warns about the symbolic files whose source reads known variables with `{{ }}`, which were
+/// probably meant to be templates. Files with `literal_braces = true` are left out.
+pub fn warn_symlinked_templates(config: &Configuration) {
+    for (source, target) in &config.files {
+        let target = match target {
+            FileTarget::Symbolic(target) if !target.literal_braces && !target.recursive => target,
+            _ => continue,
+        };
+        let contents = match fs::read_to_string(source) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        if let Some((line, variable)) = variable_reference(&contents, &config.variables) {
+            warnings::warn(
+                "symlinked-template",
+                source.display(),
+                format!(
+                    "{:?} is deployed as a symlink to {:?}, but line {} reads the variable {:?}. Make it a template with `type = \"template\"`, or set `literal_braces = true` to silence this.",
+                    source, target.target, line, variable
+                ),
+            );
+        }
+    }
+}
+
+/// The first line where the contents of `{{ }}` mention one of `variables` or `dotter`,
+/// and the variable
+fn variable_reference(contents: &str, variables: &Variables) -> Option<(usize, String)> {
+    for (index, line) in contents.lines().enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find("{{") {
+            let inner = &rest[start + 2..];
+            let end = inner.find("}}").unwrap_or(inner.len());
+            let words = inner[..end].split(|c: char| c.is_whitespace() || "()=".contains(c));
+            for word in words {
+                let name = word
+                    .trim_start_matches(|c| "{#/~^>&".contains(c))
+                    .split(['.', '['])
+                    .next()
+                    .unwrap_or_default();
+                if !name.is_empty() && (name == "dotter" || variables.contains_key(name)) {
+                    return Some((index + 1, name.to_string()));
+                }
+            }
+            rest = &inner[end..];
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variable_reference() {
+        let mut variables = Variables::new();
+        variables.insert("email".into(), "me@example.com".into());
+        variables.insert("font".into(), toml::Value::Table(Default::default()));
+
+        assert_eq!(
+            variable_reference("[user]\n  email = {{ email }}\n", &variables),
+            Some((2, "email".into()))
+        );
+        assert_eq!(
+            variable_reference("{{#if font.size}}size{{/if}}", &variables),
+            Some((1, "font".into()))
+        );
+        assert_eq!(
+            variable_reference("{{~dotter.os~}}", &variables),
+            Some((1, "dotter".into()))
+        );
+        assert_eq!(
+            variable_reference("set -g status-left '{{ unknown }}'", &variables),
+            None
+        );
+        assert_eq!(
+            variable_reference("email = me@example.com", &variables),
+            None
+        );
+    }
+}
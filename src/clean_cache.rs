@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use args::Options;
+use config;
+use filesystem;
+
+pub fn clean_cache(opt: &Options, package: Option<&str>) -> Result<()> {
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let package = match package {
+        Some(package) => package,
+        None => {
+            info!("Emptying entire cache...");
+            if opt.act {
+                empty_cache(&opt.cache_file, &opt.cache_directory)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let mut cache = match config::load_cache(&opt.cache_file)? {
+        Some(cache) => cache,
+        None => {
+            warn!("Cache file not found. Nothing to clean.");
+            return Ok(());
+        }
+    };
+
+    let configuration = config::load_configuration(
+        &opt.local_config,
+        &opt.global_config,
+        &opt.cache_directory,
+        opt.profile.as_deref(),
+        opt.duplicate_targets,
+        None,
+    )
+    .context("get a configuration")?;
+    if !configuration.packages.iter().any(|p| p == package) {
+        bail!(
+            "package {:?} is not selected in local configuration",
+            package
+        );
+    }
+
+    for (source, _) in configuration
+        .file_packages
+        .iter()
+        .filter(|(_, p)| p.as_str() == package)
+    {
+        if let Some(target) = cache.symlinks.remove(source) {
+            info!("Forgetting symlink {:?} -> {:?}", source, target);
+        }
+        if let Some(target) = cache.copies.remove(source) {
+            info!("Forgetting copy {:?} -> {:?}", source, target);
+        }
+        cache.copy_hashes.remove(source);
+        cache.template_dependencies.remove(source);
+        let render = cache.render_path(source, &opt.cache_directory);
+        cache.render_caches.remove(source);
+        if let Some(target) = cache.templates.remove(source) {
+            info!("Forgetting template {:?} -> {:?}", source, target);
+            if opt.act {
+                remove_cached_render(&render)
+                    .with_context(|| format!("remove cached render of {:?}", source))?;
+            }
+        }
+    }
+
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+    }
+
+    Ok(())
+}
+
+pub fn empty_cache(cache_file: &Path, cache_directory: &Path) -> Result<()> {
+    config::save_cache(cache_file, config::Cache::default()).context("save empty cache file")?;
+    match fs::remove_dir_all(cache_directory) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+    .context("remove cache directory")?;
+
+    Ok(())
+}
+
+fn remove_cached_render(cache: &Path) -> Result<()> {
+    match fs::remove_file(cache) {
+        Ok(()) => {
+            filesystem::delete_parents(cache, false).context("delete parent directory in cache")
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context("remove cache file"),
+    }
+}
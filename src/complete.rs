@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+
+use std::path::PathBuf;
+
+use args::{CompletionKind, Options};
+use config;
+
+/// Prints the candidates that start with `current`.
+/// Files are taken from the cache rather than the configuration, because loading the
+/// configuration may fetch remote variables or ask for elevation, which is too slow for completion.
+pub fn complete(opt: &Options, kind: CompletionKind, current: &str) -> Result<()> {
+    let candidates = match kind {
+        CompletionKind::Packages => config::load_package_declarations(&opt.global_config)
+            .context("load packages")?
+            .into_iter()
+            .map(|declaration| declaration.name)
+            .collect(),
+        CompletionKind::Profiles => {
+            config::load_profile_names(&opt.global_config).context("load profiles")?
+        }
+        CompletionKind::Sources => cached_files(opt)?
+            .into_iter()
+            .map(|(source, _)| source.to_string_lossy().into_owned())
+            .collect(),
+        CompletionKind::Targets => cached_files(opt)?
+            .into_iter()
+            .map(|(_, target)| target.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+    };
+
+    for candidate in candidates {
+        if candidate.starts_with(current) {
+            println!("{}", candidate);
+        }
+    }
+
+    Ok(())
+}
+
+fn cached_files(opt: &Options) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    Ok(cache
+        .symlinks
+        .into_iter()
+        .chain(cache.templates)
+        .chain(cache.copies)
+        .collect())
+}
@@ -0,0 +1,77 @@
+//! Completion scripts for `dotter gen-completions`. The options and subcommands are completed
+//! by the scripts that clap generates, and the scripts of bash, zsh and fish also complete
+//! packages, profiles and sources by asking `dotter _complete`, in the repository of the
+//! current directory.
+
+use anyhow::{Context, Result};
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+use args::Options;
+
+const BASH: &str = r#"
+_dotter_dynamic() {
+    local current="${COMP_WORDS[COMP_CWORD]}" kind=
+    case "${COMP_WORDS[COMP_CWORD-1]}" in
+        --profile) kind=profiles ;;
+        --only|--package|undeploy|enable|disable) kind=packages ;;
+        mv) kind=sources ;;
+    esac
+    if [ -z "$kind" ]; then
+        _dotter "$@"
+        return
+    fi
+    COMPREPLY=($(dotter _complete "$kind" "$current" 2>/dev/null))
+}
+
+complete -F _dotter_dynamic -o bashdefault -o default dotter
+"#;
+
+const ZSH: &str = r#"
+_dotter() {
+    local kind
+    case "${words[CURRENT-1]}" in
+        --profile) kind=profiles ;;
+        --only|--package|undeploy|enable|disable) kind=packages ;;
+        mv) kind=sources ;;
+    esac
+    if [[ -z "$kind" ]]; then
+        _dotter_static "$@"
+        return
+    fi
+    compadd -- ${(f)"$(dotter _complete $kind "$PREFIX" 2>/dev/null)"}
+}
+
+_dotter "$@"
+"#;
+
+const FISH: &str = r#"
+complete -c dotter -l profile -x -a '(dotter _complete profiles (commandline -ct) 2>/dev/null)'
+complete -c dotter -n '__fish_seen_subcommand_from deploy' -l only -x -a '(dotter _complete packages (commandline -ct) 2>/dev/null)'
+complete -c dotter -n '__fish_seen_subcommand_from clean-cache context adopt' -l package -x -a '(dotter _complete packages (commandline -ct) 2>/dev/null)'
+complete -c dotter -n '__fish_seen_subcommand_from undeploy enable disable' -f -a '(dotter _complete packages (commandline -ct) 2>/dev/null)'
+complete -c dotter -n '__fish_seen_subcommand_from mv' -f -a '(dotter _complete sources (commandline -ct) 2>/dev/null)'
+"#;
+
+/// Prints the completion script of `shell`
+pub fn gen_completions(shell: Shell) -> Result<()> {
+    let mut generated = Vec::new();
+    Options::clap().gen_completions_to("dotter", shell, &mut generated);
+    let mut script = String::from_utf8(generated).context("completion script isn't UTF-8")?;
+    match shell {
+        Shell::Bash => script.push_str(BASH),
+        Shell::Zsh => {
+            // The generated `_dotter` becomes the fallback of the one that completes packages
+            script = script
+                .replacen("\n_dotter() {", "\n_dotter_static() {", 1)
+                .trim_end()
+                .trim_end_matches("_dotter \"$@\"")
+                .to_string();
+            script.push_str(ZSH);
+        }
+        Shell::Fish => script.push_str(FISH),
+        Shell::PowerShell | Shell::Elvish => {}
+    }
+    print!("{}", script);
+    Ok(())
+}
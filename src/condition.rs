@@ -0,0 +1,236 @@
+//! Conditions that enable packages and variables on the machines they're about, like
+//! `os == "linux" && hostname =~ "work-.*"`.
+//!
+//! Operands are string literals in single or double quotes, and facts: `os` (`linux`, `macos`,
+//! `windows`, ...), `family` (`unix` or `windows`), `arch` (`x86_64`, `aarch64`, ...),
+//! `hostname`, `user` and `env.<VARIABLE>`, which is empty if the variable isn't set.
+//! `==` and `!=` compare two operands, `=~` and `!~` match the whole left one against the
+//! regex on the right, and an operand on its own holds if it isn't empty. Comparisons are
+//! combined with `!`, `&&`, `||` and parentheses.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use facts;
+
+/// Whether `condition` holds on this machine
+pub fn holds(condition: &str) -> Result<bool> {
+    evaluate(condition, &machine_fact).with_context(|| format!("evaluate {:?}", condition))
+}
+
+fn machine_fact(name: &str) -> Result<String> {
+    if let Some(variable) = name.strip_prefix("env.") {
+        return Ok(std::env::var(variable).unwrap_or_default());
+    }
+    Ok(match name {
+        "os" => facts::os(),
+        "family" => facts::family(),
+        "arch" => facts::arch(),
+        "hostname" => facts::hostname(),
+        "user" => facts::user(),
+        _ => bail!(
+            "unknown fact `{}`, expected `os`, `family`, `arch`, `hostname`, `user` or `env.<VARIABLE>`",
+            name
+        ),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    Fact(String),
+    Op(&'static str),
+}
+
+#[derive(Debug)]
+enum Expression {
+    Operand(Token),
+    Compare(&'static str, Token, Token),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+fn evaluate(condition: &str, fact: &dyn Fn(&str) -> Result<String>) -> Result<bool> {
+    let tokens = tokenize(condition)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+    };
+    let expression = parser.or()?;
+    if let Some(token) = parser.tokens.get(parser.position) {
+        bail!("unexpected {}", describe(token));
+    }
+    expression.evaluate(fact)
+}
+
+/// Operators, longest first so that `!=` isn't read as `!`
+const OPERATORS: &[&str] = &["==", "!=", "=~", "!~", "&&", "||", "!", "(", ")"];
+
+fn tokenize(condition: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = condition.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .with_context(|| format!("string {} isn't closed", rest))?;
+            tokens.push(Token::Str(rest[1..=end].into()));
+            rest = &rest[end + 2..];
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Fact(rest[..end].into()));
+            rest = &rest[end..];
+        } else {
+            bail!("unexpected character {:?}", c);
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Str(s) => format!("string {:?}", s),
+        Token::Fact(f) => format!("`{}`", f),
+        Token::Op(op) => format!("`{}`", op),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is(&mut self, op: &str) -> bool {
+        if self.tokens.get(self.position) == Some(&Token::Op(Self::op(op))) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn op(op: &str) -> &'static str {
+        OPERATORS
+            .iter()
+            .find(|o| **o == op)
+            .expect("known operator")
+    }
+
+    fn or(&mut self) -> Result<Expression> {
+        let mut expression = self.and()?;
+        while self.next_is("||") {
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression> {
+        let mut expression = self.unary()?;
+        while self.next_is("&&") {
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        if self.next_is("!") {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+        if self.next_is("(") {
+            let expression = self.or()?;
+            if !self.next_is(")") {
+                bail!("`(` isn't closed");
+            }
+            return Ok(expression);
+        }
+        let left = self.operand()?;
+        for op in ["==", "!=", "=~", "!~"] {
+            if self.next_is(op) {
+                return Ok(Expression::Compare(Self::op(op), left, self.operand()?));
+            }
+        }
+        Ok(Expression::Operand(left))
+    }
+
+    fn operand(&mut self) -> Result<Token> {
+        match self.next() {
+            Some(token @ Token::Str(_)) | Some(token @ Token::Fact(_)) => Ok(token),
+            Some(token) => bail!("expected a string or a fact, found {}", describe(&token)),
+            None => bail!("expected a string or a fact, found the end"),
+        }
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, fact: &dyn Fn(&str) -> Result<String>) -> Result<bool> {
+        let value = |token: &Token| match token {
+            Token::Str(s) => Ok(s.clone()),
+            Token::Fact(name) => fact(name),
+            Token::Op(_) => unreachable!("operators aren't operands"),
+        };
+        Ok(match self {
+            Expression::Operand(token) => !value(token)?.is_empty(),
+            Expression::Compare(op, left, right) => {
+                let (left, right) = (value(left)?, value(right)?);
+                match *op {
+                    "==" => left == right,
+                    "!=" => left != right,
+                    _ => {
+                        let regex = Regex::new(&format!("^(?:{})$", right))
+                            .with_context(|| format!("parse regex {:?}", right))?;
+                        regex.is_match(&left) == (*op == "=~")
+                    }
+                }
+            }
+            Expression::Not(expression) => !expression.evaluate(fact)?,
+            Expression::And(left, right) => left.evaluate(fact)? && right.evaluate(fact)?,
+            Expression::Or(left, right) => left.evaluate(fact)? || right.evaluate(fact)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let fact = |name: &str| -> Result<String> {
+            Ok(match name {
+                "os" => "linux",
+                "hostname" => "work-laptop",
+                "env.EMPTY" => "",
+                _ => bail!("unknown fact"),
+            }
+            .into())
+        };
+        let holds = |condition: &str| evaluate(condition, &fact).unwrap();
+
+        assert!(holds(r#"os == "linux" && hostname =~ "work-.*""#));
+        assert!(!holds("os == 'linux' && hostname =~ 'work'"));
+        assert!(holds("os != 'macos' || env.EMPTY"));
+        assert!(!holds("env.EMPTY"));
+        assert!(holds("!(os == 'windows') && hostname !~ 'home-.*'"));
+        assert!(holds("os == 'macos' || os == 'linux' && !env.EMPTY"));
+
+        assert!(evaluate("shell == 'zsh'", &fact).is_err());
+        assert!(evaluate("os == ", &fact).is_err());
+        assert!(evaluate("(os == 'linux'", &fact).is_err());
+        assert!(evaluate("os == 'linux' os", &fact).is_err());
+        assert!(evaluate("os == \"linux", &fact).is_err());
+    }
+}
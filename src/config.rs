@@ -1,10 +1,26 @@
 use anyhow::{Context, Result};
 
+use ansible_vars;
+use backup::Backup;
+use capabilities::ProbedCapabilities;
+use condition;
+use equivalence::Equivalence;
 use filesystem;
-
-use std::collections::BTreeMap;
+use hooks::{self, HookEnvironments};
+use remote_variables;
+use variable_providers;
+use variable_sources;
+use verify::Verify;
+use warnings;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(untagged)]
@@ -17,14 +33,241 @@ pub enum UnixUser {
 pub struct SymbolicTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    /// Owning group, as a gid or a group name. Groups that the user isn't a member of are set
+    /// with sudo.
+    pub group: Option<UnixUser>,
+    /// The target lives on a tmpfs (like `$XDG_RUNTIME_DIR`) and is expected to disappear on reboot
+    pub volatile: bool,
+    /// Deployed before the other files, and a failure aborts the deploy before they're touched
+    pub critical: bool,
+    /// Overwrites the target when it was changed since it was deployed, like `--force` does.
+    /// A target that was there before dotter deployed it still needs `--force`.
+    pub allow_overwrite: bool,
+    /// Changes that the user isn't allowed to make to the target are retried with `sudo`,
+    /// for system files like the ones in `/etc`
+    pub elevate: bool,
+    /// Program that is told to reload its configuration when the target changes, see `reload.rs`
+    pub reload: Option<String>,
+    /// The target is a systemd user unit or a launchd agent, which the service manager is told
+    /// about when it changes
+    pub service_unit: bool,
+    /// Hook that runs after a deploy that created or updated the target, see `hooks.rs`
+    pub on_deploy: Option<PathBuf>,
+    /// Hook that runs after the target was deleted
+    pub on_undeploy: Option<PathBuf>,
+    /// Owner of the parent directories that have to be created for the target, which are
+    /// created with sudo unless dotter runs as that owner or as root already
+    pub directory_owner: Option<UnixUser>,
+    /// Permissions of the parent directories that have to be created for the target
+    pub directory_mode: Option<u32>,
+    /// Targets that have to be deployed before this one
+    pub after: Vec<PathBuf>,
+    /// `link_type = "recursive"`: the source is a directory whose files are deployed one by one
+    /// into the target directory with these settings, next to the files already there
+    pub recursive: bool,
+    /// `link_type = "junction"` or `"hardlink"`: what links the target to the source
+    pub link: LinkKind,
+    /// The source's `{{` isn't meant as template syntax, which silences the warning about
+    /// symlinks that look like templates
+    pub literal_braces: bool,
+    /// Permissions of the symlink itself, which only macOS and the BSDs have
+    pub mode: Option<u32>,
+    /// File flags of the symlink itself, like `uchg` or `hidden`, which only macOS and the BSDs
+    /// have. They're set when the symlink is created.
+    pub flags: Vec<String>,
+    /// Checks of the target right after it's created or updated, see `verify.rs`
+    pub verify: Option<Verify>,
+}
+
+/// What a symbolic target is linked to its source with
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    #[default]
+    Symlink,
+    /// A directory junction, which Windows creates without developer mode or admin rights.
+    /// Other platforms create a symlink instead.
+    Junction,
+    /// A hard link, which needs neither symlink support nor rights, but only works on the same
+    /// filesystem and breaks when the source is replaced instead of edited in place, like git
+    /// does on checkout
+    Hardlink,
 }
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TemplateTarget {
     pub target: PathBuf,
     pub owner: Option<UnixUser>,
+    /// Owning group, as a gid or a group name. Groups that the user isn't a member of are set
+    /// with sudo.
+    pub group: Option<UnixUser>,
+    /// Permissions of the target. By default they're copied from the source.
+    pub mode: Option<u32>,
+    /// Windows file attributes of the target, like `hidden`. Ignored on other platforms.
+    pub attributes: Vec<FileAttribute>,
+    /// Permissions that are granted on the target with `icacls /grant`, like `Users:(R)`.
+    /// Ignored on other platforms.
+    pub acl: Vec<String>,
     pub append: Option<String>,
     pub prepend: Option<String>,
+    /// The target lives on a tmpfs (like `$XDG_RUNTIME_DIR`) and is expected to disappear on reboot
+    pub volatile: bool,
+    /// Only written by the first deploy (or kept if it already exists), and left unmanaged after
+    /// that, for generated state like machine IDs and secrets
+    pub write_once: bool,
+    /// Deployed before the other files, and a failure aborts the deploy before they're touched
+    pub critical: bool,
+    /// Overwrites the target when it was changed since it was deployed, like `--force` does.
+    /// A target that was there before dotter deployed it still needs `--force`.
+    pub allow_overwrite: bool,
+    /// Changes that the user isn't allowed to make to the target are retried with `sudo`,
+    /// for system files like the ones in `/etc`
+    pub elevate: bool,
+    /// Program that is told to reload its configuration when the target changes, see `reload.rs`
+    pub reload: Option<String>,
+    /// The target is a systemd user unit or a launchd agent, which the service manager is told
+    /// about when it changes
+    pub service_unit: bool,
+    /// Hook that runs after a deploy that created or updated the target, see `hooks.rs`
+    pub on_deploy: Option<PathBuf>,
+    /// Hook that runs after the target was deleted
+    pub on_undeploy: Option<PathBuf>,
+    /// Owner of the parent directories that have to be created for the target, which are
+    /// created with sudo unless dotter runs as that owner or as root already
+    pub directory_owner: Option<UnixUser>,
+    /// Permissions of the parent directories that have to be created for the target
+    pub directory_mode: Option<u32>,
+    /// Targets that have to be deployed before this one
+    pub after: Vec<PathBuf>,
+    /// `link_type = "recursive"`: the source is a directory whose files are deployed one by one
+    /// into the target directory with these settings, next to the files already there
+    pub recursive: bool,
+    /// Differences between the target and the render that aren't treated as changes
+    pub equivalence: Equivalence,
+    /// Whether `--verbose` prints the changes of the target as a diff
+    pub diff: DiffSetting,
+    /// `None` uses the `template_engine` of the file's package, or handlebars
+    pub engine: Option<TemplateEngine>,
+    /// Directory that keeps the rendered copy instead of the cache directory, like a tmpfs for
+    /// templates with secrets in them
+    pub render_cache: Option<PathBuf>,
+    /// Command that checks the render before it's written to the target, like
+    /// `sshd -t -f {}`, see `validate.rs`
+    pub validate: Option<String>,
+    /// The source is encrypted with age (`.age`) or gpg, and is deployed as it decrypts instead
+    /// of being rendered. The cache only keeps a hash of the plaintext.
+    pub encrypted: bool,
+    /// Checks of the target right after it's created or updated, see `verify.rs`
+    pub verify: Option<Verify>,
+}
+
+/// A Windows file attribute that templates and copies can set on their target
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum FileAttribute {
+    /// Hidden from Explorer and `dir`, like marker files that tools look for
+    Hidden,
+    /// dotter clears it before it writes or deletes the target, and sets it again after
+    ReadOnly,
+    System,
+}
+
+/// The language that a template is written in. Helpers and partials are only available to
+/// handlebars templates, tera templates have tera's own filters and functions instead.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateEngine {
+    #[default]
+    Handlebars,
+    Tera,
+}
+
+/// `diff = false` never prints the diff of a template, for generated files like compiled
+/// themes that would flood the terminal, and `diff = <lines>` only prints diffs that change
+/// at most that many lines
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum DiffSetting {
+    Enabled(bool),
+    MaxLines(usize),
+}
+
+impl Default for DiffSetting {
+    fn default() -> Self {
+        DiffSetting::Enabled(true)
+    }
+}
+
+impl DiffSetting {
+    /// Whether a diff that changes `changed_lines` lines is printed
+    pub fn shows(&self, changed_lines: usize) -> bool {
+        match *self {
+            DiffSetting::Enabled(enabled) => enabled,
+            DiffSetting::MaxLines(max) => changed_lines <= max,
+        }
+    }
+}
+
+/// A target that is a plain copy of its source, for places where symlinks don't work
+/// (synced folders, or Windows without developer mode). Unlike a template it isn't rendered,
+/// so binary files can be copied too.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CopyTarget {
+    pub target: PathBuf,
+    pub owner: Option<UnixUser>,
+    /// Owning group, as a gid or a group name. Groups that the user isn't a member of are set
+    /// with sudo.
+    pub group: Option<UnixUser>,
+    /// Permissions of the target. By default they're copied from the source.
+    pub mode: Option<u32>,
+    /// Windows file attributes of the target, like `hidden`. Ignored on other platforms.
+    pub attributes: Vec<FileAttribute>,
+    /// Permissions that are granted on the target with `icacls /grant`, like `Users:(R)`.
+    /// Ignored on other platforms.
+    pub acl: Vec<String>,
+    /// The target lives on a tmpfs (like `$XDG_RUNTIME_DIR`) and is expected to disappear on reboot
+    pub volatile: bool,
+    /// Only copied by the first deploy (or kept if it already exists), and left unmanaged after that
+    pub write_once: bool,
+    /// Deployed before the other files, and a failure aborts the deploy before they're touched
+    pub critical: bool,
+    /// Overwrites the target when it was changed since it was deployed, like `--force` does.
+    /// A target that was there before dotter deployed it still needs `--force`.
+    pub allow_overwrite: bool,
+    /// Changes that the user isn't allowed to make to the target are retried with `sudo`,
+    /// for system files like the ones in `/etc`
+    pub elevate: bool,
+    /// Program that is told to reload its configuration when the target changes, see `reload.rs`
+    pub reload: Option<String>,
+    /// The target is a systemd user unit or a launchd agent, which the service manager is told
+    /// about when it changes
+    pub service_unit: bool,
+    /// Hook that runs after a deploy that created or updated the target, see `hooks.rs`
+    pub on_deploy: Option<PathBuf>,
+    /// Hook that runs after the target was deleted
+    pub on_undeploy: Option<PathBuf>,
+    /// Owner of the parent directories that have to be created for the target, which are
+    /// created with sudo unless dotter runs as that owner or as root already
+    pub directory_owner: Option<UnixUser>,
+    /// Permissions of the parent directories that have to be created for the target
+    pub directory_mode: Option<u32>,
+    /// Targets that have to be deployed before this one
+    pub after: Vec<PathBuf>,
+    /// `link_type = "recursive"`: the source is a directory whose files are deployed one by one
+    /// into the target directory with these settings, next to the files already there
+    pub recursive: bool,
+    /// Checks of the target right after it's created or updated, see `verify.rs`
+    pub verify: Option<Verify>,
+}
+
+/// One part of a target that is assembled from files in several packages
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FragmentTarget {
+    pub target: PathBuf,
+    /// Fragments of the same target are concatenated in ascending order
+    pub order: i64,
+    /// Whether handlebars in the fragment is rendered, or copied as it is
+    pub template: bool,
 }
 
 // Deserialize implemented manually
@@ -34,18 +277,97 @@ pub enum FileTarget {
     Automatic(PathBuf),
     Symbolic(SymbolicTarget),
     ComplexTemplate(TemplateTarget),
+    Copy(CopyTarget),
+    Fragment(FragmentTarget),
 }
 
+/// Name of the gitignore-style file that excludes paths from directory expansion.
+/// It is honored at the repository root and inside every expanded directory.
+pub const IGNORE_FILE: &str = ".dotterignore";
+
 pub type Files = BTreeMap<PathBuf, FileTarget>;
 pub type Variables = toml::value::Table;
-pub type Helpers = BTreeMap<String, PathBuf>;
+pub type Helpers = BTreeMap<String, HelperFile>;
+
+/// A helper of global.toml: the path of a Rhai script, or `{ program = "<path>" }` for a
+/// program that is run with the helper's parameters as arguments
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HelperFile {
+    Script(PathBuf),
+    Program { program: PathBuf },
+}
+
+impl HelperFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            HelperFile::Script(path) => path,
+            HelperFile::Program { program } => program,
+        }
+    }
+}
+/// Merge strategies by the dotted path of the variable they apply to, like `git.aliases`
+pub type MergeStrategies = BTreeMap<String, MergeStrategy>;
+/// Lines that must exist in a line-based file like `~/.ssh/authorized_keys`, by target.
+/// The rest of the file is left alone, so it can also be edited by hand or by other programs.
+pub type ManagedLines = BTreeMap<PathBuf, Vec<String>>;
+/// Files that dotter creates instead of deploying them from the repository, like a named pipe
+/// that a program expects to exist, by target. See `special_files.rs`.
+pub type SpecialFiles = BTreeMap<PathBuf, SpecialFile>;
+/// Maps each file's source to the name of the package that declared it.
+/// Files that come from local.toml or from a patch aren't part of any package.
+pub type FilePackages = BTreeMap<PathBuf, String>;
 
 #[derive(Debug, Clone)]
 pub struct Configuration {
     pub files: Files,
+    pub file_packages: FilePackages,
     pub variables: Variables,
     pub helpers: Helpers,
+    /// Enabled packages, after the packages they depend on
     pub packages: Vec<String>,
+    /// How deep every enabled package is in the dependencies between packages: 0 without
+    /// dependencies, one more than its deepest dependency otherwise
+    pub package_levels: BTreeMap<String, usize>,
+    /// Notes of the enabled packages, by package
+    pub notes: BTreeMap<String, String>,
+    /// Hooks of the enabled packages that have any, by package
+    pub package_hooks: BTreeMap<String, PackageHooks>,
+    /// Variables that only the templates of their own package see, by package.
+    /// Empty unless global.toml sets `isolate_variables = true`.
+    pub private_variables: BTreeMap<String, Variables>,
+    /// Files whose source doesn't exist in the repository, with the package that declared them
+    pub missing_sources: BTreeMap<PathBuf, Option<String>>,
+    /// Prefixes that belong to other dotfile managers, with tildes expanded
+    pub foreign: ForeignPaths,
+    /// Whether templates may download resources with the `fetch_url` helper
+    pub allow_fetch: bool,
+    /// Whether helpers declared as `{ program = "<path>" }` are run
+    pub allow_program_helpers: bool,
+    /// Addresses that tell whether the machine is online, see `hooks::network_available`
+    pub network_probes: Vec<String>,
+    /// Directory of the partials that templates include, `partials` if it exists and none is set
+    pub partials: Option<PathBuf>,
+    /// Lines of the enabled packages and local.toml, with tildes expanded
+    pub lines: ManagedLines,
+    /// Special files of the enabled packages and local.toml, with tildes expanded
+    pub special: SpecialFiles,
+    pub secrets: SecretsConfig,
+    /// Environment variables of every hook and command, with `~` and `$VARIABLE` expanded
+    pub env: Environment,
+    /// Command that merges a template's edited target with its new render, from local.toml
+    pub merge_tool: Option<String>,
+    /// Engine of the templates of every package that sets `template_engine`, by package
+    pub template_engines: BTreeMap<String, TemplateEngine>,
+    /// Directories that every target must be inside of, with tildes expanded. `None` allows
+    /// targets anywhere.
+    pub allowed_target_roots: Option<Vec<PathBuf>>,
+    pub hash_algorithm: HashAlgorithm,
+    /// Enabled packages with `lazy = true`
+    pub lazy_packages: BTreeSet<String>,
+    /// Shims that stand in for the executables of the lazy packages that weren't activated yet,
+    /// by target, see `lazy::hold_back`. The files of those packages aren't in `files`.
+    pub shims: BTreeMap<PathBuf, String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -53,6 +375,149 @@ pub struct Configuration {
 pub struct Package {
     #[serde(default)]
     files: Files,
+    /// Directory of TOML files whose files are added to `files`, so that generated tooling can
+    /// add and remove entries without rewriting global.toml
+    files_from: Option<PathBuf>,
+    /// Patterns of the paths that the globs of `files` leave out, like `*.swp` or `.git`
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(default)]
+    variables: Variables,
+    /// Merge strategies of the package's variables, which let several packages define the
+    /// same variable, like a list that every package appends its entries to
+    #[serde(default)]
+    merge: MergeStrategies,
+    /// Variables that the templates of every package see, when global.toml isolates the
+    /// variables of packages
+    #[serde(default)]
+    exports: Vec<String>,
+    #[serde(default)]
+    lines: ManagedLines,
+    #[serde(default)]
+    special: SpecialFiles,
+    /// Set on top of the `[env]` of global.toml and local.toml, only for the hooks of the
+    /// package and its files
+    #[serde(default)]
+    env: Environment,
+    auto_enable: Option<AutoEnable>,
+    /// Enables the package on machines where the condition holds, see `condition.rs`
+    condition: Option<String>,
+    /// Variables that are merged on top of the package's variables where their condition holds
+    #[serde(default)]
+    when: Vec<ConditionalVariables>,
+    /// Manual follow-up steps, printed after the package is deployed for the first time
+    notes: Option<String>,
+    /// Hook that runs after a deploy that created or updated any file of the package
+    on_deploy: Option<PathBuf>,
+    /// Hook that runs after a deploy or undeploy that deleted any file of the package
+    on_undeploy: Option<PathBuf>,
+    /// Directory that the hooks of the package and its files run in, relative to the repository
+    cwd: Option<PathBuf>,
+    /// Directories that are put in front of the `PATH` of the hooks of the package and its
+    /// files, relative to the repository, like `bin`
+    #[serde(default)]
+    path_prepend: Vec<PathBuf>,
+    /// The hooks of the package and its files download something, like a plugin manager, and
+    /// are skipped when the machine is offline
+    #[serde(default)]
+    requires_network: bool,
+    /// Engine of the package's templates that don't set their own
+    template_engine: Option<TemplateEngine>,
+    /// Packages that are enabled along with this one, and deployed before it
+    #[serde(default)]
+    depends: Vec<String>,
+    /// Only shims of the package's executables are deployed, until one of them is run for the
+    /// first time, see `lazy.rs`
+    #[serde(default)]
+    lazy: bool,
+}
+
+/// The hooks of a package that run when its files change, see `hooks.rs`
+#[derive(Debug, Clone, Default)]
+pub struct PackageHooks {
+    pub on_deploy: Option<PathBuf>,
+    pub on_undeploy: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub path_prepend: Vec<PathBuf>,
+    pub requires_network: bool,
+    /// The package's `[env]`, with `~` and `$VARIABLE` expanded
+    pub env: Environment,
+}
+
+/// A file of `[<package>.special]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpecialFile {
+    #[serde(rename = "type")]
+    pub kind: SpecialKind,
+    /// Permissions as an octal string, like "600"
+    pub mode: Option<String>,
+    /// Package that declares it, which loading the configuration fills in. The cache keeps it,
+    /// so that undeploying the package removes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpecialKind {
+    /// A named pipe
+    Fifo,
+    /// An empty file, which is left to the program that fills it
+    Empty,
+}
+
+impl SpecialFile {
+    /// Mode that the file is created with, only readable by the user by default
+    pub fn mode(&self) -> u32 {
+        self.mode.as_deref().and_then(parse_mode).unwrap_or(0o600)
+    }
+}
+
+/// Records which package declares the special files, whatever the configuration said
+fn declare_special(special: &mut SpecialFiles, package: Option<&str>) {
+    for file in special.values_mut() {
+        file.package = package.map(String::from);
+    }
+}
+
+/// A `[[<package>.when]]` block, like variables that only apply to one architecture
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionalVariables {
+    condition: String,
+    #[serde(default)]
+    variables: Variables,
+}
+
+/// How a variable is combined with the variable of the same name from an earlier
+/// configuration file. By default tables are merged key by key and anything else is replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    Replace,
+    DeepMerge,
+    /// Lists are concatenated, with the later file's elements last
+    AppendList,
+}
+
+/// Enables a package on machines where a condition holds, even if local.toml doesn't select it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoEnable {
+    /// Name of an executable that must be in `$PATH`
+    command: String,
+}
+
+/// A named set of packages and variables, like `laptop` or `server`, that's selected with
+/// `--profile` or the `profile` of local.toml
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Enabled in addition to the packages of local.toml
+    #[serde(default)]
+    packages: Vec<String>,
+    /// Override the variables of the packages, and are overridden by the ones of local.toml
     #[serde(default)]
     variables: Variables,
 }
@@ -61,10 +526,209 @@ pub struct Package {
 struct GlobalConfig {
     #[serde(default)]
     helpers: Helpers,
+    /// Template of a URL whose variables are merged on top of the packages' variables
+    variables_url: Option<String>,
+    /// Ansible inventory directory whose `host_vars` for this machine are merged on top of
+    /// the packages' variables
+    ansible_inventory: Option<PathBuf>,
+    /// Commands whose variables are merged on top of the packages' variables, see
+    /// `variable_providers.rs` for what they're given and have to answer
+    variable_providers: Option<Vec<String>>,
+    /// Allows the `fetch_url` helper, which downloads resources while rendering
+    allow_fetch: Option<bool>,
+    /// Allows helpers declared as `{ program = "<path>" }`, which are run as programs
+    allow_program_helpers: Option<bool>,
+    /// Addresses, as `<host>:<port>`, that the machine is online if it can connect to any of.
+    /// Defaults to the HTTPS ports of public DNS servers.
+    network_probes: Option<Vec<String>>,
+    /// Commits the edits of commands like `adopt` and `new-package` to the repository
+    auto_commit: Option<bool>,
+    /// Directory of the partials that templates include with `{{> name}}`
+    partials: Option<PathBuf>,
+    /// Keeps the variables of each package to its own templates, except the ones it lists in
+    /// `exports`, so that unrelated packages can use the same names
+    #[serde(default)]
+    isolate_variables: bool,
+    secrets: Option<SecretsConfig>,
+    #[serde(default)]
+    env: Environment,
+    #[serde(default)]
+    foreign: ForeignPaths,
+    #[serde(default)]
+    merge: MergeStrategies,
+    #[serde(default)]
+    duplicate_targets: DuplicateTargets,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    lint: LintConfig,
+    #[serde(default)]
+    warnings: WarningsConfig,
+    /// Filters that `adopt` runs over adopted files to keep secrets out of the repository
+    #[serde(default)]
+    scrub: Vec<ScrubFilter>,
+    /// Directories that every target must be inside of, like `["~", "/etc/foo"]`
+    allowed_target_roots: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
     #[serde(flatten)]
     packages: BTreeMap<String, Package>,
 }
 
+/// Settings of `dotter lint`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LintConfig {
+    /// Findings that are expected, as a rule ID like `unused-package` or as a rule ID and the
+    /// package or source it's about, like `unused-package:work`
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// Warnings that aren't printed, see `warnings.rs`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarningsConfig {
+    /// Warnings that are expected, as an ID like `shadowed` or as an ID and what it's about,
+    /// like `unused-variable:font_size`
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// Secret that `adopt` replaces with a variable, whose value is saved in local.toml instead
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubFilter {
+    /// Regular expression of the secret. If it has a capture group, only the first group is
+    /// replaced, like the token in `token = "(.+)"`.
+    pub pattern: String,
+    /// Name of the variable that the secret is replaced with
+    pub variable: String,
+}
+
+/// Where the `secret` helper gets secrets from, see `secrets.rs`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// Provider of the secrets that don't name one with `provider="..."`
+    pub provider: Option<SecretProvider>,
+    /// Directory of the files that `gpg` and `age` decrypt, named `<secret>.gpg` or `<secret>.age`
+    pub directory: Option<PathBuf>,
+    /// Identity file that `age` decrypts with
+    pub identity: Option<PathBuf>,
+    /// Seconds that a revealed secret is kept for, encrypted in the cache directory, before
+    /// it's revealed again. Older copies stand in for secrets whose provider fails.
+    pub cache_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretProvider {
+    /// The `pass` password store
+    Pass,
+    Gpg,
+    Age,
+}
+
+impl SecretProvider {
+    pub fn command(&self) -> &'static str {
+        match self {
+            SecretProvider::Pass => "pass",
+            SecretProvider::Gpg => "gpg",
+            SecretProvider::Age => "age",
+        }
+    }
+}
+
+impl std::str::FromStr for SecretProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(SecretProvider::Pass),
+            "gpg" => Ok(SecretProvider::Gpg),
+            "age" => Ok(SecretProvider::Age),
+            other => Err(format!(
+                "unknown secret provider {:?}, expected `pass`, `gpg` or `age`",
+                other
+            )),
+        }
+    }
+}
+
+/// What happens when a layer of the configuration deploys a source or a target that an
+/// earlier layer already deploys. The layers are the packages in the order they're deployed,
+/// so a package comes after the ones it depends on, then local.toml, then the patch of --patch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateTargets {
+    /// The later layer's definition is used, and the earlier one is dropped
+    #[default]
+    LaterWins,
+    /// Two packages that deploy the same source, or two sources that are deployed to the same
+    /// target, are an error. local.toml and the patch can still change the targets of sources.
+    Error,
+}
+
+impl std::str::FromStr for DuplicateTargets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "later-wins" => Ok(DuplicateTargets::LaterWins),
+            "error" => Ok(DuplicateTargets::Error),
+            other => Err(format!(
+                "unknown duplicate target resolution {:?}, expected `later-wins` or `error`",
+                other
+            )),
+        }
+    }
+}
+
+/// Algorithm of the hashes that dotter records of copies and of renders with secrets, to notice
+/// when their targets were changed. Every hash is tagged with its algorithm, so that changing it
+/// doesn't make the recorded ones look changed.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    /// For audits that require a FIPS-approved hash
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            other => Err(format!(
+                "unknown hash algorithm {:?}, expected `blake3` or `sha256`",
+                other
+            )),
+        }
+    }
+}
+
+/// Environment variables that are set for hooks, reload commands, secret providers and the
+/// commands of the `command_success` and `command_output` helpers
+pub type Environment = BTreeMap<String, String>;
+
+/// Paths that belong to other dotfile managers (like home-manager or chezmoi), by the name
+/// of the manager. Nothing inside them is ever deployed or deleted.
+pub type ForeignPaths = BTreeMap<String, Vec<PathBuf>>;
+
 type IncludedConfig = BTreeMap<String, Package>;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -72,50 +736,245 @@ type IncludedConfig = BTreeMap<String, Package>;
 struct LocalConfig {
     #[serde(default)]
     includes: Vec<PathBuf>,
+    #[serde(default)]
     packages: Vec<String>,
+    /// Profile of global.toml that's used when `--profile` isn't given
+    profile: Option<String>,
     #[serde(default)]
     files: Files,
     #[serde(default)]
     variables: Variables,
+    #[serde(default)]
+    lines: ManagedLines,
+    #[serde(default)]
+    special: SpecialFiles,
+    variables_url: Option<String>,
+    ansible_inventory: Option<PathBuf>,
+    /// Replaces the `variable_providers` of global.toml
+    variable_providers: Option<Vec<String>>,
+    allow_fetch: Option<bool>,
+    allow_program_helpers: Option<bool>,
+    /// Replaces the `network_probes` of global.toml, like for a machine behind a firewall
+    network_probes: Option<Vec<String>>,
+    auto_commit: Option<bool>,
+    secrets: Option<SecretsConfig>,
+    #[serde(default)]
+    env: Environment,
+    #[serde(default)]
+    foreign: ForeignPaths,
+    #[serde(default)]
+    merge: MergeStrategies,
+    #[serde(default)]
+    lint: LintConfig,
+    #[serde(default)]
+    warnings: WarningsConfig,
+    merge_tool: Option<String>,
+    /// Replaces the `allowed_target_roots` of global.toml
+    allowed_target_roots: Option<Vec<PathBuf>>,
 }
 
 pub fn load_configuration(
     local_config: &Path,
     global_config: &Path,
+    cache_directory: &Path,
+    profile: Option<&str>,
+    duplicate_targets: Option<DuplicateTargets>,
     patch: Option<Package>,
 ) -> Result<Configuration> {
-    let global: GlobalConfig = filesystem::load_file(global_config)
+    let mut global: GlobalConfig = filesystem::load_file(global_config)
         .with_context(|| format!("load global config {:?}", global_config))?;
     trace!("Global config: {:#?}", global);
+    if let Some(duplicate_targets) = duplicate_targets {
+        global.duplicate_targets = duplicate_targets;
+    }
 
-    let local: LocalConfig = filesystem::load_file(local_config)
+    let mut local: LocalConfig = filesystem::load_file(local_config)
         .with_context(|| format!("load local config {:?}", local_config))?;
     trace!("Local config: {:#?}", local);
+    warnings::set_allowed(
+        global
+            .warnings
+            .allow
+            .iter()
+            .chain(&local.warnings.allow)
+            .cloned()
+            .collect(),
+    );
+
+    let profile = select_profile(&mut global, &mut local, profile)?;
+    trace!("Profile: {:#?}", profile);
+
+    let remote_variables = match local
+        .variables_url
+        .as_ref()
+        .or(global.variables_url.as_ref())
+    {
+        Some(url) => {
+            debug!("Fetching remote variables...");
+            remote_variables::fetch_variables(url, cache_directory)
+                .context("fetch remote variables")?
+        }
+        None => Variables::new(),
+    };
+    trace!("Remote variables: {:#?}", remote_variables);
 
-    let mut merged_config =
-        merge_configuration_files(global, local, patch).context("merge configuration files")?;
+    let ansible_variables = match local
+        .ansible_inventory
+        .as_ref()
+        .or(global.ansible_inventory.as_ref())
+    {
+        Some(inventory) => {
+            let inventory = PathBuf::from(filesystem::expand_tilde(&inventory.to_string_lossy()));
+            debug!("Loading Ansible host_vars...");
+            ansible_vars::load_host_vars(&inventory)
+                .with_context(|| format!("load Ansible variables from {:?}", inventory))?
+        }
+        None => Variables::new(),
+    };
+    trace!("Ansible variables: {:#?}", ansible_variables);
+
+    let providers = local
+        .variable_providers
+        .clone()
+        .or(global.variable_providers.clone())
+        .unwrap_or_default();
+
+    let mut merged_config = merge_configuration_files(
+        global,
+        local,
+        profile.variables,
+        ansible_variables,
+        remote_variables,
+        &providers,
+        patch,
+    )
+    .context("merge configuration files")?;
     trace!("Merged config: {:#?}", merged_config);
 
+    debug!("Looking for files whose source is missing...");
+    let (files, missing): (Files, Files) = std::mem::take(&mut merged_config.files)
+        .into_iter()
+        .partition(|(source, _)| source.exists());
+    merged_config.missing_sources = missing
+        .into_keys()
+        .map(|source| {
+            let package = merged_config.file_packages.get(&source).cloned();
+            (source, package)
+        })
+        .collect();
+    merged_config.files = files;
+
     debug!("Expanding files which are directories...");
     merged_config.files =
         expand_directories(merged_config.files).context("expand files that are directories")?;
+    merged_config.file_packages =
+        expand_file_packages(merged_config.file_packages, &merged_config.files);
 
+    // Targets written as tables were already expanded when they were parsed
     debug!("Expanding tildes to home directory...");
     merged_config.files = merged_config
         .files
         .into_iter()
-        .map(|(k, v)| {
-            (
-                k,
-                v.map(|path| {
-                    shellexpand::tilde(&path.to_string_lossy())
-                        .to_string()
-                        .into()
-                }),
-            )
+        .map(|(source, target)| match target {
+            FileTarget::Automatic(path) => (source, FileTarget::Automatic(expand_target(&path))),
+            target => (source, target),
         })
         .collect();
 
+    merged_config.lines = std::mem::take(&mut merged_config.lines)
+        .into_iter()
+        .map(|(target, lines)| (expand_target(&target), lines))
+        .collect();
+    merged_config.special = std::mem::take(&mut merged_config.special)
+        .into_iter()
+        .map(|(target, special)| (expand_target(&target), special))
+        .collect();
+    for (target, special) in &merged_config.special {
+        if let Some(mode) = special.mode.as_deref().filter(|m| parse_mode(m).is_none()) {
+            bail!(
+                "invalid mode {:?} of special file {:?}, expected an octal number like \"600\"",
+                mode,
+                target
+            );
+        }
+    }
+
+    for (name, value) in merged_config.env.iter_mut() {
+        *value = filesystem::expand_environment(value)
+            .with_context(|| format!("expand environment variable {:?}", name))?;
+    }
+    for (package, hooks) in merged_config.package_hooks.iter_mut() {
+        for (name, value) in hooks.env.iter_mut() {
+            *value = filesystem::expand_environment(value).with_context(|| {
+                format!(
+                    "expand environment variable {:?} of package {:?}",
+                    name, package
+                )
+            })?;
+        }
+    }
+
+    expand_foreign(&mut merged_config.foreign);
+
+    debug!("Checking for targets that belong to other dotfile managers...");
+    let conflicts = merged_config
+        .files
+        .iter()
+        .filter_map(|(source, target)| {
+            let (manager, prefix) = foreign_manager(&merged_config.foreign, target.path())?;
+            Some(format!(
+                "{:?} -> {:?} is inside {:?}, which belongs to {}",
+                source,
+                target.path(),
+                prefix,
+                manager
+            ))
+        })
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        bail!(
+            "some targets belong to other dotfile managers:\n{}",
+            conflicts.join("\n")
+        );
+    }
+
+    if let Some(roots) = &mut merged_config.allowed_target_roots {
+        debug!("Checking that targets are inside the allowed roots...");
+        for root in roots.iter_mut() {
+            *root = normalize_path(&expand_target(root))?;
+        }
+        let mut outside = Vec::new();
+        let targets = merged_config
+            .files
+            .iter()
+            .map(|(source, target)| (Some(source), target.path()))
+            .chain(
+                merged_config
+                    .lines
+                    .keys()
+                    .chain(merged_config.special.keys())
+                    .map(|target| (None, target.as_path())),
+            );
+        for (source, target) in targets {
+            if !roots
+                .iter()
+                .any(|root| normalize_path(target).is_ok_and(|t| t.starts_with(root)))
+            {
+                outside.push(match source {
+                    Some(source) => format!("{:?} -> {:?}", source, target),
+                    None => format!("managed lines or special file {:?}", target),
+                });
+            }
+        }
+        if !outside.is_empty() {
+            bail!(
+                "some targets are outside the allowed_target_roots {:?}:\n{}",
+                roots,
+                outside.join("\n")
+            );
+        }
+    }
+
     debug!("Scanning for 'owner' field in files...");
     if merged_config.files.iter().any(|(_, v)| v.has_owner()) {
         if cfg!(windows) {
@@ -147,52 +1006,562 @@ pub fn load_configuration(
     Ok(merged_config)
 }
 
+/// Takes the profile given with `--profile`, or else the `profile` of local.toml, out of
+/// global.toml, and adds its packages to the ones that local.toml enables
+fn select_profile(
+    global: &mut GlobalConfig,
+    local: &mut LocalConfig,
+    profile: Option<&str>,
+) -> Result<Profile> {
+    let name = match profile.or(local.profile.as_deref()) {
+        Some(name) => name,
+        None => return Ok(Profile::default()),
+    };
+    let profile = match global.profiles.remove(name) {
+        Some(profile) => profile,
+        None => bail!(
+            "unknown profile {:?}, global.toml has {:?}",
+            name,
+            global.profiles.keys().collect::<Vec<_>>()
+        ),
+    };
+    info!("Using profile {:?}", name);
+    for package in &profile.packages {
+        if !local.packages.contains(package) {
+            local.packages.push(package.clone());
+        }
+    }
+    Ok(profile)
+}
+
+/// Makes `path` absolute and removes its `.` and `..` without following symlinks, so that a
+/// target like `~/../other/.bashrc` is compared by where it actually points
+pub fn normalize_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("get current directory")?
+            .join(path)
+    };
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Returns the manager that owns `path` and the prefix that matched, if `path` is foreign
+pub fn foreign_manager<'a>(foreign: &'a ForeignPaths, path: &Path) -> Option<(&'a str, &'a Path)> {
+    foreign.iter().find_map(|(manager, prefixes)| {
+        prefixes
+            .iter()
+            .find(|prefix| path.starts_with(prefix))
+            .map(|prefix| (manager.as_str(), prefix.as_path()))
+    })
+}
+
+/// A package in global.toml, whether or not it's enabled on this machine
+#[derive(Debug, Clone)]
+pub struct PackageDeclaration {
+    pub name: String,
+    /// Executable whose presence enables the package automatically
+    pub auto_enable: Option<String>,
+    /// Condition that enables the package where it holds
+    pub condition: Option<String>,
+    /// Packages that it enables along with it
+    pub depends: Vec<String>,
+}
+
+pub fn load_package_declarations(global_config: &Path) -> Result<Vec<PackageDeclaration>> {
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    Ok(global
+        .packages
+        .into_iter()
+        .map(|(name, package)| PackageDeclaration {
+            name,
+            auto_enable: package.auto_enable.map(|a| a.command),
+            condition: package.condition,
+            depends: package.depends,
+        })
+        .collect())
+}
+
+pub fn load_scrub_filters(global_config: &Path) -> Result<Vec<ScrubFilter>> {
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    Ok(global.scrub)
+}
+
+pub fn load_profile_names(global_config: &Path) -> Result<Vec<String>> {
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    Ok(global.profiles.into_keys().collect())
+}
+
+/// The configuration as it's written, before packages are filtered and merged
+#[derive(Debug)]
+pub struct Declared {
+    /// Files of every package of global.toml and of the included files, by package
+    pub package_files: BTreeMap<String, Files>,
+    pub auto_enabled: Vec<String>,
+    /// Packages that local.toml or one of the profiles enables
+    pub selected: Vec<String>,
+    pub local_files: Files,
+    pub lint: LintConfig,
+}
+
+pub fn load_declared(local_config: &Path, global_config: &Path) -> Result<Declared> {
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    let local: LocalConfig = filesystem::load_file(local_config)
+        .with_context(|| format!("load local config {:?}", local_config))?;
+
+    let mut package_files = BTreeMap::new();
+    let mut auto_enabled = Vec::new();
+    for (name, mut package) in global.packages {
+        if let Some(directory) = &package.files_from {
+            load_files_from(&mut package.files, directory)
+                .with_context(|| format!("load files_from of package {:?}", name))?;
+        }
+        if package.auto_enable.is_some() || package.condition.is_some() {
+            auto_enabled.push(name.clone());
+        }
+        package_files.insert(name, (package.files, package.ignore));
+    }
+    for included_path in &local.includes {
+        let included: IncludedConfig = filesystem::load_file(included_path)
+            .with_context(|| format!("load included file {:?}", included_path))?;
+        for (name, package) in included {
+            let (files, ignore) = package_files.entry(name).or_default();
+            files.extend(package.files);
+            ignore.extend(package.ignore);
+        }
+    }
+    let package_files = package_files
+        .into_iter()
+        .map(|(name, (files, ignore))| {
+            let files = expand_globs(files, &ignore)
+                .with_context(|| format!("expand globs of package {:?}", name))?;
+            Ok((name, files))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut selected = local.packages;
+    for profile in global.profiles.into_values() {
+        selected.extend(profile.packages);
+    }
+
+    let mut lint = global.lint;
+    lint.allow.extend(local.lint.allow);
+    Ok(Declared {
+        package_files,
+        auto_enabled,
+        selected,
+        local_files: expand_globs(local.files, &[]).context("expand globs of local.toml")?,
+        lint,
+    })
+}
+
+/// Whether commands that edit the configuration commit their edits, which local.toml decides
+/// if it sets `auto_commit`. A missing local.toml leaves it to global.toml.
+pub fn auto_commit(local_config: &Path, global_config: &Path) -> Result<bool> {
+    let local = if local_config.exists() {
+        let local: LocalConfig = filesystem::load_file(local_config)
+            .with_context(|| format!("load local config {:?}", local_config))?;
+        local.auto_commit
+    } else {
+        None
+    };
+    if let Some(auto_commit) = local {
+        return Ok(auto_commit);
+    }
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    Ok(global.auto_commit.unwrap_or(false))
+}
+
+/// The `network_probes` of local.toml, or of global.toml, for commands that don't load the
+/// whole configuration
+pub fn network_probes(local_config: &Path, global_config: &Path) -> Result<Vec<String>> {
+    let local: LocalConfig = filesystem::load_file(local_config)
+        .with_context(|| format!("load local config {:?}", local_config))?;
+    if let Some(probes) = local.network_probes {
+        return Ok(probes);
+    }
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    Ok(global
+        .network_probes
+        .unwrap_or_else(hooks::default_network_probes))
+}
+
+/// The `foreign` paths of global.toml and local.toml, for commands that don't load the whole
+/// configuration
+pub fn load_foreign(local_config: &Path, global_config: &Path) -> Result<ForeignPaths> {
+    let global: GlobalConfig = filesystem::load_file(global_config)
+        .with_context(|| format!("load global config {:?}", global_config))?;
+    let local: LocalConfig = filesystem::load_file(local_config)
+        .with_context(|| format!("load local config {:?}", local_config))?;
+    let mut foreign = global.foreign;
+    for (manager, paths) in local.foreign {
+        foreign.entry(manager).or_default().extend(paths);
+    }
+    expand_foreign(&mut foreign);
+    Ok(foreign)
+}
+
+fn expand_foreign(foreign: &mut ForeignPaths) {
+    for paths in foreign.values_mut() {
+        for path in paths.iter_mut() {
+            *path = filesystem::expand_tilde(&path.to_string_lossy()).into();
+        }
+    }
+}
+
+/// The configuration files that can declare files: global.toml, local.toml and its includes
+pub fn configuration_files(local_config: &Path, global_config: &Path) -> Result<Vec<PathBuf>> {
+    let local: LocalConfig = filesystem::load_file(local_config)
+        .with_context(|| format!("load local config {:?}", local_config))?;
+    let mut files = vec![global_config.to_path_buf(), local_config.to_path_buf()];
+    files.extend(local.includes);
+    Ok(files)
+}
+
+/// Version of the format of cache.toml, which is raised when the format changes in a way that
+/// older caches have to be migrated for, see `migrate_cache`
+pub const CACHE_SCHEMA: u32 = 1;
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Cache {
+    /// `CACHE_SCHEMA` of the dotter that saved the cache, 0 for caches from before schemas
+    #[serde(default)]
+    pub schema: u32,
+    /// Repository that the cache belongs to, so that a relocated cache isn't shared by
+    /// several repositories. Filled in when the cache is saved.
+    #[serde(default)]
+    pub repository: Option<PathBuf>,
+    /// Version of the dotter that saved the cache. Filled in when the cache is saved.
+    #[serde(default)]
+    pub dotter_version: Option<String>,
+    /// Packages whose notes were already printed
+    #[serde(default)]
+    pub shown_notes: BTreeSet<String>,
+    /// Lazy packages that were activated, whose files are deployed for real
+    #[serde(default)]
+    pub activated: BTreeSet<String>,
+    /// Targets that were deployed with `elevate = true`, so that removing them is elevated too
+    /// after they're gone from the configuration
+    #[serde(default)]
+    pub elevated: BTreeSet<PathBuf>,
+    /// Targets that were linked with `link_type = "junction"` or `"hardlink"`, so that they're
+    /// recognized and removed as such after they're gone from the configuration
+    #[serde(default)]
+    pub links: BTreeMap<PathBuf, LinkKind>,
+    /// Directory that keeps the rendered copy of every template deployed with `render_cache`,
+    /// by source, since the renders of the others are in the cache directory
+    #[serde(default)]
+    pub render_caches: BTreeMap<PathBuf, PathBuf>,
+    /// `on_undeploy` hooks of every deployed file and of its package, by source, so that they
+    /// run after the file is gone from the configuration
+    #[serde(default)]
+    pub undeploy_hooks: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    /// Environments of the `on_undeploy` hooks that don't run in the repository with the
+    /// inherited `PATH`, by hook, for when their package is gone from the configuration
+    #[serde(default)]
+    pub hook_environments: HookEnvironments,
+    /// Package of every shim that stands in for an executable of a lazy package, by target
+    #[serde(default)]
+    pub shims: BTreeMap<PathBuf, String>,
     pub symlinks: BTreeMap<PathBuf, PathBuf>,
     pub templates: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    pub copies: BTreeMap<PathBuf, PathBuf>,
+    /// Hash of the contents that every copy was deployed with, by source, so that changes to
+    /// the target are noticed
+    #[serde(default)]
+    pub copy_hashes: BTreeMap<PathBuf, String>,
+    #[serde(default)]
+    pub template_dependencies: BTreeMap<PathBuf, TemplateDependencies>,
+    /// Sources of every deployed target that is assembled from fragments, in order
+    #[serde(default)]
+    pub fragments: BTreeMap<PathBuf, Vec<PathBuf>>,
+    /// Lines that dotter added to each managed file, which are the only ones it removes again
+    #[serde(default)]
+    pub lines: ManagedLines,
+    /// Special files that dotter created, so that they're removed after they're gone from the
+    /// configuration
+    #[serde(default)]
+    pub special: SpecialFiles,
+    /// What the filesystems of the targets support, see `capabilities.rs`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub capabilities: ProbedCapabilities,
+    /// Files that were in the way of targets and were overwritten, oldest first.
+    /// Last because TOML writes arrays of tables after the other tables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backups: Vec<Backup>,
 }
 
-pub fn load_cache(cache: &Path) -> Result<Option<Cache>> {
+impl Cache {
+    /// Where the rendered copy of the deployed template `source` is kept
+    pub fn render_path(&self, source: &Path, cache_directory: &Path) -> PathBuf {
+        self.render_caches
+            .get(source)
+            .map_or(cache_directory, PathBuf::as_path)
+            .join(source)
+    }
+
+    /// Spells the sources and targets of the cache like the configuration does, where they only
+    /// differ in case on a case-insensitive filesystem. Renaming a file's case would otherwise
+    /// delete and create the same file, and the deletion could remove the new one.
+    pub fn match_case(&mut self, files: &Files) {
+        let sources = spellings(files.keys());
+        respell_keys(&mut self.symlinks, &sources);
+        respell_keys(&mut self.templates, &sources);
+        respell_keys(&mut self.copies, &sources);
+        respell_keys(&mut self.copy_hashes, &sources);
+        respell_keys(&mut self.template_dependencies, &sources);
+        respell_keys(&mut self.undeploy_hooks, &sources);
+        respell_keys(&mut self.render_caches, &sources);
+
+        let deployed = self
+            .symlinks
+            .iter_mut()
+            .chain(self.templates.iter_mut())
+            .chain(self.copies.iter_mut());
+        for (source, target) in deployed {
+            if let Some(configured) = files.get(source).map(FileTarget::path) {
+                if configured != target && filesystem::paths_equal(configured, target) {
+                    debug!("Respelling target {:?} as {:?}", target, configured);
+                    *target = configured.into();
+                }
+            }
+        }
+    }
+}
+
+/// The spelling of paths by how they compare on this filesystem
+fn spellings<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> BTreeMap<PathBuf, &'a PathBuf> {
+    paths
+        .map(|path| (filesystem::normalize_for_comparison(path), path))
+        .collect()
+}
+
+fn respell_keys<V>(map: &mut BTreeMap<PathBuf, V>, spellings: &BTreeMap<PathBuf, &PathBuf>) {
+    let misspelled = map
+        .keys()
+        .filter_map(|key| {
+            let spelling = *spellings.get(&filesystem::normalize_for_comparison(key))?;
+            Some((key.clone(), spelling.clone())).filter(|(key, spelling)| key != spelling)
+        })
+        .collect::<Vec<_>>();
+    for (key, spelling) in misspelled {
+        if map.contains_key(&spelling) {
+            continue;
+        }
+        debug!("Respelling source {:?} as {:?}", key, spelling);
+        let value = map.remove(&key).expect("key exists");
+        map.insert(spelling, value);
+    }
+}
+
+/// The repository is the directory dotter runs in
+fn current_repository() -> Result<PathBuf> {
+    let current = std::env::current_dir().context("get current directory")?;
+    filesystem::real_path(&current).context("get real path of current directory")
+}
+
+/// What a deployed template was rendered from, used to skip rendering it again
+/// when neither its source nor the variables it reads have changed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateDependencies {
+    /// Top-level variables that the template reads
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub variables: BTreeSet<String>,
+    /// Partials that the template includes, directly or through other partials
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub partials: BTreeSet<String>,
+    /// Hash of the template's source and the values of its variables. `None` when they can't be
+    /// determined, and the template is rendered on every deploy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Built-in helpers and facts that the template uses, with the version of their output that
+    /// it was rendered with, see `dependencies::builtin_version`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub helpers: BTreeMap<String, u32>,
+}
+
+impl TemplateDependencies {
+    /// Whether the variables and partials that the template reads are known
+    pub fn is_tracked(&self) -> bool {
+        self.hash.is_some()
+    }
+}
+
+pub fn load_cache(cache_path: &Path) -> Result<Option<Cache>> {
+    let cache = read_cache(cache_path)?;
+    if let Some(Cache {
+        repository: Some(repository),
+        ..
+    }) = &cache
+    {
+        let current = current_repository()?;
+        if repository != &current {
+            bail!(
+                "cache file {:?} belongs to the repository at {:?}, not {:?}. Use a different --cache-file and --cache-directory for every repository.",
+                cache_path,
+                repository,
+                current
+            );
+        }
+    }
+
+    Ok(cache)
+}
+
+/// Loads the cache without checking that it belongs to the current repository, for
+/// `migrate-home` after the repository moved with the home directory
+pub fn read_cache(cache_path: &Path) -> Result<Option<Cache>> {
     debug!("Loading cache...");
 
-    let cache = match filesystem::load_file(cache) {
+    let cache = match filesystem::load_file(cache_path) {
         Ok(cache) => Some(cache),
         Err(filesystem::FileLoadError::Open { .. }) => None,
         Err(e) => Err(e).context("load cache file")?,
     };
 
     trace!("Cache: {:#?}", cache);
+    cache.map(migrate_cache).transpose()
+}
 
+/// Brings a cache of an older schema up to `CACHE_SCHEMA`. Caches from before packages, with
+/// all files in a single `[files]` table, are converted by `legacy.rs` before they're loaded.
+pub fn migrate_cache(mut cache: Cache) -> Result<Cache> {
+    if cache.schema > CACHE_SCHEMA {
+        bail!(
+            "the cache is of schema {}, which is newer than the schema {} of this dotter. Update dotter.",
+            cache.schema,
+            CACHE_SCHEMA
+        );
+    }
+    if cache.schema < CACHE_SCHEMA {
+        debug!(
+            "Migrating cache from schema {} to {}",
+            cache.schema, CACHE_SCHEMA
+        );
+    }
+    // Schema 1 only added the schema number
+    cache.schema = CACHE_SCHEMA;
     Ok(cache)
 }
 
-pub fn save_cache(cache_file: &Path, cache: Cache) -> Result<()> {
+pub fn save_cache(cache_file: &Path, mut cache: Cache) -> Result<()> {
     debug!("Saving cache...");
+    cache.schema = CACHE_SCHEMA;
+    cache.repository = Some(current_repository()?);
+    cache.dotter_version = Some(env!("CARGO_PKG_VERSION").into());
     filesystem::save_file(cache_file, cache)?;
 
     Ok(())
 }
 
-pub fn save_dummy_config(
-    files: Vec<String>,
+/// Exclusive access to the cache file and the cache directory, so that a deploy doesn't run
+/// while `dotter watch` is deploying and the two don't overwrite each other's recorded state.
+/// The lock is released when this is dropped, including when the process dies.
+pub struct CacheLock {
+    _file: fs::File,
+}
+
+/// Locks the cache for the commands that write it, waiting for another dotter process to
+/// release it first. Dry runs don't write the cache, so they don't take the lock.
+pub fn lock_cache(cache_file: &Path, act: bool) -> Result<Option<CacheLock>> {
+    if !act {
+        return Ok(None);
+    }
+
+    let mut location = cache_file.as_os_str().to_owned();
+    location.push(".lock");
+    let location = PathBuf::from(location);
+    if let Some(parent) = location.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context("create parent of cache lock file")?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&location)
+        .with_context(|| format!("open cache lock file {:?}", location))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            warn!("Waiting for another dotter process to finish using the cache...");
+            file.lock().context("lock cache")?;
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(e).context("lock cache"),
+    }
+    debug!("Locked cache with {:?}", location);
+
+    Ok(Some(CacheLock { _file: file }))
+}
+
+/// Files of a package in a freshly initialized configuration, as `(source, target)` pairs
+pub type InitialPackages = BTreeMap<String, Vec<(String, String)>>;
+
+/// Writes a global.toml and local.toml that enable all the packages, with comments that
+/// explain the format to new users
+pub fn save_initial_config(
+    packages: &InitialPackages,
     local_config_path: &Path,
     global_config_path: &Path,
 ) -> Result<()> {
-    debug!("Saving dummy config...");
-    let package = Package {
-        files: files.into_iter().map(|f| (f.into(), "".into())).collect(),
-        variables: Variables::new(),
-    };
-    trace!("Default package: {:#?}", package);
+    debug!("Saving initial config...");
+    let mut global = String::from(
+        "# Packages are groups of files that are enabled together in local.toml.
+# Every file maps a path in this repository to the location it's deployed to.
+# Files that contain \"{{\" are rendered as templates, other files are symlinked.
+# Files with an empty target aren't deployed until you set one.
+",
+    );
+    for (package, files) in packages {
+        let files = files
+            .iter()
+            .map(|(source, target)| (source.clone(), toml::Value::String(target.clone())))
+            .collect::<Vec<_>>();
+        global.push_str(&package_section(package, &files, &[]));
+    }
+    let parsed: GlobalConfig = toml::from_str(&global).context("validate global config")?;
+    trace!("Global config: {:#?}", parsed);
+
+    let local = format!(
+        "# Configuration of this machine only, usually not committed.
+
+# Packages from global.toml that are deployed on this machine
+packages = [{}]
+
+# Files and variables that add to or override the ones from the enabled packages
+[files]
+
+[variables]
+",
+        packages
+            .keys()
+            .map(|p| toml_string(p))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let parsed: LocalConfig = toml::from_str(&local).context("validate local config")?;
+    trace!("Local config: {:#?}", parsed);
 
-    let mut packages = BTreeMap::new();
-    packages.insert("default".into(), package);
-    let global_config = GlobalConfig {
-        helpers: Helpers::new(),
-        packages,
-    };
     debug!("Saving global config...");
     // Assume default args so all parents are the same
     std::fs::create_dir_all(
@@ -201,54 +1570,230 @@ pub fn save_dummy_config(
             .context("get parent of global config")?,
     )
     .context("create parent of global config")?;
-    filesystem::save_file(global_config_path, global_config).context("save global config")?;
+    fs::write(global_config_path, global).context("save global config")?;
+    fs::write(local_config_path, local).context("save local config")?;
 
-    let local_config = LocalConfig {
-        includes: vec![],
-        packages: vec!["default".into()],
-        files: Files::default(),
-        variables: Variables::default(),
-    };
-    trace!("Local config: {:#?}", local_config);
-    filesystem::save_file(local_config_path, local_config).context("save local config")?;
+    Ok(())
+}
 
+/// Appends a new package to the end of global.toml, leaving the rest of the file untouched
+pub fn append_package(
+    global_config_path: &Path,
+    package: &str,
+    files: &[(String, toml::Value)],
+    variables: &[(String, toml::Value)],
+) -> Result<()> {
+    let mut global = fs::read_to_string(global_config_path).context("read global config")?;
+    if !global.is_empty() && !global.ends_with('\n') {
+        global.push('\n');
+    }
+    global.push_str(&package_section(package, files, variables));
+    let parsed: GlobalConfig = toml::from_str(&global).context("validate global config")?;
+    trace!("Global config: {:#?}", parsed);
+    fs::write(global_config_path, global).context("save global config")?;
+    Ok(())
+}
+
+/// Adds a file to the `[<package>.files]` table of global.toml, which is appended if the file
+/// doesn't have one. The rest of the file is left untouched.
+pub fn add_package_file(
+    global_config_path: &Path,
+    package: &str,
+    source: &str,
+    target: &toml::Value,
+) -> Result<()> {
+    let global = fs::read_to_string(global_config_path).context("read global config")?;
+    let edited = with_package_file(&global, package, source, target);
+    let parsed: GlobalConfig = toml::from_str(&edited).context("validate global config")?;
+    if !parsed
+        .packages
+        .get(package)
+        .is_some_and(|p| p.files.contains_key(Path::new(source)))
+    {
+        bail!(
+            "couldn't add {:?} to package {:?} in {:?}. Please add it by hand.",
+            source,
+            package,
+            global_config_path
+        );
+    }
+    fs::write(global_config_path, edited).context("save global config")?;
     Ok(())
 }
 
+fn with_package_file(global: &str, package: &str, source: &str, target: &toml::Value) -> String {
+    let assignment = format!("{} = {}", toml_key(source), toml_inline(target));
+    let header = format!("[{}.files]", toml_key(package));
+    let mut lines = global.lines().map(String::from).collect::<Vec<_>>();
+    match lines.iter().position(|l| l.trim() == header) {
+        Some(start) => {
+            let end = lines
+                .iter()
+                .skip(start + 1)
+                .position(|l| l.trim_start().starts_with('['))
+                .map_or(lines.len(), |i| start + 1 + i);
+            // After the last line that isn't blank, so that blank lines before the next table stay
+            let last = lines[start + 1..end]
+                .iter()
+                .rposition(|l| !l.trim().is_empty())
+                .map_or(start + 1, |i| start + 2 + i);
+            lines.insert(last, assignment);
+        }
+        None => {
+            if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header);
+            lines.push(assignment);
+        }
+    }
+    let mut edited = lines.join("\n");
+    edited.push('\n');
+    edited
+}
+
+fn package_section(
+    package: &str,
+    files: &[(String, toml::Value)],
+    variables: &[(String, toml::Value)],
+) -> String {
+    let mut section = format!("\n[{}.files]\n", toml_key(package));
+    if files.is_empty() {
+        section.push_str(&format!(
+            "# {} = \"~/.config/{}/config\"\n",
+            toml_string(&format!("{}/config", package)),
+            package
+        ));
+    }
+    for (source, target) in files {
+        section.push_str(&format!("{} = {}\n", toml_key(source), toml_inline(target)));
+    }
+    section.push_str(&format!(
+        "\n# Variables that the package's templates can use\n[{}.variables]\n",
+        toml_key(package)
+    ));
+    for (name, value) in variables {
+        section.push_str(&format!("{} = {}\n", toml_key(name), toml_inline(value)));
+    }
+    section
+}
+
+pub fn toml_string(s: &str) -> String {
+    toml::Value::String(s.into()).to_string()
+}
+
+/// Formats a value on a single line, so that tables become inline tables
+pub fn toml_inline(value: &toml::Value) -> String {
+    match value {
+        toml::Value::Table(table) => format!(
+            "{{ {} }}",
+            table
+                .iter()
+                .map(|(key, value)| format!("{} = {}", toml_key(key), toml_inline(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        value => value.to_string(),
+    }
+}
+
+pub fn toml_key(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        s.to_string()
+    } else {
+        toml_string(s)
+    }
+}
+
+/// Merges `new` on top of `original`, following the strategy of each variable.
+/// `prefix` is the dotted path of the tables, empty at the top level.
 fn recursive_extend_map(
     original: &mut BTreeMap<String, toml::Value>,
     new: BTreeMap<String, toml::Value>,
+    strategies: &MergeStrategies,
+    prefix: &str,
 ) {
     for (key, new_value) in new.into_iter() {
-        original
-            .entry(key)
-            .and_modify(|original_value| {
-                match (
-                    original_value.as_table().cloned(),
-                    new_value.as_table().cloned(),
-                ) {
-                    (Some(mut original_table), Some(new_table)) => {
-                        recursive_extend_map(&mut original_table, new_table);
-                        *original_value = original_table.into();
-                    }
-                    _ => *original_value = new_value.clone(),
-                }
-            })
-            .or_insert(new_value);
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        let merged = match (original.remove(&key), new_value, strategies.get(&path)) {
+            (
+                Some(toml::Value::Table(mut original_table)),
+                toml::Value::Table(new_table),
+                None | Some(MergeStrategy::DeepMerge),
+            ) => {
+                recursive_extend_map(&mut original_table, new_table, strategies, &path);
+                original_table.into()
+            }
+            (
+                Some(toml::Value::Array(mut original_list)),
+                toml::Value::Array(new_list),
+                Some(MergeStrategy::AppendList),
+            ) => {
+                original_list.extend(new_list);
+                original_list.into()
+            }
+            (_, new_value, _) => new_value,
+        };
+        original.insert(key, merged);
     }
 }
 
+/// Whether a strategy merges `variable` or a variable inside of it
+fn has_merge_strategy(strategies: &MergeStrategies, variable: &str) -> bool {
+    strategies.keys().any(|path| {
+        path == variable
+            || path
+                .strip_prefix(variable)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
 #[allow(clippy::map_entry)]
 fn merge_configuration_files(
     mut global: GlobalConfig,
     local: LocalConfig,
+    profile_variables: Variables,
+    ansible_variables: Variables,
+    remote_variables: Variables,
+    variable_providers: &[String],
     patch: Option<Package>,
 ) -> Result<Configuration> {
+    let mut strategies = std::mem::take(&mut global.merge);
+    for (package_name, package) in &global.packages {
+        for (variable, strategy) in &package.merge {
+            match strategies.insert(variable.clone(), *strategy) {
+                Some(existing) if existing != *strategy => bail!(
+                    "package {:?} merges variable {:?} with {:?}, but it's merged with {:?} elsewhere",
+                    package_name,
+                    variable,
+                    strategy,
+                    existing
+                ),
+                _ => {}
+            }
+        }
+    }
+    strategies.extend(local.merge);
+
+    for (package_name, package) in global.packages.iter_mut() {
+        if let Some(directory) = &package.files_from {
+            load_files_from(&mut package.files, directory)
+                .with_context(|| format!("load files_from of package {:?}", package_name))?;
+        }
+    }
+
     // Patch each package with included.toml's
     for included_path in &local.includes {
         || -> Result<()> {
             let mut included: IncludedConfig =
-                filesystem::load_file(&included_path).context("load file")?;
+                filesystem::load_file(included_path).context("load file")?;
 
             debug!("Included config {:?}", included_path);
             trace!("{:#?}", included);
@@ -257,14 +1802,24 @@ fn merge_configuration_files(
             for (package_name, package_global) in global.packages.iter_mut() {
                 if let Some(package_included) = included.remove(package_name) {
                     package_global.files.extend(package_included.files);
-                    recursive_extend_map(&mut package_global.variables, package_included.variables);
+                    package_global.ignore.extend(package_included.ignore);
+                    package_global.when.extend(package_included.when);
+                    package_global.exports.extend(package_included.exports);
+                    extend_lines(&mut package_global.lines, package_included.lines);
+                    package_global.special.extend(package_included.special);
+                    recursive_extend_map(
+                        &mut package_global.variables,
+                        package_included.variables,
+                        &strategies,
+                        "",
+                    );
                 }
             }
 
             if !included.is_empty() {
                 bail!(
                     "unknown packages: {:?}",
-                    included.keys().into_iter().cloned().collect::<Vec<_>>()
+                    included.keys().cloned().collect::<Vec<_>>()
                 );
             }
 
@@ -274,38 +1829,233 @@ fn merge_configuration_files(
     }
 
     // Apply packages filter
-    global.packages = global
-        .packages
-        .into_iter()
-        .filter(|(k, _)| local.packages.contains(&k))
-        .collect();
+    let mut enabled_packages = local.packages;
+    // Packages that are dependencies of enabled ones aren't skipped after all
+    let mut skipped = Vec::new();
+    for (package_name, package) in &global.packages {
+        if enabled_packages.contains(package_name) {
+            continue;
+        }
+        if let Some(condition) = &package.condition {
+            if condition::holds(condition)
+                .with_context(|| format!("check condition of package {:?}", package_name))?
+            {
+                debug!(
+                    "Enabling package {:?} because {:?} holds",
+                    package_name, condition
+                );
+                enabled_packages.push(package_name.clone());
+                continue;
+            }
+            skipped.push((
+                package_name,
+                format!("its condition {:?} doesn't hold", condition),
+            ));
+        }
+        if let Some(auto_enable) = &package.auto_enable {
+            if auto_enable
+                .is_satisfied()
+                .with_context(|| format!("check auto_enable of package {:?}", package_name))?
+            {
+                debug!(
+                    "Enabling package {:?} because {:?} is installed",
+                    package_name, auto_enable.command
+                );
+                enabled_packages.push(package_name.clone());
+                continue;
+            }
+            skipped.push((
+                package_name,
+                format!(
+                    "its auto_enable command {:?} isn't installed",
+                    auto_enable.command
+                ),
+            ));
+        } else if package.condition.is_none() {
+            skipped.push((
+                package_name,
+                "it isn't in the packages of local.toml".into(),
+            ));
+        }
+    }
+    let package_levels = resolve_dependencies(&global.packages, &enabled_packages)
+        .context("resolve dependencies between packages")?;
+    for package_name in package_levels.keys() {
+        if !enabled_packages.contains(package_name) {
+            debug!(
+                "Enabling package {:?} because an enabled package depends on it",
+                package_name
+            );
+            enabled_packages.push(package_name.clone());
+        }
+    }
+    for (package_name, reason) in skipped {
+        if package_levels.contains_key(package_name) {
+            continue;
+        }
+        info!("Skipping package {:?}: {}", package_name, reason);
+        for file in global.packages[package_name].files.keys() {
+            info!(
+                "Skipping {:?}: its package {:?} isn't enabled",
+                file, package_name
+            );
+        }
+    }
+    enabled_packages.sort_by_key(|package| package_levels.get(package).copied());
+    global.packages.retain(|k, _| enabled_packages.contains(k));
+
+    // Providers are told about every enabled package, including the ones that conditions and
+    // dependencies enabled
+    let provided_variables = if variable_providers.is_empty() {
+        Variables::new()
+    } else {
+        variable_providers::provide(variable_providers, &enabled_packages)
+            .context("get variables of variable providers")?
+    };
+    trace!("Provided variables: {:#?}", provided_variables);
+
+    for (package_name, package) in global.packages.iter_mut() {
+        for block in std::mem::take(&mut package.when) {
+            if condition::holds(&block.condition)
+                .with_context(|| format!("check `when` condition of package {:?}", package_name))?
+            {
+                recursive_extend_map(&mut package.variables, block.variables, &strategies, "");
+            }
+        }
+        package.files = expand_globs(std::mem::take(&mut package.files), &package.ignore)
+            .with_context(|| format!("expand globs of package {:?}", package_name))?;
+    }
+
+    let mut private_variables = BTreeMap::new();
+    if global.isolate_variables {
+        for (package_name, package) in global.packages.iter_mut() {
+            for export in &package.exports {
+                if !package.variables.contains_key(export) {
+                    warnings::warn(
+                        "undefined-export",
+                        export,
+                        format!(
+                            "Package {:?} exports variable {:?}, which it doesn't define",
+                            package_name, export
+                        ),
+                    );
+                }
+            }
+            let (exported, private) = std::mem::take(&mut package.variables)
+                .into_iter()
+                .partition(|(name, _)| package.exports.contains(name));
+            package.variables = exported;
+            private_variables.insert(package_name.clone(), private);
+        }
+    }
+    let local_files = expand_globs(local.files, &[]).context("expand globs of local.toml")?;
 
     let mut output = Configuration {
         helpers: global.helpers,
         files: Files::default(),
+        file_packages: FilePackages::default(),
         variables: Variables::default(),
-        packages: local.packages,
+        packages: enabled_packages,
+        package_levels,
+        notes: global
+            .packages
+            .iter()
+            .filter_map(|(name, package)| Some((name.clone(), package.notes.clone()?)))
+            .collect(),
+        package_hooks: global
+            .packages
+            .iter()
+            .filter(|(_, package)| {
+                package.on_deploy.is_some()
+                    || package.on_undeploy.is_some()
+                    || package.cwd.is_some()
+                    || !package.path_prepend.is_empty()
+                    || package.requires_network
+                    || !package.env.is_empty()
+            })
+            .map(|(name, package)| {
+                let hooks = PackageHooks {
+                    on_deploy: package.on_deploy.clone(),
+                    on_undeploy: package.on_undeploy.clone(),
+                    cwd: package.cwd.clone(),
+                    path_prepend: package.path_prepend.clone(),
+                    requires_network: package.requires_network,
+                    env: package.env.clone(),
+                };
+                (name.clone(), hooks)
+            })
+            .collect(),
+        private_variables: BTreeMap::new(),
+        missing_sources: BTreeMap::new(),
+        foreign: global.foreign,
+        allow_fetch: local.allow_fetch.or(global.allow_fetch).unwrap_or(false),
+        allow_program_helpers: local
+            .allow_program_helpers
+            .or(global.allow_program_helpers)
+            .unwrap_or(false),
+        network_probes: local
+            .network_probes
+            .or(global.network_probes)
+            .unwrap_or_else(hooks::default_network_probes),
+        partials: global
+            .partials
+            .or_else(|| Some(PathBuf::from("partials")).filter(|partials| partials.is_dir())),
+        lines: ManagedLines::new(),
+        special: SpecialFiles::new(),
+        secrets: local.secrets.or(global.secrets).unwrap_or_default(),
+        env: std::mem::take(&mut global.env),
+        merge_tool: local.merge_tool,
+        allowed_target_roots: local.allowed_target_roots.or(global.allowed_target_roots),
+        hash_algorithm: global.hash_algorithm,
+        template_engines: global
+            .packages
+            .iter()
+            .filter_map(|(name, package)| Some((name.clone(), package.template_engine?)))
+            .collect(),
+        lazy_packages: global
+            .packages
+            .iter()
+            .filter(|(_, package)| package.lazy)
+            .map(|(name, _)| name.clone())
+            .collect(),
+        shims: BTreeMap::new(),
     };
+    for (name, package) in global.packages.iter_mut() {
+        extend_lines(&mut output.lines, std::mem::take(&mut package.lines));
+        declare_special(&mut package.special, Some(name));
+        output.special.append(&mut package.special);
+    }
+
+    // Layer the files of the packages in the order they're deployed
+    let mut layers = Layers::new(global.duplicate_targets);
+    for package_name in &output.packages {
+        if let Some(package) = global.packages.get_mut(package_name) {
+            layers
+                .add(
+                    std::mem::take(&mut package.files),
+                    &format!("package {:?}", package_name),
+                    Some(package_name),
+                )
+                .with_context(|| format!("merge package {:?}", package_name))?;
+        }
+    }
 
     // Merge all the packages
     let mut configuration_packages = global.packages.into_iter();
-    let mut first_package = configuration_packages
+    let (_, mut first_package) = configuration_packages
         .next()
-        .unwrap_or_else(|| (String::new(), Package::default()))
-        .1;
+        .unwrap_or_else(|| (String::new(), Package::default()));
     for (package_name, package) in configuration_packages {
         || -> Result<()> {
-            for (file_name, file_target) in package.files {
-                if first_package.files.contains_key(&file_name) {
-                    bail!("file {:?} already encountered", file_name);
-                } else {
-                    first_package.files.insert(file_name, file_target);
-                }
-            }
-
             for (variable_name, variable_value) in package.variables {
                 if first_package.variables.contains_key(&variable_name) {
-                    bail!("variable {:?} already encountered", variable_name);
+                    // Packages can only both define a variable that has a merge strategy
+                    if !has_merge_strategy(&strategies, &variable_name) {
+                        bail!("variable {:?} already encountered", variable_name);
+                    }
+                    let mut variable = Variables::new();
+                    variable.insert(variable_name, variable_value);
+                    recursive_extend_map(&mut first_package.variables, variable, &strategies, "");
                 } else {
                     first_package
                         .variables
@@ -317,27 +2067,214 @@ fn merge_configuration_files(
         }()
         .with_context(|| format!("merge package {:?}", package_name))?;
     }
-    output.files = first_package.files;
     output.variables = first_package.variables;
 
+    // Add the profile's variables
+    override_private_variables(&mut private_variables, &profile_variables, &strategies);
+    recursive_extend_map(&mut output.variables, profile_variables, &strategies, "");
+
+    // Read env("...") and cmd("...") once the repository's overrides are in, so that overridden
+    // ones don't run. The values of the sources outside of the repository are taken as they are,
+    // so that they can't run commands.
+    variable_sources::resolve(&mut output.variables)?;
+    for (package_name, private) in &mut private_variables {
+        variable_sources::resolve(private)
+            .with_context(|| format!("resolve private variables of package {:?}", package_name))?;
+    }
+
+    // Add Ansible's, remote and provided variables
+    override_private_variables(&mut private_variables, &ansible_variables, &strategies);
+    override_private_variables(&mut private_variables, &remote_variables, &strategies);
+    override_private_variables(&mut private_variables, &provided_variables, &strategies);
+    recursive_extend_map(&mut output.variables, ansible_variables, &strategies, "");
+    recursive_extend_map(&mut output.variables, remote_variables, &strategies, "");
+    recursive_extend_map(&mut output.variables, provided_variables, &strategies, "");
+
     // Add local.toml's patches
-    output.files.extend(local.files);
-    recursive_extend_map(&mut output.variables, local.variables);
+    layers.add(local_files, "local.toml", None)?;
+    extend_lines(&mut output.lines, local.lines);
+    let mut local_special = local.special;
+    declare_special(&mut local_special, None);
+    output.special.extend(local_special);
+    output.env.extend(local.env);
+    for (manager, paths) in local.foreign {
+        output.foreign.entry(manager).or_default().extend(paths);
+    }
+    let mut local_variables = local.variables;
+    variable_sources::resolve(&mut local_variables).context("resolve variables of local.toml")?;
+    override_private_variables(&mut private_variables, &local_variables, &strategies);
+    recursive_extend_map(&mut output.variables, local_variables, &strategies, "");
 
     // Add manual patch
     if let Some(patch) = patch {
-        output.files.extend(patch.files);
-        recursive_extend_map(&mut output.variables, patch.variables);
+        layers.add(
+            expand_globs(patch.files, &patch.ignore).context("expand globs of patch")?,
+            "the patch",
+            None,
+        )?;
+        extend_lines(&mut output.lines, patch.lines);
+        let mut patch_special = patch.special;
+        declare_special(&mut patch_special, None);
+        output.special.extend(patch_special);
+        let mut patch_variables = patch.variables;
+        variable_sources::resolve(&mut patch_variables)
+            .context("resolve variables of the patch")?;
+        override_private_variables(&mut private_variables, &patch_variables, &strategies);
+        recursive_extend_map(&mut output.variables, patch_variables, &strategies, "");
+    }
+
+    output.files = layers.files;
+    output.file_packages = layers.file_packages;
+
+    output.private_variables = private_variables;
+
+    // Remove files with target = ""
+    output.files.retain(|_, v| v.path().to_string_lossy() != "");
+    let files = &output.files;
+    output.file_packages.retain(|k, _| files.contains_key(k));
+
+    Ok(output)
+}
+
+/// The files of the layers of the configuration that were added so far, see `DuplicateTargets`
+struct Layers {
+    files: Files,
+    file_packages: FilePackages,
+    /// Source of every target, except the fragments, which are meant to share their target
+    sources: BTreeMap<PathBuf, PathBuf>,
+    /// Layer that added every source
+    layers: BTreeMap<PathBuf, String>,
+    resolution: DuplicateTargets,
+}
+
+impl Layers {
+    fn new(resolution: DuplicateTargets) -> Layers {
+        Layers {
+            files: Files::new(),
+            file_packages: FilePackages::new(),
+            sources: BTreeMap::new(),
+            layers: BTreeMap::new(),
+            resolution,
+        }
+    }
+
+    /// Adds the files of the layer `name`, which belong to `package` unless it's local.toml or
+    /// the patch, on top of the earlier layers
+    fn add(&mut self, files: Files, name: &str, package: Option<&str>) -> Result<()> {
+        for (source, target) in files {
+            if let Some(earlier) = self.layers.get(&source) {
+                if package.is_some() && self.resolution == DuplicateTargets::Error {
+                    bail!("file {:?} already encountered", source);
+                }
+                let message = format!(
+                    "Deploying {:?} as {} declares it, instead of {}",
+                    source, name, earlier
+                );
+                // local.toml and the patch are meant to override the packages
+                if package.is_some() {
+                    warnings::warn("shadowed", source.display(), message);
+                } else {
+                    info!("{}", message);
+                }
+                self.sources.retain(|_, s| *s != source);
+            }
+
+            let key = expand_target(target.path());
+            let shared = matches!(target, FileTarget::Fragment(_)) || key.as_os_str().is_empty();
+            if let Some(other) = self.sources.get(&key).filter(|_| !shared).cloned() {
+                if self.resolution == DuplicateTargets::Error {
+                    bail!(
+                        "{:?} of {} and {:?} of {} are both deployed to {:?}",
+                        source,
+                        name,
+                        other,
+                        self.layers[&other],
+                        key
+                    );
+                }
+                let message = format!(
+                    "Deploying {:?} to {:?} as {} declares, instead of {:?} of {}",
+                    source, key, name, other, self.layers[&other]
+                );
+                if package.is_some() {
+                    warnings::warn("shadowed", other.display(), message);
+                } else {
+                    info!("{}", message);
+                }
+                self.remove(&other);
+            }
+
+            if !shared {
+                self.sources.insert(key, source.clone());
+            }
+            if let Some(package) = package {
+                self.file_packages.insert(source.clone(), package.into());
+            }
+            self.layers.insert(source.clone(), name.into());
+            self.files.insert(source, target);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, source: &Path) {
+        self.files.remove(source);
+        self.file_packages.remove(source);
+        self.layers.remove(source);
+        self.sources.retain(|_, s| s != source);
+    }
+}
+
+/// Adds the files of every `.toml` file in `directory`, in the order of their names. A source
+/// that's declared twice is an error, since either target could be the intended one.
+fn load_files_from(files: &mut Files, directory: &Path) -> Result<()> {
+    let mut fragments = fs::read_dir(directory)
+        .with_context(|| format!("read directory {:?}", directory))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("read directory {:?}", directory))?;
+    fragments.retain(|path| path.extension().is_some_and(|e| e == "toml"));
+    fragments.sort();
+    for fragment in fragments {
+        let fragment_files: Files =
+            filesystem::load_file(&fragment).with_context(|| format!("load {:?}", fragment))?;
+        for (source, target) in fragment_files {
+            if files.contains_key(&source) {
+                bail!("file {:?} of {:?} is already declared", source, fragment);
+            }
+            files.insert(source, target);
+        }
     }
+    Ok(())
+}
 
-    // Remove files with target = ""
-    output.files = output
-        .files
-        .into_iter()
-        .filter(|(_, v)| v.path().to_string_lossy() != "")
-        .collect();
+/// Variables of local.toml and the other overrides also override the private variables of
+/// the same name, or they'd be shadowed by them
+fn override_private_variables(
+    private_variables: &mut BTreeMap<String, Variables>,
+    overrides: &Variables,
+    strategies: &MergeStrategies,
+) {
+    for private in private_variables.values_mut() {
+        let overrides = overrides
+            .iter()
+            .filter(|(name, _)| private.contains_key(*name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        recursive_extend_map(private, overrides, strategies, "");
+    }
+}
 
-    Ok(output)
+/// Several packages can manage lines of the same file, each line is kept once
+fn extend_lines(lines: &mut ManagedLines, new: ManagedLines) {
+    for (target, new) in new {
+        let existing = lines.entry(target).or_default();
+        for line in new {
+            let line = line.trim().to_string();
+            if !line.is_empty() && !existing.contains(&line) {
+                existing.push(line);
+            }
+        }
+    }
 }
 
 impl<'de> serde::Deserialize<'de> for FileTarget {
@@ -350,8 +2287,37 @@ impl<'de> serde::Deserialize<'de> for FileTarget {
         enum Field {
             Target,
             Owner,
+            Group,
             Append,
             Prepend,
+            Order,
+            Volatile,
+            WriteOnce,
+            Critical,
+            AllowOverwrite,
+            Elevate,
+            Reload,
+            ServiceUnit,
+            OnDeploy,
+            OnUndeploy,
+            Equivalence,
+            Diff,
+            DirectoryOwner,
+            DirectoryMode,
+            Mode,
+            After,
+            Template,
+            LiteralTarget,
+            TemplateEngine,
+            LinkType,
+            RenderCache,
+            Validate,
+            Attributes,
+            Acl,
+            LiteralBraces,
+            Flags,
+            Encrypted,
+            Verify,
             Type,
         }
 
@@ -378,8 +2344,37 @@ impl<'de> serde::Deserialize<'de> for FileTarget {
                 let mut file_type = None;
                 let mut target = None;
                 let mut owner = None;
+                let mut group = None;
                 let mut append = None;
                 let mut prepend = None;
+                let mut order = None;
+                let mut volatile = None;
+                let mut write_once = None;
+                let mut critical = None;
+                let mut allow_overwrite = None;
+                let mut elevate = None;
+                let mut reload = None;
+                let mut service_unit = None;
+                let mut on_deploy = None;
+                let mut on_undeploy = None;
+                let mut equivalence = None;
+                let mut diff = None;
+                let mut directory_owner = None;
+                let mut directory_mode = None;
+                let mut mode = None;
+                let mut after = None;
+                let mut template = None;
+                let mut literal_target = None;
+                let mut template_engine = None;
+                let mut link_type = None;
+                let mut render_cache = None;
+                let mut validate = None;
+                let mut attributes = None;
+                let mut acl = None;
+                let mut literal_braces = None;
+                let mut flags = None;
+                let mut encrypted = None;
+                let mut verify = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -401,6 +2396,12 @@ impl<'de> serde::Deserialize<'de> for FileTarget {
                             }
                             owner = Some(map.next_value()?);
                         }
+                        Field::Group => {
+                            if group.is_some() {
+                                return Err(serde::de::Error::duplicate_field("group"));
+                            }
+                            group = Some(map.next_value()?);
+                        }
                         Field::Append => {
                             if append.is_some() {
                                 return Err(serde::de::Error::duplicate_field("append"));
@@ -413,30 +2414,433 @@ impl<'de> serde::Deserialize<'de> for FileTarget {
                             }
                             prepend = Some(map.next_value()?);
                         }
+                        Field::LiteralTarget => {
+                            if literal_target.is_some() {
+                                return Err(serde::de::Error::duplicate_field("literal_target"));
+                            }
+                            literal_target = Some(map.next_value()?);
+                        }
+                        Field::LinkType => {
+                            if link_type.is_some() {
+                                return Err(serde::de::Error::duplicate_field("link_type"));
+                            }
+                            link_type = Some(map.next_value::<String>()?);
+                        }
+                        Field::RenderCache => {
+                            if render_cache.is_some() {
+                                return Err(serde::de::Error::duplicate_field("render_cache"));
+                            }
+                            render_cache = Some(map.next_value::<PathBuf>()?);
+                        }
+                        Field::Validate => {
+                            if validate.is_some() {
+                                return Err(serde::de::Error::duplicate_field("validate"));
+                            }
+                            validate = Some(map.next_value::<String>()?);
+                        }
+                        Field::Attributes => {
+                            if attributes.is_some() {
+                                return Err(serde::de::Error::duplicate_field("attributes"));
+                            }
+                            attributes = Some(map.next_value()?);
+                        }
+                        Field::Acl => {
+                            if acl.is_some() {
+                                return Err(serde::de::Error::duplicate_field("acl"));
+                            }
+                            acl = Some(map.next_value()?);
+                        }
+                        Field::Encrypted => {
+                            if encrypted.is_some() {
+                                return Err(serde::de::Error::duplicate_field("encrypted"));
+                            }
+                            encrypted = Some(map.next_value()?);
+                        }
+                        Field::Verify => {
+                            if verify.is_some() {
+                                return Err(serde::de::Error::duplicate_field("verify"));
+                            }
+                            verify = Some(map.next_value::<Verify>()?);
+                        }
+                        Field::LiteralBraces => {
+                            if literal_braces.is_some() {
+                                return Err(serde::de::Error::duplicate_field("literal_braces"));
+                            }
+                            literal_braces = Some(map.next_value()?);
+                        }
+                        Field::Flags => {
+                            if flags.is_some() {
+                                return Err(serde::de::Error::duplicate_field("flags"));
+                            }
+                            flags = Some(map.next_value::<Vec<String>>()?);
+                        }
+                        Field::TemplateEngine => {
+                            if template_engine.is_some() {
+                                return Err(serde::de::Error::duplicate_field("template_engine"));
+                            }
+                            template_engine = Some(map.next_value()?);
+                        }
+                        Field::Template => {
+                            if template.is_some() {
+                                return Err(serde::de::Error::duplicate_field("template"));
+                            }
+                            template = Some(map.next_value()?);
+                        }
+                        Field::Order => {
+                            if order.is_some() {
+                                return Err(serde::de::Error::duplicate_field("order"));
+                            }
+                            order = Some(map.next_value()?);
+                        }
+                        Field::Volatile => {
+                            if volatile.is_some() {
+                                return Err(serde::de::Error::duplicate_field("volatile"));
+                            }
+                            volatile = Some(map.next_value()?);
+                        }
+                        Field::WriteOnce => {
+                            if write_once.is_some() {
+                                return Err(serde::de::Error::duplicate_field("write_once"));
+                            }
+                            write_once = Some(map.next_value()?);
+                        }
+                        Field::Critical => {
+                            if critical.is_some() {
+                                return Err(serde::de::Error::duplicate_field("critical"));
+                            }
+                            critical = Some(map.next_value()?);
+                        }
+                        Field::AllowOverwrite => {
+                            if allow_overwrite.is_some() {
+                                return Err(serde::de::Error::duplicate_field("allow_overwrite"));
+                            }
+                            allow_overwrite = Some(map.next_value()?);
+                        }
+                        Field::Elevate => {
+                            if elevate.is_some() {
+                                return Err(serde::de::Error::duplicate_field("elevate"));
+                            }
+                            elevate = Some(map.next_value()?);
+                        }
+                        Field::Reload => {
+                            if reload.is_some() {
+                                return Err(serde::de::Error::duplicate_field("reload"));
+                            }
+                            reload = Some(map.next_value()?);
+                        }
+                        Field::ServiceUnit => {
+                            if service_unit.is_some() {
+                                return Err(serde::de::Error::duplicate_field("service_unit"));
+                            }
+                            service_unit = Some(map.next_value()?);
+                        }
+                        Field::OnDeploy => {
+                            if on_deploy.is_some() {
+                                return Err(serde::de::Error::duplicate_field("on_deploy"));
+                            }
+                            on_deploy = Some(map.next_value()?);
+                        }
+                        Field::OnUndeploy => {
+                            if on_undeploy.is_some() {
+                                return Err(serde::de::Error::duplicate_field("on_undeploy"));
+                            }
+                            on_undeploy = Some(map.next_value()?);
+                        }
+                        Field::Equivalence => {
+                            if equivalence.is_some() {
+                                return Err(serde::de::Error::duplicate_field("equivalence"));
+                            }
+                            equivalence = Some(map.next_value()?);
+                        }
+                        Field::Diff => {
+                            if diff.is_some() {
+                                return Err(serde::de::Error::duplicate_field("diff"));
+                            }
+                            diff = Some(map.next_value()?);
+                        }
+                        Field::DirectoryOwner => {
+                            if directory_owner.is_some() {
+                                return Err(serde::de::Error::duplicate_field("directory_owner"));
+                            }
+                            directory_owner = Some(map.next_value()?);
+                        }
+                        Field::After => {
+                            if after.is_some() {
+                                return Err(serde::de::Error::duplicate_field("after"));
+                            }
+                            after = Some(map.next_value()?);
+                        }
+                        Field::DirectoryMode => {
+                            if directory_mode.is_some() {
+                                return Err(serde::de::Error::duplicate_field("directory_mode"));
+                            }
+                            let mode: String = map.next_value()?;
+                            directory_mode = Some(parse_mode(&mode).ok_or_else(|| {
+                                serde::de::Error::custom(format!(
+                                    "invalid directory_mode {:?}, expected an octal number like \"755\"",
+                                    mode
+                                ))
+                            })?);
+                        }
+                        Field::Mode => {
+                            if mode.is_some() {
+                                return Err(serde::de::Error::duplicate_field("mode"));
+                            }
+                            let value: String = map.next_value()?;
+                            mode = Some(parse_mode(&value).ok_or_else(|| {
+                                serde::de::Error::custom(format!(
+                                    "invalid mode {:?}, expected an octal number like \"644\"",
+                                    value
+                                ))
+                            })?);
+                        }
                     }
                 }
 
-                let file_type = file_type.ok_or_else(|| serde::de::Error::missing_field("type"))?;
-                let target = target.ok_or_else(|| serde::de::Error::missing_field("target"))?;
+                // Encrypted files can only be deployed as templates, so they don't need the type
+                let file_type = match (file_type, encrypted) {
+                    (Some(file_type), _) => file_type,
+                    (None, Some(true)) => "template",
+                    (None, _) => return Err(serde::de::Error::missing_field("type")),
+                };
+                let target: PathBuf =
+                    target.ok_or_else(|| serde::de::Error::missing_field("target"))?;
+                let target = if literal_target.unwrap_or(false) {
+                    target
+                } else {
+                    expand_target(&target)
+                };
+                let after = after.map(|after: Vec<PathBuf>| {
+                    after
+                        .iter()
+                        .map(|path| expand_target(path))
+                        .collect::<Vec<_>>()
+                });
+                if write_once.is_some() && file_type != "template" && file_type != "copy" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `write_once` on a target that isn't a template or a copy",
+                    ));
+                }
+                let (recursive, link) = match link_type.as_deref() {
+                    None => (false, LinkKind::Symlink),
+                    Some("recursive") if file_type != "fragment" => (true, LinkKind::Symlink),
+                    Some("recursive") => {
+                        return Err(serde::de::Error::custom(
+                            "invalid use of `link_type` on a fragment target",
+                        ))
+                    }
+                    Some("junction") if file_type == "symbolic" => (false, LinkKind::Junction),
+                    Some("hardlink") if file_type == "symbolic" => (false, LinkKind::Hardlink),
+                    Some("junction") | Some("hardlink") => {
+                        return Err(serde::de::Error::custom(
+                            "invalid use of `link_type = \"junction\"` or `\"hardlink\"` on a target that isn't symbolic",
+                        ))
+                    }
+                    Some(other) => {
+                        return Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Str(other),
+                            &"`recursive`, `junction` or `hardlink`",
+                        ))
+                    }
+                };
+                if link != LinkKind::Symlink && elevate == Some(true) {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `elevate` on a junction or a hardlink",
+                    ));
+                }
+                if render_cache.is_some() && file_type != "template" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `render_cache` on a target that isn't a template",
+                    ));
+                }
+                if (attributes.is_some() || acl.is_some())
+                    && file_type != "template"
+                    && file_type != "copy"
+                {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `attributes` or `acl` on a target that isn't a template or a copy",
+                    ));
+                }
+                let encrypted = encrypted.unwrap_or_default();
+                if encrypted && file_type != "template" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `encrypted` on a target that isn't a template",
+                    ));
+                }
+                if encrypted && (append.is_some() || prepend.is_some() || template_engine.is_some())
+                {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `append`, `prepend` or `template_engine` on an encrypted file",
+                    ));
+                }
+                // Decrypted files are only readable by their owner unless they say otherwise
+                let mode = mode.or(Some(0o600).filter(|_| encrypted));
+                if literal_braces.is_some() && file_type != "symbolic" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `literal_braces` on a target that isn't symbolic",
+                    ));
+                }
+                if flags.is_some() && file_type != "symbolic" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `flags` on a target that isn't symbolic",
+                    ));
+                }
+                // Junctions have no permissions, and a hardlink's are the source's
+                if (mode.is_some() || flags.is_some())
+                    && file_type == "symbolic"
+                    && (link != LinkKind::Symlink || recursive)
+                {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `mode` or `flags` on a symbolic target that isn't a single symlink",
+                    ));
+                }
+                if validate.is_some() && file_type != "template" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `validate` on a target that isn't a template",
+                    ));
+                }
+                if template_engine.is_some() && file_type != "template" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `template_engine` on a target that isn't a template",
+                    ));
+                }
+                if (order.is_some() || template.is_some()) && file_type != "fragment" {
+                    return Err(serde::de::Error::custom(
+                        "invalid use of `order` or `template` on a target that isn't a fragment",
+                    ));
+                }
                 let ans = match file_type {
                     "symbolic" => {
-                        if append.is_some() || prepend.is_some() {
+                        if append.is_some()
+                            || prepend.is_some()
+                            || equivalence.is_some()
+                            || diff.is_some()
+                        {
                             return Err(serde::de::Error::custom(
-                                "invalid use of `append` or `prepend` on a symbolic target",
+                                "invalid use of `append`, `prepend`, `equivalence` or `diff` on a symbolic target",
                             ));
                         }
-                        FileTarget::Symbolic(SymbolicTarget { target, owner })
+                        FileTarget::Symbolic(SymbolicTarget {
+                            target,
+                            owner,
+                            group,
+                            volatile: volatile.unwrap_or_default(),
+                            critical: critical.unwrap_or_default(),
+                            allow_overwrite: allow_overwrite.unwrap_or_default(),
+                            elevate: elevate.unwrap_or_default(),
+                            reload,
+                            service_unit: service_unit.unwrap_or_default(),
+                            on_deploy,
+                            on_undeploy,
+                            directory_owner,
+                            directory_mode,
+                            after: after.unwrap_or_default(),
+                            recursive,
+                            link,
+                            literal_braces: literal_braces.unwrap_or_default(),
+                            mode,
+                            flags: flags.unwrap_or_default(),
+                            verify,
+                        })
                     }
                     "template" => FileTarget::ComplexTemplate(TemplateTarget {
                         target,
                         owner,
+                        group,
+                        mode,
+                        attributes: attributes.unwrap_or_default(),
+                        acl: acl.unwrap_or_default(),
                         append,
                         prepend,
+                        volatile: volatile.unwrap_or_default(),
+                        write_once: write_once.unwrap_or_default(),
+                        critical: critical.unwrap_or_default(),
+                        allow_overwrite: allow_overwrite.unwrap_or_default(),
+                        elevate: elevate.unwrap_or_default(),
+                        reload,
+                        service_unit: service_unit.unwrap_or_default(),
+                        on_deploy,
+                        on_undeploy,
+                        equivalence: equivalence.unwrap_or_default(),
+                        diff: diff.unwrap_or_default(),
+                        directory_owner,
+                        directory_mode,
+                        after: after.unwrap_or_default(),
+                        recursive,
+                        engine: template_engine,
+                        render_cache: render_cache.map(|directory| expand_target(&directory)),
+                        validate,
+                        encrypted,
+                        verify,
                     }),
+                    "copy" => {
+                        if append.is_some()
+                            || prepend.is_some()
+                            || equivalence.is_some()
+                            || diff.is_some()
+                        {
+                            return Err(serde::de::Error::custom(
+                                "invalid use of `append`, `prepend`, `equivalence` or `diff` on a copy target",
+                            ));
+                        }
+                        FileTarget::Copy(CopyTarget {
+                            target,
+                            owner,
+                            group,
+                            mode,
+                            attributes: attributes.unwrap_or_default(),
+                            acl: acl.unwrap_or_default(),
+                            volatile: volatile.unwrap_or_default(),
+                            write_once: write_once.unwrap_or_default(),
+                            critical: critical.unwrap_or_default(),
+                            allow_overwrite: allow_overwrite.unwrap_or_default(),
+                            elevate: elevate.unwrap_or_default(),
+                            reload,
+                            service_unit: service_unit.unwrap_or_default(),
+                            on_deploy,
+                            on_undeploy,
+                            directory_owner,
+                            directory_mode,
+                            after: after.unwrap_or_default(),
+                            recursive,
+                            verify,
+                        })
+                    }
+                    "fragment" => {
+                        if append.is_some()
+                            || prepend.is_some()
+                            || owner.is_some()
+                            || group.is_some()
+                            || volatile.is_some()
+                            || critical.is_some()
+                            || allow_overwrite.is_some()
+                            || elevate.is_some()
+                            || reload.is_some()
+                            || service_unit.is_some()
+                            || on_deploy.is_some()
+                            || on_undeploy.is_some()
+                            || equivalence.is_some()
+                            || diff.is_some()
+                            || directory_owner.is_some()
+                            || directory_mode.is_some()
+                            || mode.is_some()
+                            || after.is_some()
+                            || verify.is_some()
+                        {
+                            return Err(serde::de::Error::custom(
+                                "fragment targets only support `target`, `order` and `template`",
+                            ));
+                        }
+                        FileTarget::Fragment(FragmentTarget {
+                            target,
+                            order: order.unwrap_or_default(),
+                            template: template.unwrap_or(true),
+                        })
+                    }
                     other_type => {
                         return Err(serde::de::Error::invalid_value(
                             serde::de::Unexpected::Str(other_type),
-                            &"`symbolic` or `template`",
+                            &"`symbolic`, `template`, `copy` or `fragment`",
                         ))
                     }
                 };
@@ -449,26 +2853,39 @@ impl<'de> serde::Deserialize<'de> for FileTarget {
     }
 }
 
+impl AutoEnable {
+    fn is_satisfied(&self) -> Result<bool> {
+        filesystem::is_executable(&self.command).context("find executable")
+    }
+}
+
 impl FileTarget {
-    fn map<F: FnOnce(PathBuf) -> PathBuf>(self, func: F) -> Self {
+    pub fn path(&self) -> &Path {
         match self {
-            FileTarget::Automatic(path) => FileTarget::Automatic(func(path)),
-            FileTarget::Symbolic(mut s) => {
-                s.target = func(s.target);
-                FileTarget::Symbolic(s)
-            }
-            FileTarget::ComplexTemplate(mut t) => {
-                t.target = func(t.target);
-                FileTarget::ComplexTemplate(t)
-            }
+            FileTarget::Automatic(path) => path,
+            FileTarget::Symbolic(SymbolicTarget { target, .. }) => target,
+            FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => target,
+            FileTarget::Copy(CopyTarget { target, .. }) => target,
+            FileTarget::Fragment(FragmentTarget { target, .. }) => target,
         }
     }
 
-    pub fn path(&self) -> &Path {
+    fn path_mut(&mut self) -> &mut PathBuf {
         match self {
-            FileTarget::Automatic(path) => &path,
-            FileTarget::Symbolic(SymbolicTarget { target, .. }) => &target,
-            FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => &target,
+            FileTarget::Automatic(path) => path,
+            FileTarget::Symbolic(SymbolicTarget { target, .. }) => target,
+            FileTarget::ComplexTemplate(TemplateTarget { target, .. }) => target,
+            FileTarget::Copy(CopyTarget { target, .. }) => target,
+            FileTarget::Fragment(FragmentTarget { target, .. }) => target,
+        }
+    }
+
+    pub fn is_recursive(&self) -> bool {
+        match self {
+            FileTarget::Symbolic(SymbolicTarget { recursive, .. })
+            | FileTarget::ComplexTemplate(TemplateTarget { recursive, .. })
+            | FileTarget::Copy(CopyTarget { recursive, .. }) => *recursive,
+            FileTarget::Automatic(_) | FileTarget::Fragment(_) => false,
         }
     }
 
@@ -477,10 +2894,19 @@ impl FileTarget {
             FileTarget::Automatic(_) => false,
             FileTarget::Symbolic(SymbolicTarget { owner, .. }) => owner.is_some(),
             FileTarget::ComplexTemplate(TemplateTarget { owner, .. }) => owner.is_some(),
+            FileTarget::Copy(CopyTarget { owner, .. }) => owner.is_some(),
+            FileTarget::Fragment(_) => false,
         }
     }
 }
 
+/// Parses permissions written as an octal string, like "644"
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+}
+
 impl<T: Into<PathBuf>> From<T> for FileTarget {
     fn from(input: T) -> Self {
         FileTarget::Automatic(input.into())
@@ -492,6 +2918,24 @@ impl<T: Into<PathBuf>> From<T> for SymbolicTarget {
         SymbolicTarget {
             target: input.into(),
             owner: None,
+            group: None,
+            volatile: false,
+            critical: false,
+            allow_overwrite: false,
+            elevate: false,
+            reload: None,
+            service_unit: false,
+            on_deploy: None,
+            on_undeploy: None,
+            directory_owner: None,
+            directory_mode: None,
+            after: Vec::new(),
+            recursive: false,
+            link: Default::default(),
+            literal_braces: false,
+            mode: None,
+            flags: Vec::new(),
+            verify: None,
         }
     }
 }
@@ -501,24 +2945,408 @@ impl<T: Into<PathBuf>> From<T> for TemplateTarget {
         TemplateTarget {
             target: input.into(),
             owner: None,
+            group: None,
+            mode: None,
+            attributes: Vec::new(),
+            acl: Vec::new(),
             append: None,
             prepend: None,
+            volatile: false,
+            write_once: false,
+            critical: false,
+            allow_overwrite: false,
+            elevate: false,
+            reload: None,
+            service_unit: false,
+            on_deploy: None,
+            on_undeploy: None,
+            equivalence: Default::default(),
+            diff: Default::default(),
+            directory_owner: None,
+            directory_mode: None,
+            after: Vec::new(),
+            recursive: false,
+            engine: None,
+            render_cache: None,
+            validate: None,
+            encrypted: false,
+            verify: None,
+        }
+    }
+}
+
+/// Expands a leading `~` to the home directory, like a shell does: only when it's followed by
+/// a separator or nothing, so `~name` stays as it is. On Windows, `%APPDATA%` and the other
+/// environment variables between `%` are expanded too. Nothing else in a target is expanded,
+/// `{{` included. Targets marked `literal_target = true` aren't expanded at all.
+fn expand_target(path: &Path) -> PathBuf {
+    let path = filesystem::expand_tilde(&path.to_string_lossy());
+    if cfg!(windows) {
+        expand_percent_variables(&path, |name| std::env::var(name).ok()).into()
+    } else {
+        path.into()
+    }
+}
+
+/// Expands Windows' `%APPDATA%`-style variables that are set, leaving the others as they are
+fn expand_percent_variables(path: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('%') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('%')
+            .filter(|&end| end > 0)
+            .and_then(|end| Some((lookup(&after[..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                expanded.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                expanded.push('%');
+                rest = after;
+            }
         }
     }
+    expanded.push_str(rest);
+    expanded
 }
 
+/// Expands the files whose source is a directory into the files inside it, which are deployed
+/// next to whatever else is in the target directory. Files that are declared on their own
+/// take precedence over the files of a directory with the same target.
 fn expand_directories(files: Files) -> Result<Files> {
-    let expanded = files
+    let ignores = load_ignore_file(Path::new(IGNORE_FILE))
+        .context("load root ignore file")?
         .into_iter()
-        .map(|(from, to)| expand_directory(&from, to).context(format!("expand file {:?}", from)))
-        .collect::<Result<Vec<Files>>>()?;
-    Ok(expanded.into_iter().flatten().collect::<Files>())
+        .collect::<Vec<_>>();
+    let (directories, mut expanded): (Files, Files) =
+        files.into_iter().partition(|(source, _)| source.is_dir());
+    let mut declared = expanded
+        .iter()
+        .filter(|(_, target)| !matches!(target, FileTarget::Fragment(_)))
+        .map(|(source, target)| (target.path().to_path_buf(), (source.clone(), None)))
+        .collect::<BTreeMap<_, (PathBuf, Option<PathBuf>)>>();
+    for (directory, target) in directories {
+        let files = expand_directory(&directory, target, &ignores)
+            .context(format!("expand file {:?}", directory))?;
+        for (source, target) in files {
+            match declared.get(target.path()) {
+                Some((other, None)) => {
+                    info!(
+                        "Skipping {:?}: {:?} is deployed to {:?} instead",
+                        source,
+                        other,
+                        target.path()
+                    );
+                    continue;
+                }
+                Some((other, Some(other_directory))) => bail!(
+                    "{:?} of directory {:?} and {:?} of directory {:?} are both deployed to {:?}",
+                    source,
+                    directory,
+                    other,
+                    other_directory,
+                    target.path()
+                ),
+                None => {}
+            }
+            declared.insert(
+                target.path().to_path_buf(),
+                (source.clone(), Some(directory.clone())),
+            );
+            expanded.insert(source, target);
+        }
+    }
+    Ok(expanded)
+}
+
+/// Expands the files whose source is a glob, like `"nvim/**/*"`, into every file that matches
+/// it. Each file goes into the target directory at its path relative to the part of the glob
+/// before the first wildcard, except that fragments all go into the target itself. Files that
+/// are declared without a glob take precedence over the ones a glob expands to.
+/// Levels of the enabled packages and every package they depend on, directly or not: 0 for a
+/// package without dependencies, one more than its deepest dependency otherwise
+fn resolve_dependencies(
+    packages: &BTreeMap<String, Package>,
+    enabled: &[String],
+) -> Result<BTreeMap<String, usize>> {
+    fn visit(
+        name: &str,
+        packages: &BTreeMap<String, Package>,
+        path: &mut Vec<String>,
+        levels: &mut BTreeMap<String, usize>,
+    ) -> Result<usize> {
+        if let Some(level) = levels.get(name) {
+            return Ok(*level);
+        }
+        if let Some(start) = path.iter().position(|p| p == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name.to_string());
+            bail!(
+                "circular dependency between packages: {}",
+                cycle.join(" -> ")
+            );
+        }
+        // Enabled packages that global.toml doesn't define have no dependencies
+        let depends = packages
+            .get(name)
+            .map(|package| package.depends.as_slice())
+            .unwrap_or_default();
+        path.push(name.to_string());
+        let mut level = 0;
+        for dependency in depends {
+            if !packages.contains_key(dependency) {
+                bail!(
+                    "package {:?} depends on {:?}, which doesn't exist",
+                    name,
+                    dependency
+                );
+            }
+            level = level.max(visit(dependency, packages, path, levels)? + 1);
+        }
+        path.pop();
+        levels.insert(name.to_string(), level);
+        Ok(level)
+    }
+
+    let mut levels = BTreeMap::new();
+    for name in enabled {
+        visit(name, packages, &mut Vec::new(), &mut levels)?;
+    }
+    Ok(levels)
+}
+
+fn expand_globs(files: Files, ignore: &[String]) -> Result<Files> {
+    let (globs, mut files): (Files, Files) = files
+        .into_iter()
+        .partition(|(source, _)| is_glob(&source.to_string_lossy()));
+    if globs.is_empty() {
+        return Ok(files);
+    }
+
+    let mut ignores = GlobSetBuilder::new();
+    for pattern in ignore {
+        ignores.add(glob(pattern).with_context(|| format!("parse ignore pattern {:?}", pattern))?);
+    }
+    let ignores = ignores.build().context("build ignore patterns")?;
+    let ignore_files = load_ignore_file(Path::new(IGNORE_FILE))
+        .context("load root ignore file")?
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    for (pattern, target) in globs {
+        let matcher = glob(&pattern.to_string_lossy())
+            .with_context(|| format!("parse glob {:?}", pattern))?
+            .compile_matcher();
+        let base = glob_base(&pattern);
+        let mut matched = Vec::new();
+        walk_glob_base(&base, &base, &ignores, &ignore_files, &mut |source| {
+            if matcher.is_match(source) {
+                matched.push(source.to_path_buf());
+            }
+        })
+        .with_context(|| format!("expand glob {:?}", pattern))?;
+        if matched.is_empty() {
+            warnings::warn(
+                "unmatched-glob",
+                pattern.display(),
+                format!("Glob {:?} doesn't match any file", pattern),
+            );
+        }
+
+        for source in matched {
+            let mut file_target = target.clone();
+            if !matches!(file_target, FileTarget::Fragment(_)) {
+                let relative = source.strip_prefix(&base).unwrap_or(&source);
+                let path = file_target.path_mut();
+                *path = path.join(relative);
+            }
+            files.entry(source).or_insert(file_target);
+        }
+    }
+    Ok(files)
+}
+
+/// Files of the repository that `pattern` matches, in the order of their paths, leaving out
+/// the ones that ignore files exclude. A pattern without glob characters is a directory, whose
+/// files are listed without descending into its subdirectories.
+pub fn glob_files(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = if is_glob(pattern) {
+        PathBuf::from(pattern)
+    } else {
+        Path::new(pattern).join("*")
+    };
+    let matcher = glob(&pattern.to_string_lossy())
+        .with_context(|| format!("parse glob {:?}", pattern))?
+        .compile_matcher();
+    let base = glob_base(&pattern);
+    let ignore_files = load_ignore_file(Path::new(IGNORE_FILE))
+        .context("load root ignore file")?
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut matched = Vec::new();
+    walk_glob_base(
+        &base,
+        &base,
+        &GlobSet::empty(),
+        &ignore_files,
+        &mut |source| {
+            if matcher.is_match(source) {
+                matched.push(source.to_path_buf());
+            }
+        },
+    )
+    .with_context(|| format!("expand glob {:?}", pattern))?;
+    Ok(matched)
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '[', '{'])
+}
+
+/// The leading components of `pattern` that don't have glob characters
+pub fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// Whether the key `source` of a `files` table deploys the file at `path`: it's the file, a
+/// directory that contains it, or a glob that matches it
+pub fn source_covers(source: &str, path: &Path) -> bool {
+    if is_glob(source) {
+        glob(source).is_ok_and(|glob| glob.compile_matcher().is_match(path))
+    } else {
+        path.starts_with(source)
+    }
+}
+
+/// `*` stops at slashes and `**` crosses them, like in a gitignore
+fn glob(pattern: &str) -> Result<Glob> {
+    Ok(GlobBuilder::new(pattern).literal_separator(true).build()?)
+}
+
+/// Calls `found` with every file below `directory`, leaving out the paths that `ignores` match
+/// by name or by their path relative to `base`, and the ones that ignore files exclude
+fn walk_glob_base(
+    base: &Path,
+    directory: &Path,
+    ignores: &GlobSet,
+    ignore_files: &[Gitignore],
+    found: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    let read_directory = if directory.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        directory
+    };
+    let mut children = fs::read_dir(read_directory)
+        .with_context(|| format!("read contents of directory {:?}", read_directory))?
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("read contents of directory {:?}", read_directory))?;
+    children.sort_by_key(|child| child.file_name());
+    let mut ignore_files = ignore_files.to_vec();
+    ignore_files.extend(
+        load_ignore_file(&directory.join(IGNORE_FILE)).context("load directory's ignore file")?,
+    );
+
+    for child in children {
+        let name = child.file_name();
+        let path = directory.join(&name);
+        let relative = path.strip_prefix(base).unwrap_or(&path);
+        let is_dir = child.file_type()?.is_dir();
+        if name == IGNORE_FILE || ignores.is_match(&name) || ignores.is_match(relative) {
+            trace!("Glob leaves out {:?}", path);
+            continue;
+        }
+        if let Some(pattern) = ignoring_pattern(&path, is_dir, &ignore_files) {
+            info!("Skipping {:?}: ignored by {}", path, pattern);
+            continue;
+        }
+        if is_dir {
+            walk_glob_base(base, &path, ignores, &ignore_files, found)?;
+        } else if fs::metadata(&path).map(|m| m.is_file()).unwrap_or(false) {
+            found(&path);
+        }
+    }
+    Ok(())
+}
+
+fn load_ignore_file(path: &Path) -> Result<Option<Gitignore>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let (ignore, error) = Gitignore::new(path);
+    if let Some(e) = error {
+        return Err(e).context(format!("parse ignore file {:?}", path));
+    }
+    Ok(Some(ignore))
+}
+
+/// Whether the ignore files of `repository` leave out `path`, which is relative to it, the
+/// way that the expansion of directories and globs does: the ignore files of the directories
+/// above it apply, and leaving out a directory leaves out everything inside of it
+pub fn is_ignored(repository: &Path, path: &Path) -> Result<bool> {
+    if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+        return Ok(false);
+    }
+    let mut ignore_files = load_ignore_file(&repository.join(IGNORE_FILE))?
+        .into_iter()
+        .collect::<Vec<_>>();
+    let components = path.components().collect::<Vec<_>>();
+    let mut current = repository.to_path_buf();
+    for (i, component) in components.iter().enumerate() {
+        current.push(component);
+        let is_dir = i + 1 < components.len() || current.is_dir();
+        if ignoring_pattern(&current, is_dir, &ignore_files).is_some() {
+            return Ok(true);
+        }
+        if is_dir {
+            ignore_files.extend(load_ignore_file(&current.join(IGNORE_FILE))?);
+        }
+    }
+    Ok(false)
+}
+
+/// The innermost ignore file that has an opinion about the path wins.
+/// Returns the pattern that ignores the path, and the file it comes from.
+fn ignoring_pattern(path: &Path, is_dir: bool, ignores: &[Gitignore]) -> Option<String> {
+    for ignore in ignores.iter().rev() {
+        match ignore.matched(path, is_dir) {
+            Match::Ignore(glob) => {
+                return Some(match glob.from() {
+                    Some(from) => format!("{:?} in {:?}", glob.original(), from),
+                    None => format!("{:?}", glob.original()),
+                })
+            }
+            Match::Whitelist(_) => return None,
+            Match::None => {}
+        }
+    }
+    None
+}
+
+/// Assigns every file that was expanded from a directory to that directory's package
+fn expand_file_packages(file_packages: FilePackages, expanded_files: &Files) -> FilePackages {
+    expanded_files
+        .keys()
+        .filter_map(|source| {
+            file_packages
+                .iter()
+                .find(|(original, _)| source.starts_with(original))
+                .map(|(_, package)| (source.clone(), package.clone()))
+        })
+        .collect()
 }
 
 /// If a file is given, it will return a map of one element
 /// Otherwise, returns recursively all the children and their targets
 ///  in relation to parent target
-fn expand_directory(source: &Path, target: FileTarget) -> Result<Files> {
+fn expand_directory(source: &Path, target: FileTarget, ignores: &[Gitignore]) -> Result<Files> {
     if fs::metadata(source)
         .context("read file's metadata")?
         .is_file()
@@ -527,20 +3355,528 @@ fn expand_directory(source: &Path, target: FileTarget) -> Result<Files> {
         map.insert(source.into(), target);
         Ok(map)
     } else {
-        let target = match target {
-            FileTarget::Automatic(target) => target,
-            _ => bail!("Complex file target not implemented for directories yet."),
-        };
+        if !matches!(target, FileTarget::Automatic(_)) && !target.is_recursive() {
+            bail!(
+                "the source is a directory, set `link_type = \"recursive\"` to deploy its files one by one with these settings"
+            );
+        }
+        let mut ignores = ignores.to_vec();
+        ignores.extend(
+            load_ignore_file(&source.join(IGNORE_FILE)).context("load directory's ignore file")?,
+        );
         let expanded = fs::read_dir(source)
             .context("read contents of directory")?
             .map(|child| -> Result<Files> {
-                let child = child?.file_name();
+                let child = child?;
+                let is_dir = child.file_type()?.is_dir();
+                let child = child.file_name();
                 let child_source = PathBuf::from(source).join(&child);
-                let child_target = target.clone().join(&child);
-                expand_directory(&child_source, child_target.into())
+                if child == IGNORE_FILE {
+                    return Ok(Files::new());
+                }
+                if let Some(pattern) = ignoring_pattern(&child_source, is_dir, &ignores) {
+                    info!("Skipping {:?}: ignored by {}", child_source, pattern);
+                    return Ok(Files::new());
+                }
+                let mut child_target = target.clone();
+                *child_target.path_mut() = target.path().join(&child);
+                expand_directory(&child_source, child_target, &ignores)
                     .context(format!("expand file {:?}", child_source))
             })
             .collect::<Result<Vec<Files>>>()?; // Use transposition of Iterator<Result<T,E>> -> Result<Sequence<T>, E>
         Ok(expanded.into_iter().flatten().collect())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = dir.path();
+        fs::write(repository.join(IGNORE_FILE), "*.swp\nbuild/\n").unwrap();
+        fs::create_dir_all(repository.join("nvim/build")).unwrap();
+        fs::write(
+            repository.join("nvim").join(IGNORE_FILE),
+            "*.log\n!keep.swp\n",
+        )
+        .unwrap();
+
+        let ignored = |path: &str| is_ignored(repository, Path::new(path)).unwrap();
+        assert!(ignored("init.swp"));
+        assert!(ignored("nvim/init.swp"));
+        assert!(!ignored("nvim/keep.swp"));
+        assert!(ignored("nvim/debug.log"));
+        assert!(!ignored("debug.log"));
+        assert!(ignored("nvim/build/output"));
+        assert!(!ignored("nvim/init.lua"));
+        assert!(!ignored("nvim/.dotterignore"));
+    }
+
+    #[test]
+    fn test_target_expansion() {
+        let home = filesystem::home_dir();
+        assert_eq!(expand_target(Path::new("~/.vimrc")), home.join(".vimrc"));
+        assert_eq!(expand_target(Path::new("~")), home);
+        assert_eq!(expand_target(Path::new("~user/x")), Path::new("~user/x"));
+        assert_eq!(
+            expand_target(Path::new("/srv/{{name}}/~/x")),
+            Path::new("/srv/{{name}}/~/x")
+        );
+
+        let parse = |target: &str| {
+            toml::from_str::<BTreeMap<String, FileTarget>>(&format!("f = {}", target))
+                .unwrap()
+                .remove("f")
+                .unwrap()
+        };
+        assert_eq!(
+            parse("{ type = \"symbolic\", target = \"~/x\", after = [\"~/y\"] }"),
+            FileTarget::Symbolic(SymbolicTarget {
+                target: home.join("x"),
+                owner: None,
+                group: None,
+                volatile: false,
+                critical: false,
+                allow_overwrite: false,
+                elevate: false,
+                reload: None,
+                service_unit: false,
+                on_deploy: None,
+                on_undeploy: None,
+                directory_owner: None,
+                directory_mode: None,
+                after: vec![home.join("y")],
+                recursive: false,
+                link: LinkKind::Symlink,
+                literal_braces: false,
+                mode: None,
+                flags: Vec::new(),
+                verify: None,
+            })
+        );
+        assert_eq!(
+            parse("{ type = \"template\", target = \"~/x\", literal_target = true }").path(),
+            Path::new("~/x")
+        );
+        assert!(
+            parse("{ type = \"copy\", target = \"~/x\", link_type = \"recursive\" }")
+                .is_recursive()
+        );
+        assert!(matches!(
+            parse("{ type = \"symbolic\", target = \"~/x\", link_type = \"hardlink\" }"),
+            FileTarget::Symbolic(SymbolicTarget {
+                link: LinkKind::Hardlink,
+                literal_braces: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("{ type = \"symbolic\", target = \"~/x\", mode = \"700\", flags = [\"uchg\"] }"),
+            FileTarget::Symbolic(SymbolicTarget { mode: Some(0o700), ref flags, .. }) if flags == &["uchg"]
+        ));
+        assert!(toml::from_str::<BTreeMap<String, FileTarget>>(
+            "f = { type = \"symbolic\", target = \"~/x\", link_type = \"hardlink\", mode = \"700\" }"
+        )
+        .is_err());
+        let diff = |target: &str| match parse(target) {
+            FileTarget::ComplexTemplate(template) => template.diff,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            diff("{ type = \"template\", target = \"~/x\", diff = false }"),
+            DiffSetting::Enabled(false)
+        );
+        let limited = diff("{ type = \"template\", target = \"~/x\", diff = 100 }");
+        assert!(limited.shows(100) && !limited.shows(101));
+        assert!(diff("{ type = \"template\", target = \"~/x\" }").shows(usize::MAX));
+        // Targets written as strings are expanded after directories are
+        assert_eq!(parse("\"~/x\"").path(), Path::new("~/x"));
+    }
+
+    #[test]
+    fn test_with_package_file() {
+        let target = toml::Value::String("~/.zshrc".into());
+        let global = "[zsh.files]\nzshrc = \"~/.zshrc2\"\n\n[zsh.variables]\n";
+        assert_eq!(
+            with_package_file(global, "zsh", "zsh/zshrc", &target),
+            "[zsh.files]\nzshrc = \"~/.zshrc2\"\n\"zsh/zshrc\" = \"~/.zshrc\"\n\n[zsh.variables]\n"
+        );
+        assert_eq!(
+            with_package_file("[vim.files]\n", "zsh", "zshrc", &target),
+            "[vim.files]\n\n[zsh.files]\nzshrc = \"~/.zshrc\"\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let variables = |s: &str| toml::from_str::<Variables>(s).unwrap();
+        let original = "[git]\naliases = [\"st\"]\n[git.user]\nname = \"a\"\nemail = \"b\"\n";
+        let new = "[git]\naliases = [\"co\"]\n[git.user]\nname = \"c\"\n";
+
+        let mut merged = variables(original);
+        recursive_extend_map(&mut merged, variables(new), &MergeStrategies::new(), "");
+        assert_eq!(
+            merged,
+            variables("[git]\naliases = [\"co\"]\n[git.user]\nname = \"c\"\nemail = \"b\"\n")
+        );
+
+        let strategies = vec![
+            ("git.aliases".to_string(), MergeStrategy::AppendList),
+            ("git.user".to_string(), MergeStrategy::Replace),
+        ]
+        .into_iter()
+        .collect();
+        let mut merged = variables(original);
+        recursive_extend_map(&mut merged, variables(new), &strategies, "");
+        assert_eq!(
+            merged,
+            variables("[git]\naliases = [\"st\", \"co\"]\n[git.user]\nname = \"c\"\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_variable_providers_see_enabled_packages() {
+        let global = "[shell]\ndepends = [\"base\"]\n[base]\n[games]\n";
+        let local = "packages = [\"shell\"]\n";
+        let provider = r#"read request; echo "{\"variables\": {\"request\": $request}}""#;
+        let config = merge_configuration_files(
+            toml::from_str(global).unwrap(),
+            toml::from_str(local).unwrap(),
+            Variables::new(),
+            Variables::new(),
+            Variables::new(),
+            &[provider.into()],
+            None,
+        )
+        .unwrap();
+        let packages = &config.variables["request"]["packages"];
+        assert_eq!(packages, &toml::Value::from(vec!["base", "shell"]));
+    }
+
+    #[test]
+    fn test_merge_packages_variables() {
+        let variables = |s: &str| toml::from_str::<Variables>(s).unwrap();
+        let merge = |global: &str, local: &str| {
+            merge_configuration_files(
+                toml::from_str(global).unwrap(),
+                toml::from_str(local).unwrap(),
+                Variables::new(),
+                Variables::new(),
+                Variables::new(),
+                &[],
+                None,
+            )
+            .map(|config| config.variables)
+        };
+        let global = "[go.variables]\npath_entries = [\"~/go/bin\"]\n[go.variables.env]\nGOPATH = \"~/go\"\n\
+                      [rust.variables]\npath_entries = [\"~/.cargo/bin\"]\n[rust.variables.env]\nCARGO_HOME = \"~/.cargo\"\n";
+        let local = "packages = [\"go\", \"rust\"]\n";
+        assert!(merge(global, local).is_err());
+
+        let strategies = "[merge]\npath_entries = \"append-list\"\nenv = \"deep-merge\"\n";
+        assert_eq!(
+            merge(&format!("{}{}", strategies, global), local).unwrap(),
+            variables("path_entries = [\"~/go/bin\", \"~/.cargo/bin\"]\n[env]\nGOPATH = \"~/go\"\nCARGO_HOME = \"~/.cargo\"\n")
+        );
+
+        // A package can declare the strategy, and local.toml still comes last
+        let global = global.replace(
+            "[rust.variables]",
+            "[rust.merge]\npath_entries = \"append-list\"\nenv = \"deep-merge\"\n[rust.variables]",
+        );
+        let local = format!("{}[variables]\npath_entries = [\"~/bin\"]\n", local);
+        assert_eq!(
+            merge(&global, &local).unwrap()["path_entries"],
+            variables("a = [\"~/go/bin\", \"~/.cargo/bin\", \"~/bin\"]")["a"]
+        );
+
+        let conflicting = format!("[go.merge]\npath_entries = \"replace\"\n{}", global);
+        assert!(merge(&conflicting, &local).is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let files = |s: &str| toml::from_str::<Files>(s).unwrap();
+        let mut layers = Layers::new(DuplicateTargets::LaterWins);
+        layers
+            .add(
+                files("zshrc = \"/h/.zshrc\"\nvimrc = \"/h/.vimrc\"\n"),
+                "package \"base\"",
+                Some("base"),
+            )
+            .unwrap();
+        layers
+            .add(
+                files("\"work/zshrc\" = \"/h/.zshrc\"\nvimrc = \"/h/.config/vimrc\"\n"),
+                "package \"work\"",
+                Some("work"),
+            )
+            .unwrap();
+        layers
+            .add(files("vimrc = \"/h/.vimrc\"\n"), "local.toml", None)
+            .unwrap();
+        assert_eq!(
+            layers.files.keys().collect::<Vec<_>>(),
+            [Path::new("vimrc"), Path::new("work/zshrc")]
+        );
+        assert_eq!(
+            layers.files[Path::new("vimrc")].path(),
+            Path::new("/h/.vimrc")
+        );
+        assert_eq!(layers.file_packages[Path::new("vimrc")], "work");
+
+        let mut layers = Layers::new(DuplicateTargets::Error);
+        layers
+            .add(
+                files("zshrc = \"/h/.zshrc\"\n"),
+                "package \"base\"",
+                Some("base"),
+            )
+            .unwrap();
+        assert!(layers
+            .add(
+                files("\"work/zshrc\" = \"/h/.zshrc\"\n"),
+                "package \"work\"",
+                Some("work")
+            )
+            .is_err());
+        assert!(layers
+            .add(
+                files("zshrc = \"/h/.zshrc2\"\n"),
+                "package \"work\"",
+                Some("work")
+            )
+            .is_err());
+        layers
+            .add(files("zshrc = \"/h/.zshrc2\"\n"), "local.toml", None)
+            .unwrap();
+        layers
+            .add(
+                files("a = { target = \"/h/.bashrc\", type = \"fragment\" }\nb = { target = \"/h/.bashrc\", type = \"fragment\" }\n"),
+                "local.toml",
+                None,
+            )
+            .unwrap();
+        assert_eq!(layers.files.len(), 3);
+    }
+
+    #[test]
+    fn test_override_private_variables() {
+        let variables = |s: &str| toml::from_str::<Variables>(s).unwrap();
+        let mut private_variables = BTreeMap::new();
+        private_variables.insert(
+            "zsh".to_string(),
+            variables("theme = \"dark\"\nprompt = \"%\"\n"),
+        );
+        private_variables.insert("vim".to_string(), variables("theme = \"light\"\n"));
+
+        override_private_variables(
+            &mut private_variables,
+            &variables("prompt = \"$\"\nemail = \"a@b\"\n"),
+            &MergeStrategies::new(),
+        );
+        assert_eq!(
+            private_variables["zsh"],
+            variables("theme = \"dark\"\nprompt = \"$\"\n")
+        );
+        assert_eq!(private_variables["vim"], variables("theme = \"light\"\n"));
+    }
+
+    #[test]
+    fn test_match_case() {
+        let mut files = Files::new();
+        files.insert(
+            "Vimrc".into(),
+            FileTarget::Automatic("/home/me/.Vimrc".into()),
+        );
+        let mut cache = Cache::default();
+        cache
+            .symlinks
+            .insert("vimrc".into(), "/home/me/.vimrc".into());
+        cache.template_dependencies.insert(
+            "vimrc".into(),
+            TemplateDependencies {
+                variables: BTreeSet::new(),
+                partials: BTreeSet::new(),
+                hash: None,
+                helpers: BTreeMap::new(),
+            },
+        );
+        cache.match_case(&files);
+
+        // Where case matters, renaming the file deletes the old one and creates the new one
+        let insensitive = filesystem::paths_equal(Path::new("Vimrc"), Path::new("vimrc"));
+        let (source, target) = if insensitive {
+            ("Vimrc", "/home/me/.Vimrc")
+        } else {
+            ("vimrc", "/home/me/.vimrc")
+        };
+        assert_eq!(
+            cache.symlinks.into_iter().collect::<Vec<_>>(),
+            [(PathBuf::from(source), PathBuf::from(target))]
+        );
+        assert!(cache.template_dependencies.contains_key(Path::new(source)));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path(Path::new("/home/me/../other/./.bashrc")).unwrap(),
+            PathBuf::from("/home/other/.bashrc")
+        );
+        assert_eq!(
+            normalize_path(Path::new("/../etc")).unwrap(),
+            PathBuf::from("/etc")
+        );
+        assert!(normalize_path(Path::new("relative")).unwrap().is_absolute());
+    }
+
+    #[test]
+    fn test_expand_percent_variables() {
+        let lookup = |name: &str| match name {
+            "APPDATA" => Some(r"C:\Users\me\AppData\Roaming".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_percent_variables(r"%APPDATA%\Code\User", lookup),
+            r"C:\Users\me\AppData\Roaming\Code\User"
+        );
+        assert_eq!(
+            expand_percent_variables("%UNSET%/100%/%%", lookup),
+            "%UNSET%/100%/%%"
+        );
+        assert_eq!(
+            expand_percent_variables("%UNSET%APPDATA%", lookup),
+            r"%UNSETC:\Users\me\AppData\Roaming"
+        );
+    }
+
+    #[test]
+    fn test_resolve_dependencies() {
+        let packages = |toml: &str| toml::from_str::<BTreeMap<String, Package>>(toml).unwrap();
+        let diamond = packages(
+            r#"
+            [base]
+            [shell]
+            depends = ["base"]
+            [editor]
+            depends = ["base"]
+            [work]
+            depends = ["shell", "editor"]
+            [games]
+            "#,
+        );
+        let levels = resolve_dependencies(&diamond, &["work".into()]).unwrap();
+        assert_eq!(
+            levels.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("base".into(), 0),
+                ("editor".into(), 1),
+                ("shell".into(), 1),
+                ("work".into(), 2)
+            ]
+        );
+
+        let cycle = packages(
+            r#"
+            [a]
+            depends = ["b"]
+            [b]
+            depends = ["a"]
+            "#,
+        );
+        let error = resolve_dependencies(&cycle, &["a".into()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "circular dependency between packages: a -> b -> a"
+        );
+    }
+
+    /// The fields that serde expects of `T`, from its error about an unknown field
+    fn expected_fields<T: serde::de::DeserializeOwned>(wrap: &str) -> BTreeSet<String> {
+        let error = match toml::from_str::<T>(&format!("{}not_a_field = 0", wrap)) {
+            Ok(_) => panic!("an unknown field was accepted"),
+            Err(e) => e.to_string(),
+        };
+        let expected = error.split(", expected ").nth(1).unwrap();
+        let expected = expected.split(" for key").next().unwrap();
+        expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .map(String::from)
+            .collect()
+    }
+
+    fn schema_properties(schema: &serde_json::Value) -> BTreeSet<String> {
+        schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_schema_fields() {
+        use args::SchemaFile;
+        use schema::schema;
+
+        let global = schema(SchemaFile::Global);
+        let local = schema(SchemaFile::Local);
+
+        // Every other table of global.toml is a package, so its fields are the keys that it
+        // serializes
+        let config: GlobalConfig = toml::from_str("").unwrap();
+        let fields = serde_json::to_value(config).unwrap();
+        let fields = fields.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(schema_properties(&global), fields);
+
+        assert_eq!(
+            schema_properties(&local),
+            expected_fields::<LocalConfig>("")
+        );
+        let package = &global["additionalProperties"];
+        assert_eq!(schema_properties(package), expected_fields::<Package>(""));
+        let file_target = &local["properties"]["files"]["additionalProperties"]["anyOf"][1];
+        assert_eq!(
+            schema_properties(file_target),
+            expected_fields::<BTreeMap<String, FileTarget>>("[file]\n")
+        );
+        assert_eq!(
+            schema_properties(&package["properties"]["auto_enable"]),
+            expected_fields::<AutoEnable>("")
+        );
+        assert_eq!(
+            schema_properties(&package["properties"]["when"]["items"]),
+            expected_fields::<ConditionalVariables>("")
+        );
+        // The package of a special file is only recorded in the cache
+        let mut special_fields = expected_fields::<SpecialFile>("");
+        special_fields.remove("package");
+        assert_eq!(
+            schema_properties(&local["properties"]["special"]["additionalProperties"]),
+            special_fields
+        );
+        assert_eq!(
+            schema_properties(&local["properties"]["secrets"]),
+            expected_fields::<SecretsConfig>("")
+        );
+        assert_eq!(
+            schema_properties(&global["properties"]["scrub"]["items"]),
+            expected_fields::<ScrubFilter>("")
+        );
+        assert_eq!(
+            schema_properties(&global["properties"]["profiles"]["additionalProperties"]),
+            expected_fields::<Profile>("")
+        );
+        assert_eq!(
+            schema_properties(&file_target["properties"]["equivalence"]),
+            expected_fields::<Equivalence>("")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! What to do with the target of a new file when something that dotter didn't create is already
+//! there: skip it with an error like before, overwrite it, keep it, adopt it into the
+//! repository, or ask for every conflict.
+
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use args::ConflictPolicy;
+use difference;
+use render;
+
+/// A target that is in the way of a new file
+pub struct Conflict {
+    /// The file, as it's printed in the log
+    pub description: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// What would be deployed in place of the target, or `None` if it isn't text
+    pub deployed: Option<String>,
+    /// Whether the target can be adopted. The target of a template is its render, so copying
+    /// it over the source would lose the template.
+    pub adoptable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Leave the target, and fail like without --force
+    Skip,
+    Overwrite,
+    /// Leave the target, without failing
+    Keep,
+    /// Replace the source with the target's contents, then deploy it
+    Adopt,
+}
+
+pub struct Resolver {
+    policy: ConflictPolicy,
+    diff_context_lines: usize,
+    /// Whether "keep all" was answered, for the conflicts that come after
+    keep_rest: bool,
+}
+
+impl Resolver {
+    pub fn new(policy: ConflictPolicy, diff_context_lines: usize) -> Resolver {
+        Resolver {
+            policy,
+            diff_context_lines,
+            keep_rest: false,
+        }
+    }
+
+    /// Conflicts only need to be looked for if something other than skipping them could happen
+    pub fn skips_all(&self) -> bool {
+        self.policy == ConflictPolicy::Skip
+    }
+
+    pub fn resolve(&mut self, conflict: &Conflict) -> Result<Resolution> {
+        Ok(match self.policy {
+            ConflictPolicy::Skip => Resolution::Skip,
+            ConflictPolicy::Overwrite => Resolution::Overwrite,
+            ConflictPolicy::Keep => Resolution::Keep,
+            ConflictPolicy::Adopt if conflict.adoptable => Resolution::Adopt,
+            ConflictPolicy::Adopt => {
+                warn!(
+                    "Not adopting {:?}, {} is a template and would lose its template code",
+                    conflict.target, conflict.description
+                );
+                Resolution::Skip
+            }
+            ConflictPolicy::Ask if self.keep_rest => Resolution::Keep,
+            ConflictPolicy::Ask => loop {
+                eprintln!(
+                    "{} is in the way of {}, and wasn't created by dotter.\n[o]verwrite, [k]eep, show [d]iff, {}or keep a[l]l the others too?",
+                    conflict.target.display(),
+                    conflict.description,
+                    if conflict.adoptable {
+                        "[a]dopt into the repository, "
+                    } else {
+                        ""
+                    }
+                );
+                let mut answer = String::new();
+                if io::stdin()
+                    .read_line(&mut answer)
+                    .context("read answer from stdin")?
+                    == 0
+                {
+                    // Nobody is there to answer
+                    break Resolution::Skip;
+                }
+                match answer.trim().to_lowercase().as_str() {
+                    "o" | "overwrite" => break Resolution::Overwrite,
+                    "k" | "keep" => break Resolution::Keep,
+                    "a" | "adopt" if conflict.adoptable => break Resolution::Adopt,
+                    "l" | "all" => {
+                        self.keep_rest = true;
+                        break Resolution::Keep;
+                    }
+                    "d" | "diff" => self.show_diff(conflict),
+                    _ => {}
+                }
+            },
+        })
+    }
+
+    fn show_diff(&self, conflict: &Conflict) {
+        let current = fs::read_to_string(&conflict.target);
+        match (current, &conflict.deployed) {
+            (Ok(current), Some(deployed)) => {
+                let diff = difference::diff_contents(&current, deployed);
+                if difference::diff_nonempty(&diff) {
+                    difference::print_diff(diff, self.diff_context_lines);
+                } else {
+                    eprintln!("The contents are the same");
+                }
+            }
+            _ => eprintln!("The target or the file to deploy isn't text, so there's no diff"),
+        }
+    }
+}
+
+/// Replaces the source with the contents of the target, so that deploying the source leaves the
+/// target's contents where they are
+pub fn adopt(act: bool, source: &Path, target: &Path) -> Result<()> {
+    info!("Adopting {:?} into {:?}", target, source);
+    if act {
+        fs::copy(target, source).context("copy target over source")?;
+        render::forget_prerendered(source);
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+
+use std::path::Path;
+
+use args::{Options, OutputFormat};
+use config::{Configuration, Variables};
+use deploy;
+use handlebars_helpers;
+
+pub fn print_context(
+    opt: &Options,
+    package: Option<&str>,
+    file: Option<&Path>,
+    format: OutputFormat,
+) -> Result<()> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+
+    if let Some(package) = package {
+        if !config.packages.iter().any(|p| p == package) {
+            bail!("package {:?} is not enabled", package);
+        }
+    }
+    if let Some(file) = file {
+        if !config.files.contains_key(file) {
+            bail!("file {:?} is not in the configuration", file);
+        }
+        if let (Some(package), Some(file_package)) = (package, config.file_packages.get(file)) {
+            if package != file_package {
+                bail!(
+                    "file {:?} belongs to package {:?}, not {:?}",
+                    file,
+                    file_package,
+                    package
+                );
+            }
+        }
+    }
+
+    let mut variables = template_context(&config);
+    // The templates of a package also see its private variables
+    let package = file
+        .and_then(|file| config.file_packages.get(file))
+        .map(String::as_str)
+        .or(package);
+    if let Some(private) = package.and_then(|package| config.private_variables.get(package)) {
+        variables.extend(private.clone());
+    }
+    let output = match format {
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&variables).context("serialize context into json")?
+        }
+        OutputFormat::Toml => toml::to_string(&toml::Value::Table(variables))
+            .context("serialize context into toml")?,
+    };
+    println!("{}", output);
+
+    Ok(())
+}
+
+/// The variables every template is rendered with
+pub fn template_context(config: &Configuration) -> Variables {
+    let mut variables = config.variables.clone();
+    handlebars_helpers::add_dotter_variable(&mut variables, &config.files, &config.packages);
+    variables
+}
@@ -0,0 +1,680 @@
+use anyhow::{Context, Result};
+
+use handlebars::template::{DecoratorTemplate, Parameter, TemplateElement};
+use handlebars::{Handlebars, Template};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+use config::{TemplateDependencies, TemplateEngine, Variables};
+use file_state::TemplateDescription;
+use handlebars_helpers;
+use render::{self, RenderLimits};
+
+/// Helpers whose output depends only on their parameters (and the machine, which has its own cache).
+/// A template that uses any other helper, except the ones of global.toml (or a partial that
+/// isn't a file of the partials directory) is re-rendered on every deploy. The ones that dotter
+/// implements itself have the version of their output, see `builtin_version`.
+const PURE_HELPERS: &[(&str, Option<u32>)] = &[
+    ("if", None),
+    ("unless", None),
+    ("each", None),
+    ("with", None),
+    ("lookup", None),
+    ("raw", None),
+    ("log", None),
+    ("eq", None),
+    ("ne", None),
+    ("gt", None),
+    ("gte", None),
+    ("lt", None),
+    ("lte", None),
+    ("and", None),
+    ("or", None),
+    ("not", None),
+    ("len", None),
+    ("math", Some(1)),
+    ("stable_random", Some(1)),
+    ("dirname", Some(1)),
+    ("basename", Some(1)),
+    ("join_path", Some(1)),
+    ("relative_to", Some(1)),
+    ("shell_quote", Some(1)),
+    ("json_escape", Some(1)),
+    ("toml_escape", Some(1)),
+    ("xml_escape", Some(1)),
+    ("regex_escape", Some(1)),
+];
+
+/// Helpers that dotter implements itself whose output also depends on files, commands, the
+/// time or the machine, with the version of their output
+const IMPURE_HELPERS: &[(&str, u32)] = &[
+    ("include_template", 1),
+    ("render", 1),
+    ("is_executable", 1),
+    ("command_success", 1),
+    ("command_output", 1),
+    ("content_hash", 1),
+    ("git_commit", 1),
+    ("git_branch", 1),
+    ("git_dirty", 1),
+    ("hardware", 1),
+    ("canonicalize", 1),
+    ("current", 1),
+    ("dir_files", 1),
+    ("locale", 1),
+    ("lang", 1),
+    ("keyboard_layout", 1),
+    ("now", 1),
+    ("timestamp", 1),
+    ("date_passed", 1),
+    ("fetch_url", 1),
+    ("secret", 1),
+];
+
+/// Facts of the `dotter` variable, with the version of their values
+const FACTS: &[(&str, u32)] = &[("dotter.os", 1), ("dotter.windows", 1)];
+
+/// Version of the output of a helper or fact that dotter implements itself. It's raised when
+/// one renders something else for the same parameters on the same machine, so that deploys
+/// warn about the templates that were rendered with the old one.
+pub fn builtin_version(name: &str) -> Option<u32> {
+    PURE_HELPERS
+        .iter()
+        .find(|(helper, _)| *helper == name)
+        .and_then(|(_, version)| *version)
+        .or_else(|| {
+            IMPURE_HELPERS
+                .iter()
+                .chain(FACTS)
+                .find(|(builtin, _)| *builtin == name)
+                .map(|(_, version)| *version)
+        })
+}
+
+fn is_pure(name: &str) -> bool {
+    PURE_HELPERS.iter().any(|(helper, _)| *helper == name)
+}
+
+/// Built-in helpers and facts that `dependencies` was rendered with an older version of
+pub fn outdated_helpers(dependencies: &TemplateDependencies) -> Vec<&str> {
+    dependencies
+        .helpers
+        .iter()
+        .filter(|(name, version)| builtin_version(name).is_some_and(|current| current != **version))
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// What the elements of a template read
+#[derive(Default)]
+struct Reads {
+    variables: BTreeSet<String>,
+    partials: BTreeSet<String>,
+    /// Helpers of global.toml, which are assumed to give the same output for the same
+    /// parameters as long as their file doesn't change
+    helpers: BTreeSet<String>,
+}
+
+/// Helpers that read the file whose path is their first parameter
+const FILE_HELPERS: &[&str] = &["include_template", "render", "content_hash", "read_to_str"];
+
+/// Files that a template reads through helpers like `include_template`, when their path is
+/// written as a literal
+pub fn referenced_files(contents: &str) -> Result<BTreeSet<PathBuf>> {
+    let compiled = Template::compile(contents).context("parse template")?;
+    let mut files = BTreeSet::new();
+    collect_files(&compiled.elements, &mut files);
+    Ok(files)
+}
+
+fn collect_files(elements: &[TemplateElement], files: &mut BTreeSet<PathBuf>) {
+    for element in elements {
+        if let TemplateElement::Expression(helper) | TemplateElement::HelperBlock(helper) = element
+        {
+            let name = helper.name.as_name().unwrap_or_default();
+            if let (true, Some(Parameter::Literal(serde_json::Value::String(path)))) =
+                (FILE_HELPERS.contains(&name), helper.params.first())
+            {
+                files.insert(PathBuf::from(path));
+            }
+            for parameter in helper.params.iter().chain(helper.hash.values()) {
+                if let Parameter::Subexpression(subexpression) = parameter {
+                    collect_files(std::slice::from_ref(subexpression.as_element()), files);
+                }
+            }
+            for template in helper.template.iter().chain(helper.inverse.iter()) {
+                collect_files(&template.elements, files);
+            }
+        }
+    }
+}
+
+/// Top-level variables that a template like a hook reads, or `None` if they can't be known
+pub fn variables_read(contents: &str, handlebars: &Handlebars) -> Option<BTreeSet<String>> {
+    let compiled = Template::compile(contents).ok()?;
+    let mut reads = Reads::default();
+    if collect_elements(&compiled.elements, handlebars, &mut reads) {
+        Some(reads.variables)
+    } else {
+        None
+    }
+}
+
+/// Helpers that dotter implements itself which read files while the template renders, and
+/// may read targets that an earlier step of the deploy changes
+const FILE_READERS: &[&str] = &[
+    "current",
+    "dir_files",
+    "include_template",
+    "render",
+    "content_hash",
+];
+
+/// Whether the handlebars template uses a helper that reads files, directly or through its
+/// partials. Such templates aren't rendered ahead of the deploy, see `render::prerender`.
+pub fn reads_files(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    limits: &RenderLimits,
+) -> bool {
+    if template.target.encrypted || template.engine() != TemplateEngine::Handlebars {
+        return false;
+    }
+    let compiled = match render::read_template_source(template, limits)
+        .and_then(|contents| Ok(render::parse_template(&contents)?))
+    {
+        Ok(compiled) => compiled,
+        // It fails again where it's deployed, which reports why
+        Err(_) => return true,
+    };
+    let mut builtins = BTreeMap::new();
+    collect_builtins(
+        &compiled.elements,
+        handlebars,
+        &mut BTreeSet::new(),
+        &mut builtins,
+    );
+    builtins
+        .keys()
+        .any(|name| FILE_READERS.contains(&name.as_str()))
+}
+
+/// Computes which variables a template reads and a hash of everything that goes into
+/// rendering it. Returns `None` if the dependencies can't be determined statically.
+pub fn template_dependencies(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<Option<TemplateDependencies>> {
+    // Encrypted files only depend on themselves, and are decrypted again when they change
+    if template.target.encrypted {
+        let contents = fs::read(&template.source).context("read encrypted file")?;
+        return Ok(Some(TemplateDependencies {
+            variables: BTreeSet::new(),
+            partials: BTreeSet::new(),
+            hash: Some(blake3::hash(&contents).to_hex().to_string()),
+            helpers: BTreeMap::new(),
+        }));
+    }
+    // Only handlebars templates can be tracked, the others are rendered on every deploy
+    if template.engine() != TemplateEngine::Handlebars {
+        return Ok(None);
+    }
+    let contents = render::read_template_source(template, limits)?;
+    let compiled = render::parse_template(&contents).context("parse template")?;
+
+    let mut builtins = BTreeMap::new();
+    collect_builtins(
+        &compiled.elements,
+        handlebars,
+        &mut BTreeSet::new(),
+        &mut builtins,
+    );
+    // A template that can't be tracked is rendered on every deploy, but its built-ins are still
+    // recorded so that an upgrade that changes them is told
+    let untracked = |builtins: BTreeMap<String, u32>| {
+        Some(TemplateDependencies {
+            variables: BTreeSet::new(),
+            partials: BTreeSet::new(),
+            hash: None,
+            helpers: builtins,
+        })
+        .filter(|dependencies| !dependencies.helpers.is_empty())
+    };
+
+    let mut reads = Reads::default();
+    if !collect_elements(&compiled.elements, handlebars, &mut reads) {
+        return Ok(untracked(builtins));
+    }
+    let Reads {
+        variables: used,
+        partials,
+        helpers,
+    } = reads;
+
+    let variables = template.context(variables);
+    let values = used
+        .iter()
+        .map(|name| (name.as_str(), variables.get(name)))
+        .collect::<BTreeMap<_, _>>();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(contents.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(
+        serde_json::to_string(&values)
+            .context("serialize variable values")?
+            .as_bytes(),
+    );
+    for partial in &partials {
+        let file = match handlebars_helpers::partial_file(partial) {
+            Some(file) => file,
+            None => return Ok(untracked(builtins)),
+        };
+        hasher.update(&[0]);
+        hasher.update(partial.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&fs::read(&file).with_context(|| format!("read partial {:?}", file))?);
+    }
+    for helper in &helpers {
+        let file = match handlebars_helpers::script_helper_file(helper) {
+            Some(file) => file,
+            None => return Ok(untracked(builtins)),
+        };
+        hasher.update(&[0]);
+        hasher.update(helper.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&fs::read(&file).with_context(|| format!("read helper {:?}", file))?);
+    }
+
+    Ok(Some(TemplateDependencies {
+        variables: used,
+        partials,
+        hash: Some(hasher.finalize().to_hex().to_string()),
+        helpers: builtins,
+    }))
+}
+
+/// Finds the built-in helpers and facts of `builtin_version` that the elements use, following
+/// partials. Unlike `collect_elements`, it goes on through what can't be tracked.
+fn collect_builtins(
+    elements: &[TemplateElement],
+    handlebars: &Handlebars,
+    partials: &mut BTreeSet<String>,
+    builtins: &mut BTreeMap<String, u32>,
+) {
+    for element in elements {
+        match element {
+            TemplateElement::HTMLExpression(parameter) => {
+                collect_builtin_parameter(parameter, handlebars, partials, builtins)
+            }
+            TemplateElement::Expression(helper) | TemplateElement::HelperBlock(helper) => {
+                let name = helper.name.as_name().unwrap_or_default();
+                let is_helper = !helper.params.is_empty()
+                    || !helper.hash.is_empty()
+                    || helper.block
+                    || handlebars.get_helper(name).is_some();
+                match builtin_version(name).filter(|_| is_helper) {
+                    Some(version) => {
+                        builtins.insert(name.to_string(), version);
+                    }
+                    None => collect_builtin_parameter(&helper.name, handlebars, partials, builtins),
+                }
+                for parameter in helper.params.iter().chain(helper.hash.values()) {
+                    collect_builtin_parameter(parameter, handlebars, partials, builtins);
+                }
+                for template in helper.template.iter().chain(helper.inverse.iter()) {
+                    collect_builtins(&template.elements, handlebars, partials, builtins);
+                }
+            }
+            TemplateElement::PartialExpression(partial)
+            | TemplateElement::PartialBlock(partial) => {
+                for parameter in partial.params.iter().chain(partial.hash.values()) {
+                    collect_builtin_parameter(parameter, handlebars, partials, builtins);
+                }
+                if let Some(block) = &partial.template {
+                    collect_builtins(&block.elements, handlebars, partials, builtins);
+                }
+                let template = partial
+                    .name
+                    .as_name()
+                    .filter(|name| partials.insert(name.to_string()))
+                    .and_then(|name| handlebars.get_template(name));
+                if let Some(template) = template {
+                    collect_builtins(&template.elements, handlebars, partials, builtins);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_builtin_parameter(
+    parameter: &Parameter,
+    handlebars: &Handlebars,
+    partials: &mut BTreeSet<String>,
+    builtins: &mut BTreeMap<String, u32>,
+) {
+    match parameter {
+        Parameter::Subexpression(subexpression) => collect_builtins(
+            std::slice::from_ref(subexpression.as_element()),
+            handlebars,
+            partials,
+            builtins,
+        ),
+        Parameter::Name(_) | Parameter::Path(_) => {
+            let name = parameter.as_name().unwrap_or_default();
+            let name = name.trim_start_matches("@root.").replace('/', ".");
+            let fact = FACTS.iter().find(|(fact, _)| {
+                name.strip_prefix(fact)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+            });
+            if let Some((fact, version)) = fact {
+                builtins.insert(fact.to_string(), *version);
+            }
+        }
+        Parameter::Literal(_) => {}
+    }
+}
+
+/// Returns false if the elements use something that can't be tracked
+fn collect_elements(
+    elements: &[TemplateElement],
+    handlebars: &Handlebars,
+    reads: &mut Reads,
+) -> bool {
+    elements
+        .iter()
+        .all(|element| collect_element(element, handlebars, reads))
+}
+
+fn collect_element(element: &TemplateElement, handlebars: &Handlebars, reads: &mut Reads) -> bool {
+    match element {
+        TemplateElement::RawString(_) | TemplateElement::Comment(_) => true,
+        TemplateElement::HTMLExpression(parameter) => {
+            collect_parameter(parameter, handlebars, reads)
+        }
+        TemplateElement::Expression(helper) | TemplateElement::HelperBlock(helper) => {
+            let name = helper.name.as_name().unwrap_or_default();
+            let is_helper = !helper.params.is_empty()
+                || !helper.hash.is_empty()
+                || helper.block
+                || handlebars.get_helper(name).is_some();
+            if is_helper && handlebars_helpers::script_helper_file(name).is_some() {
+                reads.helpers.insert(name.to_string());
+            }
+            if is_helper {
+                (is_pure(name) || reads.helpers.contains(name))
+                    && helper
+                        .params
+                        .iter()
+                        .chain(helper.hash.values())
+                        .all(|p| collect_parameter(p, handlebars, reads))
+                    && helper
+                        .template
+                        .iter()
+                        .chain(helper.inverse.iter())
+                        .all(|t| collect_elements(&t.elements, handlebars, reads))
+            } else {
+                collect_parameter(&helper.name, handlebars, reads)
+            }
+        }
+        TemplateElement::PartialExpression(partial) | TemplateElement::PartialBlock(partial) => {
+            collect_partial(partial, handlebars, reads)
+        }
+        TemplateElement::DecoratorExpression(_) | TemplateElement::DecoratorBlock(_) => false,
+    }
+}
+
+/// Partials are followed into the files of the partials directory. A partial that is given
+/// another context, or whose name is computed, can't be tracked.
+fn collect_partial(
+    partial: &DecoratorTemplate,
+    handlebars: &Handlebars,
+    reads: &mut Reads,
+) -> bool {
+    let name = match partial.name.as_name() {
+        Some(name) if partial.params.is_empty() => name,
+        _ => return false,
+    };
+    if !partial
+        .hash
+        .values()
+        .all(|p| collect_parameter(p, handlebars, reads))
+    {
+        return false;
+    }
+    // The block of `{{#> name}}` is rendered when the partial is missing, or as `@partial-block`
+    if let Some(block) = &partial.template {
+        if !collect_elements(&block.elements, handlebars, reads) {
+            return false;
+        }
+    }
+    if reads.partials.contains(name) {
+        return true;
+    }
+    let template = match handlebars.get_template(name) {
+        Some(template) if handlebars_helpers::partial_file(name).is_some() => template,
+        _ => return false,
+    };
+    reads.partials.insert(name.to_string());
+    collect_elements(&template.elements, handlebars, reads)
+}
+
+fn collect_parameter(parameter: &Parameter, handlebars: &Handlebars, reads: &mut Reads) -> bool {
+    match parameter {
+        Parameter::Literal(_) => true,
+        Parameter::Subexpression(subexpression) => {
+            collect_element(subexpression.as_element(), handlebars, reads)
+        }
+        Parameter::Name(name) => add_variable(name, &mut reads.variables),
+        Parameter::Path(_) => add_variable(
+            parameter.as_name().unwrap_or_default(),
+            &mut reads.variables,
+        ),
+    }
+}
+
+/// Records the top-level variable that a path like `this.foo.bar` or `../foo` reads.
+/// Returns false if the path refers to the whole context.
+fn add_variable(path: &str, used: &mut BTreeSet<String>) -> bool {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("../") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("@root.").or(path.strip_prefix("@root/")) {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("this.").or(path.strip_prefix("this/")) {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+
+    if path.starts_with('@') {
+        // Local variables such as @index and @key
+        return true;
+    }
+    let root = path.split(['.', '/', '[']).next().unwrap_or_default();
+    if root.is_empty() || root == "this" || root == "@root" || root == ".." || root == "." {
+        return false;
+    }
+    used.insert(root.to_string());
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::TemplateTarget;
+
+    fn handlebars() -> Handlebars<'static> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper(
+            "command_output",
+            Box::new(
+                |_: &handlebars::Helper,
+                 _: &Handlebars,
+                 _: &handlebars::Context,
+                 _: &mut handlebars::RenderContext,
+                 _: &mut dyn handlebars::Output|
+                 -> handlebars::HelperResult { Ok(()) },
+            ),
+        );
+        handlebars
+    }
+
+    fn used_variables(template: &str) -> Option<BTreeSet<String>> {
+        let compiled = Template::compile(template).unwrap();
+        let mut reads = Reads::default();
+        if collect_elements(&compiled.elements, &handlebars(), &mut reads) {
+            Some(reads.variables)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_used_variables() {
+        assert_eq!(
+            used_variables(
+                "{{a}} {{#if b.c}}{{d}}{{else}}{{../e}}{{/if}} {{#each f}}{{@index}}{{/each}}"
+            ),
+            Some(
+                vec!["a", "b", "d", "e", "f"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+        assert_eq!(
+            used_variables("{{eq (math a \"+\" 1) this.b}}").map(|v| v.len()),
+            Some(2)
+        );
+        assert_eq!(used_variables("{{command_output \"ls\"}}"), None);
+        assert_eq!(used_variables("{{> partial}}"), None);
+    }
+
+    #[test]
+    fn test_partial_dependencies() {
+        let directory = tempfile::tempdir().unwrap();
+        let partials = directory.path().join("partials");
+        fs::create_dir_all(partials.join("common")).unwrap();
+        fs::write(partials.join("common/colors.hbs"), "fg={{fg}}").unwrap();
+        let source = directory.path().join("source");
+        let template = TemplateDescription {
+            source: source.clone(),
+            target: TemplateTarget::from(directory.path().join("target")),
+            cache: directory.path().join("cache/source"),
+            variables: Variables::new(),
+        };
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: std::time::Duration::from_secs(30),
+        };
+        let _partials = handlebars_helpers::PARTIALS_TEST
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut handlebars = Handlebars::new();
+        handlebars_helpers::register_partials(&mut handlebars, &partials);
+        let dependencies = |contents: &str| {
+            fs::write(&source, contents).unwrap();
+            template_dependencies(&template, &handlebars, &Variables::new(), &limits).unwrap()
+        };
+
+        // The variables of a partial are the template's, and its contents are hashed
+        let before = dependencies("{{> common/colors}}").unwrap();
+        assert_eq!(before.partials, ["common/colors".to_string()].into());
+        assert_eq!(before.variables, ["fg".to_string()].into());
+        fs::write(partials.join("common/colors.hbs"), "bg={{fg}}").unwrap();
+        let after = dependencies("{{> common/colors}}").unwrap();
+        assert!(after.is_tracked());
+        assert_ne!(before.hash, after.hash);
+
+        // A partial that isn't a file of the partials directory can't be tracked
+        assert!(dependencies("{{> inline}}").is_none());
+    }
+
+    #[test]
+    fn test_reads_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let template = TemplateDescription {
+            source: source.clone(),
+            target: TemplateTarget::from(directory.path().join("target")),
+            cache: directory.path().join("cache/source"),
+            variables: Variables::new(),
+        };
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: std::time::Duration::from_secs(30),
+        };
+        let handlebars = Handlebars::new();
+        let reads_files = |contents: &str| {
+            fs::write(&source, contents).unwrap();
+            reads_files(&template, &handlebars, &limits)
+        };
+
+        assert!(!reads_files("{{name}} {{shell_quote name}}"));
+        assert!(reads_files("{{current \"~/.gitconfig\"}}"));
+        assert!(reads_files(
+            "{{#if x}}{{include_template (join_path a b)}}{{/if}}"
+        ));
+        assert!(reads_files("{{#if x}}"));
+    }
+
+    #[test]
+    fn test_referenced_files() {
+        let files = referenced_files(
+            "{{include_template \"a\"}} {{#if x}}{{content_hash \"b\"}}{{/if}} {{eq (read_to_str \"c\") y}} {{include_template path}}",
+        )
+        .unwrap();
+        assert_eq!(
+            files,
+            vec!["a", "b", "c"].into_iter().map(PathBuf::from).collect()
+        );
+    }
+
+    #[test]
+    fn test_outdated_helpers() {
+        let mut dependencies = TemplateDependencies {
+            variables: BTreeSet::new(),
+            partials: BTreeSet::new(),
+            hash: None,
+            helpers: BTreeMap::new(),
+        };
+        dependencies.helpers.insert("math".into(), 1);
+        assert!(outdated_helpers(&dependencies).is_empty());
+        dependencies.helpers.insert("shell_quote".into(), 0);
+        dependencies.helpers.insert("dotter.os".into(), 0);
+        assert_eq!(
+            outdated_helpers(&dependencies),
+            vec!["dotter.os", "shell_quote"]
+        );
+    }
+
+    #[test]
+    fn test_collect_builtins() {
+        let compiled = Template::compile(
+            "{{#if (eq dotter.os \"linux\")}}{{command_output \"ls\"}}{{/if}} {{math 1 \"+\" lang}} {{dotter.packages.vim}}",
+        )
+        .unwrap();
+        let mut builtins = BTreeMap::new();
+        collect_builtins(
+            &compiled.elements,
+            &handlebars(),
+            &mut BTreeSet::new(),
+            &mut builtins,
+        );
+        // `lang` is a variable here, not the helper
+        assert_eq!(
+            builtins.keys().collect::<Vec<_>>(),
+            vec!["command_output", "dotter.os", "math"]
+        );
+    }
+}
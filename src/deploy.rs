@@ -1,50 +1,163 @@
 use anyhow::{Context, Result};
 use crossterm::style::Colorize;
 
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use handlebars::Handlebars;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 
 use super::display_error;
-use args::Options;
-use config::{self, Variables};
+use args::{ConflictPolicy, Options};
+use backup;
+use capabilities;
+use classify;
+use config::{self, FileAttribute, FilePackages, SpecialFiles, UnixUser, Variables};
+use conflict::{self, Conflict, Resolution, Resolver};
+use context;
+use dependencies;
 use difference;
+use elevate;
+use events::{self, Event};
 use file_state::*;
 use filesystem::{self, SymlinkComparison, TemplateComparison};
+use fragments;
 use handlebars_helpers;
+use hooks::{self, Change, ChangeAction, HookEnvironment, HookEnvironments};
+use journal::Journal;
+use lazy;
+use managed_lines;
+use merge;
+use plan::{Plan, PlannedChange};
+use preflight;
+use reload;
+use render::{self, RenderLimits};
+use report::Report;
+use secret_cache;
+use secrets;
+use special_files;
+use summary::Summary;
+use timings::{self, Timings};
+use validate;
+use verify::{OnVerifyFailure, Verification};
+use warnings;
 
-pub fn undeploy(opt: Options) -> Result<()> {
+/// Deletes every deployed file, or only those of `package`
+pub fn undeploy(opt: &Options, package: Option<&str>, keep_rendered: bool) -> Result<()> {
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    backup::start(&opt.cache_directory);
     let cache = config::load_cache(&opt.cache_file)?
         .context("load cache: Cannot undeploy without a cache.")?;
+    let force = Force::new(opt)?;
 
     let config::Cache {
-        symlinks: existing_symlinks,
-        templates: existing_templates,
+        symlinks: mut existing_symlinks,
+        templates: mut existing_templates,
+        copies: mut existing_copies,
+        copy_hashes: mut actual_copy_hashes,
+        template_dependencies: mut actual_template_dependencies,
+        lines: added_lines,
+        elevated,
+        links,
+        render_caches,
+        mut undeploy_hooks,
+        hook_environments,
+        mut backups,
+        shown_notes,
+        fragments,
+        special: created_special,
+        activated,
+        shims,
+        capabilities,
+        ..
     } = cache;
 
+    // Like a deploy would, targets that belong to other dotfile managers now are left to them
+    match config::load_foreign(&opt.local_config, &opt.global_config) {
+        Ok(foreign) => {
+            forget_foreign(&mut existing_symlinks, &foreign);
+            forget_foreign(&mut existing_templates, &foreign);
+            forget_foreign(&mut existing_copies, &foreign);
+        }
+        Err(e) => warn!(
+            "Not checking for targets of other dotfile managers: {:#}",
+            e
+        ),
+    }
+
+    let sources = match package {
+        Some(package) => Some(package_sources(opt, package)?),
+        None => None,
+    };
+    let file_packages = if force.packages.is_empty() {
+        FilePackages::new()
+    } else {
+        let recorded = existing_symlinks
+            .keys()
+            .chain(existing_templates.keys())
+            .chain(existing_copies.keys());
+        recorded_file_packages(opt, recorded).unwrap_or_else(|e| {
+            warn!("--force only matches targets and sources: {:#}", e);
+            FilePackages::new()
+        })
+    };
+    // Files of other packages are left deployed
+    let ours = |cached: &BTreeMap<PathBuf, PathBuf>| {
+        cached
+            .iter()
+            .filter(|(source, _)| {
+                sources
+                    .as_ref()
+                    .is_none_or(|sources| sources.iter().any(|s| source.starts_with(s)))
+            })
+            .map(|(source, target)| (source.clone(), target.clone()))
+            .collect::<BTreeMap<_, _>>()
+    };
+
     // Used just to transform them into Description structs
     let state = FileState::new(
         Default::default(),
         Default::default(),
-        existing_symlinks.clone(),
-        existing_templates.clone(),
-        opt.cache_directory,
-    );
+        ours(&existing_symlinks),
+        ours(&existing_templates),
+        opt.cache_directory.clone(),
+    )
+    .with_copies(Default::default(), ours(&existing_copies))
+    .with_elevated(&elevated)
+    .with_links(&links)
+    .with_render_caches(&render_caches);
     trace!("File state: {:#?}", state);
 
     let (deleted_symlinks, deleted_templates) = state.deleted_files();
 
     let mut actual_symlinks = existing_symlinks;
     let mut actual_templates = existing_templates;
+    let mut actual_copies = existing_copies;
     let mut suggest_force = false;
+    let mut deleted = Vec::new();
+    let mut deleted_targets = Vec::new();
+    let mut kept = Vec::new();
 
     for symlink in deleted_symlinks {
-        match delete_symlink(opt.act, &symlink, opt.force, opt.interactive) {
+        if keep_rendered {
+            match keep_symlink(opt.act, &symlink) {
+                Ok(true) => {
+                    actual_symlinks.remove(&symlink.source);
+                    kept.push(symlink.source);
+                }
+                Ok(false) => {}
+                Err(e) => display_error(e.context(format!("keep symlink {}", symlink))),
+            }
+            continue;
+        }
+        let force = force.applies(&symlink.source, &symlink.target.target, &file_packages);
+        match delete_symlink(opt.act, &symlink, force, opt.interactive) {
             Ok(true) => {
                 actual_symlinks.remove(&symlink.source);
+                deleted.push(symlink.source);
+                deleted_targets.push(symlink.target.target);
             }
             Ok(false) => {
                 suggest_force = true;
@@ -54,9 +167,25 @@ pub fn undeploy(opt: Options) -> Result<()> {
     }
 
     for template in deleted_templates {
-        match delete_template(opt.act, &template, opt.force, opt.interactive) {
+        if keep_rendered {
+            info!("Leaving {} in place", template);
+            match forget_template_render(opt.act, &template) {
+                Ok(()) => {
+                    actual_templates.remove(&template.source);
+                    actual_template_dependencies.remove(&template.source);
+                    kept.push(template.source);
+                }
+                Err(e) => display_error(e.context(format!("forget template {}", template))),
+            }
+            continue;
+        }
+        let force = force.applies(&template.source, &template.target.target, &file_packages);
+        match delete_template(opt.act, &template, force, opt.interactive) {
             Ok(true) => {
                 actual_templates.remove(&template.source);
+                actual_template_dependencies.remove(&template.source);
+                deleted.push(template.source);
+                deleted_targets.push(template.target.target);
             }
             Ok(false) => {
                 suggest_force = true;
@@ -65,18 +194,169 @@ pub fn undeploy(opt: Options) -> Result<()> {
         }
     }
 
+    for copy in state.deleted_copies() {
+        if keep_rendered {
+            info!("Leaving {} in place", copy);
+            actual_copies.remove(&copy.source);
+            actual_copy_hashes.remove(&copy.source);
+            kept.push(copy.source);
+            continue;
+        }
+        let force = force.applies(&copy.source, &copy.target.target, &file_packages);
+        let recorded_hash = actual_copy_hashes.get(&copy.source);
+        match delete_copy(opt.act, &copy, recorded_hash, force, opt.interactive) {
+            Ok(true) => {
+                actual_copies.remove(&copy.source);
+                actual_copy_hashes.remove(&copy.source);
+                deleted.push(copy.source);
+                deleted_targets.push(copy.target.target);
+            }
+            Ok(false) => {
+                suggest_force = true;
+            }
+            Err(e) => display_error(e.context(format!("delete copy {}", copy))),
+        }
+    }
+
+    // Managed lines don't belong to a package, undeploying one leaves them
+    // and keeping what was rendered leaves them in their files, unmanaged
+    let actual_lines = match package {
+        Some(_) => added_lines,
+        None if keep_rendered => Default::default(),
+        None => managed_lines::deploy(opt.act, &Default::default(), &added_lines).0,
+    };
+    let actual_special = match package {
+        Some(package) => {
+            let (ours, mut others): (SpecialFiles, SpecialFiles) = created_special
+                .into_iter()
+                .partition(|(_, special)| special.package.as_deref() == Some(package));
+            others.append(&mut special_files::deploy(opt.act, &Default::default(), &ours).0);
+            others
+        }
+        None => special_files::deploy(opt.act, &Default::default(), &created_special).0,
+    };
+
     if suggest_force {
-        error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
+        error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag, or --force=<glob-or-package> to only overwrite some of them.");
     }
 
     if opt.act {
+        for (source, target) in deleted.iter().zip(&deleted_targets) {
+            events::report(Event::Changed(&Change {
+                source: source.clone(),
+                target: target.clone(),
+                action: ChangeAction::Deleted,
+                old_hash: None,
+                new_hash: None,
+                metadata: Vec::new(),
+            }));
+        }
+
+        for source in &kept {
+            undeploy_hooks.remove(source);
+        }
+
+        // The configuration isn't loaded to undeploy, so the hooks are rendered without variables,
+        // and run where the deploy that recorded them would have
+        let hooks = deleted
+            .iter()
+            .filter_map(|source| undeploy_hooks.remove(source))
+            .flatten()
+            .collect::<BTreeSet<_>>();
+        // Only checked once there's a hook that needs it
+        let mut online = None;
+        for hook in hooks {
+            let environment = hook_environments.get(&hook).cloned().unwrap_or_default();
+            if environment.requires_network
+                && !*online.get_or_insert_with(|| network_available(opt))
+            {
+                warnings::warn(
+                    "skipped-hook",
+                    hook.display(),
+                    format!(
+                        "Skipping hook {:?}: it requires the network, and the machine is offline",
+                        hook
+                    ),
+                );
+                continue;
+            }
+            info!("Running hook {:?}", hook);
+            if let Err(e) = hooks::run_hook(
+                &hook,
+                opt,
+                &Handlebars::new(),
+                &Variables::new(),
+                None,
+                &environment,
+            ) {
+                display_error(e.context(format!("run hook {:?}", hook)));
+            }
+        }
+
+        let services = deleted_targets
+            .iter()
+            .filter_map(|target| reload::service_reload(target, true))
+            .collect::<BTreeSet<_>>();
+        for service in services {
+            info!("Reloading {}", service);
+            if let Err(e) = reload::run(&service) {
+                display_error(e.context(format!("reload {}", service)));
+            }
+        }
+
+        let fragments = fragments
+            .into_iter()
+            .filter(|(target, _)| actual_templates.values().any(|t| t == target))
+            .collect();
+        // The notes of undeployed packages are shown again when they're deployed again
+        let shown_notes = match package {
+            Some(package) => shown_notes.into_iter().filter(|p| p != package).collect(),
+            None => Default::default(),
+        };
+        // Lazy packages are lazy again when they're deployed again
+        let activated = match package {
+            Some(package) => activated.into_iter().filter(|p| p != package).collect(),
+            None => Default::default(),
+        };
+        let (removed_shims, mut shims): (BTreeMap<_, _>, _) = shims
+            .into_iter()
+            .partition(|(_, p)| package.is_none_or(|package| p == package));
+        shims.extend(lazy::remove_shims(opt, &removed_shims).context("remove shims")?);
+        let elevated = still_deployed(
+            elevated,
+            &actual_symlinks,
+            &actual_templates,
+            &actual_copies,
+        );
+        let links = still_linked(links, &actual_symlinks);
+        let render_caches = still_rendered(render_caches, &actual_templates);
         // Should be empty if everything went well, but if some things were skipped this contains
         // them.
+        backups.extend(backup::finish());
         config::save_cache(
             &opt.cache_file,
             config::Cache {
+                schema: config::CACHE_SCHEMA,
                 symlinks: actual_symlinks,
                 templates: actual_templates,
+                copies: actual_copies,
+                copy_hashes: actual_copy_hashes,
+                template_dependencies: actual_template_dependencies,
+                repository: None,
+                dotter_version: None,
+                shown_notes,
+                fragments,
+                lines: actual_lines,
+                special: actual_special,
+                elevated,
+                links,
+                hook_environments: still_hooked(hook_environments, &undeploy_hooks),
+                render_caches,
+                undeploy_hooks,
+                activated,
+                shims,
+                capabilities,
+                backups,
             },
         )?;
     }
@@ -84,10 +364,57 @@ pub fn undeploy(opt: Options) -> Result<()> {
     Ok(())
 }
 
+/// Whether the machine is online, for undeploying without the whole configuration. The
+/// default probes stand in for the configured ones if the configuration can't be read.
+fn network_available(opt: &Options) -> bool {
+    let probes =
+        config::network_probes(&opt.local_config, &opt.global_config).unwrap_or_else(|e| {
+            debug!("Probing the default addresses: {:#}", e);
+            hooks::default_network_probes()
+        });
+    hooks::network_available(&probes)
+}
+
+/// Sources that `package` declares, even if it isn't enabled. The cache doesn't know the
+/// packages of the files it records, and a directory source covers the files inside it.
+fn package_sources(opt: &Options, package: &str) -> Result<Vec<PathBuf>> {
+    let declared = config::load_declared(&opt.local_config, &opt.global_config)
+        .context("load declared packages")?;
+    match declared.package_files.get(package) {
+        Some(files) => Ok(files.keys().cloned().collect()),
+        None => bail!(
+            "package {:?} doesn't exist in {:?}",
+            package,
+            opt.global_config
+        ),
+    }
+}
+
+/// Packages of the recorded sources, for `--force=<package>`. The cache doesn't know them
+/// either, so they're the packages that declare the sources, like in `package_sources`.
+fn recorded_file_packages<'a>(
+    opt: &Options,
+    recorded: impl Iterator<Item = &'a PathBuf>,
+) -> Result<FilePackages> {
+    let declared = config::load_declared(&opt.local_config, &opt.global_config)
+        .context("load declared packages")?;
+    Ok(recorded
+        .filter_map(|source| {
+            let (package, _) = declared
+                .package_files
+                .iter()
+                .find(|(_, files)| files.keys().any(|declared| source.starts_with(declared)))?;
+            Some((source.clone(), package.clone()))
+        })
+        .collect())
+}
+
+/// Unless `act`, the files that are assembled from fragments are only kept in memory
 pub fn file_state_from_configuration(
     config: &config::Configuration,
     cache: &config::Cache,
     cache_directory: &Path,
+    act: bool,
 ) -> Result<FileState> {
     // On Windows, you need developer mode to create symlinks.
     let symlinks_enabled = if filesystem::symlinks_enabled(&PathBuf::from("DOTTER_SYMLINK_TEST"))
@@ -105,6 +432,20 @@ Proceeding by copying instead of symlinking."
 
     let mut desired_symlinks = BTreeMap::new();
     let mut desired_templates = BTreeMap::new();
+    let mut desired_copies = BTreeMap::new();
+
+    // Files with a missing source aren't deployed, but their targets shouldn't be deleted either.
+    // Neither should targets that now belong to another dotfile manager.
+    let is_ours = |source: &PathBuf, target: &PathBuf| {
+        !config.missing_sources.contains_key(source)
+            && config::foreign_manager(&config.foreign, target).is_none()
+    };
+    let mut cached_symlinks = cache.symlinks.clone();
+    cached_symlinks.retain(|source, target| is_ours(source, target));
+    let mut cached_templates = cache.templates.clone();
+    cached_templates.retain(|source, target| is_ours(source, target));
+    let mut cached_copies = cache.copies.clone();
+    cached_copies.retain(|source, target| is_ours(source, target));
 
     for (source, target) in config.files.clone() {
         match target {
@@ -118,6 +459,24 @@ Proceeding by copying instead of symlinking."
                         config::SymbolicTarget {
                             target,
                             owner: None,
+                            group: None,
+                            volatile: false,
+                            critical: false,
+                            allow_overwrite: false,
+                            elevate: false,
+                            reload: None,
+                            service_unit: false,
+                            on_deploy: None,
+                            on_undeploy: None,
+                            directory_owner: None,
+                            directory_mode: None,
+                            after: Vec::new(),
+                            recursive: false,
+                            link: Default::default(),
+                            literal_braces: false,
+                            mode: None,
+                            flags: Vec::new(),
+                            verify: None,
                         },
                     );
                 } else {
@@ -126,8 +485,32 @@ Proceeding by copying instead of symlinking."
                         config::TemplateTarget {
                             target,
                             owner: None,
+                            group: None,
+                            mode: None,
+                            attributes: Vec::new(),
+                            acl: Vec::new(),
                             append: None,
                             prepend: None,
+                            volatile: false,
+                            write_once: false,
+                            critical: false,
+                            allow_overwrite: false,
+                            elevate: false,
+                            reload: None,
+                            service_unit: false,
+                            on_deploy: None,
+                            on_undeploy: None,
+                            equivalence: Default::default(),
+                            diff: Default::default(),
+                            directory_owner: None,
+                            directory_mode: None,
+                            after: Vec::new(),
+                            recursive: false,
+                            engine: None,
+                            render_cache: None,
+                            validate: None,
+                            encrypted: false,
+                            verify: None,
                         },
                     );
                 }
@@ -141,8 +524,32 @@ Proceeding by copying instead of symlinking."
                         config::TemplateTarget {
                             target: target.target,
                             owner: target.owner,
+                            group: target.group,
+                            mode: None,
+                            attributes: Vec::new(),
+                            acl: Vec::new(),
                             append: None,
                             prepend: None,
+                            volatile: target.volatile,
+                            write_once: false,
+                            critical: target.critical,
+                            allow_overwrite: target.allow_overwrite,
+                            elevate: target.elevate,
+                            reload: target.reload,
+                            service_unit: target.service_unit,
+                            on_deploy: target.on_deploy,
+                            on_undeploy: target.on_undeploy,
+                            equivalence: Default::default(),
+                            diff: Default::default(),
+                            directory_owner: target.directory_owner,
+                            directory_mode: target.directory_mode,
+                            after: target.after,
+                            recursive: false,
+                            engine: None,
+                            render_cache: None,
+                            validate: None,
+                            encrypted: false,
+                            verify: None,
                         },
                     );
                 }
@@ -150,25 +557,71 @@ Proceeding by copying instead of symlinking."
             config::FileTarget::ComplexTemplate(target) => {
                 desired_templates.insert(source, target);
             }
+            config::FileTarget::Copy(target) => {
+                desired_copies.insert(source, target);
+            }
+            // Assembled below
+            config::FileTarget::Fragment(_) => {}
         }
     }
 
+    for (target, parts) in fragments::compositions(&config.files) {
+        let source = fragments::assemble(&target, &parts, cache_directory, act)
+            .with_context(|| format!("assemble fragments of {:?}", target))?;
+        desired_templates.insert(source, target.into());
+    }
+
     trace!("Desired symlinks: {:#?}", desired_symlinks);
     trace!("Desired templates: {:#?}", desired_templates);
+    trace!("Desired copies: {:#?}", desired_copies);
 
     let state = FileState::new(
         desired_symlinks,
         desired_templates,
-        cache.symlinks.clone(),
-        cache.templates.clone(),
+        cached_symlinks,
+        cached_templates,
         cache_directory.into(),
-    );
+    )
+    .with_copies(desired_copies, cached_copies)
+    .with_elevated(&cache.elevated)
+    .with_links(&cache.links)
+    .with_render_caches(&cache.render_caches)
+    .with_private_variables(&config.file_packages, &config.private_variables)
+    .with_template_engines(&config.file_packages, &config.template_engines);
 
     Ok(state)
 }
 
-/// Returns true if an error was printed
-pub fn deploy(opt: &Options) -> Result<bool> {
+/// The Handlebars instance that templates are rendered with
+pub fn handlebars_instance<'a>(opt: &Options, config: &config::Configuration) -> Handlebars<'a> {
+    debug!("Creating Handlebars instance...");
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(|s| s.to_string()); // Disable html-escaping
+    handlebars.set_strict_mode(true); // Report missing variables as errors
+    handlebars_helpers::register_rust_helpers(
+        &mut handlebars,
+        handlebars_helpers::render_time(opt.deterministic),
+    );
+    handlebars_helpers::register_fetch_helper(
+        &mut handlebars,
+        opt.cache_directory.clone(),
+        config.allow_fetch,
+    );
+    handlebars_helpers::register_secret_helper(&mut handlebars, config.secrets.clone());
+    handlebars_helpers::register_script_helpers(
+        &mut handlebars,
+        &config.helpers,
+        config.allow_program_helpers,
+    );
+    if let Some(partials) = &config.partials {
+        handlebars_helpers::register_partials(&mut handlebars, partials);
+    }
+    trace!("Handlebars instance: {:#?}", handlebars);
+    handlebars
+}
+
+/// Loads the configuration, including the manual patch from stdin if it was requested
+pub fn load_configuration(opt: &Options) -> Result<config::Configuration> {
     let mut patch = None;
     if opt.patch {
         debug!("Reading manual patch from stdin...");
@@ -180,180 +633,1537 @@ pub fn deploy(opt: &Options) -> Result<bool> {
     }
     trace!("Manual patch: {:#?}", patch);
 
-    let config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
-        .context("get a configuration")?;
+    let mut config = config::load_configuration(
+        &opt.local_config,
+        &opt.global_config,
+        &opt.cache_directory,
+        opt.profile.as_deref(),
+        opt.duplicate_targets,
+        patch,
+    )?;
+    if !config.lazy_packages.is_empty() {
+        let activated = config::load_cache(&opt.cache_file)?
+            .map(|cache| cache.activated)
+            .unwrap_or_default();
+        lazy::hold_back(&mut config, &activated).context("hold back lazy packages")?;
+    }
+    filesystem::set_environment(config.env.clone());
+    secrets::set_config(&config.secrets);
+    secret_cache::set_cache_directory(&opt.cache_directory, opt.refresh_secrets);
+    filesystem::set_hash_algorithm(config.hash_algorithm);
+    Ok(config)
+}
+
+/// Sources of the deployed templates that read `variable`, according to the dependencies
+/// recorded when they were rendered. Templates whose variables can't be known read it too.
+pub fn templates_reading(opt: &Options, variable: &str) -> Result<BTreeSet<PathBuf>> {
+    let cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    Ok(cache
+        .templates
+        .keys()
+        .filter(|source| {
+            cache
+                .template_dependencies
+                .get(*source)
+                .is_none_or(|dependencies| {
+                    !dependencies.is_tracked() || dependencies.variables.contains(variable)
+                })
+        })
+        .cloned()
+        .collect())
+}
+
+/// Removes the targets that belong to other dotfile managers from the cache, without touching them
+fn forget_foreign(cached: &mut BTreeMap<PathBuf, PathBuf>, foreign: &config::ForeignPaths) {
+    cached.retain(
+        |source, target| match config::foreign_manager(foreign, target) {
+            Some((manager, _)) => {
+                info!(
+                    "Forgetting {:?} -> {:?} because it belongs to {} now",
+                    source, target, manager
+                );
+                false
+            }
+            None => true,
+        },
+    );
+}
+
+/// Which targets `--force` overwrites
+struct Force {
+    all: bool,
+    globs: GlobSet,
+    packages: BTreeSet<String>,
+    /// Sources of the files that set `allow_overwrite`, whose deployed targets are overwritten
+    sources: BTreeSet<PathBuf>,
+}
+
+impl Force {
+    fn new(opt: &Options) -> Result<Force> {
+        let patterns = opt.force.clone().unwrap_or_default();
+        let mut globs = GlobSetBuilder::new();
+        for pattern in &patterns {
+            let pattern = filesystem::expand_tilde(pattern);
+            globs.add(
+                Glob::new(&pattern).with_context(|| format!("parse --force glob {:?}", pattern))?,
+            );
+        }
+        Ok(Force {
+            all: opt.force.is_some() && patterns.is_empty(),
+            globs: globs.build().context("build --force globs")?,
+            packages: patterns.into_iter().collect(),
+            sources: BTreeSet::new(),
+        })
+    }
+
+    /// Also overwrites the deployed targets of `sources`, without being asked to
+    fn with_sources(self, sources: BTreeSet<PathBuf>) -> Force {
+        Force { sources, ..self }
+    }
+
+    /// Files match by their target or source, or by the package they belong to
+    fn applies(&self, source: &Path, target: &Path, file_packages: &FilePackages) -> bool {
+        self.all
+            || self.globs.is_match(target)
+            || self.globs.is_match(source)
+            || file_packages
+                .get(source)
+                .is_some_and(|package| self.packages.contains(package))
+    }
+
+    /// Like `applies`, for a target that dotter deployed before. `allow_overwrite` only
+    /// overwrites these, a file that was there before dotter still needs `--force`.
+    fn applies_deployed(&self, source: &Path, target: &Path, file_packages: &FilePackages) -> bool {
+        self.sources.contains(source) || self.applies(source, target, file_packages)
+    }
+}
+
+/// Where template errors are reported besides the terminal, so that they're noticed while
+/// `dotter watch` runs in the background
+#[derive(Debug, Default)]
+pub struct ErrorOverlay {
+    /// Comment syntax of the banner that is written at the top of a failed template's target.
+    /// Only templates that were already deployed get a banner.
+    pub comment: Option<String>,
+    /// File that is rewritten after every deploy with the templates that failed
+    pub status_file: Option<PathBuf>,
+}
+
+/// What `dotter watch` keeps in memory between deploys: the configuration, the handlebars
+/// instance with its helpers and partials, and the variables of the templates. Every other
+/// deploy starts from an empty one.
+#[derive(Default)]
+pub struct Warm {
+    loaded: Option<Loaded>,
+    /// Sources that changed since the last deploy, when nothing else did. Only the templates
+    /// of these sources are rendered again, the others still have the dependencies that the
+    /// cache recorded.
+    changed_sources: Option<BTreeSet<PathBuf>>,
+}
+
+struct Loaded {
+    config: config::Configuration,
+    handlebars: Handlebars<'static>,
+    variables: Variables,
+}
+
+impl Warm {
+    /// Forgets what was loaded unless every changed path is the source of a file. Sources are
+    /// read again by every deploy anyway, anything else may be read while loading, like the
+    /// configuration, helpers, partials, or a new file in a directory that is deployed.
+    pub fn invalidate(&mut self, changed: &[&Path]) {
+        self.changed_sources = None;
+        let loaded = match &self.loaded {
+            Some(loaded) => loaded,
+            None => return,
+        };
+        let repository = std::env::current_dir().unwrap_or_default();
+        let sources = changed
+            .iter()
+            .map(|path| path.strip_prefix(&repository).unwrap_or(path))
+            .collect::<Vec<_>>();
+        let sources_only = !sources.is_empty()
+            && sources
+                .iter()
+                .all(|source| loaded.config.files.contains_key(*source) && source.is_file());
+        if sources_only {
+            debug!("Keeping the loaded configuration, only sources changed");
+            self.changed_sources = Some(sources.into_iter().map(PathBuf::from).collect());
+        } else {
+            self.loaded = None;
+        }
+    }
+
+    fn load(&mut self, opt: &Options) -> Result<&Loaded> {
+        if self.loaded.is_none() {
+            let config = load_configuration(opt).context("get a configuration")?;
+            self.loaded = Some(Loaded {
+                handlebars: handlebars_instance(opt, &config),
+                variables: context::template_context(&config),
+                config,
+            });
+        }
+        Ok(self.loaded.as_ref().expect("loaded above"))
+    }
+}
+
+/// Returns true if an error was printed
+pub fn deploy(opt: &Options, overlay: &ErrorOverlay) -> Result<bool> {
+    deploy_changes(opt, overlay).map(|deployed| deployed.error_occurred)
+}
+
+/// Like `deploy`, but with what `warm` loaded for an earlier deploy
+pub fn deploy_warm(opt: &Options, overlay: &ErrorOverlay, warm: &mut Warm) -> Result<bool> {
+    deploy_with(opt, overlay, warm).map(|deployed| deployed.error_occurred)
+}
+
+/// The outcome of a deploy that didn't fail as a whole
+#[derive(Debug, Default)]
+pub struct Deployed {
+    /// Targets that were changed, empty after a dry run or a rolled back deploy
+    pub changes: Vec<Change>,
+    /// Whether an error was printed
+    pub error_occurred: bool,
+    /// Whether the deploy stopped early because `opt.cancellation` was cancelled
+    pub cancelled: bool,
+}
+
+pub fn deploy_changes(opt: &Options, overlay: &ErrorOverlay) -> Result<Deployed> {
+    deploy_with(opt, overlay, &mut Warm::default())
+}
+
+fn deploy_with(opt: &Options, overlay: &ErrorOverlay, warm: &mut Warm) -> Result<Deployed> {
+    let mut timings = Timings::start();
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    backup::start(&opt.cache_directory);
+    let changed_sources = warm.changed_sources.take();
+    let Loaded {
+        config,
+        handlebars,
+        variables,
+    } = warm.load(opt)?;
+    let config = config.clone();
+
+    for (source, package) in &config.missing_sources {
+        let message = match package {
+            Some(package) => format!("Source {:?} of package {:?} is missing", source, package),
+            None => format!("Source {:?} is missing", source),
+        };
+        // With --skip-missing-sources the file is left out on purpose
+        if opt.skip_missing_sources {
+            warnings::warn(
+                "skipped-entry",
+                source.display(),
+                format!("{}, leaving it out", message),
+            );
+        } else {
+            error!("{}", message);
+        }
+    }
+    if opt.act && !config.missing_sources.is_empty() && !opt.skip_missing_sources {
+        bail!(
+            "{} sources are missing from the repository. Fix the configuration, or use --skip-missing-sources to deploy the other files anyway.",
+            config.missing_sources.len()
+        );
+    }
 
-    let cache = match config::load_cache(&opt.cache_file)? {
+    let mut cache = match config::load_cache(&opt.cache_file)? {
         Some(cache) => cache,
         None => {
             warn!("Cache file not found. Assuming cache is empty.");
             Default::default()
         }
     };
+    if let Some(version) = cache
+        .dotter_version
+        .as_deref()
+        .filter(|v| *v != env!("CARGO_PKG_VERSION"))
+    {
+        info!(
+            "The cache was saved by dotter {}, this is dotter {}",
+            version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    if !opt.refresh {
+        warn_outdated_helpers(&cache);
+    }
+    capabilities::remember(&cache.capabilities);
+    let shims = lazy::update_shims(opt, &config.shims, &cache.shims).context("update shims")?;
+    let activated = cache
+        .activated
+        .intersection(&config.lazy_packages)
+        .cloned()
+        .collect();
+    cache.match_case(&config.files);
+    classify::warn_symlinked_templates(&config);
+    warn_symlink_permissions(&config);
+    timings.lap("load configuration");
 
-    let state = file_state_from_configuration(&config, &cache, &opt.cache_directory)
+    let mut state = file_state_from_configuration(&config, &cache, &opt.cache_directory, opt.act)
         .context("get file state")?;
+    if opt.volatile_only {
+        debug!("Only deploying volatile files");
+        for source in state.retain_volatile() {
+            info!("Skipping {:?}: it isn't volatile", source);
+        }
+    }
+    if !opt.only.is_empty() {
+        for package in &opt.only {
+            if !config.packages.contains(package) {
+                bail!("package {:?} of --only is not enabled", package);
+            }
+        }
+        for source in state.retain_packages(&opt.only, &config.file_packages) {
+            debug!(
+                "Skipping {:?}: its package isn't selected with --only",
+                source
+            );
+        }
+    }
+    if let Some(sources) = &opt.only_sources {
+        for source in state.retain_sources(|source| sources.contains(source)) {
+            debug!("Skipping {:?}: it isn't affected", source);
+        }
+    }
     trace!("File state: {:#?}", state);
+    let elevated_targets = state.elevated_targets();
+    let overwritable = state.overwritable_sources();
+    let links = state.links();
+    let render_caches = state.render_caches(&cache.render_caches);
+    relocate_renders(&mut state, opt.act).context("move rendered copies of templates")?;
+
+    let reloads = state
+        .desired_symlinks
+        .iter()
+        .filter_map(|s| Some((s.source.clone(), s.target.reload.clone()?)))
+        .chain(
+            state
+                .desired_templates
+                .iter()
+                .filter_map(|t| Some((t.source.clone(), t.target.reload.clone()?))),
+        )
+        .chain(
+            state
+                .desired_copies
+                .iter()
+                .filter_map(|c| Some((c.source.clone(), c.target.reload.clone()?))),
+        )
+        .collect::<BTreeMap<_, _>>();
+    let services = service_units(&state);
+
+    let compositions = fragments::compositions(&config.files)
+        .into_iter()
+        .map(|(target, parts)| {
+            let sources = parts.into_iter().map(|(source, _)| source).collect();
+            (target, sources)
+        })
+        .collect::<BTreeMap<_, Vec<_>>>();
+    for (target, sources) in &compositions {
+        if let Some(description) = cache
+            .fragments
+            .get(target)
+            .and_then(|old| fragments::describe_composition_change(old, sources))
+        {
+            info!("Fragments of {:?} changed: {}", target, description);
+        }
+    }
 
+    let (deploy_hooks, undeploy_hooks) = hooks_by_source(&state, &config);
+    let hook_environments = hook_environments(&deploy_hooks, &undeploy_hooks, &config);
+    // A dry run reports everything it would run but can't, so that it's all fixed at once
+    let missing_programs = if opt.act {
+        Vec::new()
+    } else {
+        preflight::missing_programs(&config, &deploy_hooks, &undeploy_hooks, &reloads)
+            .context("check hooks and programs")?
+    };
+    for missing in &missing_programs {
+        error!("{}", missing);
+    }
     let config::Configuration {
-        files,
-        mut variables,
-        helpers,
-        packages,
+        file_packages,
+        package_levels,
+        notes,
+        missing_sources,
+        foreign,
+        lines,
+        special,
+        merge_tool,
+        network_probes,
+        ..
     } = config;
+    // Merging asks first, so it needs someone to ask
+    let merge_tool = merge_tool.filter(|_| opt.act && opt.interactive);
 
     let config::Cache {
         symlinks: mut actual_symlinks,
         templates: mut actual_templates,
+        copies: mut actual_copies,
+        copy_hashes: mut actual_copy_hashes,
+        template_dependencies: mut actual_template_dependencies,
+        shown_notes,
+        lines: added_lines,
+        special: created_special,
+        undeploy_hooks: recorded_undeploy_hooks,
+        hook_environments: recorded_hook_environments,
+        mut backups,
+        ..
     } = cache;
 
+    forget_foreign(&mut actual_symlinks, &foreign);
+    forget_foreign(&mut actual_templates, &foreign);
+    forget_foreign(&mut actual_copies, &foreign);
+
+    let force = Force::new(opt)?.with_sources(overwritable);
+    // A dry run doesn't ask, since the answers wouldn't change anything
+    let policy = match opt.on_conflict {
+        ConflictPolicy::Ask if !opt.act => ConflictPolicy::Skip,
+        policy => policy,
+    };
+    let mut resolver = Resolver::new(policy, opt.diff_context_lines);
     let mut suggest_force = false;
+    // Errors of critical files abort the deploy, so missing sources are only counted at the end
     let mut error_occurred = false;
+    let mut changes = Vec::new();
+    let mut template_errors = Vec::new();
 
-    let (deleted_symlinks, deleted_templates) = state.deleted_files();
-    trace!("Deleted symlinks: {:#?}", deleted_symlinks);
-    trace!("Deleted templates: {:#?}", deleted_templates);
-    for deleted_symlink in deleted_symlinks {
-        match delete_symlink(opt.act, &deleted_symlink, opt.force, opt.interactive) {
-            Ok(true) => {
-                actual_symlinks.remove(&deleted_symlink.source);
-            }
-            Ok(false) => {
-                suggest_force = true;
-            }
-            Err(e) => {
-                display_error(e.context(format!("delete symlink {}", deleted_symlink)));
-                error_occurred = true;
+    let limits = RenderLimits::new(opt);
+
+    timings.lap("compute plan");
+
+    let summarize = !opt.act || opt.summary_only;
+    let planned = if summarize || events::enabled() {
+        Some(Plan::new(
+            &state,
+            &file_packages,
+            &missing_sources,
+            handlebars,
+            variables,
+            &limits,
+        ))
+    } else {
+        None
+    };
+    if let Some(planned) = &planned {
+        for file in planned.files.iter() {
+            if file.change != PlannedChange::Unchanged {
+                events::report(Event::Planned(file));
             }
         }
+        timings.lap("summarize plan");
     }
-    for deleted_template in deleted_templates {
-        match delete_template(opt.act, &deleted_template, opt.force, opt.interactive) {
-            Ok(true) => {
-                actual_templates.remove(&deleted_template.source);
+    // The events replace the summary
+    let summary = planned
+        .filter(|_| summarize && !events::enabled())
+        .map(|planned| Summary::new(&planned));
+
+    debug!("Running pre-deploy hook");
+    hooks::run_hook(
+        &opt.pre_deploy,
+        opt,
+        handlebars,
+        variables,
+        None,
+        &HookEnvironment::default(),
+    )
+    .context("run pre-deploy hook")?;
+    timings.lap("pre-deploy hook");
+
+    let plan = state
+        .deploy_steps(&file_packages, &package_levels)
+        .context("order files by their `after` field")?;
+    trace!("Deploy steps: {:#?}", plan);
+
+    // Templates are rendered in parallel up front, the files are still written one at a time
+    // and in order. Templates that come after other targets or read files might read what an
+    // earlier step writes, so they're left to render in their turn.
+    let prerender = plan
+        .steps
+        .iter()
+        .filter_map(|step| match step {
+            DeployStep::NewTemplate(t) if t.target.write_once && exists(&t.target.target) => None,
+            DeployStep::NewTemplate(t) => Some(t),
+            DeployStep::OldTemplate(t) if !t.target.write_once => {
+                let dependencies = current_dependencies(
+                    t,
+                    handlebars,
+                    variables,
+                    &limits,
+                    &actual_template_dependencies,
+                    changed_sources.as_ref(),
+                );
+                let unchanged = !opt.refresh
+                    && dependencies.as_ref().is_some_and(|d| d.is_tracked())
+                    && actual_template_dependencies.get(&t.source) == dependencies.as_ref();
+                Some(t).filter(|_| !unchanged)
             }
-            Ok(false) => {
-                suggest_force = true;
+            _ => None,
+        })
+        .filter(|t| t.target.after.is_empty())
+        .filter(|t| !dependencies::reads_files(t, handlebars, &limits))
+        .collect::<Vec<_>>();
+    let jobs = opt.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let _prerendered = render::prerender(
+        &prerender,
+        handlebars,
+        variables,
+        &limits,
+        jobs,
+        &opt.cancellation,
+    );
+    timings.lap("render templates");
+    // The threads' rendering adds up to more than the time they took, it has its own lap
+    render::take_render_time();
+    let mut journal = if opt.act && opt.transactional {
+        Some(Journal::new(&opt.cache_directory).context("start journal")?)
+    } else {
+        None
+    };
+    let mut cancelled = false;
+    for (index, step) in plan.steps.into_iter().enumerate() {
+        if opt.cancellation.is_cancelled() {
+            warn!("The deploy was cancelled, the remaining files were left as they are");
+            cancelled = true;
+            error_occurred = true;
+            break;
+        }
+        if let Some(journal) = &mut journal {
+            if error_occurred {
+                break;
             }
-            Err(e) => {
-                display_error(e.context(format!("delete template {}", deleted_template)));
+            if let Err(e) = step
+                .touched_paths()
+                .into_iter()
+                .try_for_each(|path| journal.record(path))
+            {
+                display_error(e.context("record previous state of file"));
                 error_occurred = true;
+                break;
             }
         }
-    }
-
-    // Prepare handlebars instance
-    debug!("Creating Handlebars instance...");
-    let mut handlebars = Handlebars::new();
-    handlebars.register_escape_fn(|s| s.to_string()); // Disable html-escaping
-    handlebars.set_strict_mode(true); // Report missing variables as errors
-    handlebars_helpers::register_rust_helpers(&mut handlebars);
-    handlebars_helpers::register_script_helpers(&mut handlebars, &helpers);
-    handlebars_helpers::add_dotter_variable(&mut variables, &files, &packages);
-    trace!("Handlebars instance: {:#?}", handlebars);
-
-    let (new_symlinks, new_templates) = state.new_files();
-    trace!("New symlinks: {:#?}", new_symlinks);
-    trace!("New templates: {:#?}", new_templates);
-    for new_symlink in new_symlinks {
-        match create_symlink(opt.act, &new_symlink, opt.force) {
-            Ok(true) => {
-                actual_symlinks.insert(new_symlink.source, new_symlink.target.target);
+        if index == plan.critical && index > 0 && opt.act && (error_occurred || suggest_force) {
+            error!(
+                "A critical file couldn't be deployed, so the other files were left as they are."
+            );
+            break;
+        }
+        // Started before the step so that `on_failure = "rollback"` can undo it. A dry run
+        // doesn't change the target, so there's nothing to check or undo.
+        let verification = if opt.act {
+            match Verification::start(&step, &opt.cache_directory) {
+                Ok(verification) => verification,
+                Err(e) => {
+                    display_error(e.context(format!("prepare verifying {:?}", step.target())));
+                    error_occurred = true;
+                    continue;
+                }
             }
-            Ok(false) => {
-                suggest_force = true;
+        } else {
+            None
+        };
+        let changes_before = changes.len();
+        let copy_hash_before = actual_copy_hashes.get(step.source()).cloned();
+        match step {
+            DeployStep::DeletedSymlink(deleted_symlink) => {
+                let old_hash = hash_target(opt.act, &deleted_symlink.target.target);
+                let force = force.applies(
+                    &deleted_symlink.source,
+                    &deleted_symlink.target.target,
+                    &file_packages,
+                );
+                match delete_symlink(opt.act, &deleted_symlink, force, opt.interactive) {
+                    Ok(true) => {
+                        actual_symlinks.remove(&deleted_symlink.source);
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Deleted,
+                            &deleted_symlink.source,
+                            &deleted_symlink.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("delete symlink {}", deleted_symlink)));
+                        error_occurred = true;
+                    }
+                }
             }
-            Err(e) => {
-                display_error(e.context(format!("create symlink {}", new_symlink)));
-                error_occurred = true;
+            DeployStep::DeletedTemplate(deleted_template) => {
+                let old_hash = hash_target(opt.act, &deleted_template.target.target);
+                let force = force.applies(
+                    &deleted_template.source,
+                    &deleted_template.target.target,
+                    &file_packages,
+                );
+                match delete_template(opt.act, &deleted_template, force, opt.interactive) {
+                    Ok(true) => {
+                        actual_templates.remove(&deleted_template.source);
+                        actual_template_dependencies.remove(&deleted_template.source);
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Deleted,
+                            &deleted_template.source,
+                            &deleted_template.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("delete template {}", deleted_template)));
+                        error_occurred = true;
+                    }
+                }
             }
-        }
-    }
-    for new_template in new_templates {
-        match create_template(opt.act, &new_template, &handlebars, &variables, opt.force) {
-            Ok(true) => {
+            DeployStep::DeletedCopy(deleted_copy) => {
+                let old_hash = hash_target(opt.act, &deleted_copy.target.target);
+                let force = force.applies(
+                    &deleted_copy.source,
+                    &deleted_copy.target.target,
+                    &file_packages,
+                );
+                let recorded_hash = actual_copy_hashes.get(&deleted_copy.source);
+                match delete_copy(
+                    opt.act,
+                    &deleted_copy,
+                    recorded_hash,
+                    force,
+                    opt.interactive,
+                ) {
+                    Ok(true) => {
+                        actual_copies.remove(&deleted_copy.source);
+                        actual_copy_hashes.remove(&deleted_copy.source);
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Deleted,
+                            &deleted_copy.source,
+                            &deleted_copy.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("delete copy {}", deleted_copy)));
+                        error_occurred = true;
+                    }
+                }
+            }
+            DeployStep::NewSymlink(new_symlink) => {
+                let old_hash = hash_target(opt.act, &new_symlink.target.target);
+                let force = force.applies(
+                    &new_symlink.source,
+                    &new_symlink.target.target,
+                    &file_packages,
+                );
+                let force = match resolve_conflict(&mut resolver, opt.act, force, || {
+                    symlink_conflict(&new_symlink, &opt.cache_directory)
+                }) {
+                    Ok(Some(force)) => force,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        display_error(e.context(format!("resolve conflict of {}", new_symlink)));
+                        error_occurred = true;
+                        continue;
+                    }
+                };
+                match create_symlink(opt.act, &new_symlink, force, &opt.cache_directory) {
+                    Ok(true) => {
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Created,
+                            &new_symlink.source,
+                            &new_symlink.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                        actual_symlinks.insert(new_symlink.source, new_symlink.target.target);
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("create symlink {}", new_symlink)));
+                        error_occurred = true;
+                    }
+                }
+            }
+            DeployStep::NewTemplate(new_template)
+                if new_template.target.write_once && exists(&new_template.target.target) =>
+            {
+                info!(
+                    "Keeping the existing target of {}, it's only written once",
+                    new_template
+                );
+                if let Err(e) = keep_write_once_template(opt.act, &new_template) {
+                    display_error(e.context(format!("keep template {}", new_template)));
+                    error_occurred = true;
+                    continue;
+                }
                 actual_templates.insert(new_template.source, new_template.target.target);
             }
-            Ok(false) => {
-                suggest_force = true;
+            DeployStep::NewTemplate(new_template) => {
+                let old_hash = hash_target(opt.act, &new_template.target.target);
+                let force = force.applies(
+                    &new_template.source,
+                    &new_template.target.target,
+                    &file_packages,
+                );
+                // Before the dependencies are hashed, since adopting the target changes the source
+                let force = match resolve_conflict(&mut resolver, opt.act, force, || {
+                    template_conflict(&new_template, handlebars, variables, &limits)
+                }) {
+                    Ok(Some(force)) => force,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        display_error(e.context(format!("resolve conflict of {}", new_template)));
+                        error_occurred = true;
+                        continue;
+                    }
+                };
+                let dependencies =
+                    template_dependencies(&new_template, handlebars, variables, &limits);
+                match create_template(
+                    opt.act,
+                    &new_template,
+                    handlebars,
+                    variables,
+                    &limits,
+                    force,
+                ) {
+                    Ok(true) => {
+                        record_dependencies(
+                            &mut actual_template_dependencies,
+                            &new_template,
+                            dependencies,
+                        );
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Created,
+                            &new_template.source,
+                            &new_template.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                        actual_templates.insert(new_template.source, new_template.target.target);
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        template_errors.push(format!("{}: {:#}", new_template, e));
+                        display_error(e.context(format!("create template {}", new_template)));
+                        error_occurred = true;
+                    }
+                }
             }
-            Err(e) => {
-                display_error(e.context(format!("create template {}", new_template)));
-                error_occurred = true;
+            DeployStep::NewCopy(new_copy)
+                if new_copy.target.write_once && exists(&new_copy.target.target) =>
+            {
+                info!(
+                    "Keeping the existing target of {}, it's only written once",
+                    new_copy
+                );
+                if let Some(hash) = hash_target(opt.act, &new_copy.target.target) {
+                    actual_copy_hashes.insert(new_copy.source.clone(), hash);
+                }
+                actual_copies.insert(new_copy.source, new_copy.target.target);
+            }
+            DeployStep::NewCopy(new_copy) => {
+                let old_hash = hash_target(opt.act, &new_copy.target.target);
+                let force =
+                    force.applies(&new_copy.source, &new_copy.target.target, &file_packages);
+                let force = match resolve_conflict(&mut resolver, opt.act, force, || {
+                    copy_conflict(&new_copy)
+                }) {
+                    Ok(Some(force)) => force,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        display_error(e.context(format!("resolve conflict of {}", new_copy)));
+                        error_occurred = true;
+                        continue;
+                    }
+                };
+                match create_copy(opt.act, &new_copy, force) {
+                    Ok(true) => {
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Created,
+                            &new_copy.source,
+                            &new_copy.target.target,
+                            old_hash,
+                            Vec::new(),
+                        );
+                        if let Some(hash) = hash_target(opt.act, &new_copy.target.target) {
+                            actual_copy_hashes.insert(new_copy.source.clone(), hash);
+                        }
+                        actual_copies.insert(new_copy.source, new_copy.target.target);
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("create copy {}", new_copy)));
+                        error_occurred = true;
+                    }
+                }
+            }
+            DeployStep::OldSymlink(old_symlink) => {
+                let old_hash = hash_target(opt.act, &old_symlink.target.target);
+                let metadata = metadata_changes(old_symlink.metadata_changes(), &old_symlink);
+                match update_symlink(
+                    opt.act,
+                    &old_symlink,
+                    force.applies_deployed(
+                        &old_symlink.source,
+                        &old_symlink.target.target,
+                        &file_packages,
+                    ),
+                    &opt.cache_directory,
+                    &metadata,
+                ) {
+                    Ok(true) => {
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Updated,
+                            &old_symlink.source,
+                            &old_symlink.target.target,
+                            old_hash,
+                            metadata,
+                        );
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("update symlink {}", old_symlink)));
+                        error_occurred = true;
+                    }
+                }
+            }
+            DeployStep::OldTemplate(old_template) if old_template.target.write_once => {
+                debug!("Leaving {} unmanaged, it's only written once", old_template);
+            }
+            DeployStep::OldTemplate(old_template) => {
+                let old_hash = hash_target(opt.act, &old_template.target.target);
+                let dependencies = current_dependencies(
+                    &old_template,
+                    handlebars,
+                    variables,
+                    &limits,
+                    &actual_template_dependencies,
+                    changed_sources.as_ref(),
+                );
+                let dependencies_unchanged = !opt.refresh
+                    && dependencies.as_ref().is_some_and(|d| d.is_tracked())
+                    && actual_template_dependencies.get(&old_template.source)
+                        == dependencies.as_ref();
+                let metadata = metadata_changes(old_template.metadata_changes(), &old_template);
+                match update_template(
+                    opt.act,
+                    &old_template,
+                    handlebars,
+                    variables,
+                    &limits,
+                    force.applies_deployed(
+                        &old_template.source,
+                        &old_template.target.target,
+                        &file_packages,
+                    ),
+                    opt.diff_context_lines,
+                    dependencies_unchanged,
+                    &metadata,
+                    merge_tool.as_deref(),
+                ) {
+                    Ok(true) => {
+                        record_dependencies(
+                            &mut actual_template_dependencies,
+                            &old_template,
+                            dependencies,
+                        );
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Updated,
+                            &old_template.source,
+                            &old_template.target.target,
+                            old_hash,
+                            metadata,
+                        );
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        template_errors.push(format!("{}: {:#}", old_template, e));
+                        if let (true, Some(comment)) = (opt.act, &overlay.comment) {
+                            match write_error_banner(&old_template, comment, &e) {
+                                // Render it again next time even if nothing changes, to remove the banner
+                                Ok(()) => {
+                                    actual_template_dependencies.remove(&old_template.source);
+                                }
+                                Err(e) => display_error(e.context("write error banner")),
+                            }
+                        }
+                        display_error(e.context(format!("update template {}", old_template)));
+                        error_occurred = true;
+                    }
+                }
+            }
+            DeployStep::OldCopy(old_copy) if old_copy.target.write_once => {
+                debug!("Leaving {} unmanaged, it's only written once", old_copy);
+            }
+            DeployStep::OldCopy(old_copy) => {
+                let old_hash = hash_target(opt.act, &old_copy.target.target);
+                let metadata = metadata_changes(old_copy.metadata_changes(), &old_copy);
+                match update_copy(
+                    opt.act,
+                    &old_copy,
+                    actual_copy_hashes.get(&old_copy.source),
+                    force.applies_deployed(
+                        &old_copy.source,
+                        &old_copy.target.target,
+                        &file_packages,
+                    ),
+                    &metadata,
+                ) {
+                    Ok(true) => {
+                        record_change(
+                            opt.act,
+                            &mut changes,
+                            ChangeAction::Updated,
+                            &old_copy.source,
+                            &old_copy.target.target,
+                            old_hash,
+                            metadata,
+                        );
+                        if let Some(hash) = hash_target(opt.act, &old_copy.target.target) {
+                            actual_copy_hashes.insert(old_copy.source, hash);
+                        }
+                    }
+                    Ok(false) => {
+                        suggest_force = true;
+                    }
+                    Err(e) => {
+                        display_error(e.context(format!("update copy {}", old_copy)));
+                        error_occurred = true;
+                    }
+                }
             }
         }
-    }
 
-    let (old_symlinks, old_templates) = state.old_files();
-    trace!("Old symlinks: {:#?}", old_symlinks);
-    trace!("Old templates: {:#?}", old_templates);
-    for old_symlink in old_symlinks {
-        match update_symlink(opt.act, &old_symlink, opt.force) {
-            Ok(true) => {}
-            Ok(false) => {
-                suggest_force = true;
-            }
-            Err(e) => {
-                display_error(e.context(format!("update symlink {}", old_symlink)));
-                error_occurred = true;
+        // Only targets that the step changed are checked
+        let verification = match verification.filter(|_| changes.len() > changes_before) {
+            Some(verification) => verification,
+            None => continue,
+        };
+        if let Err(e) = verification.check() {
+            display_error(e.context(format!("verify {:?}", verification.target())));
+            error_occurred = true;
+            match verification.on_failure() {
+                OnVerifyFailure::Report => {}
+                OnVerifyFailure::Abort => {
+                    error!(
+                        "A file failed its verification, so the other files were left as they are."
+                    );
+                    break;
+                }
+                OnVerifyFailure::Rollback => {
+                    info!("Rolling back {:?}", verification.target());
+                    // The target is what it was before this deploy, and so is what the cache says
+                    let source = verification.source().to_path_buf();
+                    let created = changes[changes_before..]
+                        .iter()
+                        .any(|change| change.action == ChangeAction::Created);
+                    changes.truncate(changes_before);
+                    if created {
+                        actual_symlinks.remove(&source);
+                        actual_templates.remove(&source);
+                        actual_copies.remove(&source);
+                    }
+                    actual_template_dependencies.remove(&source);
+                    match copy_hash_before {
+                        Some(hash) => actual_copy_hashes.insert(source, hash),
+                        None => actual_copy_hashes.remove(&source),
+                    };
+                    if let Err(e) = verification.rollback() {
+                        display_error(e.context("roll back target"));
+                    }
+                }
             }
         }
     }
-    for old_template in old_templates {
-        match update_template(
-            opt.act,
-            &old_template,
-            &handlebars,
-            &variables,
-            opt.force,
-            opt.diff_context_lines,
-        ) {
-            Ok(true) => {}
-            Ok(false) => {
-                suggest_force = true;
-            }
-            Err(e) => {
-                display_error(e.context(format!("update template {}", old_template)));
-                error_occurred = true;
-            }
+
+    if let (Some(journal), false) = (&mut journal, error_occurred) {
+        // Special files are only recorded while they don't exist, since a named pipe can't be
+        // backed up
+        if let Err(e) = lines
+            .keys()
+            .chain(added_lines.keys())
+            .chain(
+                special
+                    .keys()
+                    .filter(|target| target.symlink_metadata().is_err()),
+            )
+            .try_for_each(|target| journal.record(target))
+        {
+            display_error(e.context("record previous state of file"));
+            error_occurred = true;
         }
     }
+    // A transactional deploy that failed is rolled back, so there's no point in going on
+    let (actual_lines, actual_special) = if journal.is_some() && error_occurred || cancelled {
+        (added_lines, created_special)
+    } else {
+        let (actual_lines, lines_error) = managed_lines::deploy(opt.act, &lines, &added_lines);
+        let (actual_special, special_error) =
+            special_files::deploy(opt.act, &special, &created_special);
+        error_occurred |= lines_error || special_error;
+        (actual_lines, actual_special)
+    };
 
+    timings.lap_with_part(
+        "filesystem actions",
+        Some(("rendering", render::take_render_time())),
+    );
     trace!("Actual symlinks: {:#?}", actual_symlinks);
     trace!("Actual templates: {:#?}", actual_templates);
+    trace!("Actual copies: {:#?}", actual_copies);
+
+    if !missing_sources.is_empty() || !missing_programs.is_empty() {
+        error_occurred = true;
+    }
 
     if suggest_force {
-        error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag.");
+        error!("Some files were skipped. To ignore errors and overwrite unexpected target files, use the --force flag, or --force=<glob-or-package> to only overwrite some of them.");
         error_occurred = true;
     }
 
+    if let Some(summary) = summary {
+        summary.print();
+    }
+
+    match journal {
+        Some(journal) if error_occurred => {
+            if !cancelled {
+                error!("Some files failed, so every change of this deploy is being undone.");
+            }
+            journal.rollback().context("roll back deploy")?;
+            return Ok(Deployed {
+                changes: Vec::new(),
+                error_occurred: true,
+                cancelled,
+            });
+        }
+        Some(journal) => journal.commit().context("remove journal")?,
+        None => {}
+    }
+
+    let mut changes_file = None;
     if opt.act {
+        let deployed_fragments = compositions
+            .into_iter()
+            .filter(|(target, _)| actual_templates.values().any(|t| t == target))
+            .collect();
+        let elevated = still_deployed(
+            elevated_targets,
+            &actual_symlinks,
+            &actual_templates,
+            &actual_copies,
+        );
+        let links = still_linked(links, &actual_symlinks);
+        let render_caches = still_rendered(render_caches, &actual_templates);
+        // Files that failed to be deleted keep the hooks that they were deployed with
+        let deployed_undeploy_hooks = actual_symlinks
+            .keys()
+            .chain(actual_templates.keys())
+            .chain(actual_copies.keys())
+            .filter_map(|source| {
+                let hooks = undeploy_hooks
+                    .get(source)
+                    .or_else(|| recorded_undeploy_hooks.get(source))?;
+                Some((source.clone(), hooks.clone()))
+            })
+            .collect();
+        backups.extend(backup::finish());
+        let hooks = vec![&opt.pre_deploy, &opt.post_deploy]
+            .into_iter()
+            .chain(deploy_hooks.values().flatten())
+            .chain(undeploy_hooks.values().flatten())
+            .collect::<BTreeSet<_>>();
+        warn_unused_variables(
+            &config.variables,
+            &actual_templates,
+            &actual_template_dependencies,
+            UsedElsewhere {
+                hooks: &hooks,
+                helpers: &config.helpers,
+                handlebars,
+            },
+        );
         config::save_cache(
             &opt.cache_file,
             config::Cache {
+                schema: config::CACHE_SCHEMA,
                 symlinks: actual_symlinks,
                 templates: actual_templates,
+                copies: actual_copies,
+                copy_hashes: actual_copy_hashes,
+                template_dependencies: actual_template_dependencies,
+                repository: None,
+                dotter_version: None,
+                shown_notes: notes.keys().cloned().collect(),
+                fragments: deployed_fragments,
+                lines: actual_lines,
+                special: actual_special,
+                elevated,
+                links,
+                render_caches,
+                hook_environments: still_hooked(
+                    recorded_hook_environments
+                        .clone()
+                        .into_iter()
+                        .chain(hook_environments.clone())
+                        .collect(),
+                    &deployed_undeploy_hooks,
+                ),
+                undeploy_hooks: deployed_undeploy_hooks,
+                activated,
+                shims,
+                capabilities: capabilities::recorded(),
+                backups,
             },
         )?;
-    }
 
-    Ok(error_occurred)
-}
+        print_new_notes(&notes, &shown_notes);
 
-/// Returns true if symlink should be deleted from cache
-fn delete_symlink(
-    act: bool,
-    symlink: &SymlinkDescription,
-    force: bool,
-    interactive: bool,
-) -> Result<bool> {
-    info!("{} {}", "[-]".red(), symlink);
+        trace!("Changes: {:#?}", changes);
+        for change in &changes {
+            events::report(Event::Changed(change));
+        }
+        let location = opt.cache_directory.join(".dotter_changes.json");
+        hooks::write_changes_file(&location, &changes).context("write changes file")?;
+        changes_file = Some(location);
 
-    let comparison = filesystem::compare_symlink(&symlink.source, &symlink.target.target)
-        .context("detect symlink's current state")?;
+        if let Some(status_file) = &overlay.status_file {
+            let mut status = template_errors.join("\n");
+            if !status.is_empty() {
+                status.push('\n');
+            }
+            fs::write(status_file, status).context("write status file")?;
+        }
+
+        timings.lap("save cache");
+
+        for reload in reload::changed_reloads(&changes, &reloads, &services) {
+            if opt.cancellation.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            info!("Reloading {}", reload);
+            if let Err(e) = reload::run(&reload) {
+                display_error(e.context(format!("reload {}", reload)));
+                error_occurred = true;
+            }
+        }
+        timings.lap("reloads");
+
+        // Only checked once there's a hook that needs it
+        let mut online = None;
+        for hook in hooks::changed_hooks(&changes, &deploy_hooks, &recorded_undeploy_hooks) {
+            if opt.cancellation.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            let environment = hook_environments
+                .get(&hook)
+                .or_else(|| recorded_hook_environments.get(&hook))
+                .cloned()
+                .unwrap_or_default();
+            if environment.requires_network
+                && !*online.get_or_insert_with(|| hooks::network_available(&network_probes))
+            {
+                warnings::warn(
+                    "skipped-hook",
+                    hook.display(),
+                    format!(
+                        "Skipping hook {:?}: it requires the network, and the machine is offline",
+                        hook
+                    ),
+                );
+                continue;
+            }
+            info!("Running hook {:?}", hook);
+            if let Err(e) = hooks::run_hook(
+                &hook,
+                opt,
+                handlebars,
+                variables,
+                changes_file.as_deref(),
+                &environment,
+            ) {
+                display_error(e.context(format!("run hook {:?}", hook)));
+                error_occurred = true;
+            }
+        }
+        timings.lap("file hooks");
+    }
+
+    cancelled |= opt.cancellation.is_cancelled();
+    if cancelled {
+        info!("Not running the post-deploy hook, since the deploy was cancelled");
+        error_occurred = true;
+    } else {
+        debug!("Running post-deploy hook");
+        if let Err(e) = hooks::run_hook(
+            &opt.post_deploy,
+            opt,
+            handlebars,
+            variables,
+            changes_file.as_deref(),
+            &HookEnvironment::default(),
+        ) {
+            display_error(e.context("run post-deploy hook"));
+            error_occurred = true;
+        }
+    }
+    timings.lap("post-deploy hook");
+
+    if opt.timings {
+        timings.print();
+    }
+    if opt.act {
+        timings
+            .save(&timings::timings_file(opt))
+            .context("save timings")?;
+    }
+
+    Ok(Deployed {
+        changes,
+        error_occurred,
+        cancelled,
+    })
+}
+
+/// Sources of the files marked `service_unit = true`
+fn service_units(state: &FileState) -> BTreeSet<PathBuf> {
+    let units = state
+        .desired_symlinks
+        .iter()
+        .filter(|s| s.target.service_unit)
+        .map(|s| (&s.source, &s.target.target))
+        .chain(
+            state
+                .desired_templates
+                .iter()
+                .filter(|t| t.target.service_unit)
+                .map(|t| (&t.source, &t.target.target)),
+        )
+        .chain(
+            state
+                .desired_copies
+                .iter()
+                .filter(|c| c.target.service_unit)
+                .map(|c| (&c.source, &c.target.target)),
+        );
+    let mut sources = BTreeSet::new();
+    for (source, target) in units {
+        if reload::service_reload(target, false).is_none() {
+            warn!(
+                "{:?} is a service unit, but its target {:?} isn't in a `systemd/user` or `LaunchAgents` directory",
+                source, target
+            );
+        }
+        sources.insert(source.clone());
+    }
+    sources
+}
+
+/// Prints the notes of packages that weren't deployed before
+fn print_new_notes(notes: &BTreeMap<String, String>, shown_notes: &BTreeSet<String>) {
+    let new_notes = notes
+        .iter()
+        .filter(|(package, _)| !shown_notes.contains(*package))
+        .collect::<Vec<_>>();
+    if new_notes.is_empty() {
+        return;
+    }
+    if events::enabled() {
+        for (package, note) in new_notes {
+            events::report(Event::Note { package, note });
+        }
+        return;
+    }
+
+    println!("Notes:");
+    for (package, note) in new_notes {
+        println!("  {}:", package.as_str().yellow());
+        for line in note.trim().lines() {
+            println!("    {}", line);
+        }
+    }
+}
+
+const BANNER_START: &str = "dotter: failed to render this file";
+const BANNER_END: &str = "dotter: end of error";
+
+/// Puts the error at the top of the template's target, replacing an earlier banner.
+/// The cache gets the same contents so that the next deploy doesn't see the target as changed.
+fn write_error_banner(
+    template: &TemplateDescription,
+    comment: &str,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let contents = fs::read_to_string(&template.target.target).context("read target file")?;
+    let contents = match contents.find(BANNER_END) {
+        Some(end) if contents.starts_with(&format!("{} {}", comment, BANNER_START)) => contents
+            [end..]
+            .split_once('\n')
+            .map(|(_, rest)| rest.to_string())
+            .unwrap_or_default(),
+        _ => contents,
+    };
+
+    let mut banner = format!(
+        "{} {}, it still has the last successful render\n",
+        comment, BANNER_START
+    );
+    for cause in error.chain() {
+        for line in cause.to_string().lines() {
+            banner.push_str(&format!("{} {}\n", comment, line));
+        }
+    }
+    banner.push_str(&format!("{} {}\n", comment, BANNER_END));
+    banner.push_str(&contents);
+
+    fs::write(&template.target.target, &banner).context("write banner to target file")?;
+    fs::write(&template.cache, &banner).context("write banner to cache file")?;
+    Ok(())
+}
+
+fn template_dependencies(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Option<config::TemplateDependencies> {
+    match dependencies::template_dependencies(template, handlebars, variables, limits) {
+        Ok(dependencies) => dependencies,
+        Err(e) => {
+            debug!("Couldn't determine dependencies of {}: {:?}", template, e);
+            None
+        }
+    }
+}
+
+/// Like `template_dependencies`, but a template whose source didn't change keeps the recorded
+/// dependencies when `changed_sources` says that nothing else changed either
+fn current_dependencies(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+    recorded: &BTreeMap<PathBuf, config::TemplateDependencies>,
+    changed_sources: Option<&BTreeSet<PathBuf>>,
+) -> Option<config::TemplateDependencies> {
+    match recorded.get(&template.source) {
+        Some(recorded) if changed_sources.is_some_and(|c| !c.contains(&template.source)) => {
+            Some(recorded.clone())
+        }
+        _ => template_dependencies(template, handlebars, variables, limits),
+    }
+}
+
+fn record_dependencies(
+    template_dependencies: &mut BTreeMap<PathBuf, config::TemplateDependencies>,
+    template: &TemplateDescription,
+    dependencies: Option<config::TemplateDependencies>,
+) {
+    match dependencies {
+        Some(dependencies) => {
+            template_dependencies.insert(template.source.clone(), dependencies);
+        }
+        None => {
+            template_dependencies.remove(&template.source);
+        }
+    }
+}
+
+/// Hashes a target before it is touched so that hooks can be told whether it changed
+fn hash_target(act: bool, target: &Path) -> Option<String> {
+    if !act {
+        return None;
+    }
+    match filesystem::hash_file(target) {
+        Ok(hash) => hash,
+        Err(e) => {
+            display_error(e.context(format!("hash target {:?}", target)));
+            None
+        }
+    }
+}
+
+fn record_change(
+    act: bool,
+    changes: &mut Vec<Change>,
+    action: ChangeAction,
+    source: &Path,
+    target: &Path,
+    old_hash: Option<String>,
+    metadata: Vec<MetadataChange>,
+) {
+    if !act {
+        return;
+    }
+    match Change::detect(action, source, target, old_hash, metadata) {
+        Ok(Some(change)) => changes.push(change),
+        Ok(None) => {}
+        Err(e) => display_error(e.context(format!("detect change of {:?}", target))),
+    }
+}
+
+/// Failing to detect metadata changes shouldn't stop the deployment, so errors are only displayed
+fn metadata_changes(
+    changes: Result<Vec<MetadataChange>>,
+    description: &dyn std::fmt::Display,
+) -> Vec<MetadataChange> {
+    changes.unwrap_or_else(|e| {
+        display_error(e.context(format!("detect metadata changes of {}", description)));
+        Vec::new()
+    })
+}
+
+/// Replaces a deployed symlink with a copy of what it points at, so that the target keeps
+/// working once dotter forgets it. Targets that aren't the deployed symlink anymore are left as
+/// they are. Returns whether the symlink can be forgotten.
+fn keep_symlink(act: bool, symlink: &SymlinkDescription) -> Result<bool> {
+    let comparison = symlink
+        .compare()
+        .context("detect symlink's current state")?;
+    debug!("Current state: {}", comparison);
+
+    match comparison {
+        SymlinkComparison::TargetIsSource => Err(target_is_source(symlink).into()),
+        // A hardlink is already a file of its own
+        SymlinkComparison::Identical if symlink.target.link == config::LinkKind::Hardlink => {
+            info!("Leaving {} in place", symlink);
+            Ok(true)
+        }
+        SymlinkComparison::Identical => {
+            info!("{} {} becomes a copy", "[~]".yellow(), symlink);
+            if act {
+                replace_with_copy(symlink).context("replace symlink with a copy of its source")?;
+            }
+            Ok(true)
+        }
+        SymlinkComparison::OnlyTargetExists | SymlinkComparison::Circular => {
+            warn!(
+                "Leaving {} in place, but its source is gone so it stays broken.",
+                symlink
+            );
+            Ok(true)
+        }
+        SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink => {
+            info!(
+                "Leaving {} in place, it isn't the deployed symlink anymore.",
+                symlink
+            );
+            Ok(true)
+        }
+        SymlinkComparison::OnlySourceExists | SymlinkComparison::BothMissing => {
+            warn!("Forgetting {} but target doesn't exist.", symlink);
+            Ok(true)
+        }
+    }
+}
+
+/// Copies the source next to the symlink first, so that the target isn't lost if copying fails
+fn replace_with_copy(symlink: &SymlinkDescription) -> Result<()> {
+    let target = &symlink.target.target;
+    elevate::or_elevated(
+        symlink.target.elevate,
+        target,
+        || {
+            let target = filesystem::long_path(target);
+            let mut copy = target.clone().into_os_string();
+            copy.push(".dotter_keep");
+            let copy = PathBuf::from(copy);
+            filesystem::copy_recursively(&symlink.source, &copy)?;
+            if let Err(e) = remove_target(&target, false) {
+                let _ = fs::remove_dir_all(&copy).or_else(|_| fs::remove_file(&copy));
+                return Err(e);
+            }
+            if copy.is_dir() {
+                fs::rename(&copy, &target).context("move copy to target")?;
+            } else {
+                filesystem::replace_file(&copy, &target).context("move copy to target")?;
+            }
+            Ok(())
+        },
+        || {
+            let metadata = elevate::Metadata {
+                mode: filesystem::file_mode(&symlink.source).context("get mode of source")?,
+                owner: &symlink.target.owner,
+                group: &symlink.target.group,
+            };
+            elevate::copy(
+                &symlink.source,
+                target,
+                &metadata,
+                &symlink_directories(symlink),
+            )
+        },
+    )
+}
+
+/// Deletes the cached render of a template whose target is left where it is
+fn forget_template_render(act: bool, template: &TemplateDescription) -> Result<()> {
+    if act {
+        match fs::remove_file(&template.cache) {
+            Ok(()) => {
+                delete_render_parents(template).context("delete parent directory in cache")?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("delete template cache"),
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if symlink should be deleted from cache
+fn delete_symlink(
+    act: bool,
+    symlink: &SymlinkDescription,
+    force: bool,
+    interactive: bool,
+) -> Result<bool> {
+    info!("{} {}", "[-]".red(), symlink);
+
+    let comparison = symlink
+        .compare()
+        .context("detect symlink's current state")?;
     debug!("Current state: {}", comparison);
 
     match comparison {
+        SymlinkComparison::TargetIsSource => Err(target_is_source(symlink).into()),
         SymlinkComparison::OnlySourceExists | SymlinkComparison::BothMissing => {
             warn!(
                 "Deleting {} but target doesn't exist. Removing from cache anyways.",
@@ -376,17 +2186,23 @@ fn delete_symlink(
             Ok(false)
         }
         s => {
-            if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
+            if s == SymlinkComparison::Circular {
+                warn!("Deleting {} but target is a circular symlink.", symlink);
+            } else if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
                 warn!(
                     "Deleting {} but target wasn't what was expected. Forcing.",
                     symlink
                 );
+                if act {
+                    backup::back_up(&symlink.target.target).context("back up target")?;
+                }
             }
 
             debug!("Performing deletion");
             if act {
-                fs::remove_file(&symlink.target.target).context("remove symlink")?;
-                filesystem::delete_parents(&symlink.target.target, interactive)
+                remove_target(&symlink.target.target, symlink.target.elevate)
+                    .context("remove symlink")?;
+                delete_target_parents(&symlink.target.target, symlink.target.elevate, interactive)
                     .context("delete parents of symlink")?;
             }
             Ok(true)
@@ -403,8 +2219,12 @@ fn delete_template(
 ) -> Result<bool> {
     info!("{} {}", "[-]".red(), template);
 
-    let comparison = filesystem::compare_template(&template.target.target, &template.cache)
-        .context("detect templated file's current state")?;
+    let comparison = filesystem::compare_template(
+        &template.target.target,
+        &template.cache,
+        &template.target.equivalence,
+    )
+    .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
 
     match comparison {
@@ -415,17 +2235,22 @@ fn delete_template(
             );
             if act {
                 fs::remove_file(&template.cache).context("delete template cache")?;
-                filesystem::delete_parents(&template.cache, false)
-                    .context("delete parent directory in cache")?;
+                delete_render_parents(template).context("delete parent directory in cache")?;
             }
             Ok(true)
         }
-        TemplateComparison::OnlyTargetExists | TemplateComparison::BothMissing => {
+        TemplateComparison::BothMissing => {
+            warn!(
+                "Deleting {} but neither the target nor its cached render exist.",
+                template
+            );
+            Ok(true)
+        }
+        TemplateComparison::OnlyTargetExists if !force => {
             error!(
-                "Deleting {} but cache doesn't exist. Cache probably CORRUPTED.",
+                "Deleting {} but its cached render is missing, so the target may have been edited. Skipping...",
                 template
             );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
             Ok(false)
         }
         TemplateComparison::Changed if !force => {
@@ -436,21 +2261,30 @@ fn delete_template(
             Ok(false)
         }
         t => {
-            if t == TemplateComparison::Changed {
+            if t == TemplateComparison::Changed || t == TemplateComparison::OnlyTargetExists {
                 warn!(
-                    "Deleting {} but target contents were changed. Forcing.",
+                    "Deleting {} but target contents may have been changed. Forcing.",
                     template
                 );
+                if act {
+                    backup::back_up(&template.target.target).context("back up target")?;
+                }
             }
 
             debug!("Performing deletion");
             if act {
-                fs::remove_file(&template.target.target).context("delete target file")?;
-                filesystem::delete_parents(&template.target.target, interactive)
-                    .context("delete parent directory in target location")?;
-                fs::remove_file(&template.cache).context("delete cache file")?;
-                filesystem::delete_parents(&template.cache, false)
-                    .context("delete parent directory in cache")?;
+                remove_target(&template.target.target, template.target.elevate)
+                    .context("delete target file")?;
+                delete_target_parents(
+                    &template.target.target,
+                    template.target.elevate,
+                    interactive,
+                )
+                .context("delete parent directory in target location")?;
+                if t != TemplateComparison::OnlyTargetExists {
+                    fs::remove_file(&template.cache).context("delete cache file")?;
+                    delete_render_parents(template).context("delete parent directory in cache")?;
+                }
             }
             Ok(true)
         }
@@ -458,14 +2292,22 @@ fn delete_template(
 }
 
 /// Returns true if symlink should be added to cache
-fn create_symlink(act: bool, symlink: &SymlinkDescription, force: bool) -> Result<bool> {
+fn create_symlink(
+    act: bool,
+    symlink: &SymlinkDescription,
+    force: bool,
+    cache_directory: &Path,
+) -> Result<bool> {
     info!("{} {}", "[+]".green(), symlink);
 
-    let comparison = filesystem::compare_symlink(&symlink.source, &symlink.target.target)
+    let comparison = symlink
+        .compare()
         .context("detect symlink's current state")?;
     debug!("Current state: {}", comparison);
+    let broken = is_broken_symlink(&comparison, &symlink.target.target, cache_directory);
 
     match comparison {
+        SymlinkComparison::TargetIsSource => Err(target_is_source(symlink).into()),
         SymlinkComparison::OnlyTargetExists | SymlinkComparison::BothMissing => {
             error!("Creating {} but source is missing. Skipping...", symlink);
             Ok(false)
@@ -474,7 +2316,7 @@ fn create_symlink(act: bool, symlink: &SymlinkDescription, force: bool) -> Resul
             warn!("Creating {} but target already exists and points at source. Adding to cache anyways", symlink);
             Ok(true)
         }
-        SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink if !force => {
+        SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink if !force && !broken => {
             error!(
                 "Creating {} but target already exists and differs from expected. Skipping...",
                 symlink
@@ -482,46 +2324,169 @@ fn create_symlink(act: bool, symlink: &SymlinkDescription, force: bool) -> Resul
             Ok(false)
         }
         s => {
-            if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
+            if broken {
+                warn!(
+                    "Creating {} but target is a circular symlink or points into the cache. Replacing it.",
+                    symlink
+                );
+                if act {
+                    remove_target(&symlink.target.target, symlink.target.elevate)
+                        .context("remove broken symlink")?;
+                }
+            } else if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
                 warn!(
                     "Creating {} but target already exists and differs from expected. Forcing.",
                     symlink
                 );
-                std::fs::remove_file(&symlink.target.target)
-                    .context("remove symlink target while forcing")?;
+                if act {
+                    backup::back_up(&symlink.target.target).context("back up target")?;
+                    remove_target(&symlink.target.target, symlink.target.elevate)
+                        .context("remove symlink target while forcing")?;
+                }
             }
 
             debug!("Performing creation");
             if act {
-                fs::create_dir_all(
-                    &symlink
-                        .target
-                        .target
-                        .parent()
-                        .context("get parent of target file")?,
-                )
-                .context("create parent for target file")?;
-                filesystem::make_symlink(&symlink.target.target, &symlink.source)
-                    .context("create target symlink")?;
+                perform_symlink_deployment(symlink)?;
             }
             Ok(true)
         }
     }
 }
 
+/// Asks the resolver what to do with a target that dotter didn't create but is in the way of a
+/// new file, unless the file is forced anyway. Returns whether to force the creation, or `None`
+/// to keep the target.
+fn resolve_conflict(
+    resolver: &mut Resolver,
+    act: bool,
+    force: bool,
+    conflict: impl FnOnce() -> Result<Option<Conflict>>,
+) -> Result<Option<bool>> {
+    if force || resolver.skips_all() {
+        return Ok(Some(force));
+    }
+    let conflict = match conflict()? {
+        Some(conflict) => conflict,
+        None => return Ok(Some(false)),
+    };
+    Ok(match resolver.resolve(&conflict)? {
+        Resolution::Skip => Some(false),
+        Resolution::Overwrite => Some(true),
+        Resolution::Keep => {
+            info!(
+                "Keeping {:?}, {} isn't deployed",
+                conflict.target, conflict.description
+            );
+            None
+        }
+        Resolution::Adopt => {
+            conflict::adopt(act, &conflict.source, &conflict.target)?;
+            Some(true)
+        }
+    })
+}
+
+/// The conflicts are those that `create_symlink` skips without --force
+fn symlink_conflict(
+    symlink: &SymlinkDescription,
+    cache_directory: &Path,
+) -> Result<Option<Conflict>> {
+    let comparison = symlink
+        .compare()
+        .context("detect symlink's current state")?;
+    let conflicting = matches!(
+        comparison,
+        SymlinkComparison::Changed | SymlinkComparison::TargetNotSymlink
+    ) && !is_broken_symlink(&comparison, &symlink.target.target, cache_directory);
+    Ok(conflicting.then(|| Conflict {
+        description: symlink.to_string(),
+        source: symlink.source.clone(),
+        target: symlink.target.target.clone(),
+        deployed: fs::read_to_string(&symlink.source).ok(),
+        adoptable: true,
+    }))
+}
+
+/// The conflicts are those that `create_template` skips without --force
+fn template_conflict(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<Option<Conflict>> {
+    let comparison = filesystem::compare_template(
+        &template.target.target,
+        &template.cache,
+        &template.target.equivalence,
+    )
+    .context("detect templated file's current state")?;
+    if comparison != TemplateComparison::OnlyTargetExists {
+        return Ok(None);
+    }
+    let rendered = render::render_template(template, handlebars, variables, limits)?;
+    if let Ok(current) = fs::read_to_string(&template.target.target) {
+        if template
+            .target
+            .equivalence
+            .equivalent(&rendered, &current)?
+        {
+            return Ok(None);
+        }
+    }
+    Ok(Some(Conflict {
+        description: template.to_string(),
+        source: template.source.clone(),
+        target: template.target.target.clone(),
+        deployed: Some(rendered),
+        adoptable: false,
+    }))
+}
+
+/// The conflicts are those that `create_copy` skips without --force
+fn copy_conflict(copy: &CopyDescription) -> Result<Option<Conflict>> {
+    let target_hash = match filesystem::hash_file(&copy.target.target).context("hash target")? {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let source_hash = filesystem::hash_file(&copy.source).context("hash source")?;
+    if source_hash.as_ref() == Some(&target_hash) && !is_symlink(&copy.target.target) {
+        return Ok(None);
+    }
+    Ok(Some(Conflict {
+        description: copy.to_string(),
+        source: copy.source.clone(),
+        target: copy.target.target.clone(),
+        deployed: fs::read_to_string(&copy.source).ok(),
+        adoptable: true,
+    }))
+}
+
 // Returns true if the template should be added to cache
 fn create_template(
     act: bool,
     template: &TemplateDescription,
     handlebars: &Handlebars,
     variables: &Variables,
+    limits: &RenderLimits,
     force: bool,
 ) -> Result<bool> {
     info!("{} {}", "[+]".green(), template);
 
-    let comparison = filesystem::compare_template(&template.target.target, &template.cache)
-        .context("detect templated file's current state")?;
+    let comparison = filesystem::compare_template(
+        &template.target.target,
+        &template.cache,
+        &template.target.equivalence,
+    )
+    .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
+    // A target that isn't text, or a template that fails to render, doesn't match, so that
+    // --force can still overwrite the target
+    let matches_render = comparison == TemplateComparison::OnlyTargetExists
+        && target_matches_render(template, handlebars, variables, limits).unwrap_or_else(|e| {
+            debug!("Not comparing {} with its render: {:?}", template, e);
+            false
+        });
 
     match comparison {
         TemplateComparison::OnlyCacheExists
@@ -534,6 +2499,17 @@ fn create_template(
             error!("This is probably a bug. Delete cache.toml and cache/ folder.");
             Ok(false)
         }
+        TemplateComparison::OnlyTargetExists if matches_render => {
+            warn!(
+                "Creating {} but target file already exists and matches the rendered template. Adding to cache anyways",
+                template
+            );
+            if act {
+                perform_template_deployment(template, handlebars, variables, limits)
+                    .context("perform template deployment")?;
+            }
+            Ok(true)
+        }
         TemplateComparison::OnlyTargetExists if !force => {
             error!(
                 "Creating {} but target file already exists. Skipping...",
@@ -547,10 +2523,13 @@ fn create_template(
                     "Creating {} but target file already exists. Forcing.",
                     template
                 );
+                if act {
+                    backup::back_up(&template.target.target).context("back up target")?;
+                }
             }
             debug!("Performing creation");
             if act {
-                perform_template_deployment(template, handlebars, variables)
+                perform_template_deployment(template, handlebars, variables, limits)
                     .context("perform template deployment")?;
             }
             Ok(true)
@@ -558,19 +2537,40 @@ fn create_template(
     }
 }
 
+fn target_matches_render(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<bool> {
+    let rendered = render::render_template(template, handlebars, variables, limits)?;
+    let current =
+        fs::read_to_string(&template.target.target).context("read contents of target file")?;
+    template.target.equivalence.equivalent(&rendered, &current)
+}
+
 // Returns true if the symlink wasn't skipped
-fn update_symlink(act: bool, symlink: &SymlinkDescription, force: bool) -> Result<bool> {
+fn update_symlink(
+    act: bool,
+    symlink: &SymlinkDescription,
+    force: bool,
+    cache_directory: &Path,
+    metadata: &[MetadataChange],
+) -> Result<bool> {
     debug!("Updating {}...", symlink);
-    let comparison = filesystem::compare_symlink(&symlink.source, &symlink.target.target)
+    let comparison = symlink
+        .compare()
         .context("detect symlink's current state")?;
     debug!("Current state: {}", comparison);
+    let broken = is_broken_symlink(&comparison, &symlink.target.target, cache_directory);
 
     match comparison {
+        SymlinkComparison::TargetIsSource => Err(target_is_source(symlink).into()),
         SymlinkComparison::OnlyTargetExists | SymlinkComparison::BothMissing => {
             error!("Updating {} but source is missing. Skipping...", symlink);
             Ok(false)
         }
-        SymlinkComparison::Changed if !force => {
+        SymlinkComparison::Changed if !force && !broken => {
             error!(
                 "Updating {} but target doesn't point at source. Skipping...",
                 symlink
@@ -584,94 +2584,205 @@ fn update_symlink(act: bool, symlink: &SymlinkDescription, force: bool) -> Resul
             );
             Ok(false)
         }
-        SymlinkComparison::Identical => {
+        SymlinkComparison::Identical if metadata.is_empty() => {
             debug!("Not touching symlink.");
             Ok(true)
         }
+        SymlinkComparison::Identical => {
+            info!(
+                "{} {} ({})",
+                "[~]".yellow(),
+                symlink,
+                describe_metadata_changes(metadata)
+            );
+            if act {
+                apply_owner(
+                    &symlink.target.target,
+                    &symlink.target.owner,
+                    &symlink.target.group,
+                )?;
+                apply_symlink_permissions(&symlink.target)?;
+            }
+            Ok(true)
+        }
         s => {
-            if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
+            if broken {
                 warn!(
-                    "Updating {} but target wasn't what was expected. Forcing.",
+                    "Updating {} but target is a circular symlink or points into the cache. Replacing it.",
                     symlink
                 );
-                std::fs::remove_file(&symlink.target.target)
-                    .context("remove symlink target while forcing")?;
-            }
-            if s == SymlinkComparison::OnlySourceExists {
+                if act {
+                    remove_target(&symlink.target.target, symlink.target.elevate)
+                        .context("remove broken symlink")?;
+                }
+            } else if s == SymlinkComparison::Changed || s == SymlinkComparison::TargetNotSymlink {
                 warn!(
-                    "Updating {} but target was missing. Creating it anyways.",
+                    "Updating {} but target wasn't what was expected. Forcing.",
                     symlink
                 );
+                if act {
+                    backup::back_up(&symlink.target.target).context("back up target")?;
+                    remove_target(&symlink.target.target, symlink.target.elevate)
+                        .context("remove symlink target while forcing")?;
+                }
+            }
+            if s == SymlinkComparison::OnlySourceExists {
+                if symlink.target.volatile {
+                    debug!("Target of volatile symlink is missing. Recreating it.");
+                } else {
+                    warn!(
+                        "Updating {} but target was missing. Creating it anyways.",
+                        symlink
+                    );
+                }
             }
             debug!("Creating missing symlink.");
             if act {
-                fs::create_dir_all(
-                    &symlink
-                        .target
-                        .target
-                        .parent()
-                        .context("get parent of target file")?,
-                )
-                .context("create parent for target file")?;
-                filesystem::make_symlink(&symlink.target.target, &symlink.source)
-                    .context("create target symlink")?;
+                perform_symlink_deployment(symlink)?;
             }
             Ok(true)
         }
     }
 }
 
+/// Circular symlinks and symlinks into the cache (left behind by older versions) can't be
+/// anything that the user wants to keep, so they're replaced even without --force
+fn is_broken_symlink(
+    comparison: &SymlinkComparison,
+    target: &Path,
+    cache_directory: &Path,
+) -> bool {
+    match comparison {
+        SymlinkComparison::Circular => true,
+        SymlinkComparison::Changed => filesystem::points_into(target, cache_directory),
+        _ => false,
+    }
+}
+
+fn target_is_source(symlink: &SymlinkDescription) -> Report {
+    Report::new(format!(
+        "target of {} is the source file itself, because one of its parent directories is a symlink into the repository",
+        symlink
+    ))
+    .help("replace the symlinked parent directory with a real directory, or deploy the directory itself as a symlink instead of the files in it")
+}
+
 /// Returns true if the template was not skipped
+#[allow(clippy::too_many_arguments)]
 fn update_template(
     act: bool,
     template: &TemplateDescription,
     handlebars: &Handlebars,
     variables: &Variables,
+    limits: &RenderLimits,
     force: bool,
     diff_context_lines: usize,
+    dependencies_unchanged: bool,
+    metadata: &[MetadataChange],
+    merge_tool: Option<&str>,
 ) -> Result<bool> {
     debug!("Updating {}...", template);
-    let comparison = filesystem::compare_template(&template.target.target, &template.cache)
-        .context("detect templated file's current state")?;
+    let comparison = filesystem::compare_template(
+        &template.target.target,
+        &template.cache,
+        &template.target.equivalence,
+    )
+    .context("detect templated file's current state")?;
     debug!("Current state: {}", comparison);
 
     match comparison {
         TemplateComparison::OnlyTargetExists | TemplateComparison::BothMissing => {
-            error!(
-                "Updating {} but cache is missing. Cache is CORRUPTED.",
+            // The render cache may be somewhere that doesn't survive a reboot, like a tmpfs,
+            // so the target is compared with a new render like a new template's
+            warn!(
+                "Updating {} but its cached render is missing. Comparing the target with a new render.",
                 template
             );
-            error!("This is probably a bug. Delete cache.toml and cache/ folder.");
-            Ok(true)
+            create_template(act, template, handlebars, variables, limits, force)
         }
         TemplateComparison::Changed if !force => {
+            if let Some(tool) = merge_tool {
+                if merge_template(template, handlebars, variables, limits, tool)
+                    .context("merge target with new render")?
+                {
+                    return Ok(true);
+                }
+            }
             error!(
                 "Updating {} but target's contents were changed. Skipping...",
                 template
             );
             Ok(false)
         }
+        TemplateComparison::Identical if dependencies_unchanged && metadata.is_empty() => {
+            debug!("Not touching template, its source and variables didn't change.");
+            Ok(true)
+        }
+        TemplateComparison::Identical if dependencies_unchanged => {
+            info!(
+                "{} {} ({})",
+                "[~]".yellow(),
+                template,
+                describe_metadata_changes(metadata)
+            );
+            if act {
+                apply_template_metadata(template)?;
+            }
+            Ok(true)
+        }
         t => {
             if t == TemplateComparison::Changed {
                 warn!(
                     "Updating {} but target's contents were changed. Forcing.",
                     template
                 );
+                if act {
+                    backup::back_up(&template.target.target).context("back up target")?;
+                }
+            }
+            if t == TemplateComparison::OnlyCacheExists {
+                if template.target.volatile {
+                    debug!("Target of volatile template is missing. Recreating it.");
+                } else {
+                    warn!(
+                        "Updating {} but target was missing. Creating it anyways.",
+                        template
+                    );
+                }
             }
 
-            debug!("Performing update");
-
-            if log_enabled!(log::Level::Info) {
-                let diff = difference::generate_diff(&template, handlebars, &variables)
+            debug!("Performing update");
+
+            // There's nothing to diff against if the target is missing
+            if log_enabled!(log::Level::Info)
+                && t != TemplateComparison::OnlyCacheExists
+                && !events::enabled()
+            {
+                let diff = difference::generate_diff(template, handlebars, variables, limits)
                     .context("generate diff for template")?;
-                if difference::diff_nonempty(&diff) {
+                let content_changed = difference::diff_nonempty(&diff);
+                if !metadata.is_empty() {
+                    info!(
+                        "{} {} ({})",
+                        "[~]".yellow(),
+                        template,
+                        describe_metadata_changes(metadata)
+                    );
+                } else if content_changed {
                     info!("{} {}", "[~]".yellow(), template);
+                }
+                let changed_lines = difference::changed_lines(&diff);
+                if content_changed && difference::hides_diff(template, &diff) {
+                    info!("The diff isn't shown, since it contains secrets");
+                } else if content_changed && !template.target.diff.shows(changed_lines) {
+                    info!("Changed {} lines (diff suppressed)", changed_lines);
+                } else if content_changed {
                     difference::print_diff(diff, diff_context_lines);
                 }
             }
 
             if act {
-                perform_template_deployment(template, handlebars, variables)
+                perform_template_deployment(template, handlebars, variables, limits)
                     .context("perform template deployment")?;
             }
             Ok(true)
@@ -679,47 +2790,1202 @@ fn update_template(
     }
 }
 
+fn perform_symlink_deployment(symlink: &SymlinkDescription) -> Result<()> {
+    elevate::or_elevated(
+        symlink.target.elevate,
+        &symlink.target.target,
+        || {
+            create_target_parents(
+                &symlink.target.target,
+                &symlink.target.directory_owner,
+                symlink.target.directory_mode,
+            )?;
+            match symlink.target.link {
+                config::LinkKind::Symlink => {
+                    capabilities::ensure_symlinks(&symlink.target.target)?;
+                    filesystem::make_symlink(&symlink.target.target, &symlink.source)
+                        .context("create target symlink")?;
+                }
+                config::LinkKind::Junction => {
+                    if !symlink.source.is_dir() {
+                        bail!("a junction can only link to a directory");
+                    }
+                    filesystem::make_junction(&symlink.target.target, &symlink.source)
+                        .context("create target junction")?;
+                }
+                config::LinkKind::Hardlink => {
+                    capabilities::ensure_hardlinks(&symlink.target.target)?;
+                    filesystem::make_hardlink(&symlink.target.target, &symlink.source)
+                        .context("create target hardlink")?;
+                }
+            }
+            apply_owner(
+                &symlink.target.target,
+                &symlink.target.owner,
+                &symlink.target.group,
+            )?;
+            apply_symlink_permissions(&symlink.target)
+        },
+        || {
+            elevate::symlink(
+                &symlink.target.target,
+                &symlink.source,
+                &elevate::Metadata {
+                    mode: None,
+                    owner: &symlink.target.owner,
+                    group: &symlink.target.group,
+                },
+                &symlink_directories(symlink),
+            )?;
+            if filesystem::SYMLINK_PERMISSIONS {
+                elevate::symlink_permissions(
+                    &symlink.target.target,
+                    symlink.target.mode,
+                    &symlink.target.flags,
+                )?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Creates the missing parents of a target. Parents that have to belong to someone else are
+/// created with sudo, since only root can give them away.
+fn create_target_parents(target: &Path, owner: &Option<UnixUser>, mode: Option<u32>) -> Result<()> {
+    let parent = target.parent().context("get parent of target file")?;
+    elevate::or_elevated(
+        owner.is_some(),
+        target,
+        || {
+            filesystem::create_dir_all_with(parent, owner, mode)
+                .context("create parent for target file")
+        },
+        || elevate::create_directories(parent, &elevate::Directories { owner, mode }),
+    )
+}
+
+fn symlink_directories(symlink: &SymlinkDescription) -> elevate::Directories<'_> {
+    elevate::Directories {
+        owner: &symlink.target.directory_owner,
+        mode: symlink.target.directory_mode,
+    }
+}
+
+/// What deploying the template writes to its target, without touching the filesystem
+pub fn render_to_string(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<String> {
+    render::render_template(template, handlebars, variables, limits)
+}
+
+/// Offers to merge the edits of the target with the new render when the template changed since
+/// the last deploy too, instead of losing one of them. The new render becomes the cached one,
+/// so the merged target still counts as edited. Returns whether the merge was written.
+fn merge_template(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+    tool: &str,
+) -> Result<bool> {
+    let rendered = render_to_string(template, handlebars, variables, limits)?;
+    if secrets::contains_secret(&rendered) {
+        debug!(
+            "Not merging {}, only a hash of its last render is cached",
+            template
+        );
+        return Ok(false);
+    }
+    let base = fs::read_to_string(&template.cache).context("read cached render")?;
+    if base == rendered {
+        // Only the target changed, there's nothing to merge
+        return Ok(false);
+    }
+    let edited = fs::read_to_string(&template.target.target).context("read target")?;
+    if !filesystem::ask_boolean(&format!(
+        "{} was edited, and its template changed since the last deploy. Merge them? [y/N]",
+        template
+    )) {
+        return Ok(false);
+    }
+
+    let merged = match merge::merge(tool, &template.target.target, &base, &edited, &rendered)? {
+        Some(merged) => merged,
+        None => return Ok(false),
+    };
+    backup::back_up(&template.target.target).context("back up target")?;
+    write_template(template, &rendered, &merged)?;
+    info!("{} {} (merged)", "[~]".yellow(), template);
+    Ok(true)
+}
+
 fn perform_template_deployment(
     template: &TemplateDescription,
     handlebars: &Handlebars,
     variables: &Variables,
+    limits: &RenderLimits,
 ) -> Result<()> {
-    let file_contents =
-        fs::read_to_string(&template.source).context("read template source file")?;
-    let file_contents = template.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    let rendered = render_to_string(template, handlebars, variables, limits)?;
+    write_template(template, &rendered, &rendered)
+}
+
+/// Caches `rendered` and writes `contents` to the target, which differ only after a merge.
+/// Neither is written if the template's `validate` command rejects `contents`.
+fn write_template(template: &TemplateDescription, rendered: &str, contents: &str) -> Result<()> {
+    if let Some(command) = &template.target.validate {
+        validate::validate(command, &template.target.target, contents)?;
+    }
     fs::create_dir_all(
-        &template
+        template
             .cache
             .parent()
             .context("get parent of cache file")?,
     )
     .context("create parent for cache file")?;
-    fs::write(&template.cache, rendered).context("write rendered template to cache")?;
-    fs::create_dir_all(
-        &template
-            .target
-            .target
-            .parent()
-            .context("get parent of target file")?,
+    fs::write(&template.cache, secrets::cached_render(rendered))
+        .context("write rendered template to cache")?;
+    elevate::or_elevated(
+        template.target.elevate,
+        &template.target.target,
+        || {
+            create_target_parents(
+                &template.target.target,
+                &template.target.directory_owner,
+                template.target.directory_mode,
+            )?;
+            let target = filesystem::long_path(&template.target.target);
+            clear_attributes(&target, &template.target.attributes)?;
+            if template.target.encrypted {
+                filesystem::write_private(&target, contents.as_bytes())
+            } else {
+                fs::write(&target, contents)
+            }
+            .context("write rendered template to target")?;
+            apply_template_metadata(template)
+        },
+        || {
+            elevate::write(
+                &template.target.target,
+                contents.as_bytes(),
+                &template_metadata(template)?,
+                &elevate::Directories {
+                    owner: &template.target.directory_owner,
+                    mode: template.target.directory_mode,
+                },
+            )
+        },
     )
-    .context("create parent for target file")?;
-    fs::copy(&template.cache, &template.target.target)
-        .context("copy template from cache to target")?;
-    filesystem::copy_permissions(&template.source, &template.target.target)
-        .context("copy permissions from source to target")?;
+}
+
+fn apply_template_metadata(template: &TemplateDescription) -> Result<()> {
+    elevate::or_elevated(
+        template.target.elevate,
+        &template.target.target,
+        || {
+            let target = filesystem::long_path(&template.target.target);
+            match template.target.mode {
+                Some(mode) => {
+                    filesystem::set_mode(&target, mode).context("set permissions of target")?
+                }
+                None => filesystem::copy_permissions(&template.source, &target)
+                    .context("copy permissions from source to target")?,
+            }
+            apply_owner(
+                &template.target.target,
+                &template.target.owner,
+                &template.target.group,
+            )?;
+            apply_windows_metadata(&target, &template.target.attributes, &template.target.acl)
+        },
+        || elevate::set_metadata(&template.target.target, &template_metadata(template)?),
+    )
+}
+
+/// Metadata of an elevated template, whose mode is copied from the source by default
+fn template_metadata(template: &TemplateDescription) -> Result<elevate::Metadata<'_>> {
+    Ok(elevate::Metadata {
+        mode: match template.target.mode {
+            Some(mode) => Some(mode),
+            None => filesystem::file_mode(&template.source).context("get mode of source")?,
+        },
+        owner: &template.target.owner,
+        group: &template.target.group,
+    })
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Takes over the existing target of a write-once template. The hash of its contents becomes
+/// the cached render, so that the target counts as unchanged if the template is removed later.
+/// Only the hash is kept since such files often hold generated secrets.
+fn keep_write_once_template(act: bool, template: &TemplateDescription) -> Result<()> {
+    if act {
+        fs::create_dir_all(
+            template
+                .cache
+                .parent()
+                .context("get parent of cache file")?,
+        )
+        .context("create parent for cache file")?;
+        let contents = fs::read(&template.target.target).context("read target")?;
+        fs::write(&template.cache, secrets::hash_marker(&contents))
+            .context("write hash of target to the cache")?;
+    }
     Ok(())
 }
 
+/// Whether the target's contents differ from what it was last deployed with.
+/// Copies recorded before hashes were kept are assumed to be unchanged.
+pub fn copy_changed(
+    recorded_hash: Option<&String>,
+    target: &Path,
+    target_hash: &str,
+) -> Result<bool> {
+    match recorded_hash {
+        Some(recorded) => Ok(!filesystem::same_hash(recorded, target_hash, || {
+            fs::read(target).context("read target")
+        })?),
+        None => Ok(false),
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Returns true if the copy should be deleted from cache
+fn delete_copy(
+    act: bool,
+    copy: &CopyDescription,
+    recorded_hash: Option<&String>,
+    force: bool,
+    interactive: bool,
+) -> Result<bool> {
+    info!("{} {}", "[-]".red(), copy);
+
+    let target_hash = match filesystem::hash_file(&copy.target.target).context("hash target")? {
+        Some(hash) => hash,
+        None => {
+            warn!(
+                "Deleting {} but target doesn't exist. Removing from cache anyways.",
+                copy
+            );
+            return Ok(true);
+        }
+    };
+    if copy_changed(recorded_hash, &copy.target.target, &target_hash)? {
+        if !force {
+            error!(
+                "Deleting {} but target contents were changed. Skipping...",
+                copy
+            );
+            return Ok(false);
+        }
+        warn!(
+            "Deleting {} but target contents were changed. Forcing.",
+            copy
+        );
+        if act {
+            backup::back_up(&copy.target.target).context("back up target")?;
+        }
+    }
+
+    debug!("Performing deletion");
+    if act {
+        remove_target(&copy.target.target, copy.target.elevate).context("delete target file")?;
+        delete_target_parents(&copy.target.target, copy.target.elevate, interactive)
+            .context("delete parent directory in target location")?;
+    }
+    Ok(true)
+}
+
+/// Returns true if the copy should be added to cache
+fn create_copy(act: bool, copy: &CopyDescription, force: bool) -> Result<bool> {
+    info!("{} {}", "[+]".green(), copy);
+
+    let source_hash = filesystem::hash_file(&copy.source)
+        .context("hash source")?
+        .context("source is missing")?;
+    match filesystem::hash_file(&copy.target.target).context("hash target")? {
+        None => {}
+        Some(target_hash) if target_hash == source_hash && !is_symlink(&copy.target.target) => {
+            warn!(
+                "Creating {} but target file already exists and has the same contents. Adding to cache anyways",
+                copy
+            );
+            if act {
+                apply_copy_metadata(copy)?;
+            }
+            return Ok(true);
+        }
+        Some(_) if !force => {
+            error!(
+                "Creating {} but target file already exists. Skipping...",
+                copy
+            );
+            return Ok(false);
+        }
+        Some(_) => {
+            warn!("Creating {} but target file already exists. Forcing.", copy);
+            if act {
+                backup::back_up(&copy.target.target).context("back up target")?;
+            }
+        }
+    }
+
+    debug!("Performing creation");
+    if act {
+        perform_copy_deployment(copy).context("perform copy deployment")?;
+    }
+    Ok(true)
+}
+
+/// Returns true if the copy wasn't skipped
+fn update_copy(
+    act: bool,
+    copy: &CopyDescription,
+    recorded_hash: Option<&String>,
+    force: bool,
+    metadata: &[MetadataChange],
+) -> Result<bool> {
+    debug!("Updating {}...", copy);
+
+    let source_hash = filesystem::hash_file(&copy.source)
+        .context("hash source")?
+        .context("source is missing")?;
+    match filesystem::hash_file(&copy.target.target).context("hash target")? {
+        None => {
+            if copy.target.volatile {
+                debug!("Target of volatile copy is missing. Recreating it.");
+            } else {
+                warn!(
+                    "Updating {} but target was missing. Creating it anyways.",
+                    copy
+                );
+            }
+        }
+        Some(target_hash)
+            if copy_changed(recorded_hash, &copy.target.target, &target_hash)? && !force =>
+        {
+            error!(
+                "Updating {} but target's contents were changed. Skipping...",
+                copy
+            );
+            return Ok(false);
+        }
+        Some(target_hash) if target_hash == source_hash && metadata.is_empty() => {
+            debug!("Not touching copy, its source didn't change.");
+            return Ok(true);
+        }
+        Some(target_hash) if target_hash == source_hash => {
+            info!(
+                "{} {} ({})",
+                "[~]".yellow(),
+                copy,
+                describe_metadata_changes(metadata)
+            );
+            if act {
+                apply_copy_metadata(copy)?;
+            }
+            return Ok(true);
+        }
+        Some(target_hash) => {
+            if copy_changed(recorded_hash, &copy.target.target, &target_hash)? {
+                warn!(
+                    "Updating {} but target's contents were changed. Forcing.",
+                    copy
+                );
+                if act {
+                    backup::back_up(&copy.target.target).context("back up target")?;
+                }
+            }
+            info!("{} {}", "[~]".yellow(), copy);
+        }
+    }
+
+    debug!("Performing update");
+    if act {
+        perform_copy_deployment(copy).context("perform copy deployment")?;
+    }
+    Ok(true)
+}
+
+fn perform_copy_deployment(copy: &CopyDescription) -> Result<()> {
+    elevate::or_elevated(
+        copy.target.elevate,
+        &copy.target.target,
+        || {
+            create_target_parents(
+                &copy.target.target,
+                &copy.target.directory_owner,
+                copy.target.directory_mode,
+            )?;
+            let target = filesystem::long_path(&copy.target.target);
+            // Copying onto a symlink would overwrite whatever it points at, which may be the source
+            if is_symlink(&target) {
+                fs::remove_file(&target).context("remove symlink at target")?;
+            }
+            clear_attributes(&target, &copy.target.attributes)?;
+            fs::copy(&copy.source, &target).context("copy source to target")?;
+            apply_copy_metadata(copy)
+        },
+        || {
+            elevate::copy(
+                &copy.source,
+                &copy.target.target,
+                &copy_metadata(copy)?,
+                &elevate::Directories {
+                    owner: &copy.target.directory_owner,
+                    mode: copy.target.directory_mode,
+                },
+            )
+        },
+    )
+}
+
+fn apply_copy_metadata(copy: &CopyDescription) -> Result<()> {
+    elevate::or_elevated(
+        copy.target.elevate,
+        &copy.target.target,
+        || {
+            let target = filesystem::long_path(&copy.target.target);
+            match copy.target.mode {
+                Some(mode) => {
+                    filesystem::set_mode(&target, mode).context("set permissions of target")?
+                }
+                None => filesystem::copy_permissions(&copy.source, &target)
+                    .context("copy permissions from source to target")?,
+            }
+            apply_owner(&copy.target.target, &copy.target.owner, &copy.target.group)?;
+            apply_windows_metadata(&target, &copy.target.attributes, &copy.target.acl)
+        },
+        || elevate::set_metadata(&copy.target.target, &copy_metadata(copy)?),
+    )
+}
+
+/// Metadata of an elevated copy, whose mode is copied from the source by default
+fn copy_metadata(copy: &CopyDescription) -> Result<elevate::Metadata<'_>> {
+    Ok(elevate::Metadata {
+        mode: match copy.target.mode {
+            Some(mode) => Some(mode),
+            None => filesystem::file_mode(&copy.source).context("get mode of source")?,
+        },
+        owner: &copy.target.owner,
+        group: &copy.target.group,
+    })
+}
+
+/// Directories that only an elevated target needed are left behind, rather than asking for the
+/// password again just to remove them
+fn delete_target_parents(target: &Path, elevate: bool, interactive: bool) -> Result<()> {
+    elevate::or_elevated(
+        elevate,
+        target,
+        || filesystem::delete_parents(target, interactive),
+        || {
+            info!("Leaving the empty parents of {:?}", target);
+            Ok(())
+        },
+    )
+}
+
+/// The elevated targets that are still in the cache after deploying
+/// The `on_deploy` and `on_undeploy` hooks of every desired file, from its target and from
+/// its package
+pub fn hooks_by_source(
+    state: &FileState,
+    config: &config::Configuration,
+) -> (hooks::HooksBySource, hooks::HooksBySource) {
+    let targets = state
+        .desired_symlinks
+        .iter()
+        .map(|s| (&s.source, &s.target.on_deploy, &s.target.on_undeploy))
+        .chain(
+            state
+                .desired_templates
+                .iter()
+                .map(|t| (&t.source, &t.target.on_deploy, &t.target.on_undeploy)),
+        )
+        .chain(
+            state
+                .desired_copies
+                .iter()
+                .map(|c| (&c.source, &c.target.on_deploy, &c.target.on_undeploy)),
+        );
+
+    let mut on_deploy = hooks::HooksBySource::new();
+    let mut on_undeploy = hooks::HooksBySource::new();
+    for (source, file_on_deploy, file_on_undeploy) in targets {
+        let package = config
+            .file_packages
+            .get(source)
+            .and_then(|package| config.package_hooks.get(package));
+        let package_on_deploy = package.and_then(|p| p.on_deploy.as_ref());
+        let package_on_undeploy = package.and_then(|p| p.on_undeploy.as_ref());
+        let deploy = file_on_deploy
+            .iter()
+            .chain(package_on_deploy)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let undeploy = file_on_undeploy
+            .iter()
+            .chain(package_on_undeploy)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if !deploy.is_empty() {
+            on_deploy.insert(source.clone(), deploy);
+        }
+        if !undeploy.is_empty() {
+            on_undeploy.insert(source.clone(), undeploy);
+        }
+    }
+    (on_deploy, on_undeploy)
+}
+
+/// Where the hooks of the desired files and of their packages run, from the `cwd`,
+/// `path_prepend` and `requires_network` of the packages. A hook that two packages run in different environments
+/// runs in the first one's.
+fn hook_environments(
+    on_deploy: &hooks::HooksBySource,
+    on_undeploy: &hooks::HooksBySource,
+    config: &config::Configuration,
+) -> HookEnvironments {
+    let mut environments = HookEnvironments::new();
+    for (source, hooks) in on_deploy.iter().chain(on_undeploy) {
+        let (package, package_hooks) = match config
+            .file_packages
+            .get(source)
+            .and_then(|package| Some((package, config.package_hooks.get(package)?)))
+        {
+            Some(package) => package,
+            None => continue,
+        };
+        let environment = HookEnvironment {
+            cwd: package_hooks.cwd.clone(),
+            path_prepend: package_hooks.path_prepend.clone(),
+            requires_network: package_hooks.requires_network,
+            env: package_hooks.env.clone(),
+        };
+        if environment == HookEnvironment::default() {
+            continue;
+        }
+        for hook in hooks {
+            match environments.get(hook) {
+                Some(existing) if *existing != environment => warn!(
+                    "Hook {:?} runs in the environment of another package than {:?}",
+                    hook, package
+                ),
+                Some(_) => {}
+                None => {
+                    environments.insert(hook.clone(), environment.clone());
+                }
+            }
+        }
+    }
+    environments
+}
+
+fn still_deployed(
+    elevated: BTreeSet<PathBuf>,
+    symlinks: &BTreeMap<PathBuf, PathBuf>,
+    templates: &BTreeMap<PathBuf, PathBuf>,
+    copies: &BTreeMap<PathBuf, PathBuf>,
+) -> BTreeSet<PathBuf> {
+    let deployed = symlinks
+        .values()
+        .chain(templates.values())
+        .chain(copies.values())
+        .collect::<BTreeSet<_>>();
+    elevated
+        .into_iter()
+        .filter(|target| deployed.contains(target))
+        .collect()
+}
+
+/// The junctions and hardlinks that are still in the cache after deploying
+fn still_linked(
+    links: BTreeMap<PathBuf, config::LinkKind>,
+    symlinks: &BTreeMap<PathBuf, PathBuf>,
+) -> BTreeMap<PathBuf, config::LinkKind> {
+    let deployed = symlinks.values().collect::<BTreeSet<_>>();
+    links
+        .into_iter()
+        .filter(|(target, _)| deployed.contains(target))
+        .collect()
+}
+
+/// The environments of the `on_undeploy` hooks that deployed files still have
+fn still_hooked(
+    environments: HookEnvironments,
+    undeploy_hooks: &hooks::HooksBySource,
+) -> HookEnvironments {
+    environments
+        .into_iter()
+        .filter(|(hook, _)| undeploy_hooks.values().any(|hooks| hooks.contains(hook)))
+        .collect()
+}
+
+fn still_rendered(
+    render_caches: BTreeMap<PathBuf, PathBuf>,
+    templates: &BTreeMap<PathBuf, PathBuf>,
+) -> BTreeMap<PathBuf, PathBuf> {
+    render_caches
+        .into_iter()
+        .filter(|(source, _)| templates.contains_key(source))
+        .collect()
+}
+
+/// Moves the rendered copies of deployed templates whose `render_cache` changed to where it
+/// points now. A dry run compares against them where they are instead.
+fn relocate_renders(state: &mut FileState, act: bool) -> Result<()> {
+    let mut relocated = BTreeSet::new();
+    for template in &state.desired_templates {
+        let existing = match state.existing_templates.get(template) {
+            Some(existing) if existing.cache != template.cache && existing.cache.exists() => {
+                existing
+            }
+            _ => continue,
+        };
+        let old = &existing.cache;
+        debug!(
+            "Moving rendered copy of {} from {:?} to {:?}",
+            template, old, template.cache
+        );
+        if act {
+            fs::create_dir_all(
+                template
+                    .cache
+                    .parent()
+                    .context("get parent of cache file")?,
+            )
+            .context("create parent for cache file")?;
+            // The directories may be on different filesystems
+            if fs::rename(old, &template.cache).is_err() {
+                fs::copy(old, &template.cache).context("copy rendered copy")?;
+                fs::remove_file(old).context("delete old rendered copy")?;
+            }
+            delete_render_parents(existing).context("delete empty parents of old rendered copy")?;
+        } else {
+            relocated.insert((template.clone(), old.clone()));
+        }
+    }
+    for (template, old) in relocated {
+        let mut template = state
+            .desired_templates
+            .take(&template)
+            .expect("relocated templates are desired");
+        template.cache = old;
+        state.desired_templates.insert(template);
+    }
+    Ok(())
+}
+
+/// Deletes the empty parents of a template's cached render, but never its `render_cache`, which
+/// may be a mount point
+fn delete_render_parents(template: &TemplateDescription) -> Result<()> {
+    match &template.target.render_cache {
+        Some(root) => filesystem::delete_parents_within(&template.cache, root),
+        None => filesystem::delete_parents(&template.cache, false),
+    }
+}
+
+/// Removes a deployed target, with sudo if it's elevated and the user isn't allowed to
+fn remove_target(target: &Path, elevate: bool) -> Result<()> {
+    elevate::or_elevated(
+        elevate,
+        target,
+        || {
+            let target = filesystem::long_path(target);
+            filesystem::clear_symlink_flags(&target).context("clear file flags of symlink")?;
+            // Junctions and directory symlinks are removed like the directories they point at
+            if cfg!(windows) && filesystem::is_link(&target) && target.is_dir() {
+                fs::remove_dir(&target)?;
+            } else if let Err(e) = fs::remove_file(&target) {
+                // Targets that were deployed read-only can only be deleted after that's cleared
+                if !cfg!(windows) || e.kind() != std::io::ErrorKind::PermissionDenied {
+                    return Err(e.into());
+                }
+                filesystem::clear_attributes(&target).context("clear attributes of target")?;
+                fs::remove_file(&target)?;
+            }
+            Ok(())
+        },
+        || elevate::remove(target),
+    )
+}
+
+/// Clears the attributes of an existing target before it's written, since a read-only target
+/// can't be written and a hidden one can't be replaced
+fn clear_attributes(target: &Path, attributes: &[FileAttribute]) -> Result<()> {
+    if !attributes.is_empty() && exists(target) {
+        filesystem::clear_attributes(target).context("clear attributes of target")?;
+    }
+    Ok(())
+}
+
+/// Sets the Windows attributes and ACL entries of a target, after its contents and
+/// permissions, since setting those could undo them
+fn apply_windows_metadata(
+    target: &Path,
+    attributes: &[FileAttribute],
+    acl: &[String],
+) -> Result<()> {
+    if !acl.is_empty() {
+        filesystem::grant_acl(target, acl).context("grant ACL entries on target")?;
+    }
+    if !attributes.is_empty() {
+        filesystem::set_attributes(target, attributes).context("set attributes of target")?;
+    }
+    Ok(())
+}
+
+fn apply_owner(target: &Path, owner: &Option<UnixUser>, group: &Option<UnixUser>) -> Result<()> {
+    if let Some(owner) = owner {
+        filesystem::set_owner(&filesystem::long_path(target), owner)
+            .context("set owner of target")?;
+    }
+    // Groups that the user is a member of don't need root, other ones are set with sudo
+    if let Some(name) = group {
+        elevate::or_elevated(
+            true,
+            target,
+            || {
+                filesystem::set_group(&filesystem::long_path(target), name)
+                    .context("set group of target")
+            },
+            || {
+                elevate::set_metadata(
+                    target,
+                    &elevate::Metadata {
+                        mode: None,
+                        owner: &None,
+                        group,
+                    },
+                )
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Sets the mode and flags of the symlink itself where symlinks have them, the other platforms
+/// were warned about when the configuration was loaded
+fn apply_symlink_permissions(target: &config::SymbolicTarget) -> Result<()> {
+    if !filesystem::SYMLINK_PERMISSIONS {
+        return Ok(());
+    }
+    // Flags like `uchg` would keep the mode from changing, so they're set again from scratch
+    if !target.flags.is_empty() {
+        filesystem::clear_symlink_flags(&target.target).context("clear file flags of symlink")?;
+    }
+    if let Some(mode) = target.mode {
+        filesystem::set_symlink_mode(&target.target, mode).context("set permissions of symlink")?;
+    }
+    if !target.flags.is_empty() {
+        filesystem::set_symlink_flags(&target.target, &target.flags)
+            .context("set file flags of symlink")?;
+    }
+    Ok(())
+}
+
+/// Symlinks that ask for a mode or flags on a platform where symlinks don't have any, which
+/// would otherwise be ignored silently
+fn warn_symlink_permissions(config: &config::Configuration) {
+    if filesystem::SYMLINK_PERMISSIONS {
+        return;
+    }
+    for (source, target) in &config.files {
+        if let config::FileTarget::Symbolic(target) = target {
+            if target.mode.is_some() || !target.flags.is_empty() {
+                warnings::warn(
+                    "ignored-symlink-mode",
+                    source.display(),
+                    format!(
+                        "Ignoring the `mode` and `flags` of the symlink {:?} -> {:?}, since symlinks have no permissions of their own on {}",
+                        target.target,
+                        source,
+                        std::env::consts::OS
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Warns about the deployed templates that were rendered with a built-in helper or fact whose
+/// output changed since. Tracked templates are only rendered again when something else about
+/// them changes, the others are on this deploy.
+fn warn_outdated_helpers(cache: &config::Cache) {
+    for (source, dependencies) in &cache.template_dependencies {
+        let outdated = dependencies::outdated_helpers(dependencies);
+        if !outdated.is_empty() {
+            warnings::warn(
+                "outdated-helper",
+                source.display(),
+                format!(
+                    "{:?} was rendered by an older dotter, whose {} rendered something else. {}",
+                    source,
+                    outdated.join(", "),
+                    if dependencies.is_tracked() {
+                        "Deploy with --re-render-all to render it with this one."
+                    } else {
+                        "It's rendered with this one on every deploy, check its target."
+                    }
+                ),
+            );
+        }
+    }
+}
+
+/// What reads variables besides the deployed templates
+struct UsedElsewhere<'a> {
+    /// Hooks, which are rendered like templates
+    hooks: &'a BTreeSet<&'a PathBuf>,
+    /// Helpers of global.toml, of which Rhai scripts see every variable
+    helpers: &'a config::Helpers,
+    handlebars: &'a Handlebars<'a>,
+}
+
+/// Warns about the variables that neither a deployed template nor a hook reads. It's only
+/// known when the variables of every one of them are, and no Rhai helper could read them.
+fn warn_unused_variables(
+    variables: &Variables,
+    templates: &BTreeMap<PathBuf, PathBuf>,
+    dependencies: &BTreeMap<PathBuf, config::TemplateDependencies>,
+    elsewhere: UsedElsewhere,
+) {
+    if templates.is_empty()
+        || templates
+            .keys()
+            .any(|s| dependencies.get(s).is_none_or(|d| !d.is_tracked()))
+        || elsewhere
+            .helpers
+            .values()
+            .any(|helper| matches!(helper, config::HelperFile::Script(_)))
+    {
+        return;
+    }
+    let mut read = templates
+        .keys()
+        .flat_map(|source| dependencies[source].variables.iter().cloned())
+        .collect::<BTreeSet<_>>();
+    for hook in elsewhere.hooks {
+        let contents = match fs::read_to_string(hook) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(_) => return,
+        };
+        match dependencies::variables_read(&contents, elsewhere.handlebars) {
+            Some(variables) => read.extend(variables),
+            None => return,
+        }
+    }
+    for name in variables.keys().filter(|name| !read.contains(*name)) {
+        warnings::warn(
+            "unused-variable",
+            name,
+            format!("No deployed template or hook reads the variable {:?}", name),
+        );
+    }
+}
+
 fn is_template(source: &Path) -> Result<bool> {
+    match detect_template(source)? {
+        Detection::Braces { .. } => Ok(true),
+        Detection::NoBraces => Ok(false),
+        Detection::NotUtf8 => {
+            warnings::warn(
+                "non-utf8-source",
+                source.display(),
+                format!("File {:?} is not valid UTF-8 - detecting as symlink. Explicitly specify it to silence this message.", source),
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// What automatic detection finds in a file that doesn't specify its type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Detection {
+    /// The file contains `{{`, first on `line` (counting from 1), which reads `text`
+    Braces {
+        line: usize,
+        text: String,
+    },
+    NoBraces,
+    NotUtf8,
+}
+
+pub fn detect_template(source: &Path) -> Result<Detection> {
     let mut file = File::open(source).context("open file")?;
     let mut buf = String::new();
     if file.read_to_string(&mut buf).is_err() {
-        warn!("File {:?} is not valid UTF-8 - detecting as symlink. Explicitly specify it to silence this message.", source);
-        Ok(false)
-    } else {
-        Ok(buf.contains("{{"))
+        return Ok(Detection::NotUtf8);
+    }
+    Ok(buf
+        .lines()
+        .enumerate()
+        .find(|(_, text)| text.contains("{{"))
+        .map_or(Detection::NoBraces, |(index, text)| Detection::Braces {
+            line: index + 1,
+            text: text.trim().into(),
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Held by the tests that back up targets, since the backups of a run are global
+    static BACKUPS: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_update_template_without_cached_render() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let target = directory.path().join("target");
+        fs::write(&source, "hello {{name}}").unwrap();
+        let render_cache = directory.path().join("render_cache");
+        fs::create_dir(&render_cache).unwrap();
+        let mut template_target = config::TemplateTarget::from(&target);
+        template_target.render_cache = Some(render_cache.clone());
+        let template = TemplateDescription {
+            source,
+            target: template_target,
+            cache: render_cache.join("dotfiles/source"),
+            variables: Variables::new(),
+        };
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "world".into());
+        let handlebars = Handlebars::new();
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: Duration::from_secs(30),
+        };
+        let update = |force| {
+            update_template(
+                true,
+                &template,
+                &handlebars,
+                &variables,
+                &limits,
+                force,
+                0,
+                false,
+                &[],
+                None,
+            )
+            .unwrap()
+        };
+        let _backups = BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+        backup::start(directory.path());
+
+        // A target that matches a new render is kept, and the render is cached again
+        fs::write(&target, "hello world").unwrap();
+        assert!(update(false));
+        assert_eq!(fs::read_to_string(&template.cache).unwrap(), "hello world");
+
+        // One that doesn't is only overwritten with --force
+        fs::remove_file(&template.cache).unwrap();
+        fs::write(&target, "edited").unwrap();
+        assert!(!update(false));
+        assert!(update(true));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "hello world");
+        assert_eq!(backup::finish().len(), 1);
+
+        // Forgetting the render leaves the render cache, which may be a mount point
+        forget_template_render(true, &template).unwrap();
+        assert!(!render_cache.join("dotfiles").exists());
+        assert!(render_cache.exists());
+    }
+
+    #[test]
+    fn test_create_template_over_binary_target() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let target = directory.path().join("target");
+        fs::write(&source, "text").unwrap();
+        fs::write(&target, [0xff, 0xfe]).unwrap();
+        let template = TemplateDescription {
+            source,
+            target: config::TemplateTarget::from(&target),
+            cache: directory.path().join("cache/source"),
+            variables: Variables::new(),
+        };
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: Duration::from_secs(30),
+        };
+        let create = |force| {
+            create_template(
+                true,
+                &template,
+                &Handlebars::new(),
+                &Variables::new(),
+                &limits,
+                force,
+            )
+            .unwrap()
+        };
+
+        // A target that isn't text is in the way, until --force overwrites it
+        assert!(!create(false));
+        let _backups = BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+        backup::start(directory.path());
+        assert!(create(true));
+        backup::finish();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "text");
+    }
+
+    #[test]
+    fn test_allow_overwrite() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let target = directory.path().join("target");
+        fs::write(&source, "text").unwrap();
+        fs::write(&target, "there before dotter").unwrap();
+        let template = TemplateDescription {
+            source: source.clone(),
+            target: config::TemplateTarget::from(&target),
+            cache: directory.path().join("cache/source"),
+            variables: Variables::new(),
+        };
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: Duration::from_secs(30),
+        };
+        let force = Force::new(&Options::default())
+            .unwrap()
+            .with_sources([source.clone()].into());
+        let file_packages = FilePackages::new();
+
+        // A target that dotter didn't deploy is left alone
+        let create = force.applies(&source, &target, &file_packages);
+        assert!(!create);
+        assert!(!create_template(
+            true,
+            &template,
+            &Handlebars::new(),
+            &Variables::new(),
+            &limits,
+            create,
+        )
+        .unwrap());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "there before dotter");
+
+        // One that it deployed and that was changed since is overwritten
+        fs::create_dir(directory.path().join("cache")).unwrap();
+        fs::write(&template.cache, "text").unwrap();
+        fs::write(&target, "edited").unwrap();
+        let update = force.applies_deployed(&source, &target, &file_packages);
+        assert!(update);
+        let _backups = BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+        backup::start(directory.path());
+        assert!(update_template(
+            true,
+            &template,
+            &Handlebars::new(),
+            &Variables::new(),
+            &limits,
+            update,
+            0,
+            false,
+            &[],
+            None,
+        )
+        .unwrap());
+        backup::finish();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "text");
+
+        // Files without `allow_overwrite` still need --force
+        let other = directory.path().join("other");
+        assert!(!force.applies_deployed(&other, &target, &file_packages));
+    }
+
+    #[test]
+    fn test_warm_invalidate() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("vimrc");
+        let global = directory.path().join("global.toml");
+        let local = directory.path().join("local.toml");
+        fs::write(&source, "set number").unwrap();
+        fs::write(
+            &global,
+            format!("[vim.files]\n{:?} = \"~/.vimrc\"\n", source),
+        )
+        .unwrap();
+        fs::write(&local, "packages = [\"vim\"]\n").unwrap();
+        let warm = || Warm {
+            loaded: Some(Loaded {
+                config: config::load_configuration(
+                    &local,
+                    &global,
+                    directory.path(),
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap(),
+                handlebars: Handlebars::new(),
+                variables: Variables::new(),
+            }),
+            changed_sources: None,
+        };
+
+        // Only a source changed, so only its template needs rendering again
+        let mut changed = warm();
+        changed.invalidate(&[&source]);
+        assert!(changed.loaded.is_some());
+        assert_eq!(changed.changed_sources, Some([source.clone()].into()));
+
+        // The configuration may change anything
+        let mut changed = warm();
+        changed.invalidate(&[&source, &global]);
+        assert!(changed.loaded.is_none());
+        assert!(changed.changed_sources.is_none());
+
+        // A deleted source changes which files are deployed
+        let mut changed = warm();
+        fs::remove_file(&source).unwrap();
+        changed.invalidate(&[&source]);
+        assert!(changed.loaded.is_none());
+        assert!(changed.changed_sources.is_none());
+    }
+
+    #[test]
+    fn test_hook_environments_are_kept() {
+        let environment = HookEnvironment {
+            cwd: Some("scripts".into()),
+            path_prepend: vec!["bin".into()],
+            requires_network: true,
+            env: [("EDITOR".into(), "vi".into())].into(),
+        };
+        let mut cache = config::Cache::default();
+        cache
+            .undeploy_hooks
+            .insert("vimrc".into(), [PathBuf::from("hooks/vim.sh")].into());
+        cache.hook_environments = still_hooked(
+            [
+                ("hooks/vim.sh".into(), environment.clone()),
+                ("hooks/gone.sh".into(), environment.clone()),
+            ]
+            .into(),
+            &cache.undeploy_hooks,
+        );
+
+        // Undeploying reads it back from the cache after the package is gone
+        let cache: config::Cache = toml::from_str(&toml::to_string(&cache).unwrap()).unwrap();
+        assert_eq!(
+            cache.hook_environments.into_iter().collect::<Vec<_>>(),
+            [(PathBuf::from("hooks/vim.sh"), environment)]
+        );
     }
 }
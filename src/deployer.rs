@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+
+use args::{ConflictPolicy, Options};
+use cancel::CancellationToken;
+use config::{self, Configuration};
+use context;
+use deploy::{self, ErrorOverlay};
+use file_state::FileState;
+use hooks::Change;
+use plan::Plan;
+use render::RenderLimits;
+
+/// Plans and deploys the repository in the current directory for programs that use dotter as
+/// a library. Errors of single files are still logged through `log`, like on the command line.
+#[derive(Debug, Clone)]
+pub struct Deployer {
+    opt: Options,
+}
+
+/// The outcome of `Deployer::apply`
+#[derive(Debug)]
+pub struct Applied {
+    /// Targets that were created, updated or deleted
+    pub changes: Vec<Change>,
+    /// Whether some files failed. The others were deployed anyway, unless the deploy is
+    /// transactional.
+    pub failed: bool,
+    /// Whether the deploy was cancelled before it was done. The files it didn't get to were
+    /// left as they are, and a transactional deploy was rolled back.
+    pub cancelled: bool,
+}
+
+impl Deployer {
+    /// Never prompts, since there may be no terminal to prompt on
+    pub fn new(opt: Options) -> Deployer {
+        let on_conflict = match opt.on_conflict {
+            ConflictPolicy::Ask => ConflictPolicy::Skip,
+            policy => policy,
+        };
+        Deployer {
+            opt: Options {
+                interactive: false,
+                summary_only: false,
+                on_conflict,
+                ..opt
+            },
+        }
+    }
+
+    /// Uses `cancellation` to stop `apply`, instead of a token of its own
+    pub fn with_cancellation(self, cancellation: CancellationToken) -> Deployer {
+        Deployer {
+            opt: Options {
+                cancellation,
+                ..self.opt
+            },
+        }
+    }
+
+    /// A token that stops a running `apply` when it's cancelled from another thread. It stays
+    /// cancelled, so later deploys with this `Deployer` stop right away.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.opt.cancellation.clone()
+    }
+
+    /// The merged configuration of the enabled packages
+    pub fn config(&self) -> Result<Configuration> {
+        deploy::load_configuration(&self.opt).context("get a configuration")
+    }
+
+    /// The files that the configuration wants, against the ones that the last deploy left
+    pub fn file_state(&self) -> Result<FileState> {
+        self.state(&self.config()?)
+    }
+
+    /// What a deploy would do to every file, without changing anything
+    pub fn plan(&self) -> Result<Plan> {
+        let config = self.config()?;
+        let state = self.state(&config)?;
+        Ok(Plan::new(
+            &state,
+            &config.file_packages,
+            &config.missing_sources,
+            &deploy::handlebars_instance(&self.opt, &config),
+            &context::template_context(&config),
+            &RenderLimits::new(&self.opt),
+        ))
+    }
+
+    /// Deploys, even if the options are of a dry run
+    pub fn apply(&self) -> Result<Applied> {
+        let opt = Options {
+            act: true,
+            ..self.opt.clone()
+        };
+        let deployed = deploy::deploy_changes(&opt, &ErrorOverlay::default()).context("deploy")?;
+        Ok(Applied {
+            changes: deployed.changes,
+            failed: deployed.error_occurred,
+            cancelled: deployed.cancelled,
+        })
+    }
+
+    fn state(&self, config: &Configuration) -> Result<FileState> {
+        let mut cache = config::load_cache(&self.opt.cache_file)?.unwrap_or_default();
+        cache.match_case(&config.files);
+        deploy::file_state_from_configuration(
+            config,
+            &cache,
+            &self.opt.cache_directory,
+            self.opt.act,
+        )
+        .context("get file state")
+    }
+}
@@ -5,9 +5,18 @@ use handlebars::Handlebars;
 
 use std::cmp::{max, min};
 use std::fs;
+use std::io::ErrorKind;
 
-use config::Variables;
-use file_state;
+use args::Options;
+use config::{self, Variables};
+use context;
+use deploy;
+use events::{self, Event};
+use file_state::{self, TemplateDescription};
+use filesystem::{self, SymlinkComparison, TemplateComparison};
+use plan::{Plan, PlannedChange};
+use render::RenderLimits;
+use secrets;
 
 pub type Diff = Vec<diff::Result<String>>;
 pub type HunkDiff = Vec<(usize, usize, Diff)>;
@@ -16,20 +25,30 @@ pub fn generate_diff(
     template: &file_state::TemplateDescription,
     handlebars: &Handlebars,
     variables: &Variables,
+    limits: &RenderLimits,
 ) -> Result<Diff> {
-    let file_contents =
-        fs::read_to_string(&template.source).context("read template source file")?;
-    let file_contents = template.apply_actions(file_contents);
-    let rendered = handlebars
-        .render_template(&file_contents, variables)
-        .context("render template")?;
+    let rendered = deploy::render_to_string(template, handlebars, variables, limits)?;
 
     let target_contents =
         fs::read_to_string(&template.target.target).context("read template target file")?;
 
-    let diff_result = diff::lines(&target_contents, &rendered);
+    if template
+        .target
+        .equivalence
+        .equivalent(&target_contents, &rendered)?
+    {
+        return Ok(Vec::new());
+    }
+
+    Ok(diff_contents(&target_contents, &rendered))
+}
 
-    Ok(diff_result.into_iter().map(to_owned_diff_result).collect())
+/// The lines that change from `old` to `new`
+pub fn diff_contents(old: &str, new: &str) -> Diff {
+    diff::lines(old, new)
+        .into_iter()
+        .map(to_owned_diff_result)
+        .collect()
 }
 
 fn to_owned_diff_result(from: diff::Result<&str>) -> diff::Result<String> {
@@ -52,6 +71,11 @@ pub fn diff_nonempty(diff: &[diff::Result<String>]) -> bool {
     false
 }
 
+/// Lines that were removed or added
+pub fn changed_lines(diff: &[diff::Result<String>]) -> usize {
+    diff.iter().filter(|line| is_different(line)).count()
+}
+
 fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
     let mut hunks = vec![];
 
@@ -157,6 +181,17 @@ fn print_hunk(mut left_line: usize, mut right_line: usize, hunk: Diff, max_digit
     }
 }
 
+/// Whether the diff of a template mustn't be shown. The plaintext of an encrypted source spans
+/// many lines, so it's never found in a single line of the diff and is hidden by the flag.
+pub fn hides_diff(template: &TemplateDescription, diff: &[diff::Result<String>]) -> bool {
+    template.target.encrypted
+        || diff.iter().any(|line| match line {
+            diff::Result::Left(s) | diff::Result::Right(s) | diff::Result::Both(s, _) => {
+                secrets::contains_secret(s)
+            }
+        })
+}
+
 pub fn print_diff(diff: Diff, extra_lines: usize) {
     let mut diff = hunkify_diff(diff, extra_lines);
 
@@ -171,3 +206,186 @@ pub fn print_diff(diff: Diff, extra_lines: usize) {
 
     print_hunk(last_hunk.0, last_hunk.1, last_hunk.2, max_possible_digits);
 }
+
+/// Prints what a deploy would change: a diff of every template's target against its render,
+/// where symlinks would point, and which copies and deleted files would change.
+/// Nothing is written, not even the cache.
+pub fn print_pending_changes(opt: &Options) -> Result<()> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let mut cache = match config::load_cache(&opt.cache_file)? {
+        Some(cache) => cache,
+        None => {
+            warn!("Cache file not found. Assuming nothing is deployed.");
+            Default::default()
+        }
+    };
+    cache.match_case(&config.files);
+    let state = deploy::file_state_from_configuration(&config, &cache, &opt.cache_directory, false)
+        .context("get file state")?;
+    let variables = context::template_context(&config);
+    let handlebars = deploy::handlebars_instance(opt, &config);
+    let limits = RenderLimits::new(opt);
+
+    if events::enabled() {
+        let plan = Plan::new(
+            &state,
+            &config.file_packages,
+            &config.missing_sources,
+            &handlebars,
+            &variables,
+            &limits,
+        );
+        for file in plan.files.iter() {
+            if file.change != PlannedChange::Unchanged {
+                events::report(Event::Planned(file));
+            }
+        }
+        return Ok(());
+    }
+
+    let mut changes = 0;
+    let (deleted_symlinks, deleted_templates) = state.deleted_files();
+    for symlink in deleted_symlinks {
+        println!("{} {}", "[-]".red(), symlink);
+        changes += 1;
+    }
+    for template in deleted_templates {
+        println!("{} {}", "[-]".red(), template);
+        changes += 1;
+    }
+    for copy in state.deleted_copies() {
+        println!("{} {}", "[-]".red(), copy);
+        changes += 1;
+    }
+
+    for symlink in &state.desired_symlinks {
+        let target = &symlink.target.target;
+        let comparison = symlink
+            .compare()
+            .with_context(|| format!("compare {}", symlink))?;
+        match comparison {
+            SymlinkComparison::Identical | SymlinkComparison::TargetIsSource => continue,
+            SymlinkComparison::OnlySourceExists | SymlinkComparison::BothMissing => {
+                println!("{} {}", "[+]".green(), symlink)
+            }
+            SymlinkComparison::Changed => {
+                let old = fs::read_link(target).context("read target of link")?;
+                let new =
+                    filesystem::real_path(&symlink.source).context("get real path of source")?;
+                println!("{} {} ({:?} -> {:?})", "[~]".yellow(), symlink, old, new);
+            }
+            comparison => println!(
+                "{} {} ({}, only replaced with --force)",
+                "[~]".yellow(),
+                symlink,
+                comparison
+            ),
+        }
+        changes += 1;
+    }
+
+    for template in &state.desired_templates {
+        match print_template_changes(opt, template, &handlebars, &variables, &limits) {
+            Ok(true) => changes += 1,
+            Ok(false) => {}
+            Err(e) => error!("Failed to diff {}: {:#}", template, e),
+        }
+    }
+
+    for copy in &state.desired_copies {
+        let target_hash =
+            filesystem::hash_file(&copy.target.target).context("hash target of copy")?;
+        match target_hash {
+            None => println!("{} {}", "[+]".green(), copy),
+            Some(_) if copy.target.write_once => continue,
+            Some(hash) => {
+                let source_hash = filesystem::hash_file(&copy.source).context("hash source")?;
+                if source_hash.as_ref() == Some(&hash) {
+                    continue;
+                }
+                println!("{} {} (contents differ)", "[~]".yellow(), copy);
+            }
+        }
+        changes += 1;
+    }
+
+    if changes == 0 {
+        println!("Nothing to change");
+    }
+    Ok(())
+}
+
+/// Returns whether the template's target would change
+fn print_template_changes(
+    opt: &Options,
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<bool> {
+    let target = &template.target.target;
+    let current = match fs::read_to_string(target) {
+        Ok(current) => Some(current),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("read target"),
+    };
+    if template.target.write_once && current.is_some() {
+        return Ok(false);
+    }
+    let rendered = deploy::render_to_string(template, handlebars, variables, limits)?;
+
+    let diff = match &current {
+        Some(current) => {
+            if template.target.equivalence.equivalent(current, &rendered)? {
+                return Ok(false);
+            }
+            println!("{} {}", "[~]".yellow(), template);
+            let comparison =
+                filesystem::compare_template(target, &template.cache, &template.target.equivalence)
+                    .context("compare target with cache")?;
+            if comparison == TemplateComparison::Changed {
+                println!("The target was edited since the last deploy, which only overwrites it with --force");
+            }
+            diff::lines(current, &rendered)
+        }
+        None => {
+            println!("{} {}", "[+]".green(), template);
+            diff::lines("", &rendered)
+        }
+    };
+    let diff: Diff = diff.into_iter().map(to_owned_diff_result).collect();
+
+    if !diff_nonempty(&diff) {
+        return Ok(true);
+    }
+    let changed_lines = changed_lines(&diff);
+    if hides_diff(template, &diff) {
+        println!("The diff isn't shown, since it contains secrets");
+    } else if !template.target.diff.shows(changed_lines) {
+        println!("Changed {} lines (diff suppressed)", changed_lines);
+    } else {
+        print_diff(diff, opt.diff_context_lines);
+    }
+    println!();
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hides_diff() {
+        let mut template = TemplateDescription {
+            source: "secret.age".into(),
+            target: "/home/user/.secret".into(),
+            cache: "cache/secret".into(),
+            variables: Variables::new(),
+        };
+        let diff = diff::lines("token = 1\nuser = me", "token = 2\nuser = me");
+        let diff: Diff = diff.into_iter().map(to_owned_diff_result).collect();
+        assert!(!hides_diff(&template, &diff));
+        template.target.encrypted = true;
+        assert!(hides_diff(&template, &diff));
+    }
+}
@@ -0,0 +1,478 @@
+use anyhow::{Context, Result};
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use config::UnixUser;
+use filesystem;
+use non_interactive::{self, WouldPrompt};
+
+/// Runs `operation`, and runs `elevated` instead if the target is marked `elevate = true` and
+/// the user isn't allowed to change it. Only the failed operation is elevated, not dotter.
+pub fn or_elevated<T>(
+    elevate: bool,
+    target: &Path,
+    operation: impl FnOnce() -> Result<T>,
+    elevated: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    match operation() {
+        Err(e) if elevate && is_permission_denied(&e) => {
+            debug!("Not allowed to change {:?}: {:#}", target, e);
+            elevated().context("change target with sudo")
+        }
+        result => result,
+    }
+}
+
+fn is_permission_denied(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+    })
+}
+
+/// Permissions and ownership that an elevated target gets after it's written
+pub struct Metadata<'a> {
+    /// Permission bits, or `None` to leave the ones that the target got
+    pub mode: Option<u32>,
+    pub owner: &'a Option<UnixUser>,
+    pub group: &'a Option<UnixUser>,
+}
+
+/// Owner and permissions of the parent directories that an elevated target creates, from
+/// `directory_owner` and `directory_mode` of the target
+pub struct Directories<'a> {
+    pub owner: &'a Option<UnixUser>,
+    /// Permission bits, or `None` for the usual `755`
+    pub mode: Option<u32>,
+}
+
+/// Writes `contents` to `target` with sudo, creating its parents and replacing a symlink
+pub fn write(
+    target: &Path,
+    contents: &[u8],
+    metadata: &Metadata,
+    directories: &Directories,
+) -> Result<()> {
+    info!("Writing {:?} with sudo", target);
+    prepare(target, directories)?;
+    let mut install = install(Path::new("/dev/stdin"), target, metadata);
+    install.input = Some(contents.to_vec());
+    run(install)?;
+    set_metadata(target, metadata)
+}
+
+/// Copies `source` to `target` with sudo, creating its parents and replacing a symlink
+pub fn copy(
+    source: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    directories: &Directories,
+) -> Result<()> {
+    info!("Copying to {:?} with sudo", target);
+    prepare(target, directories)?;
+    run(install(source, target, metadata))?;
+    set_metadata(target, metadata)
+}
+
+/// Creates `target` with its permissions already set, so that its contents are never readable
+/// by anyone they aren't meant for, like they would be between a `tee` and a `chmod`
+fn install(source: &Path, target: &Path, metadata: &Metadata) -> Elevated {
+    Elevated::new("install")
+        .arg("-m")
+        .arg(format!("{:o}", metadata.mode.unwrap_or(0o644)))
+        .arg("--")
+        .arg(source)
+        .arg(target)
+}
+
+/// Links `link` to `source` with sudo, creating its parents and replacing what was there
+pub fn symlink(
+    link: &Path,
+    source: &Path,
+    metadata: &Metadata,
+    directories: &Directories,
+) -> Result<()> {
+    info!("Linking {:?} with sudo", link);
+    prepare(link, directories)?;
+    let source = filesystem::real_path(source).context("get real path of source file")?;
+    run(Elevated::new("ln")
+        .arg("-s")
+        .arg("--")
+        .arg(source)
+        .arg(link))?;
+    set_metadata(link, metadata)
+}
+
+/// Changes the permissions and file flags of the symlink itself with sudo, on the platforms
+/// where symlinks have them
+pub fn symlink_permissions(link: &Path, mode: Option<u32>, flags: &[String]) -> Result<()> {
+    if !flags.is_empty() {
+        clear_symlink_flags(link)?;
+    }
+    if let Some(mode) = mode {
+        run(Elevated::new("chmod")
+            .arg("-h")
+            .arg(format!("{:o}", mode))
+            .arg("--")
+            .arg(link))?;
+    }
+    if !flags.is_empty() {
+        run(Elevated::new("chflags")
+            .arg("-h")
+            .arg(flags.join(","))
+            .arg("--")
+            .arg(link))?;
+    }
+    Ok(())
+}
+
+pub fn remove(target: &Path) -> Result<()> {
+    info!("Removing {:?} with sudo", target);
+    clear_symlink_flags(target)?;
+    run(Elevated::new("rm").arg("-f").arg("--").arg(target))
+}
+
+fn prepare(target: &Path, directories: &Directories) -> Result<()> {
+    let parent = target.parent().context("get parent of target")?;
+    create_directories(parent, directories)?;
+    clear_symlink_flags(target)?;
+    run(Elevated::new("rm").arg("-f").arg("--").arg(target))
+}
+
+/// Creates the missing directories of `path` with sudo
+pub fn create_directories(path: &Path, directories: &Directories) -> Result<()> {
+    for command in install_directories(path, directories) {
+        run(command)?;
+    }
+    Ok(())
+}
+
+/// One `install -d` for every missing directory of `path`, outermost first, so that each gets
+/// the owner and mode that were asked for instead of root and its umask
+fn install_directories(path: &Path, directories: &Directories) -> Vec<Elevated> {
+    filesystem::missing_directories(path)
+        .into_iter()
+        .map(|directory| {
+            let mut install = Elevated::new("install")
+                .arg("-d")
+                .arg("-m")
+                .arg(format!("{:o}", directories.mode.unwrap_or(0o755)));
+            if let Some(owner) = directories.owner {
+                install = install.arg("-o").arg(name(owner));
+            }
+            install.arg("--").arg(directory)
+        })
+        .collect()
+}
+
+/// Immutable flags like `schg` keep a symlink from being removed or changed, even by root
+fn clear_symlink_flags(link: &Path) -> Result<()> {
+    if !filesystem::SYMLINK_PERMISSIONS || !filesystem::is_link(link) {
+        return Ok(());
+    }
+    run(Elevated::new("chflags")
+        .arg("-h")
+        .arg("0")
+        .arg("--")
+        .arg(link))
+}
+
+/// Applies the metadata with sudo, the target is written already
+pub fn set_metadata(target: &Path, metadata: &Metadata) -> Result<()> {
+    if let Some(mode) = metadata.mode {
+        run(Elevated::new("chmod")
+            .arg(format!("{:o}", mode))
+            .arg("--")
+            .arg(target))?;
+    }
+    if let Some(owner) = metadata.owner {
+        run(Elevated::new("chown")
+            .arg("-h")
+            .arg(name(owner))
+            .arg("--")
+            .arg(target))?;
+    }
+    if let Some(group) = metadata.group {
+        run(Elevated::new("chgrp")
+            .arg("-h")
+            .arg(name(group))
+            .arg("--")
+            .arg(target))?;
+    }
+    Ok(())
+}
+
+fn name(user: &UnixUser) -> String {
+    match user {
+        UnixUser::Uid(id) => id.to_string(),
+        UnixUser::Name(name) => name.clone(),
+    }
+}
+
+/// Programs that dotter runs as root. The privileged helper refuses to run anything else.
+const PROGRAMS: &[&str] = &["install", "ln", "rm", "chmod", "chown", "chgrp", "chflags"];
+
+/// A command that runs as root, either with its own sudo or in the privileged helper
+#[derive(Debug, Serialize, Deserialize)]
+struct Elevated {
+    program: String,
+    args: Vec<OsString>,
+    /// Written to the program's standard input
+    input: Option<Vec<u8>>,
+}
+
+impl Elevated {
+    fn new(program: &str) -> Elevated {
+        Elevated {
+            program: program.into(),
+            args: Vec::new(),
+            input: None,
+        }
+    }
+
+    fn arg(mut self, arg: impl AsRef<OsStr>) -> Elevated {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+}
+
+/// Whether `--privileged-helper` was given
+static USE_HELPER: AtomicBool = AtomicBool::new(false);
+
+/// The privileged helper of this run, started the first time something is elevated
+static HELPER: Mutex<Option<Helper>> = Mutex::new(None);
+
+pub fn use_privileged_helper(enabled: bool) {
+    USE_HELPER.store(enabled, Ordering::Relaxed);
+}
+
+fn run(elevated: Elevated) -> Result<()> {
+    if !USE_HELPER.load(Ordering::Relaxed) {
+        // sudo asks for the password on the terminal, and remembers it for the following commands
+        let mut command = sudo()?;
+        command.arg(&elevated.program).args(&elevated.args);
+        return execute(command, &elevated)
+            .with_context(|| format!("run sudo {}", elevated.program));
+    }
+
+    let mut helper = HELPER.lock().unwrap_or_else(|e| e.into_inner());
+    if helper.is_none() {
+        *helper = Some(Helper::start().context("start privileged helper")?);
+    }
+    let result = helper
+        .as_mut()
+        .expect("helper was started")
+        .run(&elevated)
+        .with_context(|| format!("run {} in privileged helper", elevated.program));
+    if result.is_err() {
+        // A helper that failed to answer is started again for the next change
+        *helper = None;
+    }
+    result
+}
+
+/// `sudo`, which isn't allowed to ask for a password under --non-interactive, so it has to
+/// work without one from the start
+fn sudo() -> Result<Command> {
+    let mut command = Command::new("sudo");
+    if non_interactive::enabled() {
+        let passwordless = Command::new("sudo")
+            .args(["--non-interactive", "true"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !passwordless {
+            return Err(WouldPrompt {
+                reason: "sudo-password",
+                message: "sudo needs a password".into(),
+            }
+            .into());
+        }
+        command.arg("--non-interactive");
+    }
+    Ok(command)
+}
+
+fn execute(mut command: Command, elevated: &Elevated) -> Result<()> {
+    debug!("Running {:?}", command);
+    command.stdout(Stdio::null());
+    if elevated.input.is_some() {
+        command.stdin(Stdio::piped());
+    } else {
+        command.stdin(Stdio::inherit());
+    }
+    let mut child = command.spawn().context("spawn command")?;
+    if let Some(input) = &elevated.input {
+        child
+            .stdin
+            .take()
+            .context("open standard input")?
+            .write_all(input)
+            .context("write standard input")?;
+    }
+    let status = child.wait().context("wait for command")?;
+    if !status.success() {
+        bail!("{} exited with {}", elevated.program, status);
+    }
+    Ok(())
+}
+
+/// A copy of dotter that runs as root for the rest of the run, so that sudo is only run once
+/// and the unprivileged dotter never handles credentials. Every change is a line of JSON on
+/// its standard input, and it answers each with a line of JSON on its standard output.
+struct Helper {
+    // Dropping it closes the helper's standard input, which makes it exit
+    _child: Child,
+    requests: ChildStdin,
+    responses: BufReader<ChildStdout>,
+}
+
+impl Helper {
+    fn start() -> Result<Helper> {
+        info!("Starting the privileged helper with sudo");
+        let mut child = sudo()?
+            .arg(std::env::current_exe().context("find dotter executable")?)
+            .arg(HELPER_COMMAND)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("spawn sudo")?;
+        Ok(Helper {
+            requests: child
+                .stdin
+                .take()
+                .context("open standard input of helper")?,
+            responses: BufReader::new(
+                child
+                    .stdout
+                    .take()
+                    .context("open standard output of helper")?,
+            ),
+            _child: child,
+        })
+    }
+
+    fn run(&mut self, elevated: &Elevated) -> Result<()> {
+        debug!("Sending {:?} to the privileged helper", elevated.program);
+        let mut request = serde_json::to_vec(elevated).context("serialize request")?;
+        request.push(b'\n');
+        self.requests
+            .write_all(&request)
+            .and_then(|_| self.requests.flush())
+            .context("send request")?;
+        let mut response = String::new();
+        if self
+            .responses
+            .read_line(&mut response)
+            .context("read response")?
+            == 0
+        {
+            bail!("the privileged helper exited, maybe sudo failed");
+        }
+        let error: Option<String> = serde_json::from_str(&response).context("parse response")?;
+        match error {
+            Some(error) => Err(anyhow::anyhow!(error)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Name of the hidden subcommand that runs the privileged helper
+pub const HELPER_COMMAND: &str = "_privileged_helper";
+
+/// Runs the requests of the unprivileged dotter until it closes the standard input. Nothing
+/// but responses may be printed to the standard output, which is how they're sent.
+pub fn serve() -> Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    for request in stdin.lock().lines() {
+        let request = request.context("read request")?;
+        let result = serde_json::from_str::<Elevated>(&request)
+            .context("parse request")
+            .and_then(|elevated| {
+                if !PROGRAMS.contains(&elevated.program.as_str()) {
+                    bail!("the privileged helper doesn't run {:?}", elevated.program);
+                }
+                let mut command = Command::new(&elevated.program);
+                command.args(&elevated.args);
+                execute(command, &elevated)
+            });
+        let response = result.err().map(|e| format!("{:#}", e));
+        let mut response = serde_json::to_vec(&response).context("serialize response")?;
+        response.push(b'\n');
+        stdout.write_all(&response).context("send response")?;
+        stdout.flush().context("send response")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_install_directories() {
+        let directory = tempfile::tempdir().unwrap();
+        let parent = directory.path().join("etc").join("app");
+        let commands = |directories: &Directories| {
+            install_directories(&parent, directories)
+                .into_iter()
+                .map(|command| (command.program, command.args))
+                .collect::<Vec<_>>()
+        };
+        let args = |args: &[&str], path: &Path| {
+            let mut args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            args.push(path.into());
+            args
+        };
+
+        let owner = Some(UnixUser::Name("root".into()));
+        assert_eq!(
+            commands(&Directories {
+                owner: &owner,
+                mode: Some(0o750),
+            }),
+            vec![
+                (
+                    "install".to_string(),
+                    args(
+                        &["-d", "-m", "750", "-o", "root", "--"],
+                        &directory.path().join("etc")
+                    )
+                ),
+                (
+                    "install".to_string(),
+                    args(&["-d", "-m", "750", "-o", "root", "--"], &parent)
+                ),
+            ]
+        );
+
+        fs::create_dir(directory.path().join("etc")).unwrap();
+        assert_eq!(
+            commands(&Directories {
+                owner: &None,
+                mode: None,
+            }),
+            vec![(
+                "install".to_string(),
+                args(&["-d", "-m", "755", "--"], &parent)
+            )]
+        );
+        fs::create_dir(&parent).unwrap();
+        assert!(commands(&Directories {
+            owner: &None,
+            mode: None,
+        })
+        .is_empty());
+    }
+}
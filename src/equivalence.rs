@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+
+use regex::Regex;
+
+/// Differences between a template's target and its render that don't count as changes,
+/// for applications that write their configuration back in their own style
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(default, deny_unknown_fields)]
+pub struct Equivalence {
+    /// Ignore whitespace at the end of lines and empty lines at the end of the file
+    pub trailing_whitespace: bool,
+    /// Ignore lines that match this regex, like a generated header with a timestamp
+    pub ignore_lines: Option<String>,
+    /// Compare the files as JSON values, ignoring formatting and the order of keys.
+    /// Files that aren't valid JSON are compared as text.
+    pub json: bool,
+}
+
+impl Equivalence {
+    pub fn is_exact(&self) -> bool {
+        self == &Equivalence::default()
+    }
+
+    pub fn equivalent(&self, a: &str, b: &str) -> Result<bool> {
+        if a == b {
+            return Ok(true);
+        }
+        if self.is_exact() {
+            return Ok(false);
+        }
+        Ok(self.normalize(a)? == self.normalize(b)?)
+    }
+
+    fn normalize(&self, contents: &str) -> Result<String> {
+        let mut contents = contents.to_string();
+        if self.json {
+            if let Ok(value) = serde_json::from_str::<serde_json::Value>(&contents) {
+                // Objects are BTreeMaps, so this sorts the keys
+                contents = serde_json::to_string_pretty(&value).context("serialize JSON")?;
+            }
+        }
+
+        let ignore_lines = match &self.ignore_lines {
+            Some(regex) => Some(
+                Regex::new(regex)
+                    .with_context(|| format!("parse ignore_lines regex {:?}", regex))?,
+            ),
+            None => None,
+        };
+        let mut lines = contents
+            .lines()
+            .filter(|line| !ignore_lines.as_ref().is_some_and(|r| r.is_match(line)))
+            .map(|line| {
+                if self.trailing_whitespace {
+                    line.trim_end()
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>();
+        if self.trailing_whitespace {
+            while lines.last() == Some(&"") {
+                lines.pop();
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_equivalence() {
+        let exact = Equivalence::default();
+        assert!(!exact.equivalent("a \n", "a\n").unwrap());
+
+        let whitespace = Equivalence {
+            trailing_whitespace: true,
+            ..Default::default()
+        };
+        assert!(whitespace.equivalent("a \nb\t\n\n", "a\nb\n").unwrap());
+        assert!(!whitespace.equivalent(" a\n", "a\n").unwrap());
+
+        let header = Equivalence {
+            ignore_lines: Some("^# Generated".into()),
+            ..Default::default()
+        };
+        assert!(header
+            .equivalent("# Generated at 10:00\nx = 1\n", "x = 1\n")
+            .unwrap());
+
+        let json = Equivalence {
+            json: true,
+            ..Default::default()
+        };
+        assert!(json
+            .equivalent(
+                "{\"b\": 1, \"a\": [1, 2]}",
+                "{\n  \"a\": [1,2],\n  \"b\": 1\n}"
+            )
+            .unwrap());
+        assert!(!json
+            .equivalent("{\"a\": [2, 1]}", "{\"a\": [1, 2]}")
+            .unwrap());
+    }
+}
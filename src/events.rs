@@ -0,0 +1,92 @@
+//! Machine-readable events of deploy, undeploy, status, diff and report, for scripts that drive
+//! dotter.
+//! While a reporter is set, the events replace the output that these commands print.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use hooks::Change;
+use plan::PlannedFile;
+use status::MachineReport;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A file that the deploy is about to change, or that `diff` would
+    Planned(&'a PlannedFile),
+    /// A target that was created, updated or deleted
+    Changed(&'a Change),
+    /// How a target compares to the configuration, from `status`
+    Status {
+        source: &'a Path,
+        target: &'a Path,
+        status: &'a str,
+        detail: &'a str,
+    },
+    /// Summary of the machine, from `report`
+    Report(&'a MachineReport),
+    /// A note of a package, shown the first time that the package is deployed
+    Note {
+        package: &'a str,
+        note: &'a str,
+    },
+    Error(Failure),
+    /// The command is done, and whether it succeeded
+    Finished {
+        success: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub message: String,
+    /// What would have asked for an answer, when that's why it failed under --non-interactive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+pub trait Reporter: Send {
+    fn report(&mut self, event: &Event);
+}
+
+/// Writes every event as a line of JSON to standard output
+pub struct JsonLines;
+
+impl Reporter for JsonLines {
+    fn report(&mut self, event: &Event) {
+        let line = serde_json::to_string(event).expect("events serialize into JSON");
+        let mut stdout = std::io::stdout().lock();
+        // There's nowhere left to report a closed standard output
+        let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+    }
+}
+
+static REPORTER: Mutex<Option<Box<dyn Reporter>>> = Mutex::new(None);
+
+/// Sends the events of the commands that run from now on to `reporter`
+pub fn set_reporter(reporter: Option<Box<dyn Reporter>>) {
+    *REPORTER.lock().unwrap_or_else(|e| e.into_inner()) = reporter;
+}
+
+/// Whether events are reported, in which case the commands don't print their usual output
+pub fn enabled() -> bool {
+    REPORTER.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// Standard output of a program that dotter runs, like a hook. While events are reported, it's
+/// standard error, so that standard output only has the events.
+pub fn child_stdout() -> Stdio {
+    if enabled() {
+        Stdio::from(std::io::stderr())
+    } else {
+        Stdio::inherit()
+    }
+}
+
+pub fn report(event: Event) {
+    if let Some(reporter) = REPORTER.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        reporter.report(&event);
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use args::{ExportFormat, Options};
+use config::Cache;
+use context;
+use deploy;
+use file_state::TemplateDescription;
+use filesystem;
+use render::{self, RenderLimits};
+use secrets;
+
+/// One entry of `home.file` or `xdg.configFile`
+struct NixFile {
+    /// Target, relative to the home directory or to the XDG config directory
+    name: String,
+    /// Value of `source` or `text`, already formatted as a Nix expression
+    value: NixValue,
+    executable: bool,
+}
+
+enum NixValue {
+    Source(String),
+    Text(String),
+}
+
+/// Prints the current plan in another tool's format, to migrate a configuration gradually
+pub fn export(
+    opt: &Options,
+    format: ExportFormat,
+    output: Option<&Path>,
+    reference_rendered: bool,
+) -> Result<()> {
+    let exported = match format {
+        ExportFormat::Nix => to_home_manager(opt, reference_rendered)?,
+    };
+    match output {
+        Some(output) => fs::write(output, exported).context("write exported configuration")?,
+        None => print!("{}", exported),
+    }
+    Ok(())
+}
+
+/// A home-manager module with a `home.file` or `xdg.configFile` entry for every file.
+/// Symlinked files are referenced by their path in the repository, templates are rendered and
+/// inlined, or referenced by their rendered copy in the cache directory.
+fn to_home_manager(opt: &Options, reference_rendered: bool) -> Result<String> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let state = deploy::file_state_from_configuration(
+        &config,
+        &Cache::default(),
+        &opt.cache_directory,
+        true,
+    )
+    .context("get file state")?;
+    let variables = context::template_context(&config);
+    let handlebars = deploy::handlebars_instance(opt, &config);
+    let limits = RenderLimits::new(opt);
+
+    let home = filesystem::home_dir();
+    let xdg_config = filesystem::xdg_config_home();
+
+    let mut home_files = Vec::new();
+    let mut config_files = Vec::new();
+    let mut skipped = Vec::new();
+    let mut add = |target: &Path, value: NixValue, executable: bool| {
+        let (files, name) = if let Ok(name) = target.strip_prefix(&xdg_config) {
+            (&mut config_files, name)
+        } else if let Ok(name) = target.strip_prefix(&home) {
+            (&mut home_files, name)
+        } else {
+            warn!(
+                "Not exporting {:?}, home-manager only manages files in the home directory",
+                target
+            );
+            skipped.push(target.to_path_buf());
+            return;
+        };
+        files.push(NixFile {
+            name: name.to_string_lossy().into_owned(),
+            value,
+            executable,
+        });
+    };
+
+    for symlink in &state.desired_symlinks {
+        add(
+            &symlink.target.target,
+            NixValue::Source(nix_path(&symlink.source)),
+            is_executable(&symlink.source),
+        );
+    }
+    for copy in &state.desired_copies {
+        add(
+            &copy.target.target,
+            NixValue::Source(nix_path(&copy.source)),
+            is_executable(&copy.source),
+        );
+    }
+    for template in &state.desired_templates {
+        let value = if reference_rendered {
+            NixValue::Source(nix_path(&rendered_copy(template)?))
+        } else {
+            let rendered = render::render_template(template, &handlebars, &variables, &limits)
+                .with_context(|| format!("render {}", template))?;
+            if secrets::contains_secret(&rendered) {
+                bail!(
+                    "{} contains secrets, which would be readable by anyone in the Nix store",
+                    template
+                );
+            }
+            NixValue::Text(nix_text(&rendered))
+        };
+        add(
+            &template.target.target,
+            value,
+            is_executable(&template.source),
+        );
+    }
+
+    let mut module = String::from(
+        "# Generated by `dotter export nix`. Paths of sources are relative to the repository,\n# so this file belongs at its root.\n",
+    );
+    for target in &skipped {
+        writeln!(
+            module,
+            "# Not exported, outside of the home directory: {:?}",
+            target
+        )?;
+    }
+    module.push_str("{\n");
+    write_files(&mut module, "home.file", &home_files)?;
+    write_files(&mut module, "xdg.configFile", &config_files)?;
+    module.push_str("}\n");
+    Ok(module)
+}
+
+fn write_files(module: &mut String, attribute: &str, files: &[NixFile]) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    writeln!(module, "  {} = {{", attribute)?;
+    for file in files {
+        write!(module, "    {} = {{ ", nix_string(&file.name))?;
+        match &file.value {
+            NixValue::Source(path) => write!(module, "source = {}; ", path)?,
+            NixValue::Text(text) => write!(module, "text = {}; ", text)?,
+        }
+        if file.executable {
+            write!(module, "executable = true; ")?;
+        }
+        writeln!(module, "}};")?;
+    }
+    writeln!(module, "  }};")?;
+    Ok(())
+}
+
+/// The rendered copy that the last deploy left in the cache directory
+fn rendered_copy(template: &TemplateDescription) -> Result<PathBuf> {
+    let cached = fs::canonicalize(&template.cache).with_context(|| {
+        format!(
+            "find rendered copy of {:?} (deploy before referencing rendered templates)",
+            template.source
+        )
+    })?;
+    if fs::read_to_string(&cached).is_ok_and(|c| c.starts_with(secrets::HASH_PREFIX)) {
+        bail!(
+            "{:?} contains secrets, so the cache only keeps its hash",
+            template.source
+        );
+    }
+    Ok(cached)
+}
+
+#[cfg(unix)]
+fn is_executable(source: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(source).is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_source: &Path) -> bool {
+    false
+}
+
+/// A path literal, which Nix resolves relative to the file it's written in
+fn nix_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let plain = path
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-+/".contains(c))
+        && !path.ends_with('/')
+        && !path.contains("//");
+    match (path.starts_with('/'), plain) {
+        (true, true) => path,
+        (false, true) => format!("./{}", path.trim_start_matches("./")),
+        (true, false) => format!("/. + {}", nix_string(&path)),
+        (false, false) => format!("./. + {}", nix_string(&format!("/{}", path))),
+    }
+}
+
+fn nix_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+/// An indented string when it keeps the text intact, otherwise a regular string.
+/// Nix strips the indentation that all lines of an indented string have in common, so text
+/// whose lines are all indented can't be written as one.
+fn nix_text(text: &str) -> String {
+    let indented = text.ends_with('\n')
+        && !text.contains('\r')
+        && !text.contains('\t')
+        && text
+            .lines()
+            .any(|line| !line.trim().is_empty() && !line.starts_with(' '));
+    if !indented {
+        return nix_string(text);
+    }
+    let mut string = String::from("''\n");
+    for line in text.lines() {
+        if !line.is_empty() {
+            string.push_str("      ");
+            string.push_str(&line.replace("''", "'''").replace("${", "''${"));
+        }
+        string.push('\n');
+    }
+    string.push_str("    ''");
+    string
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nix_escaping() {
+        assert_eq!(nix_path(Path::new("nvim/init.vim")), "./nvim/init.vim");
+        assert_eq!(nix_path(Path::new("my files/a")), "./. + \"/my files/a\"");
+        assert_eq!(nix_string("a\"${b}"), "\"a\\\"\\${b}\"");
+        assert_eq!(
+            nix_text("[user]\n  name = ${x} ''\n\n"),
+            "''\n      [user]\n        name = ''${x} '''\n\n    ''"
+        );
+        assert_eq!(nix_text("  all indented\n"), "\"  all indented\\n\"");
+        assert_eq!(nix_text("no newline"), "\"no newline\"");
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use local_config;
+
+/// What could be told about the variables of a template from a file it rendered
+#[derive(Debug, Default, PartialEq)]
+struct Proposal {
+    /// Every value found for each variable, in the order they were found
+    values: BTreeMap<String, Vec<String>>,
+    /// Variables that are only used on lines that matched nothing
+    unknown: Vec<String>,
+    /// 1-based lines of the template that have no counterpart in the deployed file
+    unmatched: Vec<usize>,
+}
+
+/// A line of the template, as a regex that matches what it renders to
+struct Line {
+    number: usize,
+    regex: Regex,
+    /// Variable of every capture group of the regex
+    variables: Vec<String>,
+}
+
+/// Prints the variables that would render `template` into `deployed`, as TOML to paste into
+/// the `[variables]` of a package. Values that disagree and lines that don't match are warned
+/// about, so the proposal can be checked by hand.
+pub fn extract_vars(template: &Path, deployed: &Path) -> Result<()> {
+    let template_contents =
+        fs::read_to_string(template).with_context(|| format!("read template {:?}", template))?;
+    let deployed_contents = fs::read_to_string(deployed)
+        .with_context(|| format!("read deployed file {:?}", deployed))?;
+    let proposal = propose(&template_contents, &deployed_contents)?;
+
+    if !proposal.unmatched.is_empty() {
+        warn!(
+            "Lines {} of the template match no line of {:?}, they may hide more variables",
+            proposal
+                .unmatched
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            deployed
+        );
+    }
+    for variable in &proposal.unknown {
+        warn!("Found no value for `{}`", variable);
+    }
+
+    let mut variables = toml::value::Table::new();
+    for (variable, values) in &proposal.values {
+        if values.len() > 1 {
+            warn!(
+                "`{}` has different values in {:?}: {}. Proposing the first one.",
+                variable,
+                deployed,
+                values
+                    .iter()
+                    .map(|value| format!("{:?}", value))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        insert(
+            &mut variables,
+            variable,
+            local_config::parse_value(&values[0]),
+        );
+    }
+    if variables.is_empty() {
+        warn!("Found no variables to propose");
+        return Ok(());
+    }
+    print!(
+        "{}",
+        toml::to_string(&toml::Value::Table(variables)).context("serialize variables into toml")?
+    );
+    Ok(())
+}
+
+/// Inserts the variable into nested tables by the parts of its path, like `font.size`
+fn insert(table: &mut toml::value::Table, path: &str, value: toml::Value) {
+    match path.split_once('.') {
+        Some((first, rest)) => {
+            let inner = table
+                .entry(first.to_string())
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+            if let toml::Value::Table(inner) = inner {
+                insert(inner, rest, value);
+            }
+        }
+        None => {
+            table.insert(path.into(), value);
+        }
+    }
+}
+
+/// Lines of the template are matched in order against the lines of the deployed file, keeping
+/// the most lines that match, and the variables of every matched line are read from its match.
+/// Lines with only block tags like `{{#if}}` render to nothing and are left out, while helper
+/// calls and other expressions match anything.
+fn propose(template: &str, deployed: &str) -> Result<Proposal> {
+    let expression = Regex::new(r"\{\{\{?~?\s*(.*?)\s*~?\}?\}\}").expect("valid regex");
+    let variable =
+        Regex::new(r"^(@root\.|\./|this\.)?([A-Za-z_][\w\-]*(\.[\w\-]+)*)$").expect("valid regex");
+
+    let mut lines = Vec::new();
+    let mut used = Vec::new();
+    for (index, line) in template.lines().enumerate() {
+        let mut pattern = String::from("^");
+        let mut variables = Vec::new();
+        let mut last = 0;
+        let mut only_blocks = true;
+        for found in expression.captures_iter(line) {
+            let whole = found.get(0).expect("whole match");
+            let inner = &found[1];
+            pattern.push_str(&regex::escape(&line[last..whole.start()]));
+            only_blocks &= line[last..whole.start()].trim().is_empty();
+            last = whole.end();
+
+            if inner.starts_with('!') {
+                continue;
+            }
+            if inner.starts_with(['#', '/', '^']) || inner == "else" || inner.starts_with("else ") {
+                pattern.push_str(".*?");
+                continue;
+            }
+            only_blocks = false;
+            match variable.captures(inner) {
+                Some(name) if !name[2].starts_with("dotter.") && &name[2] != "this" => {
+                    pattern.push_str("(.*?)");
+                    variables.push(name[2].to_string());
+                }
+                _ => pattern.push_str(".*?"),
+            }
+        }
+        only_blocks &= line[last..].trim().is_empty();
+        if only_blocks && last > 0 {
+            continue;
+        }
+        pattern.push_str(&regex::escape(&line[last..]));
+        pattern.push('$');
+        used.extend(variables.iter().cloned());
+        lines.push(Line {
+            number: index + 1,
+            regex: Regex::new(&pattern)
+                .with_context(|| format!("match line {} of the template", index + 1))?,
+            variables,
+        });
+    }
+
+    let deployed = deployed.lines().collect::<Vec<_>>();
+    let matches = |t: usize, d: usize| lines[t].regex.is_match(deployed[d]);
+
+    // Longest common subsequence, where a template line and a deployed line are in common if
+    // the line's regex matches
+    let mut longest = vec![vec![0usize; deployed.len() + 1]; lines.len() + 1];
+    for t in (0..lines.len()).rev() {
+        for d in (0..deployed.len()).rev() {
+            longest[t][d] = if matches(t, d) {
+                longest[t + 1][d + 1] + 1
+            } else {
+                longest[t + 1][d].max(longest[t][d + 1])
+            };
+        }
+    }
+
+    let mut proposal = Proposal::default();
+    let (mut t, mut d) = (0, 0);
+    while t < lines.len() {
+        if d < deployed.len() && matches(t, d) && longest[t][d] == longest[t + 1][d + 1] + 1 {
+            let captures = lines[t].regex.captures(deployed[d]).expect("line matches");
+            for (variable, value) in lines[t].variables.iter().zip(captures.iter().skip(1)) {
+                let value = value.map_or("", |v| v.as_str()).to_string();
+                let values = proposal.values.entry(variable.clone()).or_default();
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+            t += 1;
+            d += 1;
+        } else if d < deployed.len() && longest[t][d + 1] >= longest[t + 1][d] {
+            d += 1;
+        } else {
+            proposal.unmatched.push(lines[t].number);
+            t += 1;
+        }
+    }
+
+    used.sort();
+    used.dedup();
+    proposal.unknown = used
+        .into_iter()
+        .filter(|variable| !proposal.values.contains_key(variable))
+        .collect();
+    Ok(proposal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_propose() {
+        let template = "[user]\n{{#if email}}\n  email = {{email}}\n{{/if}}\n  name = {{ name }} ({{name}})\n{{#if signing}}\n  signingkey = {{key}}\n{{/if}}\n  editor = {{editor}}\n{{! comment }}\nshell = {{dotter.os}}-{{font.size}}px";
+        let deployed = "[user]\n  email = me@example.com\n  name = Me (Myself)\n  editor = vim\nshell = linux-12px\n";
+        let proposal = propose(template, deployed).unwrap();
+
+        let mut values = BTreeMap::new();
+        values.insert("email".to_string(), vec!["me@example.com".to_string()]);
+        values.insert("name".into(), vec!["Me".into(), "Myself".into()]);
+        values.insert("editor".into(), vec!["vim".into()]);
+        values.insert("font.size".into(), vec!["12".into()]);
+        assert_eq!(
+            proposal,
+            Proposal {
+                values,
+                unknown: vec!["key".into()],
+                unmatched: vec![7],
+            }
+        );
+    }
+}
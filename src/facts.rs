@@ -0,0 +1,326 @@
+//! Facts about the machine that templates can adapt to, gathered from the OS when a helper
+//! asks for them. Facts that can't be determined on this platform are `None`. While deploying
+//! to another machine over SSH, the facts are that machine's, see `set_host`.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Facts of a machine that's deployed to over SSH, which stand in for the ones of this machine.
+/// The ones that couldn't be determined there are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostFacts {
+    /// Like `std::env::consts::OS`
+    pub os: String,
+    /// Like `std::env::consts::ARCH`
+    pub arch: String,
+    pub hostname: String,
+    pub user: String,
+    pub cpus: Option<usize>,
+    pub memory_mib: Option<u64>,
+    pub locale: Option<String>,
+}
+
+static HOST: Mutex<Option<HostFacts>> = Mutex::new(None);
+
+/// Makes the facts the ones of the machine that's deployed to
+pub fn set_host(facts: HostFacts) {
+    *HOST.lock().unwrap_or_else(|e| e.into_inner()) = Some(facts);
+}
+
+fn host() -> Option<HostFacts> {
+    HOST.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Commands that print the facts of a unix machine for `parse_host_facts`, one per line
+pub const HOST_FACTS_SCRIPT: &str = "\
+printf 'os=%s\\n' \"$(uname -s)\"
+printf 'arch=%s\\n' \"$(uname -m)\"
+printf 'hostname=%s\\n' \"$(hostname 2>/dev/null || uname -n)\"
+printf 'user=%s\\n' \"$(id -un)\"
+printf 'cpus=%s\\n' \"$(getconf _NPROCESSORS_ONLN 2>/dev/null)\"
+printf 'memory=%s\\n' \"$(grep '^MemTotal:' /proc/meminfo 2>/dev/null)\"
+printf 'locale=%s\\n' \"${LC_ALL:-${LC_MESSAGES:-$LANG}}\"
+";
+
+/// Reads what `HOST_FACTS_SCRIPT` printed
+pub fn parse_host_facts(output: &str) -> HostFacts {
+    let mut facts = HostFacts::default();
+    for (name, value) in output.lines().filter_map(|line| line.split_once('=')) {
+        let value = value.trim();
+        match name {
+            "os" => {
+                facts.os = match value.to_lowercase().as_str() {
+                    "darwin" => "macos".into(),
+                    os => os.into(),
+                }
+            }
+            "arch" => {
+                facts.arch = match value {
+                    "arm64" => "aarch64".into(),
+                    "amd64" => "x86_64".into(),
+                    arch => arch.into(),
+                }
+            }
+            "hostname" => facts.hostname = value.into(),
+            "user" => facts.user = value.into(),
+            "cpus" => facts.cpus = value.parse().ok(),
+            "memory" => facts.memory_mib = parse_meminfo(value),
+            "locale" => {
+                facts.locale = Some(strip_encoding(value))
+                    .filter(|locale| !locale.is_empty() && locale != "C" && locale != "POSIX")
+            }
+            _ => {}
+        }
+    }
+    facts
+}
+
+/// Operating system, like `linux` or `macos`
+pub fn os() -> String {
+    host().map_or_else(|| std::env::consts::OS.into(), |host| host.os)
+}
+
+/// `unix` or `windows`. Machines that are deployed to over SSH run a POSIX shell.
+pub fn family() -> String {
+    host().map_or_else(|| std::env::consts::FAMILY.into(), |_| "unix".into())
+}
+
+/// Architecture of the CPU, like `x86_64` or `aarch64`
+pub fn arch() -> String {
+    host().map_or_else(|| std::env::consts::ARCH.into(), |host| host.arch)
+}
+
+pub fn hostname() -> String {
+    host().map_or_else(
+        || gethostname::gethostname().to_string_lossy().into_owned(),
+        |host| host.hostname,
+    )
+}
+
+/// Name of the user that deploys
+pub fn user() -> String {
+    host().map_or_else(
+        || {
+            std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_default()
+        },
+        |host| host.user,
+    )
+}
+
+/// Number of CPU cores available to dotter
+pub fn cpus() -> Option<usize> {
+    if let Some(host) = host() {
+        return host.cpus;
+    }
+    std::thread::available_parallelism().ok().map(|n| n.get())
+}
+
+/// Total memory in MiB
+pub fn memory_mib() -> Option<u64> {
+    if let Some(host) = host() {
+        return host.memory_mib;
+    }
+    if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+        return parse_meminfo(&meminfo);
+    }
+    // macOS and the BSDs
+    command_output("sysctl", &["-n", "hw.memsize"])
+        .or_else(|| command_output("sysctl", &["-n", "hw.physmem"]))
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .map(|bytes| bytes / 1024 / 1024)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let kibibytes = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kibibytes / 1024)
+}
+
+/// Whether the machine has a battery, which is a good hint that it's a laptop
+pub fn battery() -> Option<bool> {
+    if host().is_some() {
+        return None;
+    }
+    if let Ok(supplies) = fs::read_dir("/sys/class/power_supply") {
+        return Some(supplies.filter_map(|s| s.ok()).any(|supply| {
+            fs::read_to_string(supply.path().join("type")).is_ok_and(|t| t.trim() == "Battery")
+        }));
+    }
+    command_output("pmset", &["-g", "batt"]).map(|output| output.contains("InternalBattery"))
+}
+
+/// Vendor of the first GPU: `intel`, `amd`, `nvidia`, or its PCI vendor id if it's another one
+pub fn gpu_vendor() -> Option<String> {
+    if host().is_some() {
+        return None;
+    }
+    let mut cards = fs::read_dir("/sys/class/drm")
+        .ok()?
+        .filter_map(|c| c.ok())
+        .map(|c| c.path())
+        .filter(|c| is_card(c))
+        .collect::<Vec<_>>();
+    cards.sort();
+    let vendor = fs::read_to_string(cards.first()?.join("device/vendor")).ok()?;
+    Some(vendor_name(vendor.trim()))
+}
+
+/// `card0` is a GPU, `card0-HDMI-A-1` is one of its outputs
+fn is_card(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("card"))
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn vendor_name(id: &str) -> String {
+    match id {
+        "0x8086" => "intel".into(),
+        "0x1002" | "0x1022" => "amd".into(),
+        "0x10de" => "nvidia".into(),
+        other => other.into(),
+    }
+}
+
+/// Locale of the user without its encoding, like `en_US`, from the same variables that
+/// programs read, in the order they take precedence
+pub fn locale() -> Option<String> {
+    if let Some(host) = host() {
+        return host.locale;
+    }
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|value| strip_encoding(&value))
+        .find(|value| !value.is_empty() && value != "C" && value != "POSIX")
+        .or_else(|| command_output("defaults", &["read", "-g", "AppleLocale"]))
+}
+
+/// `en_US.UTF-8@euro` is `en_US`
+fn strip_encoding(locale: &str) -> String {
+    locale.split(['.', '@']).next().unwrap_or_default().into()
+}
+
+/// Language of the locale, like `en`
+pub fn lang() -> Option<String> {
+    let locale = locale()?;
+    Some(locale.split(['_', '-']).next().unwrap_or_default().into())
+}
+
+/// XKB layout of the keyboard, like `us` or `us,de` when there are several
+pub fn keyboard_layout() -> Option<String> {
+    if host().is_some() {
+        return None;
+    }
+    if let Some(layout) = std::env::var("XKB_DEFAULT_LAYOUT")
+        .ok()
+        .filter(|l| !l.is_empty())
+    {
+        return Some(layout);
+    }
+    if let Some(layout) = command_output("localectl", &["status"])
+        .as_deref()
+        .and_then(|status| parse_assignment(status, "X11 Layout:"))
+    {
+        return Some(layout);
+    }
+    fs::read_to_string("/etc/default/keyboard")
+        .ok()
+        .as_deref()
+        .and_then(|keyboard| parse_assignment(keyboard, "XKBLAYOUT="))
+}
+
+/// Value of the first line that starts with `key`, without quotes
+fn parse_assignment(contents: &str, key: &str) -> Option<String> {
+    let value = contents
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(key))?
+        .trim()
+        .trim_matches('"');
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.into())
+    }
+}
+
+fn command_output(command: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        assert_eq!(
+            parse_meminfo("MemTotal:       16303928 kB\nMemFree:         1234 kB\n"),
+            Some(15921)
+        );
+        assert_eq!(parse_meminfo("MemFree: 1 kB\n"), None);
+        assert!(is_card(Path::new("/sys/class/drm/card1")));
+        assert!(!is_card(Path::new("/sys/class/drm/card1-DP-1")));
+    }
+
+    #[test]
+    fn test_locale_facts() {
+        assert_eq!(strip_encoding("en_US.UTF-8"), "en_US");
+        assert_eq!(strip_encoding("de_DE@euro"), "de_DE");
+        assert_eq!(
+            parse_assignment(
+                "   System Locale: LANG=en_US.UTF-8\n       X11 Layout: us,de\n",
+                "X11 Layout:"
+            ),
+            Some("us,de".into())
+        );
+        assert_eq!(
+            parse_assignment("XKBMODEL=\"pc105\"\nXKBLAYOUT=\"fr\"\n", "XKBLAYOUT="),
+            Some("fr".into())
+        );
+        assert_eq!(parse_assignment("XKBLAYOUT=\"\"\n", "XKBLAYOUT="), None);
+    }
+
+    #[test]
+    fn test_parse_host_facts() {
+        let facts = parse_host_facts(
+            "os=Darwin\narch=arm64\nhostname=server.example.com\nuser=deploy\ncpus=8\nmemory=\nlocale=C.UTF-8\n",
+        );
+        assert_eq!(
+            facts,
+            HostFacts {
+                os: "macos".into(),
+                arch: "aarch64".into(),
+                hostname: "server.example.com".into(),
+                user: "deploy".into(),
+                cpus: Some(8),
+                memory_mib: None,
+                locale: None,
+            }
+        );
+        let facts =
+            parse_host_facts("os=Linux\nmemory=MemTotal:       16303928 kB\nlocale=de_DE.UTF-8\n");
+        assert_eq!(facts.os, "linux");
+        assert_eq!(facts.memory_mib, Some(15921));
+        assert_eq!(facts.locale.as_deref(), Some("de_DE"));
+    }
+}
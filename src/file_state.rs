@@ -1,7 +1,13 @@
+use anyhow::{Context, Result};
+
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use config;
+use filesystem;
+use verify::Verify;
 
 #[derive(Debug)]
 pub struct FileState {
@@ -9,6 +15,8 @@ pub struct FileState {
     pub desired_templates: BTreeSet<TemplateDescription>,
     pub existing_symlinks: BTreeSet<SymlinkDescription>,
     pub existing_templates: BTreeSet<TemplateDescription>,
+    pub desired_copies: BTreeSet<CopyDescription>,
+    pub existing_copies: BTreeSet<CopyDescription>,
 }
 
 #[derive(Debug, Clone)]
@@ -22,6 +30,14 @@ pub struct TemplateDescription {
     pub source: PathBuf,
     pub target: config::TemplateTarget,
     pub cache: PathBuf,
+    /// Private variables of the template's package, see `isolate_variables`
+    pub variables: config::Variables,
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyDescription {
+    pub source: PathBuf,
+    pub target: config::CopyTarget,
 }
 
 // For use in FileState's Sets
@@ -33,16 +49,14 @@ impl std::cmp::PartialEq for SymlinkDescription {
 impl std::cmp::Eq for SymlinkDescription {}
 impl std::cmp::PartialOrd for SymlinkDescription {
     fn partial_cmp(&self, other: &SymlinkDescription) -> Option<std::cmp::Ordering> {
-        Some(
-            self.source
-                .cmp(&other.source)
-                .then(self.target.target.cmp(&other.target.target)),
-        )
+        Some(self.cmp(other))
     }
 }
 impl std::cmp::Ord for SymlinkDescription {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.source
+            .cmp(&other.source)
+            .then(self.target.target.cmp(&other.target.target))
     }
 }
 
@@ -54,20 +68,52 @@ impl std::cmp::PartialEq for TemplateDescription {
 impl std::cmp::Eq for TemplateDescription {}
 impl std::cmp::PartialOrd for TemplateDescription {
     fn partial_cmp(&self, other: &TemplateDescription) -> Option<std::cmp::Ordering> {
-        Some(
-            self.source
-                .cmp(&other.source)
-                .then(self.target.target.cmp(&other.target.target)),
-        )
+        Some(self.cmp(other))
     }
 }
 impl std::cmp::Ord for TemplateDescription {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.source
+            .cmp(&other.source)
+            .then(self.target.target.cmp(&other.target.target))
+    }
+}
+
+impl std::cmp::PartialEq for CopyDescription {
+    fn eq(&self, other: &CopyDescription) -> bool {
+        self.source == other.source && self.target.target == other.target.target
+    }
+}
+impl std::cmp::Eq for CopyDescription {}
+impl std::cmp::PartialOrd for CopyDescription {
+    fn partial_cmp(&self, other: &CopyDescription) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl std::cmp::Ord for CopyDescription {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.source
+            .cmp(&other.source)
+            .then(self.target.target.cmp(&other.target.target))
     }
 }
 
 impl TemplateDescription {
+    /// The variables that the template is rendered with: the shared ones, and the private
+    /// ones of its package on top
+    pub fn context<'a>(&self, variables: &'a config::Variables) -> Cow<'a, config::Variables> {
+        if self.variables.is_empty() {
+            return Cow::Borrowed(variables);
+        }
+        let mut context = variables.clone();
+        context.extend(self.variables.clone());
+        Cow::Owned(context)
+    }
+
+    pub fn engine(&self) -> config::TemplateEngine {
+        self.target.engine.unwrap_or_default()
+    }
+
     pub fn apply_actions(&self, mut file: String) -> String {
         if let Some(ref append) = self.target.append {
             file = file + append;
@@ -78,6 +124,174 @@ impl TemplateDescription {
 
         file
     }
+
+    /// Metadata of the deployed template that differs from the desired one
+    pub fn metadata_changes(&self) -> Result<Vec<MetadataChange>> {
+        let mut changes = Vec::new();
+        let desired_mode = match self.target.mode {
+            Some(mode) => Some(mode),
+            None => filesystem::file_mode(&self.source).context("get mode of source")?,
+        };
+        if let (Some(old), Some(new)) = (
+            filesystem::file_mode(&self.target.target).context("get mode of target")?,
+            desired_mode,
+        ) {
+            if old != new {
+                changes.push(MetadataChange::Mode { old, new });
+            }
+        }
+        changes.extend(owner_change(&self.target.target, &self.target.owner)?);
+        changes.extend(group_change(&self.target.target, &self.target.group)?);
+        Ok(changes)
+    }
+}
+
+/// A difference in a target's metadata, as opposed to its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum MetadataChange {
+    /// Permission bits, which templates copy from their source unless they set `mode`
+    Mode { old: u32, new: u32 },
+    /// Owning user id
+    Owner { old: u32, new: u32 },
+    /// Owning group id
+    Group { old: u32, new: u32 },
+    /// Where a symlink points
+    LinkTarget { old: PathBuf, new: PathBuf },
+    /// File flags of a symlink, like `uchg`
+    Flags { old: Vec<String>, new: Vec<String> },
+}
+
+impl std::fmt::Display for MetadataChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            MetadataChange::Mode { old, new } => write!(f, "mode {:o} -> {:o}", old, new),
+            MetadataChange::Owner { old, new } => write!(f, "owner {} -> {}", old, new),
+            MetadataChange::Group { old, new } => write!(f, "group {} -> {}", old, new),
+            MetadataChange::LinkTarget { old, new } => {
+                write!(f, "link target {:?} -> {:?}", old, new)
+            }
+            MetadataChange::Flags { old, new } => {
+                write!(f, "flags {:?} -> {:?}", old.join(","), new.join(","))
+            }
+        }
+    }
+}
+
+impl SymlinkDescription {
+    /// Compares the target against the source the way that it's linked
+    pub fn compare(&self) -> Result<filesystem::SymlinkComparison> {
+        match self.target.link {
+            config::LinkKind::Symlink | config::LinkKind::Junction => {
+                filesystem::compare_symlink(&self.source, &self.target.target)
+            }
+            config::LinkKind::Hardlink => {
+                filesystem::compare_hardlink(&self.source, &self.target.target)
+            }
+        }
+    }
+
+    /// Metadata of the deployed symlink that differs from the desired one
+    pub fn metadata_changes(&self) -> Result<Vec<MetadataChange>> {
+        let mut changes = Vec::new();
+        if let (Ok(old), Ok(new)) = (
+            fs::read_link(&self.target.target),
+            filesystem::real_path(&self.source),
+        ) {
+            if !filesystem::paths_equal(&old, &new) {
+                changes.push(MetadataChange::LinkTarget { old, new });
+            }
+        }
+        if let (true, Some(new)) = (filesystem::SYMLINK_PERMISSIONS, self.target.mode) {
+            if let Some(old) = filesystem::symlink_mode(&self.target.target)
+                .context("get mode of symlink")?
+                .filter(|&old| old != new)
+            {
+                changes.push(MetadataChange::Mode { old, new });
+            }
+        }
+        if !self.target.flags.is_empty() {
+            if let Some(old) = filesystem::symlink_flags(&self.target.target)
+                .context("get file flags of symlink")?
+            {
+                let new = &self.target.flags;
+                if old.iter().collect::<BTreeSet<_>>() != new.iter().collect::<BTreeSet<_>>() {
+                    changes.push(MetadataChange::Flags {
+                        old,
+                        new: new.clone(),
+                    });
+                }
+            }
+        }
+        changes.extend(owner_change(&self.target.target, &self.target.owner)?);
+        changes.extend(group_change(&self.target.target, &self.target.group)?);
+        Ok(changes)
+    }
+}
+
+impl CopyDescription {
+    /// Metadata of the deployed copy that differs from the desired one
+    pub fn metadata_changes(&self) -> Result<Vec<MetadataChange>> {
+        let mut changes = Vec::new();
+        let desired_mode = match self.target.mode {
+            Some(mode) => Some(mode),
+            None => filesystem::file_mode(&self.source).context("get mode of source")?,
+        };
+        if let (Some(old), Some(new)) = (
+            filesystem::file_mode(&self.target.target).context("get mode of target")?,
+            desired_mode,
+        ) {
+            if old != new {
+                changes.push(MetadataChange::Mode { old, new });
+            }
+        }
+        changes.extend(owner_change(&self.target.target, &self.target.owner)?);
+        changes.extend(group_change(&self.target.target, &self.target.group)?);
+        Ok(changes)
+    }
+}
+
+/// Formats metadata changes as a single comma-separated line
+pub fn describe_metadata_changes(changes: &[MetadataChange]) -> String {
+    changes
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn owner_change(target: &Path, owner: &Option<config::UnixUser>) -> Result<Option<MetadataChange>> {
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+    let old = match filesystem::file_owner(target).context("get owner of target")? {
+        Some(old) => old,
+        None => return Ok(None),
+    };
+    let new = filesystem::resolve_user(owner).context("resolve desired owner")?;
+    Ok(if old != new {
+        Some(MetadataChange::Owner { old, new })
+    } else {
+        None
+    })
+}
+
+fn group_change(target: &Path, group: &Option<config::UnixUser>) -> Result<Option<MetadataChange>> {
+    let group = match group {
+        Some(group) => group,
+        None => return Ok(None),
+    };
+    let old = match filesystem::file_group(target).context("get group of target")? {
+        Some(old) => old,
+        None => return Ok(None),
+    };
+    let new = filesystem::resolve_group(group).context("resolve desired group")?;
+    Ok(if old != new {
+        Some(MetadataChange::Group { old, new })
+    } else {
+        None
+    })
 }
 
 impl std::fmt::Display for SymlinkDescription {
@@ -92,6 +306,12 @@ impl std::fmt::Display for TemplateDescription {
     }
 }
 
+impl std::fmt::Display for CopyDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "copy {:?} -> {:?}", self.source, self.target.target)
+    }
+}
+
 impl FileState {
     pub fn new(
         desired_symlinks: BTreeMap<PathBuf, config::SymbolicTarget>,
@@ -112,6 +332,24 @@ impl FileState {
                             config::SymbolicTarget {
                                 target,
                                 owner: None,
+                                group: None,
+                                volatile: false,
+                                critical: false,
+                                allow_overwrite: false,
+                                elevate: false,
+                                reload: None,
+                                service_unit: false,
+                                on_deploy: None,
+                                on_undeploy: None,
+                                directory_owner: None,
+                                directory_mode: None,
+                                after: Vec::new(),
+                                recursive: false,
+                                link: Default::default(),
+                                literal_braces: false,
+                                mode: None,
+                                flags: Vec::new(),
+                                verify: None,
                             },
                         )
                     })
@@ -126,17 +364,91 @@ impl FileState {
                             config::TemplateTarget {
                                 target,
                                 owner: None,
+                                group: None,
+                                mode: None,
+                                attributes: Vec::new(),
+                                acl: Vec::new(),
                                 append: None,
                                 prepend: None,
+                                volatile: false,
+                                write_once: false,
+                                critical: false,
+                                allow_overwrite: false,
+                                elevate: false,
+                                reload: None,
+                                service_unit: false,
+                                on_deploy: None,
+                                on_undeploy: None,
+                                equivalence: Default::default(),
+                                diff: Default::default(),
+                                directory_owner: None,
+                                directory_mode: None,
+                                after: Vec::new(),
+                                recursive: false,
+                                engine: None,
+                                render_cache: None,
+                                validate: None,
+                                encrypted: false,
+                                verify: None,
                             },
                         )
                     })
                     .collect(),
                 &cache_dir,
             ),
+            desired_copies: BTreeSet::new(),
+            existing_copies: BTreeSet::new(),
         }
     }
 
+    /// Adds the files that are deployed as copies. The existing copies only know their target.
+    pub fn with_copies(
+        mut self,
+        desired_copies: BTreeMap<PathBuf, config::CopyTarget>,
+        existing_copies: BTreeMap<PathBuf, PathBuf>,
+    ) -> FileState {
+        let to_set = |copies: BTreeMap<PathBuf, config::CopyTarget>| {
+            copies
+                .into_iter()
+                .map(|(source, target)| CopyDescription { source, target })
+                .collect()
+        };
+        self.desired_copies = to_set(desired_copies);
+        self.existing_copies = to_set(
+            existing_copies
+                .into_iter()
+                .map(|(source, target)| {
+                    (
+                        source,
+                        config::CopyTarget {
+                            target,
+                            owner: None,
+                            group: None,
+                            mode: None,
+                            attributes: Vec::new(),
+                            acl: Vec::new(),
+                            volatile: false,
+                            write_once: false,
+                            critical: false,
+                            allow_overwrite: false,
+                            elevate: false,
+                            reload: None,
+                            service_unit: false,
+                            on_deploy: None,
+                            on_undeploy: None,
+                            directory_owner: None,
+                            directory_mode: None,
+                            after: Vec::new(),
+                            recursive: false,
+                            verify: None,
+                        },
+                    )
+                })
+                .collect(),
+        );
+        self
+    }
+
     pub fn symlinks_to_set(
         symlinks: BTreeMap<PathBuf, config::SymbolicTarget>,
     ) -> BTreeSet<SymlinkDescription> {
@@ -153,13 +465,269 @@ impl FileState {
         templates
             .into_iter()
             .map(|(source, target)| TemplateDescription {
-                source: source.clone(),
+                cache: target
+                    .render_cache
+                    .as_deref()
+                    .unwrap_or(cache_dir)
+                    .join(&source),
+                source,
                 target,
-                cache: cache_dir.join(&source),
+                variables: Default::default(),
             })
             .collect()
     }
 
+    /// Drops every file that isn't volatile, so only volatile files are created or updated
+    /// and nothing is deleted. Returns the sources of the desired files that were dropped.
+    /// Marks the existing files whose targets were deployed with `elevate = true`, since the
+    /// cache only knows their targets
+    pub fn with_elevated(mut self, elevated: &BTreeSet<PathBuf>) -> FileState {
+        self.existing_symlinks = std::mem::take(&mut self.existing_symlinks)
+            .into_iter()
+            .map(|mut s| {
+                s.target.elevate |= elevated.contains(&s.target.target);
+                s
+            })
+            .collect();
+        self.existing_templates = std::mem::take(&mut self.existing_templates)
+            .into_iter()
+            .map(|mut t| {
+                t.target.elevate |= elevated.contains(&t.target.target);
+                t
+            })
+            .collect();
+        self.existing_copies = std::mem::take(&mut self.existing_copies)
+            .into_iter()
+            .map(|mut c| {
+                c.target.elevate |= elevated.contains(&c.target.target);
+                c
+            })
+            .collect();
+        self
+    }
+
+    /// Marks the existing symlinks whose targets were linked as junctions or hardlinks, since
+    /// the cache only knows their targets
+    pub fn with_links(mut self, links: &BTreeMap<PathBuf, config::LinkKind>) -> FileState {
+        self.existing_symlinks = std::mem::take(&mut self.existing_symlinks)
+            .into_iter()
+            .map(|mut s| {
+                if let Some(link) = links.get(&s.target.target) {
+                    s.target.link = *link;
+                }
+                s
+            })
+            .collect();
+        self
+    }
+
+    /// Points the existing templates that were deployed with `render_cache` at their rendered
+    /// copies, since the cache only knows their targets
+    pub fn with_render_caches(mut self, render_caches: &BTreeMap<PathBuf, PathBuf>) -> FileState {
+        self.existing_templates = std::mem::take(&mut self.existing_templates)
+            .into_iter()
+            .map(|mut t| {
+                if let Some(directory) = render_caches.get(&t.source) {
+                    t.cache = directory.join(&t.source);
+                    t.target.render_cache = Some(directory.clone());
+                }
+                t
+            })
+            .collect();
+        self
+    }
+
+    /// Gives the templates of packages with private variables those variables. Existing
+    /// templates get them too, since they're the ones that are compared against sometimes.
+    pub fn with_private_variables(
+        mut self,
+        file_packages: &config::FilePackages,
+        private_variables: &BTreeMap<String, config::Variables>,
+    ) -> FileState {
+        let with_variables = |templates: BTreeSet<TemplateDescription>| {
+            templates
+                .into_iter()
+                .map(|mut t| {
+                    if let Some(variables) = file_packages
+                        .get(&t.source)
+                        .and_then(|package| private_variables.get(package))
+                    {
+                        t.variables = variables.clone();
+                    }
+                    t
+                })
+                .collect()
+        };
+        self.desired_templates = with_variables(std::mem::take(&mut self.desired_templates));
+        self.existing_templates = with_variables(std::mem::take(&mut self.existing_templates));
+        self
+    }
+
+    /// Gives the templates that don't choose an engine the `template_engine` of their package
+    pub fn with_template_engines(
+        mut self,
+        file_packages: &config::FilePackages,
+        template_engines: &BTreeMap<String, config::TemplateEngine>,
+    ) -> FileState {
+        let with_engine = |templates: BTreeSet<TemplateDescription>| {
+            templates
+                .into_iter()
+                .map(|mut t| {
+                    if t.target.engine.is_none() {
+                        t.target.engine = file_packages
+                            .get(&t.source)
+                            .and_then(|package| template_engines.get(package))
+                            .copied();
+                    }
+                    t
+                })
+                .collect()
+        };
+        self.desired_templates = with_engine(std::mem::take(&mut self.desired_templates));
+        self.existing_templates = with_engine(std::mem::take(&mut self.existing_templates));
+        self
+    }
+
+    /// Sources of the desired files that are deployed with `allow_overwrite = true`
+    pub fn overwritable_sources(&self) -> BTreeSet<PathBuf> {
+        self.desired_symlinks
+            .iter()
+            .filter(|s| s.target.allow_overwrite)
+            .map(|s| s.source.clone())
+            .chain(
+                self.desired_templates
+                    .iter()
+                    .filter(|t| t.target.allow_overwrite)
+                    .map(|t| t.source.clone()),
+            )
+            .chain(
+                self.desired_copies
+                    .iter()
+                    .filter(|c| c.target.allow_overwrite)
+                    .map(|c| c.source.clone()),
+            )
+            .collect()
+    }
+
+    /// Targets of the desired and existing files that are deployed with `elevate = true`
+    pub fn elevated_targets(&self) -> BTreeSet<PathBuf> {
+        let symlinks = self.desired_symlinks.iter().chain(&self.existing_symlinks);
+        let templates = self
+            .desired_templates
+            .iter()
+            .chain(&self.existing_templates);
+        let copies = self.desired_copies.iter().chain(&self.existing_copies);
+        symlinks
+            .filter(|s| s.target.elevate)
+            .map(|s| s.target.target.clone())
+            .chain(
+                templates
+                    .filter(|t| t.target.elevate)
+                    .map(|t| t.target.target.clone()),
+            )
+            .chain(
+                copies
+                    .filter(|c| c.target.elevate)
+                    .map(|c| c.target.target.clone()),
+            )
+            .collect()
+    }
+
+    /// Targets that are linked as junctions or hardlinks, as desired or as they were deployed
+    pub fn links(&self) -> BTreeMap<PathBuf, config::LinkKind> {
+        self.existing_symlinks
+            .iter()
+            .chain(&self.desired_symlinks)
+            .filter(|s| s.target.link != config::LinkKind::Symlink)
+            .map(|s| (s.target.target.clone(), s.target.link))
+            .collect()
+    }
+
+    /// Directories of the rendered copies of the templates deployed with `render_cache`, by
+    /// source, see `Cache::render_caches`. Templates that aren't desired, like the ones skipped
+    /// by `--only`, keep what's `cached`.
+    pub fn render_caches(&self, cached: &BTreeMap<PathBuf, PathBuf>) -> BTreeMap<PathBuf, PathBuf> {
+        let mut render_caches = cached.clone();
+        for template in &self.desired_templates {
+            match &template.target.render_cache {
+                Some(directory) => {
+                    render_caches.insert(template.source.clone(), directory.clone());
+                }
+                None => {
+                    render_caches.remove(&template.source);
+                }
+            }
+        }
+        render_caches
+    }
+
+    pub fn retain_volatile(&mut self) -> Vec<PathBuf> {
+        let dropped = self
+            .desired_symlinks
+            .iter()
+            .filter(|s| !s.target.volatile)
+            .map(|s| s.source.clone())
+            .chain(
+                self.desired_templates
+                    .iter()
+                    .filter(|t| !t.target.volatile)
+                    .map(|t| t.source.clone()),
+            )
+            .chain(
+                self.desired_copies
+                    .iter()
+                    .filter(|c| !c.target.volatile)
+                    .map(|c| c.source.clone()),
+            )
+            .collect();
+        self.desired_symlinks.retain(|s| s.target.volatile);
+        self.desired_templates.retain(|t| t.target.volatile);
+        self.desired_copies.retain(|c| c.target.volatile);
+        let desired_symlinks = &self.desired_symlinks;
+        let desired_templates = &self.desired_templates;
+        let desired_copies = &self.desired_copies;
+        self.existing_symlinks
+            .retain(|s| desired_symlinks.contains(s));
+        self.existing_templates
+            .retain(|t| desired_templates.contains(t));
+        self.existing_copies.retain(|c| desired_copies.contains(c));
+        dropped
+    }
+
+    /// Keeps the files of `packages` and returns the sources of the others. Deployed files that
+    /// don't belong to one of them, or whose package isn't known anymore, aren't ours to delete.
+    pub fn retain_packages(
+        &mut self,
+        packages: &[String],
+        file_packages: &config::FilePackages,
+    ) -> Vec<PathBuf> {
+        self.retain_sources(|source| {
+            file_packages
+                .get(source)
+                .is_some_and(|package| packages.contains(package))
+        })
+    }
+
+    /// Keeps the files whose source is `selected` and returns the sources of the others
+    pub fn retain_sources(&mut self, selected: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+        let dropped = self
+            .desired_symlinks
+            .iter()
+            .map(|s| &s.source)
+            .chain(self.desired_templates.iter().map(|t| &t.source))
+            .chain(self.desired_copies.iter().map(|c| &c.source))
+            .filter(|source| !selected(source))
+            .cloned()
+            .collect();
+        self.desired_symlinks.retain(|s| selected(&s.source));
+        self.desired_templates.retain(|t| selected(&t.source));
+        self.desired_copies.retain(|c| selected(&c.source));
+        self.existing_symlinks.retain(|s| selected(&s.source));
+        self.existing_templates.retain(|t| selected(&t.source));
+        self.existing_copies.retain(|c| selected(&c.source));
+        dropped
+    }
+
     pub fn deleted_files(&self) -> (Vec<SymlinkDescription>, Vec<TemplateDescription>) {
         (
             self.existing_symlinks
@@ -184,6 +752,88 @@ impl FileState {
                 .collect(),
         )
     }
+    pub fn deleted_copies(&self) -> Vec<CopyDescription> {
+        self.existing_copies
+            .difference(&self.desired_copies)
+            .cloned()
+            .collect()
+    }
+    pub fn new_copies(&self) -> Vec<CopyDescription> {
+        self.desired_copies
+            .difference(&self.existing_copies)
+            .cloned()
+            .collect()
+    }
+    pub fn old_copies(&self) -> Vec<CopyDescription> {
+        self.desired_copies
+            .intersection(&self.existing_copies)
+            .cloned()
+            .collect()
+    }
+
+    /// Every change of a deploy in the order it's made in: critical files and the files they
+    /// come after, deleted files, then new symlinks, templates and copies, then old ones,
+    /// except that a file comes after the targets in its `after` field. Files of packages that
+    /// others depend on come before the files of those others.
+    pub fn deploy_steps(
+        &self,
+        file_packages: &config::FilePackages,
+        package_levels: &BTreeMap<String, usize>,
+    ) -> Result<DeployPlan> {
+        let (new_symlinks, new_templates) = self.new_files();
+        let (old_symlinks, old_templates) = self.old_files();
+        let mut steps = new_symlinks
+            .into_iter()
+            .map(DeployStep::NewSymlink)
+            .chain(new_templates.into_iter().map(DeployStep::NewTemplate))
+            .chain(self.new_copies().into_iter().map(DeployStep::NewCopy))
+            .chain(old_symlinks.into_iter().map(DeployStep::OldSymlink))
+            .chain(old_templates.into_iter().map(DeployStep::OldTemplate))
+            .chain(self.old_copies().into_iter().map(DeployStep::OldCopy))
+            .collect::<Vec<_>>();
+        steps.sort_by_key(|step| {
+            file_packages
+                .get(step.source())
+                .and_then(|package| package_levels.get(package))
+                .copied()
+                .unwrap_or(0)
+        });
+        let mut steps = steps.into_iter().map(Some).collect::<Vec<_>>();
+
+        let (order, critical) = deploy_order(
+            &steps
+                .iter()
+                .flatten()
+                .map(|step| (step.target(), step.after(), step.critical()))
+                .collect::<Vec<_>>(),
+        )?;
+        let mut ordered = order
+            .into_iter()
+            .map(|i| steps[i].take().expect("every step is ordered once"))
+            .collect::<Vec<_>>();
+
+        let (deleted_symlinks, deleted_templates) = self.deleted_files();
+        let deleted = deleted_symlinks
+            .into_iter()
+            .map(DeployStep::DeletedSymlink)
+            .chain(
+                deleted_templates
+                    .into_iter()
+                    .map(DeployStep::DeletedTemplate),
+            )
+            .chain(
+                self.deleted_copies()
+                    .into_iter()
+                    .map(DeployStep::DeletedCopy),
+            );
+        ordered.splice(critical..critical, deleted);
+
+        Ok(DeployPlan {
+            steps: ordered,
+            critical,
+        })
+    }
+
     pub fn old_files(&self) -> (Vec<SymlinkDescription>, Vec<TemplateDescription>) {
         (
             self.desired_symlinks
@@ -198,6 +848,161 @@ impl FileState {
     }
 }
 
+#[derive(Debug)]
+pub struct DeployPlan {
+    pub steps: Vec<DeployStep>,
+    /// How many of the first steps are critical
+    pub critical: usize,
+}
+
+#[derive(Debug)]
+pub enum DeployStep {
+    DeletedSymlink(SymlinkDescription),
+    DeletedTemplate(TemplateDescription),
+    DeletedCopy(CopyDescription),
+    NewSymlink(SymlinkDescription),
+    NewTemplate(TemplateDescription),
+    NewCopy(CopyDescription),
+    OldSymlink(SymlinkDescription),
+    OldTemplate(TemplateDescription),
+    OldCopy(CopyDescription),
+}
+
+impl DeployStep {
+    pub fn source(&self) -> &Path {
+        match self {
+            DeployStep::DeletedSymlink(s)
+            | DeployStep::NewSymlink(s)
+            | DeployStep::OldSymlink(s) => &s.source,
+            DeployStep::DeletedTemplate(t)
+            | DeployStep::NewTemplate(t)
+            | DeployStep::OldTemplate(t) => &t.source,
+            DeployStep::DeletedCopy(c) | DeployStep::NewCopy(c) | DeployStep::OldCopy(c) => {
+                &c.source
+            }
+        }
+    }
+
+    pub fn target(&self) -> &Path {
+        match self {
+            DeployStep::DeletedSymlink(s)
+            | DeployStep::NewSymlink(s)
+            | DeployStep::OldSymlink(s) => &s.target.target,
+            DeployStep::DeletedTemplate(t)
+            | DeployStep::NewTemplate(t)
+            | DeployStep::OldTemplate(t) => &t.target.target,
+            DeployStep::DeletedCopy(c) | DeployStep::NewCopy(c) | DeployStep::OldCopy(c) => {
+                &c.target.target
+            }
+        }
+    }
+
+    /// Paths that performing the step can change: the target, and the rendered copy of a template
+    pub fn touched_paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.target()];
+        if let DeployStep::DeletedTemplate(t)
+        | DeployStep::NewTemplate(t)
+        | DeployStep::OldTemplate(t) = self
+        {
+            paths.push(&t.cache);
+        }
+        paths
+    }
+
+    /// Checks of the target after it's created or updated, see `verify.rs`
+    pub fn verify(&self) -> Option<&Verify> {
+        match self {
+            DeployStep::NewSymlink(s) | DeployStep::OldSymlink(s) => s.target.verify.as_ref(),
+            DeployStep::NewTemplate(t) | DeployStep::OldTemplate(t) => t.target.verify.as_ref(),
+            DeployStep::NewCopy(c) | DeployStep::OldCopy(c) => c.target.verify.as_ref(),
+            DeployStep::DeletedSymlink(_)
+            | DeployStep::DeletedTemplate(_)
+            | DeployStep::DeletedCopy(_) => None,
+        }
+    }
+
+    fn after(&self) -> &[PathBuf] {
+        match self {
+            DeployStep::DeletedSymlink(s)
+            | DeployStep::NewSymlink(s)
+            | DeployStep::OldSymlink(s) => &s.target.after,
+            DeployStep::DeletedTemplate(t)
+            | DeployStep::NewTemplate(t)
+            | DeployStep::OldTemplate(t) => &t.target.after,
+            DeployStep::DeletedCopy(c) | DeployStep::NewCopy(c) | DeployStep::OldCopy(c) => {
+                &c.target.after
+            }
+        }
+    }
+
+    fn critical(&self) -> bool {
+        match self {
+            DeployStep::DeletedSymlink(s)
+            | DeployStep::NewSymlink(s)
+            | DeployStep::OldSymlink(s) => s.target.critical,
+            DeployStep::DeletedTemplate(t)
+            | DeployStep::NewTemplate(t)
+            | DeployStep::OldTemplate(t) => t.target.critical,
+            DeployStep::DeletedCopy(c) | DeployStep::NewCopy(c) | DeployStep::OldCopy(c) => {
+                c.target.critical
+            }
+        }
+    }
+}
+
+/// Orders items given as (target, targets it comes after, critical) so that every item comes
+/// after the targets it depends on, and critical items and their dependencies come first,
+/// keeping the original order otherwise. Targets that aren't among the items are ignored.
+/// Returns the order and how many of the first items in it are critical.
+fn deploy_order(items: &[(&Path, &[PathBuf], bool)]) -> Result<(Vec<usize>, usize)> {
+    let indices = items
+        .iter()
+        .enumerate()
+        .map(|(i, (target, _, _))| (*target, i))
+        .collect::<BTreeMap<_, _>>();
+    let dependencies = items
+        .iter()
+        .map(|(_, after, _)| {
+            after
+                .iter()
+                .filter_map(|a| indices.get(a.as_path()).copied())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut critical = items.iter().map(|(_, _, c)| *c).collect::<Vec<_>>();
+    let mut pending = (0..items.len())
+        .filter(|&i| critical[i])
+        .collect::<Vec<_>>();
+    while let Some(i) = pending.pop() {
+        for &d in &dependencies[i] {
+            if !critical[d] {
+                critical[d] = true;
+                pending.push(d);
+            }
+        }
+    }
+
+    let mut done = vec![false; items.len()];
+    let mut order = Vec::with_capacity(items.len());
+    while order.len() < items.len() {
+        let ready = |i: &usize| !done[*i] && dependencies[*i].iter().all(|&d| done[d]);
+        let next = (0..items.len())
+            .find(|i| critical[*i] && ready(i))
+            .or_else(|| (0..items.len()).find(ready))
+            .ok_or_else(|| {
+                let cycle = (0..items.len())
+                    .filter(|&i| !done[i])
+                    .map(|i| items[i].0)
+                    .collect::<Vec<_>>();
+                anyhow!("circular `after` dependency between {:?}", cycle)
+            })?;
+        done[next] = true;
+        order.push(next);
+    }
+    Ok((order, critical.iter().filter(|&&c| c).count()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -288,6 +1093,144 @@ mod test {
             existing_templates,
             "cache".into(),
         );
-        todo!()
+
+        assert_eq!(
+            state.deleted_files(),
+            (
+                Vec::new(),
+                vec![
+                    TemplateDescription {
+                        source: "file2s".into(),
+                        target: "file2t".into(),
+                        cache: "cache/file2s".into(),
+                        variables: Default::default(),
+                    },
+                    TemplateDescription {
+                        source: "file3s".into(),
+                        target: "file3t".into(),
+                        cache: "cache/file3s".into(),
+                        variables: Default::default(),
+                    }
+                ]
+            ),
+            "deleted files correct"
+        );
+        assert_eq!(
+            state.new_files(),
+            (
+                Vec::new(),
+                vec![
+                    TemplateDescription {
+                        source: "file3s".into(),
+                        target: "file0t".into(),
+                        cache: "cache/file3s".into(),
+                        variables: Default::default(),
+                    },
+                    TemplateDescription {
+                        source: "file5s".into(),
+                        target: "file5t".into(),
+                        cache: "cache/file5s".into(),
+                        variables: Default::default(),
+                    },
+                ]
+            ),
+            "new files correct"
+        );
+        assert_eq!(
+            state.old_files(),
+            (
+                Vec::new(),
+                vec![TemplateDescription {
+                    source: "file1s".into(),
+                    target: "file1t".into(),
+                    cache: "cache/file1s".into(),
+                    variables: Default::default(),
+                }]
+            ),
+            "old files correct"
+        );
+    }
+
+    #[test]
+    fn test_deploy_order() {
+        let none: &[PathBuf] = &[];
+        let after_c = &[PathBuf::from("c")];
+        let after_a = &[PathBuf::from("a"), PathBuf::from("missing")];
+        let order = deploy_order(&[
+            (Path::new("a"), after_c, false),
+            (Path::new("b"), none, false),
+            (Path::new("c"), none, false),
+            (Path::new("d"), after_a, false),
+        ])
+        .unwrap();
+        assert_eq!(order, (vec![1, 2, 0, 3], 0));
+
+        // The critical file comes first, after the file it depends on
+        let order = deploy_order(&[
+            (Path::new("a"), after_c, false),
+            (Path::new("b"), none, false),
+            (Path::new("c"), none, false),
+            (Path::new("d"), after_a, true),
+        ])
+        .unwrap();
+        assert_eq!(order, (vec![2, 0, 3, 1], 3));
+
+        let after_b = &[PathBuf::from("b")];
+        let after_a = &[PathBuf::from("a")];
+        assert!(deploy_order(&[
+            (Path::new("a"), after_b, false),
+            (Path::new("b"), after_a, false)
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_file_state_copies() {
+        let copy = |target: &str| config::CopyTarget {
+            target: target.into(),
+            owner: None,
+            group: None,
+            mode: None,
+            attributes: Vec::new(),
+            acl: Vec::new(),
+            volatile: false,
+            write_once: false,
+            critical: false,
+            allow_overwrite: false,
+            elevate: false,
+            reload: None,
+            service_unit: false,
+            on_deploy: None,
+            on_undeploy: None,
+            directory_owner: None,
+            directory_mode: None,
+            after: Vec::new(),
+            recursive: false,
+            verify: None,
+        };
+        let mut desired_copies = BTreeMap::new();
+        desired_copies.insert("file1s".into(), copy("file1t")); // Same
+        desired_copies.insert("file3s".into(), copy("file3t")); // New
+        let mut existing_copies = BTreeMap::new();
+        existing_copies.insert("file1s".into(), "file1t".into()); // Same
+        existing_copies.insert("file2s".into(), "file2t".into()); // Deleted
+
+        let state = FileState::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            "cache".into(),
+        )
+        .with_copies(desired_copies, existing_copies);
+
+        let sources =
+            |copies: Vec<CopyDescription>| copies.into_iter().map(|c| c.source).collect::<Vec<_>>();
+        assert_eq!(sources(state.old_copies()), vec![PathBuf::from("file1s")]);
+        assert_eq!(sources(state.new_copies()), vec![PathBuf::from("file3s")]);
+        assert_eq!(
+            sources(state.deleted_copies()),
+            vec![PathBuf::from("file2s")]
+        );
     }
 }
@@ -1,14 +1,25 @@
 use anyhow::{Context, Result};
 
+use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::{self, ErrorKind, Read};
+use std::io::{self, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
+use sha2::{Digest, Sha256};
 
 use toml;
 
+use config::{Environment, HashAlgorithm, UnixUser};
+use equivalence::Equivalence;
+use non_interactive;
+use report::Report;
+use secrets;
+
 #[derive(Error, Debug)]
 pub enum FileLoadError {
     #[error("open file")]
@@ -18,7 +29,7 @@ pub enum FileLoadError {
     Read(#[source] io::Error),
 
     #[error("parse file")]
-    Parse(#[source] toml::de::Error),
+    Parse(#[source] Report),
 }
 
 pub fn load_file<T>(filename: &Path) -> Result<T, FileLoadError>
@@ -28,7 +39,8 @@ where
     let mut buf = String::new();
     let mut f = File::open(filename).map_err(FileLoadError::Open)?;
     f.read_to_string(&mut buf).map_err(FileLoadError::Read)?;
-    toml::from_str::<T>(&buf).map_err(FileLoadError::Parse)
+    toml::from_str::<T>(&buf)
+        .map_err(|e| FileLoadError::Parse(Report::from_toml(&e, filename, &buf)))
 }
 
 #[derive(Error, Debug)]
@@ -44,15 +56,50 @@ pub enum FileSaveError {
     ),
 }
 
+/// Writes to a temporary file that then replaces `filename`, so that a concurrent reader
+/// never sees a partially written file
 pub fn save_file<T>(filename: &Path, data: T) -> Result<(), FileSaveError>
 where
     T: Serialize,
 {
     let data = toml::to_string(&data)?;
-    fs::write(filename, &data).map_err(FileSaveError::Write)?;
+    let mut temporary = filename.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = File::create(&temporary).map_err(FileSaveError::Write)?;
+    file.write_all(data.as_bytes())
+        .and_then(|()| file.sync_all())
+        .map_err(FileSaveError::Write)?;
+    replace_file(&temporary, filename).map_err(FileSaveError::Write)?;
     Ok(())
 }
 
+/// Moves `temporary` over `target`, which is in the same directory. Renaming over a file that's
+/// a bind mount fails with EBUSY, and renaming on some overlay filesystems with EXDEV, like in
+/// containers and on immutable distributions. Then the contents are copied into the target
+/// instead, which isn't atomic, but is the only way to change it.
+pub fn replace_file(temporary: &Path, target: &Path) -> io::Result<()> {
+    let error = match fs::rename(temporary, target) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::ResourceBusy || e.kind() == ErrorKind::CrossesDevices => e,
+        Err(e) => return Err(e),
+    };
+    debug!(
+        "Cannot move {:?} over {:?} ({}), it's probably a bind mount or on an overlay filesystem. Copying into it instead.",
+        temporary, target, error
+    );
+    let mut from = File::open(temporary)?;
+    let mut to = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(target)?;
+    io::copy(&mut from, &mut to)?;
+    to.set_permissions(from.metadata()?.permissions())?;
+    to.sync_all()?;
+    fs::remove_file(temporary)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SymlinkComparison {
     Identical,
@@ -61,6 +108,11 @@ pub enum SymlinkComparison {
     TargetNotSymlink,
     Changed,
     BothMissing,
+    /// The target is a symlink that leads back to itself, directly or through other symlinks
+    Circular,
+    /// The target's location is the source file itself, because one of its parent directories
+    /// is a symlink into the repository
+    TargetIsSource,
 }
 
 impl std::fmt::Display for SymlinkComparison {
@@ -73,12 +125,24 @@ impl std::fmt::Display for SymlinkComparison {
             TargetNotSymlink => "target isn't a symlink",
             Changed => "target isn't point at source",
             BothMissing => "source and target are missing",
+            Circular => "target is a circular symlink",
+            TargetIsSource => "target is the source itself",
         }
         .fmt(f)
     }
 }
 
 pub fn compare_symlink(source: &Path, link: &Path) -> Result<SymlinkComparison> {
+    if is_circular_symlink(source) {
+        bail!("source {:?} is a circular symlink", source);
+    }
+    if resolves_to(link, source) {
+        return Ok(SymlinkComparison::TargetIsSource);
+    }
+    if is_circular_symlink(link) {
+        return Ok(SymlinkComparison::Circular);
+    }
+
     let source = match real_path(source) {
         Ok(s) => Some(s),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
@@ -96,7 +160,40 @@ pub fn compare_symlink(source: &Path, link: &Path) -> Result<SymlinkComparison>
 
     Ok(match (source, link_content) {
         (Some(s), Some(l)) => {
-            if s == l {
+            if paths_equal(&s, &l) {
+                SymlinkComparison::Identical
+            } else {
+                SymlinkComparison::Changed
+            }
+        }
+        (None, Some(_)) => SymlinkComparison::OnlyTargetExists,
+        (Some(_), None) => SymlinkComparison::OnlySourceExists,
+        (None, None) => SymlinkComparison::BothMissing,
+    })
+}
+
+/// Like `compare_symlink`, for a hardlink: it's identical if it's the same file as the source.
+/// Where that can't be told, it's identical if it has the same contents.
+pub fn compare_hardlink(source: &Path, link: &Path) -> Result<SymlinkComparison> {
+    if paths_equal(source, link) {
+        return Ok(SymlinkComparison::TargetIsSource);
+    }
+    let metadata = |path: &Path| match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let source_metadata = metadata(source).context("read metadata of source")?;
+    let link_metadata = metadata(link).context("read metadata of link")?;
+
+    Ok(match (source_metadata, link_metadata) {
+        (Some(_), Some(l)) if !l.is_file() => SymlinkComparison::Changed,
+        (Some(s), Some(l)) => {
+            let same = match same_file(&s, &l) {
+                Some(same) => same,
+                None => hash_file(source)? == hash_file(link)?,
+            };
+            if same {
                 SymlinkComparison::Identical
             } else {
                 SymlinkComparison::Changed
@@ -108,6 +205,10 @@ pub fn compare_symlink(source: &Path, link: &Path) -> Result<SymlinkComparison>
     })
 }
 
+pub fn make_hardlink(link: &Path, target: &Path) -> Result<()> {
+    fs::hard_link(target, long_path(link)).context("create hardlink")
+}
+
 #[derive(Debug, PartialEq)]
 pub enum TemplateComparison {
     Identical,
@@ -131,9 +232,18 @@ impl std::fmt::Display for TemplateComparison {
     }
 }
 
-pub fn compare_template(target: &Path, cache: &Path) -> Result<TemplateComparison> {
-    let target = match fs::read_to_string(target) {
-        Ok(t) => Some(t),
+/// Target and cache count as identical if they're equivalent according to `equivalence`
+pub fn compare_template(
+    target: &Path,
+    cache: &Path,
+    equivalence: &Equivalence,
+) -> Result<TemplateComparison> {
+    let target = match fs::read(target) {
+        // A target that isn't text never matches, and is replaced with --force
+        Ok(t) => Some(
+            String::from_utf8(t)
+                .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        ),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => Err(e).context("read content of target file")?,
     };
@@ -146,7 +256,11 @@ pub fn compare_template(target: &Path, cache: &Path) -> Result<TemplateCompariso
 
     Ok(match (target, cache) {
         (Some(t), Some(c)) => {
-            if t == c {
+            let identical = match secrets::matches_hash_marker(&c, &t) {
+                Some(identical) => identical,
+                None => equivalence.equivalent(&t, &c)?,
+            };
+            if identical {
                 TemplateComparison::Identical
             } else {
                 TemplateComparison::Changed
@@ -158,11 +272,300 @@ pub fn compare_template(target: &Path, cache: &Path) -> Result<TemplateCompariso
     })
 }
 
+/// Home directory given by `--home`, which replaces the real one everywhere
+static HOME_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes `~` expand to `home` for the rest of the run. Only the first call has an effect.
+pub fn set_home(home: PathBuf) {
+    let _ = HOME_OVERRIDE.set(home);
+}
+
+pub fn home_override() -> Option<&'static Path> {
+    HOME_OVERRIDE.get().map(PathBuf::as_path)
+}
+
+/// The directory that `~` expands to
+pub fn home_dir() -> PathBuf {
+    match home_override() {
+        Some(home) => home.to_path_buf(),
+        None => PathBuf::from(shellexpand::tilde("~").as_ref()),
+    }
+}
+
+/// Expands a leading `~` the way a shell does, honoring `--home`
+pub fn expand_tilde(path: &str) -> String {
+    shellexpand::tilde_with_context(path, || Some(home_dir())).into_owned()
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config` if it isn't set or `--home` is used
+pub fn xdg_config_home() -> PathBuf {
+    match (home_override(), std::env::var_os("XDG_CONFIG_HOME")) {
+        (None, Some(config)) => PathBuf::from(config),
+        _ => home_dir().join(".config"),
+    }
+}
+
+/// `$XDG_DATA_HOME`, or `~/.local/share` if it isn't set or `--home` is used
+pub fn xdg_data_home() -> PathBuf {
+    match (home_override(), std::env::var_os("XDG_DATA_HOME")) {
+        (None, Some(data)) => PathBuf::from(data),
+        _ => home_dir().join(".local").join("share"),
+    }
+}
+
+/// Variables of the configuration's `[env]` tables
+static ENVIRONMENT: Mutex<Environment> = Mutex::new(BTreeMap::new());
+
+/// Sets the variables that child processes get from now on.
+/// `dotter watch` calls it again whenever it reloads the configuration.
+pub fn set_environment(environment: Environment) {
+    *ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner()) = environment;
+}
+
+/// Expands `~` and `$VARIABLE` in the value of an `[env]` variable, so that `PATH` can be
+/// extended with `~/.local/bin:$PATH`
+pub fn expand_environment(value: &str) -> Result<String> {
+    shellexpand::full_with_context(
+        value,
+        || Some(home_dir()),
+        |name| std::env::var(name).map(Some),
+    )
+    .map(|expanded| expanded.into_owned())
+    .map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+/// Child processes see the same home directory as dotter, the variables of `[env]`, and
+/// `DOTTER_NON_INTERACTIVE=1` under --non-interactive
+pub fn set_child_environment(command: &mut Command) {
+    if let Some(home) = home_override() {
+        command
+            .env("HOME", home)
+            .env("XDG_CONFIG_HOME", home.join(".config"));
+    }
+    if non_interactive::enabled() {
+        command.env("DOTTER_NON_INTERACTIVE", "1");
+    }
+    command.envs(ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner()).iter());
+}
+
+/// Runs the command like `Command::output`, but kills it once it's still running at `deadline`.
+/// Only the pipes that the command was given are read.
+pub fn output_until(command: &mut Command, deadline: Option<Instant>) -> io::Result<Output> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return command.output(),
+    };
+    wait_with_output_until(command.spawn()?, deadline)
+}
+
+/// Waits for the child like `Child::wait_with_output`, but kills it once it's still running at
+/// `deadline`
+pub fn wait_with_output_until(mut child: Child, deadline: Instant) -> io::Result<Output> {
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "it was stopped after running out of time",
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Reads the pipe to its end, so that a child that fills it doesn't block
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut contents = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut contents);
+        }
+        contents
+    })
+}
+
+/// Algorithm of the hashes that are recorded to notice changes to targets, from the
+/// `hash_algorithm` of global.toml
+static HASH_ALGORITHM: Mutex<HashAlgorithm> = Mutex::new(HashAlgorithm::Blake3);
+
+pub fn set_hash_algorithm(algorithm: HashAlgorithm) {
+    *HASH_ALGORITHM.lock().unwrap_or_else(|e| e.into_inner()) = algorithm;
+}
+
+/// Hashes `contents` with the configured algorithm, as `<algorithm>:<hex>`. Hashes are of the
+/// bytes as they are, without normalizing line endings, so they're the same on every platform.
+pub fn hash_bytes(contents: &[u8]) -> String {
+    let algorithm = *HASH_ALGORITHM.lock().unwrap_or_else(|e| e.into_inner());
+    hash_bytes_with(algorithm, contents)
+}
+
+pub fn hash_bytes_with(algorithm: HashAlgorithm, contents: &[u8]) -> String {
+    let hex = match algorithm {
+        HashAlgorithm::Blake3 => blake3::hash(contents).to_hex().to_string(),
+        HashAlgorithm::Sha256 => Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    };
+    format!("{}:{}", algorithm.name(), hex)
+}
+
+/// The algorithm of a hash from `hash_bytes`. Hashes without a tag were recorded before there
+/// was a choice, with blake3.
+fn algorithm_of(hash: &str) -> Option<HashAlgorithm> {
+    match hash.split_once(':') {
+        Some((name, _)) => name.parse().ok(),
+        None => Some(HashAlgorithm::Blake3),
+    }
+}
+
+/// The hash without its algorithm
+fn hash_hex(hash: &str) -> &str {
+    hash.split_once(':').map_or(hash, |(_, hex)| hex)
+}
+
+/// Whether `contents` have the recorded `hash`, whichever algorithm it was recorded with
+pub fn hash_matches(hash: &str, contents: &[u8]) -> bool {
+    match algorithm_of(hash) {
+        Some(algorithm) => hash_hex(&hash_bytes_with(algorithm, contents)) == hash_hex(hash),
+        None => false,
+    }
+}
+
+/// Whether two hashes are of the same contents. Hashes of different algorithms can't tell,
+/// so the recorded one is compared with `contents` instead.
+pub fn same_hash(
+    recorded: &str,
+    hash: &str,
+    contents: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<bool> {
+    if algorithm_of(recorded) == algorithm_of(hash) {
+        return Ok(hash_hex(recorded) == hash_hex(hash));
+    }
+    Ok(hash_matches(recorded, &contents()?))
+}
+
+/// Hashes the contents of a file with `hash_bytes`, following symlinks.
+/// Returns `None` if the file doesn't exist or is a circular symlink.
+pub fn hash_file(path: &Path) -> Result<Option<String>> {
+    if is_circular_symlink(path) {
+        return Ok(None);
+    }
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(hash_bytes(&contents))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("read file contents"),
+    }
+}
+
 pub fn real_path(path: &Path) -> Result<PathBuf, io::Error> {
-    let path = std::fs::canonicalize(&path)?;
+    let path = std::fs::canonicalize(path)?;
     Ok(platform_dunce(path))
 }
 
+/// Follows the chain of symlinks that starts at `path`, and returns true if it visits
+/// a link twice
+pub fn is_circular_symlink(path: &Path) -> bool {
+    let mut visited = std::collections::BTreeSet::new();
+    let mut current = path.to_path_buf();
+    while let Ok(target) = fs::read_link(&current) {
+        let next = match current.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+        if !visited.insert(current) {
+            return true;
+        }
+        current = next;
+    }
+    false
+}
+
+/// Whether `path` refers to the same file as `other` once the symlinks in its parent
+/// directories are resolved
+pub fn resolves_to(path: &Path, other: &Path) -> bool {
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => (parent, name),
+        _ => return false,
+    };
+    match (real_path(parent), real_path(other)) {
+        (Ok(parent), Ok(other)) => paths_equal(&parent.join(name), &other),
+        _ => false,
+    }
+}
+
+/// Whether `link` is a symlink to something inside `directory`
+pub fn points_into(link: &Path, directory: &Path) -> bool {
+    match (fs::read_link(link), real_path(directory)) {
+        (Ok(target), Ok(directory)) => target.starts_with(directory),
+        _ => false,
+    }
+}
+
+/// Compares paths the way the platform's filesystem would
+/// (ignoring verbatim prefixes and case on Windows)
+pub fn paths_equal(a: &Path, b: &Path) -> bool {
+    normalize_for_comparison(a) == normalize_for_comparison(b)
+}
+
+/// Whether the path is a symlink or, on Windows, a junction
+pub fn is_link(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Whether symlinks have permissions and file flags of their own, which `lchmod` and
+/// `chflags -h` change. Elsewhere they're always `777`, and `chmod` follows them.
+pub const SYMLINK_PERMISSIONS: bool = cfg!(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+));
+
+#[cfg(windows)]
+pub fn is_executable(name: &str) -> Result<bool, std::io::Error> {
+    let name = if name.ends_with(".exe") {
+        name.to_string()
+    } else {
+        format!("{}.exe", name)
+    };
+
+    Command::new("where")
+        .arg(name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+}
+
+#[cfg(unix)]
+pub fn is_executable(name: &str) -> Result<bool, std::io::Error> {
+    Command::new("which")
+        .arg(name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+}
+
 pub fn ask_boolean(prompt: &str) -> bool {
     let mut buf = String::from("a"); // enter the loop at least once
     while !(buf.to_lowercase().starts_with('y')
@@ -182,7 +585,9 @@ pub fn ask_boolean(prompt: &str) -> bool {
 
 pub fn delete_parents(path: &Path, ask: bool) -> Result<()> {
     let mut path = path.parent().context("get parent")?;
-    while path.is_dir()
+    // Linked directories (and junctions) were not created by us, so they are never deleted
+    while !is_link(path)
+        && path.is_dir()
         && path
             .read_dir()
             .context("read the contents of parent directory")?
@@ -202,6 +607,39 @@ pub fn delete_parents(path: &Path, ask: bool) -> Result<()> {
     Ok(())
 }
 
+/// Like `delete_parents` without asking, but leaves `root` and the directories above it
+pub fn delete_parents_within(path: &Path, root: &Path) -> Result<()> {
+    let mut path = path.parent().context("get parent")?;
+    while path != root
+        && path.starts_with(root)
+        && !is_link(path)
+        && path.is_dir()
+        && path
+            .read_dir()
+            .context("read the contents of parent directory")?
+            .next()
+            .is_none()
+    {
+        fs::remove_dir(path).context(format!("remove directory {:?}", path))?;
+        path = path.parent().context(format!("get parent of {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Copies a file, or a directory and all of its contents
+pub fn copy_recursively(from: &Path, to: &Path) -> Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to).context("create directory")?;
+        for child in fs::read_dir(from).context("read contents of directory")? {
+            let child = child.context("get next file")?;
+            copy_recursively(&child.path(), &to.join(child.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to).with_context(|| format!("copy file {:?}", from))?;
+    }
+    Ok(())
+}
+
 pub fn copy_permissions(source: &Path, target: &Path) -> Result<()> {
     fs::set_permissions(
         target,
@@ -213,21 +651,83 @@ pub fn copy_permissions(source: &Path, target: &Path) -> Result<()> {
     Ok(())
 }
 
+/// The ancestors of `path` that don't exist yet, including itself, outermost first
+pub fn missing_directories(path: &Path) -> Vec<&Path> {
+    let mut missing = path
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !long_path(p).exists())
+        .collect::<Vec<_>>();
+    missing.reverse();
+    missing
+}
+
+/// Like `fs::create_dir_all`, but every directory that is created gets the owner and mode.
+/// A directory that can't be given its owner is removed again, so that it can be created
+/// with sudo instead.
+pub fn create_dir_all_with(path: &Path, owner: &Option<UnixUser>, mode: Option<u32>) -> Result<()> {
+    for directory in missing_directories(path) {
+        fs::create_dir(long_path(directory))
+            .with_context(|| format!("create directory {:?}", directory))?;
+        if let Some(mode) = mode {
+            set_mode(&long_path(directory), mode)
+                .with_context(|| format!("set permissions of directory {:?}", directory))?;
+        }
+        if let Some(owner) = owner {
+            if let Err(e) = set_owner(&long_path(directory), owner) {
+                let _ = fs::remove_dir(long_path(directory));
+                return Err(e).with_context(|| format!("set owner of directory {:?}", directory));
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(windows)]
 mod filesystem_impl {
     use anyhow::{Context, Result};
     use dunce;
 
+    use std::ffi::OsString;
     use std::fs::remove_file;
     use std::os::windows::fs;
     use std::path::{Path, PathBuf};
 
+    use config::{FileAttribute, UnixUser};
+
+    /// Paths at least this long need the verbatim prefix to be usable by the Windows API
+    const MAX_PATH: usize = 260;
+
     pub fn make_symlink(link: &Path, target: &Path) -> Result<()> {
-        Ok(fs::symlink_file(
+        fs::symlink_file(
             super::real_path(target).context("get real path of source file")?,
-            link,
+            long_path(link),
         )
-        .context("create symlink")?)
+        .context("create symlink")
+    }
+
+    /// Junctions are created by `mklink`, since the standard library can't
+    pub fn make_junction(link: &Path, target: &Path) -> Result<()> {
+        let target = super::real_path(target).context("get real path of source directory")?;
+        let output = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(long_path(link))
+            .arg(&target)
+            .output()
+            .context("run mklink")?;
+        if !output.status.success() {
+            bail!(
+                "mklink failed: {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Windows doesn't expose the file index of a file without reaching into its API
+    pub fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> Option<bool> {
+        None
     }
 
     pub fn symlinks_enabled(test_file_path: &Path) -> Result<bool> {
@@ -256,21 +756,175 @@ mod filesystem_impl {
     pub fn platform_dunce(path: PathBuf) -> PathBuf {
         dunce::simplified(&path).into()
     }
+
+    pub fn long_path(path: &Path) -> PathBuf {
+        let string = path.to_string_lossy();
+        if !path.is_absolute() || string.len() < MAX_PATH || string.starts_with(r"\\") {
+            return path.into();
+        }
+        // Verbatim paths are passed to the filesystem as-is, so they can't contain forward slashes
+        let mut long = OsString::from(r"\\?\");
+        long.push(string.replace('/', r"\"));
+        long.into()
+    }
+
+    pub fn normalize_for_comparison(path: &Path) -> PathBuf {
+        let string = path.to_string_lossy().replace('/', r"\");
+        let string = if let Some(rest) = string.strip_prefix(r"\\?\UNC\") {
+            format!(r"\\{}", rest)
+        } else if let Some(rest) = string
+            .strip_prefix(r"\\?\")
+            .or_else(|| string.strip_prefix(r"\??\"))
+        {
+            rest.to_string()
+        } else {
+            string
+        };
+        string.to_lowercase().into()
+    }
+
+    pub fn file_mode(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn file_owner(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn current_user() -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn user_name(_uid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn resolve_user(_user: &UnixUser) -> Result<u32> {
+        bail!("file owners are only supported on unix");
+    }
+
+    pub fn set_owner(_path: &Path, _user: &UnixUser) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn file_group(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn resolve_group(_group: &UnixUser) -> Result<u32> {
+        bail!("file groups are only supported on unix");
+    }
+
+    pub fn set_group(_path: &Path, _group: &UnixUser) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn symlink_mode(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn set_symlink_mode(_path: &Path, _mode: u32) -> Result<()> {
+        bail!("symlinks have no permissions of their own on this platform");
+    }
+
+    pub fn set_symlink_flags(_path: &Path, _flags: &[String]) -> Result<()> {
+        bail!("symlinks have no file flags of their own on this platform");
+    }
+
+    pub fn symlink_flags(_path: &Path) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
+    pub fn clear_symlink_flags(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn make_fifo(_path: &Path, _mode: u32) -> Result<()> {
+        bail!("named pipes can't be created on this platform");
+    }
+
+    pub fn is_fifo(_path: &Path) -> bool {
+        false
+    }
+
+    /// Attributes are set by `attrib`, like `attrib +H +R file`
+    pub fn set_attributes(path: &Path, attributes: &[FileAttribute]) -> Result<()> {
+        let mut command = std::process::Command::new("attrib");
+        for attribute in attributes {
+            command.arg(match attribute {
+                FileAttribute::Hidden => "+H",
+                FileAttribute::ReadOnly => "+R",
+                FileAttribute::System => "+S",
+            });
+        }
+        run_attrib(command.arg(long_path(path)))
+    }
+
+    /// Clears the attributes that `set_attributes` can set, so that the file can be written
+    /// or deleted. They're cleared together since `attrib` won't clear one of them alone on a
+    /// hidden or system file.
+    pub fn clear_attributes(path: &Path) -> Result<()> {
+        let mut command = std::process::Command::new("attrib");
+        run_attrib(command.args(&["-H", "-R", "-S"]).arg(long_path(path)))
+    }
+
+    fn run_attrib(command: &mut std::process::Command) -> Result<()> {
+        let output = command.output().context("run attrib")?;
+        // attrib exits successfully even when it fails, so it's only known by its output
+        let message = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || !message.trim().is_empty() {
+            bail!("attrib failed: {}", message.trim());
+        }
+        Ok(())
+    }
+
+    /// ACL entries are granted by `icacls`, like `icacls file /grant Users:(R)`
+    pub fn grant_acl(path: &Path, entries: &[String]) -> Result<()> {
+        let mut command = std::process::Command::new("icacls");
+        command.arg(long_path(path));
+        for entry in entries {
+            command.arg("/grant").arg(entry);
+        }
+        let output = command.output().context("run icacls")?;
+        if !output.status.success() {
+            let mut message = String::from_utf8_lossy(&output.stderr).into_owned();
+            message.push_str(&String::from_utf8_lossy(&output.stdout));
+            bail!("icacls failed: {}", message.trim());
+        }
+        Ok(())
+    }
 }
 
 #[cfg(unix)]
 mod filesystem_impl {
     use anyhow::{Context, Result};
 
-    use std::os::unix::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{self, FileTypeExt, MetadataExt, PermissionsExt};
     use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    use config::{FileAttribute, UnixUser};
 
     pub fn make_symlink(link: &Path, target: &Path) -> Result<()> {
-        Ok(fs::symlink(
+        fs::symlink(
             super::real_path(target).context("get real path of source file")?,
             link,
         )
-        .context("create symlink")?)
+        .context("create symlink")
+    }
+
+    /// Junctions only exist on Windows, a symlink is the same thing here
+    pub fn make_junction(link: &Path, target: &Path) -> Result<()> {
+        make_symlink(link, target)
+    }
+
+    pub fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> Option<bool> {
+        Some(a.dev() == b.dev() && a.ino() == b.ino())
     }
 
     pub fn symlinks_enabled(_test_file_path: &Path) -> Result<bool> {
@@ -280,22 +934,423 @@ mod filesystem_impl {
     pub fn platform_dunce(path: PathBuf) -> PathBuf {
         path
     }
+
+    pub fn long_path(path: &Path) -> PathBuf {
+        path.into()
+    }
+
+    pub fn normalize_for_comparison(path: &Path) -> PathBuf {
+        // The filesystems of macOS are case-insensitive unless they were formatted otherwise
+        if cfg!(target_os = "macos") {
+            path.to_string_lossy().to_lowercase().into()
+        } else {
+            path.into()
+        }
+    }
+
+    pub fn file_mode(path: &Path) -> Result<Option<u32>> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.permissions().mode() & 0o7777)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read metadata"),
+        }
+    }
+
+    pub fn file_owner(path: &Path) -> Result<Option<u32>> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.uid())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read metadata"),
+        }
+    }
+
+    pub fn current_user() -> Result<Option<u32>> {
+        let output = Command::new("id").arg("-u").output().context("run `id`")?;
+        if !output.status.success() {
+            bail!("`id -u` exited with {}", output.status);
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .map(Some)
+            .context("parse uid of current user")
+    }
+
+    /// Name of the user, if there is one
+    pub fn user_name(uid: u32) -> Option<String> {
+        let output = Command::new("id")
+            .arg("-nu")
+            .arg(uid.to_string())
+            .output()
+            .ok()?;
+        let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !name.is_empty() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    pub fn resolve_user(user: &UnixUser) -> Result<u32> {
+        match user {
+            UnixUser::Uid(uid) => Ok(*uid as u32),
+            UnixUser::Name(name) => {
+                let output = Command::new("id")
+                    .arg("-u")
+                    .arg(name)
+                    .output()
+                    .context("run `id`")?;
+                if !output.status.success() {
+                    bail!("user {:?} doesn't exist", name);
+                }
+                String::from_utf8_lossy(&output.stdout)
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("parse uid of user {:?}", name))
+            }
+        }
+    }
+
+    pub fn set_owner(path: &Path, user: &UnixUser) -> Result<()> {
+        fs::lchown(path, Some(resolve_user(user)?), None).context("change owner")
+    }
+
+    pub fn file_group(path: &Path) -> Result<Option<u32>> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.gid())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read metadata"),
+        }
+    }
+
+    /// Group names are looked up with `getent`, whose entries are `name:password:gid:members`
+    pub fn resolve_group(group: &UnixUser) -> Result<u32> {
+        match group {
+            UnixUser::Uid(gid) => Ok(*gid as u32),
+            UnixUser::Name(name) => {
+                let output = Command::new("getent")
+                    .arg("group")
+                    .arg(name)
+                    .output()
+                    .context("run `getent`")?;
+                if !output.status.success() {
+                    bail!("group {:?} doesn't exist", name);
+                }
+                String::from_utf8_lossy(&output.stdout)
+                    .trim()
+                    .split(':')
+                    .nth(2)
+                    .context("read gid from group entry")?
+                    .parse()
+                    .with_context(|| format!("parse gid of group {:?}", name))
+            }
+        }
+    }
+
+    pub fn set_group(path: &Path, group: &UnixUser) -> Result<()> {
+        fs::lchown(path, None, Some(resolve_group(group)?)).context("change group")
+    }
+
+    pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .context("change permissions")
+    }
+
+    /// Permissions of the symlink itself rather than of the file it points at
+    pub fn symlink_mode(path: &Path) -> Result<Option<u32>> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.permissions().mode() & 0o7777)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read metadata"),
+        }
+    }
+
+    /// The standard library has no `lchmod`, but `chmod -h` is one where symlinks have
+    /// permissions
+    pub fn set_symlink_mode(path: &Path, mode: u32) -> Result<()> {
+        if !super::SYMLINK_PERMISSIONS {
+            bail!(
+                "symlinks have no permissions of their own on {}",
+                std::env::consts::OS
+            );
+        }
+        run_on_path(
+            Command::new("chmod").arg("-h").arg(format!("{:o}", mode)),
+            path,
+        )
+        .context("change permissions of symlink")
+    }
+
+    pub fn set_symlink_flags(path: &Path, flags: &[String]) -> Result<()> {
+        if !super::SYMLINK_PERMISSIONS {
+            bail!(
+                "symlinks have no file flags of their own on {}",
+                std::env::consts::OS
+            );
+        }
+        run_on_path(Command::new("chflags").arg("-h").arg(flags.join(",")), path)
+            .context("change file flags of symlink")
+    }
+
+    /// File flags of the symlink itself, by the names that `chflags` takes. `None` if there's
+    /// no symlink, or if symlinks have no flags on this platform.
+    pub fn symlink_flags(path: &Path) -> Result<Option<Vec<String>>> {
+        if !super::SYMLINK_PERMISSIONS || !super::is_link(path) {
+            return Ok(None);
+        }
+        // BSD `stat` describes the link itself unless it's given `-L`
+        let output = Command::new("stat")
+            .arg("-f")
+            .arg("%Sf")
+            .arg("--")
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .context("run stat")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        let flags = String::from_utf8_lossy(&output.stdout);
+        Ok(Some(
+            flags
+                .trim()
+                .split(',')
+                .filter(|flag| !flag.is_empty() && *flag != "-")
+                .map(String::from)
+                .collect(),
+        ))
+    }
+
+    /// Clears every file flag of the symlink, since an immutable one like `uchg` can't be
+    /// removed, replaced or given another mode
+    pub fn clear_symlink_flags(path: &Path) -> Result<()> {
+        if !super::SYMLINK_PERMISSIONS || !super::is_link(path) {
+            return Ok(());
+        }
+        run_on_path(Command::new("chflags").arg("-h").arg("0"), path)
+            .context("clear file flags of symlink")
+    }
+
+    /// Named pipes have no constructor in the standard library
+    pub fn make_fifo(path: &Path, mode: u32) -> Result<()> {
+        run_on_path(
+            Command::new("mkfifo").arg("-m").arg(format!("{:o}", mode)),
+            path,
+        )
+        .context("create named pipe")
+    }
+
+    pub fn is_fifo(path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+
+    fn run_on_path(command: &mut Command, path: &Path) -> Result<()> {
+        let output = command
+            .arg("--")
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .context("run command")?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    }
+
+    /// Windows attributes have nothing to map to on unix, so they're left out
+    pub fn set_attributes(path: &Path, attributes: &[FileAttribute]) -> Result<()> {
+        debug!("Ignoring attributes {:?} of {:?} on unix", attributes, path);
+        Ok(())
+    }
+
+    pub fn clear_attributes(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Windows ACL entries have nothing to map to on unix, so they're left out
+    pub fn grant_acl(path: &Path, entries: &[String]) -> Result<()> {
+        debug!("Ignoring ACL entries {:?} of {:?} on unix", entries, path);
+        Ok(())
+    }
 }
 
 #[cfg(not(any(unix, windows)))]
 mod filesystem_impl {
-    use std::path::Path;
-    pub fn make_symlink(link: &Path, target: &Path) {
-        panic!("Unsupported platform: neither unix nor windows");
+    use anyhow::Result;
+
+    use std::path::{Path, PathBuf};
+
+    use config::{FileAttribute, UnixUser};
+
+    pub fn make_symlink(_link: &Path, _target: &Path) -> Result<()> {
+        bail!("Unsupported platform: neither unix nor windows");
+    }
+
+    pub fn make_junction(_link: &Path, _target: &Path) -> Result<()> {
+        bail!("Unsupported platform: neither unix nor windows");
+    }
+
+    pub fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> Option<bool> {
+        None
     }
 
     pub fn symlinks_enabled(_test_file_path: &Path) -> Result<bool> {
-        panic!("Unsupported platform: neither unix nor windows");
+        Ok(false)
     }
 
     pub fn platform_dunce(path: PathBuf) -> PathBuf {
-        panic!("Unsupported platform: neither unix nor windows");
+        path
+    }
+
+    pub fn long_path(path: &Path) -> PathBuf {
+        path.into()
+    }
+
+    pub fn normalize_for_comparison(path: &Path) -> PathBuf {
+        path.into()
+    }
+
+    pub fn file_mode(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn file_owner(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn current_user() -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn user_name(_uid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn resolve_user(_user: &UnixUser) -> Result<u32> {
+        bail!("file owners are only supported on unix");
+    }
+
+    pub fn set_owner(_path: &Path, _user: &UnixUser) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn file_group(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn resolve_group(_group: &UnixUser) -> Result<u32> {
+        bail!("file groups are only supported on unix");
+    }
+
+    pub fn set_group(_path: &Path, _group: &UnixUser) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn symlink_mode(_path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn set_symlink_mode(_path: &Path, _mode: u32) -> Result<()> {
+        bail!("symlinks have no permissions of their own on this platform");
+    }
+
+    pub fn set_symlink_flags(_path: &Path, _flags: &[String]) -> Result<()> {
+        bail!("symlinks have no file flags of their own on this platform");
+    }
+
+    pub fn symlink_flags(_path: &Path) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
+    pub fn clear_symlink_flags(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn make_fifo(_path: &Path, _mode: u32) -> Result<()> {
+        bail!("named pipes can't be created on this platform");
+    }
+
+    pub fn is_fifo(_path: &Path) -> bool {
+        false
+    }
+
+    pub fn set_attributes(_path: &Path, _attributes: &[FileAttribute]) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn clear_attributes(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn grant_acl(_path: &Path, _entries: &[String]) -> Result<()> {
+        Ok(())
     }
 }
 
 pub use self::filesystem_impl::*;
+
+/// Writes a file that only its owner may read at any point, like a decrypted secret: a new
+/// file is created with mode 600, and an existing one is made private before it's written.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Recorded hashes have to stay valid across platforms and versions
+    #[test]
+    fn test_hashes() {
+        let sha256 = hash_bytes_with(HashAlgorithm::Sha256, b"abc");
+        assert_eq!(
+            sha256,
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let blake3 = hash_bytes_with(HashAlgorithm::Blake3, b"abc");
+        assert!(blake3.starts_with("blake3:"));
+
+        assert!(hash_matches(&sha256, b"abc"));
+        assert!(!hash_matches(&sha256, b"abd"));
+        // Recorded before hashes were tagged
+        assert!(hash_matches(hash_hex(&blake3), b"abc"));
+        assert!(!hash_matches(
+            "md5:900150983cd24fb0d6963f7d28e17f72",
+            b"abc"
+        ));
+
+        let read = || Ok(b"abc".to_vec());
+        assert!(same_hash(hash_hex(&blake3), &blake3, read).unwrap());
+        assert!(same_hash(&sha256, &blake3, read).unwrap());
+        assert!(!same_hash(&sha256, &blake3, || Ok(b"abd".to_vec())).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("dotter-test-private-{}", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use args::Options;
+use config::Variables;
+use filesystem;
+
+/// A machine that the repository is deployed to, as declared in hosts.toml:
+///
+/// ```toml
+/// [laptop]
+/// local_config = ".dotter/laptop.toml"
+///
+/// [server]
+/// ssh = "me@server.example.com"
+/// directory = "~/dotfiles"
+/// local_config = ".dotter/server.toml"
+/// [server.variables]
+/// email = "me@work.example.com"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Host {
+    /// Destination that `ssh` connects to. Hosts without one are this machine.
+    ssh: Option<String>,
+    /// Checkout of the repository on an SSH host
+    directory: Option<String>,
+    /// dotter executable on an SSH host
+    #[serde(default = "default_command")]
+    command: String,
+    /// local.toml of the host, relative to the repository
+    #[serde(default = "default_local_config")]
+    local_config: PathBuf,
+    /// Variables that are merged on top of the host's configuration, like a `--patch`
+    #[serde(default)]
+    variables: Variables,
+}
+
+fn default_command() -> String {
+    "dotter".into()
+}
+
+fn default_local_config() -> PathBuf {
+    ".dotter/local.toml".into()
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Succeeded,
+    Failed(String),
+    /// `ssh` couldn't connect, it exits with 255 then
+    Unreachable,
+}
+
+struct Report {
+    host: String,
+    outcome: Outcome,
+    /// Counts from the summary that the host's dotter printed
+    changes: Option<String>,
+}
+
+/// Deploys to every host in turn, or only does a dry run on each of them, and prints a table
+/// of the results. Returns whether every host succeeded.
+pub fn deploy(opt: &Options, hosts_file: &Path, only: &[String]) -> Result<bool> {
+    let mut hosts: BTreeMap<String, Host> = filesystem::load_file(hosts_file)
+        .with_context(|| format!("load hosts from {:?}", hosts_file))?;
+    if let Some(unknown) = only.iter().find(|name| !hosts.contains_key(*name)) {
+        bail!("host {:?} isn't declared in {:?}", unknown, hosts_file);
+    }
+    if !only.is_empty() {
+        hosts.retain(|name, _| only.contains(name));
+    }
+
+    let mut reports = Vec::new();
+    for (name, host) in hosts {
+        println!("[{}]", name.as_str().yellow());
+        let (outcome, changes) = match deploy_host(opt, &host) {
+            Ok(result) => result,
+            Err(e) => (Outcome::Failed(format!("{:#}", e)), None),
+        };
+        reports.push(Report {
+            host: name,
+            outcome,
+            changes,
+        });
+    }
+
+    print_reports(&reports, opt.act);
+    Ok(reports
+        .iter()
+        .all(|r| matches!(r.outcome, Outcome::Succeeded)))
+}
+
+fn deploy_host(opt: &Options, host: &Host) -> Result<(Outcome, Option<String>)> {
+    let mut args = vec![
+        "--local-config".to_string(),
+        host.local_config.to_string_lossy().into_owned(),
+        "--noconfirm".into(),
+        "--summary-only".into(),
+    ];
+    if !opt.act {
+        args.push("--dry-run".into());
+    }
+    match &opt.force {
+        Some(patterns) if patterns.is_empty() => args.push("--force".into()),
+        Some(patterns) => args.push(format!("--force={}", patterns.join(","))),
+        None => {}
+    }
+    for _ in 0..opt.verbosity {
+        args.push("--verbose".into());
+    }
+    let patch = if host.variables.is_empty() {
+        None
+    } else {
+        args.push("--patch".into());
+        let mut patch = toml::value::Table::new();
+        patch.insert(
+            "variables".into(),
+            toml::Value::Table(host.variables.clone()),
+        );
+        Some(toml::to_string(&patch).context("serialize variables of host")?)
+    };
+
+    let mut command = match &host.ssh {
+        Some(destination) => {
+            let directory = host
+                .directory
+                .as_deref()
+                .context("SSH hosts need the `directory` of the repository")?;
+            let mut command = Command::new("ssh");
+            command
+                .args(["-o", "BatchMode=yes"])
+                .arg(destination)
+                .arg(remote_command(directory, &host.command, &args));
+            command
+        }
+        None => {
+            if host.directory.is_some() {
+                bail!("`directory` is only used by SSH hosts, this machine deploys the current repository");
+            }
+            let mut command =
+                Command::new(std::env::current_exe().context("find dotter executable")?);
+            command
+                .arg("--global-config")
+                .arg(&opt.global_config)
+                .args(&args);
+            command
+        }
+    };
+    debug!("Running {:?}", command);
+    let mut child = command
+        .stdin(if patch.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("spawn dotter")?;
+    if let Some(patch) = patch {
+        child
+            .stdin
+            .take()
+            .context("open standard input of dotter")?
+            .write_all(patch.as_bytes())
+            .context("write variables of host")?;
+    }
+    let output = child.wait_with_output().context("wait for dotter")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    print!("{}", stdout);
+    let changes = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Total: "))
+        .map(String::from);
+    let outcome = match output.status.code() {
+        Some(0) => Outcome::Succeeded,
+        Some(255) if host.ssh.is_some() => Outcome::Unreachable,
+        _ => Outcome::Failed(format!("exited with {}", output.status)),
+    };
+    Ok((outcome, changes))
+}
+
+/// The shell command that `ssh` runs on the host. A leading `~/` of the directory is left
+/// unquoted so that the remote shell expands it.
+fn remote_command(directory: &str, dotter: &str, args: &[String]) -> String {
+    let directory = match directory.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(directory),
+    };
+    let mut command = format!("cd {} && {}", directory, shell_quote(dotter));
+    for arg in args {
+        command.push(' ');
+        command.push_str(&shell_quote(arg));
+    }
+    command
+}
+
+/// Quotes a word for a POSIX shell, leaving words that don't need quoting as they are
+pub fn shell_quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=,@:".contains(c))
+    {
+        word.into()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+fn print_reports(reports: &[Report], act: bool) {
+    let width = reports.iter().map(|r| r.host.len()).max().unwrap_or(0);
+    println!();
+    println!("Fleet:");
+    for report in reports {
+        let host = format!("{:width$}", report.host, width = width);
+        let changes = report.changes.as_deref().unwrap_or("no summary");
+        match &report.outcome {
+            Outcome::Succeeded if act => {
+                println!("  {}  {}  {}", host, "deployed".green(), changes)
+            }
+            Outcome::Succeeded => println!("  {}  {}  {}", host, "checked".green(), changes),
+            Outcome::Failed(reason) => println!("  {}  {}  {}", host, "failed".red(), reason),
+            Outcome::Unreachable => println!("  {}  {}", host, "unreachable".red()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remote_command() {
+        let args = ["--local-config".to_string(), ".dotter/my host.toml".into()];
+        assert_eq!(
+            remote_command("~/dot files", "dotter", &args),
+            "cd ~/'dot files' && dotter --local-config '.dotter/my host.toml'"
+        );
+        assert_eq!(
+            remote_command("/srv/it's", "/usr/bin/dotter", &[]),
+            r"cd '/srv/it'\''s' && /usr/bin/dotter"
+        );
+    }
+}
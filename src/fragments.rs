@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use config::{FileTarget, Files};
+
+/// Directory inside the cache where targets assembled from fragments are generated
+const FRAGMENTS_DIRECTORY: &str = ".dotter_fragments";
+
+/// Contents of the assembled files that weren't written because nothing is changed, like in a
+/// dry run, by location
+static UNWRITTEN: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// One source of an assembled target, and whether it's rendered as a template
+pub type Part = (PathBuf, bool);
+
+/// Parts of every target that is assembled from fragments, in the order they're concatenated:
+/// ascending `order`, with ties broken by source path
+pub fn compositions(files: &Files) -> BTreeMap<PathBuf, Vec<Part>> {
+    let mut targets = BTreeMap::new();
+    for (source, target) in files {
+        if let FileTarget::Fragment(fragment) = target {
+            targets
+                .entry(fragment.target.clone())
+                .or_insert_with(Vec::new)
+                .push((fragment.order, source.clone(), fragment.template));
+        }
+    }
+    targets
+        .into_iter()
+        .map(|(target, mut parts)| {
+            parts.sort();
+            let parts = parts
+                .into_iter()
+                .map(|(_, source, template)| (source, template))
+                .collect();
+            (target, parts)
+        })
+        .collect()
+}
+
+/// Concatenates the parts of `target` into a single file in the cache directory, and returns
+/// its location. The assembled file is deployed as a template, so fragments with
+/// `template = false` are wrapped in a raw block. Unless `act`, the file is only kept in
+/// memory, see `unwritten`.
+pub fn assemble(
+    target: &Path,
+    parts: &[Part],
+    cache_directory: &Path,
+    act: bool,
+) -> Result<PathBuf> {
+    debug!("Assembling {:?} from fragments {:?}", target, parts);
+
+    let mut contents = String::new();
+    for (source, template) in parts {
+        let mut fragment =
+            fs::read_to_string(source).with_context(|| format!("read fragment {:?}", source))?;
+        if !fragment.is_empty() && !fragment.ends_with('\n') {
+            fragment.push('\n');
+        }
+        if *template {
+            contents.push_str(&fragment);
+        } else if !fragment.is_empty() {
+            contents.push_str(&format!(
+                "{{{{{{{{raw}}}}}}}}{}{{{{{{{{/raw}}}}}}}}",
+                fragment
+            ));
+        }
+    }
+
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let hash = blake3::hash(target.to_string_lossy().as_bytes()).to_hex();
+    let source =
+        cache_directory
+            .join(FRAGMENTS_DIRECTORY)
+            .join(format!("{}-{}", name, &hash[..16]));
+
+    if !act {
+        UNWRITTEN
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(source.clone(), contents);
+    } else if fs::read_to_string(&source).ok().as_ref() != Some(&contents) {
+        fs::create_dir_all(source.parent().context("get parent of assembled file")?)
+            .context("create parent of assembled file")?;
+        fs::write(&source, contents).context("write assembled file")?;
+    }
+
+    Ok(source)
+}
+
+/// Contents of the assembled file at `source`, if `assemble` didn't write it
+pub fn unwritten(source: &Path) -> Option<String> {
+    UNWRITTEN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(source)
+        .cloned()
+}
+
+/// Describes how the fragments of a target differ from the ones it was last deployed from
+pub fn describe_composition_change(old: &[PathBuf], new: &[PathBuf]) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let mut changes = Vec::new();
+    for source in new.iter().filter(|source| !old.contains(source)) {
+        changes.push(format!("+{:?}", source));
+    }
+    for source in old.iter().filter(|source| !new.contains(source)) {
+        changes.push(format!("-{:?}", source));
+    }
+    if changes.is_empty() {
+        changes.push("reordered".into());
+    }
+    Some(changes.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe_composition_change() {
+        let a = PathBuf::from("a");
+        let b = PathBuf::from("b");
+        let c = PathBuf::from("c");
+        assert_eq!(
+            describe_composition_change(&[a.clone(), b.clone()], &[a.clone(), b.clone()]),
+            None
+        );
+        assert_eq!(
+            describe_composition_change(&[a.clone(), b.clone()], &[a.clone(), c]).unwrap(),
+            "+\"c\", -\"b\""
+        );
+        assert_eq!(
+            describe_composition_change(&[a.clone(), b.clone()], &[b, a]).unwrap(),
+            "reordered"
+        );
+    }
+}
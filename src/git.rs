@@ -0,0 +1,104 @@
+//! The git operations that dotter runs in the repository: pulling before a deploy with
+//! `deploy --sync`, and committing the edits of commands that change the configuration when
+//! global.toml or local.toml set `auto_commit = true`
+
+use anyhow::{Context, Result};
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use args::Options;
+use config;
+use events;
+
+/// Runs `git pull --rebase`, and fails without touching anything else if it stops at conflicts
+pub fn pull(act: bool) -> Result<()> {
+    if !act {
+        info!("Would run `git pull --rebase`");
+        return Ok(());
+    }
+    info!("Running `git pull --rebase`");
+    let status = Command::new("git")
+        .args(["pull", "--rebase"])
+        .stdin(Stdio::null())
+        .stdout(events::child_stdout())
+        .status()
+        .context("run git")?;
+    if status.success() {
+        return Ok(());
+    }
+    let conflicts = output(&["diff", "--name-only", "--diff-filter=U"]).unwrap_or_default();
+    if !conflicts.is_empty() {
+        bail!(
+            "`git pull --rebase` stopped at conflicts in {}. Resolve them and run `git rebase --continue`, or go back with `git rebase --abort`, then deploy again.",
+            conflicts.lines().collect::<Vec<_>>().join(", ")
+        );
+    }
+    bail!("`git pull --rebase` failed with {}", status);
+}
+
+/// Commits `paths` with `message` if `auto_commit` is set. Paths that git ignores, like
+/// local.toml usually, are left out, and so are the other changes in the repository, staged
+/// or not.
+pub fn commit(opt: &Options, paths: &[&Path], message: &str) -> Result<()> {
+    if !opt.act || !config::auto_commit(&opt.local_config, &opt.global_config)? {
+        return Ok(());
+    }
+    if output(&["rev-parse", "--is-inside-work-tree"]).is_err() {
+        warn!("`auto_commit` is set, but the repository isn't a git repository");
+        return Ok(());
+    }
+    let paths = paths
+        .iter()
+        .filter(|path| !ignored(path))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        debug!(
+            "Not committing {:?}, git ignores every edited file",
+            message
+        );
+        return Ok(());
+    }
+    run(Command::new("git").args(["add", "--"]).args(&paths)).context("stage edited files")?;
+    run(Command::new("git")
+        .args(["commit", "--quiet", "--message", message, "--"])
+        .args(&paths))
+    .context("commit edited files")?;
+    info!("Committed {:?}", message);
+    Ok(())
+}
+
+fn ignored(path: &Path) -> bool {
+    Command::new("git")
+        .args(["check-ignore", "--quiet", "--"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(events::child_stdout())
+        .status()
+        .context("run git")?;
+    if !status.success() {
+        bail!("git exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Trimmed standard output of a git command that succeeded
+fn output(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("run git")?;
+    if !output.status.success() {
+        bail!("git exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
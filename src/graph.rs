@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+
+use args::{GraphFormat, Options};
+use config;
+use deploy;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeKind {
+    Package { enabled: bool },
+    Command,
+    File,
+}
+
+#[derive(Debug)]
+struct Node {
+    label: String,
+    kind: NodeKind,
+}
+
+/// Nodes are identified by their index, edges point from the dependency to the dependent
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<(usize, usize)>,
+}
+
+impl Graph {
+    fn add_node(&mut self, label: &str, kind: NodeKind) -> usize {
+        self.nodes.push(Node {
+            label: label.into(),
+            kind,
+        });
+        self.nodes.len() - 1
+    }
+}
+
+/// Prints the packages of global.toml and what enables them: commands, and the packages that
+/// depend on them. If `files` is set, also prints which package every file of an enabled
+/// package belongs to.
+pub fn print_graph(opt: &Options, format: GraphFormat, files: bool) -> Result<()> {
+    let declarations =
+        config::load_package_declarations(&opt.global_config).context("load packages")?;
+    let configuration = deploy::load_configuration(opt).context("get a configuration")?;
+
+    let mut graph = Graph::default();
+    let packages = add_packages(&mut graph, &declarations, &configuration.packages);
+
+    if files {
+        for (file, package) in &configuration.file_packages {
+            if let Some(&package) = packages.get(package.as_str()) {
+                let file = graph.add_node(&file.to_string_lossy(), NodeKind::File);
+                graph.edges.push((file, package));
+            }
+        }
+    }
+
+    let output = match format {
+        GraphFormat::Dot => to_dot(&graph),
+        GraphFormat::Mermaid => to_mermaid(&graph),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+/// Adds a node for every package and for every command that enables one. Returns the nodes of
+/// the packages, by name.
+fn add_packages<'a>(
+    graph: &mut Graph,
+    declarations: &'a [config::PackageDeclaration],
+    enabled: &[String],
+) -> BTreeMap<&'a str, usize> {
+    let mut packages = BTreeMap::new();
+    let mut commands = BTreeMap::new();
+    for declaration in declarations {
+        let enabled = enabled.contains(&declaration.name);
+        let package = graph.add_node(&declaration.name, NodeKind::Package { enabled });
+        packages.insert(declaration.name.as_str(), package);
+
+        for enabler in declaration.auto_enable.iter().chain(&declaration.condition) {
+            let enabler = *commands
+                .entry(enabler.as_str())
+                .or_insert_with(|| graph.add_node(enabler, NodeKind::Command));
+            graph.edges.push((enabler, package));
+        }
+    }
+    // A dependency is deployed before the packages that depend on it, like a dependency of files
+    for declaration in declarations {
+        let dependent = packages[declaration.name.as_str()];
+        for dependency in &declaration.depends {
+            match packages.get(dependency.as_str()) {
+                Some(&dependency) => graph.edges.push((dependency, dependent)),
+                None => warn!(
+                    "Package {:?} depends on {:?}, which isn't declared",
+                    declaration.name, dependency
+                ),
+            }
+        }
+    }
+    packages
+}
+
+fn to_dot(graph: &Graph) -> String {
+    let mut output = String::from("digraph dotter {\n    rankdir=LR;\n");
+    for (id, node) in graph.nodes.iter().enumerate() {
+        let attributes = match node.kind {
+            NodeKind::Package { enabled: true } => "shape=box",
+            NodeKind::Package { enabled: false } => "shape=box, style=dashed",
+            NodeKind::Command => "shape=hexagon",
+            NodeKind::File => "shape=note",
+        };
+        output.push_str(&format!(
+            "    n{} [label=\"{}\", {}];\n",
+            id,
+            node.label.replace('\\', "\\\\").replace('"', "\\\""),
+            attributes
+        ));
+    }
+    for (from, to) in &graph.edges {
+        output.push_str(&format!("    n{} -> n{};\n", from, to));
+    }
+    output.push_str("}\n");
+    output
+}
+
+fn to_mermaid(graph: &Graph) -> String {
+    let mut output = String::from("graph LR\n");
+    for (id, node) in graph.nodes.iter().enumerate() {
+        let label = node.label.replace('"', "#quot;");
+        let shape = match node.kind {
+            NodeKind::Package { enabled: true } => format!("[\"{}\"]", label),
+            NodeKind::Package { enabled: false } => format!("[\"{}\"]:::disabled", label),
+            NodeKind::Command => format!("{{{{\"{}\"}}}}", label),
+            NodeKind::File => format!("([\"{}\"])", label),
+        };
+        output.push_str(&format!("    n{}{}\n", id, shape));
+    }
+    for (from, to) in &graph.edges {
+        output.push_str(&format!("    n{} --> n{}\n", from, to));
+    }
+    output.push_str("    classDef disabled stroke-dasharray: 5 5\n");
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_depends_edges() {
+        let declaration = |name: &str, depends: &[&str]| config::PackageDeclaration {
+            name: name.into(),
+            auto_enable: None,
+            condition: None,
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+        };
+        let declarations = [
+            declaration("base", &[]),
+            declaration("editor", &["base"]),
+            declaration("work", &["editor", "missing"]),
+        ];
+        let mut graph = Graph::default();
+        add_packages(&mut graph, &declarations, &["editor".into(), "base".into()]);
+        assert_eq!(graph.edges, [(0, 1), (1, 2)]);
+
+        let dot = to_dot(&graph);
+        assert!(dot.contains("n2 [label=\"work\", shape=box, style=dashed];"));
+        assert!(dot.contains("n0 -> n1;\n    n1 -> n2;"));
+    }
+}
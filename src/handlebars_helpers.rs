@@ -1,8 +1,24 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
 
-use config::{Files, Helpers, Variables};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone, Utc};
 
-use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError};
+use config::{self, Files, HelperFile, Helpers, SecretsConfig, Variables};
+use facts;
+use filesystem;
+use fleet;
+use remote_variables;
+use render;
+use secrets;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonValue, Output, RenderContext,
+    RenderError, ScopedJson,
+};
 
 use meval;
 use toml::value::{Table, Value};
@@ -42,6 +58,76 @@ fn math_helper(
     Ok(())
 }
 
+thread_local! {
+    /// Templates that are being rendered on this thread, outermost first: the deployed template
+    /// and the files it's including, so that an include cycle is reported instead of
+    /// overflowing the stack
+    static INCLUDES: RefCell<Vec<Include>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Deepest that `include_template` may nest, even without a cycle
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+struct Include {
+    /// As it was written, for the diagnostic
+    path: PathBuf,
+    /// Canonical, to find cycles
+    real_path: PathBuf,
+}
+
+/// Removes the template from the includes when it's done rendering, even if it failed
+struct IncludeGuard;
+
+impl Drop for IncludeGuard {
+    fn drop(&mut self) {
+        INCLUDES.with(|includes| {
+            includes.borrow_mut().pop();
+        });
+    }
+}
+
+/// `helper` is the helper that includes the template, for the diagnostic
+fn enter_include(helper: &str, path: &Path) -> Result<IncludeGuard, String> {
+    let real_path = filesystem::real_path(path).unwrap_or_else(|_| path.into());
+    INCLUDES.with(|includes| {
+        let mut includes = includes.borrow_mut();
+        let cycle = includes.iter().any(|i| i.real_path == real_path);
+        if cycle || includes.len() >= MAX_INCLUDE_DEPTH {
+            let chain = includes
+                .iter()
+                .map(|i| i.path.as_path())
+                .chain(std::iter::once(path))
+                .map(|p| format!("{:?}", p))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(if cycle {
+                format!(
+                    "{}: templates include each other in a cycle: {}",
+                    helper, chain
+                )
+            } else {
+                format!(
+                    "{}: includes are nested more than {} deep: {}",
+                    helper, MAX_INCLUDE_DEPTH, chain
+                )
+            });
+        }
+        includes.push(Include {
+            path: path.into(),
+            real_path,
+        });
+        Ok(IncludeGuard)
+    })
+}
+
+/// Renders a deployed template with `render`, so that the templates it includes know where
+/// the chain of includes starts
+pub fn rendering_template<T>(source: &Path, render: impl FnOnce() -> T) -> T {
+    INCLUDES.with(|includes| includes.borrow_mut().clear());
+    let _guard = enter_include("include_template", source).expect("nothing is included yet");
+    render()
+}
+
 fn include_template_helper(
     h: &Helper,
     handlebars: &Handlebars,
@@ -60,17 +146,75 @@ fn include_template_helper(
         ));
     }
 
-    let included_file = std::fs::read_to_string(path)
+    let included_file = std::fs::read_to_string(&path)
         .map_err(|e| RenderError::from_error("include_template", e))?;
+    let _guard = enter_include("include_template", Path::new(&path)).map_err(RenderError::new)?;
     let rendered_file = handlebars
         .render_template_with_context(&included_file, ctx)
-        .map_err(|e| RenderError::from_error("include_template", e))?;
+        .map_err(|e| nested_error("include_template", e))?;
 
     out.write(&rendered_file)?;
 
     Ok(())
 }
 
+/// Keeps the diagnostic of a nested include instead of wrapping it once per level
+fn nested_error(helper: &str, error: handlebars::TemplateRenderError) -> RenderError {
+    match error {
+        handlebars::TemplateRenderError::RenderError(e)
+            if e.desc.starts_with("include_template: ") || e.desc.starts_with("render: ") =>
+        {
+            e
+        }
+        e => RenderError::from_error(helper, e),
+    }
+}
+
+/// Renders another template of the repository in place, with the hash parameters on top of
+/// the variables, like `{{render "snippets/binding.hbs" key="a" action="focus-left"}}`.
+/// A path that doesn't exist is looked up among the partials, without its extension.
+fn render_helper(
+    h: &Helper,
+    handlebars: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let path = match h.params().as_slice() {
+        [path] => path.render(),
+        [] => return Err(RenderError::new("render: No path given")),
+        _ => return Err(RenderError::new("render: More than one parameter given")),
+    };
+    let path = match partial_file(&Path::new(&path).with_extension("").to_string_lossy()) {
+        Some(partial) if !Path::new(&path).exists() => partial,
+        _ => PathBuf::from(path),
+    };
+
+    let mut data = ctx.data().clone();
+    match &mut data {
+        JsonValue::Object(variables) => {
+            for (name, value) in h.hash() {
+                variables.insert(name.to_string(), value.value().clone());
+            }
+        }
+        _ if h.hash().is_empty() => {}
+        _ => {
+            return Err(RenderError::new(
+                "render: Variables can't be added to a context that isn't an object",
+            ))
+        }
+    }
+
+    let template =
+        std::fs::read_to_string(&path).map_err(|e| RenderError::from_error("render", e))?;
+    let _guard = enter_include("render", &path).map_err(RenderError::new)?;
+    let rendered = handlebars
+        .render_template(&template, &data)
+        .map_err(|e| nested_error("render", e))?;
+    out.write(&rendered)?;
+    Ok(())
+}
+
 fn is_executable_helper(
     h: &Helper,
     _: &Handlebars,
@@ -89,8 +233,8 @@ fn is_executable_helper(
         ));
     }
 
-    let status =
-        is_executable(&executable).map_err(|e| RenderError::from_error("is_executable", e))?;
+    let status = filesystem::is_executable(&executable)
+        .map_err(|e| RenderError::from_error("is_executable", e))?;
     if status {
         out.write("true")?;
     }
@@ -117,13 +261,17 @@ fn command_success_helper(
         ));
     }
 
-    let status = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()?
-        .success();
+    let status = filesystem::output_until(
+        os_shell()
+            .arg(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+        render::deadline(),
+    )
+    .map_err(|e| RenderError::from_error("command_success", e))?
+    .status
+    .success();
     if status {
         out.write("true")?;
     }
@@ -150,80 +298,915 @@ fn command_output_helper(
         ));
     }
 
-    let output = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
+    let output = filesystem::output_until(
+        os_shell()
+            .arg(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped()),
         // .stderr(Stdio::piped()) - probably not wanted
-        .output()?;
+        render::deadline(),
+    )
+    .map_err(|e| RenderError::from_error("command_output", e))?;
     out.write(&String::from_utf8_lossy(&output.stdout))?;
     // writing anything other than an empty string is considered truthy
 
     Ok(())
 }
 
-#[cfg(windows)]
-fn is_executable(name: &str) -> Result<bool, std::io::Error> {
-    let name = if name.ends_with(".exe") {
-        name.to_string()
-    } else {
-        format!("{}.exe", name)
-    };
+fn content_hash_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut params = h.params().iter();
+    let path = params
+        .next()
+        .ok_or_else(|| RenderError::new("content_hash: No path given"))?
+        .render();
+    if params.next().is_some() {
+        return Err(RenderError::new(
+            "content_hash: More than one parameter given",
+        ));
+    }
 
-    Command::new("where")
-        .arg(name)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .map(|s| s.success())
+    let contents = std::fs::read(&path).map_err(|e| RenderError::from_error("content_hash", e))?;
+    let mut hash = blake3::hash(&contents).to_hex().to_string();
+    if let Some(length) = h.hash_get("length") {
+        let length = length
+            .value()
+            .as_u64()
+            .ok_or_else(|| RenderError::new("content_hash: length must be a number"))?;
+        hash.truncate(length as usize);
+    }
+
+    out.write(&hash)?;
+    Ok(())
 }
 
-#[cfg(unix)]
-fn is_executable(name: &str) -> Result<bool, std::io::Error> {
-    Command::new("which")
-        .arg(name)
+/// Runs git in the dotfiles repository and returns its trimmed output
+fn git(helper: &str, args: &[&str]) -> Result<String, RenderError> {
+    let output = Command::new("git")
+        .args(args)
         .stdin(Stdio::null())
-        .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .status()
-        .map(|s| s.success())
+        .output()
+        .map_err(|e| RenderError::from_error(helper, e))?;
+    if !output.status.success() {
+        return Err(RenderError::new(format!(
+            "{}: the repository isn't a git repository, or has no commits",
+            helper
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn git_commit_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    if !h.params().is_empty() {
+        return Err(RenderError::new("git_commit: No parameters expected"));
+    }
+    let length = match h.hash_get("length") {
+        Some(length) => length
+            .value()
+            .as_u64()
+            .ok_or_else(|| RenderError::new("git_commit: length must be a number"))?,
+        None => 7,
+    };
+
+    let mut commit = git("git_commit", &["rev-parse", "HEAD"])?;
+    commit.truncate(length as usize);
+    out.write(&commit)?;
+    Ok(())
+}
+
+fn git_branch_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    if !h.params().is_empty() {
+        return Err(RenderError::new("git_branch: No parameters expected"));
+    }
+
+    // Prints nothing on a detached HEAD
+    let branch = git("git_branch", &["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch != "HEAD" {
+        out.write(&branch)?;
+    }
+    Ok(())
+}
+
+fn git_dirty_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    if !h.params().is_empty() {
+        return Err(RenderError::new("git_dirty: No parameters expected"));
+    }
+
+    if !git("git_dirty", &["status", "--porcelain"])?.is_empty() {
+        out.write("true")?;
+    }
+    // writing anything other than an empty string is considered truthy
+
+    Ok(())
+}
+
+fn hardware_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let fact = match h.params().as_slice() {
+        [fact] => fact.render(),
+        _ => {
+            return Err(RenderError::new(
+                "hardware: Exactly one parameter expected, the name of the fact",
+            ))
+        }
+    };
+
+    // Facts that can't be determined print nothing, so that templates can fall back with `or`
+    let value = match fact.as_str() {
+        "cpus" => facts::cpus().map(|n| n.to_string()),
+        "memory_mib" => facts::memory_mib().map(|n| n.to_string()),
+        "battery" => facts::battery().filter(|&b| b).map(|_| "true".into()),
+        "gpu_vendor" => facts::gpu_vendor(),
+        other => {
+            return Err(RenderError::new(format!(
+                "hardware: Unknown fact {:?}, expected cpus, memory_mib, battery or gpu_vendor",
+                other
+            )))
+        }
+    };
+    if let Some(value) = value {
+        out.write(&value)?;
+    }
+    Ok(())
+}
+
+fn stable_random_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let (seed, min, max) = match h.params().as_slice() {
+        [seed, min, max] => (seed.render(), min.value().as_i64(), max.value().as_i64()),
+        _ => {
+            return Err(RenderError::new(
+                "stable_random: Expected a seed, a minimum and a maximum",
+            ))
+        }
+    };
+    let (min, max) = match (min, max) {
+        (Some(min), Some(max)) if min <= max => (min, max),
+        _ => return Err(RenderError::new(
+            "stable_random: The minimum and maximum must be whole numbers, with minimum <= maximum",
+        )),
+    };
+
+    let hostname = facts::hostname();
+    out.write(&stable_random(&hostname, &seed, min, max).to_string())?;
+    Ok(())
+}
+
+/// A number between `min` and `max` (inclusive) that only changes with the hostname and seed
+fn stable_random(hostname: &str, seed: &str, min: i64, max: i64) -> i64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(hostname.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(seed.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+    let range = (max as i128 - min as i128 + 1) as u128;
+    (min as i128 + (u128::from_le_bytes(bytes) % range) as i128) as i64
+}
+
+/// Renders the parameters of a path helper, which takes between `min` and `max` of them
+fn path_params(h: &Helper, name: &str, min: usize, max: usize) -> Result<Vec<String>, RenderError> {
+    let params = h.params().iter().map(|p| p.render()).collect::<Vec<_>>();
+    if params.len() < min || params.len() > max {
+        return Err(RenderError::new(match (min, max) {
+            (min, max) if min == max => format!("{}: Expected {} parameters", name, min),
+            (min, usize::MAX) => format!("{}: Expected at least {} parameters", name, min),
+            (min, max) => format!("{}: Expected {} to {} parameters", name, min, max),
+        }));
+    }
+    Ok(params)
+}
+
+/// Directory part of a path, like `dirname`: `.` for a bare file name
+fn dirname_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "dirname", 1, 1)?;
+    out.write(&dirname(&params[0]))?;
+    Ok(())
+}
+
+fn dirname(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".".into(),
+        Some(parent) => parent.to_string_lossy().into_owned(),
+        None => path.into(),
+    }
+}
+
+/// Last component of a path, like `basename`, without the suffix given as second parameter
+fn basename_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "basename", 1, 2)?;
+    let name = Path::new(&params[0])
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match params.get(1) {
+        Some(suffix) if name != *suffix => name.strip_suffix(suffix.as_str()).unwrap_or(&name),
+        _ => &name,
+    };
+    out.write(name)?;
+    Ok(())
+}
+
+/// Joins the parameters with the platform's separator. An absolute parameter replaces
+/// everything before it.
+fn join_path_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "join_path", 1, usize::MAX)?;
+    let joined = params.iter().collect::<PathBuf>();
+    out.write(&joined.to_string_lossy())?;
+    Ok(())
+}
+
+/// Absolute path with symlinks resolved, after expanding a leading `~`. The path must exist.
+fn canonicalize_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "canonicalize", 1, 1)?;
+    let path = filesystem::expand_tilde(&params[0]);
+    let canonical = filesystem::real_path(Path::new(&path))
+        .map_err(|e| RenderError::new(format!("canonicalize: Cannot resolve {:?}: {}", path, e)))?;
+    out.write(&canonical.to_string_lossy())?;
+    Ok(())
+}
+
+/// The repository's files that a glob matches, or the files of a directory, as a list for
+/// `{{#each (dir_files "wallpapers/*.jpg")}}`
+struct DirFilesHelper;
+
+impl HelperDef for DirFilesHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let params = path_params(h, "dir_files", 1, 1)?;
+        let files = config::glob_files(&params[0])
+            .map_err(|e| RenderError::new(format!("dir_files: {:#}", e)))?;
+        let files = files
+            .iter()
+            .map(|file| JsonValue::from(file.to_string_lossy()))
+            .collect();
+        Ok(Some(ScopedJson::Derived(JsonValue::Array(files))))
+    }
+}
+
+/// What the file at the path contains now, after expanding a leading `~`, or nothing if it
+/// doesn't exist yet. Lets a template keep the parts of its own target that the application
+/// writes, since the target is only replaced after rendering.
+fn current_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "current", 1, 1)?;
+    let path = filesystem::expand_tilde(&params[0]);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => out.write(&contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(RenderError::new(format!(
+                "current: Cannot read {:?}: {}",
+                path, e
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// The first path relative to the second one, like `../share/x` for `/usr/share/x`
+/// relative to `/usr/bin`. Only the text of the paths is compared, symlinks aren't resolved.
+fn relative_to_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let params = path_params(h, "relative_to", 2, 2)?;
+    let relative =
+        relative_path(Path::new(&params[0]), Path::new(&params[1])).ok_or_else(|| {
+            RenderError::new(format!(
+                "relative_to: Cannot express {:?} relative to {:?}",
+                params[0], params[1]
+            ))
+        })?;
+    out.write(&relative.to_string_lossy())?;
+    Ok(())
+}
+
+/// `None` if one path is absolute and the other isn't, or if the base goes up with `..`
+/// further than the paths have in common
+fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    if path.has_root() != base.has_root() {
+        return None;
+    }
+    let (path, base) = (clean_components(path), clean_components(base));
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    if base[common..].contains(&Component::ParentDir) {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+fn clean_components(path: &Path) -> Vec<Component<'_>> {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Helper without parameters that prints a fact, or nothing if it can't be determined
+fn fact_helper(name: &'static str, fact: fn() -> Option<String>) -> impl HelperDef {
+    move |h: &Helper,
+          _: &Handlebars,
+          _: &Context,
+          _: &mut RenderContext,
+          out: &mut dyn Output|
+          -> HelperResult {
+        if !h.params().is_empty() {
+            return Err(RenderError::new(format!(
+                "{}: No parameters expected",
+                name
+            )));
+        }
+        if let Some(value) = fact() {
+            out.write(&value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Helper that escapes its only parameter, so that a value with quotes or special characters
+/// can't break out of the string or pattern it's put in
+fn escape_helper(name: &'static str, escape: fn(&str) -> String) -> impl HelperDef {
+    move |h: &Helper,
+          _: &Handlebars,
+          _: &Context,
+          _: &mut RenderContext,
+          out: &mut dyn Output|
+          -> HelperResult {
+        let params = path_params(h, name, 1, 1)?;
+        out.write(&escape(&params[0]))?;
+        Ok(())
+    }
+}
+
+/// Contents of a JSON string, without the quotes around it
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).expect("strings serialize");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Contents of a TOML basic string, without the quotes around it
+fn toml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Text or attribute value of an XML (or HTML) document
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Embeds the contents of a URL, if `allow_fetch` is set. See `remote_variables::fetch_resource`.
+fn fetch_url_helper(cache_directory: PathBuf, allowed: bool) -> impl HelperDef {
+    move |h: &Helper,
+          _: &Handlebars,
+          _: &Context,
+          _: &mut RenderContext,
+          out: &mut dyn Output|
+          -> HelperResult {
+        let mut params = h.params().iter();
+        let url = params
+            .next()
+            .ok_or_else(|| RenderError::new("fetch_url: No URL given"))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderError::new("fetch_url: More than one parameter given"));
+        }
+        if !allowed {
+            return Err(RenderError::new(format!(
+                "fetch_url: Fetching {} isn't allowed. Set `allow_fetch = true` in global.toml or local.toml to enable it.",
+                url
+            )));
+        }
+        let checksum = h.hash_get("checksum").map(|c| c.render());
+
+        let body = remote_variables::fetch_resource(&url, checksum.as_deref(), &cache_directory)
+            .map_err(|e| RenderError::new(format!("fetch_url: {:#}", e)))?;
+        out.write(&body)?;
+        Ok(())
+    }
+}
+
+/// Reveals a secret with the configured provider, or the one given as `provider="..."`
+fn secret_helper(config: SecretsConfig) -> impl HelperDef {
+    move |h: &Helper,
+          _: &Handlebars,
+          _: &Context,
+          _: &mut RenderContext,
+          out: &mut dyn Output|
+          -> HelperResult {
+        let mut params = h.params().iter();
+        let name = params
+            .next()
+            .ok_or_else(|| RenderError::new("secret: No secret name given"))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderError::new("secret: More than one parameter given"));
+        }
+        let provider = match h.hash_get("provider") {
+            Some(provider) => Some(
+                provider
+                    .render()
+                    .parse()
+                    .map_err(|e: String| RenderError::new(format!("secret: {}", e)))?,
+            ),
+            None => None,
+        };
+
+        let secret = secrets::reveal(&config, provider, &name)
+            .map_err(|e| RenderError::new(format!("secret: {:#}", e)))?;
+        out.write(&secret)?;
+        Ok(())
+    }
+}
+
+/// The moment that the date and time helpers report, or why there is none
+pub type RenderTime = Result<DateTime<FixedOffset>, String>;
+
+fn render_now(now: &RenderTime) -> Result<&DateTime<FixedOffset>, RenderError> {
+    now.as_ref().map_err(|e| RenderError::new(e.clone()))
+}
+
+fn now_helper(h: &Helper, out: &mut dyn Output, now: &RenderTime) -> HelperResult {
+    let now = render_now(now)?;
+    let mut params = h.params().iter();
+    let formatted = match params.next() {
+        Some(format) => {
+            let format = format.render();
+            // Formatting with an invalid pattern panics, so it's checked first
+            let items = StrftimeItems::new(&format).collect::<Vec<_>>();
+            if items.contains(&Item::Error) {
+                return Err(RenderError::new(format!(
+                    "now: Invalid date format {:?}",
+                    format
+                )));
+            }
+            now.format_with_items(items.into_iter()).to_string()
+        }
+        None => now.to_rfc3339(),
+    };
+    if params.next().is_some() {
+        return Err(RenderError::new("now: More than one parameter given"));
+    }
+
+    out.write(&formatted)?;
+    Ok(())
+}
+
+fn timestamp_helper(h: &Helper, out: &mut dyn Output, now: &RenderTime) -> HelperResult {
+    let now = render_now(now)?;
+    if !h.params().is_empty() {
+        return Err(RenderError::new("timestamp: Doesn't take parameters"));
+    }
+
+    out.write(&now.timestamp().to_string())?;
+    Ok(())
+}
+
+fn date_passed_helper(h: &Helper, out: &mut dyn Output, now: &RenderTime) -> HelperResult {
+    let now = render_now(now)?;
+    let mut params = h.params().iter();
+    let date = params
+        .next()
+        .ok_or_else(|| RenderError::new("date_passed: No date given"))?
+        .render();
+    if params.next().is_some() {
+        return Err(RenderError::new(
+            "date_passed: More than one parameter given",
+        ));
+    }
+
+    let date = parse_date(&date, now.offset()).ok_or_else(|| {
+        RenderError::new(format!(
+            "date_passed: Cannot parse {:?} as a date (YYYY-MM-DD) or an RFC 3339 date and time",
+            date
+        ))
+    })?;
+    if *now >= date {
+        out.write("true")?;
+    }
+    // writing anything other than an empty string is considered truthy
+
+    Ok(())
+}
+
+/// Parses an RFC 3339 date and time, or a plain date which is taken as midnight in `offset`
+fn parse_date(date: &str, offset: &FixedOffset) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(date).ok().or_else(|| {
+        let midnight = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .ok()?
+            .and_hms(0, 0, 0);
+        offset.from_local_datetime(&midnight).single()
+    })
+}
+
+/// The moment that the date and time helpers report, taken once so every template agrees on it.
+/// In deterministic mode it is `$SOURCE_DATE_EPOCH` (or the Unix epoch) in UTC.
+pub fn render_time(deterministic: bool) -> RenderTime {
+    if deterministic {
+        let seconds = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+        Utc.timestamp_opt(seconds, 0)
+            .single()
+            .map(Into::into)
+            .ok_or_else(|| format!("SOURCE_DATE_EPOCH {} is out of range", seconds))
+    } else {
+        let now = Local::now();
+        Ok(now.with_timezone(now.offset()))
+    }
 }
 
 #[cfg(windows)]
-fn os_shell() -> Command {
+pub fn os_shell() -> Command {
     let mut cmd = Command::new("cmd");
     cmd.arg("/C");
+    filesystem::set_child_environment(&mut cmd);
     cmd
 }
 
 #[cfg(unix)]
-fn os_shell() -> Command {
+pub fn os_shell() -> Command {
     let mut cmd = Command::new("sh");
     cmd.arg("-c");
+    filesystem::set_child_environment(&mut cmd);
     cmd
 }
 
-pub fn register_rust_helpers(handlebars: &mut Handlebars) {
+pub fn register_rust_helpers(handlebars: &mut Handlebars, now: RenderTime) {
     handlebars_misc_helpers::register(handlebars);
     handlebars.register_helper("math", Box::new(math_helper));
 
     handlebars.register_helper("include_template", Box::new(include_template_helper));
+    handlebars.register_helper("render", Box::new(render_helper));
     handlebars.register_helper("is_executable", Box::new(is_executable_helper));
     handlebars.register_helper("command_success", Box::new(command_success_helper));
     handlebars.register_helper("command_output", Box::new(command_output_helper));
+    handlebars.register_helper("content_hash", Box::new(content_hash_helper));
+    handlebars.register_helper("git_commit", Box::new(git_commit_helper));
+    handlebars.register_helper("git_branch", Box::new(git_branch_helper));
+    handlebars.register_helper("git_dirty", Box::new(git_dirty_helper));
+    handlebars.register_helper("hardware", Box::new(hardware_helper));
+    handlebars.register_helper("stable_random", Box::new(stable_random_helper));
+    handlebars.register_helper("dirname", Box::new(dirname_helper));
+    handlebars.register_helper("basename", Box::new(basename_helper));
+    handlebars.register_helper("join_path", Box::new(join_path_helper));
+    // Replaces the one of handlebars_misc_helpers, which doesn't expand `~`
+    handlebars.register_helper("canonicalize", Box::new(canonicalize_helper));
+    handlebars.register_helper("relative_to", Box::new(relative_to_helper));
+    handlebars.register_helper("current", Box::new(current_helper));
+    handlebars.register_helper("dir_files", Box::new(DirFilesHelper));
+    handlebars.register_helper(
+        "shell_quote",
+        Box::new(escape_helper("shell_quote", fleet::shell_quote)),
+    );
+    handlebars.register_helper(
+        "json_escape",
+        Box::new(escape_helper("json_escape", json_escape)),
+    );
+    handlebars.register_helper(
+        "toml_escape",
+        Box::new(escape_helper("toml_escape", toml_escape)),
+    );
+    handlebars.register_helper(
+        "xml_escape",
+        Box::new(escape_helper("xml_escape", xml_escape)),
+    );
+    handlebars.register_helper(
+        "regex_escape",
+        Box::new(escape_helper("regex_escape", regex::escape)),
+    );
+    handlebars.register_helper("locale", Box::new(fact_helper("locale", facts::locale)));
+    handlebars.register_helper("lang", Box::new(fact_helper("lang", facts::lang)));
+    handlebars.register_helper(
+        "keyboard_layout",
+        Box::new(fact_helper("keyboard_layout", facts::keyboard_layout)),
+    );
+
+    let timestamp_now = now.clone();
+    let date_passed_now = now.clone();
+    handlebars.register_helper(
+        "now",
+        Box::new(
+            move |h: &Helper,
+                  _: &Handlebars,
+                  _: &Context,
+                  _: &mut RenderContext,
+                  out: &mut dyn Output| { now_helper(h, out, &now) },
+        ),
+    );
+    handlebars.register_helper(
+        "timestamp",
+        Box::new(
+            move |h: &Helper,
+                  _: &Handlebars,
+                  _: &Context,
+                  _: &mut RenderContext,
+                  out: &mut dyn Output| { timestamp_helper(h, out, &timestamp_now) },
+        ),
+    );
+    handlebars.register_helper(
+        "date_passed",
+        Box::new(
+            move |h: &Helper,
+                  _: &Handlebars,
+                  _: &Context,
+                  _: &mut RenderContext,
+                  out: &mut dyn Output| {
+                date_passed_helper(h, out, &date_passed_now)
+            },
+        ),
+    );
+}
+
+pub fn register_fetch_helper(handlebars: &mut Handlebars, cache_directory: PathBuf, allowed: bool) {
+    handlebars.register_helper(
+        "fetch_url",
+        Box::new(fetch_url_helper(cache_directory, allowed)),
+    );
 }
 
-pub fn register_script_helpers(handlebars: &mut Handlebars, helpers: &Helpers) {
+pub fn register_secret_helper(handlebars: &mut Handlebars, config: SecretsConfig) {
+    handlebars.register_helper("secret", Box::new(secret_helper(config)));
+}
+
+/// Files of the registered script helpers by name, so that the templates that use them know
+/// when they changed
+static SCRIPT_HELPERS: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// Registers the helpers of global.toml. A path is a Rhai script. With `allow_program_helpers`,
+/// a `{ program = "<path>" }` in the repository is run as a program with the helper's parameters
+/// as arguments, and what it prints is inserted without its last newline.
+pub fn register_script_helpers(
+    handlebars: &mut Handlebars,
+    helpers: &Helpers,
+    allow_programs: bool,
+) {
     debug!("Registering script helpers...");
-    for (helper_name, helper_path) in helpers {
-        if let Err(e) = handlebars.register_script_helper_file(&helper_name, &helper_path) {
+    let mut registered = SCRIPT_HELPERS.lock().unwrap_or_else(|e| e.into_inner());
+    registered.clear();
+    for (helper_name, helper) in helpers {
+        let helper_path = helper.path();
+        if let HelperFile::Script(_) = helper {
+            if let Err(e) = handlebars.register_script_helper_file(helper_name, helper_path) {
+                warn!(
+                    "Coudln't register helper script {} at path {:?} because {}",
+                    helper_name, helper_path, e
+                );
+                continue;
+            }
+        } else if !allow_programs {
             warn!(
-                "Coudln't register helper script {} at path {:?} because {}",
-                helper_name, helper_path, e
+                "Not registering program helper {} at path {:?}. Set `allow_program_helpers = true` in global.toml or local.toml to run it.",
+                helper_name, helper_path
             );
+            continue;
+        } else {
+            let program = match std::env::current_dir()
+                .map_err(|e| e.to_string())
+                .and_then(|repository| program_in_repository(helper_path, &repository))
+            {
+                Ok(program) => program,
+                Err(e) => {
+                    warn!(
+                        "Not registering helper {} at path {:?} because {}",
+                        helper_name, helper_path, e
+                    );
+                    continue;
+                }
+            };
+            handlebars.register_helper(
+                helper_name,
+                Box::new(program_helper(helper_name.clone(), program)),
+            );
+        }
+        registered.insert(helper_name.clone(), helper_path.to_path_buf());
+    }
+}
+
+/// The file of a helper that was registered by `register_script_helpers`
+pub fn script_helper_file(name: &str) -> Option<PathBuf> {
+    SCRIPT_HELPERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
+}
+
+/// The real path of a program helper, which has to be a file in the repository. It's never
+/// looked up in `PATH`.
+fn program_in_repository(path: &Path, repository: &Path) -> Result<PathBuf, String> {
+    let repository = filesystem::real_path(repository).map_err(|e| e.to_string())?;
+    let program = filesystem::real_path(&repository.join(path))
+        .map_err(|e| format!("it can't be found: {}", e))?;
+    if !program.starts_with(&repository) {
+        return Err("it's outside of the repository".into());
+    }
+    if !program.is_file() {
+        return Err("it isn't a file".into());
+    }
+    Ok(program)
+}
+
+/// Runs the program until the render's deadline, see `RenderLimits`
+fn program_helper(name: String, program: PathBuf) -> impl HelperDef {
+    move |h: &Helper,
+          _: &Handlebars,
+          _: &Context,
+          _: &mut RenderContext,
+          out: &mut dyn Output|
+          -> HelperResult {
+        let mut command = Command::new(&program);
+        command
+            .args(h.params().iter().map(|p| p.render()))
+            .stdin(Stdio::null());
+        filesystem::set_child_environment(&mut command);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let output = filesystem::output_until(&mut command, render::deadline()).map_err(|e| {
+            RenderError::new(format!("{}: Cannot run {:?} because {}", name, program, e))
+        })?;
+        if !output.status.success() {
+            return Err(RenderError::new(format!(
+                "{}: {:?} exited with {}: {}",
+                name,
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        out.write(stdout.strip_suffix('\n').unwrap_or(&stdout))?;
+        Ok(())
+    }
+}
+
+/// Files of the registered partials by name, so that the templates that include them know
+/// when they changed
+static PARTIALS: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// Held by the tests that register partials, since they're global
+#[cfg(test)]
+pub static PARTIALS_TEST: Mutex<()> = Mutex::new(());
+
+/// Registers every file under `directory` as a partial, named after its path inside it without
+/// the extension, so that `partials/common/colors.hbs` is included with `{{> common/colors}}`
+pub fn register_partials(handlebars: &mut Handlebars, directory: &Path) {
+    debug!("Registering partials...");
+    PARTIALS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    if let Err(e) = register_partials_in(handlebars, directory, directory) {
+        warn!(
+            "Couldn't register the partials in {:?} because {}",
+            directory, e
+        );
+    }
+}
+
+fn register_partials_in(
+    handlebars: &mut Handlebars,
+    root: &Path,
+    directory: &Path,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            register_partials_in(handlebars, root, &path)?;
+            continue;
+        }
+        let name = path
+            .strip_prefix(root)
+            .expect("partials are inside their directory")
+            .with_extension("")
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        match handlebars.register_template_file(&name, &path) {
+            Ok(()) => {
+                PARTIALS
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(name, path);
+            }
+            Err(e) => warn!(
+                "Couldn't register partial {} at path {:?} because {}",
+                name, path, e
+            ),
         }
     }
+    Ok(())
+}
+
+/// The file of a partial that was registered by `register_partials`
+pub fn partial_file(name: &str) -> Option<PathBuf> {
+    PARTIALS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
 }
 
 fn files_as_toml(files: &Files) -> Value {
@@ -240,6 +1223,19 @@ fn files_as_toml(files: &Files) -> Value {
     )
 }
 
+/// Known folders of Windows, the same ones that `%APPDATA%` and the like expand to in targets
+fn windows_folders() -> Table {
+    [
+        ("appdata", "APPDATA"),
+        ("local_appdata", "LOCALAPPDATA"),
+        ("program_data", "PROGRAMDATA"),
+        ("user_profile", "USERPROFILE"),
+    ]
+    .iter()
+    .filter_map(|(name, variable)| Some((name.to_string(), std::env::var(variable).ok()?.into())))
+    .collect()
+}
+
 pub fn add_dotter_variable(variables: &mut Variables, files: &Files, packages: &[String]) {
     let mut dotter = Table::new();
     dotter.insert(
@@ -252,10 +1248,204 @@ pub fn add_dotter_variable(variables: &mut Variables, files: &Files, packages: &
         ),
     );
     dotter.insert("files".into(), files_as_toml(files));
-    dotter.insert(
-        "os".into(),
-        (if cfg!(windows) { "windows" } else { "unix" }).into(),
-    );
+    dotter.insert("os".into(), facts::family().into());
+    if facts::family() == "windows" {
+        dotter.insert("windows".into(), windows_folders().into());
+    }
 
     variables.insert("dotter".into(), dotter.into());
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_date_helpers() {
+        let mut handlebars = Handlebars::new();
+        let now = Utc.timestamp(1_600_000_000, 0).into();
+        register_rust_helpers(&mut handlebars, Ok(now));
+        let render = |template: &str| handlebars.render_template(template, &()).unwrap();
+
+        assert_eq!(render("{{now}}"), "2020-09-13T12:26:40+00:00");
+        assert_eq!(render("{{now \"%Y-%m-%d\"}}"), "2020-09-13");
+        assert_eq!(render("{{timestamp}}"), "1600000000");
+        assert_eq!(render("{{date_passed \"2020-09-13\"}}"), "true");
+        assert_eq!(render("{{date_passed \"2020-09-14\"}}"), "");
+        assert_eq!(render("{{date_passed \"2020-09-13T12:30:00Z\"}}"), "");
+        assert!(handlebars.render_template("{{now \"%Q\"}}", &()).is_err());
+
+        let mut handlebars = Handlebars::new();
+        register_rust_helpers(&mut handlebars, Err("out of range".into()));
+        assert!(handlebars.render_template("{{timestamp}}", &()).is_err());
+    }
+
+    #[test]
+    fn test_path_helpers() {
+        let mut handlebars = Handlebars::new();
+        register_rust_helpers(&mut handlebars, Ok(Utc.timestamp(0, 0).into()));
+        let render = |template: &str| handlebars.render_template(template, &()).unwrap();
+
+        assert_eq!(render("{{dirname \"/etc/ssh/sshd_config\"}}"), "/etc/ssh");
+        assert_eq!(render("{{dirname \"vimrc\"}}"), ".");
+        assert_eq!(
+            render("{{basename \"/etc/ssh/sshd_config\"}}"),
+            "sshd_config"
+        );
+        assert_eq!(render("{{basename \"theme.toml\" \".toml\"}}"), "theme");
+        assert_eq!(
+            render("{{join_path \"/etc\" \"ssh\" \"config\"}}"),
+            "/etc/ssh/config"
+        );
+        assert_eq!(render("{{join_path \"a\" \"/b\"}}"), "/b");
+
+        let relative = |path: &str, base: &str| {
+            relative_path(Path::new(path), Path::new(base))
+                .map(|p| p.to_string_lossy().into_owned())
+        };
+        assert_eq!(
+            relative("/usr/share/x", "/usr/bin"),
+            Some("../share/x".into())
+        );
+        assert_eq!(relative("/usr/bin", "/usr/bin/"), Some(".".into()));
+        assert_eq!(relative("./a/b", "a"), Some("b".into()));
+        assert_eq!(relative("/a", "b"), None);
+        assert_eq!(relative("a", "../b"), None);
+    }
+
+    #[test]
+    fn test_escape_helpers() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(handlebars::no_escape);
+        register_rust_helpers(&mut handlebars, Ok(Utc.timestamp(0, 0).into()));
+        let value = serde_json::json!({"value": "it's \"a\" <b>\\\n"});
+        let render = |helper: &str| {
+            handlebars
+                .render_template(&format!("{{{{{} value}}}}", helper), &value)
+                .unwrap()
+        };
+
+        assert_eq!(render("shell_quote"), "'it'\\''s \"a\" <b>\\\n'");
+        assert_eq!(render("json_escape"), "it's \\\"a\\\" <b>\\\\\\n");
+        assert_eq!(render("toml_escape"), "it's \\\"a\\\" <b>\\\\\\n");
+        assert_eq!(
+            render("xml_escape"),
+            "it&apos;s &quot;a&quot; &lt;b&gt;\\\n"
+        );
+        assert_eq!(render("regex_escape"), "it's \"a\" <b>\\\\\n");
+        assert_eq!(toml_escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn test_stable_random() {
+        let minute = stable_random("laptop", "backup", 0, 59);
+        assert!((0..=59).contains(&minute));
+        assert_eq!(stable_random("laptop", "backup", 0, 59), minute);
+        assert_eq!(stable_random("laptop", "backup", 7, 7), 7);
+        // The whole range doesn't overflow
+        stable_random("laptop", "x", i64::MIN, i64::MAX);
+
+        let minutes = (0..20)
+            .map(|i| stable_random(&format!("host{}", i), "backup", 0, 59))
+            .collect::<std::collections::BTreeSet<_>>();
+        assert!(minutes.len() > 1);
+    }
+
+    #[test]
+    fn test_render_helper() {
+        let directory = tempfile::tempdir().unwrap();
+        let partials = directory.path().join("partials");
+        std::fs::create_dir_all(partials.join("snippets")).unwrap();
+        std::fs::write(
+            partials.join("snippets/binding.hbs"),
+            "bind {{key}} {{action}}",
+        )
+        .unwrap();
+        let _partials = PARTIALS_TEST.lock().unwrap_or_else(|e| e.into_inner());
+        let mut handlebars = Handlebars::new();
+        register_rust_helpers(&mut handlebars, Ok(Utc.timestamp(0, 0).into()));
+        register_partials(&mut handlebars, &partials);
+        let variables = serde_json::json!({"key": "a", "action": "none"});
+        let render = |template: &str| {
+            handlebars
+                .render_template(template, &variables)
+                .map_err(|e| e.to_string())
+        };
+
+        // The hash parameters are on top of the variables, only for the rendered template
+        assert_eq!(
+            render("{{render \"snippets/binding.hbs\" action=\"focus-left\"}} {{action}}").unwrap(),
+            "bind a focus-left none"
+        );
+
+        // A file of the repository comes before a partial
+        let file = directory.path().join("binding.hbs");
+        std::fs::write(&file, "file {{key}}").unwrap();
+        assert_eq!(
+            render(&format!("{{{{render {:?} key=\"b\"}}}}", file)).unwrap(),
+            "file b"
+        );
+
+        // Templates that render each other fail instead of recursing forever
+        let a = directory.path().join("a.hbs");
+        let b = directory.path().join("b.hbs");
+        std::fs::write(&a, format!("{{{{render {:?}}}}}", b)).unwrap();
+        std::fs::write(&b, format!("{{{{render {:?}}}}}", a)).unwrap();
+        let error = render(&format!("{{{{render {:?}}}}}", a)).unwrap_err();
+        assert!(error.contains("cycle"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_program_helpers() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repository = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let script = "#!/bin/sh\necho \"$1-$2\"\n";
+        for directory in [repository.path(), outside.path()] {
+            let path = directory.join("join.sh");
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let program = program_in_repository(Path::new("join.sh"), repository.path()).unwrap();
+        assert!(program_in_repository(Path::new("missing.sh"), repository.path()).is_err());
+        assert!(program_in_repository(&outside.path().join("join.sh"), repository.path()).is_err());
+        assert!(program_in_repository(Path::new(".."), repository.path()).is_err());
+
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper("join", Box::new(program_helper("join".into(), program)));
+        assert_eq!(
+            handlebars
+                .render_template("[{{join \"a\" \"b\"}}]", &())
+                .unwrap(),
+            "[a-b]"
+        );
+
+        // Without the opt-in only Rhai scripts are registered
+        let mut handlebars = Handlebars::new();
+        let mut helpers = Helpers::new();
+        helpers.insert(
+            "join".into(),
+            HelperFile::Program {
+                program: repository.path().join("join.sh"),
+            },
+        );
+        register_script_helpers(&mut handlebars, &helpers, false);
+        assert!(handlebars
+            .render_template("{{join \"a\" \"b\"}}", &())
+            .is_err());
+
+        // Only a table declares a program, a path is a Rhai script whatever its extension
+        let helpers: Helpers =
+            toml::from_str("a = \"scripts/a.sh\"\nb = { program = \"scripts/b\" }").unwrap();
+        assert_eq!(helpers["a"], HelperFile::Script("scripts/a.sh".into()));
+        assert_eq!(
+            helpers["b"],
+            HelperFile::Program {
+                program: "scripts/b".into()
+            }
+        );
+    }
+}
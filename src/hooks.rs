@@ -0,0 +1,424 @@
+use anyhow::{Context, Result};
+
+use handlebars::Handlebars;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use args::Options;
+use config::{Environment, Variables};
+use events;
+use file_state::MetadataChange;
+use filesystem;
+use render::{self, RenderLimits};
+
+/// Version of the contract between dotter and hooks: the environment variables below,
+/// and the fact that hooks which declare support for it also run during a dry run.
+/// Hooks declare it with a `dotter-hooks-api: <version>` comment.
+pub const HOOKS_API_VERSION: u32 = 1;
+
+/// Name of the environment variable that holds `HOOKS_API_VERSION`
+pub const HOOKS_API_VARIABLE: &str = "DOTTER_HOOKS_API";
+/// Name of the environment variable that points hooks at the changes file
+pub const CHANGES_FILE_VARIABLE: &str = "DOTTER_CHANGES_FILE";
+/// Name of the environment variable that is `1` during a dry run and `0` otherwise
+pub const DRY_RUN_VARIABLE: &str = "DOTTER_DRY_RUN";
+/// Name of the environment variable that holds the amount of `-v` flags
+pub const VERBOSITY_VARIABLE: &str = "DOTTER_VERBOSITY";
+/// Name of the environment variable that holds the cache directory
+pub const CACHE_DIR_VARIABLE: &str = "DOTTER_CACHE_DIR";
+
+const API_DECLARATION: &str = "dotter-hooks-api:";
+
+/// Public DNS servers whose HTTPS port tells whether the machine is online, without relying on
+/// name resolution, which can hang for a long time when it's offline. `network_probes` of
+/// global.toml or local.toml replaces them, like where they're blocked.
+const NETWORK_PROBES: &[&str] = &["1.1.1.1:443", "8.8.8.8:443"];
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A single target whose contents or metadata were changed during a run.
+/// Hashes are `None` when the target didn't exist before/after the change.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub action: ChangeAction,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<MetadataChange>,
+}
+
+impl Change {
+    /// Returns `None` if neither the contents nor the metadata of `target` changed
+    pub fn detect(
+        action: ChangeAction,
+        source: &Path,
+        target: &Path,
+        old_hash: Option<String>,
+        metadata: Vec<MetadataChange>,
+    ) -> Result<Option<Change>> {
+        let new_hash = filesystem::hash_file(target).context("hash target after change")?;
+        if old_hash == new_hash && metadata.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Change {
+            source: source.into(),
+            target: target.into(),
+            action,
+            old_hash,
+            new_hash,
+            metadata,
+        }))
+    }
+}
+
+pub fn write_changes_file(location: &Path, changes: &[Change]) -> Result<()> {
+    debug!("Writing {} changes to {:?}", changes.len(), location);
+    fs::create_dir_all(location.parent().context("get parent of changes file")?)
+        .context("create parent of changes file")?;
+    let contents = serde_json::to_string_pretty(changes).context("serialize changes")?;
+    fs::write(location, contents).context("write changes file")?;
+    Ok(())
+}
+
+/// Hooks of files and their packages by the source of the file, see `changed_hooks`
+pub type HooksBySource = BTreeMap<PathBuf, BTreeSet<PathBuf>>;
+
+/// Where a hook runs, from the `cwd` and `path_prepend` of its package. The paths are relative
+/// to the repository, so that a hook runs the same wherever dotter was started from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookEnvironment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_prepend: Vec<PathBuf>,
+    /// From `requires_network` of the package, see `network_available`
+    #[serde(default)]
+    pub requires_network: bool,
+    /// The package's `[env]`, on top of the one of every child process
+    #[serde(default, skip_serializing_if = "Environment::is_empty")]
+    pub env: Environment,
+}
+
+/// Environments of the hooks that don't run in the repository with the inherited `PATH`,
+/// by hook
+pub type HookEnvironments = BTreeMap<PathBuf, HookEnvironment>;
+
+/// Returns the `on_undeploy` hooks of the deleted files followed by the `on_deploy` hooks of
+/// the created and updated ones, running every hook once even if several of its files changed
+pub fn changed_hooks(
+    changes: &[Change],
+    on_deploy: &HooksBySource,
+    on_undeploy: &HooksBySource,
+) -> Vec<PathBuf> {
+    let hooks_of = |deleted: bool| {
+        changes
+            .iter()
+            .filter(move |change| (change.action == ChangeAction::Deleted) == deleted)
+            .filter_map(move |change| {
+                if deleted {
+                    on_undeploy.get(&change.source)
+                } else {
+                    on_deploy.get(&change.source)
+                }
+            })
+            .flatten()
+    };
+    let mut hooks = Vec::new();
+    for hook in hooks_of(true).chain(hooks_of(false)) {
+        if !hooks.contains(hook) {
+            hooks.push(hook.clone());
+        }
+    }
+    hooks
+}
+
+/// Renders the hook at `location` as a template into the cache directory and runs it.
+/// Does nothing if the hook doesn't exist, or if this is a dry run and the hook doesn't
+/// declare that it supports the hooks API.
+pub fn run_hook(
+    location: &Path,
+    opt: &Options,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    changes_file: Option<&Path>,
+    environment: &HookEnvironment,
+) -> Result<()> {
+    if !location.exists() {
+        debug!("Hook {:?} doesn't exist, skipping", location);
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(location).context("read hook file")?;
+    match declared_api_version(&contents) {
+        Some(version) if version > HOOKS_API_VERSION => bail!(
+            "hook {:?} requires hooks API version {}, but this dotter supports version {}",
+            location,
+            version,
+            HOOKS_API_VERSION
+        ),
+        None if !opt.act => {
+            info!(
+                "Skipping hook {:?} during dry run because it doesn't declare `{} {}`",
+                location, API_DECLARATION, HOOKS_API_VERSION
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let mut script_file = rendered_hook_path(&opt.cache_directory, location)?;
+    if cfg!(windows) {
+        script_file.set_extension("bat");
+    }
+    debug!("Rendering hook {:?} -> {:?}", location, script_file);
+    let rendered = render::render(handlebars, &contents, variables, &RenderLimits::new(opt))
+        .with_context(|| format!("render hook {:?}", location))?;
+    fs::create_dir_all(
+        script_file
+            .parent()
+            .context("get parent of rendered hook")?,
+    )
+    .context("create parent of rendered hook")?;
+    fs::write(&script_file, rendered).context("write rendered hook")?;
+
+    // Hooks that run in another directory still find what dotter points them at
+    let repository = std::env::current_dir().context("get current directory")?;
+    let script_file = repository.join(script_file);
+    debug!("Running hook {:?}", script_file);
+    let mut command = if cfg!(windows) {
+        Command::new(&script_file)
+    } else {
+        let mut command = Command::new("sh");
+        command.arg(&script_file);
+        command
+    };
+    command
+        .env(HOOKS_API_VARIABLE, HOOKS_API_VERSION.to_string())
+        .env(DRY_RUN_VARIABLE, if opt.act { "0" } else { "1" })
+        .env(VERBOSITY_VARIABLE, opt.verbosity.to_string())
+        .env(CACHE_DIR_VARIABLE, repository.join(&opt.cache_directory));
+    filesystem::set_child_environment(&mut command);
+    if let Some(changes_file) = changes_file {
+        command.env(CHANGES_FILE_VARIABLE, repository.join(changes_file));
+    }
+    apply_environment(&mut command, &repository, environment)?;
+    let status = command
+        .stdout(events::child_stdout())
+        .status()
+        .context("spawn hook")?;
+    if !status.success() {
+        bail!("hook {:?} exited with {}", location, status);
+    }
+
+    Ok(())
+}
+
+/// Where the hook at `location` is rendered to. A hook in the repository keeps its path under
+/// the cache directory, while one elsewhere goes in `hooks/` under a hash of its path, so that
+/// rendering never writes over the hook itself or outside the cache directory.
+fn rendered_hook_path(cache_directory: &Path, location: &Path) -> Result<PathBuf> {
+    if location
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        bail!("hook {:?} can't contain `..`", location);
+    }
+    if location.has_root() || location.is_absolute() {
+        let hash = blake3::hash(location.to_string_lossy().as_bytes()).to_hex();
+        let name = location.file_name().context("get file name of hook")?;
+        return Ok(cache_directory
+            .join("hooks")
+            .join(&hash.as_str()[..16])
+            .join(name));
+    }
+    Ok(cache_directory.join(location))
+}
+
+/// Runs the hook in its directory and with its `PATH`, on top of the `PATH` from `[env]`
+/// if that sets one
+fn apply_environment(
+    command: &mut Command,
+    repository: &Path,
+    environment: &HookEnvironment,
+) -> Result<()> {
+    command.envs(&environment.env);
+    if let Some(cwd) = &environment.cwd {
+        let cwd = repository.join(cwd);
+        if !cwd.is_dir() {
+            bail!("working directory {:?} of hook doesn't exist", cwd);
+        }
+        command.current_dir(cwd);
+    }
+    if !environment.path_prepend.is_empty() {
+        let inherited = command
+            .get_envs()
+            .find(|(name, _)| *name == "PATH")
+            .and_then(|(_, value)| value.map(OsString::from))
+            .or_else(|| std::env::var_os("PATH"))
+            .unwrap_or_default();
+        let path = std::env::join_paths(
+            environment
+                .path_prepend
+                .iter()
+                .map(|directory| repository.join(directory))
+                .chain(std::env::split_paths(&inherited)),
+        )
+        .context("join PATH of hook")?;
+        command.env("PATH", path);
+    }
+    Ok(())
+}
+
+pub fn default_network_probes() -> Vec<String> {
+    NETWORK_PROBES
+        .iter()
+        .map(|probe| probe.to_string())
+        .collect()
+}
+
+/// Whether the machine can connect to any of `probes`, for the hooks of packages with
+/// `requires_network = true`. Probes that are host names are resolved first.
+pub fn network_available(probes: &[String]) -> bool {
+    let available = probes.iter().any(|probe| {
+        let addresses = match probe.to_socket_addrs() {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                debug!("Can't probe {:?}: {}", probe, e);
+                return false;
+            }
+        };
+        addresses
+            .into_iter()
+            .any(|address| TcpStream::connect_timeout(&address, NETWORK_TIMEOUT).is_ok())
+    });
+    debug!("Network available: {}", available);
+    available
+}
+
+/// Finds a `dotter-hooks-api: <version>` declaration anywhere in the hook
+fn declared_api_version(contents: &str) -> Option<u32> {
+    contents.lines().find_map(|line| {
+        let (_, rest) = line.split_once(API_DECLARATION)?;
+        rest.split_whitespace().next()?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_declared_api_version() {
+        assert_eq!(
+            declared_api_version("#!/bin/sh\n# dotter-hooks-api: 1\necho hi\n"),
+            Some(1)
+        );
+        assert_eq!(declared_api_version("rem dotter-hooks-api:2"), Some(2));
+        assert_eq!(declared_api_version("echo hi\n"), None);
+    }
+
+    #[test]
+    fn test_network_available() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let probe = listener.local_addr().unwrap().to_string();
+        assert!(network_available(&["not a probe".into(), probe]));
+        assert!(!network_available(&["not a probe".into()]));
+        assert!(!network_available(&[]));
+    }
+
+    #[test]
+    fn test_changed_hooks() {
+        let change = |source: &str, action| Change {
+            source: source.into(),
+            target: Path::new("/home/me").join(source),
+            action,
+            old_hash: None,
+            new_hash: None,
+            metadata: Vec::new(),
+        };
+        let hooks = |entries: &[(&str, &[&str])]| -> HooksBySource {
+            entries
+                .iter()
+                .map(|(source, hooks)| {
+                    let hooks = hooks.iter().map(PathBuf::from).collect();
+                    (PathBuf::from(source), hooks)
+                })
+                .collect()
+        };
+        let on_deploy = hooks(&[
+            ("fonts/a.ttf", &["fonts.sh"]),
+            ("fonts/b.ttf", &["fonts.sh"]),
+            ("tmux.conf", &["tmux.sh", "shell.sh"]),
+        ]);
+        let on_undeploy = hooks(&[("fonts/a.ttf", &["fonts.sh"]), ("zshrc", &["zsh.sh"])]);
+
+        let changes = [
+            change("fonts/a.ttf", ChangeAction::Created),
+            change("fonts/b.ttf", ChangeAction::Updated),
+            change("zshrc", ChangeAction::Deleted),
+            change("vimrc", ChangeAction::Updated),
+        ];
+        assert_eq!(
+            changed_hooks(&changes, &on_deploy, &on_undeploy),
+            vec![PathBuf::from("zsh.sh"), PathBuf::from("fonts.sh")]
+        );
+        assert_eq!(
+            changed_hooks(
+                &[change("tmux.conf", ChangeAction::Updated)],
+                &on_deploy,
+                &on_undeploy
+            ),
+            vec![PathBuf::from("shell.sh"), PathBuf::from("tmux.sh")]
+        );
+        assert!(changed_hooks(&[], &on_deploy, &on_undeploy).is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_hook_outside_repository() {
+        let directory = tempfile::tempdir().unwrap();
+        let hook = directory.path().join("pre_deploy.sh");
+        let output = directory.path().join("output");
+        let contents = format!("echo {{{{name}}}} > {:?}\n", output);
+        fs::write(&hook, &contents).unwrap();
+        let opt = Options {
+            cache_directory: directory.path().join("cache"),
+            act: true,
+            ..Options::default()
+        };
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "rendered".into());
+
+        run_hook(
+            &hook,
+            &opt,
+            &Handlebars::new(),
+            &variables,
+            None,
+            &HookEnvironment::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "rendered\n");
+        assert_eq!(fs::read_to_string(&hook).unwrap(), contents);
+        assert!(rendered_hook_path(&opt.cache_directory, &hook)
+            .unwrap()
+            .starts_with(&opt.cache_directory));
+        assert!(rendered_hook_path(&opt.cache_directory, Path::new("hooks/../../x.sh")).is_err());
+    }
+}
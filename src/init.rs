@@ -1,12 +1,39 @@
 use anyhow::{Context, Result};
 
+use std::fs;
+use std::io;
+use std::path::Path;
+
 use args::Options;
-use config;
+use clean_cache;
+use config::{self, InitialPackages};
+use filesystem;
+
+/// Dotfiles that are offered for import: location relative to home, name in the repository,
+/// and the package they go into when every application gets its own package
+const KNOWN_DOTFILES: &[(&str, &str, &str)] = &[
+    (".bashrc", "bashrc", "bash"),
+    (".bash_profile", "bash_profile", "bash"),
+    (".zshrc", "zshrc", "zsh"),
+    (".zprofile", "zprofile", "zsh"),
+    (".profile", "profile", "shell"),
+    (".inputrc", "inputrc", "readline"),
+    (".vimrc", "vimrc", "vim"),
+    (".config/nvim", "nvim", "neovim"),
+    (".gitconfig", "gitconfig", "git"),
+    (".tmux.conf", "tmux.conf", "tmux"),
+    (".config/fish", "fish", "fish"),
+    (".config/alacritty", "alacritty", "alacritty"),
+    (".config/kitty", "kitty", "kitty"),
+    (".config/starship.toml", "starship.toml", "starship"),
+];
+
+const DEFAULT_PACKAGE: &str = "default";
 
 pub fn init(opt: Options) -> Result<()> {
     info!("Looking for existing configuration...");
     if opt.global_config.exists() {
-        if opt.force {
+        if opt.force.is_some() {
             warn!("Configuration already exists. Overwriting because of --force");
         } else {
             bail!("Configuration already exists. Use --force to overwrite.");
@@ -15,9 +42,19 @@ pub fn init(opt: Options) -> Result<()> {
         info!("No existing configuration.");
     }
 
+    // Without prompts, everything goes into a single package and nothing is imported
+    let per_application = opt.interactive
+        && ask_choice(
+            "How should the repository be organized?",
+            &[
+                "A single package with all the files",
+                "A package for every application",
+            ],
+        ) == 1;
+
     debug!("Reading files from current directory...");
-    let mut files = Vec::new();
-    for file in std::fs::read_dir(".").context("read contents of current directory")? {
+    let mut packages = InitialPackages::new();
+    for file in fs::read_dir(".").context("read contents of current directory")? {
         let file = file.context("get next file")?;
         let name = file
             .file_name()
@@ -27,28 +64,96 @@ pub fn init(opt: Options) -> Result<()> {
             debug!("Ignored file {:?}", name);
             continue;
         }
-        files.push(name);
+        let package = if per_application {
+            name.clone()
+        } else {
+            DEFAULT_PACKAGE.into()
+        };
+        packages
+            .entry(package)
+            .or_default()
+            .push((name, String::new()));
+    }
+
+    if opt.interactive {
+        import_dotfiles(&mut packages, per_application).context("import dotfiles")?;
     }
-    trace!("Files: {:#?}", files);
 
-    config::save_dummy_config(files, &opt.local_config, &opt.global_config)
-        .context("save dummy config")?;
+    if packages.is_empty() {
+        packages.insert(DEFAULT_PACKAGE.into(), Vec::new());
+    }
+    trace!("Packages: {:#?}", packages);
+
+    config::save_initial_config(&packages, &opt.local_config, &opt.global_config)
+        .context("save initial config")?;
 
     debug!("Emptying cache...");
-    config::save_cache(
-        &opt.cache_file,
-        config::Cache {
-            symlinks: Default::default(),
-            templates: Default::default(),
-        },
-    )
-    .context("save empty cache file")?;
-    match std::fs::remove_dir_all(opt.cache_directory) {
-        Ok(()) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-        Err(e) => Err(e),
+    clean_cache::empty_cache(&opt.cache_file, &opt.cache_directory)?;
+
+    Ok(())
+}
+
+/// Offers to copy every known dotfile that exists in the home directory into the repository
+fn import_dotfiles(packages: &mut InitialPackages, per_application: bool) -> Result<()> {
+    let home = filesystem::home_dir();
+    let mut imported = false;
+    for (location, source, application) in KNOWN_DOTFILES {
+        let original = home.join(location);
+        if !original.exists() || filesystem::is_link(&original) {
+            continue;
+        }
+        if Path::new(source).exists() {
+            warn!(
+                "Not offering to import {:?} because {:?} already exists in the repository",
+                original, source
+            );
+            continue;
+        }
+        if !filesystem::ask_boolean(&format!(
+            "Found {:?}. Import it into the repository [y/N]? ",
+            original
+        )) {
+            continue;
+        }
+
+        filesystem::copy_recursively(&original, Path::new(source))
+            .with_context(|| format!("copy {:?} into the repository", original))?;
+        let package = if per_application {
+            application
+        } else {
+            DEFAULT_PACKAGE
+        };
+        packages
+            .entry(package.to_string())
+            .or_default()
+            .push((source.to_string(), format!("~/{}", location)));
+        imported = true;
+    }
+
+    if imported {
+        info!("The imported files weren't removed from their original location. Run `dotter deploy --force` to replace them with the files from the repository.");
     }
-    .context("remove cache directory")?;
 
     Ok(())
 }
+
+/// Prompts until one of the options is chosen, and returns its index
+fn ask_choice(prompt: &str, options: &[&str]) -> usize {
+    loop {
+        eprintln!("{}", prompt);
+        for (i, option) in options.iter().enumerate() {
+            eprintln!("  {}) {}", i + 1, option);
+        }
+        eprint!("Choose [1-{}]: ", options.len());
+
+        let mut buf = String::new();
+        io::stdin()
+            .read_line(&mut buf)
+            .expect("Failed to read line from stdin");
+        match buf.trim().parse::<usize>() {
+            Ok(choice) if choice >= 1 && choice <= options.len() => return choice - 1,
+            _ if buf.is_empty() => return 0, // End of input, use the first option
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use filesystem;
+
+/// Directory inside the cache where a transactional deploy backs up the files it changes
+const JOURNAL_DIRECTORY: &str = ".dotter_journal";
+
+/// File in the journal's directory that lists its entries, so that a deploy that was killed
+/// can still be rolled back by the next one
+const ENTRIES_FILE: &str = "journal.toml";
+
+/// Every path that a transactional deploy touched, with what was there before, so that the
+/// deploy can be undone if one of its steps fails
+#[derive(Debug)]
+pub struct Journal {
+    backups: PathBuf,
+    entries: Entries,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entries {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    path: PathBuf,
+    previous: Previous,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "path", rename_all = "snake_case")]
+enum Previous {
+    /// Nothing was there. The directories are the missing parents of the path, deepest first.
+    Missing(Vec<PathBuf>),
+    /// A file, which was copied into the journal's directory
+    File(PathBuf),
+    /// A symlink, with what it pointed at
+    Symlink(PathBuf),
+    /// A directory, which was copied into the journal's directory with everything in it
+    Directory(PathBuf),
+}
+
+impl Journal {
+    pub fn new(cache_directory: &Path) -> Result<Journal> {
+        Journal::at(cache_directory.join(JOURNAL_DIRECTORY))
+    }
+
+    /// A journal that keeps its backups in `backups`, for one that's kept next to the
+    /// deploy's own. A journal that a killed deploy left there is rolled back first, so that
+    /// its files are the way that the cache remembers them.
+    pub fn at(backups: PathBuf) -> Result<Journal> {
+        if backups.exists() {
+            warn!(
+                "Rolling back a deploy that was interrupted, from its journal at {:?}",
+                backups
+            );
+            Journal::open(backups.clone())
+                .and_then(Journal::rollback)
+                .context("roll back interrupted deploy")?;
+        }
+        fs::create_dir_all(&backups).context("create journal directory")?;
+        let journal = Journal {
+            backups,
+            entries: Entries::default(),
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// The journal that a deploy left in `backups`
+    fn open(backups: PathBuf) -> Result<Journal> {
+        let file = backups.join(ENTRIES_FILE);
+        let entries = match filesystem::load_file(&file) {
+            Ok(entries) => entries,
+            // The deploy was killed before it recorded anything
+            Err(filesystem::FileLoadError::Open(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Entries::default()
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                    "read journal {:?}, restore the files from its backups by hand and remove it",
+                    file
+                )
+                })
+            }
+        };
+        Ok(Journal { backups, entries })
+    }
+
+    fn save(&self) -> Result<()> {
+        filesystem::save_file(&self.backups.join(ENTRIES_FILE), &self.entries)
+            .context("save journal")
+    }
+
+    /// Remembers what is at `path` before it's changed. Only the first state of every path
+    /// is kept, since that's the one a rollback restores.
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        if self.entries.entries.iter().any(|e| e.path == path) {
+            return Ok(());
+        }
+        let previous = match fs::symlink_metadata(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Previous::Missing(missing_parents(path))
+            }
+            Err(e) => return Err(e).with_context(|| format!("inspect {:?}", path)),
+            Ok(metadata) if metadata.file_type().is_symlink() => Previous::Symlink(
+                fs::read_link(path).with_context(|| format!("read symlink {:?}", path))?,
+            ),
+            Ok(metadata) if metadata.is_dir() => {
+                let backup = self.backups.join(self.entries.entries.len().to_string());
+                copy_directory(path, &backup).with_context(|| format!("back up {:?}", path))?;
+                Previous::Directory(backup)
+            }
+            Ok(_) => {
+                let backup = self.backups.join(self.entries.entries.len().to_string());
+                fs::copy(path, &backup).with_context(|| format!("back up {:?}", path))?;
+                Previous::File(backup)
+            }
+        };
+        trace!("Journal: {:?} was {:?}", path, previous);
+        self.entries.entries.push(Entry {
+            path: path.into(),
+            previous,
+        });
+        // Saved before the path is changed, so that a killed deploy can be rolled back
+        self.save()
+    }
+
+    /// Puts every recorded path back the way it was, last change first. Restoring continues
+    /// past failures, and fails at the end if any path couldn't be restored.
+    pub fn rollback(self) -> Result<()> {
+        let mut failed = Vec::new();
+        for entry in self.entries.entries.iter().rev() {
+            debug!("Restoring {:?}", entry.path);
+            if let Err(e) = entry.restore() {
+                error!("Failed to restore {:?}: {:#}", entry.path, e);
+                failed.push(entry.path.clone());
+            }
+        }
+        if !failed.is_empty() {
+            bail!(
+                "couldn't restore {} files, their backups are kept in {:?}",
+                failed.len(),
+                self.backups
+            );
+        }
+        fs::remove_dir_all(&self.backups).context("remove journal directory")
+    }
+
+    /// Drops the backups once the deploy succeeded
+    pub fn commit(self) -> Result<()> {
+        fs::remove_dir_all(&self.backups).context("remove journal directory")
+    }
+}
+
+impl Entry {
+    fn restore(&self) -> Result<()> {
+        match fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.is_dir() => {
+                fs::remove_dir_all(&self.path).context("remove directory")?
+            }
+            Ok(_) => fs::remove_file(&self.path).context("remove file")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("inspect file"),
+        }
+
+        match &self.previous {
+            Previous::Missing(directories) => {
+                for directory in directories {
+                    // Only empty directories are removed, other files may have been put there since
+                    let _ = fs::remove_dir(directory);
+                }
+            }
+            Previous::File(backup) => {
+                create_parent(&self.path)?;
+                fs::copy(backup, &self.path).context("copy backup")?;
+            }
+            Previous::Symlink(pointee) => {
+                create_parent(&self.path)?;
+                filesystem::make_symlink(&self.path, pointee).context("create symlink")?;
+            }
+            Previous::Directory(backup) => {
+                create_parent(&self.path)?;
+                copy_directory(backup, &self.path).context("copy backup")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deleting a file can delete its empty parents too
+fn create_parent(path: &Path) -> Result<()> {
+    fs::create_dir_all(path.parent().context("get parent of file")?).context("create parent")
+}
+
+/// Copies a directory with everything in it, keeping symlinks as symlinks
+fn copy_directory(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir(to).with_context(|| format!("create directory {:?}", to))?;
+    for child in fs::read_dir(from).context("read contents of directory")? {
+        let child = child.context("get next file")?;
+        let (from, to) = (child.path(), to.join(child.file_name()));
+        let file_type = child.file_type().context("get file type")?;
+        if file_type.is_symlink() {
+            let pointee = fs::read_link(&from).context("read symlink")?;
+            filesystem::make_symlink(&to, &pointee).context("copy symlink")?;
+        } else if file_type.is_dir() {
+            copy_directory(&from, &to)?;
+        } else {
+            fs::copy(&from, &to).with_context(|| format!("copy file {:?}", from))?;
+        }
+    }
+    Ok(())
+}
+
+fn missing_parents(path: &Path) -> Vec<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .take_while(|parent| !parent.as_os_str().is_empty() && !parent.exists())
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rollback_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("journal");
+        let file = dir.path().join("file");
+        let directory = dir.path().join("directory");
+        let created = dir.path().join("new/created");
+        fs::write(&file, "before").unwrap();
+        fs::create_dir(&directory).unwrap();
+        fs::write(directory.join("inside"), "before").unwrap();
+
+        let mut journal = Journal::at(backups.clone()).unwrap();
+        for path in [&file, &directory, &created] {
+            journal.record(path).unwrap();
+        }
+        fs::write(&file, "after").unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        fs::write(&directory, "after").unwrap();
+        fs::create_dir_all(created.parent().unwrap()).unwrap();
+        fs::write(&created, "after").unwrap();
+        // The deploy is killed before it commits or rolls back
+        std::mem::forget(journal);
+
+        Journal::at(backups.clone()).unwrap().commit().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "before");
+        assert_eq!(
+            fs::read_to_string(directory.join("inside")).unwrap(),
+            "before"
+        );
+        assert!(!created.parent().unwrap().exists());
+        assert!(!backups.exists());
+    }
+}
@@ -0,0 +1,261 @@
+//! Packages with `lazy = true`, which are deployed on first use: until then only a shim is
+//! deployed at the target of each of their executables, which runs `dotter activate` for the
+//! package and then the real program. Their other files wait for the activation.
+
+use anyhow::{Context, Result};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config::{self, Configuration, FilePackages, Files};
+use deploy;
+use filesystem;
+use fleet::shell_quote;
+
+/// First line after the shebang of every shim, so that only shims are replaced and removed
+const SHIM_MARKER: &str = "# dotter shim of lazy package ";
+
+/// Takes the files of the lazy packages that weren't activated yet out of the configuration,
+/// and puts the shims that stand in for their executables in `config.shims`
+pub fn hold_back(config: &mut Configuration, activated: &BTreeSet<String>) -> Result<()> {
+    let waiting = config
+        .lazy_packages
+        .iter()
+        .filter(|package| !activated.contains(*package))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    if waiting.is_empty() {
+        return Ok(());
+    }
+    if cfg!(windows) {
+        warn!(
+            "Lazy packages need a shell to run their shims, deploying {:?} right away",
+            waiting
+        );
+        return Ok(());
+    }
+    config.shims = hold_back_files(&mut config.files, &config.file_packages, &waiting)?;
+    Ok(())
+}
+
+fn hold_back_files(
+    files: &mut Files,
+    file_packages: &FilePackages,
+    waiting: &BTreeSet<String>,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let mut shims = BTreeMap::new();
+    let mut held = Vec::new();
+    for (source, target) in files.iter() {
+        let package = match file_packages.get(source) {
+            Some(package) if waiting.contains(package) => package,
+            _ => continue,
+        };
+        held.push(source.clone());
+        let executable = source.is_file()
+            && filesystem::file_mode(source)
+                .context("get mode of source")?
+                .is_some_and(|mode| mode & 0o111 != 0);
+        if executable {
+            shims.insert(target.path().to_path_buf(), package.clone());
+        } else {
+            debug!("Holding back {:?} until {:?} is activated", source, package);
+        }
+    }
+    for source in held {
+        files.remove(&source);
+    }
+    for package in waiting {
+        if !shims.values().any(|shimmed| shimmed == package) {
+            warn!(
+                "Lazy package {:?} has no executable file to put a shim in place of, so it's only deployed by `dotter activate {}`",
+                package, package
+            );
+        }
+    }
+    Ok(shims)
+}
+
+/// Removes the shims of `existing` that aren't `desired` anymore, and puts the desired ones in
+/// place. Targets that are taken by something other than a shim are left alone. Returns the
+/// shims that are in place.
+pub fn update_shims(
+    opt: &Options,
+    desired: &BTreeMap<PathBuf, String>,
+    existing: &BTreeMap<PathBuf, String>,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let mut in_place = BTreeMap::new();
+    for (target, package) in existing {
+        if desired.get(target) == Some(package) {
+            continue;
+        }
+        if !is_shim(target) {
+            debug!("{:?} isn't a shim anymore", target);
+            continue;
+        }
+        info!("Removing the shim of {:?} at {:?}", package, target);
+        if opt.act {
+            if let Err(e) = fs::remove_file(target) {
+                warn!("Failed to remove the shim at {:?}: {}", target, e);
+                in_place.insert(target.clone(), package.clone());
+            }
+        }
+    }
+
+    for (target, package) in desired {
+        let contents = shim(opt, target, package)?;
+        if target.symlink_metadata().is_ok() && !is_shim(target) {
+            warn!(
+                "Not putting the shim of lazy package {:?} at {:?}, since something else is there",
+                package, target
+            );
+            continue;
+        }
+        if fs::read_to_string(target).ok().as_deref() != Some(contents.as_str()) {
+            info!(
+                "Putting the shim of lazy package {:?} at {:?}",
+                package, target
+            );
+            if opt.act {
+                write_shim(target, &contents)
+                    .with_context(|| format!("write shim at {:?}", target))?;
+            }
+        }
+        in_place.insert(target.clone(), package.clone());
+    }
+    Ok(in_place)
+}
+
+/// Deploys the files of the lazy `package` for real, and keeps deploying them from now on
+pub fn activate(opt: &Options, package: &str) -> Result<bool> {
+    let config = deploy::load_configuration(opt).context("load configuration")?;
+    if !config.lazy_packages.contains(package) {
+        bail!("package {:?} isn't an enabled lazy package", package);
+    }
+    {
+        let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+        let mut cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+        if cache.activated.insert(package.into()) {
+            info!("Activating lazy package {:?}", package);
+            if opt.act {
+                config::save_cache(&opt.cache_file, cache)?;
+            }
+        } else {
+            info!("Lazy package {:?} is already activated", package);
+        }
+    }
+    let opt = Options {
+        only: vec![package.into()],
+        ..opt.clone()
+    };
+    deploy::deploy(&opt, &Default::default()).context("deploy package")
+}
+
+/// Removes the shims of `existing`, for `undeploy`. Returns the ones that are still there.
+pub fn remove_shims(
+    opt: &Options,
+    existing: &BTreeMap<PathBuf, String>,
+) -> Result<BTreeMap<PathBuf, String>> {
+    update_shims(opt, &BTreeMap::new(), existing)
+}
+
+fn write_shim(target: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context("create parent directory")?;
+    }
+    fs::write(target, contents).context("write shim")?;
+    filesystem::set_mode(target, 0o755).context("make shim executable")
+}
+
+/// Whether `target` is a shim, and not what replaced it
+pub fn is_shim(target: &Path) -> bool {
+    fs::read_to_string(target)
+        .map(|contents| {
+            contents
+                .lines()
+                .nth(1)
+                .unwrap_or("")
+                .starts_with(SHIM_MARKER)
+        })
+        .unwrap_or(false)
+}
+
+/// A script that activates `package` from the repository, and then runs what replaced it.
+/// It refuses to run itself again if the activation left it in place.
+fn shim(opt: &Options, target: &Path, package: &str) -> Result<String> {
+    let repository = std::env::current_dir().context("get current directory")?;
+    let executable = std::env::current_exe().context("get path of dotter")?;
+    let mut dotter = vec![executable.to_string_lossy().into_owned()];
+    for (flag, path) in [
+        ("--global-config", &opt.global_config),
+        ("--local-config", &opt.local_config),
+        ("--cache-file", &opt.cache_file),
+        ("--cache-directory", &opt.cache_directory),
+    ] {
+        dotter.push(flag.into());
+        dotter.push(repository.join(path).to_string_lossy().into_owned());
+    }
+    if let Some(home) = &opt.home {
+        dotter.push("--home".into());
+        dotter.push(repository.join(home).to_string_lossy().into_owned());
+    }
+    let dotter = dotter
+        .iter()
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let package = shell_quote(package);
+    Ok(format!(
+        r#"#!/bin/sh
+{marker}{package}
+if [ "$DOTTER_ACTIVATING" = {package} ]; then
+    echo "dotter: activating lazy package $DOTTER_ACTIVATING didn't replace $0" >&2
+    exit 1
+fi
+DOTTER_ACTIVATING={package}
+export DOTTER_ACTIVATING
+(cd {repository} && {dotter} activate {package}) >&2 || exit 1
+exec {target} "$@"
+"#,
+        marker = SHIM_MARKER,
+        package = package,
+        repository = shell_quote(&repository.to_string_lossy()),
+        dotter = dotter,
+        target = shell_quote(&target.to_string_lossy()),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hold_back_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let program = directory.path().join("program");
+        let config = directory.path().join("config");
+        let other = directory.path().join("other");
+        for source in [&program, &config, &other] {
+            fs::write(source, "").unwrap();
+        }
+        filesystem::set_mode(&program, 0o755).unwrap();
+
+        let mut files = Files::new();
+        let mut file_packages = FilePackages::new();
+        for (source, package) in [(&program, "tool"), (&config, "tool"), (&other, "shell")] {
+            let target = Path::new("/home/user").join(source.file_name().unwrap());
+            files.insert(source.clone(), config::FileTarget::Automatic(target));
+            file_packages.insert(source.clone(), package.into());
+        }
+        let waiting = std::iter::once("tool".to_string()).collect();
+
+        let shims = hold_back_files(&mut files, &file_packages, &waiting).unwrap();
+        assert_eq!(
+            shims.into_iter().collect::<Vec<_>>(),
+            [(PathBuf::from("/home/user/program"), "tool".to_string())]
+        );
+        assert_eq!(files.keys().collect::<Vec<_>>(), [&other]);
+    }
+}
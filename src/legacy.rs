@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use toml::value::{Table, Value};
+
+use args::Options;
+use deploy::{self, Detection};
+use difference;
+use filesystem;
+
+/// Package that the files and variables of a configuration from before packages go into
+const DEFAULT_PACKAGE: &str = "default";
+
+/// Rewrites configuration and cache files that are in the format of an older dotter, keeping
+/// the original next to them with a `.bak` extension:
+///
+/// - global.toml with top-level `[files]` and `[variables]` from before packages
+/// - file targets written as tables without a `type`, from before symbolic and template targets
+///   were told apart
+/// - cache.toml with a single `[files]` table instead of `[symlinks]` and `[templates]`
+///
+/// A dry run prints what would change instead. Returns whether any file was in the format of an
+/// older dotter.
+pub fn migrate(opt: &Options) -> Result<bool> {
+    let is_template = |source: &str| {
+        matches!(
+            deploy::detect_template(Path::new(source)),
+            Ok(Detection::Braces { .. })
+        )
+    };
+
+    let mut packages_added = false;
+    let mut migrated = migrate_file(opt, &opt.global_config, true, |global| {
+        packages_added = migrate_global(global);
+        let targets = package_files(global)
+            .into_iter()
+            .map(|files| add_target_types(files, &is_template))
+            .fold(false, |a, b| a | b);
+        Ok(packages_added | targets)
+    })
+    .context("migrate global config")?;
+
+    migrated |= migrate_file(opt, &opt.local_config, true, |local| {
+        let table = local.as_table_mut().context("local config isn't a table")?;
+        let mut changed = false;
+        if packages_added && !table.contains_key("packages") {
+            table.insert(
+                "packages".into(),
+                Value::Array(vec![Value::String(DEFAULT_PACKAGE.into())]),
+            );
+            changed = true;
+        }
+        if let Some(Value::Table(files)) = table.get_mut("files") {
+            changed |= add_target_types(files, &is_template);
+        }
+        Ok(changed)
+    })
+    .context("migrate local config")?;
+
+    migrated |= migrate_file(opt, &opt.cache_file, false, |cache| {
+        migrate_cache(cache, |source| opt.cache_directory.join(source).is_file())
+    })
+    .context("migrate cache")?;
+    Ok(migrated)
+}
+
+/// Converts the file with `convert`, which returns whether anything changed. Returns whether
+/// it needed converting.
+fn migrate_file(
+    opt: &Options,
+    path: &Path,
+    in_repository: bool,
+    convert: impl FnOnce(&mut Value) -> Result<bool>,
+) -> Result<bool> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("read {:?}", path)),
+    };
+    let mut value: Value = match toml::from_str(&contents) {
+        Ok(value) => value,
+        // Loading it reports the error properly
+        Err(_) => return Ok(false),
+    };
+    if !convert(&mut value)? {
+        return Ok(false);
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    if !opt.act {
+        info!(
+            "{:?} is in the format of an older dotter. Without --dry-run it's migrated like this, keeping the original in {:?}:",
+            path, backup
+        );
+        let migrated = toml::to_string(&value).context("serialize migrated file")?;
+        difference::print_diff(
+            difference::diff_contents(&contents, &migrated),
+            opt.diff_context_lines,
+        );
+        return Ok(true);
+    }
+    if in_repository && opt.read_only_repo {
+        bail!(
+            "{:?} is in the format of an older dotter, but the repository is read-only because of --read-only-repo. Run `dotter migrate` without it once.",
+            path
+        );
+    }
+    if backup.exists() {
+        bail!(
+            "{:?} is in the format of an older dotter, but {:?} is in the way of its backup",
+            path,
+            backup
+        );
+    }
+    warn!(
+        "Migrating {:?} from the format of an older dotter, the original is kept in {:?}",
+        path, backup
+    );
+    fs::copy(path, &backup).context("back up original")?;
+    filesystem::save_file(path, value).context("save migrated file")?;
+    Ok(true)
+}
+
+/// Moves the top-level `files` and `variables` into the default package, if they're from
+/// before packages. Returns whether it did.
+fn migrate_global(global: &mut Value) -> bool {
+    let table = match global.as_table_mut() {
+        Some(table) => table,
+        None => return false,
+    };
+    // A package called `files` has tables of files and variables, not targets
+    let legacy = match table.get("files") {
+        Some(Value::Table(files)) => files
+            .values()
+            .any(|v| v.is_str() || v.get("target").is_some()),
+        _ => false,
+    };
+    if !legacy || table.contains_key(DEFAULT_PACKAGE) {
+        return false;
+    }
+
+    let mut package = Table::new();
+    for key in ["files", "variables"] {
+        if let Some(value) = table.remove(key) {
+            package.insert(key.into(), value);
+        }
+    }
+    table.insert(DEFAULT_PACKAGE.into(), Value::Table(package));
+    true
+}
+
+/// The `files` tables of every package
+fn package_files(global: &mut Value) -> Vec<&mut Table> {
+    global
+        .as_table_mut()
+        .into_iter()
+        .flat_map(|table| table.values_mut())
+        .filter_map(|package| package.get_mut("files")?.as_table_mut())
+        .collect()
+}
+
+/// Gives every target that is a table without a `type` the type that its source deploys as.
+/// Targets with `append` or `prepend` were always templates. Encrypted files don't need a type,
+/// and are newer than the format. Returns whether any changed.
+fn add_target_types(files: &mut Table, is_template: &dyn Fn(&str) -> bool) -> bool {
+    let mut changed = false;
+    for (source, target) in files.iter_mut() {
+        if let Value::Table(target) = target {
+            if target.contains_key("type") || target.contains_key("encrypted") {
+                continue;
+            }
+            let template = target.contains_key("append")
+                || target.contains_key("prepend")
+                || is_template(source);
+            let file_type = if template { "template" } else { "symbolic" };
+            target.insert("type".into(), Value::String(file_type.into()));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Splits the `files` of a cache from before templates were cached separately. Templates are
+/// the files whose render is in the cache directory.
+fn migrate_cache(cache: &mut Value, is_cached: impl Fn(&str) -> bool) -> Result<bool> {
+    let table = cache.as_table_mut().context("cache isn't a table")?;
+    if table.contains_key("symlinks") || table.contains_key("templates") {
+        return Ok(false);
+    }
+    let files = match table.remove("files") {
+        Some(Value::Table(files)) => files,
+        Some(_) => bail!("`files` of the cache isn't a table"),
+        None => return Ok(false),
+    };
+
+    let (templates, symlinks): (Table, Table) =
+        files.into_iter().partition(|(source, _)| is_cached(source));
+    table.insert("symlinks".into(), Value::Table(symlinks));
+    table.insert("templates".into(), Value::Table(templates));
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_migrate_global() {
+        let mut global: Value = toml::from_str(
+            r##"
+            [helpers]
+            [files]
+            zshrc = "~/.zshrc"
+            gitconfig = { target = "~/.gitconfig", append = "# local" }
+            [variables]
+            name = "me"
+            "##,
+        )
+        .unwrap();
+        assert!(migrate_global(&mut global));
+        assert_eq!(global["default"]["variables"]["name"].as_str(), Some("me"));
+        assert!(global.get("files").is_none());
+        assert!(global.get("helpers").is_some());
+
+        let changed = package_files(&mut global)
+            .into_iter()
+            .any(|files| add_target_types(files, &|_| false));
+        assert!(changed);
+        let gitconfig = &global["default"]["files"]["gitconfig"];
+        assert_eq!(gitconfig["type"].as_str(), Some("template"));
+        assert!(global["default"]["files"]["zshrc"].is_str());
+
+        // Already migrated
+        assert!(!migrate_global(&mut global));
+        let mut package: Value = toml::from_str("[files.files]\na = \"~/a\"").unwrap();
+        assert!(!migrate_global(&mut package));
+    }
+
+    #[test]
+    fn test_migrate_cache() {
+        let mut cache: Value =
+            toml::from_str("[files]\nzshrc = \"/home/me/.zshrc\"\nvimrc = \"/home/me/.vimrc\"")
+                .unwrap();
+        assert!(migrate_cache(&mut cache, |source| source == "vimrc").unwrap());
+        assert_eq!(cache["symlinks"]["zshrc"].as_str(), Some("/home/me/.zshrc"));
+        assert_eq!(
+            cache["templates"]["vimrc"].as_str(),
+            Some("/home/me/.vimrc")
+        );
+        assert!(!migrate_cache(&mut cache, |_| true).unwrap());
+        assert!(!migrate_cache(&mut Value::Table(Table::new()), |_| true).unwrap());
+    }
+}
@@ -0,0 +1,453 @@
+//! Dotter as a library: the command line is a thin wrapper around `run`, and `Deployer` plans
+//! and applies deploys for programs that want the results instead of the printed output.
+
+#[cfg(windows)]
+extern crate dunce;
+#[cfg(unix)]
+extern crate libc;
+
+#[macro_use]
+extern crate anyhow;
+extern crate attohttpc;
+extern crate blake3;
+extern crate chrono;
+extern crate clap;
+extern crate crossterm;
+extern crate diff;
+extern crate gethostname;
+extern crate getrandom;
+extern crate globset;
+extern crate handlebars;
+extern crate handlebars_misc_helpers;
+extern crate ignore;
+#[macro_use]
+extern crate log;
+extern crate meval;
+extern crate regex;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate sha2;
+extern crate shellexpand;
+extern crate structopt;
+extern crate sudo;
+extern crate tempfile;
+extern crate tera;
+#[macro_use]
+extern crate thiserror;
+extern crate toml;
+extern crate watchexec;
+
+mod adopt;
+mod ansible_vars;
+mod archetypes;
+pub mod args;
+mod backup;
+mod cancel;
+mod capabilities;
+mod checkpoint;
+mod classify;
+mod clean_cache;
+mod complete;
+mod completions;
+mod condition;
+mod config;
+mod conflict;
+mod context;
+mod dependencies;
+mod deploy;
+mod deployer;
+mod difference;
+mod elevate;
+mod equivalence;
+mod events;
+mod export;
+mod extract_vars;
+mod facts;
+mod file_state;
+mod filesystem;
+mod fleet;
+mod fragments;
+mod git;
+mod graph;
+mod handlebars_helpers;
+mod hooks;
+mod init;
+mod journal;
+mod lazy;
+mod legacy;
+mod lint;
+mod local_config;
+mod managed_lines;
+mod merge;
+mod migrate_home;
+mod move_file;
+mod new_package;
+mod non_interactive;
+mod plan;
+mod preflight;
+mod read_only;
+mod reload;
+mod remote_deploy;
+mod remote_variables;
+mod render;
+mod report;
+mod schema;
+mod secret_cache;
+mod secrets;
+mod selftest;
+mod snapshot;
+mod special_files;
+mod state;
+mod status;
+mod summary;
+mod sweep;
+mod timings;
+mod validate;
+mod variable_providers;
+mod variable_sources;
+mod verify;
+mod verify_cache;
+mod warnings;
+mod watch;
+
+use anyhow::{Context, Result};
+
+pub use args::Options;
+pub use cancel::CancellationToken;
+pub use config::Configuration as Config;
+pub use deployer::{Applied, Deployer};
+pub use events::{report, set_reporter, Event, Failure, JsonLines, Reporter};
+pub use file_state::FileState;
+pub use hooks::{Change, ChangeAction};
+pub use plan::{FileKind, Plan, PlannedChange, PlannedFile};
+
+/// Prints an error with its causes, and the lines of the file that it's about
+pub fn display_error(error: anyhow::Error) {
+    error!("{}", report::render(&error));
+    events::report(Event::Error(events::Failure {
+        message: format!("{:#}", error),
+        reason: non_interactive::reason(&error),
+    }));
+}
+
+/// Deploys migrate the files of an older dotter first, which a dry run can only show
+fn migrate_legacy_files(opt: &Options) -> Result<()> {
+    if legacy::migrate(opt).context("migrate files of an older dotter")? && !opt.act {
+        return Err(anyhow::anyhow!(
+            "deploy without --dry-run or run `dotter migrate` to migrate it first"
+        ))
+        .context("deploy a configuration of an older dotter on a dry run");
+    }
+    Ok(())
+}
+
+/// Runs the action of `opt` in the repository of the current directory, like the command line
+/// does. Returns true if program should exit with success status
+pub fn run(opt: Options) -> Result<bool> {
+    trace!("Loaded options: {:#?}", opt);
+
+    if opt.output == args::OutputMode::Json {
+        events::set_reporter(Some(Box::new(JsonLines)));
+    }
+
+    if let Some(home) = &opt.home {
+        let home = std::env::current_dir()
+            .context("get current directory")?
+            .join(home);
+        let home = filesystem::real_path(&home).unwrap_or(home);
+        debug!("Using {:?} as the home directory", home);
+        filesystem::set_home(home);
+    }
+
+    elevate::use_privileged_helper(opt.privileged_helper);
+    non_interactive::set_enabled(opt.non_interactive);
+    variable_sources::forbid_commands(opt.no_exec);
+
+    if opt.read_only_repo {
+        read_only::check(&opt).context("check that the repository isn't written to")?;
+    }
+
+    match opt.action.clone().unwrap_or_default() {
+        args::Action::Deploy {
+            only,
+            host: Some(host),
+            sync,
+        } => {
+            if sync {
+                git::pull(opt.act).context("pull repository")?;
+            }
+            if !only.is_empty() {
+                bail!("--only can't be combined with --host, every file is deployed to a host");
+            }
+            migrate_legacy_files(&opt)?;
+            debug!("Deploying to {}...", host);
+            remote_deploy::deploy(&opt, &host).context("deploy over SSH")?;
+        }
+        args::Action::Deploy {
+            only,
+            host: None,
+            sync,
+        } => {
+            if sync {
+                git::pull(opt.act).context("pull repository")?;
+            }
+            migrate_legacy_files(&opt)?;
+            debug!("Deploying...");
+            let opt = Options { only, ..opt };
+            if deploy::deploy(&opt, &Default::default()).context("deploy")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Undeploy {
+            package,
+            keep_rendered,
+        } => {
+            debug!("Un-Deploying...");
+            deploy::undeploy(&opt, package.as_deref(), keep_rendered).context("undeploy")?;
+        }
+        args::Action::Init => {
+            debug!("Initializing repo...");
+            init::init(opt).context("initalize directory")?;
+        }
+        args::Action::Watch {
+            error_comment,
+            status_file,
+            listen,
+            no_file_watching,
+            initial,
+            debounce,
+        } => {
+            debug!("Watching...");
+            let overlay = deploy::ErrorOverlay {
+                comment: error_comment,
+                status_file,
+            };
+            let watch_files = !no_file_watching;
+            watch::watch(opt, overlay, listen, watch_files, initial, debounce)
+                .context("watch repository")?;
+        }
+        args::Action::CleanCache { package } => {
+            debug!("Cleaning cache...");
+            clean_cache::clean_cache(&opt, package.as_deref()).context("clean cache")?;
+        }
+        args::Action::Sweep { directory } => {
+            sweep::sweep(&opt, &directory).context("sweep stale symlinks")?;
+        }
+        args::Action::Cache { action } => match action {
+            args::CacheAction::Verify => {
+                if !verify_cache::verify(&opt).context("verify cache")? {
+                    return Ok(false);
+                }
+            }
+            args::CacheAction::Repair => {
+                verify_cache::repair(&opt).context("repair cache")?;
+            }
+        },
+        args::Action::State { action } => match action {
+            args::StateAction::Export { format, output } => {
+                state::export(&opt, format, output.as_deref()).context("export state")?;
+            }
+            args::StateAction::Import { state, replace } => {
+                state::import(&opt, &state, replace).context("import state")?;
+            }
+        },
+        args::Action::Context {
+            package,
+            file,
+            format,
+        } => {
+            context::print_context(&opt, package.as_deref(), file.as_deref(), format)
+                .context("print template context")?;
+        }
+        args::Action::Mv {
+            old_source,
+            new_source,
+            relink,
+        } => {
+            debug!("Moving file...");
+            move_file::move_file(&opt, &old_source, &new_source, relink).context("move file")?;
+        }
+        args::Action::NewPackage {
+            name,
+            from,
+            from_archetype,
+        } => {
+            debug!("Creating package...");
+            new_package::new_package(&opt, &name, from.as_deref(), from_archetype.as_deref())
+                .context("create package")?;
+        }
+        args::Action::Adopt { file, package } => {
+            adopt::adopt(&opt, &file, package.as_deref())
+                .with_context(|| format!("adopt {:?}", file))?;
+        }
+        args::Action::Graph { format, files } => {
+            graph::print_graph(&opt, format, files).context("print package graph")?;
+        }
+        args::Action::Classify => {
+            classify::classify(&opt).context("classify files")?;
+        }
+        args::Action::Lint => {
+            lint::lint(&opt).context("lint configuration")?;
+        }
+        args::Action::Export {
+            format,
+            output,
+            reference_rendered,
+        } => {
+            export::export(&opt, format, output.as_deref(), reference_rendered)
+                .context("export configuration")?;
+        }
+        args::Action::ExtractVars { template, deployed } => {
+            extract_vars::extract_vars(&template, &deployed).context("extract variables")?;
+        }
+        args::Action::Stats => {
+            timings::print_stats(&opt).context("print stats")?;
+        }
+        args::Action::Snapshot { output } => {
+            debug!("Taking snapshot...");
+            snapshot::snapshot(&opt, output.as_deref()).context("take snapshot")?;
+        }
+        args::Action::Restore { target } => {
+            backup::restore(&opt, target.as_deref()).context("restore backup")?;
+        }
+        args::Action::Checkpoint { name } => {
+            checkpoint::checkpoint(&opt, name.as_deref()).context("take checkpoint")?;
+        }
+        args::Action::RevertTo { name } => {
+            checkpoint::revert_to(&opt, &name)
+                .with_context(|| format!("revert to checkpoint {:?}", name))?;
+        }
+        args::Action::Diff => {
+            difference::print_pending_changes(&opt).context("print pending changes")?;
+        }
+        args::Action::Plan { detailed_exit_code } => {
+            let unchanged = plan::print_plan(&opt).context("print plan")?;
+            if detailed_exit_code && !unchanged {
+                return Ok(false);
+            }
+        }
+        args::Action::Status => {
+            if !status::status(&opt).context("print status")? {
+                return Ok(false);
+            }
+        }
+        args::Action::Report { .. } => {
+            status::report(&opt).context("print report")?;
+        }
+        #[cfg(feature = "tray")]
+        args::Action::Tray => {
+            status::tray(&opt).context("print tray menu")?;
+        }
+        #[cfg(not(feature = "tray"))]
+        args::Action::Tray => {
+            bail!("this dotter was built without the `tray` feature");
+        }
+        args::Action::Fleet {
+            action: args::FleetAction::Deploy { hosts, only },
+        } => {
+            if !fleet::deploy(&opt, &hosts, &only).context("deploy fleet")? {
+                return Ok(false);
+            }
+        }
+        args::Action::Package { action } => {
+            local_config::package(&opt, action).context("edit packages of local config")?;
+        }
+        args::Action::Var { action } => {
+            let affected = match &action {
+                args::VariableAction::Set {
+                    name,
+                    deploy_affected: true,
+                    ..
+                } => Some(name.clone()),
+                _ => None,
+            };
+            local_config::variable(&opt, action).context("edit variables of local config")?;
+            if let Some(name) = affected {
+                let sources = deploy::templates_reading(&opt, &name)
+                    .context("find templates that read the variable")?;
+                if sources.is_empty() {
+                    info!("No deployed template reads {:?}", name);
+                } else {
+                    debug!("Deploying the templates that read {:?}...", name);
+                    let opt = Options {
+                        only_sources: Some(sources),
+                        ..opt
+                    };
+                    if deploy::deploy(&opt, &Default::default()).context("deploy")? {
+                        // An error occurred
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        args::Action::Selftest { directory, keep } => {
+            if !selftest::selftest(directory.as_deref(), keep).context("run self-test")? {
+                return Ok(false);
+            }
+        }
+        args::Action::Activate { package } => {
+            debug!("Activating package...");
+            if lazy::activate(&opt, &package).context("activate lazy package")? {
+                // An error occurred
+                return Ok(false);
+            }
+        }
+        args::Action::Migrate => {
+            debug!("Migrating files of an older dotter...");
+            if !legacy::migrate(&opt).context("migrate files of an older dotter")? {
+                info!("Nothing to migrate");
+            }
+        }
+        args::Action::MigrateHome { old, new } => {
+            debug!("Migrating home directory...");
+            if !migrate_home::migrate_home(&opt, &old, &new).context("migrate home directory")? {
+                return Ok(false);
+            }
+        }
+        args::Action::SwitchProfile { name } => {
+            let profiles =
+                config::load_profile_names(&opt.global_config).context("load profiles")?;
+            if !profiles.contains(&name) {
+                return Err(anyhow::anyhow!(
+                    "unknown profile {:?}, global.toml has {:?}",
+                    name,
+                    profiles
+                ))
+                .context("switch profile");
+            }
+            debug!("Deploying profile {:?}...", name);
+            // The deploy deletes what only the current profile deployed, and a transactional
+            // deploy puts everything back if any of it fails
+            let deploy_opt = Options {
+                profile: Some(name.clone()),
+                transactional: true,
+                ..opt.clone()
+            };
+            if deploy::deploy(&deploy_opt, &Default::default()).context("deploy")? {
+                error!(
+                    "Failed to deploy profile {:?}, local.toml is left as it was",
+                    name
+                );
+                return Ok(false);
+            }
+            local_config::set_profile(&opt, &name).context("save profile in local config")?;
+        }
+        args::Action::PrivilegedHelper => {
+            elevate::serve().context("run privileged helper")?;
+        }
+        args::Action::Schema { file } => {
+            schema::print_schema(file).context("print schema")?;
+        }
+        args::Action::GenCompletions { shell } => {
+            completions::gen_completions(shell).context("generate completion script")?;
+        }
+        args::Action::Complete { kind, current } => {
+            complete::complete(&opt, kind, &current).context("complete")?;
+        }
+    }
+
+    Ok(true)
+}
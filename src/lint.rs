@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use args::Options;
+use config::{self, FileTarget, LintConfig};
+
+/// Words that make a variable look like it holds a secret
+const SECRET_WORDS: &[&str] = &[
+    "secret",
+    "password",
+    "passwd",
+    "passphrase",
+    "token",
+    "api_key",
+    "apikey",
+    "private_key",
+];
+
+#[derive(Debug, PartialEq)]
+struct Finding {
+    rule: &'static str,
+    /// Package or source that the finding is about, which `allow` can name after the rule
+    subject: String,
+    message: String,
+}
+
+impl Finding {
+    fn is_allowed(&self, lint: &LintConfig) -> bool {
+        let qualified = format!("{}:{}", self.rule, self.subject);
+        lint.allow
+            .iter()
+            .any(|allowed| allowed == self.rule || *allowed == qualified)
+    }
+}
+
+/// Prints the likely mistakes in the configuration files, and fails if there are any that
+/// aren't allowed. The files are checked as they're written, so that mistakes which would
+/// make a deploy fail are reported too.
+pub fn lint(opt: &Options) -> Result<()> {
+    let declared = config::load_declared(&opt.local_config, &opt.global_config)
+        .context("load configuration")?;
+
+    let mut files = declared
+        .package_files
+        .iter()
+        .flat_map(|(package, files)| files.iter().map(move |f| (Some(package.as_str()), f)))
+        .collect::<Vec<_>>();
+    files.extend(declared.local_files.iter().map(|f| (None, f)));
+
+    let mut findings = Vec::new();
+
+    for (package, files) in &declared.package_files {
+        if !declared.selected.contains(package) && !declared.auto_enabled.contains(package) {
+            findings.push(Finding {
+                rule: "unused-package",
+                subject: package.clone(),
+                message: format!(
+                    "package {:?} isn't enabled in local.toml or a profile and has no auto_enable or condition, so its {} files are never deployed",
+                    package,
+                    files.len()
+                ),
+            });
+        }
+    }
+
+    let mut declared_by = BTreeMap::<PathBuf, Vec<&str>>::new();
+    for (package, (source, _)) in &files {
+        if let Some(package) = package {
+            declared_by
+                .entry(normalize(source))
+                .or_default()
+                .push(package);
+        }
+    }
+    for (source, packages) in declared_by {
+        if packages.len() > 1 {
+            findings.push(Finding {
+                rule: "duplicate-source",
+                subject: source.to_string_lossy().into_owned(),
+                message: format!(
+                    "{:?} is declared by several packages: {}",
+                    source,
+                    packages.join(", ")
+                ),
+            });
+        }
+    }
+
+    for (package, (source, target)) in files {
+        let declared_in = match package {
+            Some(package) => format!("package {:?}", package),
+            None => "local.toml".to_string(),
+        };
+        let subject = source.to_string_lossy().into_owned();
+
+        if !source.exists() {
+            findings.push(Finding {
+                rule: "missing-source",
+                subject: subject.clone(),
+                message: format!("{:?} of {} doesn't exist", source, declared_in),
+            });
+        }
+
+        let path = target.path().to_string_lossy();
+        if !path.is_empty() && !path.starts_with('~') && !target.path().is_absolute() {
+            findings.push(Finding {
+                rule: "relative-target",
+                subject: subject.clone(),
+                message: format!(
+                    "target {:?} of {:?} is relative to wherever dotter runs, it should start with `~` or `/`",
+                    target.path(),
+                    source
+                ),
+            });
+        }
+
+        let template = match target {
+            FileTarget::Symbolic(_) | FileTarget::Copy(_) => false,
+            FileTarget::Fragment(fragment) => fragment.template,
+            _ => true,
+        };
+        if !template {
+            // Unreadable and binary files have nothing to render anyway
+            if let Ok(contents) = fs::read_to_string(source) {
+                for (line, expression) in secret_references(&contents) {
+                    findings.push(Finding {
+                        rule: "secret-in-symlink",
+                        subject: subject.clone(),
+                        message: format!(
+                            "{:?} line {} references {:?}, but isn't rendered as a template, so the reference is deployed as it is",
+                            source, line, expression
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    let (allowed, findings): (Vec<_>, Vec<_>) = findings
+        .into_iter()
+        .partition(|finding| finding.is_allowed(&declared.lint));
+    for finding in &allowed {
+        debug!("Allowed {}: {}", finding.rule, finding.message);
+    }
+    for finding in &findings {
+        println!("{}: {}", finding.rule.yellow(), finding.message);
+    }
+
+    if !findings.is_empty() {
+        bail!(
+            "found {} problems ({} allowed)",
+            findings.len(),
+            allowed.len()
+        );
+    }
+    info!("No problems found ({} allowed)", allowed.len());
+    Ok(())
+}
+
+/// Sources are compared without `.` components, so that `./vimrc` and `vimrc` are the same
+fn normalize(source: &Path) -> PathBuf {
+    source
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Handlebars expressions that mention a secret-looking name, with their line number
+fn secret_references(contents: &str) -> Vec<(usize, String)> {
+    let mut references = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let end = match after.find("}}") {
+                Some(end) => end,
+                None => break,
+            };
+            let expression = after[..end].trim_matches(|c: char| c == '{' || c.is_whitespace());
+            let lowercase = expression.to_lowercase();
+            if SECRET_WORDS.iter().any(|word| lowercase.contains(word)) {
+                references.push((index + 1, expression.to_string()));
+            }
+            rest = &after[end + 2..];
+        }
+    }
+    references
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret_references() {
+        let contents = "user = {{ name }}\npass = {{github_token}} {{ name }}\n{{{ db.password }}}\n{{ unclosed";
+        assert_eq!(
+            secret_references(contents),
+            vec![
+                (2, "github_token".to_string()),
+                (3, "db.password".to_string())
+            ]
+        );
+
+        let finding = Finding {
+            rule: "unused-package",
+            subject: "work".into(),
+            message: String::new(),
+        };
+        let allow = |allow: &[&str]| LintConfig {
+            allow: allow.iter().map(|a| a.to_string()).collect(),
+        };
+        assert!(finding.is_allowed(&allow(&["unused-package"])));
+        assert!(finding.is_allowed(&allow(&["unused-package:work"])));
+        assert!(!finding.is_allowed(&allow(&["unused-package:home", "missing-source"])));
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use args::{Options, PackageAction, VariableAction};
+use config;
+use git;
+
+/// Enables or disables a package in local.toml, keeping the rest of the file as it is
+pub fn package(opt: &Options, action: PackageAction) -> Result<()> {
+    let contents = fs::read_to_string(&opt.local_config)
+        .with_context(|| format!("read local config {:?}", opt.local_config))?;
+    let mut packages = parse(&contents)?
+        .get("packages")
+        .and_then(|p| p.as_array())
+        .map(|p| {
+            p.iter()
+                .filter_map(|p| p.as_str().map(String::from))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let message = match action {
+        PackageAction::Enable { name } => {
+            let declarations =
+                config::load_package_declarations(&opt.global_config).context("load packages")?;
+            if !declarations.iter().any(|d| d.name == name) {
+                bail!(
+                    "package {:?} doesn't exist in {:?}",
+                    name,
+                    opt.global_config
+                );
+            }
+            if packages.contains(&name) {
+                info!("Package {:?} is already enabled", name);
+                return Ok(());
+            }
+            info!("Enabling package {:?}", name);
+            let message = format!("Enable package {}", name);
+            packages.push(name);
+            message
+        }
+        PackageAction::Disable { name } => {
+            if !packages.contains(&name) {
+                info!("Package {:?} isn't enabled in {:?}", name, opt.local_config);
+                return Ok(());
+            }
+            info!("Disabling package {:?}", name);
+            packages.retain(|p| p != &name);
+            format!("Disable package {}", name)
+        }
+    };
+
+    let value = toml::Value::Array(packages.into_iter().map(toml::Value::String).collect());
+    let edited = set_top_level(&contents, "packages", &value);
+    save(opt, &edited, &message, |parsed| {
+        parsed.get("packages") == Some(&value)
+    })
+}
+
+/// Sets or removes a variable in the `[variables]` table of local.toml
+pub fn variable(opt: &Options, action: VariableAction) -> Result<()> {
+    let contents = fs::read_to_string(&opt.local_config)
+        .with_context(|| format!("read local config {:?}", opt.local_config))?;
+
+    match action {
+        VariableAction::Set { name, value, .. } => {
+            let value = parse_value(&value);
+            info!(
+                "Setting variable {:?} to {}",
+                name,
+                config::toml_inline(&value)
+            );
+            let edited = set_variable(&contents, &name, Some(&value));
+            save(opt, &edited, &format!("Set variable {}", name), |parsed| {
+                variables(parsed).and_then(|v| v.get(&name)) == Some(&value)
+            })
+        }
+        VariableAction::Unset { name } => {
+            if variables(&parse(&contents)?)
+                .and_then(|v| v.get(&name))
+                .is_none()
+            {
+                info!("Variable {:?} isn't set in {:?}", name, opt.local_config);
+                return Ok(());
+            }
+            info!("Removing variable {:?}", name);
+            let edited = set_variable(&contents, &name, None);
+            save(
+                opt,
+                &edited,
+                &format!("Unset variable {}", name),
+                |parsed| variables(parsed).and_then(|v| v.get(&name)).is_none(),
+            )
+        }
+    }
+}
+
+/// Sets every variable of `values` in the `[variables]` table of local.toml, without committing
+/// it, since they're the secrets that `adopt` keeps out of the repository
+pub fn set_variables(opt: &Options, values: &BTreeMap<String, String>) -> Result<()> {
+    let mut edited = fs::read_to_string(&opt.local_config)
+        .with_context(|| format!("read local config {:?}", opt.local_config))?;
+    for (name, value) in values {
+        edited = set_variable(&edited, name, Some(&toml::Value::String(value.clone())));
+    }
+    check(opt, &edited, |parsed| {
+        values.iter().all(|(name, value)| {
+            variables(parsed)
+                .and_then(|v| v.get(name))
+                .and_then(|v| v.as_str())
+                == Some(value.as_str())
+        })
+    })?;
+    if opt.act {
+        fs::write(&opt.local_config, edited).context("save local config")?;
+    }
+    Ok(())
+}
+
+/// String variables of local.toml
+pub fn string_variables(opt: &Options) -> Result<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(&opt.local_config)
+        .with_context(|| format!("read local config {:?}", opt.local_config))?;
+    Ok(variables(&parse(&contents)?)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+        .collect())
+}
+
+fn parse(contents: &str) -> Result<toml::Value> {
+    contents.parse().context("parse local config")
+}
+
+fn variables(parsed: &toml::Value) -> Option<&toml::value::Table> {
+    parsed.get("variables").and_then(|v| v.as_table())
+}
+
+/// Makes sure that the edit did what it was supposed to before saving it, since the file is
+/// edited as text to keep its formatting
+fn check(opt: &Options, edited: &str, holds: impl Fn(&toml::Value) -> bool) -> Result<()> {
+    let parsed = parse(edited).context("validate edited local config")?;
+    if !holds(&parsed) {
+        bail!(
+            "couldn't edit {:?} without changing its meaning. Please edit it by hand.",
+            opt.local_config
+        );
+    }
+    Ok(())
+}
+
+fn save(
+    opt: &Options,
+    edited: &str,
+    message: &str,
+    check: impl Fn(&toml::Value) -> bool,
+) -> Result<()> {
+    self::check(opt, edited, check)?;
+    if opt.act {
+        fs::write(&opt.local_config, edited).context("save local config")?;
+    }
+    git::commit(opt, &[&opt.local_config], message).context("commit local config")
+}
+
+/// Makes `name` the `profile` of local.toml
+pub fn set_profile(opt: &Options, name: &str) -> Result<()> {
+    let contents = fs::read_to_string(&opt.local_config)
+        .with_context(|| format!("read local config {:?}", opt.local_config))?;
+    let value = toml::Value::String(name.into());
+    if parse(&contents)?.get("profile") == Some(&value) {
+        info!(
+            "Profile {:?} is already selected in {:?}",
+            name, opt.local_config
+        );
+        return Ok(());
+    }
+    info!("Selecting profile {:?} in {:?}", name, opt.local_config);
+    let edited = set_top_level(&contents, "profile", &value);
+    save(
+        opt,
+        &edited,
+        &format!("Switch to profile {}", name),
+        |parsed| parsed.get("profile") == Some(&value),
+    )
+}
+
+/// Values that are valid TOML are kept as they are, anything else is a string
+pub fn parse_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|mut parsed| parsed.as_table_mut()?.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.into()))
+}
+
+fn is_header(line: &str) -> bool {
+    line.trim_start().starts_with('[')
+}
+
+/// Returns whether the line assigns `key`
+fn assigns(line: &str, key: &str) -> bool {
+    line.trim_start()
+        .strip_prefix(&config::toml_key(key))
+        .is_some_and(|rest| rest.trim_start().starts_with('='))
+}
+
+/// Index of the last line of the value that is assigned on line `start`,
+/// which is a different line if it's an array or inline table that spans several lines
+fn end_of_value(lines: &[&str], start: usize) -> usize {
+    let mut depth = 0i32;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        let mut chars = line.chars();
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some('"'), '\\') => {
+                    chars.next();
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (None, '#') => break,
+                (None, '[') | (None, '{') => depth += 1,
+                (None, ']') | (None, '}') => depth -= 1,
+                _ => {}
+            }
+        }
+        if depth <= 0 {
+            return index;
+        }
+    }
+    lines.len() - 1
+}
+
+/// Replaces the lines of the assignment on line `start` by `replacement`
+fn replace_assignment(lines: &mut Vec<String>, start: usize, replacement: Option<String>) {
+    let borrowed = lines.iter().map(String::as_str).collect::<Vec<_>>();
+    let end = end_of_value(&borrowed, start);
+    lines.drain(start..=end);
+    if let Some(replacement) = replacement {
+        lines.insert(start, replacement);
+    }
+}
+
+fn join(lines: Vec<String>, original: &str) -> String {
+    let mut joined = lines.join("\n");
+    if original.ends_with('\n') || original.is_empty() {
+        joined.push('\n');
+    }
+    joined
+}
+
+/// Sets a key before the first table of the file
+fn set_top_level(contents: &str, key: &str, value: &toml::Value) -> String {
+    let mut lines = contents.lines().map(String::from).collect::<Vec<_>>();
+    let assignment = format!("{} = {}", config::toml_key(key), config::toml_inline(value));
+    let first_header = lines
+        .iter()
+        .position(|l| is_header(l))
+        .unwrap_or(lines.len());
+    match lines[..first_header].iter().position(|l| assigns(l, key)) {
+        Some(start) => replace_assignment(&mut lines, start, Some(assignment)),
+        None => lines.insert(0, assignment),
+    }
+    join(lines, contents)
+}
+
+/// Sets or removes a key of the `[variables]` table, which is created if it doesn't exist
+fn set_variable(contents: &str, name: &str, value: Option<&toml::Value>) -> String {
+    let mut lines = contents.lines().map(String::from).collect::<Vec<_>>();
+    let assignment = value.map(|value| {
+        format!(
+            "{} = {}",
+            config::toml_key(name),
+            config::toml_inline(value)
+        )
+    });
+
+    let header = lines.iter().position(|l| l.trim() == "[variables]");
+    let header = match (header, &assignment) {
+        (Some(header), _) => header,
+        (None, Some(assignment)) => {
+            if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push("[variables]".into());
+            lines.push(assignment.clone());
+            return join(lines, contents);
+        }
+        (None, None) => return contents.into(),
+    };
+    let end = lines
+        .iter()
+        .skip(header + 1)
+        .position(|l| is_header(l))
+        .map_or(lines.len(), |i| header + 1 + i);
+
+    match lines[header + 1..end].iter().position(|l| assigns(l, name)) {
+        Some(i) => replace_assignment(&mut lines, header + 1 + i, assignment),
+        None => {
+            if let Some(assignment) = assignment {
+                // After the last assignment, so that blank lines before the next table stay there
+                let last = lines[header + 1..end]
+                    .iter()
+                    .rposition(|l| !l.trim().is_empty())
+                    .map_or(header + 1, |i| header + 2 + i);
+                lines.insert(last, assignment);
+            }
+        }
+    }
+    join(lines, contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit_local_config() {
+        let original = "# This machine\npackages = [\n  \"a\", # the first\n  \"b\",\n]\n\n[files]\n\n[variables]\n# My email\nemail = \"old\"\nname = \"me\"\n\n[other]\n";
+
+        let packages = toml::Value::Array(vec!["a".into()]);
+        assert_eq!(
+            set_top_level(original, "packages", &packages),
+            original.replacen(
+                "packages = [\n  \"a\", # the first\n  \"b\",\n]",
+                "packages = [\"a\"]",
+                1
+            )
+        );
+
+        let email = toml::Value::String("x@y".into());
+        assert_eq!(
+            set_variable(original, "email", Some(&email)),
+            original.replace("email = \"old\"", "email = \"x@y\"")
+        );
+        assert_eq!(
+            set_variable(original, "size", Some(&toml::Value::Integer(3))),
+            original.replace("name = \"me\"\n", "name = \"me\"\nsize = 3\n")
+        );
+        assert_eq!(
+            set_variable(original, "name", None),
+            original.replace("name = \"me\"\n", "")
+        );
+        assert_eq!(
+            set_variable("packages = []\n", "a", Some(&email)),
+            "packages = []\n\n[variables]\na = \"x@y\"\n"
+        );
+
+        assert_eq!(parse_value("42"), toml::Value::Integer(42));
+        assert_eq!(parse_value("x@y"), toml::Value::String("x@y".into()));
+    }
+}
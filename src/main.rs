@@ -1,68 +1,34 @@
-#[cfg(windows)]
-extern crate dunce;
-
-#[macro_use]
 extern crate anyhow;
-extern crate clap;
-extern crate crossterm;
-extern crate diff;
-extern crate handlebars;
-extern crate handlebars_misc_helpers;
-#[macro_use]
-extern crate log;
-extern crate meval;
-#[macro_use]
-extern crate serde;
-extern crate shellexpand;
+extern crate dotter;
 extern crate simplelog;
-extern crate structopt;
-extern crate sudo;
-#[macro_use]
-extern crate thiserror;
-extern crate toml;
-extern crate watchexec;
-
-mod args;
-mod config;
-mod deploy;
-mod difference;
-mod file_state;
-mod filesystem;
-mod handlebars_helpers;
-mod init;
-mod watch;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 fn main() {
-    match run() {
-        Ok(success) if success => std::process::exit(0),
-        Ok(_) => std::process::exit(1),
+    // Parse arguments
+    let opt = dotter::args::get_options();
+    // Pending changes aren't a failure of `plan --detailed-exit-code`, but they're told apart
+    let unsuccessful = match opt.action {
+        Some(dotter::args::Action::Plan {
+            detailed_exit_code: true,
+        }) => 2,
+        _ => 1,
+    };
+
+    let code = match run(opt) {
+        Ok(true) => 0,
+        Ok(false) => unsuccessful,
         Err(e) => {
-            display_error(e);
-            std::process::exit(1);
+            dotter::display_error(e);
+            1
         }
-    }
-}
-
-pub(crate) fn display_error(error: anyhow::Error) {
-    let mut chain = error.chain();
-    let mut error_message = format!("Failed to {}\nCaused by:\n", chain.next().unwrap());
-
-    for e in chain {
-        error_message.push_str(&format!("    {}\n", e));
-    }
-    // Remove last \n
-    error_message.pop();
-
-    error!("{}", error_message);
+    };
+    dotter::report(dotter::Event::Finished { success: code == 0 });
+    std::process::exit(code);
 }
 
 /// Returns true if program should exit with success status
-fn run() -> Result<bool> {
-    // Parse arguments
-    let opt = args::get_options();
-
+fn run(opt: dotter::args::Options) -> Result<bool> {
     use simplelog::LevelFilter;
 
     simplelog::TermLogger::init(
@@ -85,33 +51,14 @@ fn run() -> Result<bool> {
             .set_level_padding(simplelog::LevelPadding::Left)
             .add_filter_allow("dotter".into())
             .build(),
-        simplelog::TerminalMode::Mixed,
+        // Standard output is kept for the events
+        if opt.output == dotter::args::OutputMode::Json {
+            simplelog::TerminalMode::Stderr
+        } else {
+            simplelog::TerminalMode::Mixed
+        },
     )
     .unwrap();
 
-    trace!("Loaded options: {:#?}", opt);
-
-    match opt.action.unwrap_or_default() {
-        args::Action::Deploy => {
-            debug!("Deploying...");
-            if deploy::deploy(&opt).context("deploy")? {
-                // An error occurred
-                return Ok(false);
-            }
-        }
-        args::Action::Undeploy => {
-            debug!("Un-Deploying...");
-            deploy::undeploy(opt).context("undeploy")?;
-        }
-        args::Action::Init => {
-            debug!("Initializing repo...");
-            init::init(opt).context("initalize directory")?;
-        }
-        args::Action::Watch => {
-            debug!("Watching...");
-            watch::watch(opt).context("watch repository")?;
-        }
-    }
-
-    Ok(true)
+    dotter::run(opt)
 }
@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::display_error;
+use config::ManagedLines;
+use filesystem;
+
+/// Mode of managed files that dotter creates, since ssh refuses `authorized_keys` that others
+/// can write to
+const NEW_FILE_MODE: u32 = 0o600;
+
+/// Makes sure every target contains its desired lines, and removes the lines that dotter added
+/// to it before but that aren't desired anymore. Lines that were already there are never
+/// removed. Returns the lines that dotter added, by target, and whether an error occurred.
+pub fn deploy(act: bool, desired: &ManagedLines, added: &ManagedLines) -> (ManagedLines, bool) {
+    let targets = desired.keys().chain(added.keys()).collect::<BTreeSet<_>>();
+    let mut actual = ManagedLines::new();
+    let mut error_occurred = false;
+
+    for target in targets {
+        let desired = desired.get(target).map(Vec::as_slice).unwrap_or_default();
+        let added = added.get(target).map(Vec::as_slice).unwrap_or_default();
+        let now_added = match update_file(act, target, desired, added) {
+            Ok(now_added) => now_added,
+            Err(e) => {
+                display_error(e.context(format!("update managed lines of {:?}", target)));
+                error_occurred = true;
+                added.to_vec()
+            }
+        };
+        if !now_added.is_empty() {
+            actual.insert(target.clone(), now_added);
+        }
+    }
+
+    (actual, error_occurred)
+}
+
+fn update_file(
+    act: bool,
+    target: &Path,
+    desired: &[String],
+    added: &[String],
+) -> Result<Vec<String>> {
+    let contents = match fs::read_to_string(target) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("read file"),
+    };
+    let original = contents.as_deref().unwrap_or_default();
+    let (edited, now_added) = edit(original, desired, added);
+    if edited == original {
+        debug!("Managed lines of {:?} are up to date", target);
+        return Ok(now_added);
+    }
+
+    let inserted = now_added.iter().filter(|l| !added.contains(l)).count();
+    let removed = added.iter().filter(|l| !desired.contains(l)).count();
+    info!(
+        "Updating {:?}: adding {} lines, removing {} lines",
+        target, inserted, removed
+    );
+    if act {
+        if contents.is_none() {
+            fs::create_dir_all(target.parent().context("get parent of managed file")?)
+                .context("create parent of managed file")?;
+        }
+        fs::write(target, edited).context("write file")?;
+        if contents.is_none() {
+            filesystem::set_mode(target, NEW_FILE_MODE)?;
+        }
+    }
+    Ok(now_added)
+}
+
+/// Removes the `added` lines that aren't `desired` anymore and appends the `desired` lines
+/// that are missing. Returns the edited contents and the lines that dotter added to them.
+fn edit(contents: &str, desired: &[String], added: &[String]) -> (String, Vec<String>) {
+    let is_desired = |line: &str| desired.iter().any(|d| d == line.trim());
+    let was_added = |line: &str| added.iter().any(|a| a == line.trim());
+
+    let mut lines = contents
+        .lines()
+        .filter(|line| is_desired(line) || !was_added(line))
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut now_added = added
+        .iter()
+        .filter(|line| is_desired(line) && lines.iter().any(|l| l.trim() == line.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    for line in desired {
+        if !lines.iter().any(|l| l.trim() == line.as_str()) {
+            lines.push(line.clone());
+            now_added.push(line.clone());
+        }
+    }
+
+    let mut edited = lines.join("\n");
+    if !edited.is_empty() {
+        edited.push('\n');
+    }
+    if edited.trim_end_matches('\n') == contents.trim_end_matches('\n') {
+        // Keep the file as it was if only its final newline differs
+        edited = contents.into();
+    }
+    (edited, now_added)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit() {
+        let lines = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let original = "# mine\nssh-rsa OLD me\n";
+
+        let (edited, added) = edit(original, &lines(&["ssh-ed25519 NEW me"]), &[]);
+        assert_eq!(edited, "# mine\nssh-rsa OLD me\nssh-ed25519 NEW me\n");
+        assert_eq!(added, lines(&["ssh-ed25519 NEW me"]));
+        assert_eq!(
+            edit(&edited, &lines(&["ssh-ed25519 NEW me"]), &added),
+            (edited.clone(), added.clone())
+        );
+
+        // Lines that were there before dotter aren't recorded, so they're never removed
+        let (edited, added) = edit(&edited, &lines(&["ssh-rsa OLD me"]), &added);
+        assert_eq!(edited, original);
+        assert!(added.is_empty());
+        assert_eq!(edit(&edited, &[], &added), (original.to_string(), vec![]));
+
+        assert_eq!(edit("", &[], &[]), (String::new(), vec![]));
+        assert_eq!(edit("a", &lines(&["a"]), &[]), ("a".to_string(), vec![]));
+    }
+}
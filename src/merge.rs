@@ -0,0 +1,111 @@
+//! Three-way merges of a template's target that was edited by hand with the template's new
+//! render, through the `merge_tool` of local.toml
+
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use events;
+use filesystem;
+
+/// Runs `tool` through the shell with `$BASE`, `$LOCAL`, `$REMOTE` and `$MERGED` set to files
+/// with the last deployed render, the edited target, the new render and the result, like
+/// git's `mergetool.<tool>.cmd`. The result starts out as the edited target.
+/// Returns the result, or `None` if the tool failed or left conflict markers in it.
+pub fn merge(
+    tool: &str,
+    target: &Path,
+    base: &str,
+    local: &str,
+    remote: &str,
+) -> Result<Option<String>> {
+    // A new directory that only the user can enter, since the renders may contain secrets
+    let directory = tempfile::Builder::new()
+        .prefix("dotter-merge-")
+        .tempdir()
+        .context("create directory of merged files")?;
+    let result = run_tool(tool, target, directory.path(), base, local, remote);
+    if let Err(e) = directory.close() {
+        warn!("Failed to remove the directory of merged files: {}", e);
+    }
+    result
+}
+
+fn run_tool(
+    tool: &str,
+    target: &Path,
+    directory: &Path,
+    base: &str,
+    local: &str,
+    remote: &str,
+) -> Result<Option<String>> {
+    // The files keep the target's name, so that the tool recognizes its syntax
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let base_file = directory.join(format!("BASE.{}", name));
+    let local_file = directory.join(format!("LOCAL.{}", name));
+    let remote_file = directory.join(format!("REMOTE.{}", name));
+    let merged_file = directory.join(&name);
+    fs::write(&base_file, base).context("write last deployed render")?;
+    fs::write(&local_file, local).context("write edited target")?;
+    fs::write(&remote_file, remote).context("write new render")?;
+    fs::write(&merged_file, local).context("write merge result")?;
+
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(tool);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(tool);
+        command
+    };
+    command
+        .env("BASE", &base_file)
+        .env("LOCAL", &local_file)
+        .env("REMOTE", &remote_file)
+        .env("MERGED", &merged_file);
+    filesystem::set_child_environment(&mut command);
+    let status = command
+        .stdout(events::child_stdout())
+        .status()
+        .with_context(|| format!("run merge tool {:?}", tool))?;
+    if !status.success() {
+        warn!("The merge tool exited with {}", status);
+        return Ok(None);
+    }
+
+    let merged = fs::read_to_string(&merged_file).context("read merge result")?;
+    if has_conflict_markers(&merged) {
+        warn!("The merge result still has conflict markers");
+        return Ok(None);
+    }
+    Ok(Some(merged))
+}
+
+fn has_conflict_markers(contents: &str) -> bool {
+    let mut lines = contents.lines();
+    lines.any(|line| line.starts_with("<<<<<<<"))
+        && lines.any(|line| line.starts_with("======="))
+        && lines.any(|line| line.starts_with(">>>>>>>"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_has_conflict_markers() {
+        assert!(has_conflict_markers(
+            "a\n<<<<<<< LOCAL\nb\n=======\nc\n>>>>>>> REMOTE\nd\n"
+        ));
+        assert!(!has_conflict_markers("a\n=======\nb\n"));
+        assert!(!has_conflict_markers(
+            ">>>>>>> REMOTE\n=======\n<<<<<<< LOCAL\n"
+        ));
+    }
+}
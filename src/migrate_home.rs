@@ -0,0 +1,255 @@
+//! `dotter migrate-home`: after a home directory moved, like for a new username or a new mount
+//! point, rewrites the targets that the cache recorded under the old home and the symlinks that
+//! point into it, so that the next deploy recognizes its files instead of needing the cache
+//! removed and a forced deploy.
+
+use anyhow::{Context, Result};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::display_error;
+use args::Options;
+use config::{self, Cache};
+use elevate;
+use filesystem;
+
+/// Moves paths at or under `old` to the same place under `new`
+struct Relocation<'a> {
+    old: &'a Path,
+    new: &'a Path,
+    /// Amount of paths that were moved
+    count: usize,
+}
+
+impl Relocation<'_> {
+    fn path(&mut self, path: &mut PathBuf) {
+        if let Ok(rest) = path.strip_prefix(self.old) {
+            *path = if rest.as_os_str().is_empty() {
+                self.new.to_path_buf()
+            } else {
+                self.new.join(rest)
+            };
+            self.count += 1;
+        }
+    }
+
+    fn values<K: Ord>(&mut self, map: &mut BTreeMap<K, PathBuf>) {
+        map.values_mut().for_each(|path| self.path(path));
+    }
+
+    fn keys<T>(&mut self, map: &mut BTreeMap<PathBuf, T>) {
+        *map = std::mem::take(map)
+            .into_iter()
+            .map(|(mut path, value)| {
+                self.path(&mut path);
+                (path, value)
+            })
+            .collect();
+    }
+
+    fn set(&mut self, set: &mut BTreeSet<PathBuf>) {
+        *set = std::mem::take(set)
+            .into_iter()
+            .map(|mut path| {
+                self.path(&mut path);
+                path
+            })
+            .collect();
+    }
+}
+
+/// Rewrites the paths under `old` in the cache and in the deployed symlinks to `new`.
+/// Returns whether every symlink could be updated.
+pub fn migrate_home(opt: &Options, old: &Path, new: &Path) -> Result<bool> {
+    if !old.is_absolute() || !new.is_absolute() {
+        bail!("the old and new home directories must be absolute paths");
+    }
+    if old == new {
+        bail!("the old and new home directories are the same");
+    }
+    if !new.is_dir() {
+        bail!("new home directory {:?} doesn't exist", new);
+    }
+
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    // The repository may have moved along with the home directory, so the cache is loaded
+    // without checking which repository it belongs to. Saving it records the current one.
+    let mut cache = match config::read_cache(&opt.cache_file)? {
+        Some(cache) => cache,
+        None => bail!(
+            "there's no cache at {:?}, nothing was deployed",
+            opt.cache_file
+        ),
+    };
+    let mut relocation = Relocation { old, new, count: 0 };
+    relocate_cache(&mut cache, &mut relocation);
+    info!(
+        "Moving {} recorded paths from {:?} to {:?}",
+        relocation.count, old, new
+    );
+
+    let mut success = true;
+    for target in cache.symlinks.values() {
+        let elevate = cache.elevated.contains(target);
+        if let Err(e) = relink(opt.act, target, elevate, &mut relocation) {
+            display_error(e.context(format!("update symlink {:?}", target)));
+            success = false;
+        }
+    }
+
+    for file in config::configuration_files(&opt.local_config, &opt.global_config)
+        .context("find configuration files")?
+    {
+        let contents =
+            fs::read_to_string(&file).with_context(|| format!("read config file {:?}", file))?;
+        if contents.contains(&*old.to_string_lossy()) {
+            warn!(
+                "{:?} mentions the old home directory {:?}, update it by hand",
+                file, old
+            );
+        }
+    }
+
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+    }
+    info!("Run `dotter deploy` to update the shims and the templates that use the home directory");
+    Ok(success)
+}
+
+/// Moves every path of the cache, whether it's a target, a source or a directory
+fn relocate_cache(cache: &mut Cache, relocation: &mut Relocation) {
+    if let Some(repository) = &mut cache.repository {
+        relocation.path(repository);
+    }
+    relocation.set(&mut cache.elevated);
+    relocation.keys(&mut cache.links);
+    relocation.keys(&mut cache.render_caches);
+    relocation.values(&mut cache.render_caches);
+    relocation.keys(&mut cache.undeploy_hooks);
+    for hooks in cache.undeploy_hooks.values_mut() {
+        relocation.set(hooks);
+    }
+    relocation.keys(&mut cache.hook_environments);
+    for environment in cache.hook_environments.values_mut() {
+        if let Some(cwd) = &mut environment.cwd {
+            relocation.path(cwd);
+        }
+        environment
+            .path_prepend
+            .iter_mut()
+            .for_each(|path| relocation.path(path));
+    }
+    relocation.keys(&mut cache.shims);
+    for files in [&mut cache.symlinks, &mut cache.templates, &mut cache.copies] {
+        relocation.keys(files);
+        relocation.values(files);
+    }
+    relocation.keys(&mut cache.copy_hashes);
+    relocation.keys(&mut cache.template_dependencies);
+    relocation.keys(&mut cache.fragments);
+    for sources in cache.fragments.values_mut() {
+        sources
+            .iter_mut()
+            .for_each(|source| relocation.path(source));
+    }
+    relocation.keys(&mut cache.lines);
+    relocation.keys(&mut cache.special);
+    for backup in &mut cache.backups {
+        relocation.path(&mut backup.target);
+        relocation.path(&mut backup.backup);
+    }
+}
+
+/// Points the symlink at `target` at the new home, if it points into the old one. The old
+/// symlink is only replaced once the new one exists, so a failure leaves it as it was.
+fn relink(act: bool, target: &Path, elevate: bool, relocation: &mut Relocation) -> Result<()> {
+    let mut pointee = match fs::read_link(target) {
+        Ok(pointee) => pointee,
+        Err(_) => {
+            debug!("{:?} isn't a symlink, leaving it alone", target);
+            return Ok(());
+        }
+    };
+    let before = relocation.count;
+    relocation.path(&mut pointee);
+    if relocation.count == before {
+        return Ok(());
+    }
+    if !pointee.exists() {
+        bail!(
+            "{:?} doesn't exist under the new home directory, move it there first",
+            pointee
+        );
+    }
+    info!("Relinking {:?} -> {:?}", target, pointee);
+    if !act {
+        return Ok(());
+    }
+    if elevate {
+        return elevate::symlink(
+            target,
+            &pointee,
+            &elevate::Metadata {
+                mode: None,
+                owner: &None,
+                group: &None,
+            },
+            &elevate::Directories {
+                owner: &None,
+                mode: None,
+            },
+        );
+    }
+    let file_name = target.file_name().context("get file name of symlink")?;
+    let mut temporary = file_name.to_os_string();
+    temporary.push(".dotter-relink");
+    let temporary = target.with_file_name(temporary);
+    filesystem::make_symlink(&temporary, &pointee).context("create new symlink")?;
+    fs::rename(&temporary, target)
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+        .context("replace old symlink")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relocate_cache() {
+        let mut cache = Cache::default();
+        cache
+            .symlinks
+            .insert("zshrc".into(), "/home/old/.zshrc".into());
+        cache.templates.insert(
+            "/home/old/dotfiles/gitconfig".into(),
+            "/home/old/.gitconfig".into(),
+        );
+        cache.elevated.insert("/etc/hosts".into());
+        let mut relocation = Relocation {
+            old: Path::new("/home/old"),
+            new: Path::new("/home/new"),
+            count: 0,
+        };
+        relocate_cache(&mut cache, &mut relocation);
+        assert_eq!(relocation.count, 3);
+        assert_eq!(
+            cache.symlinks[Path::new("zshrc")],
+            Path::new("/home/new/.zshrc")
+        );
+        assert_eq!(
+            cache.templates[Path::new("/home/new/dotfiles/gitconfig")],
+            Path::new("/home/new/.gitconfig")
+        );
+        assert!(cache.elevated.contains(Path::new("/etc/hosts")));
+
+        // A sibling that only starts with the same characters isn't moved
+        let mut path = PathBuf::from("/home/older/.zshrc");
+        relocation.path(&mut path);
+        assert_eq!(path, Path::new("/home/older/.zshrc"));
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config;
+use filesystem;
+
+/// Moves a file or directory in the repository, renaming its references in the configuration
+/// and cache so that the next deploy treats it as the same file.
+pub fn move_file(opt: &Options, old: &Path, new: &Path, relink: bool) -> Result<()> {
+    if !old.exists() {
+        bail!("source {:?} doesn't exist", old);
+    }
+    if new.exists() {
+        bail!("destination {:?} already exists", new);
+    }
+    let old_key = old.to_str().context("old source is not valid unicode")?;
+    let new_key = new.to_str().context("new source is not valid unicode")?;
+
+    let mut rewrites = Vec::new();
+    let mut covered = false;
+    for file in config::configuration_files(&opt.local_config, &opt.global_config)
+        .context("find configuration files")?
+    {
+        let contents =
+            fs::read_to_string(&file).with_context(|| format!("read config file {:?}", file))?;
+        let renamed = rename_file_keys(&contents, old_key, new_key)
+            .with_context(|| format!("rename references in {:?}", file))?;
+        covered |= renamed.covering > 0;
+        if renamed.count > 0 {
+            info!("Renaming {} reference(s) in {:?}", renamed.count, file);
+            rewrites.push((file, renamed.contents));
+        }
+    }
+    if rewrites.is_empty() && !covered {
+        bail!(
+            "{:?} isn't a key in the `files` of any configuration file",
+            old
+        );
+    }
+
+    let old_real = filesystem::real_path(old).context("get real path of old source")?;
+    info!("Moving {:?} to {:?}", old, new);
+    if opt.act {
+        if let Some(parent) = new.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("create parent of new source")?;
+        }
+        fs::rename(old, new).context("move source")?;
+        for (file, contents) in rewrites {
+            fs::write(&file, contents).with_context(|| format!("write config file {:?}", file))?;
+        }
+    }
+
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let mut cache = match config::load_cache(&opt.cache_file)? {
+        Some(cache) => cache,
+        None => {
+            debug!("Cache file not found, so there is nothing to update in it");
+            return Ok(());
+        }
+    };
+
+    let symlinks = rename_keys(&mut cache.symlinks, old, new);
+    let templates = rename_keys(&mut cache.templates, old, new);
+    rename_keys(&mut cache.copies, old, new);
+    rename_keys(&mut cache.copy_hashes, old, new);
+    rename_keys(&mut cache.template_dependencies, old, new);
+    rename_keys(&mut cache.render_caches, old, new);
+
+    for (from, to) in &templates {
+        let directory = cache.render_caches.get(to).unwrap_or(&opt.cache_directory);
+        let from = directory.join(from);
+        let to = directory.join(to);
+        debug!("Moving cached render {:?} to {:?}", from, to);
+        if opt.act && from.exists() {
+            fs::create_dir_all(to.parent().context("get parent of cached render")?)
+                .context("create parent of cached render")?;
+            fs::rename(&from, &to).context("move cached render")?;
+            filesystem::delete_parents(&from, false).context("delete parent directory in cache")?;
+        }
+    }
+
+    for (_, source) in &symlinks {
+        let target = &cache.symlinks[source];
+        let points_at_old = fs::read_link(target)
+            .map(|content| content.starts_with(&old_real))
+            .unwrap_or(false);
+        if !points_at_old {
+            continue;
+        }
+        if relink {
+            info!("Relinking {:?} -> {:?}", source, target);
+            if opt.act {
+                fs::remove_file(target).context("remove old symlink")?;
+                filesystem::make_symlink(target, source).context("create new symlink")?;
+            }
+        } else {
+            warn!(
+                "Symlink {:?} still points at the old location. Pass --relink or run `dotter deploy --force` to update it.",
+                target
+            );
+        }
+    }
+
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+    }
+
+    Ok(())
+}
+
+/// Moves every entry at or under `old` to the same place under `new`.
+/// Returns the old and new keys of the moved entries.
+fn rename_keys<T>(
+    map: &mut BTreeMap<PathBuf, T>,
+    old: &Path,
+    new: &Path,
+) -> Vec<(PathBuf, PathBuf)> {
+    let renamed = map
+        .keys()
+        .filter_map(|key| {
+            key.strip_prefix(old).ok().map(|rest| {
+                if rest.as_os_str().is_empty() {
+                    (key.clone(), new.to_path_buf())
+                } else {
+                    (key.clone(), new.join(rest))
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for (from, to) in &renamed {
+        if let Some(value) = map.remove(from) {
+            map.insert(to.clone(), value);
+        }
+    }
+    renamed
+}
+
+/// A TOML document whose `files` keys were renamed by `rename_file_keys`
+struct RenamedKeys {
+    contents: String,
+    /// Amount of keys that were renamed
+    count: usize,
+    /// Amount of directory or glob keys that deploy the file before and after the move alike
+    covering: usize,
+}
+
+/// Renames the keys at or under `old` to the same place under `new` in every `files` table of
+/// a TOML document, leaving the rest of the document (including comments and formatting)
+/// untouched. A glob is renamed if the directory it starts with is moved, like `zsh/*` when
+/// moving `zsh`. Fails if a directory or glob key deploys `old` but not `new`, since the moved
+/// file would silently stop being deployed.
+fn rename_file_keys(contents: &str, old: &str, new: &str) -> Result<RenamedKeys> {
+    let mut output = String::with_capacity(contents.len());
+    let mut count = 0;
+    let mut covering = 0;
+    let mut in_files = false;
+
+    for line in contents.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[').split(']').next();
+            in_files = header
+                .and_then(|h| h.rsplit('.').next())
+                .map(|last| last.trim() == "files")
+                .unwrap_or(false);
+            output.push_str(line);
+            continue;
+        }
+
+        let indent = &line[..line.len() - trimmed.len()];
+        let (key, style, rest) = match parse_key(trimmed) {
+            Some((key, style, rest)) if in_files && rest.trim_start().starts_with('=') => {
+                (key, style, rest)
+            }
+            _ => {
+                output.push_str(line);
+                continue;
+            }
+        };
+        if let Some(renamed) = rename_key(&key, Path::new(old), Path::new(new)) {
+            output.push_str(indent);
+            output.push_str(&format_key(&renamed.to_string_lossy(), style));
+            output.push_str(rest);
+            count += 1;
+            continue;
+        }
+        if config::source_covers(&key, Path::new(old)) {
+            if !config::source_covers(&key, Path::new(new)) {
+                bail!(
+                    "{:?} is deployed through the key {:?}, which doesn't cover {:?}. Give it a key of its own first.",
+                    old,
+                    key,
+                    new
+                );
+            }
+            covering += 1;
+        }
+        output.push_str(line);
+    }
+
+    Ok(RenamedKeys {
+        contents: output,
+        count,
+        covering,
+    })
+}
+
+/// The key `key` after moving `old` to `new`, if it's at or under `old`. Globs are moved along
+/// with the directory they start with.
+fn rename_key(key: &str, old: &Path, new: &Path) -> Option<PathBuf> {
+    let key = Path::new(key);
+    let base = config::glob_base(key);
+    let rest = base.strip_prefix(old).ok()?;
+    let pattern = key.strip_prefix(&base).ok()?;
+    // Joining an empty path would add a trailing slash
+    Some(
+        [rest, pattern]
+            .iter()
+            .filter(|part| !part.as_os_str().is_empty())
+            .fold(new.to_path_buf(), |path, part| path.join(part)),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyStyle {
+    Bare,
+    Basic,
+    Literal,
+}
+
+/// Splits a line into its leading key and the rest of the line
+fn parse_key(line: &str) -> Option<(String, KeyStyle, &str)> {
+    if let Some(rest) = line.strip_prefix('"') {
+        let mut key = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => key.push(chars.next()?.1),
+                '"' => return Some((key, KeyStyle::Basic, &rest[i + 1..])),
+                c => key.push(c),
+            }
+        }
+        None
+    } else if let Some(rest) = line.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        Some((rest[..end].into(), KeyStyle::Literal, &rest[end + 1..]))
+    } else {
+        let end = line
+            .find(|c: char| !is_bare_key_char(c))
+            .unwrap_or(line.len());
+        if end == 0 {
+            return None;
+        }
+        Some((line[..end].into(), KeyStyle::Bare, &line[end..]))
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Formats the key in the original style if it can be represented in it
+fn format_key(key: &str, style: KeyStyle) -> String {
+    match style {
+        KeyStyle::Bare if key.chars().all(is_bare_key_char) => key.into(),
+        KeyStyle::Literal if !key.contains('\'') => format!("'{}'", key),
+        _ => format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rename_file_keys() {
+        let contents = r#"[default.files]
+zshrc = "~/.zshrc" # comment
+"nvim/init.vim" = "~/.config/nvim/init.vim"
+
+[default.variables]
+zshrc = "not a file"
+"#;
+        let renamed = rename_file_keys(contents, "zshrc", "zsh/zshrc").unwrap();
+        assert_eq!(renamed.count, 1);
+        assert!(renamed
+            .contents
+            .contains("\"zsh/zshrc\" = \"~/.zshrc\" # comment\n"));
+        assert!(renamed.contents.contains("zshrc = \"not a file\""));
+
+        let renamed = rename_file_keys(contents, "nvim/init.vim", "nvim/init.lua").unwrap();
+        assert_eq!(renamed.count, 1);
+        assert!(renamed.contents.contains("\"nvim/init.lua\" = "));
+
+        // Moving a directory moves the keys inside of it
+        let renamed = rename_file_keys(contents, "nvim", "vim").unwrap();
+        assert!(renamed.contents.contains("\"vim/init.vim\" = "));
+
+        assert_eq!(
+            rename_file_keys(contents, "missing", "other")
+                .unwrap()
+                .count,
+            0
+        );
+    }
+
+    #[test]
+    fn test_rename_covered_keys() {
+        let contents = r#"[default.files]
+nvim = "~/.config/nvim"
+"zsh/*" = "~"
+"#;
+        // Keys that still deploy the file after the move are left as they are
+        let renamed = rename_file_keys(contents, "nvim/init.vim", "nvim/init.lua").unwrap();
+        assert_eq!((renamed.count, renamed.covering), (0, 1));
+        let renamed = rename_file_keys(contents, "zsh/zshrc", "zsh/zshenv").unwrap();
+        assert_eq!((renamed.count, renamed.covering), (0, 1));
+
+        // Moving the file out of them would stop deploying it
+        assert!(rename_file_keys(contents, "nvim/init.vim", "vim/init.vim").is_err());
+        assert!(rename_file_keys(contents, "zsh/zshrc", "shell/zshrc").is_err());
+
+        // A glob moves along with the directory it starts with
+        let renamed = rename_file_keys(contents, "zsh", "shell").unwrap();
+        assert!(renamed.contents.contains("\"shell/*\" = \"~\""));
+    }
+}
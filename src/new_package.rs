@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use archetypes;
+use args::Options;
+use config;
+use filesystem;
+use git;
+
+/// Creates a directory for a new package and adds the package to global.toml.
+/// If `from` is given, it is copied into the package directory and deployed back to where it was.
+/// If `archetype` is given, the package starts out with the archetype's files and variables.
+pub fn new_package(
+    opt: &Options,
+    name: &str,
+    from: Option<&Path>,
+    archetype: Option<&str>,
+) -> Result<()> {
+    let declarations =
+        config::load_package_declarations(&opt.global_config).context("load packages")?;
+    if declarations.iter().any(|d| d.name == name) {
+        bail!("package {:?} already exists", name);
+    }
+    let directory = PathBuf::from(name);
+    if directory.exists() {
+        bail!("{:?} already exists in the repository", directory);
+    }
+
+    let archetype = match archetype {
+        Some(archetype) => Some(archetypes::find(archetype).with_context(|| {
+            format!(
+                "there's no archetype {:?}, the archetypes are {}",
+                archetype,
+                archetypes::names()
+            )
+        })?),
+        None => None,
+    };
+
+    let mut files = Vec::new();
+    let mut variables = Vec::new();
+    if let Some(archetype) = archetype {
+        info!(
+            "Scaffolding package {:?} from archetype {:?}",
+            name, archetype.name
+        );
+        for file in archetype.files {
+            let source = directory.join(file.source);
+            debug!("Writing {:?}", source);
+            if opt.act {
+                fs::create_dir_all(source.parent().context("get parent of scaffolded file")?)
+                    .context("create directory of scaffolded file")?;
+                fs::write(&source, file.contents).with_context(|| format!("write {:?}", source))?;
+            }
+            files.push((
+                source.to_string_lossy().replace('\\', "/"),
+                file.target_value(),
+            ));
+        }
+        for (variable, value) in archetype.variables {
+            variables.push((variable.to_string(), toml::Value::String(value.to_string())));
+        }
+    } else if let Some(from) = from {
+        if !from.exists() {
+            bail!("{:?} doesn't exist", from);
+        }
+        let file_name = from
+            .file_name()
+            .context("get file name of imported file")?
+            .to_string_lossy()
+            .trim_start_matches('.')
+            .to_string();
+        let source = directory.join(file_name);
+        info!("Importing {:?} into {:?}", from, source);
+        if opt.act {
+            fs::create_dir_all(&directory).context("create package directory")?;
+            filesystem::copy_recursively(from, &source)
+                .with_context(|| format!("copy {:?} into the repository", from))?;
+        }
+        files.push((
+            source.to_string_lossy().replace('\\', "/"),
+            imported_target(from)?,
+        ));
+    } else {
+        info!("Creating package directory {:?}", directory);
+        if opt.act {
+            fs::create_dir_all(&directory).context("create package directory")?;
+        }
+    }
+
+    info!("Adding package {:?} to {:?}", name, opt.global_config);
+    if opt.act {
+        config::append_package(&opt.global_config, name, &files, &variables)
+            .context("add package")?;
+    }
+    // Git doesn't track the directory until something is imported into it
+    let mut edited = vec![opt.global_config.as_path()];
+    if from.is_some() || archetype.is_some() {
+        edited.push(&directory);
+    }
+    git::commit(opt, &edited, &format!("Add package {}", name)).context("commit new package")?;
+    info!(
+        "Add {:?} to the packages in {:?} to deploy it on this machine.",
+        name, opt.local_config
+    );
+
+    Ok(())
+}
+
+/// Target of an imported file. A file that belongs to another user, like the ones in `/etc`,
+/// becomes a template that restores its owner and permissions when it's deployed.
+fn imported_target(from: &Path) -> Result<toml::Value> {
+    let target = collapse_home(from);
+    let owner = filesystem::file_owner(from).context("get owner of imported file")?;
+    let current = filesystem::current_user().context("get current user")?;
+    let owner = match (owner, current) {
+        (Some(owner), Some(current)) if owner != current => owner,
+        _ => return Ok(toml::Value::String(target)),
+    };
+    if from.is_dir() {
+        warn!(
+            "{:?} belongs to user {}, but the owner of a directory can't be recorded. Add `owner` to its files manually.",
+            from, owner
+        );
+        return Ok(toml::Value::String(target));
+    }
+
+    let mode = filesystem::file_mode(from)
+        .context("get mode of imported file")?
+        .context("imported file disappeared")?;
+    let owner = match filesystem::user_name(owner) {
+        Some(name) => toml::Value::String(name),
+        None => toml::Value::Integer(owner.into()),
+    };
+    info!(
+        "{:?} belongs to {}, recording its owner and mode {:o} in the configuration",
+        from, owner, mode
+    );
+
+    let mut table = toml::value::Table::new();
+    table.insert("target".into(), toml::Value::String(target));
+    table.insert("type".into(), toml::Value::String("template".into()));
+    table.insert("owner".into(), owner);
+    table.insert("mode".into(), toml::Value::String(format!("{:o}", mode)));
+    Ok(toml::Value::Table(table))
+}
+
+/// Turns an absolute path inside the home directory back into a `~/` path,
+/// so the target works on other machines too
+pub fn collapse_home(path: &Path) -> String {
+    let home = filesystem::home_dir();
+    let absolute = std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    match absolute.strip_prefix(&home) {
+        Ok(rest) => format!("~/{}", rest.to_string_lossy().replace('\\', "/")),
+        Err(_) => absolute.to_string_lossy().into_owned(),
+    }
+}
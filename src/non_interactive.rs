@@ -0,0 +1,53 @@
+//! `--non-interactive`: nothing asks anything on the terminal. What would have asked fails
+//! instead, with a reason that the `error` event of `--output json` carries for scripts.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Something that needed an answer that nobody may give
+#[derive(Error, Debug)]
+#[error("{message} (not asking because of --non-interactive)")]
+pub struct WouldPrompt {
+    /// Stable identifier of what would have asked, like `sudo-password`
+    pub reason: &'static str,
+    pub message: String,
+}
+
+/// The reason of the first `WouldPrompt` that caused `error`
+pub fn reason(error: &anyhow::Error) -> Option<&'static str> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<WouldPrompt>())
+        .map(|would_prompt| would_prompt.reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reason() {
+        let error = anyhow::Error::new(WouldPrompt {
+            reason: "sudo-password",
+            message: "sudo needs a password".into(),
+        })
+        .context("create /etc/hosts")
+        .context("deploy");
+        assert_eq!(reason(&error), Some("sudo-password"));
+        assert!(format!("{:#}", error).contains("not asking because of --non-interactive"));
+
+        assert_eq!(
+            reason(&anyhow!("permission denied").context("deploy")),
+            None
+        );
+    }
+}
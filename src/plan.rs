@@ -0,0 +1,319 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+use handlebars::Handlebars;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use super::display_error;
+use args::Options;
+use config::{self, FilePackages, Variables};
+use context;
+use deploy;
+use events::{self, Event};
+use file_state::{FileState, MetadataChange};
+use render::RenderLimits;
+use summary;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Symlink,
+    Template,
+    Copy,
+}
+
+/// What a deploy would do to a target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedChange {
+    Create,
+    Update,
+    /// Only the mode, owner or group of the target changes, or where the symlink points
+    Metadata,
+    Delete,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedFile {
+    pub kind: FileKind,
+    pub change: PlannedChange,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// `None` for files that don't belong to a package
+    pub package: Option<String>,
+    /// Changes of the mode, owner, group or where the symlink points, like `mode 644 -> 600`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<String>,
+}
+
+/// Every file that a deploy would touch, without changing anything
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+    /// Sources that are missing from the repository, with their package
+    pub missing_sources: BTreeMap<PathBuf, Option<String>>,
+    /// Why the change of the files that are left out couldn't be told, like a template that
+    /// fails to render
+    pub errors: Vec<anyhow::Error>,
+}
+
+impl Plan {
+    /// Files whose change couldn't be told are left out, like they are from the summary, and
+    /// their error is kept in `errors`
+    pub fn new(
+        state: &FileState,
+        file_packages: &FilePackages,
+        missing_sources: &BTreeMap<PathBuf, Option<String>>,
+        handlebars: &Handlebars,
+        variables: &Variables,
+        limits: &RenderLimits,
+    ) -> Plan {
+        let mut plan = Plan {
+            files: Vec::new(),
+            missing_sources: missing_sources.clone(),
+            errors: Vec::new(),
+        };
+        let mut errors = Vec::new();
+        let mut push = |kind, change, source: &Path, target: &Path, metadata: &[MetadataChange]| {
+            plan.files.push(PlannedFile {
+                kind,
+                change,
+                source: source.into(),
+                target: target.into(),
+                package: file_packages.get(source).cloned(),
+                metadata: metadata.iter().map(ToString::to_string).collect(),
+            })
+        };
+        let changed = |changed: bool, metadata: &[MetadataChange]| {
+            if changed {
+                PlannedChange::Update
+            } else if !metadata.is_empty() {
+                PlannedChange::Metadata
+            } else {
+                PlannedChange::Unchanged
+            }
+        };
+        // Write-once files are left alone once their target exists
+        let created = |write_once: bool, target: &Path| {
+            if write_once && target.exists() {
+                PlannedChange::Unchanged
+            } else {
+                PlannedChange::Create
+            }
+        };
+
+        let (deleted_symlinks, deleted_templates) = state.deleted_files();
+        for s in deleted_symlinks {
+            push(
+                FileKind::Symlink,
+                PlannedChange::Delete,
+                &s.source,
+                &s.target.target,
+                &[],
+            );
+        }
+        for t in deleted_templates {
+            push(
+                FileKind::Template,
+                PlannedChange::Delete,
+                &t.source,
+                &t.target.target,
+                &[],
+            );
+        }
+        for c in state.deleted_copies() {
+            push(
+                FileKind::Copy,
+                PlannedChange::Delete,
+                &c.source,
+                &c.target.target,
+                &[],
+            );
+        }
+
+        let (new_symlinks, new_templates) = state.new_files();
+        for s in new_symlinks {
+            push(
+                FileKind::Symlink,
+                PlannedChange::Create,
+                &s.source,
+                &s.target.target,
+                &[],
+            );
+        }
+        for t in new_templates {
+            let change = created(t.target.write_once, &t.target.target);
+            push(FileKind::Template, change, &t.source, &t.target.target, &[]);
+        }
+        for c in state.new_copies() {
+            let change = created(c.target.write_once, &c.target.target);
+            push(FileKind::Copy, change, &c.source, &c.target.target, &[]);
+        }
+
+        let (old_symlinks, old_templates) = state.old_files();
+        for s in old_symlinks {
+            let change = summary::symlink_changed(&s).and_then(|c| Ok((c, s.metadata_changes()?)));
+            match change {
+                Ok((c, m)) => push(
+                    FileKind::Symlink,
+                    changed(c, &m),
+                    &s.source,
+                    &s.target.target,
+                    &m,
+                ),
+                Err(e) => errors.push(e.context(format!("check {}", s))),
+            }
+        }
+        for t in old_templates {
+            let change = if t.target.write_once {
+                Ok((false, Vec::new()))
+            } else {
+                summary::template_changed(&t, handlebars, variables, limits)
+                    .and_then(|c| Ok((c, t.metadata_changes()?)))
+            };
+            match change {
+                Ok((c, m)) => push(
+                    FileKind::Template,
+                    changed(c, &m),
+                    &t.source,
+                    &t.target.target,
+                    &m,
+                ),
+                Err(e) => errors.push(e.context(format!("check {}", t))),
+            }
+        }
+        for c in state.old_copies() {
+            let change = if c.target.write_once {
+                Ok((false, Vec::new()))
+            } else {
+                summary::copy_changed(&c).and_then(|changed| Ok((changed, c.metadata_changes()?)))
+            };
+            match change {
+                Ok((changed_contents, m)) => push(
+                    FileKind::Copy,
+                    changed(changed_contents, &m),
+                    &c.source,
+                    &c.target.target,
+                    &m,
+                ),
+                Err(e) => errors.push(e.context(format!("check {}", c))),
+            }
+        }
+
+        plan.errors = errors;
+        plan
+    }
+}
+
+/// Prints what a deploy would do, grouped by the kind of change, and the `on_deploy` and
+/// `on_undeploy` hooks that would run. Returns whether nothing would change.
+pub fn print_plan(opt: &Options) -> Result<bool> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let mut cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    cache.match_case(&config.files);
+    let state = deploy::file_state_from_configuration(&config, &cache, &opt.cache_directory, false)
+        .context("get file state")?;
+    let plan = Plan::new(
+        &state,
+        &config.file_packages,
+        &config.missing_sources,
+        &deploy::handlebars_instance(opt, &config),
+        &context::template_context(&config),
+        &RenderLimits::new(opt),
+    );
+    let pending = plan
+        .files
+        .iter()
+        .filter(|file| file.change != PlannedChange::Unchanged)
+        .collect::<Vec<_>>();
+    // Files that couldn't be checked may change too, so the plan fails after it's printed
+    let failed = plan.errors.len();
+    for error in plan.errors {
+        display_error(error);
+    }
+
+    if events::enabled() {
+        for file in &pending {
+            events::report(Event::Planned(file));
+        }
+        if failed > 0 {
+            bail!("failed to check {} targets", failed);
+        }
+        return Ok(pending.is_empty());
+    }
+
+    let (on_deploy, _) = deploy::hooks_by_source(&state, &config);
+    let mut hooks = BTreeSet::new();
+    for file in &pending {
+        let file_hooks = match file.change {
+            PlannedChange::Delete => cache.undeploy_hooks.get(&file.source),
+            _ => on_deploy.get(&file.source),
+        };
+        hooks.extend(file_hooks.into_iter().flatten());
+    }
+
+    let groups = [
+        (PlannedChange::Create, "Create", "[+]".green()),
+        (PlannedChange::Update, "Update", "[~]".yellow()),
+        (PlannedChange::Metadata, "Change metadata", "[~]".yellow()),
+        (PlannedChange::Delete, "Delete", "[-]".red()),
+    ];
+    let mut counts = Vec::new();
+    for (change, title, marker) in groups {
+        let files = pending
+            .iter()
+            .filter(|file| file.change == change)
+            .collect::<Vec<_>>();
+        counts.push(files.len());
+        if files.is_empty() {
+            continue;
+        }
+        println!("{} ({}):", title, files.len());
+        for file in files {
+            let kind = match file.kind {
+                FileKind::Symlink => "symlink",
+                FileKind::Template => "template",
+                FileKind::Copy => "copy",
+            };
+            print!(
+                "  {} {} {:?} -> {:?}",
+                marker, kind, file.source, file.target
+            );
+            if !file.metadata.is_empty() {
+                print!(" ({})", file.metadata.join(", "));
+            }
+            println!();
+        }
+    }
+    if !hooks.is_empty() {
+        println!("Run hooks ({}):", hooks.len());
+        for hook in &hooks {
+            println!("  {:?}", hook);
+        }
+    }
+    if !plan.missing_sources.is_empty() {
+        println!("Missing sources ({}):", plan.missing_sources.len());
+        for source in plan.missing_sources.keys() {
+            println!("  {} {:?}", "[!]".red(), source);
+        }
+    }
+
+    if pending.is_empty() && failed == 0 {
+        println!("No changes, the targets match the configuration.");
+    } else if !pending.is_empty() {
+        println!(
+            "Plan: {} to create, {} to update, {} to change metadata, {} to delete, {} hooks to run.",
+            counts[0],
+            counts[1],
+            counts[2],
+            counts[3],
+            hooks.len()
+        );
+    }
+    if failed > 0 {
+        bail!("failed to check {} targets", failed);
+    }
+    Ok(pending.is_empty())
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use config::Configuration;
+use filesystem;
+use hooks::HooksBySource;
+use reload::Reload;
+
+/// Finds what a deploy would run but isn't on this machine: `on_deploy` and `on_undeploy`
+/// hooks, helper scripts, the program of every reload command and the secret provider.
+/// A dry run reports them all at once, instead of the deploy failing on them one by one.
+pub fn missing_programs(
+    config: &Configuration,
+    deploy_hooks: &HooksBySource,
+    undeploy_hooks: &HooksBySource,
+    reloads: &BTreeMap<PathBuf, String>,
+) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+
+    // Hooks of packages belong to every file of the package, they're reported once
+    let mut hook_sources = BTreeMap::<&PathBuf, Vec<&PathBuf>>::new();
+    for (source, hooks) in deploy_hooks.iter().chain(undeploy_hooks) {
+        for hook in hooks {
+            hook_sources.entry(hook).or_default().push(source);
+        }
+    }
+    for (hook, sources) in hook_sources {
+        if hook.is_file() {
+            continue;
+        }
+        missing.push(match sources.len() {
+            1 => format!("Hook {:?} of {:?} doesn't exist", hook, sources[0]),
+            n => format!(
+                "Hook {:?} of {:?} and {} other files doesn't exist",
+                hook,
+                sources[0],
+                n - 1
+            ),
+        });
+    }
+
+    for (name, helper) in &config.helpers {
+        if !helper.path().is_file() {
+            missing.push(format!(
+                "Script {:?} of helper {:?} doesn't exist",
+                helper.path(),
+                name
+            ));
+        }
+    }
+
+    for (source, reload) in reloads {
+        let program = match Reload::from(reload.as_str()) {
+            Reload::Command(command) => command_program(&command),
+            // The built-ins only run programs that belong to what they reload when it's running
+            _ => None,
+        };
+        if let Some(program) = program {
+            if !filesystem::is_executable(&program)
+                .with_context(|| format!("find executable {:?}", program))?
+            {
+                missing.push(format!(
+                    "Program {:?} of the reload command of {:?} isn't installed",
+                    program, source
+                ));
+            }
+        }
+    }
+
+    if let Some(provider) = config.secrets.provider {
+        let program = provider.command();
+        if !filesystem::is_executable(program)
+            .with_context(|| format!("find executable {:?}", program))?
+        {
+            missing.push(format!(
+                "Program {:?} of the secret provider isn't installed",
+                program
+            ));
+        }
+    }
+
+    Ok(missing)
+}
+
+/// The program that a shell command runs first, skipping the variables it sets for it.
+/// Commands that start with shell syntax have no program that can be told for sure.
+fn command_program(command: &str) -> Option<String> {
+    let word = command
+        .split_whitespace()
+        .find(|word| !is_assignment(word))?;
+    let word = shellexpand::tilde(word).into_owned();
+    if word.contains(|c: char| "$`\"'()<>|&;{}".contains(c)) {
+        return None;
+    }
+    Some(word)
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_program() {
+        assert_eq!(
+            command_program("tmux source-file ~/.tmux.conf"),
+            Some("tmux".into())
+        );
+        assert_eq!(
+            command_program("FOO=1 BAR= fc-cache -f"),
+            Some("fc-cache".into())
+        );
+        assert_eq!(command_program("  "), None);
+        assert_eq!(command_program("$EDITOR --reload"), None);
+        assert_eq!(command_program("(cd ~ && make)"), None);
+    }
+}
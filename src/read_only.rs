@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+
+use std::path::{Component, Path, PathBuf};
+
+use args::{Action, CacheAction, Options, StateAction};
+use filesystem;
+
+/// Fails before doing anything if the command would write into the repository, for
+/// repositories on a read-only mount like the Nix store
+pub fn check(opt: &Options) -> Result<()> {
+    let repository =
+        filesystem::real_path(Path::new(".")).context("get real path of repository")?;
+
+    let command = match opt.action.clone().unwrap_or_default() {
+        Action::Adopt { .. } => Some("adopt"),
+        Action::Init => Some("init"),
+        Action::Migrate => Some("migrate"),
+        Action::Mv { .. } => Some("mv"),
+        Action::NewPackage { .. } => Some("new-package"),
+        Action::Package { .. } => Some("package"),
+        Action::SwitchProfile { .. } => Some("switch-profile"),
+        Action::Var { .. } => Some("var"),
+        // Only read
+        Action::Cache {
+            action: CacheAction::Verify,
+        }
+        | Action::Classify
+        | Action::Diff
+        | Action::ExtractVars { .. }
+        | Action::Lint
+        | Action::Plan { .. }
+        | Action::Report { .. }
+        | Action::Schema { .. }
+        | Action::Selftest { .. }
+        | Action::Stats
+        | Action::Status
+        | Action::Complete { .. } => return Ok(()),
+        Action::Snapshot { output } => {
+            let output = output.unwrap_or_else(|| PathBuf::from("dotter-snapshot.tar"));
+            ensure_outside(&repository, &output, "The snapshot", "--output")?;
+            None
+        }
+        Action::State {
+            action:
+                StateAction::Export {
+                    output: Some(output),
+                    ..
+                },
+        } => {
+            ensure_outside(&repository, &output, "The exported state", "--output")?;
+            None
+        }
+        Action::Export {
+            output: Some(output),
+            ..
+        } => {
+            ensure_outside(
+                &repository,
+                &output,
+                "The exported configuration",
+                "--output",
+            )?;
+            None
+        }
+        _ => None,
+    };
+    if let Some(command) = command {
+        bail!(
+            "`dotter {}` edits the repository, which is read-only because of --read-only-repo",
+            command
+        );
+    }
+
+    ensure_outside(
+        &repository,
+        &opt.cache_file,
+        "The cache file",
+        "--cache-file or $DOTTER_CACHE_FILE",
+    )?;
+    ensure_outside(
+        &repository,
+        &opt.cache_directory,
+        "The cache directory",
+        "--cache-directory or $DOTTER_CACHE_DIRECTORY",
+    )
+}
+
+fn ensure_outside(repository: &Path, path: &Path, what: &str, option: &str) -> Result<()> {
+    if resolve(path)?.starts_with(repository) {
+        bail!(
+            "{} {:?} is inside the repository, which is read-only because of --read-only-repo. Move it elsewhere with {}.",
+            what,
+            path,
+            option
+        );
+    }
+    Ok(())
+}
+
+/// Real path of a file that may not exist yet: the real path of its closest existing
+/// ancestor, followed by the rest of the path
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let absolute = std::env::current_dir()
+        .context("get current directory")?
+        .join(path);
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(Component::Normal(name))) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = filesystem::real_path(existing)
+        .with_context(|| format!("get real path of {:?}", existing))?;
+    resolved.extend(rest.iter().rev());
+    Ok(resolved)
+}
@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use events;
+use filesystem;
+use handlebars_helpers;
+use hooks::{Change, ChangeAction};
+
+/// What is done after a file with `reload = "..."` changes.
+/// Values that aren't the name of a built-in are run as a shell command.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reload {
+    /// Sends SIGUSR1 to every running kitty, which makes it reload kitty.conf
+    Kitty,
+    /// Alacritty watches its configuration file and reloads it by itself
+    Alacritty,
+    /// foot can't reload its configuration, only new windows pick it up
+    Foot,
+    I3,
+    Sway,
+    Hyprland,
+    /// Whichever of i3, sway and Hyprland is running
+    Compositor,
+    Command(String),
+    /// `systemctl --user daemon-reload`, after a user unit changed
+    SystemdUser,
+    /// Unloads and loads the plist of a launchd agent, after it was created or updated
+    LaunchAgent(PathBuf),
+    /// Removes a launchd agent by its label, after its plist was deleted
+    RemovedLaunchAgent(String),
+}
+
+impl From<&str> for Reload {
+    fn from(s: &str) -> Reload {
+        match s {
+            "kitty" => Reload::Kitty,
+            "alacritty" => Reload::Alacritty,
+            "foot" => Reload::Foot,
+            "i3" => Reload::I3,
+            "sway" => Reload::Sway,
+            "hyprland" => Reload::Hyprland,
+            "compositor" => Reload::Compositor,
+            command => Reload::Command(command.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for Reload {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reload::Kitty => write!(f, "kitty"),
+            Reload::Alacritty => write!(f, "alacritty"),
+            Reload::Foot => write!(f, "foot"),
+            Reload::I3 => write!(f, "i3"),
+            Reload::Sway => write!(f, "sway"),
+            Reload::Hyprland => write!(f, "Hyprland"),
+            Reload::Compositor => write!(f, "compositor"),
+            Reload::Command(command) => write!(f, "`{}`", command),
+            Reload::SystemdUser => write!(f, "systemd user units"),
+            Reload::LaunchAgent(plist) => write!(f, "launch agent {:?}", plist),
+            Reload::RemovedLaunchAgent(label) => write!(f, "launch agent {}", label),
+        }
+    }
+}
+
+/// Returns every reload whose file is among the changes, and the service managers of the
+/// changed `service_unit` files, without duplicates
+pub fn changed_reloads(
+    changes: &[Change],
+    reloads: &BTreeMap<PathBuf, String>,
+    services: &BTreeSet<PathBuf>,
+) -> Vec<Reload> {
+    let commands = changes
+        .iter()
+        .filter_map(|change| reloads.get(&change.source))
+        .map(|reload| Reload::from(reload.as_str()));
+    // The configuration that marked a deleted unit may be gone, so any deleted unit counts
+    let services = changes
+        .iter()
+        .filter(|change| {
+            services.contains(&change.source) || change.action == ChangeAction::Deleted
+        })
+        .filter_map(|change| {
+            service_reload(&change.target, change.action == ChangeAction::Deleted)
+        });
+    commands
+        .chain(services)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// What the service manager of `target` has to be told after it changed, or `None` if it isn't
+/// in a directory of systemd user units or launchd agents
+pub fn service_reload(target: &Path, deleted: bool) -> Option<Reload> {
+    // Drop-ins like `foo.service.d/override.conf` are a directory deeper
+    if target
+        .ancestors()
+        .skip(1)
+        .take(2)
+        .any(|directory| directory.ends_with("systemd/user"))
+    {
+        return Some(Reload::SystemdUser);
+    }
+    let parent = target.parent()?;
+    if parent.ends_with("LaunchAgents") && target.extension()? == "plist" {
+        return Some(if deleted {
+            Reload::RemovedLaunchAgent(target.file_stem()?.to_string_lossy().into_owned())
+        } else {
+            Reload::LaunchAgent(target.into())
+        });
+    }
+    None
+}
+
+pub fn run(reload: &Reload) -> Result<()> {
+    debug!("Reloading {}", reload);
+    match reload {
+        Reload::Kitty => signal_processes("kitty", "USR1").context("signal kitty"),
+        Reload::Alacritty => {
+            debug!("Alacritty reloads its configuration by itself");
+            Ok(())
+        }
+        Reload::Foot => {
+            info!("foot can't reload its configuration. It will be used by new windows.");
+            Ok(())
+        }
+        Reload::I3 if !i3_running() => {
+            debug!("i3 isn't running");
+            Ok(())
+        }
+        Reload::I3 => run_program("i3-msg", &["reload"]),
+        Reload::Sway if std::env::var_os("SWAYSOCK").is_none() => {
+            debug!("sway isn't running");
+            Ok(())
+        }
+        Reload::Sway => run_program("swaymsg", &["reload"]),
+        Reload::Hyprland if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_none() => {
+            debug!("Hyprland isn't running");
+            Ok(())
+        }
+        Reload::Hyprland => run_program("hyprctl", &["reload"]),
+        Reload::Compositor => match running_compositor() {
+            Some(compositor) => run(&compositor),
+            None => {
+                debug!("No supported compositor is running");
+                Ok(())
+            }
+        },
+        Reload::SystemdUser if !Path::new("/run/systemd/system").exists() => {
+            debug!("systemd isn't running");
+            Ok(())
+        }
+        Reload::SystemdUser => run_program("systemctl", &["--user", "daemon-reload"]),
+        Reload::LaunchAgent(_) | Reload::RemovedLaunchAgent(_) if !cfg!(target_os = "macos") => {
+            debug!("launchd only runs on macOS");
+            Ok(())
+        }
+        Reload::LaunchAgent(plist) => {
+            let plist = plist.to_string_lossy();
+            // Fails if the agent wasn't loaded yet, which is fine
+            if let Err(e) = run_program("launchctl", &["unload", &plist]) {
+                debug!("Not unloading {}: {:#}", plist, e);
+            }
+            run_program("launchctl", &["load", &plist])
+        }
+        Reload::RemovedLaunchAgent(label) => {
+            if let Err(e) = run_program("launchctl", &["remove", label]) {
+                debug!("Not removing {}: {:#}", label, e);
+            }
+            Ok(())
+        }
+        Reload::Command(command) => {
+            let status = handlebars_helpers::os_shell()
+                .arg(command)
+                .stdin(Stdio::null())
+                .stdout(events::child_stdout())
+                .status()
+                .context("run reload command")?;
+            if !status.success() {
+                bail!("reload command exited with {}", status);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Detects the compositor from the environment variables that it sets for its clients
+fn running_compositor() -> Option<Reload> {
+    if std::env::var_os("SWAYSOCK").is_some() {
+        Some(Reload::Sway)
+    } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        Some(Reload::Hyprland)
+    } else if i3_running() {
+        Some(Reload::I3)
+    } else {
+        None
+    }
+}
+
+/// i3 doesn't always export `$I3SOCK`, but it can be asked for its socket on the X display
+fn i3_running() -> bool {
+    std::env::var_os("I3SOCK").is_some()
+        || (std::env::var_os("DISPLAY").is_some()
+            && std::process::Command::new("i3")
+                .arg("--get-socketpath")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false))
+}
+
+fn run_program(program: &str, args: &[&str]) -> Result<()> {
+    let mut command = std::process::Command::new(program);
+    filesystem::set_child_environment(&mut command);
+    let output = command
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("run {}", program))?;
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn signal_processes(name: &str, signal: &str) -> Result<()> {
+    let status = std::process::Command::new("pkill")
+        .arg(format!("-{}", signal))
+        .arg("-x")
+        .arg(name)
+        .stdin(Stdio::null())
+        .status()
+        .context("run pkill")?;
+    // pkill exits with 1 if no process matched
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(1) => {
+            debug!("No running {} to signal", name);
+            Ok(())
+        }
+        _ => bail!("pkill exited with {}", status),
+    }
+}
+
+#[cfg(not(unix))]
+fn signal_processes(name: &str, _signal: &str) -> Result<()> {
+    bail!("reloading {} isn't supported on this platform", name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_service_reload() {
+        assert_eq!(
+            service_reload(
+                Path::new("/home/a/.config/systemd/user/sync.service"),
+                false
+            ),
+            Some(Reload::SystemdUser)
+        );
+        assert_eq!(
+            service_reload(
+                Path::new("/home/a/.config/systemd/user/sync.service.d/override.conf"),
+                true
+            ),
+            Some(Reload::SystemdUser)
+        );
+        assert_eq!(
+            service_reload(
+                Path::new("/Users/a/Library/LaunchAgents/com.a.sync.plist"),
+                false
+            ),
+            Some(Reload::LaunchAgent(
+                "/Users/a/Library/LaunchAgents/com.a.sync.plist".into()
+            ))
+        );
+        assert_eq!(
+            service_reload(
+                Path::new("/Users/a/Library/LaunchAgents/com.a.sync.plist"),
+                true
+            ),
+            Some(Reload::RemovedLaunchAgent("com.a.sync".into()))
+        );
+        assert_eq!(
+            service_reload(Path::new("/home/a/.config/systemd/user"), false),
+            None
+        );
+        assert_eq!(service_reload(Path::new("/home/a/.zshrc"), false), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! Deploys to a machine over SSH without dotter or the repository on it: everything is rendered
+//! here, with the local.toml given by `--local-config`, and the resulting files are written on
+//! the host by a shell script that a single `ssh` session runs.
+//!
+//! Templates see the facts of the host, like its `hostname` and `os`, and so do the conditions
+//! that enable packages. Symlinks become copies, since their sources aren't on the host. Targets in the home
+//! directory are written relative to the home directory of the SSH user, other targets at the
+//! same absolute path. The hashes of the written files are kept on the host in
+//! `~/.cache/dotter/remote.json`, so later deploys only write what changed and delete what
+//! isn't deployed anymore. Edits made on the host are overwritten. Hooks, reloads, managed
+//! lines, owners and elevation are local features that aren't applied.
+
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use args::Options;
+use config::Cache;
+use context;
+use deploy;
+use facts;
+use filesystem;
+use render::{self, RenderLimits};
+
+/// Where the hashes of the deployed files are kept, relative to the home directory on the host
+const REMOTE_CACHE: &str = ".cache/dotter/remote.json";
+
+/// A file as it's written on the host
+struct RemoteFile {
+    contents: Vec<u8>,
+    mode: Option<u32>,
+}
+
+/// Hashes of the files deployed on the host, by their path there
+type RemoteCache = BTreeMap<String, String>;
+
+/// Renders the configuration and writes the files that changed since the last deploy to
+/// `destination`, or only prints what it would write in a dry run
+pub fn deploy(opt: &Options, destination: &str) -> Result<()> {
+    let host = ssh(destination, &["sh", "-s"], Some(facts::HOST_FACTS_SCRIPT))
+        .context("gather facts of host")?;
+    facts::set_host(facts::parse_host_facts(&host));
+    let files = desired_files(opt).context("render files")?;
+    let cached = fetch_cache(destination).context("fetch cache from host")?;
+
+    // Files are private until they get their mode, and so are the directories that are created
+    let mut script = String::from("set -e\numask 077\n");
+    let mut hashes = RemoteCache::new();
+    let (mut written, mut unchanged) = (0, 0);
+    for (path, file) in &files {
+        let hash = blake3::hash(&file.contents).to_hex().to_string();
+        if cached.get(path) == Some(&hash) {
+            unchanged += 1;
+        } else {
+            let sign = if cached.contains_key(path) {
+                "[~]".yellow()
+            } else {
+                "[+]".green()
+            };
+            info!("{} {}:{}", sign, destination, path);
+            write_file(&mut script, path, &file.contents, file.mode);
+            written += 1;
+        }
+        hashes.insert(path.clone(), hash);
+    }
+    let mut deleted = 0;
+    for path in cached.keys().filter(|path| !files.contains_key(*path)) {
+        info!("{} {}:{}", "[-]".red(), destination, path);
+        script.push_str(&format!("rm -f -- {}\n", quote(path)));
+        deleted += 1;
+    }
+    let cache = serde_json::to_string_pretty(&hashes).context("serialize cache")?;
+    write_file(&mut script, REMOTE_CACHE, cache.as_bytes(), None);
+
+    if written == 0 && deleted == 0 {
+        println!("{} is up to date ({} files)", destination, unchanged);
+        return Ok(());
+    }
+    if !opt.act {
+        println!(
+            "Would write {} files to {} and delete {}, {} are unchanged",
+            written, destination, deleted, unchanged
+        );
+        return Ok(());
+    }
+    ssh(destination, &["sh", "-s"], Some(&script)).context("run deploy script on host")?;
+    println!(
+        "Wrote {} files to {} and deleted {}, {} are unchanged",
+        written, destination, deleted, unchanged
+    );
+    Ok(())
+}
+
+/// Every file of the configuration with its rendered contents, by its path on the host
+fn desired_files(opt: &Options) -> Result<BTreeMap<String, RemoteFile>> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    if !config.lines.is_empty() {
+        warn!("Managed lines aren't deployed over SSH");
+    }
+    let state = deploy::file_state_from_configuration(
+        &config,
+        &Cache::default(),
+        &opt.cache_directory,
+        true,
+    )
+    .context("get file state")?;
+    let variables = context::template_context(&config);
+    let handlebars = deploy::handlebars_instance(opt, &config);
+    let limits = RenderLimits::new(opt);
+
+    let mut files = BTreeMap::new();
+    for symlink in &state.desired_symlinks {
+        add_source(&mut files, &symlink.source, &symlink.target.target)
+            .with_context(|| format!("read {}", symlink))?;
+    }
+    for copy in &state.desired_copies {
+        add_source(&mut files, &copy.source, &copy.target.target)
+            .with_context(|| format!("read {}", copy))?;
+        if let Some(mode) = copy.target.mode {
+            if let Some(file) = files.get_mut(&remote_path(&copy.target.target)) {
+                file.mode = Some(mode);
+            }
+        }
+    }
+    for template in &state.desired_templates {
+        let rendered = render::render_template(template, &handlebars, &variables, &limits)
+            .with_context(|| format!("render {}", template))?;
+        let mode = match template.target.mode {
+            Some(mode) => Some(mode),
+            None => filesystem::file_mode(&template.source).context("get mode of template")?,
+        };
+        files.insert(
+            remote_path(&template.target.target),
+            RemoteFile {
+                contents: rendered.into_bytes(),
+                mode,
+            },
+        );
+    }
+    Ok(files)
+}
+
+/// Adds the file at `source`, or every file inside it if it's a directory
+fn add_source(
+    files: &mut BTreeMap<String, RemoteFile>,
+    source: &Path,
+    target: &Path,
+) -> Result<()> {
+    if source.is_dir() {
+        for child in fs::read_dir(source).context("read contents of directory")? {
+            let child = child.context("get next file")?;
+            add_source(files, &child.path(), &target.join(child.file_name()))?;
+        }
+        return Ok(());
+    }
+    let file = RemoteFile {
+        contents: fs::read(source).with_context(|| format!("read {:?}", source))?,
+        mode: filesystem::file_mode(source).context("get mode of source")?,
+    };
+    files.insert(remote_path(target), file);
+    Ok(())
+}
+
+/// Path of a target on the host: relative to the home directory there if it's in the home
+/// directory here, since `ssh` runs commands in the home directory
+fn remote_path(target: &Path) -> String {
+    let home = filesystem::home_dir();
+    let path = target
+        .strip_prefix(&home)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| target.to_path_buf());
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Appends the commands that write `contents` to `path` through a temporary file, so that
+/// an interrupted deploy doesn't leave a truncated target
+fn write_file(script: &mut String, path: &str, contents: &[u8], mode: Option<u32>) {
+    let temporary = quote(&format!("{}.dotter_tmp", path));
+    if let Some((parent, _)) = path
+        .rsplit_once('/')
+        .filter(|(parent, _)| !parent.is_empty())
+    {
+        script.push_str(&format!("mkdir -p -- {}\n", quote(parent)));
+    }
+    script.push_str(&format!(": > {}\n", temporary));
+    for chunk in contents.chunks(4096) {
+        script.push_str(&format!(
+            "printf '{}' >> {}\n",
+            printf_escape(chunk),
+            temporary
+        ));
+    }
+    if let Some(mode) = mode {
+        script.push_str(&format!("chmod {:o} {}\n", mode & 0o7777, temporary));
+    }
+    // A target that's a bind mount can't be replaced, only written to
+    script.push_str(&format!(
+        "mv -f -- {0} {1} 2>/dev/null || {{ cat -- {0} > {1} && rm -f -- {0}; }}\n",
+        temporary,
+        quote(path)
+    ));
+}
+
+/// Format string of `printf` that prints exactly `bytes`, which can be anything
+fn printf_escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'%' => escaped.push_str("%%"),
+            b'\\' => escaped.push_str("\\\\"),
+            b'\'' => escaped.push_str("\\047"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
+
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+fn fetch_cache(destination: &str) -> Result<RemoteCache> {
+    let command = format!("cat {} 2>/dev/null || true", REMOTE_CACHE);
+    let output = ssh(destination, &[&command], None)?;
+    if output.trim().is_empty() {
+        debug!("No cache on {}, deploying every file", destination);
+        return Ok(RemoteCache::new());
+    }
+    serde_json::from_str(&output).with_context(|| format!("parse ~/{}", REMOTE_CACHE))
+}
+
+/// Runs a command on the host and returns its standard output
+fn ssh(destination: &str, command: &[&str], input: Option<&str>) -> Result<String> {
+    let mut ssh = Command::new("ssh");
+    ssh.args(["-o", "BatchMode=yes", "--", destination])
+        .args(command);
+    debug!("Running {:?}", ssh);
+    let mut child = ssh
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("spawn ssh")?;
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .context("open standard input of ssh")?
+            .write_all(input.as_bytes())
+            .context("write script to ssh")?;
+    }
+    let output = child.wait_with_output().context("wait for ssh")?;
+    match output.status.code() {
+        Some(0) => {}
+        Some(255) => bail!("cannot connect to {}", destination),
+        _ => bail!("ssh exited with {}", output.status),
+    }
+    String::from_utf8(output.stdout).context("output of ssh isn't UTF-8")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_file() {
+        let mut script = String::new();
+        write_file(&mut script, ".config/it's", b"100%\n\\\x01", Some(0o100755));
+        assert_eq!(
+            script,
+            "mkdir -p -- '.config'\n\
+             : > '.config/it'\\''s.dotter_tmp'\n\
+             printf '100%%\\012\\\\\\001' >> '.config/it'\\''s.dotter_tmp'\n\
+             chmod 755 '.config/it'\\''s.dotter_tmp'\n\
+             mv -f -- '.config/it'\\''s.dotter_tmp' '.config/it'\\''s' 2>/dev/null || { cat -- '.config/it'\\''s.dotter_tmp' > '.config/it'\\''s' && rm -f -- '.config/it'\\''s.dotter_tmp'; }\n"
+        );
+    }
+}
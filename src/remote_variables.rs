@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+
+use handlebars::Handlebars;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use config::Variables;
+use facts;
+
+/// How long to wait for the remote endpoint before falling back to the cached copy
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches the variables at `url` (a template that can reference the machine's
+/// `hostname`, `os` and `user`) and caches them in `cache_directory`.
+/// If the endpoint can't be reached, the last cached copy is used instead.
+pub fn fetch_variables(url: &str, cache_directory: &Path) -> Result<Variables> {
+    let url = render_url(url).context("render variables_url")?;
+    let cache_file = cache_file(cache_directory, &url);
+
+    let body = match fetch(&url) {
+        Ok(body) => {
+            debug!("Fetched remote variables from {}", url);
+            if let Err(e) = save_cached(&cache_file, &body) {
+                warn!("Failed to cache remote variables: {:?}", e);
+            }
+            body
+        }
+        Err(e) => match fs::read_to_string(&cache_file) {
+            Ok(body) => {
+                warn!(
+                    "Failed to fetch remote variables from {}, using cached copy: {:?}",
+                    url, e
+                );
+                body
+            }
+            Err(_) => {
+                return Err(e).with_context(|| format!("fetch {} (and no cached copy exists)", url))
+            }
+        },
+    };
+
+    parse_variables(&body).with_context(|| format!("parse variables from {}", url))
+}
+
+/// Fetches a small resource for the `fetch_url` helper and caches it in `cache_directory`.
+/// With a `checksum` (the blake3 hash of the contents, like `content_hash` prints) a matching
+/// cached copy is used without going to the network, and a different download is rejected.
+/// If the resource can't be fetched, the last cached copy is used instead.
+pub fn fetch_resource(url: &str, checksum: Option<&str>, cache_directory: &Path) -> Result<String> {
+    let cache_file = cache_directory
+        .join(".dotter_fetched")
+        .join(format!("{}.txt", blake3::hash(url.as_bytes()).to_hex()));
+    let cached = fs::read_to_string(&cache_file).ok();
+
+    if let (Some(cached), Some(checksum)) = (&cached, checksum) {
+        if verify_checksum(cached, checksum).is_ok() {
+            debug!("Using cached copy of {}", url);
+            return Ok(cached.clone());
+        }
+    }
+
+    match fetch(url) {
+        Ok(body) => {
+            if let Some(checksum) = checksum {
+                verify_checksum(&body, checksum).with_context(|| format!("verify {}", url))?;
+            }
+            debug!("Fetched {}", url);
+            if let Err(e) = save_cached(&cache_file, &body) {
+                warn!("Failed to cache {}: {:?}", url, e);
+            }
+            Ok(body)
+        }
+        Err(e) => match cached {
+            Some(cached) => {
+                if let Some(checksum) = checksum {
+                    verify_checksum(&cached, checksum)
+                        .with_context(|| format!("verify cached copy of {}", url))?;
+                }
+                warn!("Failed to fetch {}, using cached copy: {:?}", url, e);
+                Ok(cached)
+            }
+            None => Err(e).with_context(|| format!("fetch {} (and no cached copy exists)", url)),
+        },
+    }
+}
+
+fn verify_checksum(body: &str, checksum: &str) -> Result<()> {
+    let actual = blake3::hash(body.as_bytes()).to_hex();
+    if !actual.as_str().eq_ignore_ascii_case(checksum.trim()) {
+        bail!("checksum is {}, expected {}", actual, checksum);
+    }
+    Ok(())
+}
+
+fn render_url(url: &str) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    Ok(handlebars.render_template(url, &machine_facts())?)
+}
+
+/// The `hostname`, `os` and `user` of this machine, which identify it to a remote source
+pub fn machine_facts() -> Variables {
+    let mut machine = Variables::new();
+    machine.insert("hostname".into(), facts::hostname().into());
+    machine.insert("os".into(), facts::family().into());
+    machine.insert("user".into(), facts::user().into());
+    machine
+}
+
+fn fetch(url: &str) -> Result<String> {
+    let response = attohttpc::get(url)
+        .timeout(TIMEOUT)
+        .send()
+        .context("send request")?;
+    if !response.is_success() {
+        bail!("server responded with {}", response.status());
+    }
+    response.text().context("read response body")
+}
+
+/// Each URL gets its own cache file, so a cached copy is never used for a different machine
+fn cache_file(cache_directory: &Path, url: &str) -> PathBuf {
+    cache_directory
+        .join(".dotter_remote_variables")
+        .join(format!("{}.txt", blake3::hash(url.as_bytes()).to_hex()))
+}
+
+fn save_cached(cache_file: &Path, body: &str) -> Result<()> {
+    fs::create_dir_all(cache_file.parent().context("get parent of cache file")?)
+        .context("create parent of cache file")?;
+    fs::write(cache_file, body).context("write cache file")
+}
+
+/// Accepts either a JSON object or a TOML document
+fn parse_variables(body: &str) -> Result<Variables> {
+    match serde_json::from_str(body) {
+        Ok(variables) => Ok(variables),
+        Err(json_error) => toml::from_str(body).map_err(|toml_error| {
+            anyhow!(
+                "neither valid json ({}) nor valid toml ({})",
+                json_error,
+                toml_error
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_variables() {
+        let json = parse_variables(r#"{"a": 1, "b": {"c": "d"}}"#).unwrap();
+        let toml = parse_variables("a = 1\n[b]\nc = \"d\"\n").unwrap();
+        assert_eq!(json, toml);
+        assert!(parse_variables("[1, 2").is_err());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let checksum = blake3::hash(b"ssh-ed25519 AAAA").to_hex().to_string();
+        assert!(verify_checksum("ssh-ed25519 AAAA", &checksum).is_ok());
+        assert!(verify_checksum("ssh-ed25519 AAAA", &checksum.to_uppercase()).is_ok());
+        assert!(verify_checksum("ssh-ed25519 BBBB", &checksum).is_err());
+    }
+}
@@ -0,0 +1,614 @@
+use anyhow::{Context, Result};
+
+use handlebars::{
+    Context as HandlebarsContext, Handlebars, Output, RenderContext, Renderable, Template,
+    TemplateError, TemplateRenderError,
+};
+use serde::Serialize;
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use args::Options;
+use cancel::CancellationToken;
+use config::{TemplateEngine, Variables};
+use file_state::TemplateDescription;
+use fragments;
+use handlebars_helpers;
+use secrets;
+
+/// Lines of the template that are shown before and after the one that failed to render
+const CONTEXT_LINES: usize = 2;
+
+/// Nanoseconds spent rendering since the last call to `take_render_time`
+static RENDER_NANOS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// When the template that's being rendered on this thread runs out of time, for the
+    /// helpers that run programs
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// When the template that's being rendered runs out of time, if one is
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.with(Cell::get)
+}
+
+/// Renders with `render` until `deadline`, or the earlier deadline of the template that this
+/// one is a part of
+fn with_deadline<T>(deadline: Instant, render: impl FnOnce() -> T) -> T {
+    let outer = DEADLINE.with(|d| d.replace(Some(d.get().map_or(deadline, |d| d.min(deadline)))));
+    let result = render();
+    DEADLINE.with(|d| d.set(outer));
+    result
+}
+
+/// Returns the time spent rendering since the last call, so deploy timings can tell rendering
+/// apart from the filesystem work around it
+pub fn take_render_time() -> Duration {
+    Duration::from_nanos(RENDER_NANOS.swap(0, Ordering::Relaxed))
+}
+
+/// Outputs of the templates that `prerender` rendered ahead of the deploy, by source
+static PRERENDERED: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// Forgets the outputs of `prerender` when dropped, so the next deploy renders again
+pub struct Prerendered;
+
+impl Drop for Prerendered {
+    fn drop(&mut self) {
+        PRERENDERED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+/// Forgets the output of `prerender` for a source that was replaced during the deploy
+pub fn forget_prerendered(source: &Path) {
+    PRERENDERED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(source);
+}
+
+/// Parsed templates by the hash of their contents, kept between the deploys of `dotter watch`
+/// so that only the templates that changed are parsed again. `None` outside of watch mode.
+static PARSED: Mutex<Option<ParsedTemplates>> = Mutex::new(None);
+
+#[derive(Default)]
+struct ParsedTemplates {
+    templates: HashMap<blake3::Hash, Arc<Template>>,
+    /// Templates that were used since the last call to `forget_unused_templates`
+    used: HashSet<blake3::Hash>,
+}
+
+/// Keeps every parsed template from now on, for `dotter watch`
+pub fn keep_parsed_templates() {
+    *PARSED.lock().unwrap_or_else(|e| e.into_inner()) = Some(ParsedTemplates::default());
+}
+
+/// Forgets the kept templates that weren't used since the last call, like the earlier versions
+/// of a template that was edited
+pub fn forget_unused_templates() {
+    if let Some(parsed) = PARSED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let used = std::mem::take(&mut parsed.used);
+        parsed.templates.retain(|hash, _| used.contains(hash));
+    }
+}
+
+/// Parses `contents`, or takes the template that was kept from parsing the same contents.
+/// The lock isn't held while parsing, so that `prerender`'s threads parse in parallel.
+pub fn parse_template(contents: &str) -> Result<Arc<Template>, Box<TemplateError>> {
+    let hash = blake3::hash(contents.as_bytes());
+    match PARSED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(parsed) => {
+            parsed.used.insert(hash);
+            if let Some(template) = parsed.templates.get(&hash) {
+                return Ok(template.clone());
+            }
+        }
+        None => return Ok(Arc::new(Template::compile2(contents, true)?)),
+    }
+    let template = Arc::new(Template::compile2(contents, true)?);
+    if let Some(parsed) = PARSED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        parsed.templates.insert(hash, template.clone());
+    }
+    Ok(template)
+}
+
+/// Renders `templates` on up to `jobs` threads, so that `render_template` only has to look up
+/// their outputs while the deploy writes the files one by one. Templates that fail are left
+/// out, rendering them again reports the failure where the template is deployed. Stops once
+/// the deploy is cancelled.
+pub fn prerender(
+    templates: &[&TemplateDescription],
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+    jobs: usize,
+    cancellation: &CancellationToken,
+) -> Prerendered {
+    PRERENDERED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+    if jobs <= 1 || templates.len() <= 1 {
+        return Prerendered;
+    }
+
+    let next = AtomicUsize::new(0);
+    let outputs = Mutex::new(BTreeMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(templates.len()) {
+            scope.spawn(|| {
+                while let Some(template) = templates
+                    .get(next.fetch_add(1, Ordering::Relaxed))
+                    .filter(|_| !cancellation.is_cancelled())
+                {
+                    if let Ok(output) = render_template(template, handlebars, variables, limits) {
+                        outputs
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(template.source.clone(), output);
+                    }
+                }
+            });
+        }
+    });
+    debug!("Rendered {} templates ahead of the deploy", templates.len());
+    *PRERENDERED.lock().unwrap_or_else(|e| e.into_inner()) =
+        outputs.into_inner().unwrap_or_else(|e| e.into_inner());
+    Prerendered
+}
+
+/// Guardrails that make a runaway template fail instead of exhausting memory or hanging
+#[derive(Debug, Clone, Copy)]
+pub struct RenderLimits {
+    /// Maximum size in bytes of a template's source and of its rendered output
+    pub max_size: u64,
+    /// Maximum time that rendering a single template may take.
+    /// It is checked whenever the template produces output, and the programs that its
+    /// helpers run are stopped when it's up.
+    pub timeout: Duration,
+}
+
+impl RenderLimits {
+    pub fn new(opt: &Options) -> RenderLimits {
+        RenderLimits {
+            max_size: opt.max_template_size,
+            timeout: Duration::from_secs(opt.render_timeout),
+        }
+    }
+}
+
+/// Reads the template's source file and applies its actions (append, prepend)
+pub fn read_template_source(
+    template: &TemplateDescription,
+    limits: &RenderLimits,
+) -> Result<String> {
+    let contents = match fragments::unwritten(&template.source) {
+        Some(contents) => contents,
+        None => {
+            let size = fs::metadata(&template.source)
+                .context("read metadata of template source file")?
+                .len();
+            if size > limits.max_size {
+                bail!(
+                    "template source is {} bytes, which is more than the limit of {} bytes",
+                    size,
+                    limits.max_size
+                );
+            }
+            fs::read_to_string(&template.source).context("read template source file")?
+        }
+    };
+    Ok(template.apply_actions(contents))
+}
+
+pub fn render_template(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<String> {
+    if let Some(output) = PRERENDERED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&template.source)
+    {
+        return Ok(output.clone());
+    }
+    // Encrypted files are deployed as they decrypt, without being rendered
+    if template.target.encrypted {
+        return secrets::decrypt_file(&template.source)
+            .with_context(|| format!("decrypt {:?}", template.source));
+    }
+    let contents = read_template_source(template, limits)?;
+    // Lines of the source file are numbered as in the file, not counting what is prepended
+    let prepended = template
+        .target
+        .prepend
+        .as_ref()
+        .map_or(0, |p| p.matches('\n').count());
+    let variables = template.context(variables);
+    let engine: &dyn Engine = match template.engine() {
+        TemplateEngine::Handlebars => handlebars,
+        TemplateEngine::Tera => &Tera,
+    };
+    handlebars_helpers::rendering_template(&template.source, || {
+        engine.render(&contents, &variables, limits)
+    })
+    .map_err(|e| match e.downcast::<TemplateFailure>() {
+        Ok(failure) => failure.shifted(prepended).into(),
+        Err(e) => e,
+    })
+    .with_context(|| format!("render template {:?}", template.source))
+}
+
+/// A template language. Every engine renders the same variables within the same limits, and
+/// its renders are cached and compared against the target the same way.
+pub trait Engine {
+    /// Renders a template string, failing once the output or the time spent exceeds the limits
+    fn render(
+        &self,
+        contents: &str,
+        variables: &Variables,
+        limits: &RenderLimits,
+    ) -> Result<String>;
+}
+
+impl Engine for Handlebars<'_> {
+    fn render(
+        &self,
+        contents: &str,
+        variables: &Variables,
+        limits: &RenderLimits,
+    ) -> Result<String> {
+        render(self, contents, variables, limits)
+    }
+}
+
+/// Templates that use tera's syntax, which has more filters and loops than handlebars but
+/// none of dotter's helpers
+pub struct Tera;
+
+impl Engine for Tera {
+    /// Tera writes its output as it renders, like handlebars, so a loop that outputs too much
+    /// is stopped as soon as it does
+    fn render(
+        &self,
+        contents: &str,
+        variables: &Variables,
+        limits: &RenderLimits,
+    ) -> Result<String> {
+        const TEMPLATE_NAME: &str = "template";
+        let context =
+            tera::Context::from_serialize(variables).context("convert variables for tera")?;
+        let mut tera = tera::Tera::default();
+        tera.add_raw_template(TEMPLATE_NAME, contents)?;
+        let mut output = LimitedOutput::new(limits);
+        let start = Instant::now();
+        let result = with_deadline(output.deadline, || {
+            tera.render_to(TEMPLATE_NAME, &context, &mut output)
+        });
+        RENDER_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        if let Some(exceeded) = output.exceeded {
+            bail!(exceeded);
+        }
+        result?;
+        String::from_utf8(output.buffer).context("rendered template is not valid UTF-8")
+    }
+}
+
+/// Renders a template string, failing once the output or the time spent exceeds the limits
+pub fn render<T: Serialize>(
+    handlebars: &Handlebars,
+    contents: &str,
+    data: &T,
+    limits: &RenderLimits,
+) -> Result<String> {
+    let mut output = LimitedOutput::new(limits);
+    let start = Instant::now();
+    let deadline = output.deadline;
+    let result = match (parse_template(contents), HandlebarsContext::wraps(data)) {
+        (Ok(template), Ok(context)) => with_deadline(deadline, || {
+            template.render(
+                handlebars,
+                &context,
+                &mut RenderContext::new(None),
+                &mut output,
+            )
+        })
+        .map_err(TemplateRenderError::from),
+        (Err(e), _) => Err(TemplateRenderError::from(*e)),
+        (_, Err(e)) => Err(TemplateRenderError::from(e)),
+    };
+    RENDER_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    if let Some(exceeded) = output.exceeded {
+        bail!(exceeded);
+    }
+    result.map_err(|e| TemplateFailure::locate(e, contents))?;
+    String::from_utf8(output.buffer).context("rendered template is not valid UTF-8")
+}
+
+/// A template that failed to parse or render, with the lines around the location of the error
+#[derive(Debug)]
+pub struct TemplateFailure {
+    message: String,
+    /// Line numbers are relative to the template's source file, so lines that were prepended
+    /// to it are numbered 0 or less
+    line: isize,
+    column: usize,
+    context: Vec<(isize, String)>,
+}
+
+impl TemplateFailure {
+    /// Errors that handlebars can't place in the template are kept as they are
+    fn locate(error: TemplateRenderError, contents: &str) -> anyhow::Error {
+        let (message, line, column) = match &error {
+            TemplateRenderError::TemplateError(e) => (e.reason.to_string(), e.line_no, e.column_no),
+            TemplateRenderError::RenderError(e) => (describe(&e.desc), e.line_no, e.column_no),
+            TemplateRenderError::IOError(..) => (String::new(), None, None),
+        };
+        let (line, column) = match (line, column) {
+            (Some(line), Some(column)) => (line, column),
+            _ => return error.into(),
+        };
+
+        let first = line.saturating_sub(CONTEXT_LINES).max(1);
+        let context = contents
+            .lines()
+            .enumerate()
+            .skip(first - 1)
+            .take(line + CONTEXT_LINES + 1 - first)
+            .map(|(index, text)| ((index + 1) as isize, text.to_string()))
+            .collect();
+        TemplateFailure {
+            message,
+            line: line as isize,
+            column,
+            context,
+        }
+        .into()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn shifted(mut self, prepended: usize) -> TemplateFailure {
+        self.line -= prepended as isize;
+        for (number, _) in &mut self.context {
+            *number -= prepended as isize;
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for TemplateFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.line > 0 {
+            write!(
+                f,
+                "line {}, column {}: {}",
+                self.line, self.column, self.message
+            )?;
+        } else {
+            write!(f, "in the prepended text: {}", self.message)?;
+        }
+        let width = self
+            .context
+            .iter()
+            .map(|(number, _)| number.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (number, text) in &self.context {
+            let gutter = if *number > 0 {
+                number.to_string()
+            } else {
+                "+".into()
+            };
+            write!(f, "\n    {:>width$} | {}", gutter, text, width = width)?;
+            if *number == self.line {
+                write!(
+                    f,
+                    "\n    {:>width$} | {:>column$}",
+                    "",
+                    "^",
+                    width = width,
+                    column = self.column
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TemplateFailure {}
+
+/// Rewords the errors of handlebars that name a missing variable or helper
+fn describe(desc: &str) -> String {
+    if let Some(name) = desc
+        .strip_prefix("Variable ")
+        .and_then(|rest| rest.strip_suffix(" not found in strict mode."))
+    {
+        return format!("variable {} is not defined", name);
+    }
+    if let Some(name) = desc.strip_prefix("Helper not defined: ") {
+        let name = name
+            .strip_prefix("Name(")
+            .and_then(|n| n.strip_suffix(')'))
+            .unwrap_or(name);
+        return format!("helper {} is not defined", name);
+    }
+    desc.into()
+}
+
+struct LimitedOutput {
+    buffer: Vec<u8>,
+    limits: RenderLimits,
+    deadline: Instant,
+    exceeded: Option<String>,
+}
+
+impl LimitedOutput {
+    fn new(limits: &RenderLimits) -> LimitedOutput {
+        LimitedOutput {
+            buffer: Vec::new(),
+            limits: *limits,
+            deadline: Instant::now() + limits.timeout,
+            exceeded: None,
+        }
+    }
+}
+
+impl Write for LimitedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.buffer.len() + buf.len()) as u64 > self.limits.max_size {
+            self.exceeded = Some(format!(
+                "rendered output is larger than the limit of {} bytes",
+                self.limits.max_size
+            ));
+        } else if Instant::now() > self.deadline {
+            self.exceeded = Some(format!(
+                "rendering took longer than the limit of {} seconds",
+                self.limits.timeout.as_secs()
+            ));
+        }
+        if let Some(exceeded) = &self.exceeded {
+            return Err(io::Error::other(exceeded.clone()));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output for LimitedOutput {
+    fn write(&mut self, segment: &str) -> io::Result<()> {
+        self.write_all(segment.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_limits() {
+        let handlebars = Handlebars::new();
+        let mut variables = Variables::new();
+        variables.insert("name".into(), "world".into());
+        let limits = RenderLimits {
+            max_size: 16,
+            timeout: Duration::from_secs(30),
+        };
+
+        assert_eq!(
+            render(&handlebars, "hello {{name}}", &variables, &limits).unwrap(),
+            "hello world"
+        );
+        assert!(render(&handlebars, "hello {{name}} {{name}}", &variables, &limits).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_timeout_stops_helpers() {
+        let mut handlebars = Handlebars::new();
+        handlebars_helpers::register_rust_helpers(
+            &mut handlebars,
+            handlebars_helpers::render_time(false),
+        );
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: Duration::from_secs(1),
+        };
+        let start = Instant::now();
+        assert!(render(
+            &handlebars,
+            "{{command_output \"sleep 30\"}}",
+            &Variables::new(),
+            &limits
+        )
+        .is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(deadline(), None);
+    }
+
+    #[test]
+    fn test_tera() {
+        let mut variables = Variables::new();
+        variables.insert(
+            "fonts".into(),
+            toml::Value::Array(vec!["mono".into(), "sans".into()]),
+        );
+        let limits = RenderLimits {
+            max_size: 32,
+            timeout: Duration::from_secs(30),
+        };
+
+        assert_eq!(
+            Tera.render(
+                "{% for font in fonts %}{{ font | upper }} {% endfor %}",
+                &variables,
+                &limits
+            )
+            .unwrap(),
+            "MONO SANS "
+        );
+        assert!(Tera
+            .render(
+                "{{ fonts | join(sep=\", \") }}{{ missing }}",
+                &variables,
+                &limits
+            )
+            .is_err());
+        assert!(Tera
+            .render(
+                "{% for i in range(end=30) %}{{ i }}{% endfor %}",
+                &variables,
+                &limits
+            )
+            .is_err());
+        // A runaway loop stops at the limit instead of running to its end
+        let error = Tera
+            .render(
+                "{% for i in range(end=100000) %}{% for j in range(end=100000) %}x{% endfor %}{% endfor %}",
+                &variables,
+                &limits,
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("larger than the limit"));
+    }
+
+    #[test]
+    fn test_template_failure() {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        let limits = RenderLimits {
+            max_size: 1024,
+            timeout: Duration::from_secs(30),
+        };
+        let contents = "a\nb\nc\n  {{missing}}\nd\ne\nf\n";
+
+        let error = render(&handlebars, contents, &Variables::new(), &limits).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 4, column 3: variable \"missing\" is not defined\n    2 | b\n    3 | c\n    4 |   {{missing}}\n      |   ^\n    5 | d\n    6 | e"
+        );
+
+        let shifted = error.downcast::<TemplateFailure>().unwrap().shifted(3);
+        assert_eq!(shifted.line, 1);
+        assert_eq!(shifted.context[0].0, -1);
+    }
+}
@@ -0,0 +1,266 @@
+//! Failures that are shown with the lines of the file they're about, a label that points at
+//! the problem, and a hint on how to fix it
+
+use crossterm::style::Colorize;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use render::TemplateFailure;
+
+/// Lines of the file that are shown before and after the one that the report points at
+const CONTEXT_LINES: usize = 2;
+
+#[derive(Debug)]
+pub struct Report {
+    message: String,
+    snippet: Option<Box<Snippet>>,
+    help: Option<String>,
+}
+
+/// The lines around a location in a file, the location is 1-based
+#[derive(Debug)]
+struct Snippet {
+    path: PathBuf,
+    line: usize,
+    column: usize,
+    label: String,
+    lines: Vec<(usize, String)>,
+}
+
+impl Report {
+    pub fn new(message: impl Into<String>) -> Report {
+        Report {
+            message: message.into(),
+            snippet: None,
+            help: None,
+        }
+    }
+
+    /// Points at `line` and `column` of `contents`, which were read from `path`
+    pub fn at(
+        mut self,
+        path: &Path,
+        contents: &str,
+        line: usize,
+        column: usize,
+        label: impl Into<String>,
+    ) -> Report {
+        let first = line.saturating_sub(CONTEXT_LINES).max(1);
+        let lines = contents
+            .lines()
+            .enumerate()
+            .skip(first - 1)
+            .take(line + CONTEXT_LINES + 1 - first)
+            .map(|(index, text)| (index + 1, text.to_string()))
+            .collect();
+        self.snippet = Some(Box::new(Snippet {
+            path: path.into(),
+            line,
+            column,
+            label: label.into(),
+            lines,
+        }));
+        self
+    }
+
+    pub fn help(mut self, help: impl Into<String>) -> Report {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Reports a configuration file that isn't valid TOML or doesn't fit its schema
+    pub fn from_toml(error: &toml::de::Error, path: &Path, contents: &str) -> Report {
+        let message = error.to_string();
+        // Only syntax errors know their location, the others are found by their key
+        let location = error
+            .line_col()
+            .map(|(line, column)| (line + 1, column + 1))
+            .or_else(|| locate_key(contents, &message));
+        let report = match location {
+            Some((line, column)) => {
+                // The message is repeated above, the label only names the problem
+                let label = message
+                    .split(&[',', '`'][..])
+                    .next()
+                    .unwrap_or(&message)
+                    .split(" at line ")
+                    .next()
+                    .unwrap_or(&message)
+                    .trim()
+                    .to_string();
+                Report::new(message.clone()).at(path, contents, line, column, label)
+            }
+            None => Report::new(message.clone()),
+        };
+        match toml_help(&message) {
+            Some(help) => report.help(help),
+            None => report,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Report {}
+
+/// The line and column of the key that a deserialization error names, like
+/// "unknown field `tpye`" or "missing field `target` for key `zsh.files.zshrc`"
+fn locate_key(contents: &str, message: &str) -> Option<(usize, usize)> {
+    let quoted = |prefix: &str| -> Option<String> {
+        let rest = &message[message.find(prefix)? + prefix.len()..];
+        Some(rest[..rest.find('`')?].to_string())
+    };
+    let key = quoted("unknown field `")
+        .or_else(|| quoted("for key `")?.rsplit('.').next().map(String::from))?;
+    contents.lines().enumerate().find_map(|(index, line)| {
+        let indent = line.len() - line.trim_start().len();
+        let rest = line.trim_start();
+        let rest = rest
+            .strip_prefix(key.as_str())
+            .or_else(|| rest.strip_prefix(&format!("\"{}\"", key)))
+            .or_else(|| {
+                // A table header that ends with the key, like `[zsh.files]`
+                let header = rest.strip_prefix('[')?.trim_start_matches('[');
+                let end = header.find(']')?;
+                header[..end]
+                    .rsplit('.')
+                    .next()
+                    .filter(|last| last.trim().trim_matches('"') == key)
+                    .map(|_| "=")
+            })?;
+        if rest.trim_start().starts_with('=') {
+            Some((index + 1, indent + 1))
+        } else {
+            None
+        }
+    })
+}
+
+fn toml_help(message: &str) -> Option<&'static str> {
+    Some(if message.starts_with("unknown field") {
+        "remove the key, or check its spelling against the fields that are expected here"
+    } else if message.starts_with("missing field") {
+        "add the field, it has no default"
+    } else if message.starts_with("unknown variant") {
+        "use one of the values that are expected here"
+    } else if message.starts_with("invalid type") {
+        "check the type of the value, strings need quotes and tables need braces or a header"
+    } else if message.starts_with("duplicate key") || message.starts_with("redefinition of table") {
+        "merge the two definitions into one"
+    } else if message.contains("expected an equals") || message.contains("unquoted string") {
+        "quote strings, like `key = \"value\"`"
+    } else {
+        return None;
+    })
+}
+
+/// A hint for a template that failed to render, by its message
+pub fn template_help(message: &str) -> Option<&'static str> {
+    Some(
+        if message.starts_with("variable ") && message.ends_with(" is not defined") {
+            "define the variable in a package, in local.toml or with --patch, or guard it with `{{#if}}`"
+        } else if message.starts_with("helper ") && message.ends_with(" is not defined") {
+            "register the helper in the `[helpers]` of global.toml, or check its spelling"
+        } else if message.contains("not closed") || message.contains("mismatch") {
+            "every block like `{{#if}}` needs a matching close like `{{/if}}`"
+        } else {
+            return None;
+        },
+    )
+}
+
+/// Writes the chain of causes like `display_error` always did, then the snippet and the help of
+/// the first report in the chain
+pub fn render(error: &anyhow::Error) -> String {
+    let mut chain = error.chain();
+    let mut output = format!("Failed to {}\nCaused by:", chain.next().unwrap());
+    for cause in chain {
+        output.push_str(&format!("\n    {}", cause));
+    }
+
+    for cause in error.chain() {
+        if let Some(report) = cause.downcast_ref::<Report>() {
+            if let Some(snippet) = &report.snippet {
+                output.push('\n');
+                output.push_str(&snippet.render());
+            }
+            if let Some(help) = &report.help {
+                output.push_str(&format!("\n  {} {}", "help:".cyan(), help));
+            }
+            break;
+        }
+        if let Some(failure) = cause.downcast_ref::<TemplateFailure>() {
+            if let Some(help) = template_help(failure.message()) {
+                output.push_str(&format!("\n  {} {}", "help:".cyan(), help));
+            }
+            break;
+        }
+    }
+    output
+}
+
+impl Snippet {
+    fn render(&self) -> String {
+        let width = self
+            .lines
+            .iter()
+            .map(|(number, _)| number.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let mut output = format!(
+            "{:>width$}{} {}:{}:{}",
+            "",
+            "-->".blue(),
+            self.path.display(),
+            self.line,
+            self.column,
+            width = width
+        );
+        for (number, text) in &self.lines {
+            let gutter = format!("{:>width$} |", number, width = width);
+            output.push_str(&format!("\n{} {}", gutter.as_str().blue(), text));
+            if *number == self.line {
+                let gutter = format!("{:>width$} |", "", width = width);
+                let caret = format!("{:>column$} {}", "^", self.label, column = self.column);
+                output.push_str(&format!(
+                    "\n{} {}",
+                    gutter.as_str().blue(),
+                    caret.as_str().red()
+                ));
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_locate_key() {
+        let contents = "[zsh.files]\nzshrc = { target = \"~/.zshrc\", tpye = \"symbolic\" }\n\n[vim]\n  \"colors\" = 1\n[vim.variables]\n";
+        assert_eq!(
+            locate_key(contents, "unknown field `colors`, expected `files`"),
+            Some((5, 3))
+        );
+        assert_eq!(
+            locate_key(
+                contents,
+                "invalid type: map, expected a string for key `vim.variables`"
+            ),
+            Some((6, 1))
+        );
+        assert_eq!(
+            locate_key(contents, "missing field `target` for key `zsh.files.zshrc`"),
+            Some((2, 1))
+        );
+        assert_eq!(locate_key(contents, "unknown field `nope`"), None);
+    }
+}
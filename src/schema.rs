@@ -0,0 +1,491 @@
+//! JSON Schemas of global.toml and local.toml for `dotter schema`, for editors to complete and
+//! check configuration files with. Taplo (and the Even Better TOML extension that uses it) reads
+//! them with a `#:schema <path>` comment at the top of the file.
+//!
+//! The schemas are written by hand, since file targets have their own `Deserialize`. They have
+//! to be updated along with the fields of `config.rs`, which `test_schema_fields` there checks.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use args::SchemaFile;
+
+const SCHEMA_VERSION: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Prints the schema of `file`
+pub fn print_schema(file: SchemaFile) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&schema(file)).context("serialize schema")?
+    );
+    Ok(())
+}
+
+pub fn schema(file: SchemaFile) -> Value {
+    match file {
+        SchemaFile::Global => global_schema(),
+        SchemaFile::Local => local_schema(),
+    }
+}
+
+fn global_schema() -> Value {
+    let mut properties = shared_properties();
+    let extra = json!({
+        "helpers": {
+            "description": "Helpers of handlebars templates, by helper name: the path, relative to the repository, of a Rhai script, or with `allow_program_helpers` `{ program = \"<path>\" }` of a program that's run with the helper's parameters as arguments",
+            "type": "object",
+            "additionalProperties": {
+                "oneOf": [
+                    { "type": "string" },
+                    {
+                        "type": "object",
+                        "properties": { "program": { "type": "string" } },
+                        "required": ["program"],
+                        "additionalProperties": false
+                    }
+                ]
+            }
+        },
+        "partials": {
+            "description": "Directory of the partials that templates include with `{{> name}}`",
+            "type": "string"
+        },
+        "isolate_variables": {
+            "description": "Keeps the variables of each package to its own templates, except the ones it lists in `exports`",
+            "type": "boolean"
+        },
+        "duplicate_targets": {
+            "description": "What happens when two packages deploy the same source or target",
+            "enum": ["later-wins", "error"]
+        },
+        "hash_algorithm": {
+            "description": "Algorithm of the hashes that are recorded to notice changes to targets",
+            "enum": ["blake3", "sha256"]
+        },
+        "scrub": {
+            "description": "Secrets that `adopt` replaces with variables, whose values are saved in local.toml",
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "pattern": {
+                        "description": "Regular expression of the secret, whose first capture group is replaced if it has one",
+                        "type": "string"
+                    },
+                    "variable": {
+                        "description": "Variable that the secret is replaced with",
+                        "type": "string"
+                    }
+                },
+                "required": ["pattern", "variable"],
+                "additionalProperties": false
+            }
+        },
+        "profiles": {
+            "description": "Named sets of packages and variables, selected with `--profile` or the `profile` of local.toml",
+            "type": "object",
+            "additionalProperties": {
+                "type": "object",
+                "properties": {
+                    "packages": string_list("Enabled in addition to the packages of local.toml"),
+                    "variables": variables()
+                },
+                "additionalProperties": false
+            }
+        }
+    });
+    extend(&mut properties, extra);
+    json!({
+        "$schema": SCHEMA_VERSION,
+        "title": "dotter global.toml",
+        "description": "Packages of the repository, and the settings that every machine shares",
+        "type": "object",
+        "properties": properties,
+        // Every other table is a package
+        "additionalProperties": package()
+    })
+}
+
+fn local_schema() -> Value {
+    let mut properties = shared_properties();
+    let extra = json!({
+        "includes": string_list("Files whose packages are added to the ones of global.toml"),
+        "packages": string_list("Packages of global.toml that are deployed on this machine"),
+        "profile": {
+            "description": "Profile of global.toml that's used when `--profile` isn't given",
+            "type": "string"
+        },
+        "files": files(),
+        "variables": variables(),
+        "lines": lines(),
+        "special": special(),
+        "merge_tool": {
+            "description": "Command that merges a template's edited target with its new render",
+            "type": "string"
+        }
+    });
+    extend(&mut properties, extra);
+    json!({
+        "$schema": SCHEMA_VERSION,
+        "title": "dotter local.toml",
+        "description": "The packages, files and variables of this machine",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false
+    })
+}
+
+/// Settings that both files have, where local.toml's replace global.toml's
+fn shared_properties() -> Value {
+    json!({
+        "variables_url": {
+            "description": "Template of a URL whose variables are merged on top of the packages' variables",
+            "type": "string"
+        },
+        "ansible_inventory": {
+            "description": "Ansible inventory directory whose `host_vars` for this machine are merged on top of the packages' variables",
+            "type": "string"
+        },
+        "variable_providers": string_list("Commands whose variables are merged on top of the packages' variables"),
+        "allow_fetch": {
+            "description": "Allows the `fetch_url` helper, which downloads resources while rendering",
+            "type": "boolean"
+        },
+        "allow_program_helpers": {
+            "description": "Allows the helpers declared as `{ program = \"<path>\" }`, which are run as programs with the helper's parameters as arguments",
+            "type": "boolean"
+        },
+        "network_probes": string_list("Addresses like `1.1.1.1:443` that the machine is online if it can connect to any of, for packages with `requires_network`"),
+        "auto_commit": {
+            "description": "Commits the edits of commands like `adopt` and `new-package` to the repository",
+            "type": "boolean"
+        },
+        "secrets": {
+            "description": "Where the `secret` helper gets secrets from",
+            "type": "object",
+            "properties": {
+                "provider": { "enum": ["pass", "gpg", "age"] },
+                "directory": {
+                    "description": "Directory of the files that `gpg` and `age` decrypt",
+                    "type": "string"
+                },
+                "identity": {
+                    "description": "Identity file that `age` decrypts with",
+                    "type": "string"
+                },
+                "cache_ttl": {
+                    "description": "Seconds that a revealed secret is kept for, encrypted in the cache directory, before it's revealed again. Older copies stand in for secrets whose provider fails.",
+                    "type": "integer",
+                    "minimum": 0
+                }
+            },
+            "additionalProperties": false
+        },
+        "env": environment(),
+        "foreign": {
+            "description": "Paths that belong to other dotfile managers, by the name of the manager",
+            "type": "object",
+            "additionalProperties": { "type": "array", "items": { "type": "string" } }
+        },
+        "merge": merge(),
+        "lint": {
+            "description": "Settings of `dotter lint`",
+            "type": "object",
+            "properties": {
+                "allow": string_list("Findings that are expected, like `unused-package` or `unused-package:work`")
+            },
+            "additionalProperties": false
+        },
+        "warnings": {
+            "description": "Warnings that aren't printed",
+            "type": "object",
+            "properties": {
+                "allow": string_list("Warnings that are expected, like `shadowed` or `unused-variable:font_size`")
+            },
+            "additionalProperties": false
+        },
+        "allowed_target_roots": string_list("Directories that every target must be inside of, like `[\"~\", \"/etc/foo\"]`")
+    })
+}
+
+fn package() -> Value {
+    json!({
+        "description": "A package: files that are deployed together, and their variables",
+        "type": "object",
+        "properties": {
+            "files": files(),
+            "files_from": {
+                "description": "Directory of TOML files whose files are added to `files`",
+                "type": "string"
+            },
+            "ignore": string_list("Patterns of the paths that the globs of `files` leave out, like `*.swp`"),
+            "variables": variables(),
+            "merge": merge(),
+            "exports": string_list("Variables that the templates of every package see, when global.toml isolates the variables of packages"),
+            "lines": lines(),
+            "special": special(),
+            "env": {
+                "description": "Environment variables of the hooks of the package and its files, on top of `[env]`",
+                "type": "object",
+                "additionalProperties": { "type": "string" }
+            },
+            "auto_enable": {
+                "description": "Enables the package on machines where a condition holds",
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "description": "Name of an executable that must be in `$PATH`",
+                        "type": "string"
+                    }
+                },
+                "required": ["command"],
+                "additionalProperties": false
+            },
+            "condition": {
+                "description": "Enables the package on machines where the condition holds",
+                "type": "string"
+            },
+            "when": {
+                "description": "Variables that are merged on top of the package's variables where their condition holds",
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "condition": { "type": "string" },
+                        "variables": variables()
+                    },
+                    "required": ["condition"],
+                    "additionalProperties": false
+                }
+            },
+            "notes": {
+                "description": "Manual follow-up steps, printed after the package is deployed for the first time",
+                "type": "string"
+            },
+            "on_deploy": {
+                "description": "Hook that runs after a deploy that created or updated any file of the package",
+                "type": "string"
+            },
+            "on_undeploy": {
+                "description": "Hook that runs after a deploy or undeploy that deleted any file of the package",
+                "type": "string"
+            },
+            "cwd": {
+                "description": "Directory that the hooks of the package and its files run in, relative to the repository",
+                "type": "string"
+            },
+            "path_prepend": string_list("Directories that are put in front of the `PATH` of the package's hooks"),
+            "requires_network": {
+                "description": "Skips the hooks of the package and its files when the machine is offline",
+                "type": "boolean"
+            },
+            "template_engine": template_engine(),
+            "depends": string_list("Packages that are enabled along with this one, and deployed before it"),
+            "lazy": {
+                "description": "Only deploys shims of the package's executables, which deploy the rest of the package when one of them is run for the first time",
+                "type": "boolean"
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn files() -> Value {
+    json!({
+        "description": "Targets by source. A string is deployed as a template if the source contains `{{`, and as a symlink otherwise.",
+        "type": "object",
+        "additionalProperties": {
+            "anyOf": [
+                { "description": "Target of the file", "type": "string" },
+                file_target()
+            ]
+        }
+    })
+}
+
+fn file_target() -> Value {
+    let user = json!({ "type": ["integer", "string"] });
+    let string = |description: &str| json!({ "description": description, "type": "string" });
+    let boolean = |description: &str| json!({ "description": description, "type": "boolean" });
+    json!({
+        "type": "object",
+        "properties": {
+            "type": {
+                "description": "How the file is deployed. Optional for encrypted files.",
+                "enum": ["symbolic", "template", "copy", "fragment"]
+            },
+            "target": string("Where the file is deployed"),
+            "literal_target": boolean("The target is used as it is, without expanding `~` or variables"),
+            "owner": user,
+            "group": user,
+            "mode": string("Permissions of the target as an octal number, like \"644\". Symlinks only have permissions of their own on macOS and the BSDs."),
+            "directory_owner": user,
+            "directory_mode": string("Permissions of the parent directories that have to be created"),
+            "attributes": {
+                "description": "Windows file attributes of the target",
+                "type": "array",
+                "items": { "enum": ["hidden", "readonly", "system"] }
+            },
+            "acl": string_list("Permissions that are granted on the target with `icacls /grant`, like `Users:(R)`"),
+            "append": string("Appended to the template's source before it's rendered"),
+            "prepend": string("Prepended to the template's source before it's rendered"),
+            "volatile": boolean("The target is expected to disappear on reboot"),
+            "write_once": boolean("Only deployed by the first deploy, and left unmanaged after that"),
+            "critical": boolean("Deployed before the other files, and a failure aborts the deploy"),
+            "allow_overwrite": boolean("Overwrites the target when it was changed since it was deployed"),
+            "elevate": boolean("Changes that the user isn't allowed to make are retried with `sudo`"),
+            "reload": string("Program that is told to reload its configuration when the target changes"),
+            "service_unit": boolean("The target is a systemd user unit or a launchd agent"),
+            "on_deploy": string("Hook that runs after a deploy that created or updated the target"),
+            "on_undeploy": string("Hook that runs after the target was deleted"),
+            "equivalence": {
+                "description": "Differences between the target and the render that don't count as changes",
+                "type": "object",
+                "properties": {
+                    "trailing_whitespace": { "type": "boolean" },
+                    "ignore_lines": { "type": "string" },
+                    "json": { "type": "boolean" }
+                },
+                "additionalProperties": false
+            },
+            "diff": {
+                "description": "Whether diffs of the target are printed, or the most lines that a printed diff changes",
+                "type": ["boolean", "integer"]
+            },
+            "after": string_list("Targets that have to be deployed before this one"),
+            "order": {
+                "description": "Fragments of the same target are concatenated in ascending order",
+                "type": "integer"
+            },
+            "template": boolean("Whether the fragment is rendered, or copied as it is"),
+            "template_engine": template_engine(),
+            "link_type": { "enum": ["recursive", "junction", "hardlink"] },
+            "render_cache": string("Directory that keeps the rendered copy of the template"),
+            "validate": string("Command that checks the render before it's written, like `sshd -t -f {}`"),
+            "flags": string_list("File flags of the symlink itself, like `uchg`, on macOS and the BSDs"),
+            "literal_braces": boolean("The source's `{{` isn't meant as template syntax"),
+            "encrypted": boolean("The source is encrypted with age or gpg, and is deployed as it decrypts"),
+            "verify": verify()
+        },
+        "required": ["target"],
+        "additionalProperties": false
+    })
+}
+
+fn variables() -> Value {
+    json!({
+        "description": "Variables of the templates",
+        "type": "object"
+    })
+}
+
+fn lines() -> Value {
+    json!({
+        "description": "Lines that every target has to contain, by target, without managing the rest of it",
+        "type": "object",
+        "additionalProperties": { "type": "array", "items": { "type": "string" } }
+    })
+}
+
+fn special() -> Value {
+    json!({
+        "description": "Files that dotter creates without a source, like named pipes, by target",
+        "type": "object",
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "type": {
+                    "description": "`fifo` for a named pipe, `empty` for an empty placeholder file",
+                    "enum": ["fifo", "empty"]
+                },
+                "mode": {
+                    "description": "Permissions as an octal string, \"600\" by default",
+                    "type": "string"
+                }
+            },
+            "required": ["type"],
+            "additionalProperties": false
+        }
+    })
+}
+
+fn environment() -> Value {
+    json!({
+        "description": "Environment variables of every hook and command",
+        "type": "object",
+        "additionalProperties": { "type": "string" }
+    })
+}
+
+fn merge() -> Value {
+    json!({
+        "description": "How variables are combined with the ones of the same name from earlier files, by variable",
+        "type": "object",
+        "additionalProperties": { "enum": ["replace", "deep-merge", "append-list"] }
+    })
+}
+
+fn verify() -> Value {
+    let string = |description: &str| json!({ "description": description, "type": "string" });
+    json!({
+        "description": "Checks of the target right after it's created or updated. A string is a command, like `test -x {}`.",
+        "type": ["string", "object"],
+        "properties": {
+            "command": string("Command that has to succeed, with `{}` replaced by the target"),
+            "hash": string("Hash that the target's contents must have, like \"sha256:<hex>\""),
+            "mode": string("Permissions that the target must have, like \"755\""),
+            "on_failure": {
+                "description": "Whether a failure is only reported, aborts the deploy, or puts the target back the way it was",
+                "enum": ["report", "abort", "rollback"]
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn template_engine() -> Value {
+    json!({
+        "description": "The language that templates are written in",
+        "enum": ["handlebars", "tera"]
+    })
+}
+
+fn string_list(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "array",
+        "items": { "type": "string" }
+    })
+}
+
+fn extend(properties: &mut Value, extra: Value) {
+    if let (Value::Object(properties), Value::Object(extra)) = (properties, extra) {
+        properties.extend(extra);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schemas() {
+        let schema = file_target();
+        let properties = schema["properties"].as_object().unwrap();
+        for field in [
+            "type",
+            "target",
+            "owner",
+            "mode",
+            "attributes",
+            "validate",
+            "literal_braces",
+            "encrypted",
+            "verify",
+        ] {
+            assert!(properties.contains_key(field), "{} is missing", field);
+        }
+        assert_eq!(global_schema()["properties"]["partials"]["type"], "string");
+        assert_eq!(local_schema()["additionalProperties"], false);
+    }
+}
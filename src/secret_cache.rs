@@ -0,0 +1,258 @@
+//! Copies of revealed secrets that outlive a run, for `cache_ttl` of the [secrets] table: a
+//! secret that was revealed less than `cache_ttl` seconds ago isn't revealed again, and an older
+//! copy stands in for it when its provider fails, like when the password store is on a machine
+//! that can't be reached. The copies are encrypted with a key that never leaves this machine, so
+//! the cache directory can be synced or backed up like the rest of the repository.
+
+use anyhow::{Context, Result};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use config::SecretProvider;
+use filesystem;
+
+/// Directory inside the cache where the encrypted copies are kept, a file per secret
+const SECRET_DIRECTORY: &str = ".dotter_secrets";
+
+const ENCRYPTION_CONTEXT: &str = "dotter secret cache encryption";
+const AUTHENTICATION_CONTEXT: &str = "dotter secret cache authentication";
+
+struct Settings {
+    cache_directory: PathBuf,
+    /// From --refresh-secrets, which reveals every secret again
+    refresh: bool,
+}
+
+static SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+pub fn set_cache_directory(cache_directory: &Path, refresh: bool) {
+    *SETTINGS.lock().unwrap_or_else(|e| e.into_inner()) = Some(Settings {
+        cache_directory: cache_directory.into(),
+        refresh,
+    });
+}
+
+/// A copy of a secret, as it's written to the cache directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    /// When the secret was revealed, in seconds since the Unix epoch
+    revealed: u64,
+    nonce: String,
+    ciphertext: String,
+    /// Authenticates the other fields, and the provider and name of the secret
+    tag: String,
+}
+
+/// A copy of a secret that was read back
+pub struct Cached {
+    pub secret: String,
+    /// How long ago it was revealed
+    pub age: u64,
+}
+
+/// The copy of the secret `name` of `provider`, if there's one and --refresh-secrets wasn't
+/// given. Copies that can't be read are ignored, so that the secret is revealed again.
+pub fn load(provider: SecretProvider, name: &str) -> Option<Cached> {
+    let path = entry_path(provider, name).filter(|_| !refreshing())?;
+    let entry = match fs::read_to_string(&path) {
+        Ok(entry) => entry,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read the cached copy of secret {:?}: {}", name, e);
+            return None;
+        }
+    };
+    let secret = toml::from_str(&entry)
+        .context("parse cached copy")
+        .and_then(|entry| open(&machine_key()?, provider, name, &entry));
+    match secret {
+        Ok(secret) => Some(secret),
+        Err(e) => {
+            warn!("Ignoring the cached copy of secret {:?}: {:#}", name, e);
+            None
+        }
+    }
+}
+
+/// Keeps a copy of the secret `name` of `provider`, which was just revealed
+pub fn store(provider: SecretProvider, name: &str, secret: &str) -> Result<()> {
+    let path = match entry_path(provider, name) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let entry = seal(&machine_key()?, provider, name, secret, now())?;
+    fs::create_dir_all(path.parent().context("get parent of cached copy")?)
+        .context("create directory of cached copies")?;
+    let entry = toml::to_string(&entry).context("serialize cached copy")?;
+    filesystem::write_private(&path, entry.as_bytes()).context("write cached copy")
+}
+
+fn refreshing() -> bool {
+    SETTINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|settings| settings.refresh)
+}
+
+/// Each secret gets its own file, named by a hash so that the names of secrets don't show
+fn entry_path(provider: SecretProvider, name: &str) -> Option<PathBuf> {
+    let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let file = blake3::hash(format!("{}\0{}", provider.command(), name).as_bytes()).to_hex();
+    Some(
+        settings
+            .as_ref()?
+            .cache_directory
+            .join(SECRET_DIRECTORY)
+            .join(format!("{}.toml", file)),
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// The key of this machine, which is created the first time that a secret is cached. It lives
+/// in the user's data directory, outside of the repository and its cache directory.
+fn machine_key() -> Result<[u8; 32]> {
+    let path = filesystem::xdg_data_home()
+        .join("dotter")
+        .join("secret_cache.key");
+    match fs::read(&path) {
+        Ok(key) => {
+            return <[u8; 32]>::try_from(key)
+                .map_err(|_| anyhow!("{:?} isn't a key of the secret cache", path))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("read key of secret cache {:?}", path)),
+    }
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key)
+        .map_err(|e| anyhow!("{}", e))
+        .context("generate key of secret cache")?;
+    fs::create_dir_all(path.parent().context("get parent of key")?)
+        .context("create directory of key")?;
+    filesystem::write_private(&path, &key)
+        .with_context(|| format!("write key of secret cache {:?}", path))?;
+    Ok(key)
+}
+
+/// Encrypts the secret with a keystream of blake3 in keyed mode, and authenticates it along with
+/// what it's the secret of, so that a copy can't stand in for a different secret
+fn seal(
+    key: &[u8; 32],
+    provider: SecretProvider,
+    name: &str,
+    secret: &str,
+    revealed: u64,
+) -> Result<Entry> {
+    let mut nonce = [0; 16];
+    getrandom::getrandom(&mut nonce)
+        .map_err(|e| anyhow!("{}", e))
+        .context("generate nonce")?;
+    let mut ciphertext = secret.as_bytes().to_vec();
+    apply_keystream(key, &nonce, &mut ciphertext);
+    let tag = tag(key, provider, name, revealed, &nonce, &ciphertext);
+    Ok(Entry {
+        revealed,
+        nonce: to_hex(&nonce),
+        ciphertext: to_hex(&ciphertext),
+        tag: tag.to_hex().to_string(),
+    })
+}
+
+fn open(key: &[u8; 32], provider: SecretProvider, name: &str, entry: &Entry) -> Result<Cached> {
+    let nonce = from_hex(&entry.nonce).context("decode nonce")?;
+    let mut secret = from_hex(&entry.ciphertext).context("decode ciphertext")?;
+    let expected = blake3::Hash::from_hex(&entry.tag).context("decode tag")?;
+    // Hashes compare in constant time
+    if tag(key, provider, name, entry.revealed, &nonce, &secret) != expected {
+        bail!("it was written with a different key, or it was tampered with");
+    }
+    apply_keystream(key, &nonce, &mut secret);
+    Ok(Cached {
+        secret: String::from_utf8(secret).context("secret isn't valid UTF-8")?,
+        age: now().saturating_sub(entry.revealed),
+    })
+}
+
+fn apply_keystream(key: &[u8; 32], nonce: &[u8], data: &mut [u8]) {
+    let mut keystream = vec![0; data.len()];
+    blake3::Hasher::new_keyed(&blake3::derive_key(ENCRYPTION_CONTEXT, key))
+        .update(nonce)
+        .finalize_xof()
+        .fill(&mut keystream);
+    for (byte, key) in data.iter_mut().zip(keystream) {
+        *byte ^= key;
+    }
+}
+
+fn tag(
+    key: &[u8; 32],
+    provider: SecretProvider,
+    name: &str,
+    revealed: u64,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> blake3::Hash {
+    blake3::Hasher::new_keyed(&blake3::derive_key(AUTHENTICATION_CONTEXT, key))
+        .update(provider.command().as_bytes())
+        .update(b"\0")
+        .update(name.as_bytes())
+        .update(b"\0")
+        .update(&revealed.to_le_bytes())
+        .update(nonce)
+        .update(ciphertext)
+        .finalize()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .context("invalid hex digit")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = [7; 32];
+        let entry = seal(&key, SecretProvider::Pass, "mail/work", "hunter2", 100).unwrap();
+        assert!(!entry.ciphertext.contains(&to_hex(b"hunter2")));
+        let cached = open(&key, SecretProvider::Pass, "mail/work", &entry).unwrap();
+        assert_eq!(cached.secret, "hunter2");
+
+        // The copy of one secret doesn't stand in for another
+        assert!(open(&key, SecretProvider::Pass, "mail/home", &entry).is_err());
+        assert!(open(&key, SecretProvider::Gpg, "mail/work", &entry).is_err());
+        assert!(open(&[8; 32], SecretProvider::Pass, "mail/work", &entry).is_err());
+        let older = Entry {
+            revealed: 0,
+            ..entry.clone()
+        };
+        assert!(open(&key, SecretProvider::Pass, "mail/work", &older).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+
+use config::{SecretProvider, SecretsConfig};
+use filesystem;
+use non_interactive::{self, WouldPrompt};
+use secret_cache;
+
+/// Cached renders that contain a secret are replaced by this, followed by the render's hash,
+/// so that secrets are never written to the cache directory
+pub const HASH_PREFIX: &str = "dotter-secret-hash:";
+
+/// Secrets that were revealed during this run by provider and name, so that each one is only
+/// decrypted (and its passphrase asked for) once. `dotter watch` keeps them until it's restarted.
+static REVEALED: Mutex<BTreeMap<(SecretProvider, String), String>> = Mutex::new(BTreeMap::new());
+
+fn revealed() -> MutexGuard<'static, BTreeMap<(SecretProvider, String), String>> {
+    REVEALED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Identity that `age` decrypts the encrypted files of the repository with, from the
+/// [secrets] table of the loaded configuration
+static IDENTITY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn set_config(config: &SecretsConfig) {
+    *IDENTITY.lock().unwrap_or_else(|e| e.into_inner()) = config.identity.clone();
+}
+
+/// Decrypts the secret called `name` with `provider`, or with the configured provider.
+/// `pass` secrets are the first line of the entry, like `pass -c` copies.
+pub fn reveal(
+    config: &SecretsConfig,
+    provider: Option<SecretProvider>,
+    name: &str,
+) -> Result<String> {
+    let provider = provider.or(config.provider).context(
+        "no secret provider is configured. Set `provider` in the [secrets] table of global.toml.",
+    )?;
+    let key = (provider, name.to_string());
+    // Held until the secret is revealed, so that templates that are rendered in parallel ask
+    // for one passphrase at a time, and only once
+    let mut revealed = revealed();
+    if let Some(secret) = revealed.get(&key) {
+        return Ok(secret.clone());
+    }
+    if Path::new(name)
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        bail!(
+            "secret names are relative paths without `..`, not {:?}",
+            name
+        );
+    }
+
+    let cache_ttl = config.cache_ttl;
+    let cached = cache_ttl.and_then(|_| secret_cache::load(provider, name));
+    if let (Some(ttl), Some(cached)) = (cache_ttl, &cached) {
+        if cached.age < ttl {
+            debug!("Using the cached copy of secret {:?}", name);
+            revealed.insert(key, cached.secret.clone());
+            return Ok(cached.secret.clone());
+        }
+    }
+
+    let secret = match run_provider_of(config, provider, name) {
+        Ok(secret) => secret,
+        Err(e) => match cached {
+            Some(cached) => {
+                warn!(
+                    "Failed to reveal secret {:?}, using its copy from {} seconds ago: {:#}",
+                    name, cached.age, e
+                );
+                cached.secret
+            }
+            None => return Err(e),
+        },
+    };
+    if cache_ttl.is_some() {
+        if let Err(e) = secret_cache::store(provider, name, &secret) {
+            warn!("Failed to cache secret {:?}: {:#}", name, e);
+        }
+    }
+
+    revealed.insert(key, secret.clone());
+    Ok(secret)
+}
+
+/// Reveals `name` with the command of `provider`
+fn run_provider_of(config: &SecretsConfig, provider: SecretProvider, name: &str) -> Result<String> {
+    let command = match provider {
+        SecretProvider::Pass => {
+            let mut command = Command::new("pass");
+            command.arg("show").arg(name);
+            command
+        }
+        SecretProvider::Gpg => {
+            let mut command = gpg_command();
+            command.arg(encrypted_file(config, name, "gpg")?);
+            command
+        }
+        SecretProvider::Age => {
+            let mut command = age_command(config.identity.as_deref());
+            command.arg(encrypted_file(config, name, "age")?);
+            command
+        }
+    };
+    let secret = run_provider(provider, command, name)?;
+    Ok(match provider {
+        SecretProvider::Pass => secret.lines().next().unwrap_or_default().to_string(),
+        _ => secret.trim_end_matches(['\r', '\n']).to_string(),
+    })
+}
+
+/// Decrypts a file of the repository that is deployed with `encrypted = true`: `.age` files
+/// with age, and the others with gpg. The plaintext counts as a revealed secret, so that it's
+/// only decrypted once and the cache only keeps its hash.
+pub fn decrypt_file(path: &Path) -> Result<String> {
+    let provider = match path.extension() {
+        Some(extension) if extension == "age" => SecretProvider::Age,
+        _ => SecretProvider::Gpg,
+    };
+    let name = path.to_string_lossy().into_owned();
+    // Keyed by the contents too, so that `dotter watch` decrypts the file again when it changes
+    let encrypted = fs::read(path).context("read encrypted file")?;
+    let key = (
+        provider,
+        format!("{}@{}", name, blake3::hash(&encrypted).to_hex()),
+    );
+    let mut revealed = revealed();
+    if let Some(plaintext) = revealed.get(&key) {
+        return Ok(plaintext.clone());
+    }
+
+    let mut command = match provider {
+        SecretProvider::Age => {
+            let identity = IDENTITY.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            age_command(identity.as_deref())
+        }
+        _ => gpg_command(),
+    };
+    command.arg(path);
+    let plaintext = run_provider(provider, command, &name)?;
+    revealed.insert(key, plaintext.clone());
+    Ok(plaintext)
+}
+
+/// Under --non-interactive, gpg fails instead of starting pinentry, unless the agent has the
+/// passphrase already
+const GPG_BATCH_OPTIONS: &[&str] = &["--batch", "--pinentry-mode", "error"];
+
+fn gpg_command() -> Command {
+    let mut command = Command::new("gpg");
+    if non_interactive::enabled() {
+        command.args(GPG_BATCH_OPTIONS);
+    }
+    command.args(["--quiet", "--decrypt"]);
+    command
+}
+
+fn age_command(identity: Option<&Path>) -> Command {
+    let mut command = Command::new("age");
+    command.arg("--decrypt");
+    if let Some(identity) = identity {
+        command
+            .arg("--identity")
+            .arg(filesystem::expand_tilde(&identity.to_string_lossy()));
+    }
+    command
+}
+
+/// Runs the command of `provider` that reveals `name`, and returns what it prints
+fn run_provider(provider: SecretProvider, mut command: Command, name: &str) -> Result<String> {
+    filesystem::set_child_environment(&mut command);
+    let non_interactive = non_interactive::enabled();
+    if non_interactive && provider == SecretProvider::Pass {
+        command.env("PASSWORD_STORE_GPG_OPTS", GPG_BATCH_OPTIONS.join(" "));
+    }
+    // Passphrases are asked for on the terminal. Under --non-interactive, what the provider
+    // prints is kept to tell a passphrase that it couldn't ask for from other failures.
+    let output = if non_interactive {
+        command.stdin(Stdio::null()).stderr(Stdio::piped())
+    } else {
+        command.stdin(Stdio::inherit()).stderr(Stdio::inherit())
+    }
+    .output()
+    .with_context(|| format!("run {}", provider.command()))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{}", stderr);
+    if !output.status.success() && non_interactive && needs_passphrase(&stderr) {
+        return Err(WouldPrompt {
+            reason: "secret-passphrase",
+            message: format!(
+                "{} couldn't reveal {:?} without asking for a passphrase: {}",
+                provider.command(),
+                name,
+                output.status
+            ),
+        }
+        .into());
+    }
+    if !output.status.success() {
+        bail!(
+            "{} couldn't reveal {:?}: {}",
+            provider.command(),
+            name,
+            output.status
+        );
+    }
+    String::from_utf8(output.stdout).context("secret isn't valid UTF-8")
+}
+
+/// `<directory>/<name>.<extension>`, where `directory` is relative to the repository
+fn encrypted_file(config: &SecretsConfig, name: &str, extension: &str) -> Result<PathBuf> {
+    let directory = config.directory.as_ref().with_context(|| {
+        format!(
+            "{} secrets are read from files, set `directory` in the [secrets] table of global.toml",
+            extension
+        )
+    })?;
+    let directory = PathBuf::from(filesystem::expand_tilde(&directory.to_string_lossy()));
+    Ok(directory.join(format!("{}.{}", name, extension)))
+}
+
+/// Whether a provider failed because it would have asked for a passphrase: gpg, and pass
+/// through it, fail to reach pinentry or the agent, and age fails to open the terminal
+fn needs_passphrase(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    ["pinentry", "gpg-agent", "passphrase", "/dev/tty"]
+        .iter()
+        .any(|marker| stderr.contains(marker))
+}
+
+/// Whether `text` contains a secret that was revealed during this run
+pub fn contains_secret(text: &str) -> bool {
+    revealed()
+        .values()
+        .any(|secret| !secret.is_empty() && text.contains(secret.as_str()))
+}
+
+/// What the cache keeps of a render: the render itself, or only its hash if it contains a secret
+pub fn cached_render(rendered: &str) -> String {
+    if contains_secret(rendered) {
+        hash_marker(rendered.as_bytes())
+    } else {
+        rendered.into()
+    }
+}
+
+pub fn hash_marker(contents: &[u8]) -> String {
+    format!("{}{}\n", HASH_PREFIX, filesystem::hash_bytes(contents))
+}
+
+/// Whether `contents` have the hash that `cached` records, or `None` if `cached` is a plain render
+pub fn matches_hash_marker(cached: &str, contents: &str) -> Option<bool> {
+    let hash = cached.strip_prefix(HASH_PREFIX)?.trim();
+    Some(filesystem::hash_matches(hash, contents.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cached_render() {
+        revealed().insert((SecretProvider::Pass, "test/key".into()), "hunter2".into());
+
+        assert_eq!(cached_render("user = me\n"), "user = me\n");
+        let cached = cached_render("password = hunter2\n");
+        assert!(!cached.contains("hunter2"));
+        assert_eq!(
+            matches_hash_marker(&cached, "password = hunter2\n"),
+            Some(true)
+        );
+        assert_eq!(
+            matches_hash_marker(&cached, "password = hunter3\n"),
+            Some(false)
+        );
+        assert_eq!(matches_hash_marker("user = me\n", "user = me\n"), None);
+    }
+
+    #[test]
+    fn test_needs_passphrase() {
+        assert!(needs_passphrase(
+            "gpg: public key decryption failed: No pinentry\ngpg: decryption failed: No secret key"
+        ));
+        assert!(needs_passphrase(
+            "age: error: could not read passphrase: open /dev/tty: no such device"
+        ));
+        assert!(!needs_passphrase(
+            "Error: mail/work is not in the password store."
+        ));
+        assert!(!needs_passphrase(
+            "gpg: can't open 'mail.gpg': No such file or directory"
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! `dotter selftest`: deploys a small repository into a scratch home, changes it and undeploys
+//! it again, checking the files after every step, to see whether dotter works on this platform
+//! and filesystem before it's trusted with real configuration
+
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use filesystem;
+
+/// A repository and a home directory in a scratch directory
+struct Scratch {
+    repository: PathBuf,
+    home: PathBuf,
+}
+
+/// A step of the self-test, which fails if the files aren't what they should be afterwards
+type Step = fn(&Scratch) -> Result<()>;
+
+/// Runs every step, and prints whether each one worked. Returns whether all of them did.
+/// The scratch directory is created inside `directory`, or the temporary directory.
+pub fn selftest(directory: Option<&Path>, keep: bool) -> Result<bool> {
+    let parent = match directory {
+        Some(directory) => directory.to_path_buf(),
+        None => std::env::temp_dir(),
+    };
+    let directory = tempfile::Builder::new()
+        .prefix("dotter-selftest-")
+        .tempdir_in(&parent)
+        .with_context(|| format!("create scratch directory in {:?}", parent))?;
+    let root = directory.path().to_path_buf();
+    let scratch = Scratch {
+        repository: root.join("repository"),
+        home: root.join("home"),
+    };
+    println!("Testing in {}", root.display());
+
+    let steps: &[(&str, Step)] = &[
+        ("create repository", create_repository),
+        ("deploy", deploy),
+        ("redeploy without changes", redeploy),
+        ("deploy a changed variable", change_variable),
+        ("deploy a changed template", change_template),
+        ("remove a file from the configuration", remove_file),
+        ("undeploy", undeploy),
+    ];
+    let mut passed = true;
+    for (name, step) in steps {
+        match step(&scratch) {
+            Ok(()) => println!("ok      {}", name),
+            Err(e) => {
+                println!("FAILED  {}: {:#}", name, e);
+                passed = false;
+                // The later steps build on the earlier ones
+                break;
+            }
+        }
+    }
+
+    if keep {
+        println!("Kept {}", directory.into_path().display());
+    } else if let Err(e) = directory.close() {
+        warn!("Failed to remove {:?}: {}", root, e);
+    }
+    if passed {
+        println!("All steps passed on {}", std::env::consts::OS);
+    }
+    Ok(passed)
+}
+
+fn create_repository(scratch: &Scratch) -> Result<()> {
+    fs::create_dir_all(scratch.repository.join(".dotter")).context("create repository")?;
+    fs::create_dir_all(&scratch.home).context("create home")?;
+    write(
+        &scratch.repository.join(".dotter/global.toml"),
+        r#"[shell.files]
+profile = "~/.profile"
+"config.toml" = { target = "~/.config/tool/config.toml", type = "template" }
+"#,
+    )?;
+    write_local(scratch, "blue")?;
+    write(&scratch.repository.join("profile"), "export EDITOR=vi\n")?;
+    write(
+        &scratch.repository.join("config.toml"),
+        "color = \"{{color}}\"\n",
+    )
+}
+
+fn deploy(scratch: &Scratch) -> Result<()> {
+    dotter(scratch, &["deploy"])?;
+    check_symlink(scratch, "profile", ".profile")?;
+    check_contents(scratch, ".config/tool/config.toml", "color = \"blue\"\n")
+}
+
+fn redeploy(scratch: &Scratch) -> Result<()> {
+    dotter(scratch, &["deploy"])?;
+    check_symlink(scratch, "profile", ".profile")?;
+    check_contents(scratch, ".config/tool/config.toml", "color = \"blue\"\n")
+}
+
+fn change_variable(scratch: &Scratch) -> Result<()> {
+    write_local(scratch, "green")?;
+    dotter(scratch, &["deploy"])?;
+    check_contents(scratch, ".config/tool/config.toml", "color = \"green\"\n")
+}
+
+fn change_template(scratch: &Scratch) -> Result<()> {
+    write(
+        &scratch.repository.join("config.toml"),
+        "color = \"{{color}}\"\nsize = 2\n",
+    )?;
+    dotter(scratch, &["deploy"])?;
+    check_contents(
+        scratch,
+        ".config/tool/config.toml",
+        "color = \"green\"\nsize = 2\n",
+    )
+}
+
+fn remove_file(scratch: &Scratch) -> Result<()> {
+    write(
+        &scratch.repository.join(".dotter/global.toml"),
+        "[shell.files]\nprofile = \"~/.profile\"\n",
+    )?;
+    dotter(scratch, &["deploy"])?;
+    check_missing(scratch, ".config/tool/config.toml")?;
+    check_symlink(scratch, "profile", ".profile")
+}
+
+fn undeploy(scratch: &Scratch) -> Result<()> {
+    dotter(scratch, &["undeploy"])?;
+    check_missing(scratch, ".profile")?;
+    // The source is untouched
+    let source = scratch.repository.join("profile");
+    if fs::read_to_string(&source).ok().as_deref() != Some("export EDITOR=vi\n") {
+        bail!("undeploying changed the source {:?}", source);
+    }
+    Ok(())
+}
+
+fn write_local(scratch: &Scratch, color: &str) -> Result<()> {
+    write(
+        &scratch.repository.join(".dotter/local.toml"),
+        &format!(
+            "packages = [\"shell\"]\n\n[variables]\ncolor = \"{}\"\n",
+            color
+        ),
+    )
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("write {:?}", path))
+}
+
+/// Runs this dotter in the scratch repository, without asking anything. The variables that
+/// configure dotter are left out, so that it runs like it would for a new user.
+fn dotter(scratch: &Scratch, args: &[&str]) -> Result<()> {
+    let executable = std::env::current_exe().context("get path of dotter")?;
+    let mut command = Command::new(executable);
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("DOTTER_") {
+            command.env_remove(name);
+        }
+    }
+    let output = command
+        .current_dir(&scratch.repository)
+        .arg("--home")
+        .arg(&scratch.home)
+        .arg("--non-interactive")
+        .arg("--noconfirm")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("run `dotter {}`", args.join(" ")))?;
+    if !output.status.success() {
+        bail!(
+            "`dotter {}` failed with {}:\n{}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The target is a symlink to the source, or a copy of it where symlinks aren't allowed
+fn check_symlink(scratch: &Scratch, source: &str, target: &str) -> Result<()> {
+    let source = scratch.repository.join(source);
+    let target = scratch.home.join(target);
+    let metadata = target
+        .symlink_metadata()
+        .with_context(|| format!("{:?} wasn't deployed", target))?;
+    if metadata.file_type().is_symlink() {
+        let pointee = fs::read_link(&target).context("read symlink")?;
+        let pointee = target.parent().unwrap_or(&scratch.home).join(pointee);
+        if filesystem::real_path(&pointee)? != filesystem::real_path(&source)? {
+            bail!(
+                "{:?} points at {:?} instead of {:?}",
+                target,
+                pointee,
+                source
+            );
+        }
+        return Ok(());
+    }
+    if fs::read(&target)? != fs::read(&source)? {
+        bail!(
+            "{:?} is neither a symlink to {:?} nor a copy of it",
+            target,
+            source
+        );
+    }
+    warn!("{:?} is a copy, symlinks aren't allowed here", target);
+    Ok(())
+}
+
+fn check_contents(scratch: &Scratch, target: &str, expected: &str) -> Result<()> {
+    let target = scratch.home.join(target);
+    let contents = fs::read_to_string(&target).with_context(|| format!("read {:?}", target))?;
+    if contents != expected {
+        bail!(
+            "{:?} contains {:?} instead of {:?}",
+            target,
+            contents,
+            expected
+        );
+    }
+    Ok(())
+}
+
+fn check_missing(scratch: &Scratch, target: &str) -> Result<()> {
+    let target = scratch.home.join(target);
+    if target.symlink_metadata().is_ok() {
+        bail!("{:?} is still there", target);
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+
+use args::Options;
+use config;
+
+/// Entry of the snapshot's manifest.json
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    source: PathBuf,
+    target: PathBuf,
+    kind: &'static str,
+    /// Location of the target's contents inside the archive, `None` if it couldn't be read
+    archived: Option<String>,
+    hash: Option<String>,
+}
+
+/// Archives the current contents of every deployed target into a tar file, along with a
+/// manifest.json that maps every target to its source. A directory target, like a symlinked
+/// directory, is archived as the files inside of it.
+/// Targets are read from the cache, so a broken configuration doesn't prevent a snapshot.
+pub fn snapshot(opt: &Options, output: Option<&Path>) -> Result<()> {
+    let cache = config::load_cache(&opt.cache_file)?
+        .context("load cache: Cannot take a snapshot without a cache.")?;
+
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => PathBuf::from(format!(
+            "dotter-snapshot-{}-{}.tar",
+            gethostname::gethostname().to_string_lossy(),
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        )),
+    };
+
+    let files = cache
+        .symlinks
+        .into_iter()
+        .map(|(source, target)| (source, target, "symlink"))
+        .chain(
+            cache
+                .templates
+                .into_iter()
+                .map(|(source, target)| (source, target, "template")),
+        )
+        .chain(
+            cache
+                .copies
+                .into_iter()
+                .map(|(source, target)| (source, target, "copy")),
+        );
+
+    let mut archive = TarWriter::new(File::create(&output).context("create archive")?);
+    let mut manifest = Vec::new();
+    let mut expanded = Vec::new();
+    for (source, target, kind) in files {
+        if target.is_dir() {
+            directory_files(&source, &target, kind, &mut expanded)
+                .with_context(|| format!("read directory target {:?}", target))?;
+        } else {
+            expanded.push((source, target, kind));
+        }
+    }
+    for (source, target, kind) in expanded {
+        let contents = match fs::read(&target) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("Target {:?} is missing, recording it as such", target);
+                None
+            }
+            Err(e) => {
+                warn!("Couldn't read target {:?}, skipping it: {}", target, e);
+                None
+            }
+        };
+        let archived = match &contents {
+            Some(contents) => {
+                let name = archive_name(&target);
+                archive
+                    .append(&name, contents)
+                    .with_context(|| format!("archive {:?}", target))?;
+                Some(name)
+            }
+            None => None,
+        };
+        manifest.push(ManifestEntry {
+            source,
+            target,
+            kind,
+            archived,
+            hash: contents.map(|c| blake3::hash(&c).to_hex().to_string()),
+        });
+    }
+
+    let count = manifest.len();
+    let manifest = serde_json::to_string_pretty(&manifest).context("serialize manifest")?;
+    archive
+        .append("manifest.json", manifest.as_bytes())
+        .context("archive manifest")?;
+    archive.finish().context("finish archive")?;
+
+    info!("Saved snapshot of {} targets to {:?}", count, output);
+    Ok(())
+}
+
+/// Adds the files inside of the directory `target`, with their paths inside of `source`.
+/// Directories inside of it are descended into, unless they're symlinks.
+fn directory_files(
+    source: &Path,
+    target: &Path,
+    kind: &'static str,
+    files: &mut Vec<(PathBuf, PathBuf, &'static str)>,
+) -> Result<()> {
+    let mut children = fs::read_dir(target)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let source = source.join(child.file_name());
+        if child.file_type()?.is_dir() {
+            directory_files(&source, &child.path(), kind, files)?;
+        } else {
+            files.push((source, child.path(), kind));
+        }
+    }
+    Ok(())
+}
+
+/// Targets are stored under `targets/` with their absolute path, minus the root or drive
+fn archive_name(target: &Path) -> String {
+    let mut name = String::from("targets");
+    for component in target.components() {
+        if let Component::Normal(part) = component {
+            name.push('/');
+            name.push_str(&part.to_string_lossy());
+        }
+    }
+    name
+}
+
+/// Writes regular files into an uncompressed ustar archive
+struct TarWriter {
+    out: BufWriter<File>,
+}
+
+impl TarWriter {
+    fn new(file: File) -> TarWriter {
+        TarWriter {
+            out: BufWriter::new(file),
+        }
+    }
+
+    fn append(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        self.out
+            .write_all(&tar_header(name, contents.len() as u64)?)?;
+        self.out.write_all(contents)?;
+        let padding = (512 - contents.len() % 512) % 512;
+        self.out.write_all(&vec![0; padding])?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.out.write_all(&[0; 1024])?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn tar_header(name: &str, size: u64) -> Result<[u8; 512]> {
+    let (prefix, name) = split_tar_name(name)?;
+    let mut header = [0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed with the checksum field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    header[155] = b' ';
+    Ok(header)
+}
+
+/// Fills the field with zero-padded octal digits followed by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+/// Names longer than 100 bytes are split at a slash into a prefix of up to 155 bytes and a name
+fn split_tar_name(name: &str) -> Result<(&str, &str)> {
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+    name.match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100)
+        .with_context(|| format!("path {:?} is too long for a tar archive", name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_directory_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("nvim");
+        fs::create_dir_all(target.join("lua")).unwrap();
+        fs::write(target.join("init.lua"), "").unwrap();
+        fs::write(target.join("lua/plugins.lua"), "").unwrap();
+
+        let mut files = Vec::new();
+        directory_files(Path::new("nvim"), &target, "symlink", &mut files).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                ("nvim/init.lua".into(), target.join("init.lua"), "symlink"),
+                (
+                    "nvim/lua/plugins.lua".into(),
+                    target.join("lua/plugins.lua"),
+                    "symlink"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_tar_name() {
+        assert_eq!(split_tar_name("targets/a").unwrap(), ("", "targets/a"));
+
+        let long = format!("targets/{}/{}", "a".repeat(100), "b".repeat(50));
+        let (prefix, name) = split_tar_name(&long).unwrap();
+        assert_eq!(prefix, format!("targets/{}", "a".repeat(100)));
+        assert_eq!(name, "b".repeat(50));
+
+        assert!(split_tar_name(&"c".repeat(101)).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Special files like named pipes and empty placeholders, which some programs expect to exist.
+//! They have no source in the repository: dotter creates them at their targets, and removes
+//! them again once they're gone from the configuration.
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use super::display_error;
+use config::{SpecialFile, SpecialFiles, SpecialKind};
+use filesystem;
+
+/// Creates the desired special files that are missing, and removes the ones that dotter
+/// created before but that aren't desired anymore. Returns the special files that dotter
+/// created, by target, and whether an error occurred.
+pub fn deploy(act: bool, desired: &SpecialFiles, created: &SpecialFiles) -> (SpecialFiles, bool) {
+    let targets = desired
+        .keys()
+        .chain(created.keys())
+        .collect::<BTreeSet<_>>();
+    let mut actual = SpecialFiles::new();
+    let mut error_occurred = false;
+
+    for target in targets {
+        let desired = desired.get(target);
+        let created = created.get(target);
+        // A file that only changed its mode stays, the mode is fixed below
+        let kept = created.filter(|c| desired.is_some_and(|d| d.kind == c.kind));
+        if let Some(created) = created.filter(|_| kept.is_none()) {
+            match remove(act, target, created) {
+                Ok(()) => {}
+                Err(e) => {
+                    display_error(e.context(format!("remove special file {:?}", target)));
+                    error_occurred = true;
+                    actual.insert(target.clone(), created.clone());
+                    continue;
+                }
+            }
+        }
+        if let Some(desired) = desired {
+            match update(act, target, desired, kept.is_some()) {
+                Ok(true) => {
+                    actual.insert(target.clone(), desired.clone());
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    display_error(e.context(format!("create special file {:?}", target)));
+                    error_occurred = true;
+                }
+            }
+        }
+    }
+
+    (actual, error_occurred)
+}
+
+/// Creates the special file if it's missing, and fixes its mode otherwise. Returns whether
+/// dotter created it: one that was already there is left in place when it's undeployed.
+fn update(act: bool, target: &Path, special: &SpecialFile, ours: bool) -> Result<bool> {
+    let mode = special.mode();
+    if target.symlink_metadata().is_err() {
+        info!("Creating {} {:?}", description(special.kind), target);
+        if act {
+            fs::create_dir_all(target.parent().context("get parent of special file")?)
+                .context("create parent of special file")?;
+            match special.kind {
+                SpecialKind::Fifo => filesystem::make_fifo(target, mode)?,
+                SpecialKind::Empty => {
+                    fs::write(target, "").context("create file")?;
+                    filesystem::set_mode(target, mode)?;
+                }
+            }
+        }
+        return Ok(true);
+    }
+
+    if !is_kind(target, special.kind) {
+        bail!(
+            "{:?} is in the way of {}",
+            target,
+            description(special.kind)
+        );
+    }
+    // A file that was there before belongs to the user, mode and all
+    if !ours {
+        debug!(
+            "{:?} already exists, it's left as it is and in place when it's undeployed",
+            target
+        );
+        return Ok(false);
+    }
+    if filesystem::file_mode(target)?.is_some_and(|actual| actual != mode) {
+        info!("Changing the mode of {:?} to {:o}", target, mode);
+        if act {
+            filesystem::set_mode(target, mode)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Removes a special file that dotter created. Placeholders that were filled in since are
+/// kept, since their contents belong to the program now.
+fn remove(act: bool, target: &Path, special: &SpecialFile) -> Result<()> {
+    if target.symlink_metadata().is_err() {
+        debug!("{:?} is already gone", target);
+        return Ok(());
+    }
+    if !is_kind(target, special.kind) {
+        warn!(
+            "Not removing {:?}, it isn't {} anymore",
+            target,
+            description(special.kind)
+        );
+        return Ok(());
+    }
+    if special.kind == SpecialKind::Empty && fs::metadata(target)?.len() > 0 {
+        warn!("Not removing {:?}, it isn't empty anymore", target);
+        return Ok(());
+    }
+    info!("Removing {} {:?}", description(special.kind), target);
+    if act {
+        fs::remove_file(target).context("remove file")?;
+    }
+    Ok(())
+}
+
+fn is_kind(target: &Path, kind: SpecialKind) -> bool {
+    match kind {
+        SpecialKind::Fifo => filesystem::is_fifo(target),
+        SpecialKind::Empty => target.symlink_metadata().is_ok_and(|m| m.is_file()),
+    }
+}
+
+fn description(kind: SpecialKind) -> &'static str {
+    match kind {
+        SpecialKind::Fifo => "named pipe",
+        SpecialKind::Empty => "placeholder",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn placeholder(mode: &str) -> SpecialFile {
+        SpecialFile {
+            kind: SpecialKind::Empty,
+            mode: Some(mode.into()),
+            package: Some("default".into()),
+        }
+    }
+
+    #[test]
+    fn test_deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = dir.path().join("created");
+        let existing = dir.path().join("existing");
+        fs::write(&existing, "").unwrap();
+        filesystem::set_mode(&existing, 0o644).unwrap();
+
+        let mut desired = SpecialFiles::new();
+        desired.insert(created.clone(), placeholder("600"));
+        desired.insert(existing.clone(), placeholder("600"));
+        let (actual, error_occurred) = deploy(true, &desired, &SpecialFiles::new());
+        assert!(!error_occurred);
+        assert!(created.is_file());
+        // Only the file that dotter created is recorded, and the user's keeps its mode
+        assert_eq!(actual.keys().collect::<Vec<_>>(), [&created]);
+        if cfg!(unix) {
+            assert_eq!(filesystem::file_mode(&created).unwrap(), Some(0o600));
+            assert_eq!(filesystem::file_mode(&existing).unwrap(), Some(0o644));
+        }
+
+        // The mode of a file that dotter created follows the configuration
+        desired.insert(created.clone(), placeholder("640"));
+        let (actual, _) = deploy(true, &desired, &actual);
+        if cfg!(unix) {
+            assert_eq!(filesystem::file_mode(&created).unwrap(), Some(0o640));
+        }
+
+        // Files that aren't desired anymore are removed, unless they were filled in since
+        let (actual, error_occurred) = deploy(true, &SpecialFiles::new(), &actual);
+        assert!(!error_occurred);
+        assert!(actual.is_empty());
+        assert!(!created.exists());
+        assert!(existing.exists());
+
+        let (actual, _) = deploy(true, &desired, &SpecialFiles::new());
+        fs::write(&created, "filled in").unwrap();
+        deploy(true, &SpecialFiles::new(), &actual);
+        assert!(created.exists());
+    }
+}
@@ -0,0 +1,182 @@
+//! The state of the deployed files as a single file, for `dotter state export` and
+//! `dotter state import`: the cache, and the renders of the templates that are kept next to it
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use args::{Options, OutputFormat};
+use config::{self, Cache};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct State {
+    cache: Cache,
+    /// Cached render of every deployed template, by source. Templates whose renders contain
+    /// secrets only have a hash of it cached.
+    #[serde(default)]
+    renders: BTreeMap<PathBuf, String>,
+}
+
+/// Prints the state, or writes it to `output`
+pub fn export(opt: &Options, format: OutputFormat, output: Option<&Path>) -> Result<()> {
+    let cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    let mut renders = BTreeMap::new();
+    for source in cache.templates.keys() {
+        let render = cache.render_path(source, &opt.cache_directory);
+        match fs::read_to_string(&render) {
+            Ok(contents) => {
+                renders.insert(source.clone(), contents);
+            }
+            Err(e) => warn!(
+                "Leaving out the render of {:?}, which can't be read from {:?}: {}",
+                source, render, e
+            ),
+        }
+    }
+
+    let state = State { cache, renders };
+    let exported = match format {
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&state).context("serialize state into json")?
+        }
+        OutputFormat::Toml => toml::to_string(&state).context("serialize state into toml")?,
+    };
+    match output {
+        Some(output) => {
+            fs::write(output, exported).with_context(|| format!("write {:?}", output))?;
+            info!("Exported state to {:?}", output);
+        }
+        None => println!("{}", exported),
+    }
+    Ok(())
+}
+
+/// Saves the cache of the state at `path` as this repository's, and puts the renders where
+/// it says the templates' renders are
+pub fn import(opt: &Options, path: &Path, replace: bool) -> Result<()> {
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let contents = fs::read_to_string(path).with_context(|| format!("read {:?}", path))?;
+    let State { cache, renders } = parse(&contents)?;
+    let mut cache = config::migrate_cache(cache).context("migrate cache of state")?;
+    // The renders are written under the cache directory, so a source like `../../.bashrc`
+    // would write outside of it
+    if let Some(source) = cache
+        .templates
+        .keys()
+        .chain(renders.keys())
+        .find(|source| !is_plain_relative(source))
+    {
+        bail!(
+            "the state has a template source {:?} that isn't a plain relative path",
+            source
+        );
+    }
+    // Where the other machine kept its renders means nothing here, so they're kept in the
+    // cache directory, and a deploy moves them to the `render_cache` of this machine
+    if !cache.render_caches.is_empty() {
+        debug!("Ignoring the render caches of the state");
+        cache.render_caches.clear();
+    }
+
+    if let Some(existing) = config::load_cache(&opt.cache_file)? {
+        let deployed = existing.symlinks.len() + existing.templates.len() + existing.copies.len();
+        if deployed > 0 && !replace {
+            bail!(
+                "the cache already records {} deployed files. Use --replace to replace it anyway.",
+                deployed
+            );
+        }
+    }
+
+    for (source, render) in &renders {
+        if !cache.templates.contains_key(source) {
+            warn!("Skipping the render of {:?}, which isn't deployed", source);
+            continue;
+        }
+        let location = cache.render_path(source, &opt.cache_directory);
+        info!("Writing the render of {:?} to {:?}", source, location);
+        if opt.act {
+            if let Some(parent) = location.parent() {
+                fs::create_dir_all(parent).context("create parent of render")?;
+            }
+            fs::write(&location, render).with_context(|| format!("write {:?}", location))?;
+        }
+    }
+    for source in cache.templates.keys() {
+        if !renders.contains_key(source) {
+            warn!(
+                "The state has no render of {:?}. Run `dotter cache repair` to render it again.",
+                source
+            );
+        }
+    }
+
+    info!(
+        "Importing {} symlinks, {} templates and {} copies",
+        cache.symlinks.len(),
+        cache.templates.len(),
+        cache.copies.len()
+    );
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is relative and only goes down, without `..` or a root
+fn is_plain_relative(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// A state in either format of `export`
+fn parse(contents: &str) -> Result<State> {
+    if contents.trim_start().starts_with('{') {
+        serde_json::from_str(contents).context("parse state as json")
+    } else {
+        toml::from_str(contents).context("parse state as toml")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut cache = Cache::default();
+        cache
+            .symlinks
+            .insert("zshrc".into(), "/home/me/.zshrc".into());
+        cache
+            .templates
+            .insert("gitconfig".into(), "/home/me/.gitconfig".into());
+        let mut renders = BTreeMap::new();
+        renders.insert(PathBuf::from("gitconfig"), "[user]\n".to_string());
+        let state = State { cache, renders };
+
+        for exported in [
+            serde_json::to_string_pretty(&state).unwrap(),
+            toml::to_string(&state).unwrap(),
+        ] {
+            let parsed = parse(&exported).unwrap();
+            assert_eq!(parsed.cache.symlinks, state.cache.symlinks);
+            assert_eq!(parsed.cache.templates, state.cache.templates);
+            assert_eq!(parsed.renders, state.renders);
+        }
+    }
+
+    #[test]
+    fn test_is_plain_relative() {
+        assert!(is_plain_relative(Path::new("git/gitconfig")));
+        assert!(!is_plain_relative(Path::new("../.bashrc")));
+        assert!(!is_plain_relative(Path::new("git/../../.bashrc")));
+        assert!(!is_plain_relative(Path::new("/etc/passwd")));
+        assert!(!is_plain_relative(Path::new("")));
+    }
+}
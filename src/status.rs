@@ -0,0 +1,456 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config::{self, Variables};
+use context;
+use deploy;
+use events::{self, Event};
+use file_state::{CopyDescription, SymlinkDescription, TemplateDescription};
+use filesystem::{self, SymlinkComparison, TemplateComparison};
+use handlebars::Handlebars;
+use lazy;
+use render::RenderLimits;
+use timings;
+
+/// How a target compares to what the configuration wants there and what the last deploy left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Missing,
+    /// The contents were edited since they were deployed, or something else is in the way
+    Modified,
+    WrongSymlinkTarget,
+    /// The target is what the last deploy left, but its source or variables changed since
+    Outdated,
+    /// Deployed by an earlier run, but no longer in the configuration
+    Orphaned,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Missing => "missing",
+            Status::Modified => "modified on target",
+            Status::WrongSymlinkTarget => "wrong symlink target",
+            Status::Outdated => "outdated",
+            Status::Orphaned => "orphaned",
+        }
+    }
+
+    /// How scripts know the status, from `--output json`
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Missing => "missing",
+            Status::Modified => "modified",
+            Status::WrongSymlinkTarget => "wrong_symlink_target",
+            Status::Outdated => "outdated",
+            Status::Orphaned => "orphaned",
+        }
+    }
+}
+
+struct Row {
+    status: Status,
+    target: PathBuf,
+    source: PathBuf,
+    detail: String,
+}
+
+/// Summary of a machine, for auditing many of them with tooling that collects the JSON
+#[derive(Debug, Serialize)]
+pub struct MachineReport {
+    pub machine: String,
+    pub version: &'static str,
+    pub packages: Vec<String>,
+    /// When the last deploy finished, in RFC 3339
+    pub last_deploy: Option<String>,
+    pub targets: usize,
+    /// How many targets aren't OK, by status
+    pub drift: BTreeMap<&'static str, usize>,
+    pub missing_sources: usize,
+}
+
+/// Prints a table of the state of every target, comparing the configuration against the
+/// filesystem and the cache without changing anything. Returns whether everything is in sync.
+pub fn status(opt: &Options) -> Result<bool> {
+    let (config, rows) = check_targets(opt)?;
+    let out_of_sync = rows.iter().filter(|r| r.status != Status::Ok).count();
+    if events::enabled() {
+        for row in &rows {
+            events::report(Event::Status {
+                source: &row.source,
+                target: &row.target,
+                status: row.status.name(),
+                detail: &row.detail,
+            });
+        }
+    } else {
+        print_rows(&rows);
+    }
+
+    for (source, package) in &config.missing_sources {
+        match package {
+            Some(package) => error!("Source {:?} of package {:?} is missing", source, package),
+            None => error!("Source {:?} is missing", source),
+        }
+    }
+
+    if !events::enabled() {
+        println!();
+        println!(
+            "{} targets, {} out of sync",
+            rows.len(),
+            if out_of_sync == 0 {
+                out_of_sync.to_string().green()
+            } else {
+                out_of_sync.to_string().red()
+            }
+        );
+    }
+    Ok(out_of_sync == 0 && config.missing_sources.is_empty())
+}
+
+/// Prints what this machine has enabled and deployed, and how far it drifted from the
+/// configuration. Nothing is sent anywhere, collecting the reports is up to the caller.
+pub fn report(opt: &Options) -> Result<()> {
+    let (config, rows) = check_targets(opt)?;
+    let mut drift = BTreeMap::new();
+    for row in rows.iter().filter(|r| r.status != Status::Ok) {
+        *drift.entry(row.status.name()).or_insert(0) += 1;
+    }
+    let report = MachineReport {
+        machine: gethostname::gethostname().to_string_lossy().into_owned(),
+        version: env!("CARGO_PKG_VERSION"),
+        packages: config.packages.clone(),
+        last_deploy: timings::last_deploy(opt)?,
+        targets: rows.len(),
+        drift,
+        missing_sources: config.missing_sources.len(),
+    };
+
+    if events::enabled() {
+        events::report(Event::Report(&report));
+        return Ok(());
+    }
+    println!("Machine:          {}", report.machine);
+    println!("Dotter version:   {}", report.version);
+    println!("Packages:         {}", report.packages.join(", "));
+    println!(
+        "Last deploy:      {}",
+        report.last_deploy.as_deref().unwrap_or("never")
+    );
+    let out_of_sync = report.drift.values().sum::<usize>();
+    let drift = report
+        .drift
+        .iter()
+        .map(|(status, count)| format!("{} {}", count, status))
+        .collect::<Vec<_>>();
+    if drift.is_empty() {
+        println!("Targets:          {}, all in sync", report.targets);
+    } else {
+        println!(
+            "Targets:          {}, {} out of sync ({})",
+            report.targets,
+            out_of_sync,
+            drift.join(", ")
+        );
+    }
+    if report.missing_sources > 0 {
+        println!("Missing sources:  {}", report.missing_sources);
+    }
+    Ok(())
+}
+
+/// Prints the drift of this machine as a plugin of a menu bar or tray host like xbar, SwiftBar
+/// or Argos: a title with how many targets are out of sync, then a menu with the last deploy,
+/// the targets that drifted, and items that deploy or show the diff in this repository.
+#[cfg(feature = "tray")]
+pub fn tray(opt: &Options) -> Result<()> {
+    use fleet::shell_quote;
+
+    let (config, rows) = check_targets(opt)?;
+    let drifted = rows
+        .iter()
+        .filter(|r| r.status != Status::Ok)
+        .collect::<Vec<_>>();
+    let problems = drifted.len() + config.missing_sources.len();
+    if problems == 0 {
+        println!("dotter ✓");
+    } else {
+        println!("dotter ⚠ {}", problems);
+    }
+    println!("---");
+    println!(
+        "Last deploy: {}",
+        timings::last_deploy(opt)?.as_deref().unwrap_or("never")
+    );
+    println!("{} targets, {} out of sync", rows.len(), drifted.len());
+    for row in &drifted {
+        println!("--{}: {}", row.target.display(), row.status.label());
+    }
+    for source in config.missing_sources.keys() {
+        println!("Source {} is missing", source.display());
+    }
+
+    // The host runs the items elsewhere, so they go back to the repository first
+    let repository = std::env::current_dir().context("get current directory")?;
+    let executable = std::env::current_exe().context("get path of dotter")?;
+    let command = |action: &str| {
+        format!(
+            "cd {} && {} {}",
+            shell_quote(&repository.to_string_lossy()),
+            shell_quote(&executable.to_string_lossy()),
+            action
+        )
+        .replace('"', "\\\"")
+    };
+    println!("---");
+    println!(
+        "Deploy | bash=/bin/sh param1=-c param2=\"{}\" terminal=false refresh=true",
+        command("deploy")
+    );
+    println!(
+        "Diff | bash=/bin/sh param1=-c param2=\"{}; read _\" terminal=true",
+        command("diff")
+    );
+    Ok(())
+}
+
+/// Compares every target against the configuration and the cache, sorted by target
+fn check_targets(opt: &Options) -> Result<(config::Configuration, Vec<Row>)> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let mut cache = match config::load_cache(&opt.cache_file)? {
+        Some(cache) => cache,
+        None => {
+            warn!("Cache file not found. Assuming nothing is deployed.");
+            Default::default()
+        }
+    };
+    cache.match_case(&config.files);
+    let state = deploy::file_state_from_configuration(&config, &cache, &opt.cache_directory, false)
+        .context("get file state")?;
+    let variables = context::template_context(&config);
+    let handlebars = deploy::handlebars_instance(opt, &config);
+    let limits = RenderLimits::new(opt);
+
+    let mut rows = Vec::new();
+    let (new_symlinks, new_templates) = state.new_files();
+    let (old_symlinks, old_templates) = state.old_files();
+    for symlink in new_symlinks.iter().chain(&old_symlinks) {
+        rows.push(symlink_row(symlink).with_context(|| format!("check {}", symlink))?);
+    }
+    for template in new_templates.iter().chain(&old_templates) {
+        rows.push(
+            template_row(template, &handlebars, &variables, &limits)
+                .with_context(|| format!("check {}", template))?,
+        );
+    }
+    for copy in state.new_copies().iter().chain(&state.old_copies()) {
+        let recorded_hash = cache.copy_hashes.get(&copy.source);
+        rows.push(copy_row(copy, recorded_hash).with_context(|| format!("check {}", copy))?);
+    }
+    for (target, package) in &config.shims {
+        rows.push(shim_row(target, package));
+    }
+
+    let (deleted_symlinks, deleted_templates) = state.deleted_files();
+    let orphans = deleted_symlinks
+        .into_iter()
+        .map(|s| (s.source, s.target.target))
+        .chain(
+            deleted_templates
+                .into_iter()
+                .map(|t| (t.source, t.target.target)),
+        )
+        .chain(
+            state
+                .deleted_copies()
+                .into_iter()
+                .map(|c| (c.source, c.target.target)),
+        );
+    for (source, target) in orphans {
+        let detail = if std::fs::symlink_metadata(&target).is_ok() {
+            "no longer in the configuration, the next deploy deletes it"
+        } else {
+            "no longer in the configuration, and already gone"
+        };
+        rows.push(Row {
+            status: Status::Orphaned,
+            target,
+            source,
+            detail: detail.into(),
+        });
+    }
+
+    rows.sort_by(|a, b| a.target.cmp(&b.target));
+    Ok((config, rows))
+}
+
+/// The executables of lazy packages that weren't activated yet are a shim, which is listed with
+/// the package as its source
+fn shim_row(target: &Path, package: &str) -> Row {
+    let (status, detail) = if lazy::is_shim(target) {
+        (Status::Ok, "shim of lazy package, deployed on first use")
+    } else if std::fs::symlink_metadata(target).is_ok() {
+        (
+            Status::Modified,
+            "something other than the shim of lazy package is there",
+        )
+    } else {
+        (Status::Missing, "shim of lazy package is missing")
+    };
+    Row {
+        status,
+        target: target.to_path_buf(),
+        source: package.into(),
+        detail: detail.into(),
+    }
+}
+
+fn symlink_row(symlink: &SymlinkDescription) -> Result<Row> {
+    let comparison = symlink.compare().context("compare symlink")?;
+    let status = match comparison {
+        SymlinkComparison::Identical | SymlinkComparison::TargetIsSource => Status::Ok,
+        SymlinkComparison::OnlySourceExists | SymlinkComparison::BothMissing => Status::Missing,
+        SymlinkComparison::OnlyTargetExists | SymlinkComparison::TargetNotSymlink => {
+            Status::Modified
+        }
+        SymlinkComparison::Changed | SymlinkComparison::Circular => Status::WrongSymlinkTarget,
+    };
+    Ok(Row {
+        status,
+        target: symlink.target.target.clone(),
+        source: symlink.source.clone(),
+        detail: comparison.to_string(),
+    })
+}
+
+/// Templates are compared against their render in the cache directory, which is what the
+/// last deploy wrote, so manual edits show up even if the template changed since. A target
+/// that matches it is then compared against a fresh render, for changes of the template or
+/// its variables that the next deploy would write.
+fn template_row(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<Row> {
+    let target = &template.target.target;
+    let (status, detail) = if template.target.write_once {
+        match std::fs::symlink_metadata(target) {
+            Ok(_) => (
+                Status::Ok,
+                "write once, unmanaged after the first deploy".into(),
+            ),
+            Err(_) => (Status::Missing, "write once, not written yet".into()),
+        }
+    } else {
+        let comparison =
+            filesystem::compare_template(target, &template.cache, &template.target.equivalence)
+                .context("compare template")?;
+        let status = match comparison {
+            TemplateComparison::Identical => {
+                let rendered = deploy::render_to_string(template, handlebars, variables, limits)
+                    .context("render template")?;
+                let cached = std::fs::read(&template.cache).context("read cached render")?;
+                if rendered.as_bytes() == cached.as_slice() {
+                    Status::Ok
+                } else {
+                    return Ok(Row {
+                        status: Status::Outdated,
+                        target: target.clone(),
+                        source: template.source.clone(),
+                        detail: "template or its variables changed since the last deploy".into(),
+                    });
+                }
+            }
+            TemplateComparison::OnlyCacheExists | TemplateComparison::BothMissing => {
+                Status::Missing
+            }
+            TemplateComparison::Changed | TemplateComparison::OnlyTargetExists => Status::Modified,
+        };
+        (status, comparison.to_string())
+    };
+    Ok(Row {
+        status,
+        target: target.clone(),
+        source: template.source.clone(),
+        detail,
+    })
+}
+
+/// Copies are compared against the hash that was recorded when they were copied, or against
+/// their source if they weren't deployed yet. An unchanged copy of a source that changed since
+/// is outdated.
+fn copy_row(copy: &CopyDescription, recorded_hash: Option<&String>) -> Result<Row> {
+    let target_hash = filesystem::hash_file(&copy.target.target).context("hash target")?;
+    let (status, detail) = match target_hash {
+        None => (Status::Missing, "target is missing"),
+        Some(_) if copy.target.write_once => {
+            (Status::Ok, "write once, unmanaged after the first deploy")
+        }
+        Some(hash) if deploy::copy_changed(recorded_hash, &copy.target.target, &hash)? => {
+            (Status::Modified, "target changed since it was copied")
+        }
+        Some(hash) if recorded_hash.is_none() => {
+            let source_hash = filesystem::hash_file(&copy.source).context("hash source")?;
+            if source_hash.as_ref() == Some(&hash) {
+                (Status::Ok, "target is a copy of the source")
+            } else {
+                (
+                    Status::Modified,
+                    "target exists, but wasn't copied by dotter",
+                )
+            }
+        }
+        Some(_) => {
+            let recorded = recorded_hash.context("get recorded hash")?;
+            let source_changed = match filesystem::hash_file(&copy.source).context("hash source")? {
+                Some(source_hash) => !filesystem::same_hash(recorded, &source_hash, || {
+                    std::fs::read(&copy.source).context("read source")
+                })?,
+                None => false,
+            };
+            if source_changed {
+                (Status::Outdated, "source changed since it was copied")
+            } else {
+                (Status::Ok, "target is unchanged since it was copied")
+            }
+        }
+    };
+    Ok(Row {
+        status,
+        target: copy.target.target.clone(),
+        source: copy.source.clone(),
+        detail: detail.into(),
+    })
+}
+
+fn print_rows(rows: &[Row]) {
+    let width = rows
+        .iter()
+        .map(|r| r.status.label().len())
+        .max()
+        .unwrap_or(0);
+    for row in rows {
+        let label = format!("{:width$}", row.status.label(), width = width);
+        let label = match row.status {
+            Status::Ok => label.as_str().green(),
+            Status::Orphaned => label.as_str().yellow(),
+            _ => label.as_str().red(),
+        };
+        println!(
+            "{}  {:?} <- {:?}  {}",
+            label,
+            row.target,
+            row.source,
+            format!("({})", row.detail).as_str().dark_grey()
+        );
+    }
+}
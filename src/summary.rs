@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use crossterm::style::Colorize;
+
+use handlebars::Handlebars;
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use config::Variables;
+use file_state::{CopyDescription, SymlinkDescription, TemplateDescription};
+use filesystem::{self, SymlinkComparison};
+use plan::{Plan, PlannedChange};
+use render::{self, RenderLimits};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub new: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+    /// Files that can't be deployed because their source is missing from the repository
+    pub missing: usize,
+}
+
+impl Counts {
+    pub fn add(&mut self, other: Counts) {
+        self.new += other.new;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+        self.unchanged += other.unchanged;
+        self.missing += other.missing;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.new + self.updated + self.deleted > 0
+    }
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{} new, {} updated, {} deleted, {} unchanged",
+            self.new, self.updated, self.deleted, self.unchanged
+        )?;
+        if self.missing > 0 {
+            write!(f, ", {} missing source", self.missing)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts of pending changes, grouped by package.
+/// Files that don't belong to a package are grouped under `None`.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub packages: BTreeMap<Option<String>, Counts>,
+}
+
+impl Summary {
+    pub fn new(plan: &Plan) -> Summary {
+        let mut summary = Summary::default();
+
+        for package in plan.missing_sources.values() {
+            summary.entry(package.clone()).missing += 1;
+        }
+        for file in &plan.files {
+            let counts = summary.entry(file.package.clone());
+            match file.change {
+                PlannedChange::Create => counts.new += 1,
+                PlannedChange::Update | PlannedChange::Metadata => counts.updated += 1,
+                PlannedChange::Delete => counts.deleted += 1,
+                PlannedChange::Unchanged => counts.unchanged += 1,
+            }
+        }
+
+        summary
+    }
+
+    fn entry(&mut self, package: Option<String>) -> &mut Counts {
+        self.packages.entry(package).or_default()
+    }
+
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+        for counts in self.packages.values() {
+            total.add(*counts);
+        }
+        total
+    }
+
+    pub fn print(&self) {
+        println!("Summary:");
+        for (package, counts) in &self.packages {
+            let package = package.as_deref().unwrap_or("(no package)");
+            if counts.missing > 0 {
+                println!("  {}: {}", package.red(), counts);
+            } else if counts.pending() {
+                println!("  {}: {}", package.yellow(), counts);
+            } else {
+                println!("  {}: {}", package, counts);
+            }
+        }
+        println!("Total: {}", self.total());
+    }
+}
+
+/// Whether the symlink would be created again, not counting its metadata
+pub fn symlink_changed(symlink: &SymlinkDescription) -> Result<bool> {
+    Ok(symlink.compare()? != SymlinkComparison::Identical)
+}
+
+/// Whether the contents of the copy would change, not counting its metadata
+pub fn copy_changed(copy: &CopyDescription) -> Result<bool> {
+    Ok(filesystem::hash_file(&copy.source)? != filesystem::hash_file(&copy.target.target)?)
+}
+
+/// Whether the contents of the template's target would change, not counting its metadata
+pub fn template_changed(
+    template: &TemplateDescription,
+    handlebars: &Handlebars,
+    variables: &Variables,
+    limits: &RenderLimits,
+) -> Result<bool> {
+    let rendered = render::render_template(template, handlebars, variables, limits)?;
+    let target = match fs::read_to_string(&template.target.target) {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e).context("read template target file"),
+    };
+    Ok(!template.target.equivalence.equivalent(&rendered, &target)?)
+}
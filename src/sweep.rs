@@ -0,0 +1,127 @@
+//! Finds symlinks into the repository or the cache directory that the cache doesn't know about,
+//! like the leftovers of experiments that were deployed by hand or of another dotfile manager,
+//! and offers to delete them
+
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config;
+use deploy;
+use filesystem;
+
+/// A symlink that points into the repository but isn't deployed
+struct Stale {
+    link: PathBuf,
+    points_to: PathBuf,
+}
+
+/// Scans `directory`, which has to be inside the `allowed_target_roots` (or the home directory
+/// if there are none), and deletes the stale symlinks in it, asking about each one unless
+/// --noconfirm is given
+pub fn sweep(opt: &Options, directory: &Path) -> Result<()> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let directory = config::normalize_path(Path::new(&filesystem::expand_tilde(
+        &directory.to_string_lossy(),
+    )))?;
+    let roots = match &config.allowed_target_roots {
+        Some(roots) => roots
+            .iter()
+            .map(|root| {
+                config::normalize_path(Path::new(&filesystem::expand_tilde(
+                    &root.to_string_lossy(),
+                )))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![config::normalize_path(&filesystem::home_dir())?],
+    };
+    if !roots.iter().any(|root| directory.starts_with(root)) {
+        bail!(
+            "{:?} is outside of the directories that targets are deployed to ({:?}), set `allowed_target_roots` to sweep it",
+            directory,
+            roots
+        );
+    }
+
+    let cache = config::load_cache(&opt.cache_file)?.unwrap_or_default();
+    let deployed = cache
+        .symlinks
+        .values()
+        .chain(cache.templates.values())
+        .chain(cache.copies.values())
+        .collect::<Vec<_>>();
+    let repository = config::normalize_path(Path::new("."))?;
+    let cache_directory = config::normalize_path(&opt.cache_directory)?;
+    let owned = [repository, cache_directory];
+
+    let mut stale = Vec::new();
+    find_stale(&directory, &owned, &mut stale).context("scan directory")?;
+    stale.retain(|s| !deployed.iter().any(|d| filesystem::paths_equal(d, &s.link)));
+    if stale.is_empty() {
+        println!("No stale symlinks in {:?}", directory);
+        return Ok(());
+    }
+
+    let mut deleted = 0;
+    for Stale { link, points_to } in &stale {
+        println!("{:?} -> {:?}", link, points_to);
+        if !opt.act {
+            continue;
+        }
+        if opt.interactive && !filesystem::ask_boolean(&format!("Delete {:?}? [y/N]", link)) {
+            continue;
+        }
+        // Directory symlinks are removed like the directories they point at on Windows
+        let removed = if cfg!(windows) && link.is_dir() {
+            fs::remove_dir(link)
+        } else {
+            fs::remove_file(link)
+        };
+        removed.with_context(|| format!("delete {:?}", link))?;
+        deleted += 1;
+    }
+    if opt.act {
+        println!("Deleted {} of {} stale symlinks", deleted, stale.len());
+    } else {
+        println!("Would delete {} stale symlinks", stale.len());
+    }
+    Ok(())
+}
+
+/// Collects the symlinks in `directory` that point into one of the `owned` directories,
+/// without descending into symlinked directories or the owned directories themselves
+fn find_stale(directory: &Path, owned: &[PathBuf], stale: &mut Vec<Stale>) -> Result<()> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            debug!("Skipping {:?}: {}", directory, e);
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("read directory {:?}", directory)),
+    };
+    for entry in entries {
+        let path = entry.context("get next file")?.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("inspect {:?}", path)),
+        };
+        if metadata.file_type().is_symlink() {
+            let link_target = fs::read_link(&path).with_context(|| format!("read {:?}", path))?;
+            // Relative to the symlink's directory, and maybe broken, so it isn't resolved
+            let points_to = config::normalize_path(&directory.join(&link_target))?;
+            if owned.iter().any(|o| points_to.starts_with(o)) {
+                stale.push(Stale {
+                    link: path,
+                    points_to,
+                });
+            }
+        } else if metadata.is_dir() && !owned.iter().any(|o| filesystem::paths_equal(o, &path)) {
+            find_stale(&path, owned, stale)?;
+        }
+    }
+    Ok(())
+}
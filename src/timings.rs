@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use args::Options;
+use config;
+
+/// How long each phase of a deploy took, in the order they ran
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Timings {
+    /// When the deploy finished, in RFC 3339
+    pub finished: String,
+    pub phases: Vec<Phase>,
+    /// End of the previous phase
+    #[serde(skip, default = "Instant::now")]
+    last: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    pub seconds: f64,
+}
+
+impl Timings {
+    pub fn start() -> Timings {
+        Timings {
+            finished: String::new(),
+            phases: Vec::new(),
+            last: Instant::now(),
+        }
+    }
+
+    /// Records the time since the previous phase ended
+    pub fn lap(&mut self, name: &str) {
+        self.lap_with_part(name, None);
+    }
+
+    /// Like `lap`, but `part` of the phase is recorded as a separate phase before it
+    pub fn lap_with_part(&mut self, name: &str, part: Option<(&str, Duration)>) {
+        let mut elapsed = self.last.elapsed();
+        if let Some((part_name, part)) = part {
+            self.record(part_name, part);
+            elapsed = elapsed.saturating_sub(part);
+        }
+        self.record(name, elapsed);
+        self.last = Instant::now();
+    }
+
+    fn record(&mut self, name: &str, duration: Duration) {
+        self.phases.push(Phase {
+            name: name.into(),
+            seconds: duration.as_secs_f64(),
+        });
+    }
+
+    pub fn total(&self) -> f64 {
+        self.phases.iter().map(|p| p.seconds).sum()
+    }
+
+    pub fn print(&self) {
+        println!("Timings:");
+        let width = self.phases.iter().map(|p| p.name.len()).max().unwrap_or(0);
+        for phase in &self.phases {
+            println!(
+                "  {:width$}  {:>8.3}s",
+                phase.name,
+                phase.seconds,
+                width = width
+            );
+        }
+        println!(
+            "  {:width$}  {:>8.3}s",
+            "total",
+            self.total(),
+            width = width
+        );
+    }
+
+    pub fn save(&mut self, location: &Path) -> Result<()> {
+        self.finished = chrono::Local::now().to_rfc3339();
+        fs::create_dir_all(location.parent().context("get parent of timings file")?)
+            .context("create parent of timings file")?;
+        let contents = serde_json::to_string_pretty(self).context("serialize timings")?;
+        fs::write(location, contents).context("write timings file")
+    }
+}
+
+/// Where the timings of the last deploy are kept
+pub fn timings_file(opt: &Options) -> PathBuf {
+    opt.cache_directory.join(".dotter_timings.json")
+}
+
+/// Prints what the cache knows about the deployed files, and how long the last deploy took
+pub fn print_stats(opt: &Options) -> Result<()> {
+    match config::load_cache(&opt.cache_file)? {
+        Some(cache) => println!(
+            "Deployed: {} symlinks, {} templates ({} assembled from fragments), {} copies",
+            cache.symlinks.len(),
+            cache.templates.len(),
+            cache.fragments.len(),
+            cache.copies.len()
+        ),
+        None => println!("Nothing is deployed yet"),
+    }
+
+    match load_timings(opt)? {
+        Some(timings) => {
+            println!("Last deploy finished at {}", timings.finished);
+            timings.print();
+        }
+        None => println!("No timings were recorded yet, they're saved by every deploy"),
+    }
+    Ok(())
+}
+
+/// When the last deploy finished, in RFC 3339, or `None` if nothing was deployed yet
+pub fn last_deploy(opt: &Options) -> Result<Option<String>> {
+    Ok(load_timings(opt)?.map(|timings| timings.finished))
+}
+
+fn load_timings(opt: &Options) -> Result<Option<Timings>> {
+    match fs::read_to_string(timings_file(opt)) {
+        Ok(contents) => Ok(Some(
+            serde_json::from_str(&contents).context("parse timings file")?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("read timings file"),
+    }
+}
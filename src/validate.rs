@@ -0,0 +1,117 @@
+//! Checks of a template's render before it replaces the target, through the `validate` command
+//! of the template, like `sshd -t -f {}` or `nginx -t -c {}`, so that a broken render doesn't
+//! lock the user out of their shell or their machine
+
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use filesystem;
+use fleet;
+
+/// Writes `contents` to a temporary file named like `target`, and runs `command` through the
+/// shell with `{}` replaced by the file's path, or with the path appended if there's no `{}`.
+/// Fails with the command's output if it doesn't succeed.
+pub fn validate(command: &str, target: &Path, contents: &str) -> Result<()> {
+    // A new directory that only the user can enter, so that nobody can swap the file
+    let directory = tempfile::Builder::new()
+        .prefix("dotter-validate-")
+        .tempdir()
+        .context("create directory of validated file")?;
+    let result = run_validator(command, target, directory.path(), contents);
+    if let Err(e) = directory.close() {
+        warn!(
+            "Failed to remove the directory of the validated file: {}",
+            e
+        );
+    }
+    result
+}
+
+fn run_validator(command: &str, target: &Path, directory: &Path, contents: &str) -> Result<()> {
+    // The file keeps the target's name, so that validators that go by the extension work
+    let file = directory.join(target.file_name().unwrap_or_else(|| "render".as_ref()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .context("write render to validate")?;
+
+    run_with_path("validator", command, &file)
+}
+
+/// Runs `command` through the shell with `{}` replaced by `path`, or with the path appended if
+/// there's no `{}`. Fails with the command's output if it doesn't succeed. `description` names
+/// the command in the error.
+pub fn run_with_path(description: &str, command: &str, path: &Path) -> Result<()> {
+    let command_line = with_path(command, &path.to_string_lossy());
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(&command_line);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(&command_line);
+        shell
+    };
+    filesystem::set_child_environment(&mut shell);
+    let output = shell
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("run {} {:?}", description, command))?;
+    if !output.status.success() {
+        let mut message = String::from_utf8_lossy(&output.stderr).into_owned();
+        message.push_str(&String::from_utf8_lossy(&output.stdout));
+        if message.trim().is_empty() {
+            bail!(
+                "{} {:?} failed with {}",
+                description,
+                command,
+                output.status
+            );
+        }
+        bail!(
+            "{} {:?} failed with {}:\n{}",
+            description,
+            command,
+            output.status,
+            message.trim()
+        );
+    }
+    Ok(())
+}
+
+fn with_path(command: &str, path: &str) -> String {
+    let quoted = if cfg!(windows) {
+        format!("\"{}\"", path)
+    } else {
+        fleet::shell_quote(path)
+    };
+    if command.contains("{}") {
+        command.replace("{}", &quoted)
+    } else {
+        format!("{} {}", command, quoted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_with_path() {
+        assert_eq!(
+            with_path("sshd -t -f {}", "/tmp/sshd_config"),
+            "sshd -t -f /tmp/sshd_config"
+        );
+        assert_eq!(
+            with_path("jq empty", "/tmp/my settings.json"),
+            "jq empty '/tmp/my settings.json'"
+        );
+    }
+}
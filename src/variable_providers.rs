@@ -0,0 +1,133 @@
+//! Variables contributed by executables named in `variable_providers`, for sources that dotter
+//! doesn't know about, like a directory service or a configuration management system.
+//!
+//! Each provider is run through the shell when the configuration is loaded. It gets a JSON
+//! object on its standard input:
+//!
+//! ```json
+//! {"protocol": 1, "hostname": "laptop", "os": "unix", "user": "me", "packages": ["zsh"]}
+//! ```
+//!
+//! with the enabled packages, and answers with a JSON object on its standard output:
+//!
+//! ```json
+//! {"variables": {"email": "me@example.com"}}
+//! ```
+//!
+//! A provider that exits with an error, or that doesn't answer within `TIMEOUT`, fails the
+//! whole command. Its standard error goes to dotter's. The variables of later providers
+//! override the ones of earlier providers.
+
+use anyhow::{Context, Result};
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use config::Variables;
+use filesystem;
+use remote_variables;
+use variable_sources;
+
+/// Version of the protocol, which changes if the request or response change incompatibly
+const PROTOCOL: u32 = 1;
+
+/// How long a provider may take to answer, so that a service that can't be reached doesn't
+/// hang every command
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    protocol: u32,
+    #[serde(flatten)]
+    facts: Variables,
+    packages: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Response {
+    variables: Variables,
+}
+
+/// Runs every provider in order and merges their variables
+pub fn provide(providers: &[String], packages: &[String]) -> Result<Variables> {
+    let request = serde_json::to_string(&Request {
+        protocol: PROTOCOL,
+        facts: remote_variables::machine_facts(),
+        packages,
+    })
+    .context("serialize request")?;
+
+    let mut variables = Variables::new();
+    for provider in providers {
+        if variable_sources::commands_forbidden() {
+            bail!(
+                "variable provider {:?} isn't run because of --no-exec",
+                provider
+            );
+        }
+        debug!("Running variable provider {:?}...", provider);
+        let response = run(provider, &request)
+            .with_context(|| format!("run variable provider {:?}", provider))?;
+        let response: Response = serde_json::from_str(&response)
+            .with_context(|| format!("parse response of variable provider {:?}", provider))?;
+        variables.extend(response.variables);
+    }
+    Ok(variables)
+}
+
+fn run(provider: &str, request: &str) -> Result<String> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(provider);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(provider);
+        command
+    };
+    filesystem::set_child_environment(&mut command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("start provider")?;
+    // A provider that doesn't read its request closes standard input early, which isn't an error
+    if let Err(e) = child
+        .stdin
+        .take()
+        .context("get standard input of provider")?
+        .write_all(request.as_bytes())
+    {
+        debug!("Failed to send the request: {}", e);
+    }
+    let output = filesystem::wait_with_output_until(child, Instant::now() + TIMEOUT)
+        .context("wait for provider")?;
+    if !output.status.success() {
+        bail!("provider exited with {}", output.status);
+    }
+    String::from_utf8(output.stdout).context("response isn't UTF-8")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let mut facts = Variables::new();
+        facts.insert("hostname".into(), "laptop".into());
+        let packages = ["zsh".to_string()];
+        let request = Request {
+            protocol: PROTOCOL,
+            facts,
+            packages: &packages,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"protocol":1,"hostname":"laptop","packages":["zsh"]}"#
+        );
+    }
+}
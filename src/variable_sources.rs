@@ -0,0 +1,193 @@
+//! Variables whose value is read from the machine when the configuration is loaded:
+//! `env("EDITOR")` is an environment variable, `env("EDITOR", "vi")` falls back to `vi` if it's
+//! unset, and `cmd("git config user.email")` is the output of a shell command, without the
+//! trailing newline. Only the configuration files on the machine can use them: the variables
+//! of Ansible, remote variables and variable providers are taken as they are.
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use config::Variables;
+use filesystem;
+
+/// Set by `--no-exec`, which makes `cmd(...)` variables and variable providers fail instead of
+/// running their command
+static NO_EXEC: AtomicBool = AtomicBool::new(false);
+
+/// Output of every command that ran, so that `dotter watch` doesn't run them again every time
+/// it reloads the configuration
+static OUTPUTS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+fn outputs() -> MutexGuard<'static, BTreeMap<String, String>> {
+    OUTPUTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn forbid_commands(no_exec: bool) {
+    NO_EXEC.store(no_exec, Ordering::Relaxed);
+}
+
+pub fn commands_forbidden() -> bool {
+    NO_EXEC.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Source {
+    Env {
+        name: String,
+        default: Option<String>,
+    },
+    Command(String),
+}
+
+/// Replaces every string that is an `env(...)` or `cmd(...)`, including the ones inside tables
+/// and arrays, with its value
+pub fn resolve(variables: &mut Variables) -> Result<()> {
+    for (name, value) in variables.iter_mut() {
+        resolve_value(value).with_context(|| format!("resolve variable {:?}", name))?;
+    }
+    Ok(())
+}
+
+fn resolve_value(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(string) => {
+            if let Some(source) = parse(string) {
+                *string = read(&source)?;
+            }
+        }
+        toml::Value::Table(table) => resolve(table)?,
+        toml::Value::Array(array) => {
+            for element in array {
+                resolve_value(element)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn read(source: &Source) -> Result<String> {
+    match source {
+        Source::Env { name, default } => match (std::env::var(name), default) {
+            (Ok(value), _) => Ok(value),
+            (Err(std::env::VarError::NotPresent), Some(default)) => Ok(default.clone()),
+            (Err(e), _) => Err(e).with_context(|| format!("read environment variable {:?}", name)),
+        },
+        Source::Command(command) => {
+            if commands_forbidden() {
+                bail!("command {:?} isn't run because of --no-exec", command);
+            }
+            if let Some(output) = outputs().get(command) {
+                return Ok(output.clone());
+            }
+            let output = run(command).with_context(|| format!("run command {:?}", command))?;
+            outputs().insert(command.clone(), output.clone());
+            Ok(output)
+        }
+    }
+}
+
+fn run(command: &str) -> Result<String> {
+    let mut process = if cfg!(windows) {
+        let mut process = Command::new("cmd");
+        process.arg("/C").arg(command);
+        process
+    } else {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+    filesystem::set_child_environment(&mut process);
+    let output = process
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("start command")?;
+    if !output.status.success() {
+        bail!("command exited with {}", output.status);
+    }
+    let output = String::from_utf8(output.stdout).context("output of command isn't UTF-8")?;
+    Ok(output
+        .strip_suffix('\n')
+        .map(|o| o.strip_suffix('\r').unwrap_or(o))
+        .unwrap_or(&output)
+        .to_string())
+}
+
+/// The whole string has to be the call, anything else is an ordinary value. The arguments
+/// are double-quoted, with `\"` and `\\` escaped.
+fn parse(value: &str) -> Option<Source> {
+    let (function, rest) = value.trim().split_once('(')?;
+    let arguments = arguments(rest.strip_suffix(')')?)?;
+    match (function, arguments.as_slice()) {
+        ("env", [name]) => Some(Source::Env {
+            name: name.clone(),
+            default: None,
+        }),
+        ("env", [name, default]) => Some(Source::Env {
+            name: name.clone(),
+            default: Some(default.clone()),
+        }),
+        ("cmd", [command]) => Some(Source::Command(command.clone())),
+        _ => None,
+    }
+}
+
+fn arguments(list: &str) -> Option<Vec<String>> {
+    let mut arguments = Vec::new();
+    let mut chars = list.trim().chars();
+    loop {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut argument = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => argument.push(chars.next()?),
+                c => argument.push(c),
+            }
+        }
+        arguments.push(argument);
+        let rest = chars.as_str().trim_start();
+        if rest.is_empty() {
+            return Some(arguments);
+        }
+        chars = rest.strip_prefix(',')?.trim_start().chars();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("env(\"EDITOR\")"),
+            Some(Source::Env {
+                name: "EDITOR".into(),
+                default: None
+            })
+        );
+        assert_eq!(
+            parse(" env( \"EDITOR\" , \"vi\" ) "),
+            Some(Source::Env {
+                name: "EDITOR".into(),
+                default: Some("vi".into())
+            })
+        );
+        assert_eq!(
+            parse(r#"cmd("git config --get \"user.email\"")"#),
+            Some(Source::Command("git config --get \"user.email\"".into()))
+        );
+        assert_eq!(parse("env(EDITOR)"), None);
+        assert_eq!(parse("cmd(\"a\", \"b\")"), None);
+        assert_eq!(parse("my env(\"EDITOR\")"), None);
+        assert_eq!(parse("env(\"EDITOR\") and more"), None);
+    }
+}
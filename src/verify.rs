@@ -0,0 +1,262 @@
+//! Checks of a target right after a deploy changed it, through its `verify` setting: a command
+//! like `test -x {}`, the hash its contents must have, or the mode it must have. They catch
+//! filesystems that silently mangle what dotter writes, like synced folders that drop the
+//! executable bit or rewrite line endings.
+
+use anyhow::{Context, Result};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use config::HashAlgorithm;
+use file_state::DeployStep;
+use filesystem;
+use journal::Journal;
+use validate;
+
+/// Directory inside the cache where the previous state of a target with
+/// `on_failure = "rollback"` is kept until its checks passed
+const JOURNAL_DIRECTORY: &str = ".dotter_verify_journal";
+
+/// `verify` of a target. `verify = "<command>"` is short for a table with only `command`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "RawVerify", into = "RawVerify")]
+pub struct Verify {
+    /// Command that has to succeed, with `{}` replaced by the target like in `validate`
+    pub command: Option<String>,
+    /// Hash that the target's contents must have, as `<algorithm>:<hex>`
+    pub hash: Option<String>,
+    /// Permissions that the target must have
+    pub mode: Option<u32>,
+    pub on_failure: OnVerifyFailure,
+}
+
+/// What happens to the deploy when a check fails. The failure is reported either way.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum OnVerifyFailure {
+    /// Keep the target as it was deployed, and go on with the other files
+    #[default]
+    Report,
+    /// Stop the deploy, leaving the files after this one as they are
+    Abort,
+    /// Put the target back the way it was before the deploy, and go on with the other files
+    Rollback,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawVerify {
+    Command(String),
+    Checks(Checks),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Checks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(default)]
+    on_failure: OnVerifyFailure,
+}
+
+impl From<Verify> for RawVerify {
+    fn from(verify: Verify) -> Self {
+        RawVerify::Checks(Checks {
+            command: verify.command,
+            hash: verify.hash,
+            mode: verify.mode.map(|mode| format!("{:o}", mode)),
+            on_failure: verify.on_failure,
+        })
+    }
+}
+
+impl TryFrom<RawVerify> for Verify {
+    type Error = String;
+
+    fn try_from(raw: RawVerify) -> Result<Self, Self::Error> {
+        let checks = match raw {
+            RawVerify::Command(command) => Checks {
+                command: Some(command),
+                hash: None,
+                mode: None,
+                on_failure: OnVerifyFailure::default(),
+            },
+            RawVerify::Checks(checks) => checks,
+        };
+        if checks.command.is_none() && checks.hash.is_none() && checks.mode.is_none() {
+            return Err("`verify` needs a `command`, `hash` or `mode` to check".into());
+        }
+        if let Some(hash) = &checks.hash {
+            parse_hash(hash)?;
+        }
+        let mode = match &checks.mode {
+            Some(mode) => Some(
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o7777)
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid mode {:?} in `verify`, expected an octal number like \"755\"",
+                            mode
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Verify {
+            command: checks.command,
+            hash: checks.hash.map(|hash| hash.to_lowercase()),
+            mode,
+            on_failure: checks.on_failure,
+        })
+    }
+}
+
+/// Splits `<algorithm>:<hex>` into the algorithm and the hex
+fn parse_hash(hash: &str) -> Result<(HashAlgorithm, &str), String> {
+    let (algorithm, hex) = hash.split_once(':').ok_or_else(|| {
+        format!(
+            "invalid hash {:?} in `verify`, expected one like \"sha256:<hex>\"",
+            hash
+        )
+    })?;
+    let algorithm = algorithm.parse::<HashAlgorithm>()?;
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "invalid hash {:?} in `verify`, expected 64 hex digits after the algorithm",
+            hash
+        ));
+    }
+    Ok((algorithm, hex))
+}
+
+/// The checks of a step's target, started before the step so that it can be undone
+pub struct Verification {
+    verify: Verify,
+    source: PathBuf,
+    target: PathBuf,
+    /// What the step's paths were before it, for `on_failure = "rollback"`
+    journal: Option<Journal>,
+}
+
+impl Verification {
+    /// Prepares checking the target of `step` once it's performed. `None` if the target has
+    /// no checks, or if the step deletes it.
+    pub fn start(step: &DeployStep, cache_directory: &Path) -> Result<Option<Verification>> {
+        let verify = match step.verify() {
+            Some(verify) => verify.clone(),
+            None => return Ok(None),
+        };
+        let journal = if verify.on_failure == OnVerifyFailure::Rollback {
+            let mut journal = Journal::at(cache_directory.join(JOURNAL_DIRECTORY))
+                .context("start journal of target")?;
+            step.touched_paths()
+                .into_iter()
+                .try_for_each(|path| journal.record(path))
+                .context("record previous state of target")?;
+            Some(journal)
+        } else {
+            None
+        };
+        Ok(Some(Verification {
+            verify,
+            source: step.source().to_path_buf(),
+            target: step.target().to_path_buf(),
+            journal,
+        }))
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    pub fn on_failure(&self) -> OnVerifyFailure {
+        self.verify.on_failure
+    }
+
+    /// Fails with the first check that the target doesn't pass
+    pub fn check(&self) -> Result<()> {
+        let target = &self.target;
+        if let Some(expected) = self.verify.mode {
+            if let Some(mode) = filesystem::file_mode(target).context("get mode of target")? {
+                if mode != expected {
+                    bail!("the mode is {:o} instead of {:o}", mode, expected);
+                }
+            }
+        }
+        if let Some(expected) = &self.verify.hash {
+            let (algorithm, _) = parse_hash(expected).map_err(|e| anyhow!(e))?;
+            let contents = fs::read(target).context("read target")?;
+            let hash = filesystem::hash_bytes_with(algorithm, &contents);
+            if &hash != expected {
+                bail!("the hash is {} instead of {}", hash, expected);
+            }
+        }
+        if let Some(command) = &self.verify.command {
+            validate::run_with_path("verification", command, target)?;
+        }
+        Ok(())
+    }
+
+    /// Puts the step's paths back the way they were before it
+    pub fn rollback(mut self) -> Result<()> {
+        match self.journal.take() {
+            Some(journal) => journal.rollback(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Verification {
+    /// The step passed its checks, or was skipped, so the previous state isn't needed anymore
+    fn drop(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            if let Err(e) = journal.commit() {
+                warn!("Failed to remove the journal of {:?}: {:#}", self.target, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        #[derive(Deserialize)]
+        struct Target {
+            verify: Verify,
+        }
+        let target: Target = toml::from_str("verify = \"test -x {}\"").unwrap();
+        assert_eq!(target.verify.command.as_deref(), Some("test -x {}"));
+        assert_eq!(target.verify.on_failure, OnVerifyFailure::Report);
+
+        let verify: Verify = toml::from_str(&format!(
+            "hash = \"sha256:{}\"\nmode = \"755\"\non_failure = \"rollback\"",
+            "A".repeat(64)
+        ))
+        .unwrap();
+        assert_eq!(verify.hash, Some(format!("sha256:{}", "a".repeat(64))));
+        assert_eq!(verify.mode, Some(0o755));
+        assert_eq!(verify.on_failure, OnVerifyFailure::Rollback);
+
+        // The mode is written back the way it's read
+        let written = toml::to_string(&verify).unwrap();
+        assert_eq!(toml::from_str::<Verify>(&written).unwrap(), verify);
+
+        assert!(toml::from_str::<Verify>("on_failure = \"abort\"").is_err());
+        assert!(toml::from_str::<Verify>("mode = \"999\"").is_err());
+        assert!(toml::from_str::<Verify>("hash = \"sha256:abc\"").is_err());
+    }
+}
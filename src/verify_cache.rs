@@ -0,0 +1,285 @@
+//! Checks that the cache agrees with the filesystem, since a cache that was partially written,
+//! or whose targets were deleted or replaced by hand, makes deploys and diffs compare against
+//! the wrong thing
+
+use anyhow::{Context, Result};
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use args::Options;
+use config::{self, Cache, LinkKind};
+use context;
+use deploy;
+use file_state::FileState;
+use filesystem::{self, SymlinkComparison};
+use render::RenderLimits;
+use secrets;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Problem {
+    /// The target of a deployed file is gone. Repairing forgets the file, so that the next
+    /// deploy creates it again.
+    TargetMissing {
+        kind: &'static str,
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// The target isn't the symlink that was deployed anymore. Repairing forgets the symlink,
+    /// so that the next deploy treats whatever is there as a conflict.
+    NotDeployedSymlink { source: PathBuf, target: PathBuf },
+    /// The render that the template's target is compared against is gone. Repairing renders
+    /// the template again, or forgets it if it isn't in the configuration anymore.
+    RenderMissing { source: PathBuf, target: PathBuf },
+    /// A hash or the dependencies of a file that isn't deployed. Repairing removes them.
+    Leftover { what: &'static str, source: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::TargetMissing {
+                kind,
+                source,
+                target,
+            } => write!(
+                f,
+                "{:?}: target of {} {:?} is missing",
+                target, kind, source
+            ),
+            Problem::NotDeployedSymlink { source, target } => write!(
+                f,
+                "{:?}: isn't the symlink to {:?} that was deployed",
+                target, source
+            ),
+            Problem::RenderMissing { source, target } => write!(
+                f,
+                "{:?}: cached render of template {:?} is missing",
+                target, source
+            ),
+            Problem::Leftover { what, source } => {
+                write!(f, "{:?}: {} of a file that isn't deployed", source, what)
+            }
+        }
+    }
+}
+
+/// Prints every mismatch between the cache and the filesystem. Returns whether there were none.
+pub fn verify(opt: &Options) -> Result<bool> {
+    let (_, cache, state) = load(opt)?;
+    let problems = check(&cache, &state, &opt.cache_directory).context("check cache")?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("The cache matches the filesystem");
+    } else {
+        println!(
+            "{} problems in the cache, `dotter cache repair` fixes them",
+            problems.len()
+        );
+    }
+    Ok(problems.is_empty())
+}
+
+/// Fixes every problem that `verify` finds, see `Problem` for how
+pub fn repair(opt: &Options) -> Result<()> {
+    let _lock = config::lock_cache(&opt.cache_file, opt.act)?;
+    let (config, mut cache, state) = load(opt)?;
+    let problems = check(&cache, &state, &opt.cache_directory).context("check cache")?;
+    if problems.is_empty() {
+        println!("The cache matches the filesystem, nothing to repair");
+        return Ok(());
+    }
+
+    let handlebars = deploy::handlebars_instance(opt, &config);
+    let variables = context::template_context(&config);
+    let limits = RenderLimits::new(opt);
+    for problem in &problems {
+        if !opt.act {
+            println!("Would repair {}", problem);
+            continue;
+        }
+        info!("Repairing {}", problem);
+        match problem {
+            Problem::TargetMissing { source, .. } | Problem::NotDeployedSymlink { source, .. } => {
+                forget(&mut cache, source, &opt.cache_directory)?
+            }
+            Problem::RenderMissing { source, target } => {
+                let template = state
+                    .desired_templates
+                    .iter()
+                    .find(|t| &t.source == source && &t.target.target == target);
+                let template = match template {
+                    Some(template) => template,
+                    None => {
+                        warn!(
+                            "{:?} isn't in the configuration anymore, so {:?} is left where it is and no longer managed",
+                            source, target
+                        );
+                        forget(&mut cache, source, &opt.cache_directory)?;
+                        continue;
+                    }
+                };
+                // If the target differs from the new render, the next deploy sees it as edited
+                // instead of overwriting it
+                let rendered = deploy::render_to_string(template, &handlebars, &variables, &limits)
+                    .with_context(|| format!("render {}", template))?;
+                fs::create_dir_all(
+                    template
+                        .cache
+                        .parent()
+                        .context("get parent of cache file")?,
+                )
+                .context("create parent for cache file")?;
+                fs::write(&template.cache, secrets::cached_render(&rendered))
+                    .context("write rendered template to cache")?;
+                cache.template_dependencies.remove(source);
+            }
+            Problem::Leftover { source, .. } => {
+                cache.copy_hashes.remove(source);
+                cache.template_dependencies.remove(source);
+            }
+        }
+    }
+
+    if opt.act {
+        config::save_cache(&opt.cache_file, cache)?;
+        println!("Repaired {} problems", problems.len());
+    }
+    Ok(())
+}
+
+fn load(opt: &Options) -> Result<(config::Configuration, Cache, FileState)> {
+    let config = deploy::load_configuration(opt).context("get a configuration")?;
+    let mut cache = config::load_cache(&opt.cache_file)?
+        .context("load cache: there is no cache, nothing is deployed")?;
+    cache.match_case(&config.files);
+    let state = deploy::file_state_from_configuration(&config, &cache, &opt.cache_directory, false)
+        .context("get file state")?;
+    Ok((config, cache, state))
+}
+
+fn check(cache: &Cache, state: &FileState, cache_directory: &Path) -> Result<Vec<Problem>> {
+    // Targets of volatile files are expected to go missing
+    let volatile = state
+        .desired_symlinks
+        .iter()
+        .filter(|s| s.target.volatile)
+        .map(|s| &s.source)
+        .chain(
+            state
+                .desired_templates
+                .iter()
+                .filter(|t| t.target.volatile)
+                .map(|t| &t.source),
+        )
+        .chain(
+            state
+                .desired_copies
+                .iter()
+                .filter(|c| c.target.volatile)
+                .map(|c| &c.source),
+        )
+        .collect::<BTreeSet<_>>();
+    let missing = |source: &PathBuf, target: &Path| -> Result<bool> {
+        match fs::symlink_metadata(target) {
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(!volatile.contains(source)),
+            Err(e) => Err(e).with_context(|| format!("inspect {:?}", target)),
+        }
+    };
+
+    let mut problems = Vec::new();
+    for (source, target) in &cache.symlinks {
+        if missing(source, target)? {
+            problems.push(Problem::TargetMissing {
+                kind: "symlink",
+                source: source.clone(),
+                target: target.clone(),
+            });
+            continue;
+        }
+        let comparison = match cache.links.get(target) {
+            Some(LinkKind::Hardlink) => filesystem::compare_hardlink(source, target),
+            _ => filesystem::compare_symlink(source, target),
+        }
+        .with_context(|| format!("compare symlink {:?}", target))?;
+        match comparison {
+            SymlinkComparison::Identical
+            | SymlinkComparison::TargetIsSource
+            // The source is gone, which the configuration reports
+            | SymlinkComparison::OnlyTargetExists
+            | SymlinkComparison::OnlySourceExists
+            | SymlinkComparison::BothMissing => {}
+            SymlinkComparison::Changed
+            | SymlinkComparison::TargetNotSymlink
+            | SymlinkComparison::Circular => problems.push(Problem::NotDeployedSymlink {
+                source: source.clone(),
+                target: target.clone(),
+            }),
+        }
+    }
+    for (source, target) in &cache.templates {
+        if missing(source, target)? {
+            problems.push(Problem::TargetMissing {
+                kind: "template",
+                source: source.clone(),
+                target: target.clone(),
+            });
+        } else if !cache.render_path(source, cache_directory).is_file() {
+            problems.push(Problem::RenderMissing {
+                source: source.clone(),
+                target: target.clone(),
+            });
+        }
+    }
+    for (source, target) in &cache.copies {
+        if missing(source, target)? {
+            problems.push(Problem::TargetMissing {
+                kind: "copy",
+                source: source.clone(),
+                target: target.clone(),
+            });
+        }
+    }
+    for source in cache.copy_hashes.keys() {
+        if !cache.copies.contains_key(source) {
+            problems.push(Problem::Leftover {
+                what: "hash",
+                source: source.clone(),
+            });
+        }
+    }
+    for source in cache.template_dependencies.keys() {
+        if !cache.templates.contains_key(source) {
+            problems.push(Problem::Leftover {
+                what: "dependencies",
+                source: source.clone(),
+            });
+        }
+    }
+    Ok(problems)
+}
+
+/// Removes everything the cache knows about a deployed file
+fn forget(cache: &mut Cache, source: &Path, cache_directory: &Path) -> Result<()> {
+    cache.symlinks.remove(source);
+    cache.copies.remove(source);
+    cache.copy_hashes.remove(source);
+    cache.template_dependencies.remove(source);
+    let render = cache.render_path(source, cache_directory);
+    cache.render_caches.remove(source);
+    if cache.templates.remove(source).is_some() {
+        match fs::remove_file(&render) {
+            Ok(()) => filesystem::delete_parents(&render, false)
+                .context("delete empty parents of cached render")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("delete cached render"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Warnings with an ID, so that the ones that a setup expects can be silenced with
+//! `[warnings] allow = [...]` in global.toml or local.toml, the way `[lint] allow` silences
+//! the findings of `dotter lint`. An entry is an ID like `shadowed`, or an ID and the source,
+//! target, package or variable that the warning is about, like `unused-variable:font_size`.
+//!
+//! The IDs are:
+//! - `shadowed`: a package's file replaces the one of an earlier package
+//! - `unmatched-glob`: a glob of `files` doesn't match any file
+//! - `undefined-export`: a package exports a variable that it doesn't define
+//! - `unused-variable`: no deployed template or hook reads a variable
+//! - `symlinked-template`: a symlinked file reads a variable
+//! - `ignored-symlink-mode`: the `mode` or `flags` of a symlink can't be applied
+//! - `non-utf8-source`: a source that isn't UTF-8 is symlinked instead of checked for braces
+//! - `skipped-hook`: a hook that needs the network is skipped while offline
+//! - `skipped-entry`: a file whose source is missing is left out with --skip-missing-sources
+//! - `outdated-helper`: a template was rendered with a built-in helper whose output changed
+
+use std::fmt::Display;
+use std::sync::Mutex;
+
+static ALLOWED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Sets the warnings that aren't printed, from the configuration
+pub fn set_allowed(allowed: Vec<String>) {
+    *ALLOWED.lock().unwrap() = allowed;
+}
+
+pub fn is_allowed(id: &str, subject: &str) -> bool {
+    let qualified = format!("{}:{}", id, subject);
+    ALLOWED
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|allowed| allowed == id || *allowed == qualified)
+}
+
+/// Prints the warning `message` about `subject` with its ID, unless it's allowed
+pub fn warn(id: &str, subject: impl Display, message: impl Display) {
+    let subject = subject.to_string();
+    if is_allowed(id, &subject) {
+        debug!("Allowed warning {}: {}", id, message);
+    } else {
+        warn!("{} [{}:{}]", message, id, subject);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        set_allowed(vec!["shadowed".into(), "unused-variable:font_size".into()]);
+        assert!(is_allowed("shadowed", "zsh/zshrc"));
+        assert!(is_allowed("unused-variable", "font_size"));
+        assert!(!is_allowed("unused-variable", "font_family"));
+        assert!(!is_allowed("unmatched-glob", "*.conf"));
+        set_allowed(Vec::new());
+    }
+}
@@ -2,45 +2,448 @@ use anyhow::{Context, Result};
 
 use watchexec;
 
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(windows)]
+use std::net::{TcpListener as Listener, TcpStream as Stream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener as Listener, UnixStream as Stream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use super::display_error;
-use args::Options;
+use args::{InitialDeploy, Options};
+use config;
+use dependencies;
 use deploy;
+use render;
 
-struct WatchDeployHandler(Options);
+/// Deploys on behalf of both the filesystem watcher and the listener, one deploy at a time
+struct Deployer {
+    opt: Options,
+    overlay: deploy::ErrorOverlay,
+    /// Kept between deploys, so that saving a template only renders again what changed
+    warm: Mutex<deploy::Warm>,
+    debounce: u64,
+}
 
-impl watchexec::Handler for WatchDeployHandler {
-    fn on_manual(&self) -> watchexec::error::Result<bool> {
+impl Deployer {
+    /// Deploys after `changed` changed, everything is loaded again if it's empty.
+    /// Returns true if the deploy succeeded without errors.
+    fn deploy(&self, changed: &[&Path]) -> bool {
+        let mut warm = self.warm.lock().unwrap_or_else(|e| e.into_inner());
+        warm.invalidate(changed);
         println!("[Dotter] Deploying...");
-        if let Err(e) = deploy::deploy(&self.0) {
-            display_error(e);
+        let result = deploy::deploy_warm(&self.opt, &self.overlay, &mut warm);
+        render::forget_unused_templates();
+        match result {
+            Ok(error_occurred) => !error_occurred,
+            Err(e) => {
+                // The next deploy starts over, in case what was loaded is what failed
+                *warm = deploy::Warm::default();
+                display_error(e);
+                false
+            }
         }
+    }
+
+    fn start(&self, initial: InitialDeploy) {
+        match initial {
+            InitialDeploy::Deploy => {
+                self.deploy(&[]);
+            }
+            InitialDeploy::DryRun => {
+                let opt = Options {
+                    act: false,
+                    ..self.opt.clone()
+                };
+                println!("[Dotter] Dry run of the initial deploy...");
+                if let Err(e) = deploy::deploy(&opt, &self.overlay) {
+                    display_error(e);
+                }
+            }
+            InitialDeploy::Skip => debug!("Skipping the initial deploy"),
+        }
+    }
+}
+
+struct WatchDeployHandler(Arc<Deployer>);
+
+impl watchexec::Handler for WatchDeployHandler {
+    fn on_manual(&self) -> watchexec::error::Result<bool> {
+        self.0.deploy(&[]);
         Ok(true)
     }
 
-    fn on_update(&self, _: &[watchexec::pathop::PathOp]) -> watchexec::error::Result<bool> {
-        self.on_manual()
+    fn on_update(&self, ops: &[watchexec::pathop::PathOp]) -> watchexec::error::Result<bool> {
+        let changed = ops
+            .iter()
+            .map(|op| op.path.as_path())
+            .filter(|path| !is_ignored(path))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            debug!("Only ignored files changed, not deploying");
+            return Ok(true);
+        }
+        println!("[Dotter] Changed: {}", describe_changes(&changed));
+        self.0.deploy(&changed);
+        Ok(true)
     }
 
     fn args(&self) -> watchexec::Args {
+        let opt = &self.0.opt;
+        let ignores = vec![
+            ".git".into(),
+            opt.cache_file.to_string_lossy().into(),
+            format!("{}.lock", opt.cache_file.to_string_lossy()),
+            format!("{}.tmp", opt.cache_file.to_string_lossy()),
+            opt.cache_directory.to_string_lossy().into(),
+            "DOTTER_SYMLINK_TEST".into(),
+        ];
+
         watchexec::cli::ArgsBuilder::default()
             .cmd(vec!["".into()])
             .filters(vec!["*".into(), ".*".into()])
-            .ignores(vec![
-                ".git".into(),
-                self.0.global_config.to_string_lossy().into(),
-                self.0.local_config.to_string_lossy().into(),
-                self.0.cache_file.to_string_lossy().into(),
-                self.0.cache_directory.to_string_lossy().into(),
-                "DOTTER_SYMLINK_TEST".into(),
-            ])
-            .paths(vec![".".into()])
+            .ignores(ignores)
+            .paths(watched_paths(opt))
+            .run_initially(false)
+            .debounce(self.0.debounce)
             .build()
             .expect("valid watchexec args")
     }
 }
 
-pub(crate) fn watch(opt: Options) -> Result<()> {
-    watchexec::watch(&WatchDeployHandler(opt)).context("run watch deploy")?;
+/// The repository, and the files outside of it that a deploy reads: the configuration files,
+/// helper scripts, and files that templates read with helpers like `include_template`.
+/// They're found when the watch starts, so files referenced by later edits aren't watched
+/// until it's restarted.
+fn watched_paths(opt: &Options) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(".")];
+    let repository = match std::env::current_dir().and_then(|d| d.canonicalize()) {
+        Ok(repository) => repository,
+        Err(_) => return paths,
+    };
+    let mut outside = |path: &Path| {
+        if let Ok(path) = path.canonicalize() {
+            if !path.starts_with(&repository) && !paths.contains(&path) {
+                debug!("Also watching {:?}", path);
+                paths.push(path);
+            }
+        }
+    };
+
+    match config::configuration_files(&opt.local_config, &opt.global_config) {
+        Ok(files) => files.iter().for_each(|f| outside(f)),
+        Err(e) => warn!("Not watching included configuration files: {:#}", e),
+    }
+    let configuration = match deploy::load_configuration(opt) {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            warn!(
+                "Not watching files referenced by templates and helpers: {:#}",
+                e
+            );
+            return paths;
+        }
+    };
+    configuration
+        .helpers
+        .values()
+        .for_each(|h| outside(h.path()));
+    for source in configuration.files.keys() {
+        let contents = match std::fs::read_to_string(source) {
+            Ok(contents) if contents.contains("{{") => contents,
+            _ => continue,
+        };
+        if let Ok(referenced) = dependencies::referenced_files(&contents) {
+            referenced.iter().for_each(|f| outside(f));
+        }
+    }
+    paths
+}
+
+/// The changed paths, relative to the repository when they're inside it, each mentioned once
+fn describe_changes(changed: &[&Path]) -> String {
+    let repository = std::env::current_dir().unwrap_or_default();
+    let mut described = Vec::new();
+    for path in changed {
+        let path = path.strip_prefix(&repository).unwrap_or(path);
+        let path = format!("{}", path.display());
+        if !described.contains(&path) {
+            described.push(path);
+        }
+    }
+    described.join(", ")
+}
+
+/// Whether the ignore files of the repository leave out the changed `path`, so that changes to
+/// ignored files don't trigger a deploy
+fn is_ignored(path: &Path) -> bool {
+    let repository = std::env::current_dir().unwrap_or_default();
+    let path = match path.strip_prefix(&repository) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    config::is_ignored(&repository, path)
+        .inspect_err(|e| debug!("Couldn't check whether {:?} is ignored: {:#}", path, e))
+        .unwrap_or(false)
+}
+
+pub(crate) fn watch(
+    opt: Options,
+    overlay: deploy::ErrorOverlay,
+    listen: Option<PathBuf>,
+    watch_files: bool,
+    initial: InitialDeploy,
+    debounce: u64,
+) -> Result<()> {
+    let deployer = Arc::new(Deployer {
+        opt,
+        overlay,
+        warm: Mutex::new(deploy::Warm::default()),
+        debounce,
+    });
+    render::keep_parsed_templates();
+
+    if let Some(path) = listen {
+        let (listener, token) = bind(&path)?;
+        info!("Listening for changes on {:?}", path);
+        if !watch_files {
+            deployer.start(initial);
+            listen_for_changes(listener, token, &deployer);
+            return Ok(());
+        }
+        let deployer = deployer.clone();
+        std::thread::spawn(move || listen_for_changes(listener, token, &deployer));
+    }
+
+    deployer.start(initial);
+    watchexec::watch(&WatchDeployHandler(deployer)).context("run watch deploy")?;
 
     Ok(())
 }
+
+/// Creates the Unix domain socket at `path`, which only the user may connect to. A socket
+/// that is left over from a watch that ended is replaced, one that is in use isn't.
+#[cfg(unix)]
+fn bind(path: &Path) -> Result<(Listener, Option<String>)> {
+    if fs::symlink_metadata(path).is_ok() {
+        if Stream::connect(path).is_ok() {
+            bail!("another watch is listening on {:?} already", path);
+        }
+        fs::remove_file(path).context("remove socket of an earlier watch")?;
+    }
+    // The umask applies to the socket as it's created, so it's never open to anyone else
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = Listener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("listen on {:?}", path))?;
+    Ok((listener, None))
+}
+
+/// Windows has no Unix domain sockets that every editor can connect to, so the watch listens on
+/// a local port instead, and writes the port and a random token into the file at `path`.
+/// Every connection has to send the token, since any local user or web page can reach the port.
+#[cfg(windows)]
+fn bind(path: &Path) -> Result<(Listener, Option<String>)> {
+    let listener = Listener::bind("127.0.0.1:0").context("listen on a local port")?;
+    let port = listener.local_addr().context("get local port")?.port();
+    let mut token = [0u8; 16];
+    getrandom::getrandom(&mut token)
+        .map_err(|e| anyhow!("{}", e))
+        .context("generate token")?;
+    let token = token
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    fs::write(path, format!("{} {}\n", port, token))
+        .with_context(|| format!("write port and token to {:?}", path))?;
+    Ok((listener, Some(token)))
+}
+
+/// Most that a notification may send, which is plenty for a path and the headers of a request
+const MAX_NOTIFICATION_SIZE: u64 = 64 * 1024;
+
+/// How long a connection may take to send its notification
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections that are handled at once, the ones above it are closed right away
+const MAX_CONNECTIONS: usize = 8;
+
+/// Connections that are being handled
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a connection while it's handled
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    fn take() -> Option<ConnectionSlot> {
+        CONNECTIONS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connections| {
+                Some(connections + 1).filter(|&connections| connections <= MAX_CONNECTIONS)
+            })
+            .ok()
+            .map(|_| ConnectionSlot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Each connection is handled on its own thread, so that a slow one doesn't hold up the others.
+/// The deploys that they ask for still run one at a time.
+fn listen_for_changes(listener: Listener, token: Option<String>, deployer: &Arc<Deployer>) {
+    let token = Arc::new(token);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                display_error(anyhow::Error::new(e).context("accept connection"));
+                continue;
+            }
+        };
+        let slot = match ConnectionSlot::take() {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Closing a connection, since {} are being handled already",
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+        };
+        let deployer = Arc::clone(deployer);
+        let token = Arc::clone(&token);
+        std::thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(stream, token.as_deref(), &deployer) {
+                display_error(e.context("handle change notification"));
+            }
+        });
+    }
+}
+
+/// Reads the changed file, either as a single line or as the body of an HTTP request,
+/// deploys, and answers with the result in the same protocol. With a `token`, a line
+/// notification starts with a line that holds it, and a request has an
+/// `Authorization: Bearer <token>` header.
+fn handle_connection(mut stream: Stream, token: Option<&str>, deployer: &Deployer) -> Result<()> {
+    stream
+        .set_read_timeout(Some(NOTIFICATION_TIMEOUT))
+        .context("set timeout of connection")?;
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .context("clone connection")?
+            .take(MAX_NOTIFICATION_SIZE),
+    );
+    let mut first_line = String::new();
+    reader
+        .read_line(&mut first_line)
+        .context("read notification")?;
+
+    let http = first_line.starts_with("POST ") || first_line.starts_with("PUT ");
+    let mut authorized = token.is_none();
+    let changed = if http {
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).context("read header")?;
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().context("parse Content-Length")?;
+                    if content_length as u64 > MAX_NOTIFICATION_SIZE {
+                        bail!(
+                            "the body is {} bytes, which is more than the limit of {} bytes",
+                            content_length,
+                            MAX_NOTIFICATION_SIZE
+                        );
+                    }
+                }
+                if name.eq_ignore_ascii_case("authorization") {
+                    authorized |= token
+                        .is_some_and(|token| value.trim().strip_prefix("Bearer ") == Some(token));
+                }
+            }
+        }
+        if !authorized {
+            write!(
+                stream,
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .context("send result")?;
+            bail!("the request doesn't have the token");
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).context("read body")?;
+        String::from_utf8_lossy(&body).trim().to_string()
+    } else {
+        let mut line = first_line;
+        if !authorized {
+            if Some(line.trim()) != token {
+                bail!("the notification doesn't start with the token");
+            }
+            line = String::new();
+            reader.read_line(&mut line).context("read notification")?;
+        }
+        line.trim().to_string()
+    };
+    let changed = if changed.is_empty() {
+        debug!("Deploy requested");
+        Vec::new()
+    } else {
+        println!(
+            "[Dotter] Changed: {}",
+            describe_changes(&[Path::new(&changed)])
+        );
+        vec![Path::new(&changed)]
+    };
+    let status = if deployer.deploy(&changed) {
+        "ok"
+    } else {
+        "error"
+    };
+
+    if http {
+        let code = if status == "ok" {
+            "200 OK"
+        } else {
+            "500 Internal Server Error"
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+            code,
+            status.len() + 1,
+            status
+        )
+    } else {
+        writeln!(stream, "{}", status)
+    }
+    .context("send result")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_slots() {
+        let slots = (0..MAX_CONNECTIONS)
+            .map(|_| ConnectionSlot::take().unwrap())
+            .collect::<Vec<_>>();
+        assert!(ConnectionSlot::take().is_none());
+        drop(slots);
+        assert!(ConnectionSlot::take().is_some());
+        assert_eq!(CONNECTIONS.load(Ordering::SeqCst), 0);
+    }
+}